@@ -0,0 +1,13 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+
+use ball_gfx_hal::networking::client::ClientPacket;
+use ball_gfx_hal::networking::connection::Connection;
+
+// Payloads behind a valid header, so that they get deserialized like
+// anything a server receives.
+fuzz_target!(|data: &[u8]| {
+    let (sealed, _) = Connection::default().encode_payload(data);
+    let _ = Connection::default().decode::<ClientPacket>(&sealed);
+});
@@ -0,0 +1,15 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+
+use ball_gfx_hal::networking::client::ClientPacket;
+use ball_gfx_hal::networking::connection::Connection;
+use ball_gfx_hal::networking::server::ServerPacket;
+
+// Whole datagrams, header and all, as either end would receive them.
+// Almost nothing gets past the checksum, so the header checks are
+// what this exercises.
+fuzz_target!(|data: &[u8]| {
+    let _ = Connection::default().decode::<ClientPacket>(data);
+    let _ = Connection::default().decode::<ServerPacket>(data);
+});
@@ -0,0 +1,13 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+
+use ball_gfx_hal::networking::connection::Connection;
+use ball_gfx_hal::networking::server::ServerPacket;
+
+// Payloads behind a valid header, so that they get deserialized like
+// anything a client receives.
+fuzz_target!(|data: &[u8]| {
+    let (sealed, _) = Connection::default().encode_payload(data);
+    let _ = Connection::default().decode::<ServerPacket>(&sealed);
+});
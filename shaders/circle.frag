@@ -9,6 +9,7 @@ layout (location = 0) in vec2 inPos;
 
 layout (push_constant) uniform PushConstant {
   float radius;
+  float inner_radius;
   vec2 center;
   vec4 color;
 } push_constants;
@@ -17,12 +18,16 @@ layout (location = 0) out vec4 outColor;
 
 void main() {
   float radius_sq = push_constants.radius * push_constants.radius;
+  float inner_sq = push_constants.inner_radius * push_constants.inner_radius;
   float len_sq = dot(inPos, inPos);
-  if(len_sq > radius_sq) {
+  if(len_sq > radius_sq || len_sq < inner_sq) {
     discard;
   }
   float delta = fwidth(len_sq);
   float alpha = 1.0 - smoothstep(radius_sq - delta, radius_sq, len_sq);
+  if(inner_sq > 0.0) {
+    alpha *= smoothstep(inner_sq, inner_sq + delta, len_sq);
+  }
   // float alpha = clamp((push_constants.radius - globals.pixel_size - len) / globals.pixel_size, 0.0, 1.0);
   outColor = vec4(push_constants.color.rgb, push_constants.color.a * alpha);
 }
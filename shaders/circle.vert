@@ -8,6 +8,7 @@ layout (location = 0) in vec2 inPos;
 
 layout (push_constant) uniform PushConstant {
   float radius;
+  float inner_radius;
   vec2 center;
   vec4 color;
 } push_constants;
@@ -0,0 +1,125 @@
+use easer::functions::*;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Curve that a tween follows from start to end.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Easing {
+    Linear,
+    ExpoIn,
+    ExpoOut,
+}
+
+/// A value moving from one number to another over a fixed duration.
+///
+/// Once finished, the tween holds its end value.
+#[derive(Copy, Clone, Debug)]
+pub struct Tween {
+    from: f32,
+    to: f32,
+    /// Seconds before the tween starts moving.
+    delay: f32,
+    duration: f32,
+    easing: Easing,
+    elapsed: f32,
+}
+
+/// A set of tweens keyed by id, which are all advanced together.
+#[derive(Clone, Debug)]
+pub struct Animations<K> {
+    tweens: HashMap<K, Tween>,
+}
+
+impl Tween {
+    pub fn new(from: f32, to: f32, duration: f32, easing: Easing) -> Tween {
+        Tween {
+            from,
+            to,
+            delay: 0.0,
+            duration,
+            easing,
+            elapsed: 0.0,
+        }
+    }
+
+    /// Waits for some number of seconds before starting to move.
+    pub fn delay(self, delay: f32) -> Tween {
+        Tween {
+            delay,
+            ..self
+        }
+    }
+
+    /// Advances the tween by `dt` seconds.
+    pub fn tick(&mut self, dt: f32) {
+        self.elapsed += dt;
+    }
+
+    pub fn finished(&self) -> bool {
+        self.elapsed >= self.delay + self.duration
+    }
+
+    /// Gets the current value of the tween.
+    pub fn value(&self) -> f32 {
+        if self.finished() {
+            return self.to;
+        }
+        let t = (self.elapsed - self.delay).max(0.0);
+        let change = self.to - self.from;
+        match self.easing {
+            Easing::Linear => {
+                Linear::ease_in(t, self.from, change, self.duration)
+            },
+            Easing::ExpoIn => {
+                Expo::ease_in(t, self.from, change, self.duration)
+            },
+            Easing::ExpoOut => {
+                Expo::ease_out(t, self.from, change, self.duration)
+            },
+        }
+    }
+}
+
+#[test]
+fn tween_holds_end_value() {
+    let mut tween = Tween::new(0.0, 2.0, 1.0, Easing::Linear).delay(0.5);
+    tween.tick(0.25);
+    assert_eq!(tween.value(), 0.0);
+    tween.tick(0.75);
+    assert!((tween.value() - 1.0).abs() < 1e-6);
+    tween.tick(10.0);
+    assert!(tween.finished());
+    assert_eq!(tween.value(), 2.0);
+}
+
+impl<K: Hash + Eq> Animations<K> {
+    /// Starts a tween, replacing any existing one with the same id.
+    pub fn start(&mut self, id: K, tween: Tween) {
+        self.tweens.insert(id, tween);
+    }
+
+    pub fn stop(&mut self, id: &K) {
+        self.tweens.remove(id);
+    }
+
+    /// Advances every tween by `dt` seconds.
+    pub fn tick(&mut self, dt: f32) {
+        for tween in self.tweens.values_mut() {
+            tween.tick(dt);
+        }
+    }
+
+    /// Gets the current value of a tween, if one is running or has
+    /// finished without being stopped.
+    pub fn value(&self, id: &K) -> Option<f32> {
+        self.tweens.get(id).map(Tween::value)
+    }
+}
+
+impl<K: Hash + Eq> Default for Animations<K> {
+    fn default() -> Animations<K> {
+        Animations {
+            tweens: HashMap::new(),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use log::{info, warn};
+use rodio::source::{SineWave, Source};
+use rodio::Device;
+use std::time::Duration;
+
+/// Every sound effect that can be played.
+///
+/// These are all synthesized, so there aren't any asset files to
+/// ship.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Sound {
+    /// Two balls, or a ball and the wall, bounced off of each other.
+    /// The intensity goes from 0 to 1.
+    Collision(f32),
+    /// Some player died.
+    Kill,
+    /// The local player died.
+    Death,
+    RoundStart,
+    RoundEnd,
+    /// A UI button was clicked.
+    Click,
+}
+
+/// Plays sound effects on the default output device.
+///
+/// If there isn't an output device, this silently does nothing.
+pub struct Audio {
+    device: Option<Device>,
+    /// Volume of all sounds, from 0 to 1.
+    volume: f32,
+}
+
+impl Audio {
+    pub fn new(volume: f32) -> Audio {
+        let device = rodio::default_output_device();
+        match device {
+            Some(ref device) => info!("using audio device {}", device.name()),
+            None => warn!("no audio output device found, sound disabled"),
+        }
+        Audio {
+            device,
+            volume: volume.max(0.0).min(1.0),
+        }
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.max(0.0).min(1.0);
+    }
+
+    /// Starts playing a sound, without waiting for it to finish.
+    pub fn play(&self, sound: Sound) {
+        let device = match self.device {
+            Some(ref device) => device,
+            None => return,
+        };
+        if self.volume <= 0.0 {
+            return;
+        }
+
+        // Each sound is a short tone: (frequency in Hz, duration in
+        // ms, gain).
+        let (frequency, duration, gain) = match sound {
+            Sound::Collision(intensity) => {
+                (220, 40, 0.3 * intensity.max(0.0).min(1.0))
+            },
+            Sound::Kill => (660, 120, 0.4),
+            Sound::Death => (110, 300, 0.5),
+            Sound::RoundStart => (880, 150, 0.3),
+            Sound::RoundEnd => (440, 250, 0.3),
+            Sound::Click => (1320, 15, 0.2),
+        };
+        let source = SineWave::new(frequency)
+            .take_duration(Duration::from_millis(duration))
+            .fade_in(Duration::from_millis(5))
+            .amplify(gain * self.volume);
+        rodio::play_raw(device, source);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::networking::server::{self, ServerConfig};
+use crate::networking::transport::ChannelNetwork;
+use crate::networking::{self, client};
+use crate::swarm::{self, Behavior, Pattern};
+use log::{info, warn};
+use rand::thread_rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Speed of each synthetic player's cursor in game units per second.
+const CURSOR_SPEED: f32 = 0.45;
+
+/// How often the server's metrics are checked for new ticks and
+/// snapshots.
+const SAMPLE_RATE: Duration = Duration::from_millis(1);
+
+/// Options for benchmarking a server.
+#[derive(Copy, Clone, Debug)]
+pub struct BenchConfig {
+    /// Number of synthetic players.
+    pub players: u16,
+    /// Seconds to run for.
+    pub duration: f32,
+}
+
+/// Logs the distribution of some durations in seconds.
+fn report(name: &str, samples: &mut [f32]) {
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let sorted = &*samples;
+    let mean = sorted.iter().sum::<f32>() / sorted.len().max(1) as f32;
+    let percentile = |percentile| {
+        networking::percentile(sorted, percentile).unwrap_or(0.0) * 1e6
+    };
+    info!(
+        "{}: {} samples, mean {:.1} us, p50 {:.1} us, p95 {:.1} us, p99 {:.1} \
+         us, max {:.1} us",
+        name,
+        sorted.len(),
+        mean * 1e6,
+        percentile(0.5),
+        percentile(0.95),
+        percentile(0.99),
+        percentile(1.0),
+    );
+}
+
+/// Records the latest value of a gauge if its counter has moved on
+/// since it was last seen.
+fn sample(
+    counter: &AtomicU64,
+    micros: &AtomicU64,
+    seen: &mut u64,
+    samples: &mut Vec<f32>,
+) {
+    let count = counter.load(Ordering::Relaxed);
+    if count != *seen {
+        *seen = count;
+        samples.push(micros.load(Ordering::Relaxed) as f32 / 1e6);
+    }
+}
+
+/// Hosts a server with synthetic players for a while, and logs how
+/// long game ticks and snapshots took.
+///
+/// The server and players run their real event loops, talking over an
+/// in-process network rather than UDP. Each synthetic player moves
+/// their cursor around a circle.
+pub fn run(config: BenchConfig) {
+    let network = ChannelNetwork::new();
+    let transport = network.bind();
+    let addr = transport.local_addr();
+    let (server, server_thread) =
+        match server::host_with(Box::new(transport), ServerConfig::default()) {
+            Ok(result) => result,
+            Err(err) => {
+                warn!("failed to start server: {}", err);
+                return;
+            },
+        };
+
+    let mut rng = thread_rng();
+    let behavior = Behavior {
+        pattern: Pattern::Circle,
+        speed: CURSOR_SPEED,
+        path: None,
+    };
+    let mut clients = Vec::new();
+    let mut threads = Vec::new();
+    for i in 0..config.players {
+        let cursor = swarm::random_cursor(&mut rng);
+        let name = format!("synthetic {}", i + 1);
+        let transport = Box::new(network.bind());
+        match client::connect_with(transport, addr, None, cursor, name, None) {
+            Ok((handle, connecting, thread)) => {
+                let behavior = behavior.clone();
+                clients.push(handle);
+                threads.push(thread);
+                threads.push(thread::spawn(move || {
+                    swarm::drive(connecting, cursor, behavior);
+                }));
+            },
+            Err(err) => warn!("synthetic player {} failed: {}", i + 1, err),
+        }
+    }
+    info!(
+        "benchmarking server with {} players for {} s",
+        config.players, config.duration
+    );
+
+    let metrics = &server.metrics;
+    let mut tick_times = Vec::new();
+    let mut snapshot_times = Vec::new();
+    let mut snapshot_bytes = Vec::new();
+    let (mut ticks, mut snapshots) = (0, 0);
+    let start = Instant::now();
+    let bytes_sent = metrics.bytes_sent.load(Ordering::Relaxed);
+    while start.elapsed().as_secs_f32() < config.duration {
+        thread::sleep(SAMPLE_RATE);
+        sample(
+            &metrics.ticks,
+            &metrics.tick_micros,
+            &mut ticks,
+            &mut tick_times,
+        );
+        let before = snapshot_times.len();
+        sample(
+            &metrics.snapshots,
+            &metrics.snapshot_micros,
+            &mut snapshots,
+            &mut snapshot_times,
+        );
+        if snapshot_times.len() > before {
+            snapshot_bytes.push(metrics.snapshot_bytes.load(Ordering::Relaxed));
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f32();
+    let bytes_sent = metrics.bytes_sent.load(Ordering::Relaxed) - bytes_sent;
+
+    for client in &clients {
+        client.shutdown();
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    server.shutdown();
+    server_thread.join().unwrap();
+
+    report("game tick", &mut tick_times);
+    report("snapshots (all players)", &mut snapshot_times);
+    info!(
+        "snapshots: {} bytes on average before headers, {:.1} KB/s sent in \
+         total",
+        snapshot_bytes.iter().sum::<usize>() / snapshot_bytes.len().max(1),
+        bytes_sent as f32 / elapsed / 1000.0,
+    );
+}
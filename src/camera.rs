@@ -0,0 +1,299 @@
+use crate::game::PlayerId;
+use crate::graphics::Circle;
+use nalgebra::{Point2, Vector2};
+use ord_subset::OrdSubsetIterExt;
+
+/// How much bigger things look when the camera is fully focused on a
+/// point, compared to showing the whole arena.
+pub const FOCUS_ZOOM: f32 = 2.5;
+
+/// Seconds the spectator camera takes to get most of the way to where
+/// it's heading.
+const EASE_TIME: f32 = 0.2;
+
+/// How far the camera is focused on a player it's following.
+const FOLLOW_FOCUS: f32 = 0.6;
+
+/// Distance in world units between two balls that the director zooms
+/// all the way out at.
+const DIRECTOR_SPREAD: f32 = 1.5;
+
+/// Change in focus for each line scrolled.
+const ZOOM_STEP: f32 = 0.1;
+
+/// Furthest from the middle of the arena that the free camera can be
+/// panned to.
+const MAX_PAN: f32 = 1.0;
+
+/// View of the arena, which can zoom in on a point.
+///
+/// This only changes what's drawn, so cursor input is still mapped
+/// as if the whole arena was shown.
+#[derive(Copy, Clone, Debug)]
+pub struct Camera {
+    /// Point in world space to zoom in on.
+    pub target: Point2<f32>,
+    /// How far the camera is zoomed in on the target, from 0 for the
+    /// whole arena to 1 for centered on it at `FOCUS_ZOOM`.
+    pub focus: f32,
+}
+
+/// What the camera looks at while this client isn't playing, either
+/// because it's spectating or because its player is dead.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraMode {
+    /// The whole arena, as while playing.
+    Arena,
+    /// Wherever it was panned and zoomed to by hand.
+    Free,
+    /// A player's ball.
+    Follow(PlayerId),
+    /// Between the two surviving players closest to each other, which
+    /// is usually where the action is.
+    Director,
+}
+
+/// Camera that can be moved around while spectating, and that eases
+/// between where it's looking rather than jumping.
+#[derive(Clone, Debug)]
+pub struct SpectatorCamera {
+    pub mode: CameraMode,
+    /// Where the free camera was left.
+    free: Camera,
+    /// Where the camera is looking right now.
+    current: Camera,
+}
+
+impl Default for Camera {
+    fn default() -> Camera {
+        Camera {
+            target: Point2::origin(),
+            focus: 0.0,
+        }
+    }
+}
+
+impl Camera {
+    pub fn zoom(&self) -> f32 {
+        1.0 + (FOCUS_ZOOM - 1.0) * self.focus
+    }
+
+    /// Moves a circle into view, given the scale it was drawn at.
+    pub fn apply(&self, circle: Circle, scale: f32) -> Circle {
+        let zoom = self.zoom();
+        let center = self.target * (scale * self.focus);
+        Circle {
+            center: Point2::origin() + (circle.center - center) * zoom,
+            radius: circle.radius * zoom,
+            inner_radius: circle.inner_radius * zoom,
+            ..circle
+        }
+    }
+
+    /// Maps a point on screen back to where it is in the world, given
+    /// the scale it was drawn at, undoing `apply`.
+    pub fn unapply(&self, point: Point2<f32>, scale: f32) -> Point2<f32> {
+        let center = self.target * (scale * self.focus);
+        (point / self.zoom() + center.coords) / scale
+    }
+}
+
+impl Default for SpectatorCamera {
+    fn default() -> SpectatorCamera {
+        SpectatorCamera {
+            mode: CameraMode::Arena,
+            free: Camera::default(),
+            current: Camera::default(),
+        }
+    }
+}
+
+impl SpectatorCamera {
+    /// Gets where the camera is looking right now.
+    pub fn camera(&self) -> Camera {
+        self.current
+    }
+
+    /// Moves the camera straight to `camera`, for when something else
+    /// is in control of it.
+    pub fn jump(&mut self, camera: Camera) {
+        self.current = camera;
+    }
+
+    /// Eases the camera towards `target` over `dt` seconds.
+    pub fn ease(&mut self, dt: f32, target: Camera) {
+        let t = 1.0 - (-dt / EASE_TIME).exp();
+        self.current.target += (target.target - self.current.target) * t;
+        self.current.focus += (target.focus - self.current.focus) * t;
+    }
+
+    /// Gets where the camera should be heading for its mode, given
+    /// where every surviving player's ball is.
+    pub fn target(&self, balls: &[(PlayerId, Point2<f32>)]) -> Camera {
+        match self.mode {
+            CameraMode::Arena => Camera::default(),
+            CameraMode::Free => self.free,
+            CameraMode::Follow(id) => {
+                match balls.iter().find(|&&(other, _)| other == id) {
+                    Some(&(_, ball)) => {
+                        Camera {
+                            target: ball,
+                            focus: FOLLOW_FOCUS,
+                        }
+                    },
+                    // Wait where they were until they're back.
+                    None => self.current,
+                }
+            },
+            CameraMode::Director => director_target(balls),
+        }
+    }
+
+    /// Follows the next player along from the one being followed, or
+    /// the previous one if `forward` is false.
+    pub fn cycle(&mut self, balls: &[(PlayerId, Point2<f32>)], forward: bool) {
+        let mut ids = balls.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+        ids.sort();
+        if ids.is_empty() {
+            return;
+        }
+        let current = match self.mode {
+            CameraMode::Follow(id) => ids.iter().position(|&other| other == id),
+            _ => None,
+        };
+        let next = match (current, forward) {
+            (Some(i), true) => (i + 1) % ids.len(),
+            (Some(i), false) => (i + ids.len() - 1) % ids.len(),
+            (None, true) => 0,
+            (None, false) => ids.len() - 1,
+        };
+        self.mode = CameraMode::Follow(ids[next]);
+    }
+
+    /// Follows whoever's ball is at `point` in the world, returning
+    /// whether there was one.
+    pub fn select(
+        &mut self,
+        point: Point2<f32>,
+        balls: &[(PlayerId, Point2<f32>)],
+        ball_radius: f32,
+    ) -> bool {
+        let selected = balls.iter().find(|&&(_, ball)| {
+            nalgebra::distance(&ball, &point) <= ball_radius
+        });
+        if let Some(&(id, _)) = selected {
+            self.mode = CameraMode::Follow(id);
+        }
+        selected.is_some()
+    }
+
+    /// Pans the free camera so that the view moves by `delta` on
+    /// screen, given the scale it's drawn at.
+    pub fn pan(&mut self, delta: Vector2<f32>, scale: f32) {
+        self.free_from_current();
+        let focus = self.free.focus;
+        if focus <= 0.0 {
+            // The whole arena is in view, so there's nowhere to go.
+            return;
+        }
+        let center =
+            self.free.target * focus - delta / (scale * self.free.zoom());
+        let target = center / focus;
+        let distance = target.coords.norm();
+        self.free.target = if distance > MAX_PAN {
+            target * (MAX_PAN / distance)
+        } else {
+            target
+        };
+    }
+
+    /// Zooms the free camera in by some number of lines scrolled, or
+    /// out for negative ones.
+    pub fn zoom(&mut self, lines: f32) {
+        self.free_from_current();
+        let focus = self.free.focus + lines * ZOOM_STEP;
+        self.free.focus = focus.max(0.0).min(1.0);
+    }
+
+    /// Switches to the free camera, starting where it's looking now.
+    fn free_from_current(&mut self) {
+        if self.mode != CameraMode::Free {
+            self.mode = CameraMode::Free;
+            self.free = self.current;
+        }
+    }
+}
+
+/// Frames the two balls closest to each other, zooming in more the
+/// closer they are, or just follows the last one left.
+fn director_target(balls: &[(PlayerId, Point2<f32>)]) -> Camera {
+    let closest = balls
+        .iter()
+        .enumerate()
+        .flat_map(|(i, &(_, a))| {
+            balls[i + 1..].iter().map(move |&(_, b)| (a, b))
+        })
+        .ord_subset_min_by_key(|(a, b)| nalgebra::distance(a, b));
+    match (closest, balls.first()) {
+        (Some((a, b)), _) => {
+            let spread = nalgebra::distance(&a, &b) / DIRECTOR_SPREAD;
+            Camera {
+                target: nalgebra::center(&a, &b),
+                focus: FOLLOW_FOCUS * (1.0 - spread).max(0.0),
+            }
+        },
+        (None, Some(&(_, ball))) => {
+            Camera {
+                target: ball,
+                focus: FOLLOW_FOCUS,
+            }
+        },
+        (None, None) => Camera::default(),
+    }
+}
+
+#[test]
+fn focused_target_centered() {
+    let camera = Camera {
+        target: Point2::new(0.5, -0.5),
+        focus: 1.0,
+    };
+    let circle = Circle {
+        center: Point2::new(0.25, -0.25),
+        radius: 0.1,
+        inner_radius: 0.0,
+        color: palette::LinSrgb::new(1.0, 1.0, 1.0),
+    };
+    let viewed = camera.apply(circle, 0.5);
+    assert_eq!(viewed.center, Point2::origin());
+    assert_eq!(viewed.radius, 0.1 * FOCUS_ZOOM);
+}
+
+#[test]
+fn unapply_undoes_apply() {
+    let camera = Camera {
+        target: Point2::new(0.3, 0.1),
+        focus: 0.5,
+    };
+    let circle = Circle {
+        center: Point2::new(-0.2, 0.4),
+        radius: 0.1,
+        inner_radius: 0.0,
+        color: palette::LinSrgb::new(1.0, 1.0, 1.0),
+    };
+    let viewed = camera.apply(circle, 0.9);
+    let world = camera.unapply(viewed.center, 0.9);
+    assert!(nalgebra::distance(&world, &(circle.center / 0.9)) < 1e-5);
+}
+
+#[test]
+fn director_frames_closest_pair() {
+    let balls = [
+        (0, Point2::new(-0.8, 0.0)),
+        (1, Point2::new(0.4, 0.0)),
+        (2, Point2::new(0.6, 0.0)),
+    ];
+    let camera = director_target(&balls);
+    assert!(nalgebra::distance(&camera.target, &Point2::new(0.5, 0.0)) < 1e-5);
+    assert!(camera.focus > 0.0 && camera.focus < FOLLOW_FOCUS);
+}
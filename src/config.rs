@@ -0,0 +1,328 @@
+use crate::game::InterpolationMode;
+use crate::ui::Theme;
+use crate::viewport::Framing;
+use failure::Fail;
+use gfx_hal::PresentMode;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Name of the config file inside the config directory.
+const CONFIG_FILE: &str = "config.toml";
+
+/// Name of the log file a headless server uses by default, inside the
+/// config directory.
+const SERVER_LOG_FILE: &str = "server.log";
+
+/// Name of the settings file for a headless server, inside the config
+/// directory.
+const SERVER_SETTINGS_FILE: &str = "server.toml";
+
+/// Name of the file a headless server saves its match state to,
+/// inside the config directory.
+const SERVER_STATE_FILE: &str = "server_state.toml";
+
+/// Preferences that are persisted between launches.
+///
+/// Missing fields are filled in with defaults, so older config files
+/// keep working as more options get added.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub graphics: GraphicsConfig,
+    pub network: NetworkConfig,
+    pub gameplay: GameplayConfig,
+    pub audio: AudioConfig,
+    pub gamepad: GamepadConfig,
+    pub interface: InterfaceConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GraphicsConfig {
+    /// Logical width of the window.
+    pub window_width: f64,
+    /// Logical height of the window.
+    pub window_height: f64,
+    /// Preferred present mode, if it's supported. Otherwise the
+    /// graphics backend picks one.
+    pub present_mode: Option<PresentMode>,
+    /// Preferred number of swapchain images, clamped to what the
+    /// window supports.
+    pub image_count: Option<u32>,
+    /// Whether the window covers the whole monitor.
+    pub fullscreen: bool,
+    /// Name of the monitor to go fullscreen on, or `None` for
+    /// whichever one the window is on.
+    pub monitor: Option<String>,
+    /// How the arena is fitted into the window.
+    pub framing: Framing,
+    /// Space kept clear around the arena for UI windows, as a
+    /// fraction of the window's shorter side.
+    pub ui_margin: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// The delay in multiples of the snapshot rate to buffer
+    /// snapshots for interpolation.
+    pub interpolation_delay: f32,
+    pub interpolation_mode: InterpolationMode,
+    /// Address last typed in for a new bookmark.
+    pub server_address: String,
+    /// Address last typed in as a rendezvous for connecting to servers
+    /// behind NAT, or empty to connect directly.
+    pub rendezvous_address: String,
+    /// Address of the last hosted server.
+    pub host_address: String,
+    /// Whether to encrypt connections to hosted servers.
+    pub host_encrypted: bool,
+    /// Whether hosted servers are listed on the master server.
+    pub host_announced: bool,
+    /// Address of the master server that lists internet games, if
+    /// there is one.
+    pub master_server: Option<String>,
+    /// Saved servers shown in the main menu.
+    pub bookmarks: Vec<Bookmark>,
+    /// Secret that admins have to send when connecting to the admin
+    /// port of a headless server.
+    pub admin_secret: Option<String>,
+}
+
+/// A saved server in the main menu.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    pub address: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameplayConfig {
+    pub player_name: String,
+    /// Preferred player hue from 0 to 1, or `None` for a random one.
+    pub color_hue: Option<f32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// Volume of all sound effects, from 0 to 1.
+    pub volume: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GamepadConfig {
+    /// Cursor speed in game units per second with the stick fully
+    /// deflected.
+    pub sensitivity: f32,
+    /// Fraction of the stick range around the center that's ignored.
+    pub dead_zone: f32,
+    /// Exponent applied to the stick deflection. Higher values give
+    /// finer control near the center.
+    pub curve: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InterfaceConfig {
+    pub theme: Theme,
+    /// Multiplier for every size in the UI, including fonts.
+    pub scale: f32,
+    /// Whether players are marked with patterns, so that they can be
+    /// told apart without relying on color.
+    pub player_outlines: bool,
+    /// Whether each ball has a dot above it showing how laggy its
+    /// player's connection is.
+    pub latency_indicators: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// File to log to, in addition to stdout.
+    pub file: Option<PathBuf>,
+    /// Size in bytes a log file can grow to before it's rotated.
+    pub max_file_size: u64,
+    /// Number of rotated log files to keep around.
+    pub max_files: usize,
+    /// Whether a headless server logs to `server.log` in the config
+    /// directory if no other file is given.
+    pub server_log_to_file: bool,
+}
+
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "couldn't find a config directory")]
+    NoConfigDir,
+    #[fail(display = "reading file failed: {}", _0)]
+    Read(#[cause] io::Error),
+    #[fail(display = "writing file failed: {}", _0)]
+    Write(#[cause] io::Error),
+    #[fail(display = "parsing file failed: {}", _0)]
+    Deserialize(#[cause] toml::de::Error),
+    #[fail(display = "serializing failed: {}", _0)]
+    Serialize(#[cause] toml::ser::Error),
+}
+
+impl Default for GraphicsConfig {
+    fn default() -> GraphicsConfig {
+        GraphicsConfig {
+            window_width: 1024.0,
+            window_height: 768.0,
+            present_mode: None,
+            image_count: None,
+            fullscreen: false,
+            monitor: None,
+            framing: Framing::Fit,
+            ui_margin: 0.0,
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            interpolation_delay: 1.5,
+            interpolation_mode: InterpolationMode::Hermite,
+            server_address: String::new(),
+            rendezvous_address: String::new(),
+            host_address: "0.0.0.0:6666".to_owned(),
+            host_encrypted: false,
+            host_announced: false,
+            master_server: None,
+            bookmarks: Vec::new(),
+            admin_secret: None,
+        }
+    }
+}
+
+impl Default for GameplayConfig {
+    fn default() -> GameplayConfig {
+        GameplayConfig {
+            player_name: "Player".to_owned(),
+            color_hue: None,
+        }
+    }
+}
+
+impl Default for GamepadConfig {
+    fn default() -> GamepadConfig {
+        GamepadConfig {
+            sensitivity: 1.5,
+            dead_zone: 0.15,
+            curve: 2.0,
+        }
+    }
+}
+
+impl Default for AudioConfig {
+    fn default() -> AudioConfig {
+        AudioConfig {
+            volume: 0.5,
+        }
+    }
+}
+
+impl Default for InterfaceConfig {
+    fn default() -> InterfaceConfig {
+        InterfaceConfig {
+            theme: Theme::Dark,
+            scale: 1.0,
+            player_outlines: false,
+            latency_indicators: false,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            file: None,
+            max_file_size: 10 * 1024 * 1024,
+            max_files: 3,
+            server_log_to_file: true,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Gets the default log file for a headless server, if it should
+    /// log to one.
+    pub fn server_file(&self) -> Option<PathBuf> {
+        if self.server_log_to_file {
+            config_dir().map(|dir| dir.join(SERVER_LOG_FILE))
+        } else {
+            None
+        }
+    }
+}
+
+/// Gets the settings file for a headless server.
+pub fn server_settings_file() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(SERVER_SETTINGS_FILE))
+}
+
+/// Gets the file a headless server saves its match state to.
+pub fn server_state_file() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(SERVER_STATE_FILE))
+}
+
+/// Gets the directory that all persistent files are stored in.
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("ball-gfx-hal"))
+}
+
+/// Loads a TOML file from the config directory.
+pub fn load_file<T>(name: &str) -> Result<T, Error>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    let path = config_dir().ok_or(Error::NoConfigDir)?.join(name);
+    let contents = fs::read_to_string(path).map_err(Error::Read)?;
+    toml::from_str(&contents).map_err(Error::Deserialize)
+}
+
+/// Writes a TOML file to the config directory, creating the directory
+/// if needed.
+pub fn save_file<T: Serialize>(name: &str, value: &T) -> Result<(), Error> {
+    let dir = config_dir().ok_or(Error::NoConfigDir)?;
+    fs::create_dir_all(&dir).map_err(Error::Write)?;
+    let contents = toml::to_string(value).map_err(Error::Serialize)?;
+    fs::write(dir.join(name), contents).map_err(Error::Write)
+}
+
+/// Loads a TOML file from the config directory, falling back to the
+/// default if it doesn't exist or can't be read.
+pub fn load_or_default<T>(name: &str) -> T
+where
+    for<'de> T: Deserialize<'de> + Default,
+{
+    match load_file(name) {
+        Ok(value) => value,
+        Err(Error::Read(ref err)) if err.kind() == io::ErrorKind::NotFound => {
+            info!("no {} found, using defaults", name);
+            T::default()
+        },
+        Err(err) => {
+            warn!("failed to load {}: {}", name, err);
+            T::default()
+        },
+    }
+}
+
+impl Config {
+    /// Loads the config file, falling back to the defaults.
+    pub fn load() -> Config {
+        load_or_default(CONFIG_FILE)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        save_file(CONFIG_FILE, self)
+    }
+}
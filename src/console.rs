@@ -0,0 +1,369 @@
+use crate::game::{GameSettings, PlayerId};
+use crate::networking::server::{ServerCommand, ServerHandle, ServerStatus};
+use crossbeam::channel;
+use log::warn;
+use std::fmt::Write;
+use std::io::{self, BufRead};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long to wait for the server to answer a request.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+const HELP: &str = "\
+commands:
+  list                      list connected players
+  kick <id>                 disconnect a player
+  ban <id>                  disconnect a player and ban their address
+  unban <address>           let a banned address connect again
+  settings                  show the game settings
+  settings <field> <value>  change a game setting
+  reload                    apply the server settings file again
+  say <message>             show a message to every player
+  start                     start a round right away
+  reset                     reset every player's wins
+  pause                     freeze the game
+  resume                    unfreeze the game
+  shutdown                  stop the server";
+
+/// A command for a headless server, typed in as a line of text.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Help,
+    List,
+    Kick(PlayerId),
+    Ban(PlayerId),
+    Unban(IpAddr),
+    ShowSettings,
+    SetSetting {
+        field: String,
+        value: String,
+    },
+    Say(String),
+    Reload,
+    StartRound,
+    ResetScores,
+    Pause,
+    Resume,
+    Shutdown,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Command, String> {
+        let s = s.trim();
+        let (name, args) = match s.find(char::is_whitespace) {
+            Some(i) => (&s[..i], s[i..].trim()),
+            None => (s, ""),
+        };
+        let mut words = args.split_whitespace();
+        let command = match name {
+            "help" => Command::Help,
+            "list" => Command::List,
+            "kick" => {
+                let id = args.parse().map_err(|_| {
+                    format!("expected a player id, got {:?}", args)
+                })?;
+                Command::Kick(id)
+            },
+            "ban" => {
+                let id = args.parse().map_err(|_| {
+                    format!("expected a player id, got {:?}", args)
+                })?;
+                Command::Ban(id)
+            },
+            "unban" => {
+                let ip = args.parse().map_err(|_| {
+                    format!("expected an address, got {:?}", args)
+                })?;
+                Command::Unban(ip)
+            },
+            "settings" => {
+                match (words.next(), words.next(), words.next()) {
+                    (None, ..) => Command::ShowSettings,
+                    (Some(field), Some(value), None) => {
+                        Command::SetSetting {
+                            field: field.to_owned(),
+                            value: value.to_owned(),
+                        }
+                    },
+                    _ => {
+                        return Err("usage: settings <field> <value>".to_owned())
+                    },
+                }
+            },
+            "say" if !args.is_empty() => Command::Say(args.to_owned()),
+            "say" => return Err("usage: say <message>".to_owned()),
+            "reload" => Command::Reload,
+            "start" => Command::StartRound,
+            "reset" => Command::ResetScores,
+            "pause" => Command::Pause,
+            "resume" => Command::Resume,
+            "shutdown" => Command::Shutdown,
+            _ => return Err(format!("unknown command {:?}, try help", name)),
+        };
+        Ok(command)
+    }
+}
+
+/// Runs a command against a server, returning what to print back.
+pub fn execute(
+    server: &ServerHandle,
+    command: Command,
+) -> Result<String, String> {
+    match command {
+        Command::Help => Ok(HELP.to_owned()),
+        Command::List => Ok(format_clients(&status(server)?)),
+        Command::Kick(id) => {
+            server.command(ServerCommand::Kick(id));
+            Ok(format!("kicked player {}", id))
+        },
+        Command::Ban(id) => {
+            server.command(ServerCommand::Ban(id));
+            Ok(format!("banned player {}", id))
+        },
+        Command::Unban(ip) => {
+            server.command(ServerCommand::Unban(ip));
+            Ok(format!("unbanned {}", ip))
+        },
+        Command::ShowSettings => Ok(format_settings(&status(server)?.settings)),
+        Command::SetSetting {
+            field,
+            value,
+        } => {
+            let mut settings = status(server)?.settings;
+            set_setting(&mut settings, &field, &value)?;
+            server.command(ServerCommand::SetSettings(settings));
+            Ok(format!("set {} to {}", field, value))
+        },
+        Command::Say(message) => {
+            server.command(ServerCommand::Say(message));
+            Ok(String::new())
+        },
+        Command::Reload => {
+            let (reply_tx, reply_rx) = channel::bounded(1);
+            server.command(ServerCommand::Reload(reply_tx));
+            match reply_rx.recv_timeout(STATUS_TIMEOUT) {
+                Ok(Ok(())) => Ok("reloaded server settings".to_owned()),
+                Ok(Err(err)) => Err(err),
+                Err(_) => Err("server didn't respond".to_owned()),
+            }
+        },
+        Command::StartRound => {
+            server.command(ServerCommand::StartRound);
+            Ok("starting round".to_owned())
+        },
+        Command::ResetScores => {
+            server.command(ServerCommand::ResetScores);
+            Ok("reset scores".to_owned())
+        },
+        Command::Pause => {
+            server.command(ServerCommand::SetPaused(true));
+            Ok("paused".to_owned())
+        },
+        Command::Resume => {
+            server.command(ServerCommand::SetPaused(false));
+            Ok("resumed".to_owned())
+        },
+        Command::Shutdown => {
+            server.shutdown();
+            Ok("shutting down".to_owned())
+        },
+    }
+}
+
+/// Reads commands from stdin on a new thread, until stdin is closed.
+pub fn spawn(server: Arc<ServerHandle>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    warn!("failed to read console input: {}", err);
+                    break;
+                },
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.parse().and_then(|command| execute(&server, command)) {
+                Ok(ref output) if output.is_empty() => (),
+                Ok(output) => println!("{}", output),
+                Err(err) => println!("error: {}", err),
+            }
+        }
+    })
+}
+
+/// Asks the server for its current status.
+fn status(server: &ServerHandle) -> Result<ServerStatus, String> {
+    let (status_tx, status_rx) = channel::bounded(1);
+    server.command(ServerCommand::Status(status_tx));
+    status_rx
+        .recv_timeout(STATUS_TIMEOUT)
+        .map_err(|_| "server didn't respond".to_owned())
+}
+
+fn format_clients(status: &ServerStatus) -> String {
+    if status.clients.is_empty() {
+        return "no players connected".to_owned();
+    }
+    let mut output = format!(
+        "{:>5}  {:<24}  {:<21}  {:>4}  {:<5}  {:>6}",
+        "id", "name", "address", "wins", "alive", "ping"
+    );
+    for client in &status.clients {
+        let ping = match client.rtt {
+            Some(rtt) => format!("{:.0} ms", rtt * 1000.0),
+            None => "?".to_owned(),
+        };
+        write!(
+            output,
+            "\n{:>5}  {:<24}  {:<21}  {:>4}  {:<5}  {:>6}",
+            client.player,
+            client.name,
+            client.addr.to_string(),
+            client.wins,
+            client.alive,
+            ping
+        )
+        .unwrap();
+    }
+    output
+}
+
+fn format_settings(settings: &GameSettings) -> String {
+    let respawn_delay = match settings.respawn_delay {
+        Some(delay) => delay.to_string(),
+        None => "off".to_owned(),
+    };
+    let round_time_limit = match settings.round_time_limit {
+        Some(limit) => limit.to_string(),
+        None => "off".to_owned(),
+    };
+    let afk_timeout = match settings.afk_timeout {
+        Some(timeout) => timeout.to_string(),
+        None => "off".to_owned(),
+    };
+    let fields = [
+        ("ball_radius", settings.ball_radius.to_string()),
+        ("cursor_radius", settings.cursor_radius.to_string()),
+        ("spring_constant", settings.spring_constant.to_string()),
+        ("ball_start_distance", settings.ball_start_distance.to_string()),
+        ("ball_start_speed", settings.ball_start_speed.to_string()),
+        ("bounds_radius", settings.bounds_radius.to_string()),
+        ("kill_own_cursor", settings.kill_own_cursor.to_string()),
+        ("damping", settings.damping.to_string()),
+        ("restitution", settings.restitution.to_string()),
+        ("gravity", settings.gravity.to_string()),
+        ("vortex", settings.vortex.to_string()),
+        ("respawn_delay", respawn_delay),
+        ("round_time_limit", round_time_limit),
+        ("afk_timeout", afk_timeout),
+    ];
+    fields
+        .iter()
+        .map(|(field, value)| format!("{} {}", field, value))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Changes one of the game settings, by the same name and in the same
+/// format as `format_settings` shows it.
+fn set_setting(
+    settings: &mut GameSettings,
+    field: &str,
+    value: &str,
+) -> Result<(), String> {
+    let float = || {
+        value
+            .parse::<f32>()
+            .map_err(|_| format!("expected a number, got {:?}", value))
+    };
+    match field {
+        "ball_radius" => settings.ball_radius = float()?,
+        "cursor_radius" => settings.cursor_radius = float()?,
+        "spring_constant" => settings.spring_constant = float()?,
+        "ball_start_distance" => settings.ball_start_distance = float()?,
+        "ball_start_speed" => settings.ball_start_speed = float()?,
+        "bounds_radius" => settings.bounds_radius = float()?,
+        "kill_own_cursor" => {
+            settings.kill_own_cursor = value.parse().map_err(|_| {
+                format!("expected true or false, got {:?}", value)
+            })?;
+        },
+        "damping" => settings.damping = float()?,
+        "restitution" => settings.restitution = float()?,
+        "gravity" => settings.gravity = float()?,
+        "vortex" => settings.vortex = float()?,
+        "respawn_delay" => {
+            settings.respawn_delay = match value {
+                "off" => None,
+                _ => Some(float()?),
+            };
+        },
+        "round_time_limit" => {
+            settings.round_time_limit = match value {
+                "off" => None,
+                _ => Some(float()?),
+            };
+        },
+        "afk_timeout" => {
+            settings.afk_timeout = match value {
+                "off" => None,
+                _ => Some(float()?),
+            };
+        },
+        _ => return Err(format!("unknown setting {:?}", field)),
+    }
+    Ok(())
+}
+
+#[test]
+fn parse_commands() {
+    assert_eq!("list".parse(), Ok(Command::List));
+    assert_eq!(" kick 3 ".parse(), Ok(Command::Kick(3)));
+    assert!("kick bob".parse::<Command>().is_err());
+    assert_eq!(
+        "unban 10.0.0.1".parse(),
+        Ok(Command::Unban("10.0.0.1".parse().unwrap()))
+    );
+    assert_eq!("pause".parse(), Ok(Command::Pause));
+    assert_eq!(
+        "say  hello   there".parse(),
+        Ok(Command::Say("hello   there".to_owned()))
+    );
+    assert_eq!(
+        "settings respawn_delay off".parse(),
+        Ok(Command::SetSetting {
+            field: "respawn_delay".to_owned(),
+            value: "off".to_owned(),
+        })
+    );
+    assert!("settings respawn_delay".parse::<Command>().is_err());
+    assert!("dance".parse::<Command>().is_err());
+}
+
+#[test]
+fn settings_round_trip() {
+    let mut settings = GameSettings::default();
+    set_setting(&mut settings, "damping", "0.5").unwrap();
+    set_setting(&mut settings, "respawn_delay", "2").unwrap();
+    set_setting(&mut settings, "afk_timeout", "off").unwrap();
+    set_setting(&mut settings, "gravity", "1.5").unwrap();
+    assert!(set_setting(&mut settings, "damping", "lots").is_err());
+    assert!(set_setting(&mut settings, "friction", "1").is_err());
+
+    let mut parsed = GameSettings::default();
+    for line in format_settings(&settings).lines() {
+        let mut words = line.split(' ');
+        let (field, value) = (words.next().unwrap(), words.next().unwrap());
+        set_setting(&mut parsed, field, value).unwrap();
+    }
+    assert_eq!(parsed, settings);
+}
@@ -1,15 +1,67 @@
+use crate::config;
+use crate::game::{client::Game, GetPlayer, InterpolationMode, PlayerState};
 use crate::graphics::{self, renderdoc::RenderDoc, Graphics};
 use crate::logger;
+use crate::networking::{
+    self,
+    client::ConnectionState,
+    RTT_HISTOGRAM_BUCKETS,
+    RTT_HISTOGRAM_BUCKET_WIDTH,
+};
+use crate::profile::{self, SECTIONS, SECTION_COUNT};
 use crate::ui;
 use crossbeam::channel::{self, Receiver, Sender};
 use gfx_hal::{Backend, PresentMode};
-use imgui::{im_str, ImString, Ui};
+use imgui::{im_str, sys, ImGui, ImGuiCond, ImString, Ui};
+use imgui_winit::ImGuiWinit;
+use log::{info, warn};
+use nalgebra::Point2;
 use smallvec::SmallVec;
-use std::time::Duration;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::mem::ManuallyDrop;
+use std::path::PathBuf;
+use std::ptr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use winit::{
+    dpi::LogicalSize,
+    CreationError,
+    EventsLoop,
+    Window,
+    WindowBuilder,
+    WindowId,
+};
 
 const NETWORK_HISTORY_LENGTH: usize = 256;
 const FRAME_TIME_HISTORY_LENGTH: usize = 256;
 
+/// Number of network samples that packet loss is measured over.
+///
+/// Loss is only detected once later packets are acked, so measuring
+/// over a single sample would compare against the wrong packets.
+const PACKET_LOSS_WINDOW: usize = 10;
+
+/// Height in pixels of the frame breakdown bar.
+const SECTION_BAR_HEIGHT: f32 = 12.0;
+
+/// Color of each profiling section, in the same order as `SECTIONS`.
+const SECTION_COLORS: [[f32; 4]; SECTION_COUNT] = [
+    [0.9, 0.6, 0.2, 1.0],
+    [0.3, 0.8, 0.3, 1.0],
+    [0.3, 0.6, 0.9, 1.0],
+    [0.8, 0.4, 0.8, 1.0],
+    [0.9, 0.3, 0.3, 1.0],
+];
+
+/// Range of swapchain image counts that can be picked.
+const IMAGE_COUNT_RANGE: (i32, i32) = (2, 4);
+
+/// Refresh rate that presents are checked against until it's changed
+/// in the debug window, in Hz.
+const DEFAULT_REFRESH_RATE: f32 = 60.0;
+
 pub const NETWORK_STATS_RATE: Duration = Duration::from_millis(100);
 
 #[derive(Default, Debug, Copy, Clone)]
@@ -20,10 +72,28 @@ pub struct NetworkStats {
     pub bytes_in: u32,
     /// Number of packets sent since the last recorded stats.
     pub packets_sent: u16,
-    /// Number of packets marked lost since the last recorded stats.
-    pub packets_lost: u16,
+    /// Number of packets received since the last recorded stats.
+    pub packets_received: u16,
+    /// Number of sent packets that the remote end's acks showed were
+    /// lost since the last recorded stats.
+    pub packets_lost_out: u16,
+    /// Number of gaps in the sequence numbers of received packets
+    /// since the last recorded stats.
+    ///
+    /// Packets that arrive out of order are counted as lost, since
+    /// they're usually too late to be useful anyways.
+    pub packets_lost_in: u16,
     /// Estimated round trip time.
     pub rtt: f32,
+    /// Mean deviation of recent round trip times.
+    pub jitter: f32,
+    /// Median of recent round trip times.
+    pub rtt_p50: f32,
+    /// 95th percentile of recent round trip times.
+    pub rtt_p95: f32,
+    /// Number of recent round trip times in each bucket of
+    /// `RTT_HISTOGRAM_BUCKET_WIDTH`.
+    pub rtt_histogram: [f32; RTT_HISTOGRAM_BUCKETS],
 }
 
 /// State and options related to the debug window.
@@ -41,14 +111,52 @@ pub struct DebugState {
     /// Increasing this will make things smoother in the presence of
     /// packet loss or jitter, but will increase visual latency.
     pub interpolation_delay: f32,
+    /// Method used to interpolate balls between snapshots.
+    pub interpolation_mode: InterpolationMode,
+    /// Snapshot in the game's history to freeze playback on, or
+    /// `None` to play normally.
+    pub scrub: Option<usize>,
     pub network_tx: Sender<NetworkStats>,
     pub show_window: bool,
+    /// Whether the debug window is popped out into its own OS window,
+    /// instead of being drawn over the game.
+    pub detached: bool,
+    /// Time that metrics are timestamped relative to.
+    start: Instant,
     network_rx: Receiver<NetworkStats>,
     bandwidth_in_history: [f32; NETWORK_HISTORY_LENGTH],
     bandwidth_out_history: [f32; NETWORK_HISTORY_LENGTH],
-    packet_loss_history: [f32; NETWORK_HISTORY_LENGTH],
+    packet_loss_out_history: [f32; NETWORK_HISTORY_LENGTH],
+    packet_loss_in_history: [f32; NETWORK_HISTORY_LENGTH],
+    /// The last `PACKET_LOSS_WINDOW` network samples.
+    loss_window: VecDeque<NetworkStats>,
     rtt_history: [f32; NETWORK_HISTORY_LENGTH],
+    jitter_history: [f32; NETWORK_HISTORY_LENGTH],
+    /// Latest network stats received, for the values that aren't
+    /// plotted over time.
+    latest_network: NetworkStats,
+    /// Seconds since `start` that each network sample was sent.
+    network_timestamps: [f32; NETWORK_HISTORY_LENGTH],
+    /// Number of network samples recorded so far, up to the history
+    /// length.
+    network_samples: usize,
     frame_time_history: [f32; FRAME_TIME_HISTORY_LENGTH],
+    /// Seconds since `start` that each frame time was logged.
+    frame_timestamps: [f32; FRAME_TIME_HISTORY_LENGTH],
+    /// Number of frame times recorded so far, up to the history
+    /// length.
+    frame_samples: usize,
+    /// Milliseconds spent in each profiling section per frame.
+    section_history: [[f32; FRAME_TIME_HISTORY_LENGTH]; SECTION_COUNT],
+    /// Refresh rate of the display in Hz, which presents are checked
+    /// against to find missed vblanks.
+    pub refresh_rate: f32,
+    /// Milliseconds between each frame being presented.
+    present_history: [f32; FRAME_TIME_HISTORY_LENGTH],
+    /// Number of presents that missed at least one vblank.
+    stutters: u32,
+    /// Total vblanks that passed without a new frame.
+    missed_vblanks: u32,
 }
 
 impl Default for DebugState {
@@ -57,73 +165,272 @@ impl Default for DebugState {
         DebugState {
             draw_latest_snapshot: false,
             interpolation_delay: 1.5,
+            interpolation_mode: InterpolationMode::Hermite,
+            scrub: None,
             network_tx,
             show_window: false,
+            detached: false,
+            start: Instant::now(),
             network_rx,
             bandwidth_in_history: [0.0; NETWORK_HISTORY_LENGTH],
             bandwidth_out_history: [0.0; NETWORK_HISTORY_LENGTH],
-            packet_loss_history: [0.0; NETWORK_HISTORY_LENGTH],
+            packet_loss_out_history: [0.0; NETWORK_HISTORY_LENGTH],
+            packet_loss_in_history: [0.0; NETWORK_HISTORY_LENGTH],
+            loss_window: VecDeque::with_capacity(PACKET_LOSS_WINDOW),
             rtt_history: [0.0; NETWORK_HISTORY_LENGTH],
+            jitter_history: [0.0; NETWORK_HISTORY_LENGTH],
+            latest_network: NetworkStats::default(),
+            network_timestamps: [0.0; NETWORK_HISTORY_LENGTH],
+            network_samples: 0,
             frame_time_history: [0.0; FRAME_TIME_HISTORY_LENGTH],
+            frame_timestamps: [0.0; FRAME_TIME_HISTORY_LENGTH],
+            frame_samples: 0,
+            section_history: [[0.0; FRAME_TIME_HISTORY_LENGTH]; SECTION_COUNT],
+            refresh_rate: DEFAULT_REFRESH_RATE,
+            present_history: [0.0; FRAME_TIME_HISTORY_LENGTH],
+            stutters: 0,
+            missed_vblanks: 0,
         }
     }
 }
 
 impl DebugState {
+    /// Logs any network statistics received since the last call.
+    ///
+    /// This is called by `ui`, but can also be called directly to
+    /// record statistics without a window.
+    pub fn update_network(&mut self) {
+        let size = self.network_rx.len();
+        if size == 0 {
+            return;
+        }
+        // Copy elements to make room for new ones.
+        self.bandwidth_in_history.copy_within(size.., 0);
+        self.bandwidth_out_history.copy_within(size.., 0);
+        self.packet_loss_out_history.copy_within(size.., 0);
+        self.packet_loss_in_history.copy_within(size.., 0);
+        self.rtt_history.copy_within(size.., 0);
+        self.jitter_history.copy_within(size.., 0);
+        self.network_timestamps.copy_within(size.., 0);
+        let start = NETWORK_HISTORY_LENGTH - size;
+        let now = self.start.elapsed().as_secs_f32();
+        // Only take as many as there's room for, in case more came
+        // in since checking the length.
+        for (i, stats) in self.network_rx.try_iter().take(size).enumerate() {
+            let bandwidth_in =
+                stats.bytes_in as f32 / NETWORK_STATS_RATE.as_secs_f32();
+            let bandwidth_out =
+                stats.bytes_out as f32 / NETWORK_STATS_RATE.as_secs_f32();
+            if self.loss_window.len() == PACKET_LOSS_WINDOW {
+                self.loss_window.pop_front();
+            }
+            self.loss_window.push_back(stats);
+            let (loss_out, loss_in) = self.packet_loss();
+            // Convert to KB
+            self.bandwidth_in_history[start + i] = bandwidth_in / 1000.0;
+            self.bandwidth_out_history[start + i] = bandwidth_out / 1000.0;
+            self.packet_loss_out_history[start + i] = loss_out * 100.0;
+            self.packet_loss_in_history[start + i] = loss_in * 100.0;
+            self.rtt_history[start + i] = stats.rtt * 1000.0;
+            self.jitter_history[start + i] = stats.jitter * 1000.0;
+            self.latest_network = stats;
+            // Stats are sent at a fixed rate, so older ones in the
+            // same batch were sent that much earlier.
+            self.network_timestamps[start + i] =
+                now - (size - 1 - i) as f32 * NETWORK_STATS_RATE.as_secs_f32();
+        }
+        self.network_samples =
+            (self.network_samples + size).min(NETWORK_HISTORY_LENGTH);
+    }
+
+    /// Gets the fraction of sent and received packets that were lost
+    /// over the loss window.
+    fn packet_loss(&self) -> (f32, f32) {
+        let (mut sent, mut received, mut lost_out, mut lost_in) =
+            (0.0, 0.0, 0.0, 0.0);
+        for stats in &self.loss_window {
+            sent += f32::from(stats.packets_sent);
+            received += f32::from(stats.packets_received);
+            lost_out += f32::from(stats.packets_lost_out);
+            lost_in += f32::from(stats.packets_lost_in);
+        }
+        let ratio = |lost: f32, total: f32| {
+            if total > 0.0 {
+                (lost / total).min(1.0)
+            } else {
+                0.0
+            }
+        };
+        (ratio(lost_out, sent), ratio(lost_in, received + lost_in))
+    }
+
+    /// Writes the recorded network statistics and frame times as CSV,
+    /// with one `time,metric,value` row per measurement.
+    ///
+    /// Times are in seconds since the debug state was created.
+    pub fn write_metrics<W: Write>(&self, mut out: W) -> Result<(), io::Error> {
+        writeln!(out, "time,metric,value")?;
+        let network = NETWORK_HISTORY_LENGTH - self.network_samples..;
+        let metrics = [
+            ("bandwidth_in_kbps", &self.bandwidth_in_history),
+            ("bandwidth_out_kbps", &self.bandwidth_out_history),
+            ("packet_loss_out_percent", &self.packet_loss_out_history),
+            ("packet_loss_in_percent", &self.packet_loss_in_history),
+            ("rtt_ms", &self.rtt_history),
+            ("jitter_ms", &self.jitter_history),
+        ];
+        for (name, history) in metrics.iter() {
+            let samples = self.network_timestamps[network.clone()]
+                .iter()
+                .zip(&history[network.clone()]);
+            for (time, value) in samples {
+                writeln!(out, "{:.3},{},{}", time, name, value)?;
+            }
+        }
+        let frames = FRAME_TIME_HISTORY_LENGTH - self.frame_samples..;
+        let samples = self.frame_timestamps[frames.clone()]
+            .iter()
+            .zip(&self.frame_time_history[frames]);
+        for (time, value) in samples {
+            writeln!(out, "{:.3},frame_time_ms,{}", time, value)?;
+        }
+        out.flush()
+    }
+
+    /// Writes the recorded metrics to a new file in the config
+    /// directory, returning its path.
+    pub fn dump_metrics(&self) -> Result<PathBuf, io::Error> {
+        let dir = config::config_dir().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no config directory")
+        })?;
+        fs::create_dir_all(&dir)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+        let path = dir.join(format!("metrics-{}.csv", timestamp));
+        self.write_metrics(BufWriter::new(File::create(&path)?))?;
+        Ok(path)
+    }
+
     /// Draws the debug window into imgui.
     pub fn ui<'a, B: Backend>(
         &mut self,
         ui: &Ui<'a>,
         graphics: &mut Graphics<B>,
         renderdoc: &mut RenderDoc,
+        game: Option<(&Game, Point2<f32>)>,
+        connection: Option<ConnectionState>,
         frame_time: f32,
     ) {
         // Convert frame_time to ms.
         let frame_time = frame_time * 1000.0;
 
-        // Log network statistics.
-        let size = self.network_rx.len();
-        if size > 0 {
-            // Copy elements to make room for new ones.
-            self.bandwidth_in_history.copy_within(size.., 0);
-            self.bandwidth_out_history.copy_within(size.., 0);
-            self.packet_loss_history.copy_within(size.., 0);
-            self.rtt_history.copy_within(size.., 0);
-            let start = NETWORK_HISTORY_LENGTH - size;
-            for (i, stats) in self.network_rx.try_iter().enumerate() {
-                let bandwidth_in = stats.bytes_in as f32 /
-                    NETWORK_STATS_RATE.as_secs_f32();
-                let bandwidth_out = stats.bytes_out as f32 /
-                    NETWORK_STATS_RATE.as_secs_f32();
-                let packet_loss = f32::from(stats.packets_lost) /
-                    f32::from(stats.packets_sent);
-                // Convert to KB
-                self.bandwidth_in_history[start + i] = bandwidth_in / 1000.0;
-                self.bandwidth_out_history[start + i] = bandwidth_out / 1000.0;
-                self.packet_loss_history[start + i] = packet_loss * 100.0;
-                self.rtt_history[start + i] = stats.rtt * 1000.0;
-            }
-        }
+        self.update_network();
 
         // Log the frame time.
         self.frame_time_history.copy_within(1.., 0);
         *self.frame_time_history.last_mut().unwrap() = frame_time;
+        self.frame_timestamps.copy_within(1.., 0);
+        *self.frame_timestamps.last_mut().unwrap() =
+            self.start.elapsed().as_secs_f32();
+        self.frame_samples =
+            (self.frame_samples + 1).min(FRAME_TIME_HISTORY_LENGTH);
 
-        if !self.show_window {
+        // Log the time spent in each section since the last frame,
+        // converted to ms.
+        let mut sections = profile::take_frame();
+        for (history, time) in
+            self.section_history.iter_mut().zip(sections.iter_mut())
+        {
+            *time *= 1000.0;
+            history.copy_within(1.., 0);
+            *history.last_mut().unwrap() = *time;
+        }
+
+        if let Some(interval) = graphics.take_present_interval() {
+            self.present_history.copy_within(1.., 0);
+            *self.present_history.last_mut().unwrap() = interval * 1000.0;
+            let missed = missed_vblanks(interval, self.refresh_rate);
+            if missed > 0 {
+                self.stutters += 1;
+                self.missed_vblanks += missed;
+            }
+        }
+
+        // Playback can only be unfrozen from the window, so closing it
+        // or leaving the game does that too.
+        if !self.show_window || game.is_none() {
+            self.scrub = None;
+        }
+
+        if !self.show_window || self.detached {
             return;
         }
 
         ui.window(im_str!("Debug")).build(|| {
-            ui.tree_node(im_str!("Networking")).build(|| {
-                let bandwidth_in = *self.bandwidth_in_history.last().unwrap();
-                let bandwidth_out = *self.bandwidth_out_history.last().unwrap();
-                let rtt = *self.rtt_history.last().unwrap();
-                let packet_loss = *self.packet_loss_history.last().unwrap();
-
-                ui.plot_lines(
-                    im_str!("Bandwidth in"),
-                    &self.bandwidth_in_history,
-                )
+            self.window_contents(ui, graphics, renderdoc, game, connection);
+        });
+    }
+
+    /// Draws the debug window over the whole of a detached window's
+    /// imgui frame, showing the stats recorded by the last call to
+    /// `ui`.
+    pub fn detached_ui<'a, B: Backend>(
+        &mut self,
+        ui: &Ui<'a>,
+        graphics: &mut Graphics<B>,
+        renderdoc: &mut RenderDoc,
+        game: Option<(&Game, Point2<f32>)>,
+        connection: Option<ConnectionState>,
+    ) {
+        let (width, height) = ui.frame_size().logical_size;
+        ui.window(im_str!("Debug"))
+            .position((0.0, 0.0), ImGuiCond::Always)
+            .size((width as f32, height as f32), ImGuiCond::Always)
+            .title_bar(false)
+            .resizable(false)
+            .movable(false)
+            .collapsible(false)
+            .build(|| {
+                self.window_contents(ui, graphics, renderdoc, game, connection);
+            });
+    }
+
+    fn window_contents<'a, B: Backend>(
+        &mut self,
+        ui: &Ui<'a>,
+        graphics: &mut Graphics<B>,
+        renderdoc: &mut RenderDoc,
+        game: Option<(&Game, Point2<f32>)>,
+        connection: Option<ConnectionState>,
+    ) {
+        let frame_time = *self.frame_time_history.last().unwrap();
+        let mut sections = [0.0; SECTION_COUNT];
+        for (time, history) in sections.iter_mut().zip(&self.section_history) {
+            *time = *history.last().unwrap();
+        }
+
+        let label = if self.detached {
+            im_str!("Pop in")
+        } else {
+            im_str!("Pop out")
+        };
+        if ui.small_button(label) {
+            self.detached = !self.detached;
+        }
+
+        ui.tree_node(im_str!("Networking")).build(|| {
+            ui.text(format!("Connection: {}", connection_text(connection)));
+
+            let bandwidth_in = *self.bandwidth_in_history.last().unwrap();
+            let bandwidth_out = *self.bandwidth_out_history.last().unwrap();
+            let rtt = *self.rtt_history.last().unwrap();
+            let jitter = *self.jitter_history.last().unwrap();
+            let loss_out = *self.packet_loss_out_history.last().unwrap();
+            let loss_in = *self.packet_loss_in_history.last().unwrap();
+
+            ui.plot_lines(im_str!("Bandwidth in"), &self.bandwidth_in_history)
                 .scale_max(8.0)
                 .scale_min(0.0)
                 .overlay_text(&ImString::new(format!(
@@ -132,87 +439,480 @@ impl DebugState {
                 )))
                 .build();
 
-                ui.plot_lines(
-                    im_str!("Bandwidth out"),
-                    &self.bandwidth_out_history,
-                )
-                .scale_max(8.0)
+            ui.plot_lines(
+                im_str!("Bandwidth out"),
+                &self.bandwidth_out_history,
+            )
+            .scale_max(8.0)
+            .scale_min(0.0)
+            .overlay_text(&ImString::new(format!("{:.2} KB/s", bandwidth_out)))
+            .build();
+
+            ui.plot_lines(im_str!("RTT"), &self.rtt_history)
+                .scale_max(100.0)
                 .scale_min(0.0)
-                .overlay_text(&ImString::new(format!(
-                    "{:.2} KB/s",
-                    bandwidth_out
-                )))
+                .overlay_text(&ImString::new(format!("{:.2} ms", rtt)))
                 .build();
 
-                ui.plot_lines(im_str!("RTT"), &self.rtt_history)
-                    .scale_max(100.0)
-                    .scale_min(0.0)
-                    .overlay_text(&ImString::new(format!("{:.2} ms", rtt)))
-                    .build();
+            ui.plot_lines(im_str!("Jitter"), &self.jitter_history)
+                .scale_max(20.0)
+                .scale_min(0.0)
+                .overlay_text(&ImString::new(format!("{:.2} ms", jitter)))
+                .build();
 
-                ui.plot_lines(
-                    im_str!("Packet loss"),
-                    &self.packet_loss_history,
-                )
-                .scale_max(100.0)
+            let latest = &self.latest_network;
+            ui.plot_histogram(
+                im_str!("RTT distribution"),
+                &latest.rtt_histogram,
+            )
+            .scale_min(0.0)
+            .overlay_text(&ImString::new(format!(
+                "p50 {:.1} ms, p95 {:.1} ms (0-{:.0} ms)",
+                latest.rtt_p50 * 1000.0,
+                latest.rtt_p95 * 1000.0,
+                RTT_HISTOGRAM_BUCKETS as f32 *
+                    RTT_HISTOGRAM_BUCKET_WIDTH *
+                    1000.0
+            )))
+            .build();
+
+            ui.plot_lines(
+                im_str!("Packet loss out"),
+                &self.packet_loss_out_history,
+            )
+            .scale_max(100.0)
+            .scale_min(0.0)
+            .overlay_text(&ImString::new(format!("{:.0} %", loss_out)))
+            .build();
+
+            ui.plot_lines(
+                im_str!("Packet loss in"),
+                &self.packet_loss_in_history,
+            )
+            .scale_max(100.0)
+            .scale_min(0.0)
+            .overlay_text(&ImString::new(format!("{:.0} %", loss_in)))
+            .build();
+
+            ui.checkbox(
+                im_str!("Draw latest snapshot"),
+                &mut self.draw_latest_snapshot,
+            );
+
+            ui.input_float(
+                im_str!("Interpolation delay"),
+                &mut self.interpolation_delay,
+            )
+            .build();
+
+            ui::enum_combo(
+                &ui,
+                im_str!("Interpolation mode"),
+                &mut self.interpolation_mode,
+                &[im_str!("linear"), im_str!("hermite")],
+                &[InterpolationMode::Linear, InterpolationMode::Hermite],
+                2,
+            );
+        });
+
+        ui.tree_node(im_str!("Graphics")).build(|| {
+            ui.plot_lines(im_str!("Frame time"), &self.frame_time_history)
+                .scale_max(1000.0 / 20.0)
                 .scale_min(0.0)
-                .overlay_text(&ImString::new(format!("{:.0} %", packet_loss)))
+                .overlay_text(&ImString::new(format!("{:.2} ms", frame_time)))
                 .build();
 
-                ui.checkbox(
-                    im_str!("Draw latest snapshot"),
-                    &mut self.draw_latest_snapshot,
-                );
+            let mut sorted = self.frame_time_history
+                [FRAME_TIME_HISTORY_LENGTH - self.frame_samples..]
+                .to_vec();
+            sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            ui.text(format!(
+                "Frame time p95 {:.2} ms, p99 {:.2} ms",
+                networking::percentile(&sorted, 0.95).unwrap_or(0.0),
+                networking::percentile(&sorted, 0.99).unwrap_or(0.0),
+            ));
 
-                ui.input_float(
-                    im_str!("Interpolation delay"),
-                    &mut self.interpolation_delay,
-                )
+            let present_interval = *self.present_history.last().unwrap();
+            ui.plot_lines(im_str!("Present interval"), &self.present_history)
+                .scale_max(1000.0 / 20.0)
+                .scale_min(0.0)
+                .overlay_text(&ImString::new(format!(
+                    "{:.2} ms",
+                    present_interval
+                )))
                 .build();
-            });
+            ui.text(format!(
+                "Stutters: {} ({} missed vblanks)",
+                self.stutters, self.missed_vblanks
+            ));
+            ui.same_line(0.0);
+            if ui.small_button(im_str!("Reset")) {
+                self.stutters = 0;
+                self.missed_vblanks = 0;
+            }
+            ui.input_float(
+                im_str!("Refresh rate (Hz)"),
+                &mut self.refresh_rate,
+            )
+            .build();
 
-            ui.tree_node(im_str!("Graphics")).build(|| {
-                ui.plot_lines(im_str!("Frame time"), &self.frame_time_history)
-                    .scale_max(1000.0 / 20.0)
-                    .scale_min(0.0)
-                    .overlay_text(&ImString::new(format!(
-                        "{:.2} ms",
-                        frame_time
-                    )))
-                    .build();
-
-                let supported = graphics.supported_present_modes();
-                let labels = supported
-                    .iter()
-                    .map(|present_mode| {
-                        match present_mode {
-                            PresentMode::Immediate => im_str!("immediate"),
-                            PresentMode::Relaxed => im_str!("relaxed"),
-                            PresentMode::Fifo => im_str!("fifo"),
-                            PresentMode::Mailbox => im_str!("mailbox"),
-                        }
-                    })
-                    .collect::<SmallVec<[_; 4]>>();
-                let mut present_mode = graphics.present_mode();
-                if ui::enum_combo(
-                    &ui,
-                    im_str!("Present mode"),
-                    &mut present_mode,
-                    &labels,
-                    supported,
-                    4,
-                ) {
-                    graphics.set_present_mode(present_mode);
-                }
+            ui.tree_node(im_str!("Frame breakdown")).build(|| {
+                section_bar(ui, &sections);
 
-                if ui.small_button(im_str!("Capture frame")) {
-                    graphics::renderdoc::trigger_capture(renderdoc, 1);
+                for ((section, history), time) in
+                    SECTIONS.iter().zip(&self.section_history).zip(&sections)
+                {
+                    ui.plot_lines(&ImString::new(section.name()), history)
+                        .scale_max(1000.0 / 60.0)
+                        .scale_min(0.0)
+                        .overlay_text(&ImString::new(format!("{:.2} ms", time)))
+                        .build();
                 }
             });
 
-            ui.tree_node(im_str!("Logger")).build(|| {
-                logger::LOGGER.ui(&ui);
-            });
+            let supported = graphics.supported_present_modes();
+            let labels = supported
+                .iter()
+                .map(|present_mode| {
+                    match present_mode {
+                        PresentMode::Immediate => im_str!("immediate"),
+                        PresentMode::Relaxed => im_str!("relaxed"),
+                        PresentMode::Fifo => im_str!("fifo"),
+                        PresentMode::Mailbox => im_str!("mailbox"),
+                    }
+                })
+                .collect::<SmallVec<[_; 4]>>();
+            let mut present_mode = graphics.present_mode();
+            if ui::enum_combo(
+                &ui,
+                im_str!("Present mode"),
+                &mut present_mode,
+                &labels,
+                supported,
+                4,
+            ) {
+                graphics.set_present_mode(present_mode);
+            }
+
+            let mut image_count = graphics.image_count() as i32;
+            if ui
+                .slider_int(
+                    im_str!("Swapchain images"),
+                    &mut image_count,
+                    IMAGE_COUNT_RANGE.0,
+                    IMAGE_COUNT_RANGE.1,
+                )
+                .build()
+            {
+                graphics.set_image_count(image_count as u32);
+            }
+            match graphics.swapchain_image_count() {
+                Some(count) => {
+                    ui.text(format!(
+                        "Swapchain has {} images, {} frames in flight",
+                        count,
+                        graphics::MAX_FRAMES
+                    ))
+                },
+                None => ui.text("No swapchain"),
+            }
+
+            if ui.small_button(im_str!("Capture frame")) {
+                graphics::renderdoc::trigger_capture(renderdoc, 1);
+            }
+
+            if ui.small_button(im_str!("Dump metrics")) {
+                match self.dump_metrics() {
+                    Ok(path) => {
+                        info!("wrote metrics to {}", path.display())
+                    },
+                    Err(err) => warn!("failed to dump metrics: {}", err),
+                }
+            }
         });
+
+        ui.tree_node(im_str!("Game state")).build(|| {
+            match game {
+                Some((game, cursor)) => {
+                    game_state_tree(ui, game, cursor, self.interpolation_mode)
+                },
+                None => ui.text(im_str!("Not in a game")),
+            }
+        });
+
+        ui.tree_node(im_str!("Snapshot history")).build(|| {
+            match game {
+                Some((game, _)) => {
+                    snapshot_history_tree(ui, game, &mut self.scrub)
+                },
+                None => ui.text(im_str!("Not in a game")),
+            }
+        });
+
+        ui.tree_node(im_str!("Logger")).build(|| {
+            logger::LOGGER.ui(&ui);
+        });
+    }
+}
+
+/// Size that the debug window opens at when it's popped out.
+const DETACHED_SIZE: (f64, f64) = (480.0, 720.0);
+
+/// The debug window popped out into its own OS window, with its own
+/// imgui context so that its input and layout are separate from the
+/// game's.
+pub struct DetachedWindow {
+    pub window: Window,
+    imgui: ManuallyDrop<ImGui>,
+    imgui_winit: ImGuiWinit,
+    context: *mut sys::ImGuiContext,
+}
+
+impl DetachedWindow {
+    pub fn new(
+        events_loop: &EventsLoop,
+    ) -> Result<DetachedWindow, CreationError> {
+        let window = WindowBuilder::new()
+            .with_title("Debug")
+            .with_dimensions(LogicalSize::new(DETACHED_SIZE.0, DETACHED_SIZE.1))
+            .build(events_loop)?;
+        // A new context only becomes current if there isn't one
+        // already, so the game's has to be set aside while it's made.
+        let (mut imgui, context) = unsafe {
+            let previous = sys::igGetCurrentContext();
+            sys::igSetCurrentContext(ptr::null_mut());
+            let imgui = ImGui::init();
+            let context = sys::igGetCurrentContext();
+            sys::igSetCurrentContext(previous);
+            (imgui, context)
+        };
+        let imgui_winit = ImGuiWinit::new(&mut imgui);
+        let mut detached = DetachedWindow {
+            window,
+            imgui: ManuallyDrop::new(imgui),
+            imgui_winit,
+            context,
+        };
+        // Both contexts would save their layout to the same file.
+        detached.with_context(|imgui, _, _| imgui.set_ini_filename(None));
+        Ok(detached)
+    }
+
+    pub fn id(&self) -> WindowId {
+        self.window.id()
+    }
+
+    /// Calls `f` with this window's imgui context made current, since
+    /// every imgui call goes to the current context.
+    pub fn with_context<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut ImGui, &mut ImGuiWinit, &Window) -> T,
+    {
+        unsafe {
+            let previous = sys::igGetCurrentContext();
+            sys::igSetCurrentContext(self.context);
+            let result =
+                f(&mut self.imgui, &mut self.imgui_winit, &self.window);
+            sys::igSetCurrentContext(previous);
+            result
+        }
     }
 }
+
+impl Drop for DetachedWindow {
+    fn drop(&mut self) {
+        unsafe {
+            let previous = sys::igGetCurrentContext();
+            sys::igSetCurrentContext(self.context);
+            ManuallyDrop::drop(&mut self.imgui);
+            sys::igSetCurrentContext(previous);
+        }
+    }
+}
+
+/// Counts the vblanks that went by without a new frame, given the
+/// seconds between two presents.
+///
+/// A present only counts as late once it's closer to the vblank after
+/// the one it was due on, so jitter in when presents return isn't
+/// counted.
+fn missed_vblanks(interval: f32, refresh_rate: f32) -> u32 {
+    if refresh_rate <= 0.0 {
+        return 0;
+    }
+    ((interval * refresh_rate).round() as u32).saturating_sub(1)
+}
+
+/// Describes the connection to a server for the debug window.
+fn connection_text(connection: Option<ConnectionState>) -> String {
+    match connection {
+        Some(ConnectionState::Connecting {
+            attempts,
+        }) => format!("connecting, attempt {}", attempts),
+        Some(ConnectionState::Connected {
+            rtt,
+            loss,
+        }) => {
+            let rtt = match rtt {
+                Some(rtt) => format!("{:.1} ms", rtt * 1000.0),
+                None => "?".to_owned(),
+            };
+            format!("connected, rtt {}, {:.0} % loss", rtt, loss * 100.0)
+        },
+        Some(ConnectionState::Disconnecting) => "disconnecting".to_owned(),
+        None => "none".to_owned(),
+    }
+}
+
+/// Draws a bar split up by how long each section took, with a legend
+/// underneath.
+fn section_bar<'a>(ui: &Ui<'a>, times: &[f32; SECTION_COUNT]) {
+    let total: f32 = times.iter().sum();
+    let (x, y) = ui.get_cursor_screen_pos();
+    let (width, _) = ui.get_content_region_avail();
+
+    let draw_list = ui.get_window_draw_list();
+    if total > 0.0 {
+        let mut start = x;
+        for (time, color) in times.iter().zip(&SECTION_COLORS) {
+            let end = start + width * time / total;
+            draw_list
+                .add_rect((start, y), (end, y + SECTION_BAR_HEIGHT), *color)
+                .filled(true)
+                .build();
+            start = end;
+        }
+    }
+    drop(draw_list);
+    ui.dummy((width, SECTION_BAR_HEIGHT));
+
+    for ((section, time), color) in
+        SECTIONS.iter().zip(times).zip(&SECTION_COLORS)
+    {
+        ui.text_colored(
+            *color,
+            &ImString::new(format!("{}: {:.2} ms", section.name(), time)),
+        );
+    }
+}
+
+/// Lists the state of the game and every player in it, as both
+/// interpolated for drawing and as of the latest snapshot.
+fn game_state_tree<'a>(
+    ui: &Ui<'a>,
+    game: &Game,
+    cursor: Point2<f32>,
+    mode: InterpolationMode,
+) {
+    ui.text(format!("Round: {:?}", game.round));
+    ui.text(format!("Buffered snapshots: {}", game.snapshot_count()));
+    ui.text(format!("Interpolation alpha: {:.3}", game.interpolation_alpha()));
+
+    let latest = game.latest_players();
+    let latest = latest.into_iter().collect::<Vec<_>>();
+    for (id, player) in game.interpolated_players(cursor, mode).into_iter() {
+        let name = &player.static_state().name;
+        let label = if id == game.player_id() {
+            format!("{} ({}, predicted)", name, id)
+        } else {
+            format!("{} ({})", name, id)
+        };
+        ui.tree_node(&ImString::new(label)).build(|| {
+            ui.text(im_str!("Interpolated:"));
+            player_state_text(ui, &player.state());
+            match latest.iter().find(|(latest_id, _)| *latest_id == id) {
+                Some((_, latest)) => {
+                    ui.text(im_str!("Latest snapshot:"));
+                    player_state_text(ui, &latest.state());
+                },
+                None => ui.text(im_str!("Not in latest snapshot")),
+            }
+        });
+    }
+}
+
+/// Shows when recent snapshots arrived, with a scrubber to freeze
+/// playback on one of them and step through the rest.
+fn snapshot_history_tree<'a>(
+    ui: &Ui<'a>,
+    game: &Game,
+    scrub: &mut Option<usize>,
+) {
+    let history = game.snapshot_history().collect::<Vec<_>>();
+    let last = history.len() - 1;
+    let (_, latest_time) = history[last];
+    let gaps = history
+        .windows(2)
+        .map(|pair| pair[1].1.duration_since(pair[0].1).as_secs_f32() * 1000.0)
+        .collect::<Vec<_>>();
+    ui.plot_lines(im_str!("Arrival gaps"), &gaps)
+        .scale_max(100.0)
+        .scale_min(0.0)
+        .overlay_text(&ImString::new(format!("{} snapshots", history.len())))
+        .build();
+
+    let mut frozen = scrub.is_some();
+    if ui.checkbox(im_str!("Freeze playback"), &mut frozen) {
+        *scrub = if frozen {
+            Some(last)
+        } else {
+            None
+        };
+    }
+    let mut index = match *scrub {
+        Some(index) => index.min(last) as i32,
+        None => return,
+    };
+    ui.slider_int(im_str!("Snapshot"), &mut index, 0, last as i32).build();
+    if ui.small_button(im_str!("Previous")) {
+        index -= 1;
+    }
+    ui.same_line(0.0);
+    if ui.small_button(im_str!("Next")) {
+        index += 1;
+    }
+    let index = index.max(0).min(last as i32) as usize;
+    *scrub = Some(index);
+
+    let (snapshot, time) = history[index];
+    ui.text(format!(
+        "Tick {}, received {:.0} ms before the latest",
+        snapshot.tick,
+        latest_time.duration_since(time).as_secs_f32() * 1000.0
+    ));
+    let mut players = snapshot.players.iter().collect::<Vec<_>>();
+    players.sort_by_key(|&(&id, _)| id);
+    for (id, state) in players {
+        let name =
+            game.players.get(id).map_or("unknown", |player| &player.name);
+        ui.tree_node(&ImString::new(format!("{} ({})", name, id)))
+            .build(|| player_state_text(ui, state));
+    }
+}
+
+fn player_state_text<'a>(ui: &Ui<'a>, state: &PlayerState) {
+    ui.text(format!("  alive: {}", state.cursor.is_some()));
+    if let Some(cursor) = state.cursor {
+        ui.text(format!("  cursor: ({:.3}, {:.3})", cursor.x, cursor.y));
+    }
+    let ball = &state.ball;
+    ui.text(format!(
+        "  ball position: ({:.3}, {:.3})",
+        ball.position.x, ball.position.y
+    ));
+    ui.text(format!(
+        "  ball velocity: ({:.3}, {:.3})",
+        ball.velocity.x, ball.velocity.y
+    ));
+}
+
+#[test]
+fn missed_vblank_detection() {
+    assert_eq!(missed_vblanks(1.0 / 60.0, 60.0), 0);
+    assert_eq!(missed_vblanks(1.2 / 60.0, 60.0), 0);
+    assert_eq!(missed_vblanks(2.0 / 60.0, 60.0), 1);
+    assert_eq!(missed_vblanks(3.1 / 60.0, 60.0), 2);
+    // Presenting faster than the display refreshes never misses.
+    assert_eq!(missed_vblanks(0.002, 60.0), 0);
+    assert_eq!(missed_vblanks(1.0, 0.0), 0);
+}
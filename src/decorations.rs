@@ -0,0 +1,174 @@
+use crate::game::{client::Game, GameSettings, RoundState, WAITING_DURATION};
+use crate::graphics::Circle;
+use nalgebra::{Point2, Vector2};
+use palette::LinSrgb;
+
+/// Seconds a pulse ring takes to spread out to the edge of the arena.
+const PULSE_TIME: f32 = 1.0;
+
+/// Width in world units of each pulse ring.
+const PULSE_WIDTH: f32 = 0.02;
+
+/// Brightness of a pulse ring as it leaves the center, after which it
+/// fades into the arena.
+const PULSE_BRIGHTNESS: f32 = 0.8;
+
+/// Number of dots the countdown arc is made of when it goes all the
+/// way around.
+const ARC_DOTS: usize = 360;
+
+/// Radius in world units of each dot in the countdown arc.
+const ARC_DOT_RADIUS: f32 = 0.01;
+
+/// Number of rings of dots that show the force fields.
+const FIELD_RINGS: usize = 4;
+
+/// Number of dots in each field ring.
+const FIELD_DOTS: usize = 24;
+
+/// Radius in world units of each field dot.
+const FIELD_DOT_RADIUS: f32 = 0.006;
+
+/// Brightness of the field dots, which are only just darker than the
+/// arena.
+const FIELD_BRIGHTNESS: f32 = 0.85;
+
+/// How fast the field dots drift for each unit of field strength, as
+/// a fraction of the way in per second, or radians per second around.
+const FIELD_DRIFT: f32 = 0.05;
+
+/// Gets circles for the decorations on the arena floor, which go
+/// between the arena and the effects.
+///
+/// Faint dots show which way any force fields push. While waiting
+/// for a round to start, a ring pulses out from the center every
+/// second of the countdown, and an arc around the edge shrinks away
+/// with the time left.
+pub fn circles(game: &Game, scale: f32) -> Vec<Circle> {
+    let mut circles = field_dots(game.settings(), game.round_duration, scale);
+    if game.round != RoundState::Waiting {
+        return circles;
+    }
+    let time = game.round_duration.max(0.0).min(WAITING_DURATION);
+    let bounds_radius = scale * game.settings().bounds_radius;
+    // Spectators don't have a color of their own.
+    let color = game
+        .players
+        .get(&game.player_id())
+        .map_or(LinSrgb::new(0.5, 0.5, 0.5), |player| player.color);
+    circles.extend(pulse_rings(time, bounds_radius, scale));
+    circles.extend(countdown_arc(
+        1.0 - time / WAITING_DURATION,
+        bounds_radius,
+        scale,
+        color,
+    ));
+    circles
+}
+
+/// Gets the rings that have pulsed out `time` seconds into the
+/// countdown and haven't reached the edge yet.
+fn pulse_rings(time: f32, bounds_radius: f32, scale: f32) -> Vec<Circle> {
+    let pulses = WAITING_DURATION.ceil() as u32;
+    (0..pulses)
+        .map(|pulse| (time - pulse as f32) / PULSE_TIME)
+        .filter(|&progress| progress >= 0.0 && progress < 1.0)
+        .map(|progress| {
+            let radius = bounds_radius * progress;
+            let brightness =
+                PULSE_BRIGHTNESS + (1.0 - PULSE_BRIGHTNESS) * progress;
+            Circle {
+                center: Point2::origin(),
+                radius,
+                inner_radius: (radius - scale * PULSE_WIDTH).max(0.0),
+                color: LinSrgb::new(brightness, brightness, brightness),
+            }
+        })
+        .collect()
+}
+
+/// Gets rings of dots `time` seconds into the round, which drift
+/// inwards with gravity and turn around the middle with the vortex.
+fn field_dots(settings: &GameSettings, time: f32, scale: f32) -> Vec<Circle> {
+    let (gravity, vortex) = (settings.gravity, settings.vortex);
+    if gravity == 0.0 && vortex == 0.0 {
+        return Vec::new();
+    }
+    let bounds_radius = scale * settings.bounds_radius;
+    let radius = scale * FIELD_DOT_RADIUS;
+    let drift = time * FIELD_DRIFT;
+    let color =
+        LinSrgb::new(FIELD_BRIGHTNESS, FIELD_BRIGHTNESS, FIELD_BRIGHTNESS);
+    (0..FIELD_RINGS)
+        .flat_map(|ring| {
+            // Rings that reach the middle start again from the edge,
+            // or the other way around when pushing out.
+            let progress = (ring as f32 + 0.5) / FIELD_RINGS as f32;
+            let progress = (progress - drift * gravity).rem_euclid(1.0);
+            let distance = (bounds_radius - radius) * progress;
+            (0..FIELD_DOTS).map(move |dot| {
+                let angle = 2.0 * std::f32::consts::PI * dot as f32 /
+                    FIELD_DOTS as f32 +
+                    drift * vortex;
+                let direction = Vector2::new(angle.cos(), angle.sin());
+                Circle {
+                    center: Point2::origin() + direction * distance,
+                    radius,
+                    inner_radius: 0.0,
+                    color,
+                }
+            })
+        })
+        .collect()
+}
+
+/// Gets the dots of an arc around the inside of the edge, going
+/// clockwise from the top for `fraction` of the way around.
+fn countdown_arc(
+    fraction: f32,
+    bounds_radius: f32,
+    scale: f32,
+    color: LinSrgb,
+) -> impl Iterator<Item = Circle> {
+    let dots = (ARC_DOTS as f32 * fraction.max(0.0).min(1.0)).ceil() as usize;
+    let radius = scale * ARC_DOT_RADIUS;
+    (0..dots).map(move |dot| {
+        let angle = 2.0 * std::f32::consts::PI * dot as f32 / ARC_DOTS as f32 -
+            std::f32::consts::FRAC_PI_2;
+        let direction = Vector2::new(angle.cos(), angle.sin());
+        Circle {
+            center: Point2::origin() + direction * (bounds_radius - radius),
+            radius,
+            inner_radius: 0.0,
+            color,
+        }
+    })
+}
+
+#[test]
+fn arc_shrinks_with_countdown() {
+    let color = LinSrgb::new(1.0, 0.0, 0.0);
+    let full = countdown_arc(1.0, 1.0, 1.0, color).collect::<Vec<_>>();
+    assert_eq!(full.len(), ARC_DOTS);
+    // The first dot is at the top, which is negative y.
+    assert!(full[0].center.y < 0.0 && full[0].center.x.abs() < 1e-5);
+    assert_eq!(countdown_arc(0.5, 1.0, 1.0, color).count(), ARC_DOTS / 2);
+    assert_eq!(countdown_arc(0.0, 1.0, 1.0, color).count(), 0);
+}
+
+#[test]
+fn field_dots_follow_gravity() {
+    let still = GameSettings::default();
+    assert!(field_dots(&still, 1.0, 1.0).is_empty());
+
+    let settings = GameSettings {
+        gravity: 1.0,
+        ..GameSettings::default()
+    };
+    let distance = |time| {
+        let dots = field_dots(&settings, time, 1.0);
+        assert_eq!(dots.len(), FIELD_RINGS * FIELD_DOTS);
+        nalgebra::distance(&dots[0].center, &Point2::origin())
+    };
+    assert!(distance(1.0) < distance(0.0));
+}
@@ -0,0 +1,229 @@
+use crate::config::GraphicsConfig;
+use crate::ui;
+use crate::viewport::{self, Framing, ViewportMapping};
+use imgui::{im_str, ImString, Ui};
+use winit::dpi::LogicalSize;
+use winit::{MonitorId, Window};
+
+/// Window sizes offered besides each monitor's own, on monitors big
+/// enough for them.
+const WINDOW_SIZES: [(f64, f64); 5] = [
+    (1024.0, 768.0),
+    (1280.0, 720.0),
+    (1600.0, 900.0),
+    (1920.0, 1080.0),
+    (2560.0, 1440.0),
+];
+
+/// Whether the game window is fullscreen, and on which monitor, and
+/// how the arena is framed inside it.
+///
+/// Fullscreen is borderless, covering the monitor at its current
+/// resolution, so switching in and out of it is quick and doesn't
+/// disturb other windows. The resolution picked in the settings window
+/// is the size of the window when it isn't fullscreen, since winit
+/// can't change a monitor's video mode.
+pub struct Display {
+    pub fullscreen: bool,
+    /// Name of the monitor to go fullscreen on, or `None` for
+    /// whichever one the window is on.
+    pub monitor: Option<String>,
+    pub mapping: ViewportMapping,
+    pub show_window: bool,
+    /// Monitors connected when the settings window was opened.
+    monitors: Vec<MonitorId>,
+    /// Window sizes to pick from, which fit on those monitors.
+    window_sizes: Vec<LogicalSize>,
+    /// The window's size the last time the settings were applied.
+    window_size: Option<LogicalSize>,
+    /// Window size that was picked, and is set once the window isn't
+    /// fullscreen.
+    picked_size: Option<LogicalSize>,
+    /// Whether the window doesn't match the settings yet.
+    dirty: bool,
+}
+
+impl Display {
+    pub fn new(config: &GraphicsConfig) -> Display {
+        Display {
+            fullscreen: config.fullscreen,
+            monitor: config.monitor.clone(),
+            mapping: ViewportMapping {
+                framing: config.framing,
+                margin: config.ui_margin.max(0.0).min(viewport::MAX_MARGIN),
+            },
+            show_window: false,
+            monitors: Vec::new(),
+            window_sizes: Vec::new(),
+            window_size: None,
+            picked_size: None,
+            dirty: config.fullscreen,
+        }
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        self.fullscreen = !self.fullscreen;
+        self.dirty = true;
+    }
+
+    /// Switches the window in or out of fullscreen if the settings
+    /// changed since they were last applied.
+    ///
+    /// Returns whether the window was switched, in which case the
+    /// swapchain has to be rebuilt for its new size.
+    pub fn apply(&mut self, window: &Window) -> bool {
+        if self.show_window && self.monitors.is_empty() {
+            self.monitors = window.get_available_monitors().collect();
+            self.window_sizes = window_sizes(&self.monitors);
+        }
+        if self.show_window {
+            self.window_size = window.get_inner_size();
+        }
+        if !self.dirty {
+            return false;
+        }
+        self.dirty = false;
+
+        let monitor = if self.fullscreen {
+            // The chosen monitor might have been unplugged since.
+            let chosen = self.monitor.as_ref().and_then(|name| {
+                window
+                    .get_available_monitors()
+                    .find(|monitor| monitor.get_name().as_ref() == Some(name))
+            });
+            Some(chosen.unwrap_or_else(|| window.get_current_monitor()))
+        } else {
+            None
+        };
+        let windowed = monitor.is_none();
+        window.set_fullscreen(monitor);
+        if windowed {
+            if let Some(size) = self.picked_size.take() {
+                window.set_inner_size(size);
+            }
+        }
+        true
+    }
+
+    /// Shows the display settings window, if it's open.
+    pub fn window<'a>(&mut self, ui: &Ui<'a>) {
+        if !self.show_window {
+            // Look for monitors again the next time it's opened.
+            self.monitors.clear();
+            return;
+        }
+        let fullscreen = &mut self.fullscreen;
+        let chosen = &mut self.monitor;
+        let mapping = &mut self.mapping;
+        let monitors = &self.monitors;
+        let window_sizes = &self.window_sizes;
+        let window_size = self.picked_size.or(self.window_size);
+        let picked_size = &mut self.picked_size;
+        let mut changed = false;
+        ui.window(im_str!("Display"))
+            .always_auto_resize(true)
+            .opened(&mut self.show_window)
+            .build(|| {
+                changed |= ui.checkbox(im_str!("Fullscreen (F11)"), fullscreen);
+
+                ui.text("Monitor");
+                if ui.radio_button_bool(
+                    im_str!("Whichever the window is on"),
+                    chosen.is_none(),
+                ) {
+                    *chosen = None;
+                    changed = true;
+                }
+                for (i, monitor) in monitors.iter().enumerate() {
+                    let name = monitor.get_name();
+                    let size = monitor.get_dimensions();
+                    let label = ImString::new(format!(
+                        "{} ({}x{})##{}",
+                        name.as_ref().map_or("Unnamed", String::as_str),
+                        size.width,
+                        size.height,
+                        i
+                    ));
+                    // Monitors are remembered by name, so one without
+                    // a name can't be picked.
+                    let selected = name.is_some() && *chosen == name;
+                    if ui.radio_button_bool(&label, selected) && name.is_some()
+                    {
+                        *chosen = name;
+                        changed = true;
+                    }
+                }
+
+                ui.separator();
+                ui.text("Window size");
+                if *fullscreen {
+                    ui.text("(used once the window isn't fullscreen)");
+                }
+                for (i, &size) in window_sizes.iter().enumerate() {
+                    let label = ImString::new(format!(
+                        "{}x{}##size{}",
+                        size.width, size.height, i
+                    ));
+                    if ui.radio_button_bool(&label, window_size == Some(size)) {
+                        *picked_size = Some(size);
+                        changed = true;
+                    }
+                }
+
+                ui.separator();
+                // Framing is applied every frame, so it doesn't need
+                // to mark the window dirty.
+                ui::enum_combo(
+                    ui,
+                    im_str!("Framing"),
+                    &mut mapping.framing,
+                    &[im_str!("fit"), im_str!("fill"), im_str!("fixed height")],
+                    &[Framing::Fit, Framing::Fill, Framing::FixedHeight],
+                    3,
+                );
+                ui.slider_float(
+                    im_str!("UI margin"),
+                    &mut mapping.margin,
+                    0.0,
+                    viewport::MAX_MARGIN,
+                )
+                .build();
+            });
+        self.dirty |= changed;
+    }
+
+    /// Stores the settings back into the config.
+    pub fn save(&self, config: &mut GraphicsConfig) {
+        config.fullscreen = self.fullscreen;
+        config.monitor = self.monitor.clone();
+        config.framing = self.mapping.framing;
+        config.ui_margin = self.mapping.margin;
+    }
+}
+
+/// Lists the window sizes that fit on any of `monitors`, including
+/// each monitor's own size, from smallest to largest.
+fn window_sizes(monitors: &[MonitorId]) -> Vec<LogicalSize> {
+    let native: Vec<_> = monitors
+        .iter()
+        .map(|monitor| {
+            let size =
+                monitor.get_dimensions().to_logical(monitor.get_hidpi_factor());
+            LogicalSize::new(size.width.round(), size.height.round())
+        })
+        .collect();
+    let fits = |&(width, height): &(f64, f64)| {
+        native.iter().any(|size| width <= size.width && height <= size.height)
+    };
+    let mut sizes: Vec<_> = WINDOW_SIZES
+        .iter()
+        .filter(|&size| fits(size))
+        .map(|&(width, height)| LogicalSize::new(width, height))
+        .chain(native.iter().cloned())
+        .collect();
+    sizes.sort_by(|a, b| {
+        (a.width, a.height).partial_cmp(&(b.width, b.height)).unwrap()
+    });
+    sizes.dedup();
+    sizes
+}
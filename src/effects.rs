@@ -0,0 +1,261 @@
+use crate::game::{
+    client::{Game, GameEvent},
+    GameSettings,
+    GetPlayer,
+    RoundState,
+};
+use crate::graphics::Circle;
+use nalgebra::{Point2, Vector2};
+use palette::LinSrgb;
+use rand::{thread_rng, Rng};
+
+/// Seconds that playback holds still for after a kill.
+const HIT_STOP_TIME: f32 = 0.06;
+
+/// Seconds the arena shakes for after a kill.
+const SHAKE_TIME: f32 = 0.25;
+
+/// Distance in world units the arena is knocked at the start of a
+/// shake.
+const SHAKE_DISTANCE: f32 = 0.02;
+
+/// Gap in world units between your ball and another player's cursor
+/// that counts as nearly hitting it.
+const NEAR_MISS_DISTANCE: f32 = 0.05;
+
+/// Seconds the edge flash takes to fade out.
+const FLASH_TIME: f32 = 0.3;
+
+/// Brightness of the edge flash when it starts.
+const FLASH_INTENSITY: f32 = 0.5;
+
+/// Radius in world units that the edge flash reaches out to, which
+/// just has to be past the corners of the window.
+const FLASH_OUTER_RADIUS: f32 = 10.0;
+
+/// Seconds a ghost stays where a player died.
+const GHOST_TIME: f32 = 1.0;
+
+/// Marker left where a player died.
+#[derive(Copy, Clone, Debug)]
+struct Ghost {
+    position: Point2<f32>,
+    color: LinSrgb,
+    /// Seconds since the player died.
+    age: f32,
+}
+
+/// Short lived visual feedback for things happening in the game,
+/// which is driven by the client's game events.
+#[derive(Clone, Debug, Default)]
+pub struct Effects {
+    /// Seconds of hit-stop left.
+    hit_stop: f32,
+    /// Seconds of shaking left.
+    shake: f32,
+    /// Seconds of the edge flash left.
+    flash: f32,
+    /// Whether your ball was close to a cursor last update, so that a
+    /// near miss only flashes once as it comes close.
+    near_miss: bool,
+    ghosts: Vec<Ghost>,
+}
+
+impl Effects {
+    pub fn handle_event(&mut self, event: &GameEvent, game: &Game) {
+        if let GameEvent::Kill {
+            victim,
+            ..
+        } = *event
+        {
+            self.hit_stop = HIT_STOP_TIME;
+            self.shake = SHAKE_TIME;
+            let victim =
+                game.latest_players().into_iter().find(|&(id, _)| id == victim);
+            if let Some((_, player)) = victim {
+                self.ghosts.push(Ghost {
+                    position: player.state().ball.position,
+                    color: player.static_state().color,
+                    age: 0.0,
+                });
+            }
+        }
+    }
+
+    /// Advances every effect by `dt` seconds, and checks whether your
+    /// ball nearly hit someone's cursor.
+    pub fn update(&mut self, dt: f32, game: &Game) {
+        self.hit_stop = (self.hit_stop - dt).max(0.0);
+        self.shake = (self.shake - dt).max(0.0);
+        self.flash = (self.flash - dt).max(0.0);
+        for ghost in &mut self.ghosts {
+            ghost.age += dt;
+        }
+        self.ghosts.retain(|ghost| ghost.age < GHOST_TIME);
+
+        let near_miss =
+            game.round == RoundState::Round && nearly_hit_cursor(game);
+        if near_miss && !self.near_miss {
+            self.flash = FLASH_TIME;
+        }
+        self.near_miss = near_miss;
+    }
+
+    /// Whether playback should hold still for a moment after a kill.
+    pub fn hit_stop(&self) -> bool {
+        self.hit_stop > 0.0
+    }
+
+    /// Gets how far to knock everything in the arena, in world units.
+    pub fn shake_offset(&self) -> Vector2<f32> {
+        if self.shake <= 0.0 {
+            return Vector2::zeros();
+        }
+        let angle = thread_rng().gen_range(0.0, 2.0 * std::f32::consts::PI);
+        let distance = SHAKE_DISTANCE * self.shake / SHAKE_TIME;
+        Vector2::new(angle.cos(), angle.sin()) * distance
+    }
+
+    /// Gets circles for the effects, which go between the arena and
+    /// the players.
+    pub fn circles(
+        &self,
+        scale: f32,
+        settings: &GameSettings,
+    ) -> impl Iterator<Item = Circle> + '_ {
+        let flash = if self.flash > 0.0 {
+            let intensity = FLASH_INTENSITY * self.flash / FLASH_TIME;
+            Some(Circle {
+                center: Point2::origin(),
+                radius: FLASH_OUTER_RADIUS,
+                inner_radius: scale * settings.bounds_radius,
+                color: LinSrgb::new(1.0, 0.2, 0.2) * intensity,
+            })
+        } else {
+            None
+        };
+        let radius = scale * settings.ball_radius;
+        let ghosts = self.ghosts.iter().map(move |ghost| {
+            let fade = 1.0 - ghost.age / GHOST_TIME;
+            // Fade into the arena, which is white.
+            let white = LinSrgb::new(1.0, 1.0, 1.0);
+            Circle {
+                center: ghost.position * scale,
+                radius: radius * (1.0 + 0.5 * ghost.age / GHOST_TIME),
+                inner_radius: 0.8 * radius,
+                color: white * (1.0 - fade) + ghost.color * fade,
+            }
+        });
+        flash.into_iter().chain(ghosts)
+    }
+}
+
+/// Whether your ball is just short of touching another player's
+/// cursor.
+fn nearly_hit_cursor(game: &Game) -> bool {
+    let settings = game.settings();
+    let player_id = game.player_id();
+    let players = game.latest_players().into_iter().collect::<Vec<_>>();
+    let ball = match players.iter().find(|&&(id, _)| id == player_id) {
+        Some((_, player)) if player.state().alive() => player.state().ball,
+        _ => return false,
+    };
+    let touching = ball.radius(settings) + settings.cursor_radius;
+    players
+        .iter()
+        .filter(|&&(id, _)| id != player_id || settings.kill_own_cursor)
+        .filter_map(|(_, player)| player.state().cursor)
+        .any(|cursor| {
+            let gap = nalgebra::distance(&ball.position, &cursor) - touching;
+            gap > 0.0 && gap < NEAR_MISS_DISTANCE
+        })
+}
+
+#[cfg(test)]
+fn test_game(
+    round: RoundState,
+    players: &[(crate::game::PlayerId, crate::game::PlayerState)],
+) -> Game {
+    use crate::game::{Snapshot, StaticPlayerState};
+
+    let statics = players
+        .iter()
+        .map(|&(id, _)| {
+            let player = StaticPlayerState {
+                color: LinSrgb::new(1.0, 0.0, 0.0),
+                name: format!("player {}", id),
+                wins: 0,
+            };
+            (id, player)
+        })
+        .collect();
+    let (game, _) = Game::new(
+        statics,
+        Snapshot::new(1, players.iter().cloned()),
+        round,
+        0.0,
+        GameSettings::default(),
+        0,
+        0.0,
+        0,
+        Point2::origin(),
+    );
+    game
+}
+
+#[test]
+fn kill_hit_stops_and_shakes() {
+    let settings = GameSettings::default();
+    let victim =
+        crate::game::PlayerState::new(Point2::new(0.5, 0.0), &settings);
+    let game = test_game(RoundState::Round, &[(1, victim)]);
+    let mut effects = Effects::default();
+    effects.handle_event(
+        &GameEvent::Kill {
+            killer: 0,
+            victim: 1,
+        },
+        &game,
+    );
+    assert!(effects.hit_stop());
+    assert_eq!(effects.ghosts.len(), 1);
+    assert!((effects.shake_offset().norm() - SHAKE_DISTANCE).abs() < 1e-5);
+
+    effects.update(HIT_STOP_TIME, &game);
+    assert!(!effects.hit_stop());
+    let shaking = effects.shake_offset().norm();
+    assert!(shaking > 0.0 && shaking < SHAKE_DISTANCE);
+
+    effects.update(SHAKE_TIME, &game);
+    assert_eq!(effects.shake_offset(), Vector2::zeros());
+}
+
+#[test]
+fn near_miss_flashes_once() {
+    let settings = GameSettings::default();
+    let mut own =
+        crate::game::PlayerState::new(Point2::new(0.5, 0.0), &settings);
+    own.ball.position = Point2::origin();
+    let gap = own.ball.radius(&settings) +
+        settings.cursor_radius +
+        NEAR_MISS_DISTANCE / 2.0;
+    let mut other =
+        crate::game::PlayerState::new(Point2::new(-0.5, 0.0), &settings);
+    other.cursor = Some(Point2::new(0.0, gap));
+    let game = test_game(RoundState::Round, &[(0, own), (1, other)]);
+    let mut effects = Effects::default();
+    effects.update(0.01, &game);
+    assert_eq!(effects.circles(1.0, &settings).count(), 1);
+
+    // Staying close doesn't flash again.
+    effects.update(0.1, &game);
+    assert!((effects.flash - (FLASH_TIME - 0.1)).abs() < 1e-5);
+    effects.update(FLASH_TIME, &game);
+    assert_eq!(effects.circles(1.0, &settings).count(), 0);
+
+    // Nothing flashes between rounds.
+    let waiting = test_game(RoundState::Waiting, &[(0, own), (1, other)]);
+    let mut effects = Effects::default();
+    effects.update(0.01, &waiting);
+    assert_eq!(effects.flash, 0.0);
+}
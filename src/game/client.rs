@@ -1,26 +1,107 @@
 use crate::game::{
+    clamp_cursor,
+    Ball,
     Event,
     GameSettings,
     GetPlayer,
     Input,
     InterpolatedSnapshot,
+    InterpolationMode,
     PlayerId,
     PlayerState,
     RoundState,
     Snapshot,
     SnapshotView,
     StaticPlayerState,
+    DASH_COOLDOWN,
+    DASH_DISTANCE,
 };
 use crate::networking::SNAPSHOT_RATE;
+use crate::stats::SessionStats;
 use crossbeam::channel::{self, Receiver, Sender};
 use log::{info, warn};
-use nalgebra::Point2;
+use nalgebra::{self, Point2, Vector2};
 use parking_lot::Mutex;
+use smallvec::SmallVec;
 use std::borrow::Cow;
 use std::collections::{HashMap, VecDeque};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+/// Maximum fraction by which the playback clock is sped up or slowed
+/// down to catch up with the target time.
+const MAX_PLAYBACK_SLEW: f32 = 0.05;
+
+/// Fraction of speed change per second that the playback clock is
+/// off from the target time.
+const PLAYBACK_GAIN: f32 = 2.0;
+
+/// Time in seconds for a respawned player to fade back in.
+const RESPAWN_FADE_TIME: f32 = 0.5;
+
+/// Time in seconds that messages from the server stay on screen.
+const MESSAGE_TIME: f32 = 8.0;
+
+/// Minimum time between requests for the full static state from the
+/// server.
+pub const STATE_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// If the playback clock is further than this many seconds off from
+/// the target time, it's hopeless to catch up smoothly, so it just
+/// snaps to the target.
+const PLAYBACK_SNAP: f32 = 0.25;
+
+/// Number of received snapshots kept around to step through in the
+/// debug window, which is about four seconds worth.
+const SNAPSHOT_HISTORY_LENGTH: usize = 128;
+
+/// Balls closer than this fraction past touching are considered to be
+/// in contact, when detecting collisions from snapshots.
+const CONTACT_TOLERANCE: f32 = 1.05;
+
+/// Relative speed at which a collision is considered to be at full
+/// intensity.
+const FULL_COLLISION_SPEED: f32 = 4.0;
+
+/// Something noteworthy that happened in the game, for things like
+/// sound effects to react to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GameEvent {
+    /// A ball bounced off of another ball or the wall, as detected
+    /// locally from snapshots. The intensity goes from 0 to 1.
+    Collision(f32),
+    Kill {
+        killer: PlayerId,
+        victim: PlayerId,
+    },
+    Respawn(PlayerId),
+    /// A player was killed for being idle.
+    Afk(PlayerId),
+    RoundStart,
+    RoundEnd,
+    /// The round was won by a player, or by nobody.
+    Winner(Option<PlayerId>),
+    /// The next round is being waited for after a winner was
+    /// declared.
+    NextRound,
+}
+
+/// Clock that determines the point in time snapshots get
+/// interpolated at.
+///
+/// Rather than snapping straight to the target delay behind the
+/// latest snapshot, which jumps whenever a burst of snapshots arrives
+/// after a stall, the clock runs a few percent faster or slower than
+/// real time until it catches up, similar to an audio jitter buffer.
+#[derive(Copy, Clone, Debug)]
+struct PlaybackClock {
+    time: Instant,
+    last_update: Instant,
+    /// Whether the clock has been synced to a target yet.
+    synced: bool,
+}
 
 #[derive(Debug)]
 pub struct Player<'a> {
@@ -33,7 +114,7 @@ pub struct Player<'a> {
 pub struct Players<'a, S> {
     players: &'a HashMap<PlayerId, StaticPlayerState>,
     snapshot: S,
-    predicted: Option<(PlayerId, PlayerState)>,
+    predicted: SmallVec<[(PlayerId, PlayerState); 2]>,
 }
 
 pub struct Game {
@@ -41,13 +122,52 @@ pub struct Game {
     pub last_round: Option<RoundState>,
     pub round: RoundState,
     pub round_duration: f32,
+    /// Seconds since each recently respawned player came back.
+    respawns: HashMap<PlayerId, f32>,
     snapshots: VecDeque<(Snapshot, Instant)>,
+    /// Recently received snapshots and when they arrived, oldest
+    /// first, which unlike `snapshots` are kept after being played.
+    history: VecDeque<(Snapshot, Instant)>,
+    /// Index into `history` that playback is frozen on, while
+    /// stepping through it.
+    scrub: Option<usize>,
+    playback: PlaybackClock,
     settings: GameSettings,
     settings_handle: Arc<SettingsHandle>,
     cursor: Arc<Mutex<Point2<f32>>>,
+    /// Whether this client's player is charging up their ball.
+    charging: Arc<AtomicBool>,
+    /// Number of times this client's player has dashed.
+    dashes: Arc<AtomicU32>,
+    /// Seconds until this client's player can dash again, as
+    /// predicted from when it last dashed.
+    dash_cooldown: f32,
     events: Receiver<Event>,
+    /// Set to signal the networking thread to request the full static
+    /// state from the server.
+    request_state: Arc<AtomicBool>,
+    /// Time that the full static state was last requested.
+    last_state_request: Option<Instant>,
     /// Player id for this client.
     player_id: PlayerId,
+    /// Hue the server assigned to this client's player.
+    hue: f32,
+    /// Seed the server's game draws its random numbers from.
+    seed: u64,
+    /// Statistics for this client's player since connecting.
+    stats: SessionStats,
+    /// Events that haven't been taken by `drain_events` yet.
+    game_events: Vec<GameEvent>,
+    /// Latest round trip time in milliseconds for each player.
+    pings: HashMap<PlayerId, u16>,
+    /// Recent messages from the server and how many seconds ago they
+    /// arrived, oldest first.
+    messages: VecDeque<(String, f32)>,
+    /// Copies of the events handled since `drain_relayed` was last
+    /// called, if they're being kept to relay to spectators.
+    relayed: Option<Vec<Event>>,
+    /// When the server paused the game, if it's paused.
+    paused: Option<Instant>,
 }
 
 pub struct SettingsHandle {
@@ -58,9 +178,65 @@ pub struct SettingsHandle {
 pub struct GameHandle {
     events: Sender<Event>,
     cursor: Arc<Mutex<Point2<f32>>>,
+    charging: Arc<AtomicBool>,
+    dashes: Arc<AtomicU32>,
+    request_state: Arc<AtomicBool>,
     pub settings: Arc<SettingsHandle>,
 }
 
+/// Finds balls that came into contact with each other or the wall
+/// between two snapshots.
+///
+/// Collisions are resolved on the server by pushing balls apart until
+/// they're just touching, so any pair that's touching now but wasn't
+/// before must have bounced.
+fn detect_collisions(
+    old: &Snapshot,
+    new: &Snapshot,
+    settings: &GameSettings,
+    events: &mut Vec<GameEvent>,
+) {
+    // Charged balls are bigger, so these depend on each ball.
+    let wall = |ball: &Ball| {
+        settings.bounds_radius - ball.radius(settings) * CONTACT_TOLERANCE
+    };
+    let touching = |snapshot: &Snapshot, a: PlayerId, b: PlayerId| {
+        match (snapshot.players.get(&a), snapshot.players.get(&b)) {
+            (Some(a), Some(b)) => {
+                let contact = (a.ball.radius(settings) +
+                    b.ball.radius(settings)) *
+                    CONTACT_TOLERANCE;
+                nalgebra::distance(&a.ball.position, &b.ball.position) < contact
+            },
+            _ => false,
+        }
+    };
+    let intensity = |speed: f32| (speed / FULL_COLLISION_SPEED).min(1.0);
+
+    for (&id_a, a) in &new.players {
+        for (&id_b, b) in &new.players {
+            if id_a < id_b &&
+                touching(new, id_a, id_b) &&
+                !touching(old, id_a, id_b)
+            {
+                let speed = (a.ball.velocity - b.ball.velocity).norm();
+                events.push(GameEvent::Collision(intensity(speed)));
+            }
+        }
+
+        let hit_wall = a.ball.position.coords.norm() > wall(&a.ball);
+        let was_hitting_wall = old
+            .players
+            .get(&id_a)
+            .map(|old| old.ball.position.coords.norm() > wall(&old.ball))
+            .unwrap_or(false);
+        if hit_wall && !was_hitting_wall {
+            let speed = a.ball.velocity.norm();
+            events.push(GameEvent::Collision(intensity(speed)));
+        }
+    }
+}
+
 impl<'a, 'b> GetPlayer for &'b Player<'a> {
     type State = &'b PlayerState;
     type StaticState = &'a StaticPlayerState;
@@ -90,8 +266,11 @@ impl<'a, S: SnapshotView<'a>> Players<'a, S> {
             snapshot,
         } = self;
 
-        let predicted_id = predicted.map(|(id, _)| id);
-        let predicted = predicted.map(|(id, state)| {
+        let predicted_ids = predicted
+            .iter()
+            .map(|&(id, _)| id)
+            .collect::<SmallVec<[PlayerId; 2]>>();
+        let predicted = predicted.into_iter().map(move |(id, state)| {
             (
                 id,
                 Player {
@@ -104,10 +283,7 @@ impl<'a, S: SnapshotView<'a>> Players<'a, S> {
         snapshot
             .players()
             .filter_map(move |(id, state)| {
-                if predicted_id
-                    .map(|predicted_id| id == predicted_id)
-                    .unwrap_or(false)
-                {
+                if predicted_ids.contains(&id) {
                     return None;
                 }
                 // Only handle players who also have static state
@@ -128,6 +304,40 @@ impl<'a, S: SnapshotView<'a>> Players<'a, S> {
     }
 }
 
+impl PlaybackClock {
+    fn new(now: Instant) -> PlaybackClock {
+        PlaybackClock {
+            time: now,
+            last_update: now,
+            synced: false,
+        }
+    }
+
+    /// Advances the clock to a new frame, slewing it towards the
+    /// target time.
+    fn advance(&mut self, now: Instant, target: Instant) {
+        let dt = now.duration_since(self.last_update).as_secs_f32();
+        self.last_update = now;
+
+        // Positive if playback is lagging behind the target.
+        let error = if target > self.time {
+            target.duration_since(self.time).as_secs_f32()
+        } else {
+            -self.time.duration_since(target).as_secs_f32()
+        };
+        if !self.synced || error.abs() > PLAYBACK_SNAP {
+            self.time = target;
+            self.synced = true;
+            return;
+        }
+
+        let slew = (error * PLAYBACK_GAIN)
+            .max(-MAX_PLAYBACK_SLEW)
+            .min(MAX_PLAYBACK_SLEW);
+        self.time += Duration::from_secs_f32(dt * (1.0 + slew));
+    }
+}
+
 impl GameHandle {
     pub fn event(&self, event: Event) {
         let _ = self.events.send(event);
@@ -136,8 +346,16 @@ impl GameHandle {
     pub fn latest_input(&self) -> Input {
         Input {
             cursor: *self.cursor.lock(),
+            charging: self.charging.load(Ordering::SeqCst),
+            dashes: self.dashes.load(Ordering::SeqCst),
         }
     }
+
+    /// Returns whether the game has asked for the full static state
+    /// to be requested from the server since this was last called.
+    pub fn take_state_request(&self) -> bool {
+        self.request_state.swap(false, Ordering::SeqCst)
+    }
 }
 
 impl SettingsHandle {
@@ -165,12 +383,20 @@ impl Game {
         round_duration: f32,
         settings: GameSettings,
         player_id: PlayerId,
+        hue: f32,
+        seed: u64,
         cursor: Point2<f32>,
     ) -> (Game, GameHandle) {
+        let now = Instant::now();
+        let mut history = VecDeque::with_capacity(SNAPSHOT_HISTORY_LENGTH);
+        history.push_back((snapshot.clone(), now));
         let mut snapshots = VecDeque::new();
-        snapshots.push_back((snapshot, Instant::now()));
+        snapshots.push_back((snapshot, now));
         let (events_tx, events_rx) = channel::bounded(16);
         let cursor = Arc::new(Mutex::new(cursor));
+        let charging = Arc::new(AtomicBool::new(false));
+        let dashes = Arc::new(AtomicU32::new(0));
+        let request_state = Arc::new(AtomicBool::new(false));
         let settings_handle = Arc::new(SettingsHandle {
             dirty: AtomicBool::new(false),
             settings: Mutex::new(settings),
@@ -178,18 +404,38 @@ impl Game {
         let game = Game {
             players,
             snapshots,
+            history,
+            scrub: None,
+            playback: PlaybackClock::new(now),
             cursor: cursor.clone(),
+            charging: Arc::clone(&charging),
+            dashes: Arc::clone(&dashes),
+            dash_cooldown: 0.0,
             events: events_rx,
+            request_state: Arc::clone(&request_state),
+            last_state_request: None,
             round,
             last_round: None,
             round_duration,
+            respawns: HashMap::new(),
             player_id,
+            hue,
+            seed,
+            stats: SessionStats::default(),
+            game_events: Vec::new(),
+            pings: HashMap::new(),
+            messages: VecDeque::new(),
+            relayed: None,
+            paused: None,
             settings,
             settings_handle: Arc::clone(&settings_handle),
         };
         let handle = GameHandle {
             cursor,
+            charging,
+            dashes,
             events: events_tx,
+            request_state,
             settings: settings_handle,
         };
         (game, handle)
@@ -201,7 +447,10 @@ impl Game {
 
     /// Modifies the game settings and flags the change to be sent to
     /// the server.
+    ///
+    /// The settings are clamped into valid ranges first.
     pub fn set_settings(&mut self, settings: GameSettings) {
+        let settings = settings.clamp();
         self.settings = settings;
         let mut shared = self.settings_handle.settings.lock();
         *shared = settings;
@@ -211,52 +460,329 @@ impl Game {
 
     /// Handles events from the server.
     pub fn handle_events(&mut self) {
-        for event in self.events.try_iter() {
-            match event {
-                Event::RoundState(round) => {
-                    info!("transitioning to round state {:?}", round);
-                    self.last_round = Some(self.round);
-                    self.round_duration = 0.0;
-                    self.round = round;
-                },
-                Event::Settings(settings) => {
-                    self.settings = settings;
-                },
-                Event::NewPlayer {
-                    id,
-                    static_state,
-                } => {
-                    info!("new player {}", id);
-                    self.players.insert(id, static_state);
-                },
-                Event::RemovePlayer(id) => {
-                    info!("removing player {}", id);
-                    if self.players.remove(&id).is_none() {
-                        warn!(
-                            "attempting to remove player that was never added \
-                             ({})",
-                            id
-                        );
+        let events = self.events.try_iter().collect::<Vec<_>>();
+        for event in events {
+            self.handle_event(event, Instant::now());
+        }
+    }
+
+    /// Handles a single event from the server, which arrived at `now`.
+    ///
+    /// Replays call this directly with the time each event was
+    /// recorded at, rather than going through a `GameHandle`.
+    pub fn handle_event(&mut self, event: Event, now: Instant) {
+        if let Some(ref mut relayed) = self.relayed {
+            relayed.push(event.clone());
+        }
+        match event {
+            Event::RoundState(round) => {
+                info!("transitioning to round state {:?}", round);
+                self.last_round = Some(self.round);
+                self.round_duration = 0.0;
+                self.round = round;
+                match round {
+                    RoundState::Round => {
+                        self.game_events.push(GameEvent::RoundStart)
+                    },
+                    RoundState::RoundEnd => {
+                        self.game_events.push(GameEvent::RoundEnd)
+                    },
+                    RoundState::Winner(winner) => {
+                        if let Some(id) = winner {
+                            if let Some(player) = self.players.get_mut(&id) {
+                                player.wins += 1;
+                            }
+                            if id == self.player_id {
+                                self.stats.wins += 1;
+                            }
+                        }
+                        self.game_events.push(GameEvent::Winner(winner));
+                    },
+                    RoundState::Waiting => {
+                        if let Some(RoundState::Winner(_)) = self.last_round {
+                            self.game_events.push(GameEvent::NextRound);
+                        }
+                    },
+                    _ => (),
+                }
+            },
+            Event::Settings(settings) => {
+                self.settings = settings;
+            },
+            Event::NewPlayer {
+                id,
+                static_state,
+            } => {
+                info!("new player {}", id);
+                self.players.insert(id, static_state);
+            },
+            Event::RemovePlayer {
+                id,
+                reason,
+            } => {
+                info!("removing player {} ({:?})", id, reason);
+                if self.players.remove(&id).is_none() {
+                    warn!(
+                        "attempting to remove player that was never added ({})",
+                        id
+                    );
+                }
+            },
+            Event::Respawn(id) => {
+                info!("player {} respawned", id);
+                self.respawns.insert(id, 0.0);
+                self.game_events.push(GameEvent::Respawn(id));
+            },
+            Event::Afk(id) => {
+                info!("player {} was killed for being idle", id);
+                if id == self.player_id {
+                    self.stats.deaths += 1;
+                }
+                self.game_events.push(GameEvent::Afk(id));
+            },
+            Event::Kill {
+                killer,
+                victim,
+            } => {
+                if victim == self.player_id {
+                    self.stats.deaths += 1;
+                } else if killer == self.player_id {
+                    self.stats.kills += 1;
+                }
+                self.game_events.push(GameEvent::Kill {
+                    killer,
+                    victim,
+                });
+            },
+            Event::Snapshot(snapshot) => {
+                let mut snapshot = snapshot.decode();
+                // Far away players are left out of some snapshots,
+                // so they're held where they were last seen.
+                if let Some((last, _)) = self.snapshots.back() {
+                    for (&id, &state) in &last.players {
+                        if self.players.contains_key(&id) {
+                            snapshot.players.entry(id).or_insert(state);
+                        }
                     }
-                },
-                Event::Snapshot(snapshot) => {
-                    self.snapshots.push_back((snapshot, Instant::now()));
-                },
-            }
+                }
+                // If the snapshot has players we've never heard
+                // of, the events adding them must have been lost.
+                let unknown = snapshot
+                    .players
+                    .keys()
+                    .any(|id| !self.players.contains_key(id));
+                if unknown {
+                    self.request_state();
+                }
+                if let Some((last, _)) = self.snapshots.back() {
+                    detect_collisions(
+                        last,
+                        &snapshot,
+                        &self.settings,
+                        &mut self.game_events,
+                    );
+                }
+                // The history holds still while it's being stepped
+                // through.
+                if self.scrub.is_none() {
+                    if self.history.len() == SNAPSHOT_HISTORY_LENGTH {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back((snapshot.clone(), now));
+                }
+                self.snapshots.push_back((snapshot, now));
+            },
+            Event::PlayerPing(pings) => {
+                self.pings = pings;
+            },
+            Event::StaticState(players) => {
+                info!("received full static state");
+                self.players = players;
+            },
+            Event::ResetScores => {
+                info!("scores were reset");
+                for player in self.players.values_mut() {
+                    player.wins = 0;
+                }
+            },
+            Event::Message(message) => {
+                info!("server says: {}", message);
+                self.messages.push_back((message, 0.0));
+            },
+            Event::Paused(true) => {
+                info!("game paused");
+                self.paused.get_or_insert(now);
+            },
+            Event::Paused(false) => {
+                info!("game resumed");
+                if let Some(since) = self.paused.take() {
+                    // Pick playback up where it stopped, rather
+                    // than interpolating across the whole pause.
+                    let pause = now.duration_since(since);
+                    for (_, time) in &mut self.snapshots {
+                        *time += pause;
+                    }
+                    self.playback.time += pause;
+                }
+            },
+        }
+    }
+
+    /// Flags the full static state to be requested from the server,
+    /// unless it was already requested recently.
+    fn request_state(&mut self) {
+        let now = Instant::now();
+        let recent = self
+            .last_state_request
+            .map(|last| now.duration_since(last) < STATE_REQUEST_INTERVAL)
+            .unwrap_or(false);
+        if !recent {
+            warn!("snapshot contains unknown players, requesting state");
+            self.last_state_request = Some(now);
+            self.request_state.store(true, Ordering::SeqCst);
         }
     }
 
     /// Steps client prediction forward in time.
     pub fn tick(&mut self, dt: f32) {
-        self.round_duration += dt;
+        if self.paused.is_none() {
+            self.round_duration += dt;
+            self.stats.play_time += dt;
+            self.dash_cooldown = (self.dash_cooldown - dt).max(0.0);
+        }
+        for time in self.respawns.values_mut() {
+            *time += dt;
+        }
+        self.respawns.retain(|_, &mut time| time < RESPAWN_FADE_TIME);
+        for (_, time) in &mut self.messages {
+            *time += dt;
+        }
+        while let Some(&(_, time)) = self.messages.front() {
+            if time < MESSAGE_TIME {
+                break;
+            }
+            self.messages.pop_front();
+        }
         self.handle_events();
     }
 
+    /// Gets the id of this client's player.
+    pub fn player_id(&self) -> PlayerId {
+        self.player_id
+    }
+
+    /// Gets the hue from 0 to 1 that the server assigned to this
+    /// client's player.
+    pub fn hue(&self) -> f32 {
+        self.hue
+    }
+
+    /// Gets the seed the server's game draws its random numbers from,
+    /// which along with everyone's input is enough to play the match
+    /// out again.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Gets the latest round trip time in milliseconds for a player,
+    /// if the server has reported it yet.
+    pub fn ping(&self, id: PlayerId) -> Option<u16> {
+        self.pings.get(&id).cloned()
+    }
+
+    /// Returns whether the server has paused the game.
+    pub fn paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Gets the messages from the server that should still be shown,
+    /// oldest first.
+    pub fn messages(&self) -> impl Iterator<Item = &str> {
+        self.messages.iter().map(|(message, _)| message.as_str())
+    }
+
+    /// Takes every game event that happened since this was last
+    /// called.
+    pub fn drain_events(&mut self) -> impl Iterator<Item = GameEvent> + '_ {
+        self.game_events.drain(..)
+    }
+
+    /// Gets the statistics for this client's player since
+    /// connecting.
+    pub fn session_stats(&self) -> SessionStats {
+        self.stats
+    }
+
+    /// Gets how far faded in a player is, from `0` right after
+    /// respawning to `1` once fully visible.
+    pub fn respawn_fade(&self, id: PlayerId) -> f32 {
+        self.respawns
+            .get(&id)
+            .map(|&time| time / RESPAWN_FADE_TIME)
+            .unwrap_or(1.0)
+    }
+
     /// Updates the cursor position for this client player.
     pub fn update_cursor(&self, cursor: Point2<f32>) {
         *self.cursor.lock() = cursor;
     }
 
+    /// Gets the cursor position last given to `update_cursor`.
+    pub fn cursor(&self) -> Point2<f32> {
+        *self.cursor.lock()
+    }
+
+    /// Sets whether this client player is charging up their ball.
+    pub fn update_charging(&self, charging: bool) {
+        self.charging.store(charging, Ordering::SeqCst);
+    }
+
+    pub fn charging(&self) -> bool {
+        self.charging.load(Ordering::SeqCst)
+    }
+
+    /// Jumps this client player's cursor `DASH_DISTANCE` towards
+    /// `direction`, returning where it ended up, or `None` if the dash
+    /// is still cooling down.
+    pub fn dash(&mut self, direction: Vector2<f32>) -> Option<Point2<f32>> {
+        if self.dash_cooldown > 0.0 || direction == Vector2::zeros() {
+            return None;
+        }
+        let cursor = self.cursor() + direction.normalize() * DASH_DISTANCE;
+        let cursor = clamp_cursor(cursor, &self.settings);
+        self.update_cursor(cursor);
+        self.dash_cooldown = DASH_COOLDOWN;
+        self.dashes.fetch_add(1, Ordering::SeqCst);
+        Some(cursor)
+    }
+
+    /// Gets how much of the dash cooldown is left, from 1 right after
+    /// dashing to 0 once this client player can dash again.
+    pub fn dash_cooldown(&self) -> f32 {
+        self.dash_cooldown / DASH_COOLDOWN
+    }
+
+    /// Starts keeping a copy of every event from the server, to be
+    /// taken with `drain_relayed`.
+    pub fn keep_relayed(&mut self) {
+        self.relayed.get_or_insert_with(Vec::new);
+    }
+
+    /// Takes the events from the server handled since this was last
+    /// called, in the order they arrived.
+    pub fn drain_relayed(&mut self) -> Vec<Event> {
+        match self.relayed {
+            Some(ref mut relayed) => mem::replace(relayed, Vec::new()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Gets the most recent snapshot from the server.
+    pub fn latest_snapshot(&self) -> &Snapshot {
+        let (snapshot, _) = &self.snapshots[self.snapshots.len() - 1];
+        snapshot
+    }
+
     /// Returns the set of players corresponding to the most recent
     /// snapshot.
     pub fn latest_players(&self) -> Players<&Snapshot> {
@@ -264,55 +790,125 @@ impl Game {
         Players {
             players: &self.players,
             snapshot,
-            predicted: None,
+            predicted: SmallVec::new(),
         }
     }
 
-    /// Removes any old snapshots that are no longer needed for
-    /// interpolation.
-    pub fn clean_old_snapshots(&mut self, time: Instant, delay: f32) {
-        let delayed_time = time - SNAPSHOT_RATE.mul_f64(delay.into());
+    /// Advances the interpolation playback clock towards a target
+    /// delay, and removes any old snapshots that are no longer needed
+    /// for interpolation.
+    ///
+    /// This should be called once per frame, before
+    /// `interpolated_players`.
+    pub fn update_playback(&mut self, time: Instant, delay: f32) {
+        if self.paused.is_some() {
+            // Hold still, without counting the frame towards the next
+            // advance.
+            self.playback.last_update = time;
+            return;
+        }
+        let target = time - SNAPSHOT_RATE.mul_f64(delay.into());
+        self.playback.advance(time, target);
+
+        let delayed_time = self.playback.time;
         while self.snapshots.len() > 1 && delayed_time > self.snapshots[1].1 {
             // Yay for short circuiting &&
             self.snapshots.pop_front();
         }
     }
 
-    /// Interpolates snapshots with delay and returns the resulting
-    /// set of player states.
+    /// Gets how far the playback time is between the two oldest
+    /// snapshots, from 0 to 1.
+    pub fn interpolation_alpha(&self) -> f32 {
+        let delayed_time = self.playback.time;
+        let old_time = self.snapshots[0].1;
+        match self.snapshots.get(1) {
+            Some(&(_, new_time)) if delayed_time > old_time => {
+                let span = new_time.duration_since(old_time);
+                let alpha = delayed_time
+                    .duration_since(old_time)
+                    .div_duration_f32(span);
+                // If delayed_time is newer than both of the
+                // snapshots, one of them would have been removed
+                // earlier, so alpha should always be [0, 1].
+                debug_assert!(alpha < 1.0);
+                alpha
+            },
+            _ => 0.0,
+        }
+    }
+
+    /// Number of snapshots buffered for interpolation.
+    pub fn snapshot_count(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Iterates over recently received snapshots along with when each
+    /// one arrived, oldest first.
+    pub fn snapshot_history(
+        &self,
+    ) -> impl Iterator<Item = (&Snapshot, Instant)> + '_ {
+        self.history.iter().map(|(snapshot, time)| (snapshot, *time))
+    }
+
+    /// Freezes playback on the snapshot at `index` in the history, or
+    /// goes back to playing normally for `None`.
+    ///
+    /// No new snapshots are added to the history while it's frozen,
+    /// so that indices keep pointing at the same ones.
+    pub fn scrub(&mut self, index: Option<usize>) {
+        self.scrub = index.map(|index| index.min(self.history.len() - 1));
+    }
+
+    /// Interpolates snapshots at the playback time and returns the
+    /// resulting set of player states.
     pub fn interpolated_players(
         &self,
-        time: Instant,
         cursor: Point2<f32>,
-        delay: f32,
+        mode: InterpolationMode,
     ) -> Players<InterpolatedSnapshot> {
-        let delayed_time = time - SNAPSHOT_RATE.mul_f64(delay.into());
+        self.interpolated_players_for(&[(self.player_id, cursor)], mode)
+    }
 
-        let (ref old, old_time) = self.snapshots[0];
+    /// Like `interpolated_players`, but predicts the cursor of every
+    /// local player given, for when several share this window.
+    ///
+    /// Players who aren't in the snapshot yet are left out.
+    pub fn interpolated_players_for(
+        &self,
+        cursors: &[(PlayerId, Point2<f32>)],
+        mode: InterpolationMode,
+    ) -> Players<InterpolatedSnapshot> {
+        if let Some(index) = self.scrub {
+            // Show exactly what was received, without predicting.
+            let (ref frozen, _) = self.history[index];
+            return Players {
+                players: &self.players,
+                snapshot: InterpolatedSnapshot::new(0.0, mode, frozen, frozen),
+                predicted: SmallVec::new(),
+            };
+        }
+        let alpha = self.interpolation_alpha();
+        let (ref old, _) = self.snapshots[0];
         let snapshot = match self.snapshots.get(1) {
-            Some(&(ref new, new_time)) => {
-                let alpha = if delayed_time > old_time {
-                    let span = new_time.duration_since(old_time);
-                    delayed_time.duration_since(old_time).div_duration_f32(span)
-                } else {
-                    0.0
-                };
-                // If delayed_time is newer than both of the
-                // snapshots, one of them would have been removed
-                // earlier, so alpha should always be [0, 1].
-                debug_assert!(alpha < 1.0);
-                InterpolatedSnapshot::new(alpha as f32, old, new)
-            },
-            None => InterpolatedSnapshot::new(0.0, old, old),
+            Some((new, _)) => InterpolatedSnapshot::new(alpha, mode, old, new),
+            None => InterpolatedSnapshot::new(0.0, mode, old, old),
         };
 
-        let mut predicted = *snapshot.get(self.player_id).unwrap();
-        predicted.set_cursor(cursor);
+        let predicted = cursors
+            .iter()
+            .filter(|(id, _)| self.players.contains_key(id))
+            .filter_map(|&(id, cursor)| {
+                let mut predicted = *snapshot.get(id)?;
+                predicted.set_cursor(cursor);
+                Some((id, predicted))
+            })
+            .collect();
 
         Players {
             players: &self.players,
             snapshot,
-            predicted: Some((self.player_id, predicted)),
+            predicted,
         }
     }
 }
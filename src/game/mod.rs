@@ -1,24 +1,99 @@
+use crate::game::quantize::QuantizedSnapshot;
 use crate::graphics::Circle;
 use nalgebra::{self, Point2, Vector2};
-use palette::LinSrgb;
+use palette::{LabHue, Lch, LinSrgb};
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
-use std::iter;
+use std::collections::HashMap;
 use std::ops::Deref;
 
 pub mod client;
 pub mod physics;
+pub mod quantize;
+pub mod replay;
 pub mod server;
 pub mod snapshot;
+pub mod tutorial;
 
 pub use self::snapshot::*;
 
 pub type PlayerId = u16;
 
+/// Maximum number of characters in a player name.
+pub const MAX_NAME_LENGTH: usize = 24;
+
+/// Maximum number of characters in a message from the server.
+pub const MAX_MESSAGE_LENGTH: usize = 200;
+
+/// Seconds spent in `RoundState::Waiting` before a round starts.
+pub const WAITING_DURATION: f32 = 3.0;
+
+/// Gets the player color for a hue from 0 to 1.
+pub fn hue_color(hue: f32) -> LinSrgb {
+    Lch::new(75.0, 80.0, LabHue::from_degrees(hue * 360.0)).into()
+}
+
+/// Set of hues the server assigns players.
+///
+/// The restricted palettes leave out hues that look alike with that
+/// kind of color blindness, at the cost of running out of distinct
+/// colors sooner.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Palette {
+    /// Any hue.
+    Full,
+    /// Without green-red pairs, for weak green perception.
+    Deuteranopia,
+    /// Without green-red pairs, for weak red perception, which also
+    /// makes reds look darker.
+    Protanopia,
+    /// Without blue-green and yellow-violet pairs.
+    Tritanopia,
+}
+
+impl Palette {
+    /// Gets the hues from 0 to 1 that this palette is limited to, or
+    /// nothing if it isn't limited.
+    pub fn hues(self) -> &'static [f32] {
+        match self {
+            Palette::Full => &[],
+            // Blue, yellow, sky blue, orange and purple.
+            Palette::Deuteranopia => &[0.72, 0.25, 0.58, 0.12, 0.87],
+            // Like deuteranopia, but away from red, which looks dark.
+            Palette::Protanopia => &[0.72, 0.25, 0.56, 0.16, 0.84],
+            // Red, teal, pink and orange.
+            Palette::Tritanopia => &[0.08, 0.5, 0.93, 0.17],
+        }
+    }
+}
+
+/// Mass of an uncharged ball.
+pub const DEFAULT_BALL_MASS: f32 = 1.0;
+
+/// Seconds it takes to charge a ball up fully, and for it to wind back
+/// down after letting go.
+pub const CHARGE_TIME: f32 = 0.5;
+
+/// How many times bigger a fully charged ball is.
+pub const CHARGED_RADIUS: f32 = 1.5;
+
+/// How many times stronger the spring is on a fully charged ball.
+pub const CHARGED_SPRING: f32 = 2.0;
+
+/// Fastest a cursor can move while charging, in units per second.
+pub const CHARGING_CURSOR_SPEED: f32 = 1.5;
+
+/// Distance in world units that a dash jumps the cursor.
+pub const DASH_DISTANCE: f32 = 0.3;
+
+/// Seconds after dashing before the next dash.
+pub const DASH_COOLDOWN: f32 = 3.0;
+
 /// Finite state machine for the round state.
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub enum RoundState {
-    /// Less than two players, so nothing happens.
+    /// Less than two players, so players can warm up freely. Balls
+    /// are simulated, but nobody can be killed.
     Lobby,
     /// More than two players, waiting for a round to start.
     Waiting,
@@ -31,6 +106,7 @@ pub enum RoundState {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct GameSettings {
     pub ball_radius: f32,
     pub cursor_radius: f32,
@@ -39,6 +115,31 @@ pub struct GameSettings {
     pub ball_start_speed: f32,
     pub bounds_radius: f32,
     pub kill_own_cursor: bool,
+    /// Fraction of ball velocity lost per second.
+    pub damping: f32,
+    /// Coefficient of restitution for ball collisions, with `1`
+    /// being perfectly elastic.
+    pub restitution: f32,
+    /// Pull towards the middle of the arena, in units per second
+    /// squared for each unit away from it, or a push away from the
+    /// middle when negative.
+    pub gravity: f32,
+    /// Swirl around the middle of the arena, in the same units as
+    /// `gravity`, which turns clockwise on screen when positive.
+    pub vortex: f32,
+    /// Seconds before dead players respawn at a random safe position,
+    /// or `None` for them to stay dead until the round ends.
+    pub respawn_delay: Option<f32>,
+    /// Seconds a round can go on for while players respawn, after
+    /// which whoever got the most kills wins, or `None` for no limit.
+    /// Without respawning, rounds always go on until one player is
+    /// left.
+    pub round_time_limit: Option<f32>,
+    /// Seconds a player's cursor can stay still during a round before
+    /// they're killed for being idle, or `None` to never.
+    pub afk_timeout: Option<f32>,
+    /// Hues that new players are given.
+    pub palette: Palette,
 }
 
 impl Default for GameSettings {
@@ -51,10 +152,123 @@ impl Default for GameSettings {
             ball_start_speed: 1.0,
             bounds_radius: 1.0,
             kill_own_cursor: false,
+            damping: 0.0,
+            restitution: 1.0,
+            gravity: 0.0,
+            vortex: 0.0,
+            respawn_delay: None,
+            round_time_limit: None,
+            afk_timeout: Some(30.0),
+            palette: Palette::Full,
         }
     }
 }
 
+/// Clamps a value into a range.
+///
+/// `f32::max` returns the other argument when one is NaN, so NaN gets
+/// mapped to `min`.
+fn clamp_range(value: f32, min: f32, max: f32) -> f32 {
+    value.max(min).min(max)
+}
+
+impl GameSettings {
+    /// Returns whether all of the settings are within sane ranges.
+    pub fn validate(&self) -> bool {
+        *self == self.clamp()
+    }
+
+    /// Returns a copy of the settings with every field clamped into a
+    /// range that won't break the physics.
+    ///
+    /// Settings come in verbatim from the network, so this needs to
+    /// be applied before they are used for anything.
+    pub fn clamp(&self) -> GameSettings {
+        let bounds_radius = clamp_range(self.bounds_radius, 0.1, 10.0);
+        // Both the ball and the cursor have to fit inside the
+        // bounds, otherwise they're permanently colliding with it.
+        let max_radius = 0.5 * bounds_radius;
+        GameSettings {
+            ball_radius: clamp_range(self.ball_radius, 0.01, max_radius),
+            cursor_radius: clamp_range(self.cursor_radius, 0.01, max_radius),
+            spring_constant: clamp_range(self.spring_constant, 0.0, 100.0),
+            ball_start_distance: clamp_range(
+                self.ball_start_distance,
+                0.0,
+                bounds_radius,
+            ),
+            ball_start_speed: clamp_range(self.ball_start_speed, 0.0, 10.0),
+            bounds_radius,
+            kill_own_cursor: self.kill_own_cursor,
+            damping: clamp_range(self.damping, 0.0, 0.95),
+            restitution: clamp_range(self.restitution, 0.0, 1.0),
+            gravity: clamp_range(self.gravity, -10.0, 10.0),
+            vortex: clamp_range(self.vortex, -10.0, 10.0),
+            respawn_delay: self
+                .respawn_delay
+                .map(|delay| clamp_range(delay, 0.5, 30.0)),
+            round_time_limit: self
+                .round_time_limit
+                .map(|limit| clamp_range(limit, 10.0, 3600.0)),
+            afk_timeout: self
+                .afk_timeout
+                .map(|timeout| clamp_range(timeout, 5.0, 600.0)),
+            palette: self.palette,
+        }
+    }
+
+    /// Gets the acceleration that the gravity and vortex fields give
+    /// a ball at `position`.
+    pub fn field_acceleration(&self, position: Point2<f32>) -> Vector2<f32> {
+        let offset = position.coords;
+        let around = Vector2::new(-offset.y, offset.x);
+        around * self.vortex - offset * self.gravity
+    }
+}
+
+#[test]
+fn settings_default_valid() {
+    assert!(GameSettings::default().validate());
+}
+
+#[test]
+fn settings_clamp_invalid() {
+    let settings = GameSettings {
+        ball_radius: -1.0,
+        cursor_radius: std::f32::NAN,
+        bounds_radius: 0.0,
+        ball_start_speed: std::f32::INFINITY,
+        ..GameSettings::default()
+    };
+    assert!(!settings.validate());
+    let clamped = settings.clamp();
+    assert!(clamped.validate());
+    assert_eq!(clamped.ball_radius, 0.01);
+    assert_eq!(clamped.cursor_radius, 0.01);
+    assert_eq!(clamped.bounds_radius, 0.1);
+    assert_eq!(clamped.ball_start_speed, 10.0);
+}
+
+#[test]
+fn fields_pull_and_swirl() {
+    let settings = GameSettings {
+        gravity: 2.0,
+        ..GameSettings::default()
+    };
+    let position = Point2::new(0.5, 0.0);
+    let pull = settings.field_acceleration(position);
+    assert_eq!(pull, Vector2::new(-1.0, 0.0));
+    assert_eq!(settings.field_acceleration(Point2::origin()), Vector2::zeros());
+
+    let settings = GameSettings {
+        vortex: 2.0,
+        ..GameSettings::default()
+    };
+    // Down on screen is positive y, so this is clockwise.
+    let swirl = settings.field_acceleration(position);
+    assert_eq!(swirl, Vector2::new(0.0, 1.0));
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Event {
     RoundState(RoundState),
@@ -63,13 +277,64 @@ pub enum Event {
         id: PlayerId,
         static_state: StaticPlayerState,
     },
-    RemovePlayer(PlayerId),
-    Snapshot(Snapshot),
+    RemovePlayer {
+        id: PlayerId,
+        reason: LeaveReason,
+    },
+    /// A dead player came back to life mid-round.
+    Respawn(PlayerId),
+    /// A player was killed for leaving their cursor still for too
+    /// long, and won't respawn until the next round.
+    Afk(PlayerId),
+    /// A player died, either to another player's ball or by hitting
+    /// the wall, in which case `killer` is the same as `victim`.
+    Kill {
+        killer: PlayerId,
+        victim: PlayerId,
+    },
+    Snapshot(QuantizedSnapshot),
+    /// Latest round trip time in milliseconds for every player,
+    /// broadcast periodically.
+    PlayerPing(HashMap<PlayerId, u16>),
+    /// Static state for every player, replacing whatever the client
+    /// currently has.
+    ///
+    /// This is sent in response to `ClientPacket::RequestState`.
+    StaticState(HashMap<PlayerId, StaticPlayerState>),
+    /// Every player's win count was set back to zero.
+    ResetScores,
+    /// Announcement from whoever is running the server.
+    Message(String),
+    /// The game was frozen or unfrozen by whoever is running the
+    /// server.
+    Paused(bool),
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+/// Why a player was removed from the game.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum LeaveReason {
+    /// The player disconnected on purpose.
+    Left,
+    /// The connection was lost without the player leaving, from a
+    /// timeout or an error.
+    Lost,
+    /// The player was kicked or banned, either by whoever is running
+    /// the server or for breaking the rules.
+    Kicked,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Input {
     pub cursor: Point2<f32>,
+    /// Whether the player is holding down the button to charge up
+    /// their ball.
+    pub charging: bool,
+    /// Number of times the player has dashed since connecting.
+    ///
+    /// Input isn't sent reliably, so this is a count rather than a
+    /// flag, which keeps a dash from being missed when the packet
+    /// carrying it is lost.
+    pub dashes: u32,
 }
 
 /// Dynamic state for the large ball.
@@ -77,12 +342,18 @@ pub struct Input {
 pub struct Ball {
     pub position: Point2<f32>,
     pub velocity: Vector2<f32>,
+    /// How far the ball is charged up, from 0 to 1, which makes it
+    /// bigger and pulls it harder towards the cursor.
+    pub charge: f32,
 }
 
 /// Static player state that is unlikely to change between frames.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StaticPlayerState {
     pub color: LinSrgb,
+    pub name: String,
+    /// Number of rounds won since joining.
+    pub wins: u32,
 }
 
 /// Dynamic player state that is likely to change between frames.
@@ -109,9 +380,34 @@ impl Ball {
         Ball {
             position,
             velocity: cursor_dir * settings.ball_start_speed,
+            charge: 0.0,
         }
     }
 
+    /// Gets the radius of the ball, which grows as it's charged up.
+    pub fn radius(&self, settings: &GameSettings) -> f32 {
+        settings.ball_radius * (1.0 + (CHARGED_RADIUS - 1.0) * self.charge)
+    }
+
+    /// Gets the mass of the ball for collisions with other balls,
+    /// which grows with its area as it's charged up.
+    pub fn mass(&self) -> f32 {
+        let scale = 1.0 + (CHARGED_RADIUS - 1.0) * self.charge;
+        DEFAULT_BALL_MASS * scale * scale
+    }
+
+    /// Charges the ball up over `dt` seconds if `charging`, or lets it
+    /// wind back down otherwise.
+    pub fn update_charge(&mut self, dt: f32, charging: bool) {
+        let change = dt / CHARGE_TIME;
+        let charge = if charging {
+            self.charge + change
+        } else {
+            self.charge - change
+        };
+        self.charge = charge.max(0.0).min(1.0);
+    }
+
     /// Steps the ball forward in time using a provided cursor
     /// location.
     pub fn tick(
@@ -122,12 +418,33 @@ impl Ball {
     ) {
         if let Some(cursor) = cursor {
             let displacement = self.position - cursor;
-            self.velocity -= settings.spring_constant * displacement * dt;
+            let spring = settings.spring_constant *
+                (1.0 + (CHARGED_SPRING - 1.0) * self.charge);
+            self.velocity -= spring * displacement * dt;
         }
+        self.velocity += settings.field_acceleration(self.position) * dt;
+        // Exponential decay, so the damping is independent of the
+        // timestep.
+        self.velocity *= (1.0 - settings.damping).powf(dt);
         self.position += self.velocity * dt;
     }
 }
 
+/// Moves a cursor towards `target`, but no further than
+/// `max_distance`.
+pub fn approach_cursor(
+    cursor: Point2<f32>,
+    target: Point2<f32>,
+    max_distance: f32,
+) -> Point2<f32> {
+    let delta = target - cursor;
+    if delta.norm() > max_distance {
+        cursor + delta.normalize() * max_distance
+    } else {
+        target
+    }
+}
+
 /// Clamps a cursor position within bounds.
 pub fn clamp_cursor(
     cursor: Point2<f32>,
@@ -192,7 +509,8 @@ pub trait GetPlayer {
         // Ball
         circles.push(Circle {
             center: state.ball.position * scale,
-            radius: settings.ball_radius * scale,
+            radius: state.ball.radius(settings) * scale,
+            inner_radius: 0.0,
             color,
         });
         if let Some(cursor) = state.cursor {
@@ -200,6 +518,7 @@ pub trait GetPlayer {
             circles.push(Circle {
                 center: cursor * scale,
                 radius: settings.cursor_radius * scale,
+                inner_radius: 0.0,
                 color,
             });
         }
@@ -222,14 +541,13 @@ impl RoundState {
             _ => false,
         }
     }
-}
 
-/// Steps over a given time interval in chunks of at most a specified duration.
-///
-/// This is useful for things that will be stable at small timesteps,
-/// but break down over larger timesteps. In order to step a large
-/// timestep, it needs to be broken up and stepped in sequence.
-pub fn step_dt(dt: f32, max: f32) -> impl Iterator<Item = f32> {
-    let times = (dt / max) as usize;
-    iter::repeat(max).take(times).chain(iter::once(dt % max))
+    /// Whether balls are simulated in this state without anyone being
+    /// able to die.
+    pub fn warm_up(self) -> bool {
+        match self {
+            RoundState::Lobby => true,
+            _ => false,
+        }
+    }
 }
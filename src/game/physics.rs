@@ -1,11 +1,19 @@
-use crate::game::{Ball, GameSettings};
+use crate::game::{Ball, GameSettings, DEFAULT_BALL_MASS};
 use nalgebra::{Point2, Vector2};
+use std::cmp::Ordering;
+
+/// Largest multiple of the velocities that `resolve_penetration` will
+/// move circles back by before deciding something's wrong.
+const MAX_REWIND: f32 = 0.05;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Circle<V> {
     pub radius: f32,
     pub center: Point2<f32>,
     pub velocity: V,
+    /// Mass of the circle, which only matters for collisions between
+    /// two dynamic circles.
+    pub mass: f32,
     /// `true` indicates collision with the inside edge, `false` with
     /// the outside edge.
     pub inner: bool,
@@ -18,6 +26,7 @@ impl<V> Circle<V> {
             radius,
             center,
             velocity,
+            mass: DEFAULT_BALL_MASS,
             inner: true,
         }
     }
@@ -28,10 +37,17 @@ impl<V> Circle<V> {
             radius,
             center,
             velocity,
+            mass: DEFAULT_BALL_MASS,
             inner: false,
         }
     }
 
+    /// Returns the extent of the circle along the x axis, as a pair of
+    /// the minimum and maximum x coordinates.
+    pub fn extent_x(&self) -> (f32, f32) {
+        (self.center.x - self.radius, self.center.x + self.radius)
+    }
+
     /// Returns the orientation of the normals as a scalar.
     ///
     /// `1` if outside, `-1` if inside.
@@ -44,6 +60,46 @@ impl<V> Circle<V> {
     }
 }
 
+/// Sweep and prune broadphase along the x axis.
+///
+/// Takes the extents of a set of circles (see [`Circle::extent_x`]),
+/// and returns every pair of indices `(i, j)` with `i < j` whose
+/// extents overlap. Only these pairs can possibly be colliding, so
+/// the narrowphase only needs to check them.
+///
+/// This only makes sense for circles colliding on their outer edges,
+/// since an inner circle overlaps everything inside of it.
+pub fn sweep_and_prune(extents: &[(f32, f32)]) -> Vec<(usize, usize)> {
+    let mut order = (0..extents.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        extents[a].0.partial_cmp(&extents[b].0).unwrap_or(Ordering::Equal)
+    });
+
+    let mut pairs = Vec::new();
+    // Indices of circles whose extent could still overlap with the
+    // current one.
+    let mut active: Vec<usize> = Vec::new();
+    for idx in order {
+        let (min, _) = extents[idx];
+        // Anything that ends before this circle starts can't overlap
+        // with this one, or anything after it.
+        active.retain(|&other| extents[other].1 >= min);
+        for &other in active.iter() {
+            pairs.push((other.min(idx), other.max(idx)));
+        }
+        active.push(idx);
+    }
+    pairs
+}
+
+#[test]
+fn sweep_and_prune_overlaps() {
+    let extents = [(0.0, 1.0), (2.0, 3.0), (0.5, 2.5), (4.0, 5.0)];
+    let mut pairs = sweep_and_prune(&extents);
+    pairs.sort();
+    assert_eq!(pairs, vec![(0, 2), (1, 2)]);
+}
+
 /// Returns the physics circle corresponding to the boundary.
 pub fn bounds(settings: &GameSettings) -> Circle<Static> {
     Circle::inner(settings.bounds_radius, Point2::origin(), Static)
@@ -55,15 +111,24 @@ pub fn cursor(cursor: Point2<f32>, settings: &GameSettings) -> Circle<Static> {
     Circle::outer(settings.cursor_radius, cursor, Static)
 }
 
+/// Returns the physics circle corresponding to a ball.
 pub fn ball(ball: Ball, settings: &GameSettings) -> Circle<Vector2<f32>> {
-    Circle::outer(settings.ball_radius, ball.position, ball.velocity)
+    let radius = ball.radius(settings);
+    Circle {
+        mass: ball.mass(),
+        ..Circle::outer(radius, ball.position, ball.velocity)
+    }
 }
 
+/// Gets the ball for a circle, which is uncharged since circles don't
+/// keep track of that, so the charge has to be carried over from the
+/// ball the circle came from.
 impl From<Circle<Vector2<f32>>> for Ball {
     fn from(circle: Circle<Vector2<f32>>) -> Ball {
         Ball {
             position: circle.center,
             velocity: circle.velocity,
+            charge: 0.0,
         }
     }
 }
@@ -93,12 +158,19 @@ pub trait Velocity: Sized + Copy {
     /// For a static circle, this is always 0.
     fn get(self) -> Vector2<f32>;
 
-    /// Calculates the new velocity of two circles after elastic
-    /// collision.
+    /// Calculates the new velocity of two circles after collision.
+    ///
+    /// `restitution` is the ratio of the relative velocity along the
+    /// normal after the collision to before, so `1` is a perfectly
+    /// elastic collision and `0` is perfectly inelastic.
     ///
     /// Similar to [`resolve_penetration`], at least one of the circles must
     /// be non-static.
-    fn elastic_collision(a: &mut Circle<Vector2<f32>>, b: &mut Circle<Self>);
+    fn elastic_collision(
+        a: &mut Circle<Vector2<f32>>,
+        b: &mut Circle<Self>,
+        restitution: f32,
+    );
 
     /// Offsets the position of two circles along the collision normal
     /// so that they are just touching.
@@ -113,6 +185,7 @@ impl Velocity for Vector2<f32> {
     fn elastic_collision(
         a: &mut Circle<Vector2<f32>>,
         b: &mut Circle<Vector2<f32>>,
+        restitution: f32,
     ) {
         // The 2d case is equivalent to the 1d case when projected onto
         // the normal. Also, the normal doesn't actually have to be
@@ -121,10 +194,15 @@ impl Velocity for Vector2<f32> {
         let normal = collision_normal(a, b);
         let velocity_a_n = a.velocity.dot(&normal);
         let velocity_b_n = b.velocity.dot(&normal);
-        // In the 1d case with two dynamic circles, velocities are
-        // simply exchanged, since mass is assumed to be equal.
-        let accel_a_n = velocity_b_n - velocity_a_n;
-        let accel_b_n = velocity_a_n - velocity_b_n;
+        // In the 1d case with two dynamic circles, each circle picks
+        // up `1 + restitution` times the velocity difference, weighted
+        // by the other circle's share of the total mass. With equal
+        // masses and a perfectly elastic collision, this just
+        // exchanges the velocities.
+        let total_mass = a.mass + b.mass;
+        let impulse = (1.0 + restitution) / total_mass;
+        let accel_a_n = impulse * b.mass * (velocity_b_n - velocity_a_n);
+        let accel_b_n = impulse * a.mass * (velocity_a_n - velocity_b_n);
 
         a.velocity += accel_a_n * normal;
         b.velocity += accel_b_n * normal;
@@ -136,9 +214,12 @@ impl Velocity for Vector2<f32> {
     ) {
         let normal = collision_normal(a, b);
         let distance = collision_distance(a, b);
-        let midpoint = 0.5 * (a.center + b.center.coords);
-        a.center = midpoint + 0.5 * distance * normal;
-        b.center = midpoint - 0.5 * distance * normal;
+        // Split the offset around the center of mass, so that the
+        // heavier circle gets pushed less.
+        let total_mass = a.mass + b.mass;
+        let center = a.center + (b.center - a.center) * (b.mass / total_mass);
+        a.center = center + (b.mass / total_mass) * distance * normal;
+        b.center = center - (a.mass / total_mass) * distance * normal;
     }
 }
 
@@ -147,14 +228,18 @@ impl Velocity for Static {
         Vector2::new(0.0, 0.0)
     }
 
-    fn elastic_collision(a: &mut Circle<Vector2<f32>>, b: &mut Circle<Static>) {
+    fn elastic_collision(
+        a: &mut Circle<Vector2<f32>>,
+        b: &mut Circle<Static>,
+        restitution: f32,
+    ) {
         let normal = (a.center - b.center).normalize() *
             a.orientation() *
             b.orientation();
         let velocity_n = a.velocity.dot(&normal);
         // In the 1d case with one static circle, the dynamic circle
-        // velocity inverts.
-        let accel_n = -2.0 * velocity_n;
+        // velocity inverts, scaled down by the restitution.
+        let accel_n = -(1.0 + restitution) * velocity_n;
 
         a.velocity += accel_n * normal;
     }
@@ -166,23 +251,25 @@ impl Velocity for Static {
     }
 }
 
-/// Fully resolves a potential collision between two circles.
+/// Fully resolves a potential collision between two circles, with a
+/// given coefficient of restitution.
 ///
 /// Returns whether or there was a collision.
 pub fn resolve_collision<V: Velocity + std::fmt::Debug>(
     a: &mut Circle<Vector2<f32>>,
     b: &mut Circle<V>,
+    restitution: f32,
 ) -> bool {
     let collision = check_collision(a, b);
     if collision {
         // Step backward along velocity vectors until the circles are
         // no longer colliding.
         let t = match resolve_penetration(a, b) {
-            Some(t) => t,
-            None => return true,
+            Penetration::Rewound(t) => t,
+            _ => return true,
         };
         // Determine new velocities after collision.
-        Velocity::elastic_collision(a, b);
+        Velocity::elastic_collision(a, b, restitution);
         // Redo the backward movement with the new velocities.
         a.center -= t * a.velocity.get();
         b.center -= t * b.velocity.get();
@@ -190,6 +277,19 @@ pub fn resolve_collision<V: Velocity + std::fmt::Debug>(
     collision
 }
 
+#[test]
+fn elastic_collision_unequal_mass() {
+    let mut a = Circle {
+        mass: 3.0,
+        ..Circle::outer(0.5, Point2::new(0.0, 0.0), Vector2::new(1.0, 0.0))
+    };
+    let mut b = Circle::outer(0.5, Point2::new(1.0, 0.0), Vector2::zeros());
+    Velocity::elastic_collision(&mut a, &mut b, 1.0);
+    // Momentum and kinetic energy are both conserved.
+    assert!((a.velocity.x - 0.5).abs() < 1e-6);
+    assert!((b.velocity.x - 1.5).abs() < 1e-6);
+}
+
 /// Check for collision between two circles.
 pub fn check_collision<V1, V2>(a: &Circle<V1>, b: &Circle<V2>) -> bool {
     // This one is very straightforward:
@@ -207,14 +307,128 @@ pub fn check_collision<V1, V2>(a: &Circle<V1>, b: &Circle<V2>) -> bool {
     }
 }
 
-/// Moves circles out of collision along their velocity vectors.
+/// Computes the time of impact between two circles moving with
+/// constant velocity.
 ///
-/// If there is no scalar multiple of the velocity vectors that will move
-/// them out of collision, it just gives up and moves along the collision
-/// normal.
+/// `a` and `b` should be the circles at the start of the interval.
+/// Returns the earliest time in `[0, dt]` at which the circles start
+/// colliding, or `None` if they don't collide within the interval.
+pub fn time_of_impact<V1: Velocity, V2: Velocity>(
+    a: &Circle<V1>,
+    b: &Circle<V2>,
+    dt: f32,
+) -> Option<f32> {
+    if check_collision(a, b) {
+        return Some(0.0);
+    }
+
+    // This is the same quadratic as in `resolve_penetration`, just
+    // looking forward instead of backward.
+    let center = a.center - b.center;
+    let velocity = a.velocity.get() - b.velocity.get();
+    let orientation = a.orientation() * b.orientation();
+    let distance = collision_distance(a, b);
+    let co_a = velocity.dot(&velocity);
+    let co_b = 2.0 * center.dot(&velocity);
+    let co_c = center.dot(&center) - distance * distance;
+
+    if co_a <= std::f32::EPSILON {
+        // Not moving relative to each other, and not colliding to
+        // begin with.
+        return None;
+    }
+    let discriminant = co_b * co_b - 4.0 * co_a * co_c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    // Outer/outer collisions start at the first root, and
+    // outer/inner collisions start at the second root.
+    let t = (-co_b - orientation * discriminant.sqrt()) / (2.0 * co_a);
+    if t >= 0.0 && t <= dt {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+/// Returns a circle moving in a straight line from `start` to the
+/// position of `a` over a timestep `dt`.
+fn swept<V>(
+    a: &Circle<V>,
+    start: Point2<f32>,
+    dt: f32,
+) -> Circle<Vector2<f32>> {
+    Circle {
+        radius: a.radius,
+        center: start,
+        velocity: (a.center - start) / dt,
+        mass: a.mass,
+        inner: a.inner,
+    }
+}
+
+/// Checks for collision between a circle that moved from `start` to
+/// it's current position over `dt` and a static circle.
+///
+/// Unlike [`check_collision`], this will catch circles that passed
+/// all the way through each other in a single timestep.
+pub fn check_collision_swept<V>(
+    a: &Circle<V>,
+    start: Point2<f32>,
+    b: &Circle<Static>,
+    dt: f32,
+) -> bool {
+    time_of_impact(&swept(a, start, dt), b, dt).is_some()
+}
+
+/// Fully resolves a potential collision between a circle that moved
+/// from `start` to it's current position over `dt` and a static
+/// circle.
+///
+/// If the path crosses into `b`, `a` is moved back to the point of
+/// impact, bounced off of `b`, and moved forward for the rest of the
+/// timestep. If the circles were already colliding at `start`, this
+/// falls back to [`resolve_collision`].
+///
+/// Returns whether or not there was a collision.
+pub fn resolve_collision_swept(
+    a: &mut Circle<Vector2<f32>>,
+    start: Point2<f32>,
+    b: &mut Circle<Static>,
+    dt: f32,
+    restitution: f32,
+) -> bool {
+    let path = swept(a, start, dt);
+    match time_of_impact(&path, b, dt) {
+        Some(t) if t > 0.0 => {
+            a.center = start + path.velocity * t;
+            Velocity::elastic_collision(a, b, restitution);
+            a.center += a.velocity * (dt - t);
+            true
+        },
+        _ => resolve_collision(a, b, restitution),
+    }
+}
+
+/// How [`resolve_penetration`] moved two circles out of collision.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Penetration {
+    /// Moved along their velocities by this multiple of them, which
+    /// is negative when moved backward.
+    Rewound(f32),
+    /// Offset along the collision normal, since no multiple of the
+    /// velocities moves them apart, like when they aren't moving.
+    NoSolution,
+    /// Offset along the collision normal, since they'd have to be
+    /// moved by more than `MAX_REWIND` times their velocities.
+    TooFar(f32),
+}
+
+/// Moves circles out of collision along their velocity vectors.
 ///
-/// Returns `Some(t)` with the multiple of the velocities, or `None` if it
-/// offset along normal.
+/// If there is no small enough scalar multiple of the velocity vectors
+/// that will move them out of collision, it just gives up and moves
+/// along the collision normal.
 ///
 /// At least one of the circles has to be non-static. This is expressed in
 /// the type system by requiring the first argument to have `Vector2<f32>`
@@ -222,7 +436,7 @@ pub fn check_collision<V1, V2>(a: &Circle<V1>, b: &Circle<V2>) -> bool {
 pub fn resolve_penetration<V: Velocity + std::fmt::Debug>(
     a: &mut Circle<Vector2<f32>>,
     b: &mut Circle<V>,
-) -> Option<f32> {
+) -> Penetration {
     // This problem can be described by the equation:
     //
     // |(a.center + t a.velocity) - (b.center + t b.velocity)| =
@@ -245,22 +459,145 @@ pub fn resolve_penetration<V: Velocity + std::fmt::Debug>(
     let co_c = center.dot(&center) - distance * distance;
 
     let discriminant = co_b * co_b - 4.0 * co_a * co_c;
-    if discriminant < 0.0 {
+    if co_a <= std::f32::EPSILON || discriminant < 0.0 {
         // Give up and move along the normal.
         Velocity::offset_collision(a, b);
-        return None;
+        return Penetration::NoSolution;
     }
     // Multiply by orientation so that it picks the furthest back
     // point in outer/outer, and the furthest forward point in
     // outer/inner.
     let t = (-co_b - orientation * discriminant.sqrt()) / (2.0 * co_a);
-    if t.abs() > 0.05 {
+    if t.abs() > MAX_REWIND {
         // There's probably something wrong...
         Velocity::offset_collision(a, b);
-        return None;
+        return Penetration::TooFar(t);
     }
 
     a.center += t * a.velocity.get();
     b.center += t * b.velocity.get();
-    Some(t)
+    Penetration::Rewound(t)
+}
+
+#[cfg(test)]
+fn kinetic_energy(circle: &Circle<Vector2<f32>>) -> f32 {
+    0.5 * circle.mass * circle.velocity.norm_squared()
+}
+
+#[test]
+fn elastic_collision_conserves_momentum_and_energy() {
+    let mut a = Circle {
+        mass: 2.0,
+        ..Circle::outer(0.1, Point2::new(0.0, 0.0), Vector2::new(1.0, 0.5))
+    };
+    let mut b =
+        Circle::outer(0.1, Point2::new(0.15, 0.1), Vector2::new(-0.5, 0.25));
+    let momentum = a.velocity * a.mass + b.velocity * b.mass;
+    let energy = kinetic_energy(&a) + kinetic_energy(&b);
+    Velocity::elastic_collision(&mut a, &mut b, 1.0);
+    let momentum_after = a.velocity * a.mass + b.velocity * b.mass;
+    assert!((momentum_after - momentum).norm() < 1e-5);
+    let energy_after = kinetic_energy(&a) + kinetic_energy(&b);
+    assert!((energy_after - energy).abs() < 1e-5);
+}
+
+#[test]
+fn inner_collision_reflects() {
+    let mut bounds = Circle::inner(1.0, Point2::origin(), Static);
+
+    // Straight into the wall, which bounces straight back.
+    let mut ball =
+        Circle::outer(0.1, Point2::new(0.0, 0.91), Vector2::new(0.0, 1.0));
+    assert!(check_collision(&ball, &bounds));
+    assert!(resolve_collision(&mut ball, &mut bounds, 1.0));
+    assert!(ball.velocity.x.abs() < 1e-5);
+    assert!((ball.velocity.y + 1.0).abs() < 1e-5);
+    assert!(!check_collision(&ball, &bounds));
+
+    // At an angle, which keeps the speed but turns back inwards.
+    let mut ball =
+        Circle::outer(0.1, Point2::new(0.3, 0.85), Vector2::new(0.5, 1.0));
+    assert!(resolve_collision(&mut ball, &mut bounds, 1.0));
+    assert!((ball.velocity.norm() - 1.25f32.sqrt()).abs() < 1e-5);
+    assert!(ball.velocity.dot(&ball.center.coords) < 0.0);
+    assert!(!check_collision(&ball, &bounds));
+}
+
+#[test]
+fn resolve_penetration_separates() {
+    let cases = [
+        // Head on, glancing, one not moving, and both not moving.
+        (Vector2::new(1.0, 0.0), Vector2::new(-1.0, 0.0), 0.15),
+        (Vector2::new(1.0, 0.5), Vector2::new(0.0, -0.5), 0.19),
+        (Vector2::new(2.0, 0.0), Vector2::zeros(), 0.1),
+        (Vector2::zeros(), Vector2::zeros(), 0.1),
+    ];
+    for &(velocity_a, velocity_b, gap) in cases.iter() {
+        let mut a = Circle::outer(0.1, Point2::origin(), velocity_a);
+        let mut b = Circle::outer(0.1, Point2::new(gap, 0.0), velocity_b);
+        assert!(check_collision(&a, &b));
+        resolve_penetration(&mut a, &mut b);
+        let distance = nalgebra::distance(&a.center, &b.center);
+        assert!(distance >= collision_distance(&a, &b) - 1e-5);
+    }
+}
+
+#[test]
+fn resolve_penetration_typical_speeds_rewind() {
+    // A ball moving a full step at a fast speed into another, which
+    // should always be moved back along its velocity.
+    let settings = GameSettings::default();
+    let dt = crate::game::server::TIMESTEP;
+    for &speed in &[0.1, 1.0, 2.0, 4.0] {
+        let velocity = Vector2::new(speed, 0.0);
+        let touching = 2.0 * settings.ball_radius;
+        let start = Point2::new(-touching, 0.0);
+        let mut a = Circle::outer(
+            settings.ball_radius,
+            start + velocity * dt,
+            velocity,
+        );
+        let mut b =
+            Circle::outer(settings.ball_radius, Point2::origin(), Static);
+        match resolve_penetration(&mut a, &mut b) {
+            Penetration::Rewound(t) => assert!((t + dt).abs() < 1e-4),
+            other => panic!("fell back at speed {}: {:?}", speed, other),
+        }
+    }
+}
+
+#[test]
+fn swept_collision_catches_tunnelling() {
+    // Fast enough to go from one side of the other ball to the other
+    // in a single step.
+    let dt = 0.01;
+    let start = Point2::new(-1.0, 0.0);
+    let a = Circle::outer(0.1, Point2::new(1.0, 0.0), Vector2::new(200.0, 0.0));
+    let b = Circle::outer(0.1, Point2::origin(), Static);
+    assert!(!check_collision(&a, &b));
+    assert!(check_collision_swept(&a, start, &b, dt));
+    let t = time_of_impact(&swept(&a, start, dt), &b, dt).unwrap();
+    assert!((t - 0.004).abs() < 1e-5);
+}
+
+#[test]
+fn swept_collision_already_overlapping() {
+    let a = Circle::outer(0.1, Point2::new(0.05, 0.0), Vector2::new(1.0, 0.0));
+    let b = Circle::outer(0.1, Point2::origin(), Static);
+    assert_eq!(time_of_impact(&a, &b, 0.01), Some(0.0));
+    assert!(check_collision_swept(&a, Point2::new(0.04, 0.0), &b, 0.01));
+}
+
+#[test]
+fn swept_collision_parallel_misses() {
+    // Moving together, so the gap between them never closes.
+    let a = Circle::outer(0.1, Point2::new(0.0, 0.5), Vector2::new(1.0, 0.0));
+    let b = Circle::outer(0.1, Point2::origin(), Vector2::new(1.0, 0.0));
+    assert_eq!(time_of_impact(&a, &b, 1.0), None);
+
+    // Passing alongside a static circle, further away than the radii.
+    let start = Point2::new(-1.0, 0.3);
+    let a = Circle::outer(0.1, Point2::new(1.0, 0.3), Vector2::new(200.0, 0.0));
+    let b = Circle::outer(0.1, Point2::origin(), Static);
+    assert!(!check_collision_swept(&a, start, &b, 0.01));
 }
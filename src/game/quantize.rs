@@ -0,0 +1,260 @@
+use crate::game::{Ball, GameSettings, PlayerId, PlayerState, Snapshot};
+use nalgebra::{Point2, Vector2};
+use serde::{Deserialize, Serialize};
+
+/// Fastest velocity along each axis that snapshots carry by default,
+/// in units per second.
+pub const DEFAULT_VELOCITY_RANGE: f32 = 16.0;
+
+/// Largest fixed point value, which stands for the end of a range.
+const FIXED_MAX: f32 = std::i16::MAX as f32;
+
+/// Largest fixed point charge, which stands for fully charged.
+const CHARGE_MAX: f32 = std::u8::MAX as f32;
+
+/// Ranges that positions and velocities get fitted into to be sent.
+///
+/// These are sent along with every snapshot, so that the settings
+/// changing in between can't throw off decoding.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Quantization {
+    /// Largest coordinate that a position can have, which is the
+    /// arena's `bounds_radius`.
+    pub position_range: f32,
+    /// Largest velocity along each axis, in units per second. Faster
+    /// balls are sent at this speed.
+    pub velocity_range: f32,
+}
+
+/// Snapshot with every position and velocity packed into 16-bit fixed
+/// point, which is a bit over half the size of a full `Snapshot`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuantizedSnapshot {
+    /// Server simulation tick this snapshot was taken on.
+    pub tick: u32,
+    quantization: Quantization,
+    players: Vec<(PlayerId, QuantizedPlayer)>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+struct QuantizedPlayer {
+    cursor: Option<[i16; 2]>,
+    position: [i16; 2],
+    velocity: [i16; 2],
+    /// Charge of the ball in fixed point, which is left out when it
+    /// isn't charged at all.
+    charge: Option<u8>,
+}
+
+impl Quantization {
+    pub fn new(settings: &GameSettings, velocity_range: f32) -> Quantization {
+        Quantization {
+            position_range: settings.bounds_radius,
+            velocity_range,
+        }
+        .clamp()
+    }
+
+    /// Returns a copy with both ranges clamped to something that
+    /// decodes to finite values.
+    ///
+    /// Ranges come in verbatim from the network, so this needs to be
+    /// applied before they are used for anything.
+    pub fn clamp(&self) -> Quantization {
+        let clamp = |value: f32, min: f32, max: f32| value.max(min).min(max);
+        Quantization {
+            position_range: clamp(self.position_range, 0.1, 10.0),
+            velocity_range: clamp(self.velocity_range, 0.1, 1000.0),
+        }
+    }
+}
+
+/// Maps a value from `-range` to `range` onto fixed point, clamping
+/// anything outside of it.
+fn quantize(value: f32, range: f32) -> i16 {
+    // NaN turns into the bottom of the range, since `max` ignores it,
+    // and the clamp keeps the cast from overflowing.
+    (value / range * FIXED_MAX).round().max(-FIXED_MAX).min(FIXED_MAX) as i16
+}
+
+fn dequantize(value: i16, range: f32) -> f32 {
+    f32::from(value) / FIXED_MAX * range
+}
+
+fn quantize_vector(vector: Vector2<f32>, range: f32) -> [i16; 2] {
+    [quantize(vector.x, range), quantize(vector.y, range)]
+}
+
+fn dequantize_vector(vector: [i16; 2], range: f32) -> Vector2<f32> {
+    Vector2::new(dequantize(vector[0], range), dequantize(vector[1], range))
+}
+
+impl QuantizedSnapshot {
+    pub fn new(
+        snapshot: &Snapshot,
+        quantization: Quantization,
+    ) -> QuantizedSnapshot {
+        let Quantization {
+            position_range,
+            velocity_range,
+        } = quantization;
+        let players = snapshot
+            .players
+            .iter()
+            .map(|(&id, state)| {
+                let ball = &state.ball;
+                let player = QuantizedPlayer {
+                    cursor: state.cursor.map(|cursor| {
+                        quantize_vector(cursor.coords, position_range)
+                    }),
+                    position: quantize_vector(
+                        ball.position.coords,
+                        position_range,
+                    ),
+                    velocity: quantize_vector(ball.velocity, velocity_range),
+                    // The clamp keeps NaN and anything out of range from
+                    // overflowing the cast.
+                    charge: Some(
+                        (ball.charge * CHARGE_MAX)
+                            .round()
+                            .max(0.0)
+                            .min(CHARGE_MAX) as u8,
+                    )
+                    .filter(|&charge| charge != 0),
+                };
+                (id, player)
+            })
+            .collect();
+        QuantizedSnapshot {
+            tick: snapshot.tick,
+            quantization,
+            players,
+        }
+    }
+
+    /// Number of players in the snapshot, for checking limits.
+    pub fn player_count(&self) -> usize {
+        self.players.len()
+    }
+
+    /// Unpacks the full snapshot, which is only as precise as the
+    /// fixed point allows.
+    pub fn decode(&self) -> Snapshot {
+        let Quantization {
+            position_range,
+            velocity_range,
+        } = self.quantization.clamp();
+        let players = self.players.iter().map(|&(id, ref player)| {
+            let state = PlayerState {
+                cursor: player.cursor.map(|cursor| {
+                    Point2::from(dequantize_vector(cursor, position_range))
+                }),
+                ball: Ball {
+                    position: Point2::from(dequantize_vector(
+                        player.position,
+                        position_range,
+                    )),
+                    velocity: dequantize_vector(
+                        player.velocity,
+                        velocity_range,
+                    ),
+                    charge: player
+                        .charge
+                        .map_or(0.0, |charge| f32::from(charge) / CHARGE_MAX),
+                },
+            };
+            (id, state)
+        });
+        Snapshot::new(self.tick, players)
+    }
+}
+
+#[cfg(test)]
+fn test_snapshot(players: u16) -> Snapshot {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut vector = |range: f32| {
+        Vector2::new(rng.gen_range(-range, range), rng.gen_range(-range, range))
+    };
+    let players = (0..players)
+        .map(|id| {
+            let state = PlayerState {
+                cursor: Some(Point2::from(vector(0.7))),
+                ball: Ball {
+                    position: Point2::from(vector(0.7)),
+                    velocity: vector(DEFAULT_VELOCITY_RANGE),
+                    charge: 0.0,
+                },
+            };
+            (id, state)
+        })
+        .collect::<Vec<_>>();
+    Snapshot::new(42, players)
+}
+
+#[test]
+fn quantized_snapshot_round_trip() {
+    let settings = GameSettings::default();
+    let quantization = Quantization::new(&settings, DEFAULT_VELOCITY_RANGE);
+    let mut snapshot = test_snapshot(8);
+    snapshot.players.get_mut(&0).unwrap().cursor = None;
+    snapshot.players.get_mut(&2).unwrap().ball.charge = 0.4;
+
+    let encoded =
+        bincode::serialize(&QuantizedSnapshot::new(&snapshot, quantization))
+            .unwrap();
+    let decoded =
+        bincode::deserialize::<QuantizedSnapshot>(&encoded).unwrap().decode();
+    assert_eq!(decoded.tick, snapshot.tick);
+    assert_eq!(decoded.players.len(), snapshot.players.len());
+    // Rounding is off by at most half a step along each axis.
+    let position_error = settings.bounds_radius / FIXED_MAX;
+    let velocity_error = DEFAULT_VELOCITY_RANGE / FIXED_MAX;
+    for (id, state) in &snapshot.players {
+        let decoded = &decoded.players[id];
+        assert_eq!(decoded.cursor.is_some(), state.cursor.is_some());
+        if let (Some(a), Some(b)) = (decoded.cursor, state.cursor) {
+            assert!(nalgebra::distance(&a, &b) < position_error);
+        }
+        let (a, b) = (&decoded.ball, &state.ball);
+        assert!(nalgebra::distance(&a.position, &b.position) < position_error);
+        assert!((a.velocity - b.velocity).norm() < velocity_error);
+        assert!((a.charge - b.charge).abs() <= 0.5 / CHARGE_MAX);
+    }
+}
+
+#[test]
+fn out_of_range_values_clamped() {
+    let quantization = Quantization {
+        position_range: 1.0,
+        velocity_range: 2.0,
+    };
+    let mut snapshot = test_snapshot(1);
+    {
+        let ball = &mut snapshot.players.get_mut(&0).unwrap().ball;
+        ball.velocity = Vector2::new(100.0, std::f32::NAN);
+        ball.position = Point2::new(-5.0, std::f32::INFINITY);
+    }
+    let decoded = QuantizedSnapshot::new(&snapshot, quantization).decode();
+    let ball = &decoded.players[&0].ball;
+    assert_eq!(ball.velocity, Vector2::new(2.0, -2.0));
+    assert_eq!(ball.position, Point2::new(-1.0, 1.0));
+}
+
+#[test]
+fn quantized_snapshot_smaller() {
+    let snapshot = test_snapshot(8);
+    let quantization =
+        Quantization::new(&GameSettings::default(), DEFAULT_VELOCITY_RANGE);
+    let quantized = QuantizedSnapshot::new(&snapshot, quantization);
+    let full_size = bincode::serialized_size(&snapshot).unwrap();
+    let quantized_size = bincode::serialized_size(&quantized).unwrap();
+    assert!(
+        (quantized_size as f32) < 0.6 * full_size as f32,
+        "{} bytes quantized, {} bytes full",
+        quantized_size,
+        full_size
+    );
+}
@@ -0,0 +1,470 @@
+use crate::game::{
+    client::Game,
+    Event,
+    GameSettings,
+    PlayerId,
+    RoundState,
+    Snapshot,
+    StaticPlayerState,
+};
+use log::warn;
+use nalgebra::Point2;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Most events a recording holds, which at the snapshot rate is
+/// somewhere around an hour of play.
+const MAX_RECORDING_EVENTS: usize = 100_000;
+
+/// Interpolation delay in snapshots that replays are played back
+/// with.
+const PLAYBACK_DELAY: f32 = 1.0;
+
+/// Seconds of a replay between the keyframes that seeking back
+/// rebuilds the game from.
+const KEYFRAME_INTERVAL: f32 = 10.0;
+
+/// Length in seconds of highlight clips, counting back from the end.
+pub const HIGHLIGHT_LENGTH: f32 = 5.0;
+
+/// Slowest and fastest that a replay can be played back.
+pub const MIN_REPLAY_SPEED: f32 = 0.1;
+pub const MAX_REPLAY_SPEED: f32 = 4.0;
+
+/// Every event a client got from the server while in a game, along
+/// with what it knew when recording started, so that the game can be
+/// rebuilt at any point.
+#[derive(Clone, Debug)]
+pub struct Recording {
+    players: HashMap<PlayerId, StaticPlayerState>,
+    snapshot: Snapshot,
+    round: RoundState,
+    round_duration: f32,
+    settings: GameSettings,
+    player_id: PlayerId,
+    hue: f32,
+    seed: u64,
+    /// Events and how many seconds after the start each was handled.
+    events: Vec<(f32, Event)>,
+    started: Instant,
+}
+
+/// Game rebuilt from a recording up to some point in it.
+struct Playhead {
+    game: Game,
+    /// Index of the first event that `game` hasn't handled yet.
+    next: usize,
+    /// Instant that stands for `start` in `game`.
+    origin: Instant,
+    /// Seconds since the start of the recording that `game` was built
+    /// at.
+    start: f32,
+    /// Seconds since the start of the recording.
+    time: f32,
+}
+
+/// The game partway through a recording, so that seeking back doesn't
+/// have to replay everything from the start.
+struct Keyframe {
+    /// The game's state, as a recording without any events.
+    state: Recording,
+    /// Index of the first event that hadn't been handled yet.
+    next: usize,
+    /// Seconds since the start of the recording.
+    time: f32,
+}
+
+/// Plays back a recording, which can be paused, sped up and moved
+/// around in.
+pub struct Replay {
+    recording: Recording,
+    playhead: Playhead,
+    /// Keyframes every `KEYFRAME_INTERVAL` seconds, as far as playback
+    /// has got so far.
+    keyframes: Vec<Keyframe>,
+    pub playing: bool,
+    /// How many times faster than real time to play back.
+    pub speed: f32,
+}
+
+/// Short clip of a recording, saved to be watched later.
+#[derive(Clone, Debug)]
+pub struct Highlight {
+    pub name: String,
+    pub recording: Recording,
+}
+
+/// Follows a recording as it's made, `HIGHLIGHT_LENGTH` seconds
+/// behind, so that the lead up to something happening can be cut out
+/// of it as a highlight.
+pub struct HighlightCutter {
+    delayed: Playhead,
+}
+
+impl Recording {
+    /// Starts recording a game from where it is now.
+    pub fn new(game: &mut Game) -> Recording {
+        game.keep_relayed();
+        Recording::from_game(game)
+    }
+
+    /// Gets an empty recording that starts from the game's current
+    /// state.
+    fn from_game(game: &Game) -> Recording {
+        Recording {
+            players: game.players.clone(),
+            snapshot: game.latest_snapshot().clone(),
+            round: game.round,
+            round_duration: game.round_duration,
+            settings: *game.settings(),
+            player_id: game.player_id(),
+            hue: game.hue(),
+            seed: game.seed(),
+            events: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Adds the events the game handled since this was last called.
+    ///
+    /// Once the recording is full, later events are dropped.
+    pub fn record(&mut self, game: &mut Game) {
+        let time = self.started.elapsed().as_secs_f32();
+        let events = game.drain_relayed();
+        let room = MAX_RECORDING_EVENTS - self.events.len();
+        if room > 0 && events.len() >= room {
+            warn!("recording is full, the rest of the game won't be kept");
+        }
+        self.events
+            .extend(events.into_iter().take(room).map(|event| (time, event)));
+    }
+
+    /// Gets the length of the recording in seconds.
+    pub fn duration(&self) -> f32 {
+        self.events.last().map(|&(time, _)| time).unwrap_or(0.0)
+    }
+
+    /// Builds the game as it was when recording started.
+    fn start(&self) -> Game {
+        let (game, _) = Game::new(
+            self.players.clone(),
+            self.snapshot.clone(),
+            self.round,
+            self.round_duration,
+            self.settings,
+            self.player_id,
+            self.hue,
+            self.seed,
+            Point2::origin(),
+        );
+        game
+    }
+}
+
+impl Playhead {
+    fn new(recording: &Recording) -> Playhead {
+        Playhead::resume(recording, 0, 0.0)
+    }
+
+    /// Builds the game from `state`, which is `time` seconds into a
+    /// recording where event `next` is the first it hasn't handled.
+    fn resume(state: &Recording, next: usize, time: f32) -> Playhead {
+        let game = state.start();
+        Playhead {
+            game,
+            next,
+            // This has to come after the game is built, since the
+            // game's playback can't go back before then.
+            origin: Instant::now(),
+            start: time,
+            time,
+        }
+    }
+
+    /// Moves forward to `time` seconds since the start, handling every
+    /// event up to then.
+    fn advance(&mut self, events: &[(f32, Event)], time: f32) {
+        while let Some((event_time, event)) = events.get(self.next).cloned() {
+            if event_time > time {
+                break;
+            }
+            self.tick_to(event_time);
+            let arrived =
+                self.origin + Duration::from_secs_f32(event_time - self.start);
+            self.game.handle_event(event, arrived);
+            self.next += 1;
+        }
+        self.tick_to(time);
+        // Nothing reacts to what happened, so the events would only
+        // pile up.
+        self.game.drain_events().for_each(drop);
+    }
+
+    /// Ticks the game forward to `time` without handling any events.
+    fn tick_to(&mut self, time: f32) {
+        if time > self.time {
+            self.game.tick(time - self.time);
+            self.time = time;
+        }
+        let now = self.origin + Duration::from_secs_f32(self.time - self.start);
+        self.game.update_playback(now, PLAYBACK_DELAY);
+    }
+}
+
+impl Keyframe {
+    fn new(playhead: &Playhead) -> Keyframe {
+        Keyframe {
+            state: Recording::from_game(&playhead.game),
+            next: playhead.next,
+            time: playhead.time,
+        }
+    }
+
+    fn playhead(&self) -> Playhead {
+        Playhead::resume(&self.state, self.next, self.time)
+    }
+}
+
+impl Replay {
+    pub fn new(recording: Recording) -> Replay {
+        let playhead = Playhead::new(&recording);
+        Replay {
+            recording,
+            playhead,
+            keyframes: Vec::new(),
+            playing: true,
+            speed: 1.0,
+        }
+    }
+
+    /// Gives back the recording, to be played again later.
+    pub fn into_recording(self) -> Recording {
+        self.recording
+    }
+
+    /// Gets the game as it was at the current time.
+    pub fn game(&self) -> &Game {
+        &self.playhead.game
+    }
+
+    /// Gets the current time in seconds since the start.
+    pub fn time(&self) -> f32 {
+        self.playhead.time
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.recording.duration()
+    }
+
+    /// Moves playback forward by `dt` seconds of real time, if it's
+    /// playing, and stops at the end.
+    pub fn update(&mut self, dt: f32) {
+        if !self.playing {
+            return;
+        }
+        let speed = self.speed.max(MIN_REPLAY_SPEED).min(MAX_REPLAY_SPEED);
+        self.seek(self.time() + dt * speed);
+        if self.time() >= self.duration() {
+            self.playing = false;
+        }
+    }
+
+    /// Jumps to `time` seconds since the start.
+    ///
+    /// Going forward carries on from the current game, but going back
+    /// has to rebuild it from the nearest keyframe before `time`.
+    pub fn seek(&mut self, time: f32) {
+        let time = time.max(0.0).min(self.duration());
+        if time < self.playhead.time {
+            let keyframe = self.keyframes.iter().rev().find(|k| k.time <= time);
+            self.playhead = match keyframe {
+                Some(keyframe) => keyframe.playhead(),
+                None => Playhead::new(&self.recording),
+            };
+        }
+        // Keep keyframes for any new ground covered on the way.
+        loop {
+            let last = self.keyframes.last().map_or(0.0, |k| k.time);
+            let next = last + KEYFRAME_INTERVAL;
+            if next > time {
+                break;
+            }
+            self.playhead.advance(&self.recording.events, next);
+            self.keyframes.push(Keyframe::new(&self.playhead));
+        }
+        self.playhead.advance(&self.recording.events, time);
+    }
+
+    /// Moves forward to the next snapshot and pauses there.
+    pub fn step_forward(&mut self) {
+        self.playing = false;
+        let now = self.time();
+        let next = self.snapshot_times().find(|&time| time > now);
+        if let Some(time) = next {
+            self.seek(time);
+        }
+    }
+
+    /// Moves back to the previous snapshot and pauses there.
+    pub fn step_back(&mut self) {
+        self.playing = false;
+        let now = self.time();
+        let previous = self.snapshot_times().filter(|&time| time < now).last();
+        self.seek(previous.unwrap_or(0.0));
+    }
+
+    /// Iterates over the times that snapshots arrived at, in order.
+    fn snapshot_times(&self) -> impl Iterator<Item = f32> + '_ {
+        self.recording.events.iter().filter_map(|(time, event)| {
+            match event {
+                Event::Snapshot(_) => Some(*time),
+                _ => None,
+            }
+        })
+    }
+}
+
+impl HighlightCutter {
+    pub fn new(recording: &Recording) -> HighlightCutter {
+        HighlightCutter {
+            delayed: Playhead::new(recording),
+        }
+    }
+
+    /// Catches up with the recording, staying `HIGHLIGHT_LENGTH`
+    /// seconds behind its end.
+    pub fn update(&mut self, recording: &Recording) {
+        let time = recording.duration() - HIGHLIGHT_LENGTH;
+        self.delayed.advance(&recording.events, time);
+    }
+
+    /// Cuts out everything from `HIGHLIGHT_LENGTH` seconds ago up to
+    /// the end of the recording, which should have been passed to
+    /// `update` since it last changed.
+    pub fn cut(&self, recording: &Recording, name: String) -> Highlight {
+        let start = self.delayed.time;
+        let mut clip = Recording::from_game(&self.delayed.game);
+        clip.events = recording.events[self.delayed.next..]
+            .iter()
+            .map(|(time, event)| (time - start, event.clone()))
+            .collect();
+        Highlight {
+            name,
+            recording: clip,
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_recording() -> Recording {
+    use crate::game::quantize::{Quantization, QuantizedSnapshot};
+
+    let settings = GameSettings::default();
+    let quantization = Quantization::new(&settings, 1.0);
+    let snapshot = |tick| {
+        let snapshot = Snapshot::new(tick, Vec::new());
+        Event::Snapshot(QuantizedSnapshot::new(&snapshot, quantization))
+    };
+    Recording {
+        players: HashMap::new(),
+        snapshot: Snapshot::new(0, Vec::new()),
+        round: RoundState::Waiting,
+        round_duration: 0.0,
+        settings,
+        player_id: 0,
+        hue: 0.0,
+        seed: 0,
+        events: vec![
+            (0.5, snapshot(1)),
+            (1.0, Event::RoundState(RoundState::Round)),
+            (1.5, snapshot(2)),
+            (2.5, snapshot(3)),
+            (3.0, Event::RoundState(RoundState::RoundEnd)),
+        ],
+        started: Instant::now(),
+    }
+}
+
+#[test]
+fn seeking_back_rebuilds_game() {
+    let mut replay = Replay::new(test_recording());
+    replay.seek(2.0);
+    let tick = replay.game().latest_snapshot().tick;
+    let round_duration = replay.game().round_duration;
+    assert_eq!(tick, 2);
+    assert_eq!(replay.game().round, RoundState::Round);
+
+    replay.seek(3.0);
+    assert_eq!(replay.game().round, RoundState::RoundEnd);
+    replay.seek(2.0);
+    assert_eq!(replay.game().latest_snapshot().tick, tick);
+    assert_eq!(replay.game().round, RoundState::Round);
+    assert_eq!(replay.game().round_duration, round_duration);
+}
+
+#[test]
+fn seeking_back_starts_from_keyframe() {
+    use crate::game::quantize::{Quantization, QuantizedSnapshot};
+
+    let mut recording = test_recording();
+    let quantization = Quantization::new(&recording.settings, 1.0);
+    for (time, tick) in &[(15.0, 4), (25.0, 5)] {
+        let snapshot = Snapshot::new(*tick, Vec::new());
+        let snapshot = QuantizedSnapshot::new(&snapshot, quantization);
+        recording.events.push((*time, Event::Snapshot(snapshot)));
+    }
+    let mut replay = Replay::new(recording);
+    replay.seek(25.0);
+    assert_eq!(replay.keyframes.len(), 2);
+    assert_eq!(replay.game().latest_snapshot().tick, 5);
+
+    replay.seek(16.0);
+    assert_eq!(replay.playhead.start, 10.0);
+    assert_eq!(replay.game().latest_snapshot().tick, 4);
+    assert_eq!(replay.game().round, RoundState::RoundEnd);
+    replay.seek(5.0);
+    assert_eq!(replay.playhead.start, 0.0);
+    assert_eq!(replay.game().latest_snapshot().tick, 3);
+}
+
+#[test]
+fn stepping_moves_between_snapshots() {
+    let mut replay = Replay::new(test_recording());
+    replay.step_forward();
+    assert!(!replay.playing);
+    assert_eq!(replay.time(), 0.5);
+    replay.step_forward();
+    assert_eq!(replay.time(), 1.5);
+    replay.step_back();
+    assert_eq!(replay.time(), 0.5);
+    assert_eq!(replay.game().latest_snapshot().tick, 1);
+}
+
+#[test]
+fn highlight_cut_from_before_the_end() {
+    use crate::game::quantize::{Quantization, QuantizedSnapshot};
+
+    let mut recording = test_recording();
+    let snapshot = Snapshot::new(4, Vec::new());
+    let quantization = Quantization::new(&recording.settings, 1.0);
+    let snapshot = QuantizedSnapshot::new(&snapshot, quantization);
+    recording.events.push((6.0, Event::Snapshot(snapshot)));
+    recording.events.push((
+        8.0,
+        Event::Kill {
+            killer: 0,
+            victim: 1,
+        },
+    ));
+    let mut cutter = HighlightCutter::new(&recording);
+    cutter.update(&recording);
+    let highlight = cutter.cut(&recording, "kill".to_owned());
+    let clip = highlight.recording;
+    assert_eq!(clip.round, RoundState::RoundEnd);
+    assert_eq!(clip.events.len(), 2);
+    assert_eq!(clip.duration(), HIGHLIGHT_LENGTH);
+
+    let mut replay = Replay::new(clip);
+    replay.seek(HIGHLIGHT_LENGTH);
+    assert_eq!(replay.game().latest_snapshot().tick, 4);
+}
@@ -1,28 +1,145 @@
 use crate::game::{
-    physics::{self, check_collision, resolve_collision},
-    step_dt,
+    hue_color,
+    physics::{
+        self,
+        check_collision_swept,
+        resolve_collision,
+        resolve_collision_swept,
+        Circle,
+    },
     Ball,
     Event,
     GameSettings,
     GetPlayer,
+    LeaveReason,
+    Palette,
     PlayerId,
     PlayerState,
     RoundState,
     Snapshot,
     StaticPlayerState,
+    DASH_COOLDOWN,
+    MAX_NAME_LENGTH,
+    WAITING_DURATION,
 };
 use log::info;
-use nalgebra::Point2;
+use nalgebra::{self, Point2, Vector2};
 use ord_subset::OrdSubsetIterExt;
-use palette::{LabHue, Lch};
-use rand::{thread_rng, Rng};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use smallvec::SmallVec;
 use std::collections::HashMap;
+use std::iter;
 
 /// Number of hue candidates to generate for each existing player
 /// sample.
 const HUE_CANDIDATES_PER_SAMPLE: usize = 4;
 
+/// Minimum distance between a preferred hue and every other player's
+/// hue for the preference to be used as-is.
+const MIN_HUE_DISTANCE: f32 = 0.05;
+
+/// Number of evenly spaced hues to try when looking for the free hue
+/// nearest to a preferred one.
+const HUE_SEARCH_STEPS: usize = 360;
+
+/// Picks a hue for a new player that's as distinct as possible from
+/// the `taken` ones.
+///
+/// A preferred hue is used if it isn't too close to another player's,
+/// or otherwise the nearest free hue to it. With a limited palette,
+/// the palette hue nearest to the preference that the fewest players
+/// have is used instead.
+fn pick_hue<R: Rng>(
+    taken: &[f32],
+    preferred: Option<f32>,
+    palette: Palette,
+    rng: &mut R,
+) -> f32 {
+    // Clients can send anything, so wrap it into range.
+    let preferred =
+        preferred.filter(|hue| hue.is_finite()).map(|hue| hue - hue.floor());
+
+    let hues = palette.hues();
+    if !hues.is_empty() {
+        let uses = |hue: f32| {
+            taken
+                .iter()
+                .filter(|&&other| hue_distance(hue, other) < MIN_HUE_DISTANCE)
+                .count()
+        };
+        // Unwraps are okay because palettes aren't empty.
+        let fewest = hues.iter().map(|&hue| uses(hue)).min().unwrap();
+        let mut least_used =
+            hues.iter().cloned().filter(|&hue| uses(hue) == fewest);
+        return match preferred {
+            Some(preferred) => {
+                least_used
+                    .ord_subset_min_by_key(|&hue| hue_distance(hue, preferred))
+                    .unwrap()
+            },
+            None => least_used.next().unwrap(),
+        };
+    }
+
+    if taken.is_empty() {
+        // There weren't any existing players, so just use uniform RNG.
+        return preferred.unwrap_or_else(|| rng.gen());
+    }
+
+    // Unwrap is okay because we already know taken is not empty.
+    let nearest = |hue: f32| {
+        taken
+            .iter()
+            .map(|&other| hue_distance(hue, other))
+            .ord_subset_min()
+            .unwrap()
+    };
+    if let Some(preferred) = preferred {
+        // Search outwards from the preference in both directions.
+        let free = iter::once(preferred)
+            .chain((1..=HUE_SEARCH_STEPS / 2).flat_map(|step| {
+                let offset = step as f32 / HUE_SEARCH_STEPS as f32;
+                let above = preferred + offset;
+                let below = preferred - offset;
+                iter::once(above - above.floor())
+                    .chain(iter::once(below - below.floor()))
+            }))
+            .find(|&hue| nearest(hue) >= MIN_HUE_DISTANCE);
+        if let Some(hue) = free {
+            return hue;
+        }
+    }
+
+    // Otherwise use Mitchell's best-candidate algorithm for picking the
+    // hue. The main disadvantage of this is that it's O(n^2) wrt the
+    // number of players. I don't think there are ever enough players
+    // for this to matter though.
+    let num_samples = taken.len() * HUE_CANDIDATES_PER_SAMPLE;
+    let samples = (0..num_samples).map(|_| rng.gen::<f32>());
+    // Unwrap is okay because samples is not empty.
+    samples.ord_subset_max_by_key(|&hue| nearest(hue)).unwrap()
+}
+
+/// Number of random positions to try when looking for a safe spot to
+/// respawn a ball.
+const RESPAWN_CANDIDATES: usize = 32;
+
+/// Distance a cursor has to move from where it was for the player to
+/// stop counting as idle.
+const AFK_MOVE_THRESHOLD: f32 = 0.02;
+
+/// Seconds of cooldown that can be left when a dash is accepted, since
+/// input arrives with some jitter.
+const DASH_LENIENCY: f32 = 0.1;
+
+/// Fixed duration in seconds of a single simulation step.
+pub const TIMESTEP: f32 = 1.0 / 120.0;
+
+/// Most steps that a single `Game::tick` takes, so that a long stall
+/// doesn't leave the game too far behind to ever catch up.
+const MAX_STEPS_PER_TICK: u32 = 30;
+
 /// Gets the distance between two hue values, specified from 0 to 1.
 fn hue_distance(a: f32, b: f32) -> f32 {
     let dist = (a - b).abs();
@@ -46,20 +163,263 @@ fn hue_distance_gt_half() {
     assert_eq!(hue_distance(0.9, 0.2), 0.3);
 }
 
+#[test]
+fn preferred_hue_avoids_clash() {
+    let mut game = Game::default();
+    let (first, _) = game.add_player(Point2::origin(), "first", Some(0.5));
+    assert_eq!(game.players[&first].hue, 0.5);
+    let (second, _) = game.add_player(Point2::origin(), "second", Some(0.5));
+    let dist = hue_distance(game.players[&second].hue, 0.5);
+    assert!(dist >= MIN_HUE_DISTANCE);
+    assert!(dist < MIN_HUE_DISTANCE + 2.0 / HUE_SEARCH_STEPS as f32);
+}
+
+#[test]
+fn palette_hues_spread_out() {
+    let palette = Palette::Deuteranopia;
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut taken = Vec::new();
+    for _ in 0..palette.hues().len() {
+        let hue = pick_hue(&taken, Some(0.0), palette, &mut rng);
+        assert!(palette.hues().contains(&hue));
+        assert!(!taken.contains(&hue));
+        taken.push(hue);
+    }
+}
+
+#[test]
+fn same_seed_same_hues() {
+    let hues = |seed| {
+        let mut game = Game::new(seed);
+        (0..4)
+            .map(|_| {
+                let (id, _) = game.add_player(Point2::origin(), "player", None);
+                game.players[&id].hue
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(hues(7), hues(7));
+    assert_ne!(hues(7), hues(8));
+}
+
+/// Ticks the game through `seconds` in pieces short enough that none
+/// of the time is dropped.
+#[cfg(test)]
+fn tick_for(game: &mut Game, seconds: f32) {
+    let mut left = seconds;
+    while left > 0.0 {
+        let dt = left.min(0.1);
+        game.tick(dt).for_each(drop);
+        left -= dt;
+    }
+}
+
+#[test]
+fn tick_accumulates_partial_steps() {
+    let mut game = Game::default();
+    game.tick(0.5 * TIMESTEP).for_each(drop);
+    assert_eq!(game.tick, 0);
+    game.tick(0.5 * TIMESTEP).for_each(drop);
+    assert_eq!(game.tick, 1);
+    game.tick(2.0 * TIMESTEP).for_each(drop);
+    assert_eq!(game.tick, 3);
+}
+
+#[test]
+fn tick_drops_time_after_stall() {
+    let mut game = Game::default();
+    game.tick(10.0).for_each(drop);
+    assert_eq!(game.tick, MAX_STEPS_PER_TICK);
+    // The rest of the stall doesn't carry over.
+    game.tick(TIMESTEP).for_each(drop);
+    assert_eq!(game.tick, MAX_STEPS_PER_TICK + 1);
+}
+
+#[test]
+fn charging_grows_ball() {
+    use crate::game::CHARGE_TIME;
+
+    let mut game = Game::default();
+    // Alone in the lobby, so the ball is simulated while warming up.
+    let (id, _) = game.add_player(Point2::new(0.3, 0.0), "player", None);
+    game.set_player_charging(id, true);
+    tick_for(&mut game, 2.0 * CHARGE_TIME);
+    let ball = game.players[&id].state.ball;
+    assert_eq!(ball.charge, 1.0);
+    assert!(ball.radius(&game.settings) > game.settings.ball_radius);
+
+    game.set_player_charging(id, false);
+    tick_for(&mut game, 2.0 * CHARGE_TIME);
+    assert_eq!(game.players[&id].state.ball.charge, 0.0);
+}
+
+#[test]
+fn dash_cools_down() {
+    let mut game = Game::default();
+    let (id, _) = game.add_player(Point2::origin(), "player", None);
+    assert!(game.dash(id));
+    assert!(!game.dash(id));
+    tick_for(&mut game, DASH_COOLDOWN - 0.5 * DASH_LENIENCY);
+    assert!(game.dash(id));
+}
+
+#[test]
+fn reset_revives_everyone() {
+    let mut game = Game::default();
+    let (a, _) = game.add_player(Point2::new(-0.5, 0.0), "a", None);
+    let (b, _) = game.add_player(Point2::new(0.5, 0.0), "b", None);
+    game.start_round().for_each(drop);
+    game.players.get_mut(&b).unwrap().kill(&game.settings);
+    game.players.get_mut(&a).unwrap().state.ball.velocity.x = 3.0;
+
+    game.reset().for_each(drop);
+    assert_eq!(game.round, RoundState::Waiting);
+    assert!(game.players[&b].state.alive());
+    let ball = game.players[&a].state.ball;
+    assert_eq!(
+        ball.position,
+        Ball::starting(Point2::new(-0.5, 0.0), &game.settings).position
+    );
+}
+
+#[test]
+fn saved_wins_need_key() {
+    let mut game = Game::default();
+    game.restore_wins(vec![("key".to_owned(), 3)]);
+    let (impostor, _) = game.add_player(Point2::new(-0.5, 0.0), "key", None);
+    assert_eq!(game.players[&impostor].static_state.wins, 0);
+    let (returning, _) =
+        game.add_returning_player(Point2::new(0.5, 0.0), "a", None, "key");
+    assert_eq!(game.players[&returning].static_state.wins, 3);
+    assert_eq!(game.saved_wins().count(), 0);
+}
+
+#[test]
+fn respawning_round_ends_in_time() {
+    let mut game = Game::default();
+    game.settings.respawn_delay = Some(1.0);
+    game.settings.round_time_limit = Some(20.0);
+    game.settings.afk_timeout = None;
+    // Keep the balls where they start, far from any cursor.
+    game.settings.spring_constant = 0.0;
+    game.settings.ball_start_speed = 0.0;
+    let (a, _) = game.add_player(Point2::new(-0.5, 0.0), "a", None);
+    let (b, _) = game.add_player(Point2::new(0.5, 0.0), "b", None);
+    game.start_round().for_each(drop);
+
+    // Dying doesn't end the round, since the player comes back.
+    game.players.get_mut(&b).unwrap().kill(&game.settings);
+    game.players.get_mut(&a).unwrap().round_kills += 1;
+    tick_for(&mut game, 10.0);
+    assert_eq!(game.round, RoundState::Round);
+    assert!(game.players[&b].state.alive());
+
+    tick_for(&mut game, 11.0);
+    assert_eq!(game.round, RoundState::RoundEnd);
+    // Both players are still alive, so the one with more kills wins.
+    tick_for(&mut game, 1.5);
+    assert_eq!(game.round, RoundState::Winner(Some(a)));
+}
+
+#[test]
+fn time_limit_needs_respawning() {
+    let mut game = Game::default();
+    game.settings.round_time_limit = Some(20.0);
+    game.settings.afk_timeout = None;
+    game.settings.spring_constant = 0.0;
+    game.settings.ball_start_speed = 0.0;
+    game.add_player(Point2::new(-0.5, 0.0), "a", None).1.for_each(drop);
+    game.add_player(Point2::new(0.5, 0.0), "b", None).1.for_each(drop);
+    game.start_round().for_each(drop);
+    tick_for(&mut game, 30.0);
+    assert_eq!(game.round, RoundState::Round);
+}
+
+#[test]
+fn idle_player_killed() {
+    let mut game = Game::default();
+    game.settings.afk_timeout = Some(5.0);
+    // Keep the balls where they start, far from any cursor.
+    game.settings.spring_constant = 0.0;
+    game.settings.ball_start_speed = 0.0;
+    let (idle, _) = game.add_player(Point2::new(-0.5, 0.0), "idle", None);
+    let (active, _) = game.add_player(Point2::new(0.5, 0.0), "active", None);
+    let (other, _) = game.add_player(Point2::new(0.0, 0.5), "other", None);
+    game.start_round().for_each(drop);
+    let mut afk = Vec::new();
+    for i in 0..600 {
+        // Wiggle back and forth, further than the threshold.
+        let x = if i % 2 == 0 {
+            0.5
+        } else {
+            0.45
+        };
+        game.set_player_cursor(active, Point2::new(x, 0.0));
+        game.set_player_cursor(other, Point2::new(x - 0.5, 0.5));
+        afk.extend(game.tick(0.01).filter_map(|event| {
+            match event {
+                Event::Afk(id) => Some(id),
+                _ => None,
+            }
+        }));
+    }
+    assert_eq!(afk, vec![idle]);
+    assert!(!game.players[&idle].state.alive());
+    assert!(game.players[&active].state.alive());
+}
+
 #[derive(Clone, Debug)]
 pub struct Player {
     pub state: PlayerState,
     pub static_state: StaticPlayerState,
+    /// Most recent cursor position sent by the player, even if they
+    /// are dead.
+    last_cursor: Point2<f32>,
+    /// Seconds left until a dead player respawns, if they are going
+    /// to.
+    respawn_timer: Option<f32>,
+    /// Where the cursor was when the player last moved it.
+    idle_cursor: Point2<f32>,
+    /// Seconds the cursor has been within `AFK_MOVE_THRESHOLD` of
+    /// `idle_cursor` during the current round.
+    idle_time: f32,
     hue: f32,
+    /// Whether the player is holding down the button to charge up
+    /// their ball.
+    charging: bool,
+    /// Seconds until the player can dash again.
+    dash_cooldown: f32,
+    /// Other players killed during the current round, which decides
+    /// the winner when a round with respawning runs out of time.
+    round_kills: u32,
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct Game {
     pub players: HashMap<PlayerId, Player>,
     pub round: RoundState,
     pub round_duration: f32,
     pub settings: GameSettings,
+    /// Number of fixed simulation steps taken so far.
+    pub tick: u32,
+    /// Wall-clock time that hasn't been simulated yet.
+    accumulator: f32,
     next_id: PlayerId,
+    /// Win counts from before the server restarted, which are given
+    /// back to whoever joins with the same key.
+    saved_wins: HashMap<String, u32>,
+    /// Seed that `rng` started from, which clients are told so that
+    /// the match can be replayed.
+    seed: u64,
+    /// Source of everything random in the game, so that the same seed
+    /// and the same inputs always play out the same way.
+    rng: StdRng,
+}
+
+impl Default for Game {
+    fn default() -> Game {
+        Game::new(0)
+    }
 }
 
 impl<'a> GetPlayer for &'a Player {
@@ -75,7 +435,69 @@ impl<'a> GetPlayer for &'a Player {
     }
 }
 
+impl Player {
+    /// Kills the player, starting the respawn timer if enabled.
+    fn kill(&mut self, settings: &GameSettings) {
+        self.state.cursor = None;
+        self.respawn_timer = settings.respawn_delay;
+    }
+
+    /// Gets the player's hue from 0 to 1.
+    pub fn hue(&self) -> f32 {
+        self.hue
+    }
+
+    /// Counts a step towards the player being idle, returning whether
+    /// they've now been idle for too long.
+    fn idle(&mut self, dt: f32, settings: &GameSettings) -> bool {
+        let moved = nalgebra::distance(&self.last_cursor, &self.idle_cursor);
+        if moved > AFK_MOVE_THRESHOLD {
+            self.idle_cursor = self.last_cursor;
+            self.idle_time = 0.0;
+        } else {
+            self.idle_time += dt;
+        }
+        match settings.afk_timeout {
+            Some(timeout) => self.idle_time > timeout,
+            None => false,
+        }
+    }
+
+    /// Whether the player is alive or will respawn later.
+    fn in_play(&self) -> bool {
+        self.state.alive() || self.respawn_timer.is_some()
+    }
+}
+
 impl Game {
+    /// Creates an empty game, with everything random drawn from
+    /// `seed`.
+    pub fn new(seed: u64) -> Game {
+        Game {
+            players: HashMap::new(),
+            round: RoundState::default(),
+            round_duration: 0.0,
+            settings: GameSettings::default(),
+            tick: 0,
+            accumulator: 0.0,
+            next_id: 0,
+            saved_wins: HashMap::new(),
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Gets the seed the game's random numbers are drawn from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Gets the game's random number generator, for anything else
+    /// random that should be replayed along with it, like bots.
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
     /// Returns an iterator over the players.
     pub fn players(&self) -> impl Iterator<Item = (PlayerId, &Player)> {
         self.players.iter().map(|(&id, player)| (id, player))
@@ -89,7 +511,8 @@ impl Game {
     /// Sets the location of a player's cursor.
     ///
     /// If this is before the round is started, the starting position
-    /// of that player's ball is also updated.
+    /// of that player's ball is also updated, except while warming up
+    /// when the ball is left free to move.
     ///
     /// Returns `false` if there is no player corresponding to the id.
     pub fn set_player_cursor(
@@ -101,6 +524,7 @@ impl Game {
             Some(player) => player,
             None => return false,
         };
+        player.last_cursor = cursor;
 
         if self.round.running() {
             player.state.set_cursor(cursor);
@@ -109,15 +533,47 @@ impl Game {
             player.state.cursor = Some(cursor);
         }
 
-        if !self.round.running() {
+        if !self.round.running() && !self.round.warm_up() {
             player.state.ball = Ball::starting(cursor, &self.settings);
         }
         true
     }
 
+    /// Sets whether a player is charging up their ball, which only
+    /// has an effect while they're alive.
+    ///
+    /// Returns `false` if there is no player corresponding to the id.
+    pub fn set_player_charging(
+        &mut self,
+        id: PlayerId,
+        charging: bool,
+    ) -> bool {
+        match self.players.get_mut(&id) {
+            Some(player) => {
+                player.charging = charging;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Starts a player's dash cooldown, returning whether they could
+    /// dash, which is `false` if it was still cooling down or there's
+    /// no player corresponding to the id.
+    pub fn dash(&mut self, id: PlayerId) -> bool {
+        match self.players.get_mut(&id) {
+            Some(player) if player.dash_cooldown <= DASH_LENIENCY => {
+                player.dash_cooldown = DASH_COOLDOWN;
+                true
+            },
+            _ => false,
+        }
+    }
+
     /// Generates a snapshot of the current game state.
     pub fn snapshot(&self) -> Snapshot {
         Snapshot {
+            tick: self.tick,
             players: self
                 .players
                 .iter()
@@ -129,30 +585,154 @@ impl Game {
     fn switch_round(&mut self, round: RoundState) {
         self.round = round;
         self.round_duration = 0.0;
+        // Pending respawns and idle time don't carry over between
+        // rounds.
+        for player in self.players.values_mut() {
+            player.respawn_timer = None;
+            player.idle_time = 0.0;
+            if round == RoundState::Round {
+                player.round_kills = 0;
+            }
+        }
+    }
+
+    /// Gets the only player still alive, if there's exactly one.
+    fn last_alive(&self) -> Option<PlayerId> {
+        let mut alive =
+            self.players.iter().filter(|(_, player)| player.state.alive());
+        match (alive.next(), alive.next()) {
+            (Some((&id, _)), None) => Some(id),
+            _ => None,
+        }
     }
 
-    /// Steps the whole game world forward in time.
+    /// Gets the player with the most kills this round, unless nobody
+    /// got any or there's a tie.
+    fn most_kills(&self) -> Option<PlayerId> {
+        let most = self
+            .players
+            .values()
+            .map(|player| player.round_kills)
+            .max()
+            .filter(|&kills| kills > 0)?;
+        let mut leaders = self
+            .players
+            .iter()
+            .filter(|(_, player)| player.round_kills == most);
+        match (leaders.next(), leaders.next()) {
+            (Some((&id, _)), None) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Picks a random position for a respawning player's ball, which
+    /// isn't touching any other ball or cursor.
+    ///
+    /// If no safe position is found, just gives up and returns the
+    /// last candidate.
+    fn safe_ball_position(&mut self, id: PlayerId) -> Point2<f32> {
+        let settings = &self.settings;
+        let max_dist = settings.bounds_radius - settings.ball_radius;
+        let cursor_dist = settings.ball_radius + settings.cursor_radius;
+        let mut candidate = Point2::origin();
+        for _ in 0..RESPAWN_CANDIDATES {
+            candidate = Point2::new(
+                self.rng.gen_range(-max_dist, max_dist),
+                self.rng.gen_range(-max_dist, max_dist),
+            );
+            if candidate.coords.norm_squared() > max_dist * max_dist {
+                continue;
+            }
+
+            let safe = self.players.iter().all(|(&other, player)| {
+                let state = &player.state;
+                let ball = state.ball.position;
+                let ball_dist =
+                    settings.ball_radius + state.ball.radius(settings);
+                let clear_ball =
+                    nalgebra::distance(&candidate, &ball) > ball_dist;
+                let clear_cursor = match state.cursor {
+                    Some(cursor) => {
+                        nalgebra::distance(&candidate, &cursor) > cursor_dist
+                    },
+                    None => true,
+                };
+                other == id || (clear_ball && clear_cursor)
+            });
+            if safe {
+                break;
+            }
+        }
+        candidate
+    }
+
+    /// Advances the game world by a variable amount of wall-clock
+    /// time.
+    ///
+    /// The time is added to an accumulator, and the simulation is
+    /// stepped forward in fixed increments of `TIMESTEP` until the
+    /// accumulator is drained. Any leftover time is carried over to
+    /// the next call, so the simulation only depends on the number of
+    /// steps taken and not on how the time was chunked up.
+    ///
+    /// At most `MAX_STEPS_PER_TICK` steps are taken, and any time past
+    /// that is dropped, so the game runs slower after a stall instead
+    /// of spending ever longer catching up.
     pub fn tick(&mut self, dt: f32) -> impl Iterator<Item = Event> {
+        self.accumulator += dt;
+        let mut events = SmallVec::<[_; 2]>::new();
+        let mut steps = 0;
+        while self.accumulator >= TIMESTEP {
+            if steps == MAX_STEPS_PER_TICK {
+                self.accumulator %= TIMESTEP;
+                break;
+            }
+            self.accumulator -= TIMESTEP;
+            self.step(&mut events);
+            steps += 1;
+        }
+        events.into_iter()
+    }
+
+    /// Steps the whole game world forward by exactly one `TIMESTEP`.
+    fn step(&mut self, events: &mut SmallVec<[Event; 2]>) {
+        let dt = TIMESTEP;
+        self.tick += 1;
         self.round_duration += dt;
+        for player in self.players.values_mut() {
+            player.dash_cooldown = (player.dash_cooldown - dt).max(0.0);
+        }
         let transition = match self.round {
             RoundState::Lobby => None,
             RoundState::Waiting => {
-                if self.round_duration > 3.0 {
+                if self.round_duration > WAITING_DURATION {
                     Some(RoundState::Round)
                 } else {
                     None
                 }
             },
-            RoundState::Round => None,
+            RoundState::Round => {
+                // Players who respawn never run out, so they need
+                // something else to end the round.
+                let settings = &self.settings;
+                match (settings.respawn_delay, settings.round_time_limit) {
+                    (Some(_), Some(limit)) if self.round_duration > limit => {
+                        Some(RoundState::RoundEnd)
+                    },
+                    _ => None,
+                }
+            },
             RoundState::RoundEnd => {
                 if self.round_duration > 2.0 {
-                    // If a player is still alive, they win.
-                    let winner = self
-                        .players
-                        .iter()
-                        .filter(|(_, player)| player.state.alive())
-                        .next()
-                        .map(|(&id, _)| id);
+                    let winner = if self.settings.respawn_delay.is_some() {
+                        self.most_kills()
+                    } else {
+                        self.last_alive()
+                    };
+                    if let Some(id) = winner {
+                        let player = self.players.get_mut(&id).unwrap();
+                        player.static_state.wins += 1;
+                    }
                     Some(RoundState::Winner(winner))
                 } else {
                     None
@@ -168,150 +748,284 @@ impl Game {
         };
         if let Some(round) = transition {
             self.switch_round(round);
+            events.push(Event::RoundState(round));
         }
 
-        if !self.round.running() {
-            // No simulation happens if not running.
-            return transition.map(Event::RoundState).into_iter();
+        if !self.round.running() && !self.round.warm_up() {
+            // No simulation happens if not running or warming up.
+            return;
+        }
+        // Nobody can die while warming up.
+        let kills = self.round.running();
+
+        // Respawn any players whose timers ran out.
+        let respawns = self
+            .players
+            .iter_mut()
+            .filter_map(|(&id, player)| {
+                let timer = player.respawn_timer.as_mut()?;
+                *timer -= dt;
+                if *timer <= 0.0 {
+                    Some(id)
+                } else {
+                    None
+                }
+            })
+            .collect::<SmallVec<[_; 1]>>();
+        for id in respawns.into_iter() {
+            info!("respawning player {}", id);
+            let position = self.safe_ball_position(id);
+            let player = self.players.get_mut(&id).unwrap();
+            player.respawn_timer = None;
+            player.idle_time = 0.0;
+            player.state.cursor = Some(player.last_cursor);
+            player.state.ball = Ball {
+                position,
+                velocity: Vector2::zeros(),
+                charge: 0.0,
+            };
+            events.push(Event::Respawn(id));
         }
 
         // To avoid borrow issues.
         let settings = &self.settings;
 
-        for dt in step_dt(dt, 1.0 / 60.0) {
-            // Calculate individual ball spring physics.
-            for player in self.players.values_mut() {
-                player.state.tick(dt, settings);
+        // Remember where every ball started, for continuous collision
+        // detection.
+        let ids = self.players.keys().cloned().collect::<Vec<_>>();
+        let starts = ids
+            .iter()
+            .map(|id| self.players[id].state.ball.position)
+            .collect::<Vec<_>>();
+
+        // Calculate individual ball spring physics, charging balls up
+        // first since that makes the spring stronger.
+        for player in self.players.values_mut() {
+            let charging = player.charging && player.state.alive();
+            player.state.ball.update_charge(dt, charging);
+            player.state.tick(dt, settings);
+        }
+
+        // Check for collisions between balls, only testing the pairs
+        // that make it through the broadphase.
+        let balls = ids
+            .iter()
+            .map(|id| physics::ball(self.players[id].state.ball, settings))
+            .collect::<Vec<_>>();
+        let extents = balls.iter().map(Circle::extent_x).collect::<Vec<_>>();
+        let mut collisions = SmallVec::<[_; 2]>::new();
+        for (a, b) in physics::sweep_and_prune(&extents) {
+            let mut circle_a = balls[a];
+            let mut circle_b = balls[b];
+            if resolve_collision(
+                &mut circle_a,
+                &mut circle_b,
+                settings.restitution,
+            ) {
+                collisions.push((ids[a], circle_a));
+                collisions.push((ids[b], circle_b));
             }
+        }
 
-            // Check for collisions between balls.
-            let mut collisions = SmallVec::<[_; 2]>::new();
-            for (&id_a, a) in self.players.iter() {
-                for (&id_b, b) in self.players.iter() {
-                    // This ensures every unordered pair only gets checked
-                    // once.
-                    if id_a < id_b {
-                        let mut circle_a =
-                            physics::ball(a.state.ball, settings);
-                        let mut circle_b =
-                            physics::ball(b.state.ball, settings);
-                        if resolve_collision(&mut circle_a, &mut circle_b) {
-                            collisions.push((id_a, circle_a));
-                            collisions.push((id_b, circle_b));
-                        }
-                    }
+        // Process collisions updates.
+        for (id, circle) in collisions.into_iter() {
+            let ball = &mut self.players.get_mut(&id).unwrap().state.ball;
+            *ball = Ball {
+                charge: ball.charge,
+                ..circle.into()
+            };
+        }
+
+        // Check for collisions with walls. These are swept, so fast
+        // balls bounce off of the exact point of impact.
+        for (&id, &start) in ids.iter().zip(starts.iter()) {
+            let player = self.players.get_mut(&id).unwrap();
+            let alive = player.state.alive();
+            let mut circle = physics::ball(player.state.ball, settings);
+            if resolve_collision_swept(
+                &mut circle,
+                start,
+                &mut physics::bounds(settings),
+                dt,
+                settings.restitution,
+            ) {
+                player.state.ball = Ball {
+                    charge: player.state.ball.charge,
+                    ..circle.into()
+                };
+                if alive && kills {
+                    info!("{} killed {}", id, id);
+                    player.kill(settings);
+                    events.push(Event::Kill {
+                        killer: id,
+                        victim: id,
+                    });
                 }
             }
+        }
+
+        if !kills {
+            return;
+        }
+
+        // Kill anyone who's been idle for too long, so they can't keep
+        // a round going forever. They're left out of respawning, since
+        // they would just idle again.
+        for (&id, player) in self.players.iter_mut() {
+            if player.state.alive() && player.idle(dt, settings) {
+                info!("player {} is idle", id);
+                player.kill(settings);
+                player.respawn_timer = None;
+                events.push(Event::Afk(id));
+            }
+        }
 
-            // Process collisions updates.
-            for (id, circle) in collisions.into_iter() {
-                self.players.get_mut(&id).unwrap().state.ball = circle.into();
+        let mut deaths = SmallVec::<[_; 1]>::new();
+
+        // Check for collisions with cursor. Balls and cursors go
+        // through the broadphase together, with the cursors placed
+        // after all of the balls. The ball extents cover the whole
+        // path they took this step, so that fast balls can't tunnel
+        // through cursors.
+        let cursors = ids
+            .iter()
+            .filter_map(|&id| {
+                self.players[&id]
+                    .state
+                    .cursor
+                    .map(|cursor| (id, physics::cursor(cursor, settings)))
+            })
+            .collect::<Vec<_>>();
+        let extents = ids
+            .iter()
+            .zip(starts.iter())
+            .map(|(id, start)| {
+                let circle =
+                    physics::ball(self.players[id].state.ball, settings);
+                let (min, max) = circle.extent_x();
+                let radius = circle.radius;
+                (min.min(start.x - radius), max.max(start.x + radius))
+            })
+            .chain(cursors.iter().map(|(_, circle)| circle.extent_x()))
+            .collect::<Vec<_>>();
+        for (ball, cursor) in physics::sweep_and_prune(&extents) {
+            // Only care about ball/cursor pairs, and since cursors
+            // come last, the ball index is always the smaller one.
+            if ball >= ids.len() || cursor < ids.len() {
+                continue;
+            }
+            let id_ball = ids[ball];
+            let (id, circle_cursor) = cursors[cursor - ids.len()];
+            if id == id_ball && !settings.kill_own_cursor {
+                // Don't let players kill themselves unless that
+                // setting is on.
+                continue;
             }
 
-            // Check for collisions with walls.
-            for (&id, player) in self.players.iter_mut() {
-                let alive = player.state.alive();
-                let mut circle = physics::ball(player.state.ball, settings);
-                if resolve_collision(
-                    &mut circle,
-                    &mut physics::bounds(settings),
-                ) {
-                    player.state.ball = circle.into();
-                    if alive {
-                        info!("{} killed {}", id, id);
-                        player.state.cursor = None;
-                    }
-                }
+            let circle_ball =
+                physics::ball(self.players[&id_ball].state.ball, settings);
+            if check_collision_swept(
+                &circle_ball,
+                starts[ball],
+                &circle_cursor,
+                dt,
+            ) {
+                deaths.push((id_ball, id));
             }
+        }
 
-            let mut deaths = SmallVec::<[_; 1]>::new();
-
-            // Check for collisions with cursor.
-            for (&id, player) in self.players.iter() {
-                if let Some(cursor) = player.state.cursor {
-                    let circle_cursor = physics::cursor(cursor, settings);
-                    for (&id_ball, player_ball) in self.players.iter() {
-                        if id == id_ball && !settings.kill_own_cursor {
-                            // Don't let players kill themselves
-                            // unless that setting is on.
-                            continue;
-                        }
-
-                        let circle_ball =
-                            physics::ball(player_ball.state.ball, settings);
-                        if check_collision(&circle_cursor, &circle_ball) {
-                            info!("{} killed {}", id_ball, id);
-                            deaths.push(id);
-                        }
+        // Process collisions with cursor. A cursor can get hit by
+        // more than one ball in the same step, but only the first one
+        // gets the kill.
+        for (killer, victim) in deaths.into_iter() {
+            let player = self.players.get_mut(&victim).unwrap();
+            if player.state.alive() {
+                info!("{} killed {}", killer, victim);
+                player.kill(settings);
+                events.push(Event::Kill {
+                    killer,
+                    victim,
+                });
+                if killer != victim {
+                    if let Some(killer) = self.players.get_mut(&killer) {
+                        killer.round_kills += 1;
                     }
                 }
             }
-
-            // Process collisions with cursor.
-            for id in deaths.into_iter() {
-                self.players.get_mut(&id).unwrap().state.cursor = None;
-            }
         }
 
         if let RoundState::Round = self.round {
             // Start the round ending if there are one or less players
-            // still alive.
-            let num_alive = self
-                .players
-                .values()
-                .filter(|player| player.state.alive())
-                .count();
+            // still alive, counting players waiting to respawn.
+            let num_alive =
+                self.players.values().filter(|player| player.in_play()).count();
             if num_alive <= 1 {
                 self.switch_round(RoundState::RoundEnd);
-                return Some(Event::RoundState(self.round)).into_iter();
+                events.push(Event::RoundState(self.round));
             }
         }
-        transition.map(Event::RoundState).into_iter()
     }
 
     /// Adds a new player and returns the id of the added.
+    ///
+    /// Names longer than `MAX_NAME_LENGTH` are truncated, and the
+    /// preferred hue is only used if it doesn't clash with anyone
+    /// else's.
     pub fn add_player(
         &mut self,
         cursor: Point2<f32>,
+        name: &str,
+        hue: Option<f32>,
+    ) -> (PlayerId, impl Iterator<Item = Event>) {
+        self.add_player_with_wins(cursor, name, hue, 0)
+    }
+
+    /// Like `add_player`, but gives back the wins saved under `key`
+    /// from before the server restarted. The key has to be something
+    /// that players can't pick themselves, so nobody can claim
+    /// someone else's score.
+    pub fn add_returning_player(
+        &mut self,
+        cursor: Point2<f32>,
+        name: &str,
+        hue: Option<f32>,
+        key: &str,
+    ) -> (PlayerId, impl Iterator<Item = Event>) {
+        let wins = self.saved_wins.remove(key).unwrap_or(0);
+        self.add_player_with_wins(cursor, name, hue, wins)
+    }
+
+    fn add_player_with_wins(
+        &mut self,
+        cursor: Point2<f32>,
+        name: &str,
+        hue: Option<f32>,
+        wins: u32,
     ) -> (PlayerId, impl Iterator<Item = Event>) {
-        let mut rng = thread_rng();
         let id = self.next_id;
         self.next_id += 1;
 
-        // Generate a new player with random color.
-        let hue = if self.players.is_empty() {
-            // There weren't any existing players, so just use uniform
-            // RNG.
-            rng.gen()
-        } else {
-            // Otherwise use Mitchell's best-candidate algorithm for
-            // picking the hue. The main disadvantage of this is that
-            // it's O(n^2) wrt the number of players. I don't think
-            // there are ever enough players for this to matter
-            // though.
-            let num_samples = self.players.len() * HUE_CANDIDATES_PER_SAMPLE;
-            let samples = (0..num_samples).map(|_| rng.gen::<f32>());
-            // Unwrap is okay because we already know self.players and
-            // samples are both not empty.
-            samples
-                .ord_subset_max_by_key(|&hue| {
-                    self.players
-                        .values()
-                        .map(|player| hue_distance(hue, player.hue))
-                        .ord_subset_min()
-                        .unwrap()
-                })
-                .unwrap()
-        };
+        let taken =
+            self.players.values().map(|player| player.hue).collect::<Vec<_>>();
+        let hue = pick_hue(&taken, hue, self.settings.palette, &mut self.rng);
         info!("selected hue {}", hue);
-        let lab_hue = LabHue::from_degrees(hue * 360.0);
+        let name = name.chars().take(MAX_NAME_LENGTH).collect::<String>();
         let static_state = StaticPlayerState {
-            color: Lch::new(75.0, 80.0, lab_hue).into(),
+            color: hue_color(hue),
+            name,
+            wins,
         };
         let player = Player {
             state: PlayerState::new(cursor, &self.settings),
             static_state: static_state.clone(),
+            last_cursor: cursor,
+            respawn_timer: None,
+            idle_cursor: cursor,
+            idle_time: 0.0,
             hue,
+            charging: false,
+            dash_cooldown: 0.0,
+            round_kills: 0,
         };
 
         debug_assert!(!self.players.contains_key(&id));
@@ -327,21 +1041,95 @@ impl Game {
             if self.players.len() >= 2 {
                 self.switch_round(RoundState::Waiting);
                 events.push(Event::RoundState(self.round));
+                // Put every ball back from wherever it ended up during
+                // the warm-up.
+                for player in self.players.values_mut() {
+                    player.state.ball =
+                        Ball::starting(player.last_cursor, &self.settings);
+                }
             }
         }
 
         (id, events.into_iter())
     }
 
+    /// Starts a round right away, skipping the usual wait.
+    ///
+    /// This does nothing if there aren't enough players for a round.
+    pub fn start_round(&mut self) -> impl Iterator<Item = Event> {
+        let mut events = SmallVec::<[_; 2]>::new();
+        if self.players.len() < 2 {
+            return events.into_iter();
+        }
+
+        self.switch_round(RoundState::Round);
+        events.push(Event::RoundState(self.round));
+        // Everyone starts alive from their cursor, as if the round
+        // had been waited for.
+        for player in self.players.values_mut() {
+            player.state.cursor = Some(player.last_cursor);
+            player.state.ball =
+                Ball::starting(player.last_cursor, &self.settings);
+        }
+
+        events.into_iter()
+    }
+
+    /// Brings everyone back to life with their ball where it starts,
+    /// and goes back to waiting for a round, or to the lobby if there
+    /// aren't enough players for one.
+    pub fn reset(&mut self) -> impl Iterator<Item = Event> {
+        let round = if self.players.len() >= 2 {
+            RoundState::Waiting
+        } else {
+            RoundState::Lobby
+        };
+        self.switch_round(round);
+        for player in self.players.values_mut() {
+            player.state.cursor = Some(player.last_cursor);
+            player.state.ball =
+                Ball::starting(player.last_cursor, &self.settings);
+        }
+        iter::once(Event::RoundState(round))
+    }
+
+    /// Sets aside win counts from before the server restarted, for
+    /// `add_returning_player` to give back.
+    pub fn restore_wins<I: IntoIterator<Item = (String, u32)>>(
+        &mut self,
+        wins: I,
+    ) {
+        self.saved_wins = wins.into_iter().collect();
+    }
+
+    /// Gets the win counts set aside for players who haven't come back
+    /// yet.
+    pub fn saved_wins(&self) -> impl Iterator<Item = (&String, &u32)> {
+        self.saved_wins.iter()
+    }
+
+    /// Sets every player's win count back to zero.
+    pub fn reset_scores(&mut self) -> impl Iterator<Item = Event> {
+        for player in self.players.values_mut() {
+            player.static_state.wins = 0;
+        }
+        self.saved_wins.clear();
+        iter::once(Event::ResetScores)
+    }
+
     /// Removes the player with a given id.
     pub fn remove_player(
         &mut self,
         id: PlayerId,
+        reason: LeaveReason,
     ) -> impl Iterator<Item = Event> {
         self.players.remove(&id);
 
         let mut events = SmallVec::<[_; 2]>::new();
-        events.push(Event::RemovePlayer(id));
+        events.push(Event::RemovePlayer {
+            id,
+            reason,
+        });
         // If there are less than two players left, stop the round.
         if self.players.len() < 2 {
             self.switch_round(RoundState::Lobby);
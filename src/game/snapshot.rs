@@ -1,4 +1,7 @@
+use crate::game::server::TIMESTEP;
 use crate::game::{Ball, PlayerId, PlayerState};
+#[cfg(test)]
+use nalgebra::{Point2, Vector2};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::collections::HashMap;
@@ -45,22 +48,104 @@ impl<'a> Interpolate for &'a Ball {
     type Output = Ball;
 
     fn interpolate(self, other: &'a Ball, alpha: f32) -> Ball {
-        // TODO: hermite interpolation
         Ball {
             position: self.position.interpolate(other.position, alpha),
             velocity: self.velocity.interpolate(other.velocity, alpha),
+            charge: self.charge.interpolate(other.charge, alpha),
+        }
+    }
+}
+
+/// Method used to interpolate balls between snapshots.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum InterpolationMode {
+    Linear,
+    Hermite,
+}
+
+/// Wrapper to interpolate a ball with a cubic hermite spline instead
+/// of linearly.
+///
+/// The ball velocities are used as the tangents, which makes the
+/// interpolated path line up with the actual simulated path much
+/// better around collisions and at the turning points of the spring.
+#[derive(Copy, Clone, Debug)]
+pub struct Hermite<'a> {
+    ball: &'a Ball,
+    /// Time in seconds between the two snapshots being interpolated.
+    interval: f32,
+}
+
+impl<'a> Hermite<'a> {
+    pub fn new(ball: &'a Ball, interval: f32) -> Hermite<'a> {
+        Hermite {
+            ball,
+            interval,
+        }
+    }
+}
+
+impl<'a> Interpolate for Hermite<'a> {
+    type Output = Ball;
+
+    fn interpolate(self, other: Hermite<'a>, alpha: f32) -> Ball {
+        let interval = self.interval;
+        if interval <= 0.0 {
+            return self.ball.interpolate(other.ball, alpha);
+        }
+
+        let (p0, p1) = (self.ball.position.coords, other.ball.position.coords);
+        // Tangents are scaled by the interval, since the spline is
+        // parameterized over [0, 1] rather than over time.
+        let (m0, m1) =
+            (self.ball.velocity * interval, other.ball.velocity * interval);
+
+        let t = alpha;
+        let t2 = t * t;
+        let t3 = t2 * t;
+        let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+        let h10 = t3 - 2.0 * t2 + t;
+        let h01 = -2.0 * t3 + 3.0 * t2;
+        let h11 = t3 - t2;
+        // Derivatives of the basis functions, for the velocity.
+        let d00 = 6.0 * t2 - 6.0 * t;
+        let d10 = 3.0 * t2 - 4.0 * t + 1.0;
+        let d01 = -6.0 * t2 + 6.0 * t;
+        let d11 = 3.0 * t2 - 2.0 * t;
+
+        let position = p0 * h00 + m0 * h10 + p1 * h01 + m1 * h11;
+        let velocity = (p0 * d00 + m0 * d10 + p1 * d01 + m1 * d11) / interval;
+        Ball {
+            position: position.into(),
+            velocity,
+            charge: self.ball.charge.interpolate(other.ball.charge, alpha),
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Snapshot {
+    /// Server simulation tick this snapshot was taken on.
+    pub tick: u32,
     pub players: HashMap<PlayerId, PlayerState>,
 }
 
+impl Snapshot {
+    pub fn new<I: IntoIterator<Item = (PlayerId, PlayerState)>>(
+        tick: u32,
+        players: I,
+    ) -> Snapshot {
+        Snapshot {
+            tick,
+            players: players.into_iter().collect(),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct InterpolatedSnapshot<'a> {
     alpha: f32,
+    mode: InterpolationMode,
     old: &'a Snapshot,
     new: &'a Snapshot,
 }
@@ -74,6 +159,12 @@ pub trait SnapshotView<'a> {
 
     /// Gets a player by id.
     fn get(&self, id: PlayerId) -> Option<Cow<'a, PlayerState>>;
+
+    /// Gets the server tick corresponding to this view.
+    ///
+    /// For interpolated views, this is the tick of the newer
+    /// snapshot.
+    fn tick(&self) -> u32;
 }
 
 impl<'a> SnapshotView<'a> for &'a Snapshot {
@@ -88,11 +179,16 @@ impl<'a> SnapshotView<'a> for &'a Snapshot {
     fn get(&self, id: PlayerId) -> Option<Cow<'a, PlayerState>> {
         self.players.get(&id).map(Cow::Borrowed)
     }
+
+    fn tick(&self) -> u32 {
+        self.tick
+    }
 }
 
 impl<'a, S: SnapshotView<'a>> From<S> for Snapshot {
     fn from(snapshot: S) -> Snapshot {
         Snapshot {
+            tick: snapshot.tick(),
             players: snapshot
                 .players()
                 .map(|(id, player)| (id, *player))
@@ -102,18 +198,28 @@ impl<'a, S: SnapshotView<'a>> From<S> for Snapshot {
 }
 
 impl<'a> InterpolatedSnapshot<'a> {
+    /// Interpolates between two snapshots, with `alpha` clamped to
+    /// between 0 for `old` and 1 for `new`.
     pub fn new(
         alpha: f32,
+        mode: InterpolationMode,
         old: &'a Snapshot,
         new: &'a Snapshot,
     ) -> InterpolatedSnapshot<'a> {
         InterpolatedSnapshot {
-            alpha,
+            // NaN gets mapped to 0, since `max` ignores it.
+            alpha: alpha.max(0.0).min(1.0),
+            mode,
             old,
             new,
         }
     }
 
+    /// Simulation time in seconds between the two snapshots.
+    fn interval(&self) -> f32 {
+        self.new.tick.wrapping_sub(self.old.tick) as f32 * TIMESTEP
+    }
+
     fn interpolate(
         &self,
         new: &'a PlayerState,
@@ -121,7 +227,16 @@ impl<'a> InterpolatedSnapshot<'a> {
     ) -> Cow<'a, PlayerState> {
         match old {
             // If the old snapshot contains this player, interpolate.
-            Some(old) => Cow::Owned(old.interpolate(new, self.alpha)),
+            Some(old) => {
+                let mut state = old.interpolate(new, self.alpha);
+                if let InterpolationMode::Hermite = self.mode {
+                    let interval = self.interval();
+                    let old_ball = Hermite::new(&old.ball, interval);
+                    let new_ball = Hermite::new(&new.ball, interval);
+                    state.ball = old_ball.interpolate(new_ball, self.alpha);
+                }
+                Cow::Owned(state)
+            },
             // Otherwise just use only the new snapshots.
             None => Cow::Borrowed(new),
         }
@@ -143,4 +258,85 @@ impl<'a> SnapshotView<'a> for InterpolatedSnapshot<'a> {
             .get(&id)
             .map(|new| self.interpolate(new, self.old.players.get(&id)))
     }
+
+    fn tick(&self) -> u32 {
+        self.new.tick
+    }
+}
+
+#[cfg(test)]
+fn test_state(position: Point2<f32>, velocity: Vector2<f32>) -> PlayerState {
+    PlayerState {
+        cursor: Some(position),
+        ball: Ball {
+            position,
+            velocity,
+            charge: 0.0,
+        },
+    }
+}
+
+#[test]
+fn interpolate_player_missing_from_old() {
+    let old = Snapshot::new(0, Vec::new());
+    let state = test_state(Point2::new(0.5, 0.5), Vector2::new(1.0, 0.0));
+    let new = Snapshot::new(6, vec![(1, state)]);
+    for &mode in &[InterpolationMode::Linear, InterpolationMode::Hermite] {
+        let view = InterpolatedSnapshot::new(0.5, mode, &old, &new);
+        let player = view.get(1).unwrap();
+        assert_eq!(player.ball.position, state.ball.position);
+        assert_eq!(player.ball.velocity, state.ball.velocity);
+        assert_eq!(view.players().count(), 1);
+    }
+}
+
+#[test]
+fn interpolate_alpha_endpoints() {
+    let from = test_state(Point2::new(-0.5, 0.0), Vector2::new(0.0, 1.0));
+    let to = test_state(Point2::new(0.5, 0.25), Vector2::new(1.0, 0.0));
+    let old = Snapshot::new(0, vec![(1, from)]);
+    let new = Snapshot::new(6, vec![(1, to)]);
+    let position = |alpha: f32, mode: InterpolationMode| {
+        InterpolatedSnapshot::new(alpha, mode, &old, &new)
+            .get(1)
+            .unwrap()
+            .ball
+            .position
+    };
+    for &mode in &[InterpolationMode::Linear, InterpolationMode::Hermite] {
+        assert_eq!(position(0.0, mode), from.ball.position);
+        assert_eq!(position(1.0, mode), to.ball.position);
+        // Out of range alphas are clamped rather than extrapolated.
+        assert_eq!(position(-1.0, mode), from.ball.position);
+        assert_eq!(position(2.0, mode), to.ball.position);
+        assert_eq!(position(std::f32::NAN, mode), from.ball.position);
+    }
+}
+
+#[test]
+fn linear_interpolation_bounded() {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let point = |rng: &mut StdRng| {
+        Point2::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0))
+    };
+    for _ in 0..1000 {
+        let (a, b) = (point(&mut rng), point(&mut rng));
+        let old = Snapshot::new(0, vec![(1, test_state(a, Vector2::zeros()))]);
+        let new = Snapshot::new(1, vec![(1, test_state(b, Vector2::zeros()))]);
+        let alpha = rng.gen_range(-0.5, 1.5);
+        let view = InterpolatedSnapshot::new(
+            alpha,
+            InterpolationMode::Linear,
+            &old,
+            &new,
+        );
+        let position = view.get(1).unwrap().ball.position;
+        for i in 0..2 {
+            let (min, max) = (a[i].min(b[i]), a[i].max(b[i]));
+            assert!(position[i] >= min - 1e-6 && position[i] <= max + 1e-6);
+        }
+    }
 }
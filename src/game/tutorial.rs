@@ -0,0 +1,241 @@
+use crate::game::{
+    client::{Game, GameEvent},
+    Ball,
+    GameSettings,
+    GetPlayer,
+    PlayerId,
+};
+use nalgebra::Point2;
+
+/// Total distance in world units the cursor has to move to get past
+/// the first step.
+const MOVE_DISTANCE: f32 = 3.0;
+
+/// Distance in world units between the ball and the cursor that shows
+/// off the spring lag.
+const LAG_DISTANCE: f32 = 0.3;
+
+/// Gap in world units between the ball and the wall that counts as
+/// hitting it, since balls are only seen between snapshots.
+const WALL_MARGIN: f32 = 0.02;
+
+/// Seconds each prompt stays up at least, so that steps done by
+/// accident still get read.
+const MIN_STEP_TIME: f32 = 2.0;
+
+/// Number of steps before the tutorial is done.
+pub const TUTORIAL_STEPS: usize = 4;
+
+/// Steps of the tutorial, in the order they're gone through.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TutorialStep {
+    MoveCursor,
+    SpringLag,
+    HitWall,
+    HitTarget,
+    Done,
+}
+
+/// Scripted walk through the basics in a practice game, which moves
+/// on to the next step once the game shows the current one was done.
+#[derive(Clone, Debug)]
+pub struct Tutorial {
+    step: TutorialStep,
+    /// Seconds the current step has been shown for.
+    time: f32,
+    /// Whether the current step has been done, even if it hasn't been
+    /// shown for long enough to move on yet.
+    done: bool,
+    /// Distance the cursor has moved during the current step.
+    moved: f32,
+    last_cursor: Option<Point2<f32>>,
+}
+
+impl TutorialStep {
+    fn next(self) -> TutorialStep {
+        match self {
+            TutorialStep::MoveCursor => TutorialStep::SpringLag,
+            TutorialStep::SpringLag => TutorialStep::HitWall,
+            TutorialStep::HitWall => TutorialStep::HitTarget,
+            TutorialStep::HitTarget | TutorialStep::Done => TutorialStep::Done,
+        }
+    }
+
+    /// Gets the number of the step counting from 1, which is past
+    /// `TUTORIAL_STEPS` once it's done.
+    pub fn number(self) -> usize {
+        self as usize + 1
+    }
+
+    /// Gets what to tell the player to do, one line at a time.
+    pub fn prompt(self) -> &'static [&'static str] {
+        match self {
+            TutorialStep::MoveCursor => {
+                &[
+                    "Move your mouse around. The small circle is your cursor,",
+                    "and the big one is your ball.",
+                ]
+            },
+            TutorialStep::SpringLag => {
+                &[
+                    "Your ball is pulled towards your cursor by a spring, so",
+                    "it lags behind. Flick your cursor away to stretch it.",
+                ]
+            },
+            TutorialStep::HitWall => {
+                &[
+                    "Swing your ball into the edge of the arena. During a",
+                    "round that knocks you out, so learn how far it swings.",
+                ]
+            },
+            TutorialStep::HitTarget => {
+                &[
+                    "Hit a target's cursor with your ball to knock it out,",
+                    "and keep your own cursor away from their balls. Turn on",
+                    "targets in the practice window if there aren't any.",
+                ]
+            },
+            TutorialStep::Done => {
+                &[
+                    "That's all there is to it! Keep practicing, or",
+                    "disconnect from the pause menu to play online.",
+                ]
+            },
+        }
+    }
+}
+
+impl Default for Tutorial {
+    fn default() -> Tutorial {
+        Tutorial {
+            step: TutorialStep::MoveCursor,
+            time: 0.0,
+            done: false,
+            moved: 0.0,
+            last_cursor: None,
+        }
+    }
+}
+
+impl Tutorial {
+    pub fn step(&self) -> TutorialStep {
+        self.step
+    }
+
+    /// Checks the game for whether the current step has been done
+    /// after another `dt` seconds.
+    pub fn update(&mut self, dt: f32, game: &Game) {
+        let player_id = game.player_id();
+        let ball = game
+            .latest_players()
+            .into_iter()
+            .find(|&(id, _)| id == player_id)
+            .and_then(|(_, player)| {
+                let state = player.state();
+                if state.alive() {
+                    Some(state.ball)
+                } else {
+                    None
+                }
+            });
+        self.observe(dt, game.cursor(), ball, game.settings());
+    }
+
+    /// Checks whether a kill finished the current step, since the
+    /// game doesn't show it once it's happened.
+    pub fn handle_event(&mut self, event: &GameEvent, player_id: PlayerId) {
+        if let GameEvent::Kill {
+            killer,
+            victim,
+        } = *event
+        {
+            self.done |= match self.step {
+                // Hitting the wall is only a kill during a round.
+                TutorialStep::HitWall => {
+                    killer == player_id && victim == player_id
+                },
+                TutorialStep::HitTarget => {
+                    killer == player_id && victim != player_id
+                },
+                _ => false,
+            };
+        }
+    }
+
+    /// Moves on once the current step is done, given where this
+    /// client's cursor is, and its ball if it's alive.
+    fn observe(
+        &mut self,
+        dt: f32,
+        cursor: Point2<f32>,
+        ball: Option<Ball>,
+        settings: &GameSettings,
+    ) {
+        self.time += dt;
+        if let Some(last) = self.last_cursor.replace(cursor) {
+            self.moved += nalgebra::distance(&last, &cursor);
+        }
+        self.done |= match self.step {
+            TutorialStep::MoveCursor => self.moved >= MOVE_DISTANCE,
+            TutorialStep::SpringLag => {
+                ball.map_or(false, |ball| {
+                    nalgebra::distance(&ball.position, &cursor) >= LAG_DISTANCE
+                })
+            },
+            TutorialStep::HitWall => {
+                ball.map_or(false, |ball| {
+                    let reach =
+                        ball.position.coords.norm() + ball.radius(settings);
+                    reach >= settings.bounds_radius - WALL_MARGIN
+                })
+            },
+            TutorialStep::HitTarget | TutorialStep::Done => false,
+        };
+        if self.done && self.time >= MIN_STEP_TIME {
+            self.step = self.step.next();
+            self.time = 0.0;
+            self.done = false;
+            self.moved = 0.0;
+        }
+    }
+}
+
+#[test]
+fn steps_wait_to_be_done() {
+    let settings = GameSettings::default();
+    let mut tutorial = Tutorial::default();
+    // Moving far enough still leaves the prompt up for a while.
+    for i in 0..4 {
+        let cursor = Point2::new(0.5 - (i % 2) as f32, 0.0);
+        tutorial.observe(0.1, cursor, None, &settings);
+    }
+    assert_eq!(tutorial.step(), TutorialStep::MoveCursor);
+    tutorial.observe(MIN_STEP_TIME, Point2::origin(), None, &settings);
+    assert_eq!(tutorial.step(), TutorialStep::SpringLag);
+
+    // A dead ball can't lag behind.
+    tutorial.observe(MIN_STEP_TIME, Point2::origin(), None, &settings);
+    assert_eq!(tutorial.step(), TutorialStep::SpringLag);
+    let ball = Ball::starting(Point2::new(0.5, 0.0), &settings);
+    tutorial.observe(0.1, Point2::new(-0.5, 0.0), Some(ball), &settings);
+    assert_eq!(tutorial.step(), TutorialStep::HitWall);
+
+    tutorial.handle_event(
+        &GameEvent::Kill {
+            killer: 0,
+            victim: 0,
+        },
+        0,
+    );
+    tutorial.observe(MIN_STEP_TIME, Point2::origin(), None, &settings);
+    assert_eq!(tutorial.step(), TutorialStep::HitTarget);
+    tutorial.handle_event(
+        &GameEvent::Kill {
+            killer: 1,
+            victim: 0,
+        },
+        0,
+    );
+    tutorial.observe(MIN_STEP_TIME, Point2::origin(), None, &settings);
+    assert_eq!(tutorial.step(), TutorialStep::HitTarget);
+}
@@ -1,9 +1,14 @@
-use crate::graphics::{create_buffer, DrawContext, Graphics, GLOBAL_UBO_SIZE};
+use crate::graphics::{
+    create_buffer,
+    DrawContext,
+    Graphics,
+    GraphicsError,
+    GLOBAL_UBO_SIZE,
+};
 use gfx_hal::{
     buffer::{Access, Usage},
-    command::{BufferCopy, OneShot},
     format::Format,
-    memory::{Barrier, Dependencies, Properties},
+    memory::Properties,
     pass::Subpass,
     pso::{
         AttributeDesc,
@@ -44,6 +49,9 @@ struct Vertex {
 pub struct Circle {
     pub center: Point2<f32>,
     pub radius: f32,
+    /// Radius of the hole in the middle, which makes it a ring, or `0`
+    /// for a filled circle.
+    pub inner_radius: f32,
     pub color: LinSrgb,
 }
 
@@ -74,7 +82,9 @@ pub struct CircleRenderer<B: Backend> {
 }
 
 impl<B: Backend> CircleRenderer<B> {
-    pub fn new(graphics: &mut Graphics<B>) -> CircleRenderer<B> {
+    pub fn new(
+        graphics: &mut Graphics<B>,
+    ) -> Result<CircleRenderer<B>, GraphicsError> {
         // Create vertex buffer.
         let size = 4 * mem::size_of::<Vertex>() as u64;
         let (vertex_buffer, vertex_memory, _) = unsafe {
@@ -84,66 +94,15 @@ impl<B: Backend> CircleRenderer<B> {
                 Properties::DEVICE_LOCAL,
                 Usage::TRANSFER_DST | Usage::VERTEX,
                 size,
-            )
-        };
-
-        // Create staging buffer.
-        let (staging_buffer, staging_memory, _) = unsafe {
-            create_buffer::<B>(
-                &graphics.device,
-                &graphics.memory_types,
-                Properties::CPU_VISIBLE,
-                Usage::TRANSFER_SRC,
-                size,
-            )
+            )?
         };
 
-        // Copy vertices to the staging buffer.
-        unsafe {
-            let mut map = graphics
-                .device
-                .acquire_mapping_writer(&staging_memory, 0..size)
-                .unwrap();
-            map.clone_from_slice(&VERTS);
-            graphics.device.release_mapping_writer(map).unwrap();
-        }
-
-        // Copy staging buffer to vertex buffer.
-        // TODO: handle unified graphics/transfer queue differently.
-        let mut cmd_buffer =
-            graphics.transfer_command_pool.acquire_command_buffer::<OneShot>();
-
-        unsafe {
-            cmd_buffer.begin();
-
-            cmd_buffer.copy_buffer(
-                &staging_buffer,
-                &vertex_buffer,
-                &[BufferCopy {
-                    src: 0,
-                    dst: 0,
-                    size,
-                }],
-            );
-
-            let barrier = Barrier::whole_buffer(
-                &vertex_buffer,
-                Access::TRANSFER_WRITE..Access::VERTEX_BUFFER_READ,
-            );
-            cmd_buffer.pipeline_barrier(
-                PipelineStage::TRANSFER..PipelineStage::VERTEX_INPUT,
-                Dependencies::empty(),
-                &[barrier],
-            );
-
-            cmd_buffer.finish();
-
-            graphics.device.reset_fence(&graphics.transfer_fence).unwrap();
-            graphics.queue_group.queues[0].submit_nosemaphores(
-                Some(&cmd_buffer),
-                Some(&graphics.transfer_fence),
-            );
-        }
+        graphics.upload_buffer(
+            &VERTS,
+            &vertex_buffer,
+            0,
+            (Access::VERTEX_BUFFER_READ, PipelineStage::VERTEX_INPUT),
+        )?;
 
         // Load shaders.
         let vs_module = {
@@ -151,14 +110,22 @@ impl<B: Backend> CircleRenderer<B> {
                 env!("OUT_DIR"),
                 "/shaders/circle.vert.spirv"
             ));
-            unsafe { graphics.device.create_shader_module(spirv).unwrap() }
+            unsafe {
+                graphics.device.create_shader_module(spirv).map_err(|err| {
+                    GraphicsError::call("creating shader module", err)
+                })?
+            }
         };
         let fs_module = {
             let spirv = include_bytes!(concat!(
                 env!("OUT_DIR"),
                 "/shaders/circle.frag.spirv"
             ));
-            unsafe { graphics.device.create_shader_module(spirv).unwrap() }
+            unsafe {
+                graphics.device.create_shader_module(spirv).map_err(|err| {
+                    GraphicsError::call("creating shader module", err)
+                })?
+            }
         };
 
         // Create descriptor set layout and descriptor set for global
@@ -175,13 +142,17 @@ impl<B: Backend> CircleRenderer<B> {
             graphics
                 .device
                 .create_descriptor_set_layout(&[global_ubo_layout_binding], &[])
-                .unwrap()
+                .map_err(|err| {
+                    GraphicsError::call("creating descriptor set layout", err)
+                })?
         };
         let global_ubo_descriptor_set = unsafe {
             graphics
                 .descriptor_pool
                 .allocate_set(&descriptor_set_layout)
-                .unwrap()
+                .map_err(|err| {
+                    GraphicsError::call("allocating descriptor set", err)
+                })?
         };
         let write = DescriptorSetWrite {
             set: &global_ubo_descriptor_set,
@@ -204,7 +175,9 @@ impl<B: Backend> CircleRenderer<B> {
                     Some(&descriptor_set_layout),
                     &[(ShaderStageFlags::GRAPHICS, 0..8)],
                 )
-                .unwrap()
+                .map_err(|err| {
+                    GraphicsError::call("creating pipeline layout", err)
+                })?
         };
 
         let vs_entry = EntryPoint {
@@ -267,20 +240,10 @@ impl<B: Backend> CircleRenderer<B> {
             graphics
                 .device
                 .create_graphics_pipeline(&pipeline_desc, None)
-                .unwrap()
+                .map_err(|err| GraphicsError::call("creating pipeline", err))?
         };
 
-        // When transfer is finished, delete the staging buffers.
-        unsafe {
-            graphics
-                .device
-                .wait_for_fence(&graphics.transfer_fence, !0)
-                .unwrap();
-            graphics.device.destroy_buffer(staging_buffer);
-            graphics.device.free_memory(staging_memory);
-        }
-
-        CircleRenderer {
+        Ok(CircleRenderer {
             vertex_buffer,
             vertex_memory,
             pipeline_layout,
@@ -289,7 +252,7 @@ impl<B: Backend> CircleRenderer<B> {
             vs_module,
             fs_module,
             pipeline,
-        }
+        })
     }
 
     pub fn draw<I: IntoIterator<Item = Circle>>(
@@ -315,7 +278,7 @@ impl<B: Backend> CircleRenderer<B> {
             for circle in circles {
                 let push_constants = [
                     circle.radius,
-                    0.0, // padding
+                    circle.inner_radius,
                     circle.center.x,
                     circle.center.y,
                     circle.color.red,
@@ -336,7 +299,9 @@ impl<B: Backend> CircleRenderer<B> {
     }
 
     pub fn destroy(self, graphics: &mut Graphics<B>) {
-        graphics.device.wait_idle().unwrap();
+        // Destroying is still needed if the device was lost, but it
+        // can't be waited on then.
+        let _ = graphics.device.wait_idle();
         unsafe {
             graphics.device.destroy_buffer(self.vertex_buffer);
             graphics.device.free_memory(self.vertex_memory);
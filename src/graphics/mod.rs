@@ -1,4 +1,7 @@
+use crate::profile_scope;
+use crate::viewport::ViewportMapping;
 use arrayvec::ArrayVec;
+use failure::Fail;
 use gfx_hal::{
     adapter::DeviceType,
     buffer,
@@ -10,6 +13,7 @@ use gfx_hal::{
         Primary,
         RenderPassInlineEncoder,
     },
+    device::OomOrDeviceLost,
     error::DeviceCreationError,
     format::{Aspects, ChannelType, Format, Swizzle},
     image::{self, Layout, SubresourceRange, ViewKind},
@@ -25,7 +29,7 @@ use gfx_hal::{
     },
     pool::CommandPoolCreateFlags,
     pso::{DescriptorRangeDesc, DescriptorType, PipelineStage, Rect, Viewport},
-    window::CompositeAlpha,
+    window::{AcquireError, CompositeAlpha},
     Adapter,
     Backbuffer,
     Backend,
@@ -48,20 +52,31 @@ use gfx_hal::{
 use imgui::{ImGui, Ui};
 use imgui_gfx_hal;
 use itertools::Itertools;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use smallvec::SmallVec;
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
 use std::mem;
-use std::time::Duration;
-use take_mut;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use winit::WindowId;
 
 pub mod circles;
+pub mod upload;
 
 pub use self::circles::{Circle, CircleRenderer};
 
 /// The maximum number of frames in flight.
+///
+/// This is separate from the number of swapchain images, which can be
+/// more than this for triple buffering.
 pub const MAX_FRAMES: usize = 2;
 
+/// Number of swapchain images asked for until another number is
+/// picked, if the surface allows it.
+pub const DEFAULT_IMAGE_COUNT: u32 = 2;
+
 #[repr(C, packed)]
 struct GlobalUbo {
     scale: [f32; 2],
@@ -69,47 +84,139 @@ struct GlobalUbo {
 
 pub const GLOBAL_UBO_SIZE: u64 = mem::size_of::<GlobalUbo>() as u64;
 
+/// Errors from setting up or drawing with the graphics device.
+#[derive(Fail, Debug)]
+pub enum GraphicsError {
+    #[fail(display = "no suitable graphics adapter found")]
+    NoAdapter,
+    #[fail(display = "no suitable memory type for {}", _0)]
+    NoMemoryType(&'static str),
+    #[fail(display = "window surface can't be presented to")]
+    UnsupportedSurface,
+    /// The window is minimized, so there's nothing to draw to until
+    /// it's restored.
+    #[fail(display = "window surface has zero size")]
+    ZeroSizeSurface,
+    #[fail(display = "out of memory")]
+    OutOfMemory,
+    /// The device stopped working, most likely from a driver crash or
+    /// reset, and everything made with it has to be made again.
+    #[fail(display = "graphics device lost")]
+    DeviceLost,
+    #[fail(display = "window surface lost")]
+    SurfaceLost,
+    #[fail(display = "{} failed: {}", action, err)]
+    Call {
+        action: &'static str,
+        err: String,
+    },
+}
+
+impl GraphicsError {
+    /// Wraps the error from a device call that can only fail for
+    /// reasons that can't be handled.
+    pub fn call<E: fmt::Debug>(action: &'static str, err: E) -> GraphicsError {
+        GraphicsError::Call {
+            action,
+            err: format!("{:?}", err),
+        }
+    }
+
+    /// Returns whether the `Graphics` has to be made again before it
+    /// can draw anything.
+    pub fn needs_recreate(&self) -> bool {
+        match self {
+            GraphicsError::DeviceLost | GraphicsError::SurfaceLost => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<OomOrDeviceLost> for GraphicsError {
+    fn from(err: OomOrDeviceLost) -> GraphicsError {
+        match err {
+            OomOrDeviceLost::OutOfMemory(_) => GraphicsError::OutOfMemory,
+            OomOrDeviceLost::DeviceLost(_) => GraphicsError::DeviceLost,
+        }
+    }
+}
+
+/// Choices that every window's swapchain is built with.
+#[derive(Copy, Clone)]
+struct SwapchainSettings {
+    color_format: Format,
+    present_mode: PresentMode,
+    /// Number of images to ask for, which is clamped to what the
+    /// surface supports.
+    image_count: u32,
+}
+
 struct SwapchainState<B: Backend> {
     swapchain: B::Swapchain,
+    /// Number of images that the swapchain was actually built with.
+    image_count: u32,
     viewport: Viewport,
     framebuffers: Vec<B::Framebuffer>,
     frame_views: Vec<B::ImageView>,
 }
 
-pub struct Graphics<B: Backend> {
+/// Everything needed to draw to a single window.
+struct Target<B: Backend> {
     surface: B::Surface,
-    adapter: Adapter<B>,
-    device: B::Device,
-    memory_types: Vec<MemoryType>,
-    queue_group: QueueGroup<B, gfx_hal::Graphics>,
-    transfer_command_pool: CommandPool<B, gfx_hal::Graphics>,
+    /// Current swapchain, or `None` if building it failed, in which
+    /// case it's built again on the next frame.
+    swapchain_state: Option<SwapchainState<B>>,
     frame_command_pools:
         ArrayVec<[CommandPool<B, gfx_hal::Graphics>; MAX_FRAMES]>,
     frame_cmd_buffers: ArrayVec<
         [CommandBuffer<B, gfx_hal::Graphics, OneShot, Primary>; MAX_FRAMES],
     >,
+    image_available_semaphores: ArrayVec<[B::Semaphore; MAX_FRAMES]>,
+    frame_finished_semaphores: ArrayVec<[B::Semaphore; MAX_FRAMES]>,
+    frame_fences: ArrayVec<[B::Fence; MAX_FRAMES]>,
+    /// Renderer for the imgui context that draws to this window,
+    /// since the font texture belongs to the context.
+    imgui_renderer: imgui_gfx_hal::Renderer<B>,
+    cleanup: ArrayVec<[SmallVec<[Cleanup<B>; 3]>; MAX_FRAMES]>,
+    current_frame: usize,
+    swapchain_update: bool,
+}
+
+pub struct Graphics<B: Backend> {
+    adapter: Adapter<B>,
+    device: B::Device,
+    memory_types: Vec<MemoryType>,
+    queue_group: QueueGroup<B, gfx_hal::Graphics>,
+    transfer_command_pool: CommandPool<B, gfx_hal::Graphics>,
+    uploader: upload::Uploader<B>,
     global_ubo_update_command_pool: CommandPool<B, gfx_hal::Graphics>,
     global_ubo_update_cmd_buffer:
         CommandBuffer<B, gfx_hal::Graphics, OneShot, Primary>,
-    swapchain_state: SwapchainState<B>,
+    /// Every window being drawn to, each with its own surface and
+    /// swapchain.
+    targets: HashMap<WindowId, Target<B>>,
+    /// The window that the game is drawn in, whose size the global
+    /// UBO is scaled to.
+    main_window: WindowId,
     render_pass: B::RenderPass,
     global_ubo: B::Buffer,
     global_ubo_memory: B::Memory,
     descriptor_pool: B::DescriptorPool,
-    image_available_semaphores: ArrayVec<[B::Semaphore; MAX_FRAMES]>,
-    frame_finished_semaphores: ArrayVec<[B::Semaphore; MAX_FRAMES]>,
     global_ubo_update_fence: B::Fence,
-    frame_fences: ArrayVec<[B::Fence; MAX_FRAMES]>,
-    transfer_fence: B::Fence,
-    imgui_renderer: imgui_gfx_hal::Renderer<B>,
     color_format: Format,
     present_mode: PresentMode,
     supported_present_modes: Vec<PresentMode>,
-    cleanup: ArrayVec<[SmallVec<[Cleanup<B>; 3]>; MAX_FRAMES]>,
-    current_frame: usize,
-    swapchain_update: bool,
+    /// Number of swapchain images asked for.
+    image_count: u32,
+    /// How world space is fitted into the main window.
+    mapping: ViewportMapping,
     viewport_update: bool,
     first_frame: bool,
+    /// When the last frame was presented to the main window.
+    last_present: Option<Instant>,
+    /// Seconds between the last two frames presented to the main
+    /// window, until it's taken.
+    present_interval: Option<f32>,
 }
 
 /// Resources to clean up after a particular frame is done.
@@ -193,25 +300,72 @@ pub unsafe fn create_buffer<B: Backend>(
     properties: Properties,
     usage: buffer::Usage,
     size: u64,
-) -> (B::Buffer, B::Memory, u64) {
-    let mut buffer = device.create_buffer(size, usage).unwrap();
+) -> Result<(B::Buffer, B::Memory, u64), GraphicsError> {
+    let mut buffer = device
+        .create_buffer(size, usage)
+        .map_err(|err| GraphicsError::call("creating buffer", err))?;
     let requirements = device.get_buffer_requirements(&buffer);
     let memory_type =
         select_memory_type(&memory_types, Some(requirements), properties)
-            .expect("can't find memory type");
-    let memory =
-        device.allocate_memory(memory_type, requirements.size).unwrap();
-    device.bind_buffer_memory(&memory, 0, &mut buffer).unwrap();
+            .ok_or(GraphicsError::NoMemoryType("buffer"))?;
+    let memory = device
+        .allocate_memory(memory_type, requirements.size)
+        .map_err(|err| GraphicsError::call("allocating buffer memory", err))?;
+    device
+        .bind_buffer_memory(&memory, 0, &mut buffer)
+        .map_err(|err| GraphicsError::call("binding buffer memory", err))?;
+
+    Ok((buffer, memory, requirements.size))
+}
 
-    (buffer, memory, requirements.size)
+/// Makes the render pass that everything is drawn in, which clears
+/// the frame first.
+fn create_render_pass<B: Backend>(
+    device: &B::Device,
+    color_format: Format,
+) -> Result<B::RenderPass, GraphicsError> {
+    let color_attachment = Attachment {
+        format: Some(color_format),
+        samples: 1,
+        ops: AttachmentOps::new(
+            AttachmentLoadOp::Clear,
+            AttachmentStoreOp::Store,
+        ),
+        stencil_ops: AttachmentOps::DONT_CARE,
+        layouts: Layout::Undefined..Layout::Present,
+    };
+
+    let subpass = SubpassDesc {
+        colors: &[(0, Layout::ColorAttachmentOptimal)],
+        depth_stencil: None,
+        inputs: &[],
+        resolves: &[],
+        preserves: &[],
+    };
+
+    let dependency = SubpassDependency {
+        passes: SubpassRef::External..SubpassRef::Pass(0),
+        stages: PipelineStage::COLOR_ATTACHMENT_OUTPUT..
+            PipelineStage::COLOR_ATTACHMENT_OUTPUT,
+        accesses: image::Access::empty()..
+            (image::Access::COLOR_ATTACHMENT_READ |
+                image::Access::COLOR_ATTACHMENT_WRITE),
+    };
+
+    unsafe {
+        device
+            .create_render_pass(&[color_attachment], &[subpass], &[dependency])
+            .map_err(|err| GraphicsError::call("creating render pass", err))
+    }
 }
 
 impl<B: Backend> Graphics<B> {
     pub fn new<I: Instance<Backend = B>>(
         instance: &I,
-        mut surface: B::Surface,
+        surface: B::Surface,
+        window_id: WindowId,
         imgui: &mut ImGui,
-    ) -> Graphics<B> {
+    ) -> Result<Graphics<B>, GraphicsError> {
         let mut adapters =
             instance.enumerate_adapters().into_iter().sorted_by(|a, b| {
                 // Prefer discrete gpus to everything else, and everything else
@@ -225,15 +379,37 @@ impl<B: Backend> Graphics<B> {
                 }
             });
 
-        // Pick the first adapter with a graphics queue family.
-        let (adapter, device, mut queue_group) = loop {
-            let adapter = adapters.next().expect("No suitable adapter found");
-            match adapter.open_with::<_, gfx_hal::Graphics>(1, |family| {
+        // Pick the first adapter with a graphics queue family, and open
+        // a family that only does transfers alongside it if there is one.
+        let (adapter, device, queue_group, transfer_group) = loop {
+            let adapter = adapters.next().ok_or(GraphicsError::NoAdapter)?;
+            let families = &adapter.queue_families;
+            let graphics_family = match families.iter().find(|family| {
                 family.supports_graphics() &&
                     surface.supports_queue_family(family)
             }) {
-                Ok((device, queue_group)) => {
-                    break (adapter, device, queue_group);
+                Some(family) => family,
+                None => continue,
+            };
+            let transfer_family = families.iter().find(|family| {
+                family.supports_transfer() &&
+                    !family.supports_graphics() &&
+                    !family.supports_compute()
+            });
+            let graphics_id = graphics_family.id();
+            let transfer_id = transfer_family.map(|family| family.id());
+            let mut open = vec![(graphics_family, &[1.0][..])];
+            open.extend(transfer_family.map(|family| (family, &[1.0][..])));
+            match adapter.physical_device.open(&open) {
+                Ok(mut gpu) => {
+                    let queue_group = gpu
+                        .queues
+                        .take::<gfx_hal::Graphics>(graphics_id)
+                        .expect("graphics queue family wasn't opened");
+                    let transfer_group = transfer_id.and_then(|id| {
+                        gpu.queues.take::<gfx_hal::Transfer>(id)
+                    });
+                    break (adapter, gpu.device, queue_group, transfer_group);
                 },
                 // These errors mean we just give up on this adapter.
                 Err(DeviceCreationError::MissingExtension) |
@@ -248,42 +424,28 @@ impl<B: Backend> Graphics<B> {
             }
         };
         info!("selected adapter '{}'", adapter.info.name);
+        if let Some(ref transfer_group) = transfer_group {
+            info!(
+                "uploading through transfer queue family {:?}",
+                transfer_group.family()
+            );
+        }
         let physical_device = &adapter.physical_device;
         let memory_types = physical_device.memory_properties().memory_types;
 
-        let transfer_fence = device.create_fence(false).unwrap();
-
-        let mut transfer_command_pool = unsafe {
+        let create_fence = |signaled| {
             device
-                .create_command_pool_typed(
-                    &queue_group,
-                    CommandPoolCreateFlags::TRANSIENT,
-                )
-                .unwrap()
+                .create_fence(signaled)
+                .map_err(|err| GraphicsError::call("creating fence", err))
         };
-
-        // Create global UBO
-        let (global_ubo, global_ubo_memory) = unsafe {
-            let mut global_ubo = device
-                .create_buffer(
-                    GLOBAL_UBO_SIZE,
-                    buffer::Usage::TRANSFER_DST | buffer::Usage::UNIFORM,
+        // This takes the queue group, since it's borrowed mutably in
+        // between pools being created.
+        let create_command_pool =
+            |queue_group: &QueueGroup<B, gfx_hal::Graphics>, flags| unsafe {
+                device.create_command_pool_typed(queue_group, flags).map_err(
+                    |err| GraphicsError::call("creating command pool", err),
                 )
-                .unwrap();
-            let requirements = device.get_buffer_requirements(&global_ubo);
-            let memory_type = select_memory_type(
-                &memory_types,
-                Some(requirements),
-                Properties::DEVICE_LOCAL,
-            )
-            .expect("can't find memory type for global uniform buffer");
-            let global_ubo_memory =
-                device.allocate_memory(memory_type, requirements.size).unwrap();
-            device
-                .bind_buffer_memory(&global_ubo_memory, 0, &mut global_ubo)
-                .unwrap();
-            (global_ubo, global_ubo_memory)
-        };
+            };
 
         // Determine image capabilities and color format
         // TODO figure out what available present modes are
@@ -308,96 +470,204 @@ impl<B: Backend> Graphics<B> {
                 .unwrap_or(formats[0])
         });
 
-        let render_pass = {
-            let color_attachment = Attachment {
-                format: Some(color_format),
-                samples: 1,
-                ops: AttachmentOps::new(
-                    AttachmentLoadOp::Clear,
-                    AttachmentStoreOp::Store,
-                ),
-                stencil_ops: AttachmentOps::DONT_CARE,
-                layouts: Layout::Undefined..Layout::Present,
-            };
-
-            let subpass = SubpassDesc {
-                colors: &[(0, Layout::ColorAttachmentOptimal)],
-                depth_stencil: None,
-                inputs: &[],
-                resolves: &[],
-                preserves: &[],
-            };
+        // Everything made from here on has to be destroyed again if a
+        // later step fails.
+        let mut transfer_command_pool = None;
+        let mut uploader = None;
+        let mut global_ubo = None;
+        let mut render_pass = None;
+        let mut descriptor_pool = None;
+        let mut global_ubo_update_command_pool = None;
+        let mut global_ubo_update_fence = None;
+        let created = (|| -> Result<(), GraphicsError> {
+            transfer_command_pool = Some(create_command_pool(
+                &queue_group,
+                CommandPoolCreateFlags::TRANSIENT,
+            )?);
+            uploader = Some(upload::Uploader::new(
+                &device,
+                &memory_types,
+                queue_group.family(),
+                transfer_group,
+            )?);
+
+            // Create global UBO
+            global_ubo = Some(unsafe {
+                let (global_ubo, global_ubo_memory, _) = create_buffer::<B>(
+                    &device,
+                    &memory_types,
+                    Properties::DEVICE_LOCAL,
+                    buffer::Usage::TRANSFER_DST | buffer::Usage::UNIFORM,
+                    GLOBAL_UBO_SIZE,
+                )?;
+                (global_ubo, global_ubo_memory)
+            });
 
-            let dependency = SubpassDependency {
-                passes: SubpassRef::External..SubpassRef::Pass(0),
-                stages: PipelineStage::COLOR_ATTACHMENT_OUTPUT..
-                    PipelineStage::COLOR_ATTACHMENT_OUTPUT,
-                accesses: image::Access::empty()..
-                    (image::Access::COLOR_ATTACHMENT_READ |
-                        image::Access::COLOR_ATTACHMENT_WRITE),
-            };
+            render_pass = Some(create_render_pass(&device, color_format)?);
 
-            unsafe {
+            // TODO: figure out pool size
+            descriptor_pool = Some(unsafe {
                 device
-                    .create_render_pass(
-                        &[color_attachment],
-                        &[subpass],
-                        &[dependency],
+                    .create_descriptor_pool(
+                        1,
+                        &[DescriptorRangeDesc {
+                            ty: DescriptorType::UniformBuffer,
+                            count: 1,
+                        }],
                     )
-                    .unwrap()
+                    .map_err(|err| {
+                        GraphicsError::call("creating descriptor pool", err)
+                    })?
+            });
+
+            global_ubo_update_command_pool = Some(create_command_pool(
+                &queue_group,
+                CommandPoolCreateFlags::empty(),
+            )?);
+            global_ubo_update_fence = Some(create_fence(true)?);
+            Ok(())
+        })();
+        if let Err(err) = created {
+            unsafe {
+                if let Some(pool) = transfer_command_pool {
+                    device.destroy_command_pool(pool.into_raw());
+                }
+                if let Some(uploader) = uploader {
+                    uploader.destroy(&device);
+                }
+                if let Some((buffer, memory)) = global_ubo {
+                    device.destroy_buffer(buffer);
+                    device.free_memory(memory);
+                }
+                if let Some(render_pass) = render_pass {
+                    device.destroy_render_pass(render_pass);
+                }
+                if let Some(descriptor_pool) = descriptor_pool {
+                    device.destroy_descriptor_pool(descriptor_pool);
+                }
+                if let Some(pool) = global_ubo_update_command_pool {
+                    device.destroy_command_pool(pool.into_raw());
+                }
             }
+            return Err(err);
+        }
+        // Every step succeeded, so these are all set.
+        let transfer_command_pool = transfer_command_pool.unwrap();
+        let uploader = uploader.unwrap();
+        let (global_ubo, global_ubo_memory) = global_ubo.unwrap();
+        let render_pass = render_pass.unwrap();
+        let descriptor_pool = descriptor_pool.unwrap();
+        let mut global_ubo_update_command_pool =
+            global_ubo_update_command_pool.unwrap();
+        let global_ubo_update_fence = global_ubo_update_fence.unwrap();
+        let global_ubo_update_cmd_buffer =
+            global_ubo_update_command_pool.acquire_command_buffer::<OneShot>();
+
+        let mut graphics = Graphics {
+            adapter,
+            memory_types,
+            device,
+            queue_group,
+            transfer_command_pool,
+            uploader,
+            global_ubo_update_command_pool,
+            global_ubo_update_cmd_buffer,
+            targets: HashMap::new(),
+            main_window: window_id,
+            render_pass,
+            global_ubo_update_fence,
+            descriptor_pool,
+            global_ubo,
+            global_ubo_memory,
+            color_format,
+            viewport_update: false,
+            first_frame: true,
+            last_present: None,
+            present_interval: None,
+            present_mode,
+            supported_present_modes,
+            image_count: DEFAULT_IMAGE_COUNT,
+            mapping: ViewportMapping::default(),
         };
+        if let Err(err) = graphics.add_window(window_id, surface, imgui) {
+            graphics.destroy();
+            return Err(err);
+        }
+        Ok(graphics)
+    }
+
+    /// Starts drawing to another window, with UI from its own imgui
+    /// context.
+    ///
+    /// Only the main window passed to `new` is scaled for drawing
+    /// the game in, so other windows are meant for UI.
+    pub fn add_window(
+        &mut self,
+        window_id: WindowId,
+        mut surface: B::Surface,
+        imgui: &mut ImGui,
+    ) -> Result<(), GraphicsError> {
+        let device = &self.device;
+        let physical_device = &self.adapter.physical_device;
+        let queue_group = &mut self.queue_group;
+
+        // The queue was picked for the main window's surface, so this
+        // one has to be able to use it too.
+        let family_supported = self
+            .adapter
+            .queue_families
+            .iter()
+            .find(|family| family.id() == queue_group.family())
+            .map_or(false, |family| surface.supports_queue_family(family));
+        if !family_supported {
+            return Err(GraphicsError::UnsupportedSurface);
+        }
 
         let imgui_renderer = imgui_gfx_hal::Renderer::new(
             imgui,
-            &device,
+            device,
             physical_device,
-            &render_pass,
+            &self.render_pass,
             0,
             MAX_FRAMES,
-            &mut transfer_command_pool,
+            &mut self.transfer_command_pool,
             &mut queue_group.queues[0],
         )
-        .unwrap();
+        .map_err(|err| GraphicsError::call("creating imgui renderer", err))?;
 
-        // TODO: figure out pool size
-        let descriptor_pool = unsafe {
+        // TODO: this is ugly!
+        let create_semaphore = || {
             device
-                .create_descriptor_pool(
-                    1,
-                    &[DescriptorRangeDesc {
-                        ty: DescriptorType::UniformBuffer,
-                        count: 1,
-                    }],
-                )
-                .unwrap()
+                .create_semaphore()
+                .map_err(|err| GraphicsError::call("creating semaphore", err))
         };
-
-        // TODO: this is ugly!
         let image_available_semaphores = (0..MAX_FRAMES)
-            .map(|_| device.create_semaphore().unwrap())
-            .collect();
+            .map(|_| create_semaphore())
+            .collect::<Result<_, _>>()?;
         let frame_finished_semaphores = (0..MAX_FRAMES)
-            .map(|_| device.create_semaphore().unwrap())
-            .collect();
+            .map(|_| create_semaphore())
+            .collect::<Result<_, _>>()?;
         let frame_fences = (0..MAX_FRAMES)
-            .map(|_| device.create_fence(true).unwrap())
-            .collect();
+            .map(|_| {
+                device
+                    .create_fence(true)
+                    .map_err(|err| GraphicsError::call("creating fence", err))
+            })
+            .collect::<Result<_, _>>()?;
         // Allocate a separate command pool for each frame, to allow
         // resetting the corresponding command buffers individually.
-        let mut frame_command_pools: ArrayVec<[_; MAX_FRAMES]> = (0..
-            MAX_FRAMES)
-            .map(|_| {
-                unsafe {
-                    device
-                        .create_command_pool_typed(
-                            &queue_group,
-                            CommandPoolCreateFlags::empty(),
-                        )
-                        .unwrap()
-                }
+        let mut frame_command_pools = (0..MAX_FRAMES)
+            .map(|_| unsafe {
+                device
+                    .create_command_pool_typed(
+                        queue_group,
+                        CommandPoolCreateFlags::empty(),
+                    )
+                    .map_err(|err| {
+                        GraphicsError::call("creating command pool", err)
+                    })
             })
-            .collect();
+            .collect::<Result<ArrayVec<[_; MAX_FRAMES]>, _>>()?;
         // Allocate a command buffer for each frame.
         let frame_cmd_buffers = (0..MAX_FRAMES)
             .map(|frame| {
@@ -405,61 +675,54 @@ impl<B: Backend> Graphics<B> {
             })
             .collect();
 
-        let mut global_ubo_update_command_pool = unsafe {
-            device
-                .create_command_pool_typed(
-                    &queue_group,
-                    CommandPoolCreateFlags::empty(),
-                )
-                .unwrap()
-        };
-        let global_ubo_update_cmd_buffer =
-            global_ubo_update_command_pool.acquire_command_buffer::<OneShot>();
-        let global_ubo_update_fence = device.create_fence(true).unwrap();
-
         let cleanup = (0..MAX_FRAMES).map(|_| SmallVec::new()).collect();
 
         let swapchain_state = SwapchainState::new(
-            &device,
+            device,
             physical_device,
             &mut surface,
-            &render_pass,
-            color_format,
-            present_mode,
+            &self.render_pass,
+            SwapchainSettings {
+                color_format: self.color_format,
+                present_mode: self.present_mode,
+                image_count: self.image_count,
+            },
             None,
-        );
+        )?;
 
-        Graphics {
+        let target = Target {
             surface,
-            adapter,
-            memory_types,
-            device,
-            queue_group,
-            transfer_command_pool,
-            transfer_fence,
+            swapchain_state: Some(swapchain_state),
             frame_command_pools,
             frame_cmd_buffers,
-            global_ubo_update_command_pool,
-            global_ubo_update_cmd_buffer,
-            swapchain_state,
-            render_pass,
             image_available_semaphores,
             frame_finished_semaphores,
-            global_ubo_update_fence,
             frame_fences,
-            descriptor_pool,
-            global_ubo,
-            global_ubo_memory,
             imgui_renderer,
-            color_format,
             cleanup,
             current_frame: 0,
             swapchain_update: false,
-            viewport_update: false,
-            first_frame: true,
-            present_mode,
-            supported_present_modes,
+        };
+        if let Some(old) = self.targets.insert(window_id, target) {
+            old.destroy(&self.device);
         }
+        Ok(())
+    }
+
+    /// Stops drawing to a window added with `add_window`, so that it
+    /// can be closed.
+    pub fn remove_window(&mut self, window_id: WindowId) {
+        if let Some(target) = self.targets.remove(&window_id) {
+            if let Err(err) = self.device.wait_idle() {
+                warn!("failed to wait for the graphics device: {:?}", err);
+            }
+            target.destroy(&self.device);
+        }
+    }
+
+    /// Gets the target for a window, which has to have been added.
+    fn target(&self, window_id: WindowId) -> &Target<B> {
+        self.targets.get(&window_id).expect("window was never added")
     }
 
     pub fn supported_present_modes(&self) -> &[PresentMode] {
@@ -472,7 +735,85 @@ impl<B: Backend> Graphics<B> {
 
     pub fn set_present_mode(&mut self, present_mode: PresentMode) {
         self.present_mode = present_mode;
-        self.swapchain_update = true;
+        for target in self.targets.values_mut() {
+            target.swapchain_update = true;
+        }
+    }
+
+    /// Gets the number of swapchain images asked for.
+    pub fn image_count(&self) -> u32 {
+        self.image_count
+    }
+
+    /// Asks for a different number of swapchain images, like 3 for
+    /// triple buffering, which every swapchain is rebuilt with on its
+    /// next frame.
+    pub fn set_image_count(&mut self, image_count: u32) {
+        self.image_count = image_count;
+        for target in self.targets.values_mut() {
+            target.swapchain_update = true;
+        }
+    }
+
+    /// Changes how world space is fitted into the main window, which
+    /// updates the global UBO on the next frame if it's different.
+    pub fn set_viewport_mapping(&mut self, mapping: ViewportMapping) {
+        if mapping != self.mapping {
+            self.mapping = mapping;
+            self.viewport_update = true;
+        }
+    }
+
+    /// Gets the number of images that the main window's swapchain
+    /// actually has, which can differ from the number asked for if
+    /// the surface doesn't support it.
+    pub fn swapchain_image_count(&self) -> Option<u32> {
+        self.target(self.main_window)
+            .swapchain_state
+            .as_ref()
+            .map(|swapchain_state| swapchain_state.image_count)
+    }
+
+    /// Takes the seconds between the last two frames presented to the
+    /// main window, if one has been presented since this was last
+    /// called.
+    ///
+    /// Skipped frames aren't presented, so they show up as a longer
+    /// interval.
+    pub fn take_present_interval(&mut self) -> Option<f32> {
+        self.present_interval.take()
+    }
+
+    /// Queues a copy of `data` into a device local buffer, which is
+    /// submitted before the next frame. See `upload::Uploader`.
+    pub fn upload_buffer<T: Copy>(
+        &mut self,
+        data: &[T],
+        dst: &B::Buffer,
+        dst_offset: u64,
+        used_by: (buffer::Access, PipelineStage),
+    ) -> Result<(), GraphicsError> {
+        self.uploader.upload_buffer(
+            &self.device,
+            &mut self.queue_group.queues[0],
+            data,
+            dst,
+            dst_offset,
+            used_by,
+        )
+    }
+
+    /// Calls `callback` once every upload queued so far is done.
+    pub fn on_uploads_complete<F: FnOnce() + 'static>(&mut self, callback: F) {
+        self.uploader.on_complete(callback);
+    }
+
+    /// Rebuilds a window's swapchain on the next frame, to match a
+    /// window that changed size or was restored after being minimized.
+    pub fn resize(&mut self, window_id: WindowId) {
+        if let Some(target) = self.targets.get_mut(&window_id) {
+            target.swapchain_update = true;
+        }
     }
 
     /// Waits until the buffers for a new frame open up or a timeout occurs.
@@ -482,86 +823,107 @@ impl<B: Backend> Graphics<B> {
     /// This is useful to avoid input lag, since ideally inputs will
     /// be processed right before rendering, so delaying inside
     /// `draw_frame` is undesirable.
-    pub fn wait_for_frame(&self, timeout: Option<Duration>) -> bool {
-        let frame_fence = &self.frame_fences[self.current_frame];
+    pub fn wait_for_frame(
+        &self,
+        window_id: WindowId,
+        timeout: Option<Duration>,
+    ) -> Result<bool, GraphicsError> {
+        let target = self.target(window_id);
+        let frame_fence = &target.frame_fences[target.current_frame];
         let timeout = match timeout {
             Some(timeout) => timeout.as_nanos() as u64,
             None => !0,
         };
-        unsafe { self.device.wait_for_fence(frame_fence, timeout).unwrap() }
+        let ready =
+            unsafe { self.device.wait_for_fence(frame_fence, timeout)? };
+        Ok(ready)
     }
 
+    /// Records and presents a frame to a window.
+    ///
+    /// If the swapchain is out of date, the frame is skipped and the
+    /// swapchain is rebuilt for the next one.
     pub fn draw_frame<F: FnOnce(DrawContext<B>)>(
         &mut self,
+        window_id: WindowId,
         ui: Ui,
         draw_fn: F,
-    ) -> Result<(), ()> {
+    ) -> Result<(), GraphicsError> {
+        let is_main = window_id == self.main_window;
+        let target =
+            self.targets.get_mut(&window_id).expect("window was never added");
         let queue = &mut self.queue_group.queues[0];
 
+        // Submit any uploads before the frame that uses them.
+        self.uploader.poll(&self.device)?;
+        self.uploader.flush(&self.device, queue)?;
+
         // Frame specific resources...
-        let frame_fence = &self.frame_fences[self.current_frame];
+        let frame_fence = &target.frame_fences[target.current_frame];
         let image_available_semaphore =
-            &self.image_available_semaphores[self.current_frame];
+            &target.image_available_semaphores[target.current_frame];
         let frame_finished_semaphore =
-            &self.frame_finished_semaphores[self.current_frame];
+            &target.frame_finished_semaphores[target.current_frame];
 
         let mut cleanup = None;
-        if self.swapchain_update || self.viewport_update {
-            let old_viewport = self.swapchain_state.viewport.clone();
-
-            {
-                // TODO: this is dumb and bad and I want partial
-                // borrowing
-                let &mut Graphics {
-                    ref device,
-                    ref adapter,
-                    ref mut surface,
-                    ref render_pass,
-                    ref color_format,
-                    ref present_mode,
-                    ref mut swapchain_state,
-                    ..
-                } = self;
-                take_mut::take(swapchain_state, |old| {
-                    let SwapchainState {
-                        framebuffers,
-                        frame_views,
-                        swapchain,
-                        ..
-                    } = old;
-                    // Clean up the resources from the old swapchain
-                    // when the last frame to use them is done.
-                    cleanup = Some(Cleanup {
-                        framebuffers,
-                        frame_views,
-                    });
-                    SwapchainState::new(
-                        device,
-                        &adapter.physical_device,
-                        surface,
-                        render_pass,
-                        *color_format,
-                        *present_mode,
-                        Some(swapchain),
-                    )
+        if target.swapchain_update || target.swapchain_state.is_none() {
+            let old = target.swapchain_state.take();
+            let old_viewport = old.as_ref().map(|old| old.viewport.clone());
+            let old_swapchain = old.map(|old| {
+                // Clean up the resources from the old swapchain when
+                // the last frame to use them is done.
+                cleanup = Some(Cleanup {
+                    framebuffers: old.framebuffers,
+                    frame_views: old.frame_views,
                 });
-            }
-
-            if self.swapchain_state.viewport != old_viewport {
+                old.swapchain
+            });
+            let result = SwapchainState::new(
+                &self.device,
+                &self.adapter.physical_device,
+                &mut target.surface,
+                &self.render_pass,
+                SwapchainSettings {
+                    color_format: self.color_format,
+                    present_mode: self.present_mode,
+                    image_count: self.image_count,
+                },
+                old_swapchain,
+            );
+            let swapchain_state = match result {
+                Ok(swapchain_state) => swapchain_state,
+                Err(err) => {
+                    // There's no swapchain until it's rebuilt on the
+                    // next frame, so this is as good a time as any.
+                    target.cleanup[target.current_frame].extend(cleanup);
+                    return Err(err);
+                },
+            };
+            if is_main &&
+                Some(&swapchain_state.viewport) != old_viewport.as_ref()
+            {
                 self.viewport_update = true;
             }
+            target.swapchain_state = Some(swapchain_state);
         }
+        let swapchain_state = match target.swapchain_state {
+            Some(ref mut swapchain_state) => swapchain_state,
+            None => unreachable!(),
+        };
 
-        if self.first_frame || self.viewport_update {
+        if is_main && (self.first_frame || self.viewport_update) {
             // Update the global UBO.
             unsafe {
                 // Make sure the command pool doesn't get reset while the
                 // last update is still running. It's okay to block on
                 // this since it doesn't happen very often.
                 self.device
-                    .wait_for_fence(&self.global_ubo_update_fence, !0)
-                    .unwrap();
-                self.device.reset_fence(&self.global_ubo_update_fence).unwrap();
+                    .wait_for_fence(&self.global_ubo_update_fence, !0)?;
+                self.device
+                    .reset_fence(&self.global_ubo_update_fence)
+                    .map_err(|err| {
+                        GraphicsError::call("resetting fence", err)
+                    })?;
                 // Reset the command buffer, so that it can be
                 // rerecorded with the new data.
                 self.global_ubo_update_command_pool.reset();
@@ -570,16 +932,11 @@ impl<B: Backend> Graphics<B> {
                 cmd_buffer.begin();
 
                 let (width, height) = (
-                    f32::from(self.swapchain_state.viewport.rect.w),
-                    f32::from(self.swapchain_state.viewport.rect.h),
+                    f32::from(swapchain_state.viewport.rect.w),
+                    f32::from(swapchain_state.viewport.rect.h),
                 );
-                let scale = if height < width {
-                    [height / width, 1.0]
-                } else {
-                    [1.0, width / height]
-                };
                 let data = GlobalUbo {
-                    scale,
+                    scale: self.mapping.scale(width, height),
                 };
                 // Double unsafe!
                 let data: [u8; 4 * 2] = mem::transmute(data);
@@ -625,32 +982,50 @@ impl<B: Backend> Graphics<B> {
 
         // Make sure there are no more than MAX_FRAMES frames in flight.
         unsafe {
-            self.device.wait_for_fence(frame_fence, !0).unwrap();
-            self.device.reset_fence(frame_fence).unwrap();
+            self.device.wait_for_fence(frame_fence, !0)?;
         }
 
         // Get swapchain index
-        let frame_index: SwapImageIndex = unsafe {
-            self.swapchain_state
-                .swapchain
-                .acquire_image(
-                    !0,
-                    FrameSync::Semaphore(image_available_semaphore),
-                )
-                .unwrap()
+        let acquired = unsafe {
+            swapchain_state.swapchain.acquire_image(
+                !0,
+                FrameSync::Semaphore(image_available_semaphore),
+            )
+        };
+        let frame_index: SwapImageIndex = match acquired {
+            Ok(frame_index) => frame_index,
+            Err(AcquireError::OutOfDate) | Err(AcquireError::NotReady) => {
+                // The fence hasn't been reset yet, so this frame can
+                // just be skipped.
+                if let Some(cleanup) = cleanup {
+                    target.cleanup[target.current_frame].push(cleanup);
+                }
+                target.swapchain_update = true;
+                return Ok(());
+            },
+            Err(AcquireError::OutOfMemory(_)) => {
+                return Err(GraphicsError::OutOfMemory)
+            },
+            Err(AcquireError::SurfaceLost(_)) => {
+                return Err(GraphicsError::SurfaceLost)
+            },
+            Err(AcquireError::DeviceLost(_)) => {
+                return Err(GraphicsError::DeviceLost)
+            },
         };
 
         unsafe {
-            self.frame_command_pools[self.current_frame].reset();
+            target.frame_command_pools[target.current_frame].reset();
         }
-        let cmd_buffer = &mut self.frame_cmd_buffers[self.current_frame];
+        let cmd_buffer = &mut target.frame_cmd_buffers[target.current_frame];
 
         // Clean up any old resources that were waiting on this frame.
-        for cleanup in self.cleanup[self.current_frame].drain() {
+        for cleanup in target.cleanup[target.current_frame].drain() {
             cleanup.destroy(&self.device);
         }
 
         unsafe {
+            profile_scope!(Record);
             cmd_buffer.begin();
 
             {
@@ -658,8 +1033,8 @@ impl<B: Backend> Graphics<B> {
                 // buffers
                 let mut encoder = cmd_buffer.begin_render_pass_inline(
                     &self.render_pass,
-                    &self.swapchain_state.framebuffers[frame_index as usize],
-                    self.swapchain_state.viewport.rect,
+                    &swapchain_state.framebuffers[frame_index as usize],
+                    swapchain_state.viewport.rect,
                     &[ClearValue::Color(ClearColor::Float([
                         0.0, 0.0, 0.0, 1.0,
                     ]))],
@@ -668,24 +1043,34 @@ impl<B: Backend> Graphics<B> {
                 {
                     let ctx = DrawContext {
                         encoder: &mut encoder,
-                        viewport: &self.swapchain_state.viewport,
+                        viewport: &swapchain_state.viewport,
                     };
                     draw_fn(ctx);
                 }
 
-                self.imgui_renderer
+                target
+                    .imgui_renderer
                     .render(
                         ui,
-                        self.current_frame,
+                        target.current_frame,
                         &mut encoder,
                         &self.device,
                         &self.adapter.physical_device,
                     )
-                    .unwrap();
+                    .map_err(|err| GraphicsError::call("drawing imgui", err))?;
             }
 
             cmd_buffer.finish();
+        }
 
+        unsafe {
+            profile_scope!(Present);
+            // This comes right before submitting, since the fence
+            // would never be signalled again if anything above failed
+            // after resetting it.
+            self.device
+                .reset_fence(frame_fence)
+                .map_err(|err| GraphicsError::call("resetting fence", err))?;
             let submission = Submission {
                 command_buffers: Some(&*cmd_buffer),
                 wait_semaphores: Some((
@@ -698,17 +1083,19 @@ impl<B: Backend> Graphics<B> {
         }
 
         if let Some(cleanup) = cleanup {
-            self.cleanup[self.current_frame].push(cleanup);
+            target.cleanup[target.current_frame].push(cleanup);
         }
 
-        self.current_frame = (self.current_frame + 1) % MAX_FRAMES;
-        self.swapchain_update = false;
-        self.viewport_update = false;
-        self.first_frame = false;
+        target.current_frame = (target.current_frame + 1) % MAX_FRAMES;
+        target.swapchain_update = false;
+        if is_main {
+            self.viewport_update = false;
+            self.first_frame = false;
+        }
 
         unsafe {
-            if self
-                .swapchain_state
+            profile_scope!(Present);
+            if swapchain_state
                 .swapchain
                 .present(
                     queue,
@@ -718,10 +1105,16 @@ impl<B: Backend> Graphics<B> {
                 .is_err()
             {
                 // TODO: detect if it's a bad swapchain error or not
-                self.swapchain_update = true;
-                return Err(());
+                target.swapchain_update = true;
             }
         }
+        if is_main {
+            let now = Instant::now();
+            self.present_interval = self
+                .last_present
+                .map(|last| now.duration_since(last).as_secs_f32());
+            self.last_present = Some(now);
+        }
 
         Ok(())
     }
@@ -730,56 +1123,80 @@ impl<B: Backend> Graphics<B> {
         let Graphics {
             device,
             transfer_command_pool,
-            transfer_fence,
-            frame_command_pools,
+            uploader,
             global_ubo_update_command_pool,
             render_pass,
-            frame_finished_semaphores,
-            image_available_semaphores,
             global_ubo_update_fence,
-            frame_fences,
             descriptor_pool,
-            swapchain_state,
+            targets,
             global_ubo,
             global_ubo_memory,
+            ..
+        } = self;
+
+        // A lost device can't be waited on, but its resources still
+        // have to be destroyed.
+        if let Err(err) = device.wait_idle() {
+            warn!("failed to wait for the graphics device: {:?}", err);
+        }
+        for (_, target) in targets {
+            target.destroy(&device);
+        }
+        uploader.destroy(&device);
+        unsafe {
+            device.destroy_command_pool(transfer_command_pool.into_raw());
+            device.destroy_command_pool(
+                global_ubo_update_command_pool.into_raw(),
+            );
+            device.destroy_fence(global_ubo_update_fence);
+            device.destroy_descriptor_pool(descriptor_pool);
+            device.destroy_render_pass(render_pass);
+            device.destroy_buffer(global_ubo);
+            device.free_memory(global_ubo_memory);
+        }
+    }
+}
+
+impl<B: Backend> Target<B> {
+    /// Destroys everything used to draw to the window, which has to
+    /// be done before the window is closed.
+    ///
+    /// The device has to be idle first.
+    fn destroy(self, device: &B::Device) {
+        let Target {
+            swapchain_state,
+            frame_command_pools,
+            image_available_semaphores,
+            frame_finished_semaphores,
+            frame_fences,
             imgui_renderer,
             cleanup,
             ..
         } = self;
-
-        device.wait_idle().unwrap();
         unsafe {
             for cleanups in cleanup.into_iter() {
                 for cleanup in cleanups.into_iter() {
-                    cleanup.destroy(&device);
+                    cleanup.destroy(device);
                 }
             }
 
-            device.destroy_fence(transfer_fence);
-            swapchain_state.destroy(&device);
-            device.destroy_command_pool(transfer_command_pool.into_raw());
-            device.destroy_command_pool(
-                global_ubo_update_command_pool.into_raw(),
-            );
+            if let Some(swapchain_state) = swapchain_state {
+                swapchain_state.destroy(device);
+            }
             for command_pool in frame_command_pools.into_iter() {
                 device.destroy_command_pool(command_pool.into_raw());
             }
             for fence in frame_fences.into_iter() {
                 device.destroy_fence(fence);
             }
-            device.destroy_fence(global_ubo_update_fence);
             for semaphore in frame_finished_semaphores.into_iter() {
                 device.destroy_semaphore(semaphore);
             }
             for semaphore in image_available_semaphores.into_iter() {
                 device.destroy_semaphore(semaphore);
             }
-            device.destroy_descriptor_pool(descriptor_pool);
-            device.destroy_render_pass(render_pass);
-            device.destroy_buffer(global_ubo);
-            device.free_memory(global_ubo_memory);
         }
-        imgui_renderer.destroy(&device);
+        imgui_renderer.destroy(device);
     }
 }
 
@@ -808,26 +1225,46 @@ impl<B: Backend> SwapchainState<B> {
         physical_device: &B::PhysicalDevice,
         surface: &mut B::Surface,
         render_pass: &B::RenderPass,
-        color_format: Format,
-        present_mode: PresentMode,
+        settings: SwapchainSettings,
         old: Option<B::Swapchain>,
-    ) -> SwapchainState<B> {
+    ) -> Result<SwapchainState<B>, GraphicsError> {
+        let SwapchainSettings {
+            color_format,
+            present_mode,
+            image_count,
+        } = settings;
         let (caps, ..) = surface.compatibility(physical_device);
-        let extent = caps.current_extent.unwrap();
-        assert!(caps.image_count.contains(&(MAX_FRAMES as u32)));
+        let image_count = clamp_image_count(image_count, &caps.image_count);
+        let extent = match caps.current_extent {
+            Some(extent) if extent.width > 0 && extent.height > 0 => extent,
+            _ => {
+                if let Some(old) = old {
+                    let _ = device.wait_idle();
+                    unsafe {
+                        device.destroy_swapchain(old);
+                    }
+                }
+                return Err(match caps.current_extent {
+                    Some(_) => GraphicsError::ZeroSizeSurface,
+                    None => GraphicsError::UnsupportedSurface,
+                });
+            },
+        };
         let swapchain_config = SwapchainConfig {
             present_mode,
             composite_alpha: CompositeAlpha::Opaque,
-            image_count: MAX_FRAMES as u32,
+            image_count,
             ..SwapchainConfig::from_caps(&caps, color_format, extent)
         };
         debug!(
-            "building swapchain at extent {},{}",
-            extent.width, extent.height,
+            "building swapchain at extent {},{} with {} images",
+            extent.width, extent.height, image_count,
         );
 
         let (swapchain, backbuffer) = unsafe {
-            device.create_swapchain(surface, swapchain_config, old).unwrap()
+            device
+                .create_swapchain(surface, swapchain_config, old)
+                .map_err(|err| GraphicsError::call("creating swapchain", err))?
         };
 
         let (frame_views, framebuffers) = match backbuffer {
@@ -849,9 +1286,11 @@ impl<B: Backend> SwapchainState<B> {
                                 Swizzle::NO,
                                 color_range.clone(),
                             )
-                            .unwrap()
+                            .map_err(|err| {
+                                GraphicsError::call("creating image view", err)
+                            })
                     })
-                    .collect::<Vec<_>>();
+                    .collect::<Result<Vec<_>, _>>()?;
 
                 let fbos = image_views
                     .iter()
@@ -862,9 +1301,11 @@ impl<B: Backend> SwapchainState<B> {
                                 vec![image_view],
                                 extent.to_extent(),
                             )
-                            .unwrap()
+                            .map_err(|err| {
+                                GraphicsError::call("creating framebuffer", err)
+                            })
                     })
-                    .collect();
+                    .collect::<Result<_, _>>()?;
 
                 (image_views, fbos)
             },
@@ -881,12 +1322,13 @@ impl<B: Backend> SwapchainState<B> {
             depth: 0.0..1.0,
         };
 
-        SwapchainState {
+        Ok(SwapchainState {
             swapchain,
+            image_count,
             viewport,
             framebuffers,
             frame_views,
-        }
+        })
     }
 
     fn destroy(self, device: &B::Device) {
@@ -901,3 +1343,20 @@ impl<B: Backend> SwapchainState<B> {
         }
     }
 }
+
+/// Clamps the number of swapchain images asked for to the range that
+/// a surface supports.
+///
+/// Backends report the maximum as the end of the range, so it's
+/// allowed as well.
+fn clamp_image_count(image_count: u32, supported: &Range<u32>) -> u32 {
+    image_count.min(supported.end).max(supported.start)
+}
+
+#[test]
+fn image_count_clamped_to_surface() {
+    assert_eq!(clamp_image_count(2, &(2..8)), 2);
+    assert_eq!(clamp_image_count(3, &(2..8)), 3);
+    assert_eq!(clamp_image_count(2, &(3..3)), 3);
+    assert_eq!(clamp_image_count(4, &(1..3)), 3);
+}
@@ -0,0 +1,491 @@
+use crate::graphics::{create_buffer, GraphicsError};
+use arrayvec::ArrayVec;
+use gfx_hal::{
+    buffer::{Access, Usage},
+    command::{
+        BufferCopy,
+        CommandBufferFlags,
+        CommandBufferInheritanceInfo,
+        RawCommandBuffer,
+        RawLevel,
+    },
+    memory::{Barrier, Dependencies, Properties},
+    pool::{CommandPoolCreateFlags, RawCommandPool},
+    pso::PipelineStage,
+    queue::{QueueFamilyId, RawCommandQueue},
+    Backend,
+    CommandQueue,
+    Device,
+    MemoryType,
+    QueueGroup,
+    Submission,
+};
+use std::mem;
+use std::ops::Range;
+use std::ptr;
+
+/// Size of the staging ring buffer that uploads are copied through.
+pub const STAGING_SIZE: u64 = 4 << 20;
+
+/// Alignment of every upload's place in the staging buffer.
+const STAGING_ALIGNMENT: u64 = 16;
+
+/// Number of batches of copies that can be in flight at once.
+const UPLOAD_BATCHES: usize = 4;
+
+/// Copies submitted together, with their own command pool so that
+/// they can be rerecorded once they're done.
+///
+/// The pool belongs to whichever queue family the copies are submitted
+/// on, so it's untyped.
+struct Batch<B: Backend> {
+    command_pool: B::CommandPool,
+    cmd_buffer: B::CommandBuffer,
+    /// Set when copies go through a separate transfer queue.
+    acquire: Option<Acquire<B>>,
+    /// Signalled once the whole batch is done, including the acquire.
+    fence: B::Fence,
+    /// Bytes of the staging buffer used by this batch, including any
+    /// skipped at the end when it wrapped around.
+    size: u64,
+    /// Called once the copies are done.
+    callbacks: Vec<Box<dyn FnOnce()>>,
+}
+
+/// Commands on the graphics queue that take back ownership of the
+/// buffers a batch copied into on the transfer queue.
+struct Acquire<B: Backend> {
+    command_pool: B::CommandPool,
+    cmd_buffer: B::CommandBuffer,
+    /// Signalled by the copies, and waited on by the acquire.
+    semaphore: B::Semaphore,
+    /// Every stage that the copied buffers are used at.
+    stages: PipelineStage,
+}
+
+/// Uploads data to device local buffers through a persistently mapped
+/// staging buffer, without waiting for the copies to finish.
+///
+/// Copies are recorded as they're queued, and submitted together by
+/// `flush`. The staging buffer is used as a ring, and space is
+/// reclaimed as batches finish, which `poll` checks for.
+///
+/// If the device has a queue family just for transfers, copies are
+/// submitted there so they can overlap with drawing. Buffers are owned
+/// by one queue family at a time, so each copy releases its buffer
+/// from the transfer family and the graphics queue acquires it before
+/// the next frame. Otherwise copies go on the graphics queue.
+pub struct Uploader<B: Backend> {
+    staging_buffer: B::Buffer,
+    staging_memory: B::Memory,
+    mapping: *mut u8,
+    capacity: u64,
+    /// Offset that the next upload is written at.
+    head: u64,
+    /// Bytes of the staging buffer that haven't been reclaimed yet.
+    used: u64,
+    batches: ArrayVec<[Batch<B>; UPLOAD_BATCHES]>,
+    /// Index of the oldest batch that's been submitted.
+    oldest: usize,
+    /// Number of batches submitted and not known to be done.
+    in_flight: usize,
+    /// Whether the batch after the in flight ones is being recorded.
+    recording: bool,
+    /// Queue that copies are submitted on, if there's a transfer only
+    /// family.
+    transfer: Option<QueueGroup<B, gfx_hal::Transfer>>,
+    /// Transfer family to graphics family, if there's a transfer queue.
+    families: Option<Range<QueueFamilyId>>,
+}
+
+impl<B: Backend> Uploader<B> {
+    /// Makes an uploader that submits copies on `transfer` if there's a
+    /// transfer queue, and on the graphics queue otherwise.
+    pub fn new(
+        device: &B::Device,
+        memory_types: &[MemoryType],
+        graphics_family: QueueFamilyId,
+        transfer: Option<QueueGroup<B, gfx_hal::Transfer>>,
+    ) -> Result<Uploader<B>, GraphicsError> {
+        // Coherent memory means writes don't have to be flushed.
+        let (staging_buffer, staging_memory, capacity) = unsafe {
+            create_buffer::<B>(
+                device,
+                memory_types,
+                Properties::CPU_VISIBLE | Properties::COHERENT,
+                Usage::TRANSFER_SRC,
+                STAGING_SIZE,
+            )?
+        };
+        let mapping = unsafe {
+            device
+                .map_memory(&staging_memory, 0..capacity)
+                .map_err(|err| GraphicsError::call("mapping memory", err))?
+        };
+        let families = transfer
+            .as_ref()
+            .map(|transfer| transfer.family()..graphics_family);
+        let create_command_pool = |family| -> Result<_, GraphicsError> {
+            let mut command_pool = unsafe {
+                device
+                    .create_command_pool(
+                        family,
+                        CommandPoolCreateFlags::TRANSIENT,
+                    )
+                    .map_err(|err| {
+                        GraphicsError::call("creating command pool", err)
+                    })?
+            };
+            let cmd_buffer = command_pool.allocate_one(RawLevel::Primary);
+            Ok((command_pool, cmd_buffer))
+        };
+        let batches = (0..UPLOAD_BATCHES)
+            .map(|_| {
+                let copy_family = families
+                    .as_ref()
+                    .map_or(graphics_family, |families| families.start);
+                let (command_pool, cmd_buffer) =
+                    create_command_pool(copy_family)?;
+                let acquire = match families {
+                    Some(_) => {
+                        let (command_pool, cmd_buffer) =
+                            create_command_pool(graphics_family)?;
+                        let semaphore =
+                            device.create_semaphore().map_err(|err| {
+                                GraphicsError::call("creating semaphore", err)
+                            })?;
+                        Some(Acquire {
+                            command_pool,
+                            cmd_buffer,
+                            semaphore,
+                            stages: PipelineStage::empty(),
+                        })
+                    },
+                    None => None,
+                };
+                let fence = device.create_fence(true).map_err(|err| {
+                    GraphicsError::call("creating fence", err)
+                })?;
+                Ok(Batch {
+                    command_pool,
+                    cmd_buffer,
+                    acquire,
+                    fence,
+                    size: 0,
+                    callbacks: Vec::new(),
+                })
+            })
+            .collect::<Result<_, GraphicsError>>()?;
+        Ok(Uploader {
+            staging_buffer,
+            staging_memory,
+            mapping,
+            capacity,
+            head: 0,
+            used: 0,
+            batches,
+            oldest: 0,
+            in_flight: 0,
+            recording: false,
+            transfer,
+            families,
+        })
+    }
+
+    /// Queues a copy of `data` into `dst` at `dst_offset` bytes.
+    ///
+    /// `used_by` is how the buffer is used afterwards, which the copy
+    /// is made visible to. The copy isn't submitted until the next
+    /// `flush`, and `dst` has to stay alive until it's done.
+    pub fn upload_buffer<T: Copy>(
+        &mut self,
+        device: &B::Device,
+        queue: &mut CommandQueue<B, gfx_hal::Graphics>,
+        data: &[T],
+        dst: &B::Buffer,
+        dst_offset: u64,
+        used_by: (Access, PipelineStage),
+    ) -> Result<(), GraphicsError> {
+        let size = mem::size_of_val(data) as u64;
+        if size == 0 {
+            return Ok(());
+        }
+        let offset = self.allocate(device, queue, size)?;
+        let index = self.start_batch(device)?;
+        let batch = &mut self.batches[index];
+        batch.size += size;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                data.as_ptr() as *const u8,
+                self.mapping.add(offset as usize),
+                size as usize,
+            );
+
+            let cmd_buffer = &mut batch.cmd_buffer;
+            cmd_buffer.copy_buffer(
+                &self.staging_buffer,
+                dst,
+                &[BufferCopy {
+                    src: offset,
+                    dst: dst_offset,
+                    size,
+                }],
+            );
+            let (access, stage) = used_by;
+            match (&self.families, &mut batch.acquire) {
+                (Some(families), Some(acquire)) => {
+                    let release = Barrier::Buffer {
+                        states: Access::TRANSFER_WRITE..Access::empty(),
+                        target: dst,
+                        families: Some(families.clone()),
+                    };
+                    cmd_buffer.pipeline_barrier(
+                        PipelineStage::TRANSFER..PipelineStage::BOTTOM_OF_PIPE,
+                        Dependencies::empty(),
+                        &[release],
+                    );
+                    // This waits on the semaphore the copies signal,
+                    // at the stages that use the buffer.
+                    let acquire_barrier = Barrier::Buffer {
+                        states: Access::empty()..access,
+                        target: dst,
+                        families: Some(families.clone()),
+                    };
+                    acquire.cmd_buffer.pipeline_barrier(
+                        stage..stage,
+                        Dependencies::empty(),
+                        &[acquire_barrier],
+                    );
+                    acquire.stages |= stage;
+                },
+                _ => {
+                    let barrier = Barrier::whole_buffer(
+                        dst,
+                        Access::TRANSFER_WRITE..access,
+                    );
+                    cmd_buffer.pipeline_barrier(
+                        PipelineStage::TRANSFER..stage,
+                        Dependencies::empty(),
+                        &[barrier],
+                    );
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `callback` once every copy queued so far is done.
+    pub fn on_complete<F: FnOnce() + 'static>(&mut self, callback: F) {
+        let newest = if self.recording {
+            Some(self.oldest + self.in_flight)
+        } else if self.in_flight > 0 {
+            Some(self.oldest + self.in_flight - 1)
+        } else {
+            None
+        };
+        match newest {
+            Some(index) => {
+                self.batches[index % UPLOAD_BATCHES]
+                    .callbacks
+                    .push(Box::new(callback))
+            },
+            None => callback(),
+        }
+    }
+
+    /// Submits every copy queued since the last flush.
+    ///
+    /// With a transfer queue, this also submits the acquire on `queue`,
+    /// so it has to be called before anything that uses the uploads is
+    /// submitted there.
+    pub fn flush(
+        &mut self,
+        device: &B::Device,
+        queue: &mut CommandQueue<B, gfx_hal::Graphics>,
+    ) -> Result<(), GraphicsError> {
+        if !self.recording {
+            return Ok(());
+        }
+        let index = (self.oldest + self.in_flight) % UPLOAD_BATCHES;
+        let batch = &mut self.batches[index];
+        unsafe {
+            batch.cmd_buffer.finish();
+            device
+                .reset_fence(&batch.fence)
+                .map_err(|err| GraphicsError::call("resetting fence", err))?;
+            match (&mut self.transfer, &mut batch.acquire) {
+                (Some(transfer), Some(acquire)) => {
+                    acquire.cmd_buffer.finish();
+                    transfer.queues[0].as_raw_mut().submit(
+                        Submission {
+                            command_buffers: Some(&batch.cmd_buffer),
+                            wait_semaphores: None,
+                            signal_semaphores: Some(&acquire.semaphore),
+                        },
+                        None,
+                    );
+                    queue.as_raw_mut().submit(
+                        Submission {
+                            command_buffers: Some(&acquire.cmd_buffer),
+                            wait_semaphores: Some((
+                                &acquire.semaphore,
+                                acquire.stages,
+                            )),
+                            signal_semaphores: None,
+                        },
+                        Some(&batch.fence),
+                    );
+                },
+                _ => {
+                    queue.as_raw_mut().submit(
+                        Submission {
+                            command_buffers: Some(&batch.cmd_buffer),
+                            wait_semaphores: None::<(&B::Semaphore, _)>,
+                            signal_semaphores: None,
+                        },
+                        Some(&batch.fence),
+                    );
+                },
+            }
+        }
+        self.recording = false;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Reclaims the staging space of every batch that's done, and
+    /// calls their callbacks.
+    pub fn poll(&mut self, device: &B::Device) -> Result<(), GraphicsError> {
+        while self.in_flight > 0 {
+            let done = unsafe {
+                device
+                    .get_fence_status(&self.batches[self.oldest].fence)
+                    .map_err(|_| GraphicsError::DeviceLost)?
+            };
+            if !done {
+                break;
+            }
+            self.retire_oldest();
+        }
+        Ok(())
+    }
+
+    /// Finds space for `size` bytes in the staging buffer, waiting for
+    /// earlier batches to finish if it's full.
+    fn allocate(
+        &mut self,
+        device: &B::Device,
+        queue: &mut CommandQueue<B, gfx_hal::Graphics>,
+        size: u64,
+    ) -> Result<u64, GraphicsError> {
+        if size > self.capacity {
+            return Err(GraphicsError::Call {
+                action: "uploading",
+                err: format!("{} bytes don't fit in the staging buffer", size),
+            });
+        }
+        loop {
+            // Uploads have to be contiguous, so skip the rest of the
+            // buffer if it's too short.
+            let skipped = if self.capacity - self.head < size {
+                self.capacity - self.head
+            } else {
+                0
+            };
+            if self.used + skipped + size <= self.capacity {
+                let offset = (self.head + skipped) % self.capacity;
+                self.head = (offset + size + STAGING_ALIGNMENT - 1) /
+                    STAGING_ALIGNMENT *
+                    STAGING_ALIGNMENT;
+                let padding = self.head - offset - size;
+                self.head %= self.capacity;
+                self.used += skipped + size + padding;
+                // The copy itself is counted by the caller.
+                let batch = self.start_batch(device)?;
+                self.batches[batch].size += skipped + padding;
+                return Ok(offset);
+            }
+            if self.recording {
+                self.flush(device, queue)?;
+            } else {
+                self.wait_oldest(device)?;
+            }
+        }
+    }
+
+    /// Gets the index of the batch being recorded, starting a new one
+    /// if needed.
+    fn start_batch(
+        &mut self,
+        device: &B::Device,
+    ) -> Result<usize, GraphicsError> {
+        if !self.recording {
+            if self.in_flight == UPLOAD_BATCHES {
+                self.wait_oldest(device)?;
+            }
+            let batch = &mut self.batches
+                [(self.oldest + self.in_flight) % UPLOAD_BATCHES];
+            let flags = CommandBufferFlags::ONE_TIME_SUBMIT;
+            unsafe {
+                batch.command_pool.reset();
+                batch
+                    .cmd_buffer
+                    .begin(flags, CommandBufferInheritanceInfo::default());
+                if let Some(ref mut acquire) = batch.acquire {
+                    acquire.command_pool.reset();
+                    acquire
+                        .cmd_buffer
+                        .begin(flags, CommandBufferInheritanceInfo::default());
+                    acquire.stages = PipelineStage::empty();
+                }
+            }
+            self.recording = true;
+        }
+        Ok((self.oldest + self.in_flight) % UPLOAD_BATCHES)
+    }
+
+    fn wait_oldest(&mut self, device: &B::Device) -> Result<(), GraphicsError> {
+        if self.in_flight == 0 {
+            return Ok(());
+        }
+        unsafe {
+            device.wait_for_fence(&self.batches[self.oldest].fence, !0)?;
+        }
+        self.retire_oldest();
+        Ok(())
+    }
+
+    fn retire_oldest(&mut self) {
+        let batch = &mut self.batches[self.oldest];
+        self.used -= batch.size;
+        batch.size = 0;
+        if self.used == 0 {
+            // Start from the beginning again, so that nothing has to
+            // be skipped to fit.
+            self.head = 0;
+        }
+        for callback in batch.callbacks.drain(..) {
+            callback();
+        }
+        self.oldest = (self.oldest + 1) % UPLOAD_BATCHES;
+        self.in_flight -= 1;
+    }
+
+    /// Destroys the staging buffer and everything used to submit
+    /// copies. The device has to be idle first.
+    pub fn destroy(self, device: &B::Device) {
+        unsafe {
+            device.unmap_memory(&self.staging_memory);
+            device.destroy_buffer(self.staging_buffer);
+            device.free_memory(self.staging_memory);
+            for batch in self.batches {
+                device.destroy_command_pool(batch.command_pool);
+                if let Some(acquire) = batch.acquire {
+                    device.destroy_command_pool(acquire.command_pool);
+                    device.destroy_semaphore(acquire.semaphore);
+                }
+                device.destroy_fence(batch.fence);
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use crate::config::GamepadConfig;
+use gilrs::{Axis, Gilrs};
+use log::{info, warn};
+use nalgebra::{Point2, Vector2};
+
+/// A change to the cursor position from some input device.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CursorInput {
+    /// Move the cursor to an exact position, like a mouse does.
+    Absolute(Point2<f32>),
+    /// Move the cursor by an offset, like an analog stick does.
+    Relative(Vector2<f32>),
+}
+
+/// Reads analog stick input from whichever gamepad was used most
+/// recently.
+///
+/// If gamepads aren't supported on this platform, this never produces
+/// any input.
+pub struct Gamepad {
+    gilrs: Option<Gilrs>,
+    active: Option<gilrs::GamepadId>,
+}
+
+/// Applies the dead zone and response curve to a raw stick position.
+///
+/// The result has a magnitude from 0 to 1, where anything inside the
+/// dead zone is 0, and the remaining range is rescaled and raised to
+/// the curve exponent, giving finer control near the center.
+pub fn stick_response(
+    stick: Vector2<f32>,
+    config: &GamepadConfig,
+) -> Vector2<f32> {
+    let magnitude = stick.norm().min(1.0);
+    if magnitude <= config.dead_zone {
+        return Vector2::zeros();
+    }
+    let scaled = (magnitude - config.dead_zone) / (1.0 - config.dead_zone);
+    stick.normalize() * scaled.powf(config.curve)
+}
+
+#[test]
+fn stick_response_dead_zone() {
+    let config = GamepadConfig::default();
+    let stick = Vector2::new(0.5 * config.dead_zone, 0.0);
+    assert_eq!(stick_response(stick, &config), Vector2::zeros());
+    let stick = Vector2::new(0.0, 1.0);
+    assert!((stick_response(stick, &config).norm() - 1.0).abs() < 1e-6);
+}
+
+impl Gamepad {
+    pub fn new() -> Gamepad {
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => Some(gilrs),
+            Err(err) => {
+                warn!("gamepad support unavailable: {}", err);
+                None
+            },
+        };
+        Gamepad {
+            gilrs,
+            active: None,
+        }
+    }
+
+    /// Processes pending gamepad events and gets how far the cursor
+    /// should move over `dt` seconds.
+    ///
+    /// Returns `None` if the stick is resting in the dead zone, so
+    /// that it doesn't fight with the mouse.
+    pub fn update(
+        &mut self,
+        dt: f32,
+        config: &GamepadConfig,
+    ) -> Option<CursorInput> {
+        let gilrs = self.gilrs.as_mut()?;
+        while let Some(event) = gilrs.next_event() {
+            if self.active != Some(event.id) {
+                info!("switching to gamepad {:?}", event.id);
+                self.active = Some(event.id);
+            }
+        }
+
+        let gamepad = gilrs.connected_gamepad(self.active?)?;
+        // Stick y points up, but cursor y points down.
+        let stick = Vector2::new(
+            gamepad.value(Axis::LeftStickX),
+            -gamepad.value(Axis::LeftStickY),
+        );
+        let response = stick_response(stick, config);
+        if response == Vector2::zeros() {
+            None
+        } else {
+            Some(CursorInput::Relative(response * config.sensitivity * dt))
+        }
+    }
+}
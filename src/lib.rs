@@ -0,0 +1,24 @@
+#![feature(duration_float, copy_within)]
+
+pub mod animation;
+pub mod audio;
+pub mod bench;
+pub mod camera;
+pub mod config;
+pub mod console;
+pub mod debug;
+pub mod decorations;
+pub mod display;
+pub mod double_buffer;
+pub mod effects;
+pub mod game;
+pub mod graphics;
+pub mod input;
+pub mod logger;
+pub mod networking;
+pub mod profile;
+pub mod state;
+pub mod stats;
+pub mod swarm;
+pub mod ui;
+pub mod viewport;
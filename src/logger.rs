@@ -1,12 +1,14 @@
 use crate::ui;
 use colored::Colorize;
-use imgui::{im_str, ImStr, Ui};
+use imgui::{im_str, ImStr, ImString, Ui};
 use lazy_static::lazy_static;
 use log::{self, Level, LevelFilter, Log, Metadata};
 use parking_lot::Mutex;
 use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Write};
 use std::ops::Range;
+use std::path::PathBuf;
 use std::str;
 
 // 512kb max.
@@ -16,12 +18,62 @@ lazy_static! {
     pub static ref LOGGER: Logger = Logger::default();
 }
 
+/// Level that everything is logged at unless overridden.
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
 /// Sets this the global logger.
 pub fn apply() -> Result<(), log::SetLoggerError> {
-    log::set_max_level(LevelFilter::Info);
+    log::set_max_level(DEFAULT_LEVEL);
     log::set_logger(&*LOGGER)
 }
 
+/// Starts writing every log message to a file as well, replacing any
+/// previous log file.
+///
+/// Once the file grows past `max_size` bytes, it's renamed with a
+/// `.1` suffix, pushing back older files up to `.{max_files}`.
+pub fn log_to_file(
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+) -> Result<(), io::Error> {
+    let file = LogFile::open(path, max_size, max_files)?;
+    *LOGGER.file.lock() = Some(file);
+    Ok(())
+}
+
+/// Every level filter, for picking from combo boxes.
+const LEVEL_FILTERS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+fn level_combo<'a>(
+    ui: &Ui<'a>,
+    label: &ImStr,
+    filter: &mut LevelFilter,
+) -> bool {
+    ui::enum_combo(
+        ui,
+        label,
+        filter,
+        &[
+            im_str!("off"),
+            im_str!("error"),
+            im_str!("warn"),
+            im_str!("info"),
+            im_str!("debug"),
+            im_str!("trace"),
+        ],
+        &LEVEL_FILTERS,
+        4,
+    )
+}
+
 /// `Log` implementation that outputs to stdout as well as an in-game
 /// debug window.
 ///
@@ -36,6 +88,41 @@ pub fn apply() -> Result<(), log::SetLoggerError> {
 #[derive(Debug)]
 pub struct Logger {
     internal: Mutex<LoggerInternal>,
+    filters: Mutex<Filters>,
+    ui_state: Mutex<LoggerUi>,
+    file: Mutex<Option<LogFile>>,
+}
+
+/// A log file that gets rotated once it grows too large.
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    /// Current size of the file in bytes.
+    size: u64,
+    max_size: u64,
+    /// Number of rotated files to keep.
+    max_files: usize,
+}
+
+/// Which levels get logged for each target.
+///
+/// The global `log::max_level` is kept at the most verbose of these,
+/// so that overrides can be more verbose than the default.
+#[derive(Debug)]
+struct Filters {
+    /// Level for targets without an override.
+    default: LevelFilter,
+    /// Levels for targets under a module path. The longest matching
+    /// path wins.
+    overrides: Vec<(String, LevelFilter)>,
+}
+
+/// Text fields in the logger UI.
+#[derive(Debug)]
+struct LoggerUi {
+    search: ImString,
+    new_target: ImString,
 }
 
 #[derive(Debug)]
@@ -52,6 +139,8 @@ struct LoggerInternal {
 #[derive(Debug)]
 struct Record {
     level: Level,
+    /// Module path the message was logged from, unless overridden.
+    target: String,
     span: Range<usize>,
 }
 
@@ -69,10 +158,124 @@ impl Default for Logger {
                 text: vec![0; MAX_LOG_SIZE],
                 head: 0,
             }),
+            filters: Mutex::new(Filters {
+                default: DEFAULT_LEVEL,
+                overrides: Vec::new(),
+            }),
+            ui_state: Mutex::new(LoggerUi {
+                search: ui::input_string(""),
+                new_target: ui::input_string(""),
+            }),
+            file: Mutex::new(None),
         }
     }
 }
 
+impl LogFile {
+    fn open(
+        path: PathBuf,
+        max_size: u64,
+        max_files: usize,
+    ) -> Result<LogFile, io::Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    /// Gets the path of the `n`th most recent rotated file.
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    /// Moves the current file out of the way and starts a new one.
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            let oldest = self.rotated_path(self.max_files);
+            if oldest.exists() {
+                fs::remove_file(oldest)?;
+            }
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), io::Error> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+impl Filters {
+    /// Gets the level that applies to a target.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.overrides
+            .iter()
+            .filter(|(path, _)| {
+                target == path ||
+                    (target.starts_with(path.as_str()) &&
+                        target[path.len()..].starts_with("::"))
+            })
+            .max_by_key(|(path, _)| path.len())
+            .map(|&(_, level)| level)
+            .unwrap_or(self.default)
+    }
+
+    /// Sets the global max level to the most verbose filter.
+    fn update_max_level(&self) {
+        let max = self
+            .overrides
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, |a, b| a.max(b));
+        log::set_max_level(max);
+    }
+}
+
+#[test]
+fn filters_longest_path() {
+    let filters = Filters {
+        default: LevelFilter::Info,
+        overrides: vec![
+            ("ball::networking".to_owned(), LevelFilter::Trace),
+            ("ball::networking::client".to_owned(), LevelFilter::Warn),
+        ],
+    };
+    assert_eq!(filters.level("ball::graphics"), LevelFilter::Info);
+    assert_eq!(filters.level("ball::networking"), LevelFilter::Trace);
+    assert_eq!(filters.level("ball::networking::server"), LevelFilter::Trace);
+    assert_eq!(filters.level("ball::networking::client"), LevelFilter::Warn);
+    assert_eq!(filters.level("ball::networking_extra"), LevelFilter::Info);
+}
+
 impl<'a> Write for LogWriter<'a> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
         assert!(buf.len() <= MAX_LOG_SIZE);
@@ -100,7 +303,7 @@ impl<'a> Write for LogWriter<'a> {
 
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= log::max_level()
+        metadata.level() <= self.filters.lock().level(metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
@@ -132,12 +335,19 @@ impl Log for Logger {
             }
 
             // Output to stdout, re-using the format results.
+            let tag = match record.level() {
+                Level::Error => "[ERR]",
+                Level::Warn => "[WRN]",
+                Level::Info => "[INF]",
+                Level::Debug => "[DBG]",
+                Level::Trace => "[TRC]",
+            };
             let level = match record.level() {
-                Level::Error => "[ERR]".red(),
-                Level::Warn => "[WRN]".yellow(),
-                Level::Info => "[INF]".green(),
-                Level::Debug => "[DBG]".blue(),
-                Level::Trace => "[TRC]".normal(),
+                Level::Error => tag.red(),
+                Level::Warn => tag.yellow(),
+                Level::Info => tag.green(),
+                Level::Debug => tag.blue(),
+                Level::Trace => tag.normal(),
             };
             let msg = unsafe {
                 // Garaunteed to be UTF8 as long as the null
@@ -147,9 +357,17 @@ impl Log for Logger {
                 )
             };
             eprintln!("{} {}", level, msg);
+            if let Some(file) = self.file.lock().as_mut() {
+                // Logging this error would recurse, so just print it.
+                let line = format!("{} {}", tag, msg);
+                if let Err(err) = file.write_line(&line) {
+                    eprintln!("failed to write to log file: {}", err);
+                }
+            }
 
             internal.records.push_back(Record {
                 level: record.level(),
+                target: record.target().to_owned(),
                 span,
             });
         }
@@ -161,40 +379,65 @@ impl Log for Logger {
 impl Logger {
     /// Draws the logger-related UI into the debug window.
     pub fn ui<'a>(&self, ui: &Ui<'a>) {
-        let mut filter = log::max_level();
-        if ui::enum_combo(
-            ui,
-            im_str!("Log level"),
-            &mut filter,
-            &[
-                im_str!("off"),
-                im_str!("error"),
-                im_str!("warn"),
-                im_str!("info"),
-                im_str!("debug"),
-                im_str!("trace"),
-            ],
-            &[
-                LevelFilter::Off,
-                LevelFilter::Error,
-                LevelFilter::Warn,
-                LevelFilter::Info,
-                LevelFilter::Debug,
-                LevelFilter::Trace,
-            ],
-            4,
-        ) {
-            log::set_max_level(filter);
+        let mut filters = self.filters.lock();
+        let mut ui_state = self.ui_state.lock();
+        let mut changed =
+            level_combo(ui, im_str!("Log level"), &mut filters.default);
+
+        ui.tree_node(im_str!("Module levels")).build(|| {
+            let mut remove = None;
+            for (i, (path, level)) in filters.overrides.iter_mut().enumerate() {
+                changed |=
+                    level_combo(ui, &ImString::new(path.as_str()), level);
+                ui.same_line(0.0);
+                if ui.small_button(&ImString::new(format!("Remove##{}", i))) {
+                    remove = Some(i);
+                }
+            }
+            if let Some(i) = remove {
+                filters.overrides.remove(i);
+                changed = true;
+            }
+
+            ui.input_text(im_str!("Module path"), &mut ui_state.new_target)
+                .build();
+            ui.same_line(0.0);
+            let path = ui_state.new_target.to_str().trim().to_owned();
+            if ui.small_button(im_str!("Add")) && !path.is_empty() {
+                let level = filters.level(&path);
+                filters.overrides.retain(|(other, _)| *other != path);
+                filters.overrides.push((path, level));
+                ui_state.new_target = ui::input_string("");
+                changed = true;
+            }
+        });
+        if changed {
+            filters.update_max_level();
         }
 
+        ui.input_text(im_str!("Search"), &mut ui_state.search).build();
+        let search = ui_state.search.to_str().to_lowercase();
+
         let internal = self.internal.lock();
-        let filter = log::max_level();
         ui.child_frame(im_str!("Log"), (0.0, 0.0))
             .show_borders(true)
             .always_show_vertical_scroll_bar(true)
             .build(|| {
                 for record in internal.records.iter() {
-                    if record.level <= filter {
+                    if record.level > filters.level(&record.target) {
+                        continue;
+                    }
+                    let text = unsafe {
+                        // Skip the null terminator, like when logging.
+                        str::from_utf8_unchecked(
+                            &internal.text
+                                [record.span.start..record.span.end - 1],
+                        )
+                    };
+                    let matches = search.is_empty() ||
+                        text.to_lowercase().contains(&search) ||
+                        record.target.to_lowercase().contains(&search);
+                    if matches {
                         let (color, level) = match record.level {
                             Level::Error => {
                                 ((0.75, 0.25, 0.25, 1.0), im_str!("[ERR]"))
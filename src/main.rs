@@ -1,178 +1,954 @@
-#![feature(duration_float, copy_within)]
+#![feature(duration_float)]
 
 extern crate gfx_backend_vulkan as backend;
+use ball_gfx_hal::{
+    bench,
+    config,
+    console,
+    debug,
+    graphics,
+    logger,
+    networking,
+    state,
+    swarm,
+};
+use crossbeam::channel::{self, RecvTimeoutError};
 use ctrlc;
+use gfx_hal::PresentMode;
 use imgui::ImGui;
 use imgui_winit::ImGuiWinit;
-use nalgebra::Point2;
-use rand::{thread_rng, Rng};
+use log::{error, info, warn};
+use rand::thread_rng;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::BufWriter;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 use structopt::StructOpt;
 use winit::{
+    dpi::LogicalSize,
+    DeviceEvent,
     ElementState,
     Event,
     EventsLoop,
     VirtualKeyCode,
     Window,
+    WindowBuilder,
     WindowEvent,
 };
 
-pub mod debug;
-pub mod double_buffer;
-pub mod game;
-pub mod graphics;
-pub mod logger;
-pub mod networking;
-pub mod state;
-pub mod ui;
-
 #[derive(StructOpt, Debug)]
 #[structopt(name = "ball-gfx-hal")]
 struct Cli {
-    /// Instead of opening a gui window, host a headless server on
-    /// this address.
+    /// Also write logs to this file, overriding the config.
+    #[structopt(long = "log-file", parse(from_os_str), raw(global = "true"))]
+    log_file: Option<PathBuf>,
+    /// Trace every network packet sent or received to this file.
+    #[structopt(long = "packet-log", parse(from_os_str), raw(global = "true"))]
+    packet_log: Option<PathBuf>,
+    /// What to run, which is the gui if left out.
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Open a gui window to play in.
+    #[structopt(name = "play")]
+    Play,
+    /// Host a headless server. Type `help` for commands to control it
+    /// with.
+    #[structopt(name = "serve")]
+    Serve(ServeOptions),
+    /// Connect a dummy client to a server, without a gui.
+    #[structopt(name = "connect")]
+    Connect(ConnectOptions),
+    /// Simulate a server with synthetic players as fast as possible,
+    /// and report how long ticks and snapshots take.
+    #[structopt(name = "bench", raw(alias = "\"bench-server\""))]
+    Bench {
+        /// Number of synthetic players.
+        #[structopt(long = "players", default_value = "16")]
+        players: u16,
+        /// Seconds to run for.
+        #[structopt(long = "duration", default_value = "60")]
+        duration: f32,
+    },
+    /// Print a packet trace recorded with `--packet-log` as JSON.
+    #[structopt(name = "replay")]
+    Replay {
+        #[structopt(parse(from_os_str))]
+        packet_log: PathBuf,
+    },
+    /// Run a master server, which lists announced games for players to
+    /// browse.
+    #[structopt(name = "master")]
+    Master {
+        /// Address to accept heartbeats and list requests on.
+        #[structopt(long = "listen")]
+        listen: SocketAddr,
+    },
+    /// Spectate a server and serve the match to read-only viewers on
+    /// another address, so they don't use up the server's bandwidth.
+    #[structopt(name = "relay")]
+    Relay {
+        /// Address of the server to spectate.
+        upstream: SocketAddr,
+        /// Address to accept viewers on.
+        #[structopt(long = "listen")]
+        listen: SocketAddr,
+        /// Deny new viewers after this many are connected.
+        #[structopt(long = "max-viewers")]
+        max_viewers: Option<u16>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct ServeOptions {
+    /// Address to host the server on.
+    addr: SocketAddr,
+    /// Kick clients after this many implausible cursor movements.
+    #[structopt(long = "kick-violations")]
+    kick_violations: Option<u32>,
+    /// Ignore new players after this many are connected.
+    #[structopt(long = "max-players")]
+    max_players: Option<u16>,
+    /// Start with this many bots, which the server moves around itself.
     #[structopt(
-        short = "s",
-        long = "server",
-        raw(conflicts_with = "\"client\"")
+        long = "bots",
+        default_value = "0",
+        parse(try_from_str = "parse_bots")
     )]
-    server: Option<SocketAddr>,
-    /// Instead of opening a gui window, connect a dummy client to a
-    /// server on this address.
+    bots: u16,
+    /// Encrypt every connection and ignore clients that can't. Needs
+    /// the `encryption` feature. This stops eavesdropping, but not a
+    /// man in the middle, since the server's key isn't verified.
+    #[structopt(long = "encrypt")]
+    encrypt: bool,
+    /// Cap how fast data is sent to each client, in kilobits per
+    /// second.
+    #[structopt(long = "max-kbps", parse(try_from_str = "parse_kbps"))]
+    max_kbps: Option<u32>,
+    /// Fastest ball velocity along each axis that snapshots can
+    /// carry, in units per second. Faster balls are sent at this
+    /// speed.
+    #[structopt(long = "velocity-range")]
+    velocity_range: Option<f32>,
+    /// Seed for everything random in the game, so that a match can be
+    /// played out the same way again. Picked at random by default.
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+    /// Accept admin connections on this address. Admins have to send
+    /// `network.admin_secret` from the config, or `admin_password`
+    /// from the server settings, first.
+    #[structopt(long = "admin")]
+    admin: Option<SocketAddr>,
+    /// Serve Prometheus metrics over HTTP on this address. Needs the
+    /// `metrics` feature.
+    #[structopt(long = "serve-metrics")]
+    serve_metrics: Option<SocketAddr>,
+    /// Also accept WebSocket connections on this address, for browser
+    /// clients. Needs the `websocket` feature.
+    #[structopt(long = "websocket")]
+    websocket: Option<SocketAddr>,
+    /// List the server on the master server at this address so it
+    /// shows up under internet games.
+    #[structopt(long = "announce")]
+    announce: Option<SocketAddr>,
+    /// Name of the game when it's listed with `--announce`.
+    #[structopt(long = "game-name", default_value = "Headless server")]
+    game_name: String,
+    /// Save the scores, settings and ban list on shutdown.
+    #[structopt(long = "save-state")]
+    save_state: bool,
+    /// Start from the state saved last time, and save it again on
+    /// shutdown.
+    #[structopt(long = "resume")]
+    resume: bool,
+    /// Apply settings from this file on startup and on reload, instead
+    /// of `server.toml` in the config directory.
     #[structopt(
-        short = "c",
-        long = "client",
-        raw(conflicts_with = "\"server\"")
+        long = "config",
+        parse(from_os_str),
+        raw(alias = "\"server-config\"")
     )]
-    client: Option<SocketAddr>,
+    config: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+struct ConnectOptions {
+    /// Address of the server to connect to.
+    addr: SocketAddr,
+    /// Connect through the master server at this address, in case the
+    /// server is behind NAT.
+    #[structopt(long = "rendezvous")]
+    rendezvous: Option<SocketAddr>,
+    /// Connect this many bots instead, all on one thread, and log
+    /// aggregate stats.
+    #[structopt(long = "swarm")]
+    swarm: Option<usize>,
+    /// How bots move their cursors: still, circle, random, path or
+    /// chase.
+    #[structopt(
+        long = "pattern",
+        default_value = "circle",
+        raw(alias = "\"swarm-pattern\"")
+    )]
+    pattern: swarm::Pattern,
+    /// Cursor speed of bots in game units per second.
+    #[structopt(
+        long = "speed",
+        default_value = "0.5",
+        raw(alias = "\"swarm-speed\"")
+    )]
+    speed: f32,
+    /// File with the path for `--pattern path` to play back, with a
+    /// `seconds x y` point on each line.
+    #[structopt(long = "path", parse(from_os_str))]
+    path: Option<PathBuf>,
+    /// Write the most recent network statistics to this CSV file on
+    /// exit.
+    #[structopt(long = "metrics", parse(from_os_str))]
+    metrics: Option<PathBuf>,
+}
+
+/// Parses `--bots`, which can't go over what the server settings file
+/// allows.
+fn parse_bots(bots: &str) -> Result<u16, String> {
+    let bots = bots.parse::<u16>().map_err(|err| err.to_string())?;
+    if bots > networking::server_settings::MAX_BOTS {
+        return Err(format!(
+            "can be at most {}",
+            networking::server_settings::MAX_BOTS
+        ));
+    }
+    Ok(bots)
+}
+
+/// Parses `--max-kbps`, which has to leave room for something to be
+/// sent.
+fn parse_kbps(kbps: &str) -> Result<u32, String> {
+    match kbps.parse::<u32>() {
+        Ok(0) => Err("has to be more than 0".to_owned()),
+        Ok(kbps) => Ok(kbps),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Flags that used to pick what to run, before there were
+/// subcommands, and the subcommand each one stands for.
+const LEGACY_FLAGS: [(Option<&str>, &str, &str); 3] = [
+    (Some("-s"), "--server", "serve"),
+    (Some("-c"), "--client", "connect"),
+    (None, "--dump-packet-log", "replay"),
+];
+
+/// Rewrites a legacy flag like `-s <addr>` or `-s<addr>` into the
+/// subcommand it stands for, so that old scripts keep working.
+///
+/// Every other argument is moved after the subcommand, where all of
+/// its options now live.
+fn legacy_args<I: IntoIterator<Item = OsString>>(args: I) -> Vec<OsString> {
+    let mut args = args.into_iter().collect::<Vec<_>>();
+    for i in 1..args.len() {
+        let arg = args[i].to_string_lossy().into_owned();
+        let legacy = LEGACY_FLAGS.iter().find(|(short, long, _)| {
+            short.map_or(false, |short| arg.starts_with(short)) ||
+                arg == *long ||
+                arg.starts_with(&format!("{}=", long))
+        });
+        let &(short, long, command) = match legacy {
+            Some(legacy) => legacy,
+            None => continue,
+        };
+        let value = if arg.len() > long.len() && arg.starts_with(long) {
+            Some(OsString::from(&arg[long.len() + 1..]))
+        } else if let Some(joined) = short
+            .and_then(|short| arg.get(short.len()..))
+            .filter(|joined| !joined.is_empty())
+        {
+            Some(OsString::from(joined))
+        } else if i + 1 < args.len() {
+            Some(args.remove(i + 1))
+        } else {
+            None
+        };
+        args.remove(i);
+        let mut rewritten = vec![args.remove(0), OsString::from(command)];
+        rewritten.extend(value);
+        rewritten.extend(args);
+        return rewritten;
+    }
+    args
 }
 
 fn main() {
     logger::apply().unwrap();
 
-    let cli = Cli::from_args();
+    let cli = Cli::from_iter(legacy_args(std::env::args_os()));
+    let command = cli.command.unwrap_or(Command::Play);
+    if let Command::Replay {
+        packet_log,
+    } = command
+    {
+        let stdout = std::io::stdout();
+        let result = networking::packet_log::dump(&packet_log, stdout.lock());
+        if let Err(err) = result {
+            eprintln!("failed to dump packet log: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    match (cli.server, cli.client) {
-        (Some(addr), None) => {
-            let (server, thread) = networking::server::host(addr).unwrap();
-            ctrlc::set_handler(move || {
-                server.shutdown();
-            })
-            .unwrap();
+    let config = config::Config::load();
+
+    let log_file = cli.log_file.clone().or_else(|| config.logging.file.clone());
+    let log_file = match command {
+        Command::Serve(_) => log_file.or_else(|| config.logging.server_file()),
+        _ => log_file,
+    };
+    if let Some(path) = log_file {
+        info!("logging to {}", path.display());
+        let result = logger::log_to_file(
+            path,
+            config.logging.max_file_size,
+            config.logging.max_files,
+        );
+        if let Err(err) = result {
+            warn!("failed to open log file: {}", err);
+        }
+    }
+    if let Some(path) = cli.packet_log {
+        info!("tracing packets to {}", path.display());
+        if let Err(err) = networking::packet_log::enable(&path) {
+            warn!("failed to open packet log: {}", err);
+        }
+    }
+
+    match command {
+        Command::Play => run_gui(config),
+        Command::Serve(options) => serve(options, &config),
+        Command::Connect(options) => connect(options),
+        Command::Bench {
+            players,
+            duration,
+        } => {
+            bench::run(bench::BenchConfig {
+                players,
+                duration,
+            });
+        },
+        Command::Master {
+            listen,
+        } => {
+            let (_, thread) = networking::master::serve(listen).unwrap();
             thread.join().unwrap();
         },
-        (None, Some(addr)) => {
-            // Generate random cursor position within the inner 30% of the
-            // circle.
-            let mut rng = thread_rng();
-            // Rejection sampling because I'm lazy...
-            let cursor = loop {
-                let cursor = Point2::new(
-                    rng.gen_range(-0.3, 0.3),
-                    rng.gen_range(-0.3, 0.3),
-                );
-                if cursor.coords.norm_squared() < 0.5 * 0.5 {
-                    break cursor;
-                }
+        Command::Relay {
+            upstream,
+            listen,
+            max_viewers,
+        } => {
+            let config = networking::relay::RelayConfig {
+                upstream,
+                addr: listen,
+                max_viewers,
             };
-            let (client, _, thread) =
-                networking::client::connect(addr, None, cursor).unwrap();
+            let (relay, thread) = networking::relay::relay(config).unwrap();
             ctrlc::set_handler(move || {
-                client.shutdown();
+                relay.shutdown();
             })
             .unwrap();
             thread.join().unwrap();
         },
-        (None, None) => run_gui(),
-        _ => unreachable!(),
+        // This is handled before anything gets set up, so getting here
+        // is a bug, but not one worth panicking over.
+        Command::Replay {
+            ..
+        } => {
+            error!("the replay command was handled too late");
+            std::process::exit(1);
+        },
+    }
+}
+
+/// Hosts a headless server until it's shut down.
+fn serve(options: ServeOptions, config: &config::Config) {
+    let server_config = networking::server::ServerConfig {
+        max_movement_violations: options.kick_violations,
+        max_players: options.max_players,
+        encrypt: options.encrypt,
+        max_client_kbps: options.max_kbps,
+        announce: options.announce.map(|master| {
+            networking::master::Announcement {
+                master,
+                name: options.game_name.clone(),
+            }
+        }),
+        state_file: if options.save_state || options.resume {
+            config::server_state_file()
+        } else {
+            None
+        },
+        resume: options.resume,
+        settings_file: options
+            .config
+            .clone()
+            .or_else(config::server_settings_file),
+        admin_secret: config
+            .network
+            .admin_secret
+            .clone()
+            .filter(|secret| !secret.is_empty()),
+        bots: options.bots,
+        velocity_range: options.velocity_range,
+        seed: options.seed,
+        resume_state: None,
+        // Nobody is playing on a headless server to leave it.
+        migrate: false,
+        clock: networking::clock::Clock::default(),
+    };
+    let addr = options.addr;
+    let (server, thread) = match options.websocket {
+        #[cfg(feature = "websocket")]
+        Some(websocket) => {
+            let transport = networking::websocket::UdpWebSocketTransport::new(
+                networking::transport::bind_udp(addr).unwrap(),
+                networking::websocket::bind(websocket).unwrap(),
+            );
+            networking::server::host_with(Box::new(transport), server_config)
+        },
+        #[cfg(not(feature = "websocket"))]
+        Some(websocket) => {
+            warn!(
+                "built without the websocket feature, so not accepting \
+                 websocket connections on {}",
+                websocket
+            );
+            networking::server::host(addr, server_config)
+        },
+        None => networking::server::host(addr, server_config),
+    }
+    .unwrap();
+    let server = Arc::new(server);
+    console::spawn(Arc::clone(&server));
+    if let Some(addr) = options.serve_metrics {
+        #[cfg(feature = "metrics")]
+        networking::metrics::serve(Arc::clone(&server.metrics), addr).unwrap();
+        #[cfg(not(feature = "metrics"))]
+        warn!(
+            "built without the metrics feature, so not serving metrics on {}",
+            addr
+        );
+    }
+    if let Some(addr) = options.admin {
+        if server.admin_secret.lock().is_none() {
+            warn!(
+                "admin connections will be turned away until there's a \
+                 network.admin_secret in the config or an admin_password in \
+                 the server settings"
+            );
+        }
+        let config = networking::admin::AdminConfig {
+            addr,
+        };
+        networking::admin::listen(Arc::clone(&server), config).unwrap();
+    }
+    #[cfg(unix)]
+    {
+        use signal_hook::iterator::Signals;
+
+        let server = Arc::clone(&server);
+        let signals = Signals::new(&[signal_hook::SIGHUP]).unwrap();
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                info!("reloading server settings on SIGHUP");
+                let result =
+                    console::execute(&server, console::Command::Reload);
+                if let Err(err) = result {
+                    warn!("failed to reload: {}", err);
+                }
+            }
+        });
+    }
+    ctrlc::set_handler(move || {
+        server.shutdown();
+    })
+    .unwrap();
+    thread.join().unwrap();
+}
+
+/// Runs a dummy client, or a swarm of them, until it disconnects.
+fn connect(options: ConnectOptions) {
+    let addr = options.addr;
+    let path = match options.path {
+        Some(ref path) => {
+            match swarm::CursorPath::load(path) {
+                Ok(path) => Some(Arc::new(path)),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                },
+            }
+        },
+        None if options.pattern == swarm::Pattern::Path => {
+            eprintln!("the path pattern needs a path file, given with --path");
+            std::process::exit(1);
+        },
+        None => None,
+    };
+    let behavior = swarm::Behavior {
+        pattern: options.pattern,
+        speed: options.speed,
+        path,
+    };
+    if let Some(count) = options.swarm {
+        swarm::run(swarm::SwarmConfig {
+            addr,
+            count,
+            behavior,
+        });
+        return;
+    }
+
+    let cursor = swarm::random_cursor(&mut thread_rng());
+    let mut debug =
+        options.metrics.as_ref().map(|_| debug::DebugState::default());
+    let stats = debug.as_ref().map(|debug| debug.network_tx.clone());
+    let (client, connecting, thread) = match options.rendezvous {
+        Some(rendezvous) => {
+            networking::client::connect_via(
+                addr,
+                rendezvous,
+                stats,
+                cursor,
+                "bot".to_owned(),
+                None,
+            )
+        },
+        None => {
+            networking::client::connect(
+                addr,
+                stats,
+                cursor,
+                "bot".to_owned(),
+                None,
+            )
+        },
+    }
+    .unwrap();
+    ctrlc::set_handler(move || {
+        client.shutdown();
+    })
+    .unwrap();
+    std::thread::spawn(move || swarm::drive(connecting, cursor, behavior));
+    match (debug.as_mut(), options.metrics) {
+        (Some(debug), Some(path)) => {
+            // The stats channel is bounded, so keep draining it while
+            // the client runs.
+            let (done_tx, done_rx) = channel::bounded(1);
+            std::thread::spawn(move || {
+                let _ = thread.join();
+                let _ = done_tx.send(());
+            });
+            while let Err(RecvTimeoutError::Timeout) =
+                done_rx.recv_timeout(debug::NETWORK_STATS_RATE)
+            {
+                debug.update_network();
+            }
+            debug.update_network();
+            let result = File::create(&path)
+                .and_then(|file| debug.write_metrics(BufWriter::new(file)));
+            match result {
+                Ok(()) => info!("wrote metrics to {}", path.display()),
+                Err(err) => warn!("failed to write metrics: {}", err),
+            }
+        },
+        _ => thread.join().unwrap(),
     }
 }
 
-fn run_gui() {
+/// How long to sleep between updates while the window is minimized,
+/// since there aren't any frames to wait on.
+const MINIMIZED_SLEEP: Duration = Duration::from_millis(10);
+
+fn run_gui(mut config: config::Config) {
     let mut imgui = ImGui::init();
     let mut imgui_winit = ImGuiWinit::new(&mut imgui);
+
     let mut events_loop = EventsLoop::new();
-    let window = Window::new(&events_loop).unwrap();
+    let window = WindowBuilder::new()
+        .with_dimensions(LogicalSize::new(
+            config.graphics.window_width,
+            config.graphics.window_height,
+        ))
+        .build(&events_loop)
+        .unwrap();
     let mut window_size = window.get_inner_size().unwrap();
+    // Size of the window when it isn't fullscreen, which is what's
+    // saved in the config.
+    let mut windowed_size = window_size;
 
-    let mut game_state = state::GameState::default();
+    let mut game_state = state::GameState::new(&config);
     let mut debug = debug::DebugState::default();
+    debug.interpolation_delay = config.network.interpolation_delay;
+    debug.interpolation_mode = config.network.interpolation_mode;
 
     let instance = backend::Instance::create("Ball", 1);
-    let surface = instance.create_surface(&window);
-    let mut graphics = graphics::Graphics::new(&instance, surface, &mut imgui);
-    let mut circle_rend = graphics::CircleRenderer::new(&mut graphics);
+    let result = create_graphics(
+        &instance,
+        &window,
+        &mut imgui,
+        config.graphics.present_mode,
+        config.graphics.image_count,
+    );
+    let (mut graphics, mut circle_rend) = match result {
+        Ok(graphics) => graphics,
+        Err(err) => {
+            error!("failed to set up graphics: {}", err);
+            game_state.shutdown(&mut config);
+            return;
+        },
+    };
 
     let mut renderdoc = graphics::renderdoc::init();
+    // The debug window, while it's popped out. This has to be dropped
+    // after it's removed from the graphics.
+    let mut detached_window: Option<debug::DetachedWindow> = None;
 
     let mut last_frame = Instant::now();
     let mut last_update = last_frame;
 
     let mut running = true;
+    let mut minimized = false;
     while running {
-        events_loop.poll_events(|event| {
-            imgui_winit.handle_event(&mut imgui, &event);
-            if let Event::WindowEvent {
-                event,
-                ..
-            } = event
-            {
-                game_state.handle_event(&window_size, &event);
-                match event {
-                    WindowEvent::CloseRequested => {
-                        running = false;
-                    },
-                    WindowEvent::Resized(size) => {
-                        window_size = size;
-                    },
-                    WindowEvent::KeyboardInput {
-                        input,
-                        ..
-                    } => {
-                        match input.virtual_keycode {
-                            Some(VirtualKeyCode::D)
-                                if input.state == ElementState::Pressed =>
-                            {
-                                debug.show_window = !debug.show_window;
+        {
+            profile_scope!(Events);
+            events_loop.poll_events(|event| {
+                if let Some(ref mut detached) = detached_window {
+                    match event {
+                        Event::WindowEvent {
+                            window_id,
+                            event: ref window_event,
+                        } if window_id == detached.id() => {
+                            detached.with_context(|imgui, imgui_winit, _| {
+                                imgui_winit.handle_event(imgui, &event)
+                            });
+                            match *window_event {
+                                // Closing it puts it back in the main
+                                // window.
+                                WindowEvent::CloseRequested => {
+                                    debug.detached = false;
+                                },
+                                WindowEvent::Resized(_) => {
+                                    graphics.resize(window_id);
+                                },
+                                _ => (),
                             }
-                            _ => (),
-                        }
-                    },
-                    _ => (),
+                            return;
+                        },
+                        _ => (),
+                    }
                 }
-            }
-        });
+                imgui_winit.handle_event(&mut imgui, &event);
+                if let Event::DeviceEvent {
+                    event:
+                        DeviceEvent::MouseMotion {
+                            delta,
+                        },
+                    ..
+                } = event
+                {
+                    game_state.handle_mouse_motion(
+                        &window_size,
+                        window.get_hidpi_factor(),
+                        delta,
+                    );
+                }
+                if let Event::WindowEvent {
+                    event,
+                    ..
+                } = event
+                {
+                    game_state.handle_event(&window_size, &event);
+                    match event {
+                        WindowEvent::CloseRequested => {
+                            running = false;
+                        },
+                        WindowEvent::Resized(size) => {
+                            // Keep the last real size, for the config
+                            // and cursor scaling.
+                            minimized = size.width <= 0.0 || size.height <= 0.0;
+                            if !minimized {
+                                window_size = size;
+                                if !game_state.is_fullscreen() {
+                                    windowed_size = size;
+                                }
+                                graphics.resize(window.id());
+                            }
+                        },
+                        WindowEvent::KeyboardInput {
+                            input,
+                            ..
+                        } => {
+                            match input.virtual_keycode {
+                                Some(VirtualKeyCode::D)
+                                    if input.state == ElementState::Pressed =>
+                                {
+                                    debug.show_window = !debug.show_window;
+                                }
+                                Some(VirtualKeyCode::F11)
+                                    if input.state == ElementState::Pressed =>
+                                {
+                                    game_state.toggle_fullscreen();
+                                }
+                                _ => (),
+                            }
+                        },
+                        _ => (),
+                    }
+                }
+            });
+        }
 
         let now = Instant::now();
         let update_time =
             now.duration_since(last_update).as_secs_f32();
         last_update = now;
 
-        game_state.update(update_time);
-
-        if graphics.wait_for_frame(Some(Duration::from_secs_f32(1.0 / 400.0)))
         {
-            let now = Instant::now();
-            let frame_time =
-                now.duration_since(last_frame).as_secs_f32();
-            last_frame = now;
+            profile_scope!(Update);
+            game_state.update(update_time);
+        }
 
-            let ui = imgui_winit.frame(&mut imgui, &window);
-            debug.ui(&ui, &mut graphics, &mut renderdoc, frame_time);
-            game_state.ui(&ui, &debug);
+        // Switching modes changes the window size, so the swapchain is
+        // rebuilt right away instead of waiting for presenting to fail.
+        if game_state.apply_display(&window) {
+            graphics.resize(window.id());
+        }
+        graphics.set_viewport_mapping(game_state.viewport_mapping());
+        game_state.apply_cursor_capture(&window);
+        game_state.apply_cursor_warp(&window);
 
-            let result = graphics.draw_frame(ui, |mut ctx| {
-                game_state.draw(now, &mut circle_rend, &mut ctx, &debug);
-            });
-            if let Err(_) = result {
-                // graphics::renderdoc::trigger_capture(&mut renderdoc, 3);
+        if debug.show_window && debug.detached {
+            if detached_window.is_none() {
+                match detach_window(&instance, &events_loop, &mut graphics) {
+                    Ok(detached) => detached_window = Some(detached),
+                    Err(err) => {
+                        warn!("failed to pop out the debug window: {}", err);
+                        debug.detached = false;
+                    },
+                }
             }
+        } else if let Some(detached) = detached_window.take() {
+            graphics.remove_window(detached.id());
+        }
+
+        // Networking keeps going while minimized, but there's nothing
+        // to draw to.
+        if minimized {
+            std::thread::sleep(MINIMIZED_SLEEP);
+            continue;
+        }
+        let timeout = Some(Duration::from_secs_f32(1.0 / 400.0));
+        let result = match graphics.wait_for_frame(window.id(), timeout) {
+            Ok(false) => Ok(()),
+            Ok(true) => {
+                let now = Instant::now();
+                let frame_time = now.duration_since(last_frame).as_secs_f32();
+                last_frame = now;
+
+                game_state.apply_appearance(&mut imgui);
+                let ui = imgui_winit.frame(&mut imgui, &window);
+                debug.ui(
+                    &ui,
+                    &mut graphics,
+                    &mut renderdoc,
+                    game_state.game(),
+                    game_state.connection_state(),
+                    frame_time,
+                );
+                game_state.ui(&ui, &debug);
+
+                let result = graphics.draw_frame(window.id(), ui, |mut ctx| {
+                    game_state.draw(now, &mut circle_rend, &mut ctx, &debug);
+                });
+                match detached_window {
+                    Some(ref mut detached) if result.is_ok() => {
+                        detached.with_context(|imgui, imgui_winit, window| {
+                            let ui = imgui_winit.frame(imgui, window);
+                            debug.detached_ui(
+                                &ui,
+                                &mut graphics,
+                                &mut renderdoc,
+                                game_state.game(),
+                                game_state.connection_state(),
+                            );
+                            graphics.draw_frame(window.id(), ui, |_| ())
+                        })
+                    },
+                    _ => result,
+                }
+            },
+            Err(err) => Err(err),
+        };
+        match result {
+            // The window was minimized before the resize event came
+            // in.
+            Ok(()) | Err(graphics::GraphicsError::ZeroSizeSurface) => (),
+            Err(ref err) if err.needs_recreate() => {
+                warn!("{}, setting up graphics again", err);
+                let present_mode = graphics.present_mode();
+                let image_count = graphics.image_count();
+                circle_rend.destroy(&mut graphics);
+                graphics.destroy();
+                // The debug window goes back in rather than being set
+                // up again along with the main window.
+                detached_window = None;
+                debug.detached = false;
+                let result = create_graphics(
+                    &instance,
+                    &window,
+                    &mut imgui,
+                    Some(present_mode),
+                    Some(image_count),
+                );
+                match result {
+                    Ok((new_graphics, new_circle_rend)) => {
+                        graphics = new_graphics;
+                        circle_rend = new_circle_rend;
+                    },
+                    Err(err) => {
+                        error!("failed to set up graphics again: {}", err);
+                        save_preferences(
+                            config,
+                            &mut game_state,
+                            windowed_size,
+                            present_mode,
+                            image_count,
+                            &debug,
+                        );
+                        return;
+                    },
+                }
+            },
+            Err(err) => {
+                warn!("failed to draw frame: {}", err);
+                // graphics::renderdoc::trigger_capture(&mut renderdoc, 3);
+            },
         }
     }
 
+    let present_mode = graphics.present_mode();
+    let image_count = graphics.image_count();
+    save_preferences(
+        config,
+        &mut game_state,
+        windowed_size,
+        present_mode,
+        image_count,
+        &debug,
+    );
+
     // Graphics cleanup.
     circle_rend.destroy(&mut graphics);
     graphics.destroy();
+    drop(detached_window);
+}
+
+/// Sets up graphics for a window, along with the renderers that draw
+/// with them.
+fn create_graphics(
+    instance: &backend::Instance,
+    window: &Window,
+    imgui: &mut ImGui,
+    present_mode: Option<PresentMode>,
+    image_count: Option<u32>,
+) -> Result<
+    (
+        graphics::Graphics<backend::Backend>,
+        graphics::CircleRenderer<backend::Backend>,
+    ),
+    graphics::GraphicsError,
+> {
+    let surface = instance.create_surface(window);
+    let mut graphics =
+        graphics::Graphics::new(instance, surface, window.id(), imgui)?;
+    if let Some(present_mode) = present_mode {
+        if graphics.supported_present_modes().contains(&present_mode) {
+            graphics.set_present_mode(present_mode);
+        }
+    }
+    if let Some(image_count) = image_count {
+        graphics.set_image_count(image_count);
+    }
+    let circle_rend = graphics::CircleRenderer::new(&mut graphics)?;
+    Ok((graphics, circle_rend))
+}
+
+/// Opens a window for the debug window to be popped out into, and
+/// starts drawing to it.
+fn detach_window(
+    instance: &backend::Instance,
+    events_loop: &EventsLoop,
+    graphics: &mut graphics::Graphics<backend::Backend>,
+) -> Result<debug::DetachedWindow, String> {
+    let mut detached = debug::DetachedWindow::new(events_loop)
+        .map_err(|err| err.to_string())?;
+    let surface = instance.create_surface(&detached.window);
+    let window_id = detached.id();
+    detached
+        .with_context(|imgui, _, _| {
+            graphics.add_window(window_id, surface, imgui)
+        })
+        .map_err(|err| err.to_string())?;
+    Ok(detached)
+}
+
+/// Saves preferences for the next launch, and stops networking before
+/// the window goes away.
+fn save_preferences(
+    mut config: config::Config,
+    game_state: &mut state::GameState,
+    window_size: LogicalSize,
+    present_mode: PresentMode,
+    image_count: u32,
+    debug: &debug::DebugState,
+) {
+    game_state.shutdown(&mut config);
+    config.graphics.window_width = window_size.width;
+    config.graphics.window_height = window_size.height;
+    config.graphics.present_mode = Some(present_mode);
+    config.graphics.image_count = Some(image_count);
+    config.network.interpolation_delay = debug.interpolation_delay;
+    config.network.interpolation_mode = debug.interpolation_mode;
+    if let Err(err) = config.save() {
+        warn!("failed to save config: {}", err);
+    }
+}
+
+#[test]
+fn legacy_flags_become_subcommands() {
+    let rewrite = |args: &[&str]| {
+        legacy_args(args.iter().map(OsString::from))
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        rewrite(&["ball", "--log-file", "a.log", "-s", "0.0.0.0:1234"]),
+        ["ball", "serve", "0.0.0.0:1234", "--log-file", "a.log"]
+    );
+    assert_eq!(
+        rewrite(&["ball", "--client=1.2.3.4:5", "--swarm", "8"]),
+        ["ball", "connect", "1.2.3.4:5", "--swarm", "8"]
+    );
+    assert_eq!(
+        rewrite(&["ball", "-s0.0.0.0:1234", "--bots", "2"]),
+        ["ball", "serve", "0.0.0.0:1234", "--bots", "2"]
+    );
+    assert_eq!(rewrite(&["ball", "serve", "x"]), ["ball", "serve", "x"]);
 }
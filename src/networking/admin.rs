@@ -0,0 +1,300 @@
+use crate::console::{self, Command};
+use crate::networking::server::ServerHandle;
+use crate::networking::Error;
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Longest line an admin can send, so a connection can't make the
+/// server buffer forever.
+const MAX_LINE_LENGTH: usize = 1024;
+
+/// How long to wait before answering a wrong secret, to slow down
+/// guessing.
+const AUTH_FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest an address is locked out after sending wrong secrets. The
+/// lockout doubles with every failure up to this.
+const MAX_AUTH_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Connections have this long to send the secret.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Admin connections are closed after this long without a command.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Most admin connections open at once, since each one gets a thread.
+const MAX_CONNECTIONS: usize = 4;
+
+/// Wrong secrets sent from one address.
+struct Failures {
+    count: u32,
+    locked_until: Instant,
+}
+
+/// Keeps track of wrong secrets so that addresses guessing them get
+/// locked out for longer and longer.
+#[derive(Default)]
+struct AuthBackoff {
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl AuthBackoff {
+    fn is_locked(&self, ip: IpAddr, now: Instant) -> bool {
+        self.failures
+            .lock()
+            .get(&ip)
+            .map_or(false, |failures| now < failures.locked_until)
+    }
+
+    fn fail(&self, ip: IpAddr, now: Instant) {
+        let mut failures = self.failures.lock();
+        // Addresses that have stopped guessing are forgotten, so the
+        // map can't grow forever.
+        failures.retain(|_, failures| {
+            now < failures.locked_until + MAX_AUTH_BACKOFF
+        });
+        let entry = failures.entry(ip).or_insert(Failures {
+            count: 0,
+            locked_until: now,
+        });
+        entry.count += 1;
+        let backoff = AUTH_FAILURE_DELAY * 2u32.pow(entry.count.min(16) - 1);
+        entry.locked_until = now + backoff.min(MAX_AUTH_BACKOFF);
+    }
+
+    fn succeed(&self, ip: IpAddr) {
+        self.failures.lock().remove(&ip);
+    }
+}
+
+/// Counts an open connection until it's dropped.
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Options for remotely administering a server.
+#[derive(Clone, Debug)]
+pub struct AdminConfig {
+    /// Address to accept admin connections on.
+    pub addr: SocketAddr,
+}
+
+/// Accepts admin connections on a new thread, which can run the same
+/// commands as the server console.
+///
+/// The protocol is line based. The first line is the server's
+/// `admin_secret`, which gets answered with `ok`, and after that every
+/// line is a command. Every reply ends with an empty line. Without a
+/// secret, every connection is turned away.
+///
+/// At most `MAX_CONNECTIONS` are open at once, the secret has to arrive
+/// within `AUTH_TIMEOUT`, and addresses sending wrong secrets are
+/// locked out for longer each time.
+///
+/// Nothing is encrypted, so the port should only be reachable over
+/// something like an SSH tunnel or VPN.
+pub fn listen(
+    server: Arc<ServerHandle>,
+    config: AdminConfig,
+) -> Result<(SocketAddr, JoinHandle<()>), Error> {
+    let listener = TcpListener::bind(config.addr).map_err(|err| {
+        Error::BindSocket {
+            addr: config.addr,
+            err,
+        }
+    })?;
+    let addr = listener.local_addr().map_err(|err| {
+        Error::BindSocket {
+            addr: config.addr,
+            err,
+        }
+    })?;
+    info!("accepting admin connections on {}", addr);
+
+    let thread = thread::spawn(move || {
+        let open = Arc::new(AtomicUsize::new(0));
+        let backoff = Arc::new(AuthBackoff::default());
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("failed to accept admin connection: {}", err);
+                    continue;
+                },
+            };
+            let peer = stream.peer_addr().ok();
+            if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                open.fetch_sub(1, Ordering::SeqCst);
+                warn!("turning away admin from {:?}, too many connected", peer);
+                let _ = writeln!(stream, "error: too many admins connected");
+                continue;
+            }
+            let guard = ConnectionGuard(Arc::clone(&open));
+            let server = Arc::clone(&server);
+            let backoff = Arc::clone(&backoff);
+            // Admins are few and far between, so a thread each is fine.
+            thread::spawn(move || {
+                let _guard = guard;
+                if let Err(err) = handle_connection(&server, &backoff, stream) {
+                    warn!("admin connection from {:?} failed: {}", peer, err);
+                }
+            });
+        }
+        error!("stopped accepting admin connections");
+    });
+    Ok((addr, thread))
+}
+
+fn handle_connection(
+    server: &ServerHandle,
+    backoff: &AuthBackoff,
+    stream: TcpStream,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(AUTH_TIMEOUT))?;
+    stream.set_write_timeout(Some(AUTH_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    // Read this fresh every time, since reloading the server's
+    // settings can change it.
+    let secret = match server.admin_secret.lock().clone() {
+        Some(secret) => secret,
+        None => {
+            warn!("turning away admin from {}, there's no secret", peer);
+            writeln!(writer, "error: no admin secret is set")?;
+            return Ok(());
+        },
+    };
+    if backoff.is_locked(peer.ip(), Instant::now()) {
+        warn!("turning away admin from {}, too many wrong secrets", peer);
+        writeln!(writer, "error: too many wrong secrets, try again later")?;
+        return Ok(());
+    }
+    match read_line(&mut reader)? {
+        Some(ref line) if secrets_match(line.as_bytes(), secret.as_bytes()) => {
+            info!("admin connected from {}", peer);
+            backoff.succeed(peer.ip());
+            writer.set_read_timeout(Some(IDLE_TIMEOUT))?;
+            writer.set_write_timeout(None)?;
+            writeln!(writer, "ok")?;
+        },
+        Some(_) => {
+            warn!("admin connection from {} sent the wrong secret", peer);
+            backoff.fail(peer.ip(), Instant::now());
+            thread::sleep(AUTH_FAILURE_DELAY);
+            writeln!(writer, "error: wrong secret")?;
+            return Ok(());
+        },
+        None => return Ok(()),
+    }
+
+    while let Some(line) = read_line(&mut reader)? {
+        let reply = line.parse::<Command>().and_then(|command| {
+            info!("admin {} ran {:?}", peer, command);
+            console::execute(server, command)
+        });
+        match reply {
+            Ok(ref output) if output.is_empty() => writeln!(writer)?,
+            Ok(output) => writeln!(writer, "{}\n", output)?,
+            Err(err) => writeln!(writer, "error: {}\n", err)?,
+        }
+    }
+    info!("admin from {} disconnected", peer);
+    Ok(())
+}
+
+/// Reads a line without the line ending, or `None` once the other end
+/// closes the connection.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let limit = MAX_LINE_LENGTH as u64 + 1;
+    if reader.take(limit).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && line.len() > MAX_LINE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "admin line too long",
+        ));
+    }
+    let len = line.trim_end_matches(|c| c == '\r' || c == '\n').len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+/// Compares two secrets in time that only depends on their lengths.
+fn secrets_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() &&
+        a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[test]
+fn admin_requires_secret() {
+    use crate::networking::server::{self, ServerConfig};
+    use crate::networking::transport::ChannelNetwork;
+
+    let (server, _) = server::host_with(
+        Box::new(ChannelNetwork::new().bind()),
+        ServerConfig {
+            admin_secret: Some("hunter2".to_owned()),
+            ..ServerConfig::default()
+        },
+    )
+    .unwrap();
+    let server = Arc::new(server);
+    let (addr, _) = listen(
+        Arc::clone(&server),
+        AdminConfig {
+            addr: "127.0.0.1:0".parse().unwrap(),
+        },
+    )
+    .unwrap();
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    writeln!(&stream, "hunter3").unwrap();
+    let reply = read_line(&mut reader).unwrap();
+    assert_eq!(reply.as_ref().map(String::as_str), Some("error: wrong secret"));
+    assert_eq!(read_line(&mut reader).unwrap(), None);
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    writeln!(&stream, "hunter2\nlist").unwrap();
+    let mut replies = Vec::new();
+    for _ in 0..3 {
+        replies.push(read_line(&mut reader).unwrap().unwrap());
+    }
+    assert_eq!(replies, vec!["ok", "no players connected", ""]);
+    server.shutdown();
+}
+
+#[test]
+fn auth_backoff_grows() {
+    let backoff = AuthBackoff::default();
+    let ip = "127.0.0.1".parse().unwrap();
+    let now = Instant::now();
+    assert!(!backoff.is_locked(ip, now));
+    backoff.fail(ip, now);
+    assert!(backoff.is_locked(ip, now));
+    assert!(!backoff.is_locked(ip, now + AUTH_FAILURE_DELAY));
+    backoff.fail(ip, now);
+    assert!(backoff.is_locked(ip, now + AUTH_FAILURE_DELAY));
+    for _ in 0..20 {
+        backoff.fail(ip, now);
+    }
+    assert!(!backoff.is_locked(ip, now + MAX_AUTH_BACKOFF));
+    backoff.succeed(ip);
+    assert!(!backoff.is_locked(ip, now));
+}
@@ -4,12 +4,27 @@ use crate::game::{
     GameSettings,
     Input,
 };
-use crate::networking::connection::{Connection, HEADER_BYTES};
-use crate::networking::event_loop::{run_event_loop, EventHandler};
+use crate::networking::clock::{Clock, Timeout, Timer};
+use crate::networking::connection::Connection;
+use crate::networking::crypto::{KeyExchange, PublicKey, Side};
+use crate::networking::event_loop::{
+    run_event_loop,
+    run_event_loops,
+    EventHandler,
+};
+use crate::networking::master::{self, MasterPacket};
+use crate::networking::packet_log::{self, Direction, Endpoint, PacketKind};
+use crate::networking::persist::SavedState;
+use crate::networking::reliable::{GaveUp, Reliable};
 use crate::networking::server::ServerPacket;
 use crate::networking::tick::Interval;
+use crate::networking::transport::{self, Transport};
 use crate::networking::{
+    check_name,
+    CheckLimits,
     Error,
+    Migration,
+    PingSchedule,
     RecvError,
     RttEstimator,
     CONNECTION_TIMEOUT,
@@ -20,12 +35,14 @@ use crossbeam::channel::{self, Receiver, Sender};
 use log::{debug, error, info, trace, warn};
 use mio::net::UdpSocket;
 use mio::{Event, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
-use mio_extras::timer::{self, Timeout, Timer};
 use nalgebra::Point2;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, VecDeque};
-use std::io::{self, Cursor};
-use std::net::SocketAddr;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
@@ -35,11 +52,40 @@ const SHUTDOWN: Token = Token(2);
 
 const TICK_RATE: Duration = Duration::from_millis(15);
 
+/// Longest input packets go unsent while the cursor isn't moving.
+///
+/// Input packets carry acks for everything the server sends, so this
+/// has to stay well under the 32 packets the ack bits cover.
+const IDLE_INPUT_RATE: Duration = Duration::from_millis(100);
+
+/// Time to wait for the first handshake reply before sending it again,
+/// which doubles after every attempt.
+const HANDSHAKE_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Number of times the handshake is sent before giving up.
+///
+/// The last wait has to end before `CONNECTION_TIMEOUT` does.
+pub const MAX_HANDSHAKE_ATTEMPTS: usize = 4;
+
+/// Time to wait for the server to acknowledge a disconnect before
+/// sending it again.
+const DISCONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of times a disconnect is sent before shutting down without
+/// an acknowledgement.
+const MAX_DISCONNECT_ATTEMPTS: usize = 5;
+
+/// Number of handshakes sent straight to the server when connecting
+/// through a rendezvous, before asking it for a relay instead.
+const PUNCH_ATTEMPTS: usize = 2;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum TimeoutState {
     Tick,
     Ping,
     UpdateStats,
+    RetryHandshake,
+    RetryDisconnect,
     LostConnection,
 }
 
@@ -48,18 +94,42 @@ pub enum ClientPacket {
     Handshake {
         /// Cursor position when connecting.
         cursor: Point2<f32>,
+        /// Name to show for this player.
+        name: String,
+        /// Preferred hue from 0 to 1, or `None` for a random one.
+        hue: Option<f32>,
+        /// Public key to encrypt the connection with, if the client
+        /// supports it.
+        key: Option<PublicKey>,
+        /// Whether to just watch, without joining as a player.
+        spectate: bool,
     },
     Settings(GameSettings),
     Input(Input),
+    /// Asks the server to resend the full static state.
+    RequestState,
+    /// Asks the server for a summary of the game, without joining.
+    ///
+    /// This can be sent without a handshake, and the server doesn't
+    /// keep any state around for answering it.
+    Query,
     Disconnect,
     Ping,
     Pong(u32),
+    /// Port the backup client has bound to carry on hosting the game
+    /// on, sent in reply to every `ServerPacket::Backup`.
+    BackupReady(u16),
 }
 
 pub enum ClientState {
     Connecting {
         done: Sender<Result<(Game, ConnectedHandle), Option<Error>>>,
         cursor: Point2<f32>,
+        name: String,
+        hue: Option<f32>,
+        /// Key exchange offered in the handshake.
+        key_exchange: Option<KeyExchange>,
+        spectate: bool,
     },
     Connected {
         done: Sender<Option<Error>>,
@@ -67,6 +137,8 @@ pub enum ClientState {
         rtt: RttEstimator,
         ping: Interval,
         game: GameHandle,
+        /// Last input packet sent, and when it was sent.
+        last_input: Option<(Input, Instant)>,
     },
 }
 
@@ -76,20 +148,76 @@ struct Stats {
 }
 
 pub struct Client {
-    socket: UdpSocket,
+    transport: Box<dyn Transport>,
+    /// Address of the server.
+    addr: SocketAddr,
     timer: Timer<TimeoutState>,
     recv_buffer: [u8; MAX_PACKET_SIZE],
     send_queue: VecDeque<Vec<u8>>,
     poll: Poll,
     timeout: Timeout,
     connection: Connection,
-    reliable: HashMap<u32, ClientPacket>,
+    reliable: Reliable<ClientPacket>,
+    ping_schedule: PingSchedule,
     state: ClientState,
     _shutdown: Registration,
     /// Marks after `shutdown` has been received, to shutdown when the
-    /// `send_queue` is empty.
+    /// `send_queue` is empty and the server has acknowledged the
+    /// disconnect.
     needs_shutdown: bool,
+    /// Number of disconnect packets sent so far.
+    disconnect_attempts: usize,
+    /// Whether the server has acknowledged the disconnect.
+    disconnect_acked: bool,
+    /// Latest state from the server to carry on the game with, if
+    /// this client is the backup host.
+    backup: Option<SavedState>,
+    /// Socket to host the game on if this client has to take over,
+    /// bound as soon as it's picked as the backup so that the port it
+    /// reports is really free.
+    standby: Option<UdpSocket>,
     stats: Option<Stats>,
+    /// Number of handshake packets sent so far, shared with the
+    /// `ClientHandle`.
+    handshake_attempts: Arc<AtomicUsize>,
+    clock: Clock,
+    /// Master server that introduces this client to a server behind
+    /// NAT while connecting, and relays to it if that doesn't work.
+    rendezvous: Option<SocketAddr>,
+    /// Whether `addr` is a relay on the rendezvous, rather than the
+    /// server itself.
+    relayed: bool,
+    /// Cookie from the rendezvous proving our address, which it wants
+    /// with every request.
+    rendezvous_cookie: Option<u64>,
+    /// Packets sent and reported lost since the connection state was
+    /// last updated.
+    packets_sent: u32,
+    packets_lost: u32,
+    /// Recent fraction of sent packets that were lost.
+    loss: f32,
+    /// Latest connection state, shared with the `ClientHandle`.
+    connection_state: Arc<Mutex<ConnectionState>>,
+}
+
+/// What a client's connection is up to, for anything outside the
+/// networking thread.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// Waiting on a handshake reply, after sending the handshake this
+    /// many times.
+    Connecting {
+        attempts: usize,
+    },
+    Connected {
+        /// Estimated round trip time in seconds, once there's been a
+        /// sample.
+        rtt: Option<f32>,
+        /// Recent fraction of sent packets that were lost.
+        loss: f32,
+    },
+    /// Shutting down, or already shut down.
+    Disconnecting,
 }
 
 pub type ConnectingHandle =
@@ -100,28 +228,179 @@ pub type ConnectedHandle = Receiver<Option<Error>>;
 /// Client handle used while connecting to a sever.
 pub struct ClientHandle {
     shutdown: SetReadiness,
+    connection_state: Arc<Mutex<ConnectionState>>,
+    handshake_attempts: Arc<AtomicUsize>,
+}
+
+/// Where a client connects to, and what it tells the server about
+/// itself in the handshake.
+pub struct ClientOptions {
+    pub transport: Box<dyn Transport>,
+    pub addr: SocketAddr,
+    /// Master server to punch through NAT with, if the server might be
+    /// behind one.
+    pub rendezvous: Option<SocketAddr>,
+    pub cursor: Point2<f32>,
+    pub name: String,
+    pub hue: Option<f32>,
+    /// Whether to join as a spectator rather than a player.
+    pub spectate: bool,
+    /// Where the client gets the time from, which tests can step
+    /// through by hand.
+    pub clock: Clock,
 }
 
 pub fn connect(
     addr: SocketAddr,
     stats: Option<Sender<NetworkStats>>,
     cursor: Point2<f32>,
+    name: String,
+    hue: Option<f32>,
 ) -> Result<(ClientHandle, ConnectingHandle, JoinHandle<()>), Error> {
-    let (done_tx, done_rx) = channel::bounded(1);
-    let (shutdown_registration, shutdown_set_readiness) = Registration::new2();
-    let client =
-        Client::new(addr, done_tx, stats, shutdown_registration, cursor)?;
+    let socket = transport::bind_udp("0.0.0.0:0".parse().unwrap())?;
+    connect_with(Box::new(socket), addr, stats, cursor, name, hue)
+}
+
+/// Connects to a server at `addr` over some transport.
+pub fn connect_with(
+    transport: Box<dyn Transport>,
+    addr: SocketAddr,
+    stats: Option<Sender<NetworkStats>>,
+    cursor: Point2<f32>,
+    name: String,
+    hue: Option<f32>,
+) -> Result<(ClientHandle, ConnectingHandle, JoinHandle<()>), Error> {
+    let options = ClientOptions {
+        transport,
+        addr,
+        rendezvous: None,
+        cursor,
+        name,
+        hue,
+        spectate: false,
+        clock: Clock::default(),
+    };
+    let (client, handle, connecting) = start_client(options, stats)?;
     let thread = thread::spawn(move || {
         run_event_loop(client);
         info!("client done");
     });
-    Ok((
-        ClientHandle {
-            shutdown: shutdown_set_readiness,
-        },
-        done_rx,
-        thread,
-    ))
+    Ok((handle, connecting, thread))
+}
+
+/// Connects to a server that might be behind NAT, with the help of a
+/// master server it's listed on.
+///
+/// Both ends send packets toward each other to punch through, and if
+/// no handshake reply comes back, the client falls back to a relay on
+/// the master server.
+pub fn connect_via(
+    addr: SocketAddr,
+    rendezvous: SocketAddr,
+    stats: Option<Sender<NetworkStats>>,
+    cursor: Point2<f32>,
+    name: String,
+    hue: Option<f32>,
+) -> Result<(ClientHandle, ConnectingHandle, JoinHandle<()>), Error> {
+    let socket = transport::bind_udp("0.0.0.0:0".parse().unwrap())?;
+    let options = ClientOptions {
+        transport: Box::new(socket),
+        addr,
+        rendezvous: Some(rendezvous),
+        cursor,
+        name,
+        hue,
+        spectate: false,
+        clock: Clock::default(),
+    };
+    let (client, handle, connecting) = start_client(options, stats)?;
+    let thread = thread::spawn(move || {
+        run_event_loop(client);
+        info!("client done");
+    });
+    Ok((handle, connecting, thread))
+}
+
+/// Connects to a server at `addr` as a spectator, which gets
+/// everything a player would but isn't in the game.
+///
+/// The game's player id is `SPECTATOR_ID`, which isn't in any
+/// snapshot.
+pub fn spectate(
+    addr: SocketAddr,
+    name: String,
+) -> Result<(ClientHandle, ConnectingHandle, JoinHandle<()>), Error> {
+    let socket = transport::bind_udp("0.0.0.0:0".parse().unwrap())?;
+    let options = ClientOptions {
+        transport: Box::new(socket),
+        addr,
+        rendezvous: None,
+        cursor: Point2::origin(),
+        name,
+        hue: None,
+        spectate: true,
+        clock: Clock::default(),
+    };
+    let (client, handle, connecting) = start_client(options, None)?;
+    let thread = thread::spawn(move || {
+        run_event_loop(client);
+        info!("spectator client done");
+    });
+    Ok((handle, connecting, thread))
+}
+
+/// Connects several clients at once, which all share one thread.
+///
+/// Each client is given as a starting cursor position and a name.
+/// This is meant for load testing, so it's not as responsive as
+/// giving each client its own thread.
+pub fn connect_many(
+    addr: SocketAddr,
+    stats: Option<Sender<NetworkStats>>,
+    clients: Vec<(Point2<f32>, String)>,
+) -> Result<(Vec<(ClientHandle, ConnectingHandle)>, JoinHandle<()>), Error> {
+    let mut handlers = Vec::with_capacity(clients.len());
+    let mut handles = Vec::with_capacity(clients.len());
+    for (cursor, name) in clients {
+        let socket = transport::bind_udp("0.0.0.0:0".parse().unwrap())?;
+        let options = ClientOptions {
+            transport: Box::new(socket),
+            addr,
+            rendezvous: None,
+            cursor,
+            name,
+            hue: None,
+            spectate: false,
+            clock: Clock::default(),
+        };
+        let (client, handle, connecting) =
+            start_client(options, stats.clone())?;
+        handlers.push(client);
+        handles.push((handle, connecting));
+    }
+    let thread = thread::spawn(move || {
+        run_event_loops(handlers);
+        info!("clients done");
+    });
+    Ok((handles, thread))
+}
+
+/// Creates a client, without starting its event loop.
+pub fn start_client(
+    options: ClientOptions,
+    stats: Option<Sender<NetworkStats>>,
+) -> Result<(Client, ClientHandle, ConnectingHandle), Error> {
+    let (done_tx, done_rx) = channel::bounded(1);
+    let (shutdown_registration, shutdown_set_readiness) = Registration::new2();
+    let client = Client::new(options, done_tx, stats, shutdown_registration)?;
+    let connection_state = Arc::clone(&client.connection_state);
+    let handshake_attempts = Arc::clone(&client.handshake_attempts);
+    let handle = ClientHandle {
+        shutdown: shutdown_set_readiness,
+        connection_state,
+        handshake_attempts,
+    };
+    Ok((client, handle, done_rx))
 }
 
 impl ClientHandle {
@@ -131,6 +410,17 @@ impl ClientHandle {
             error!("failed to signal shutdown to client: {}", err)
         }
     }
+
+    /// Gets the latest state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        *self.connection_state.lock()
+    }
+
+    /// Gets how many times the handshake has been sent so far, up to
+    /// `MAX_HANDSHAKE_ATTEMPTS`, which is kept after disconnecting.
+    pub fn handshake_attempts(&self) -> usize {
+        self.handshake_attempts.load(Ordering::SeqCst)
+    }
 }
 
 impl Drop for ClientHandle {
@@ -148,9 +438,12 @@ impl ClientPacket {
             } => true,
             ClientPacket::Settings(_) => true,
             ClientPacket::Input(_) => false,
+            ClientPacket::RequestState => false,
+            ClientPacket::Query => false,
             ClientPacket::Disconnect => false,
             ClientPacket::Ping => false,
             ClientPacket::Pong(_) => false,
+            ClientPacket::BackupReady(_) => false,
         }
     }
 
@@ -167,6 +460,36 @@ impl ClientPacket {
     }
 }
 
+impl CheckLimits for ClientPacket {
+    fn check_limits(&self) -> Result<(), RecvError> {
+        match self {
+            ClientPacket::Handshake {
+                name,
+                ..
+            } => check_name(name),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PacketKind for ClientPacket {
+    fn kind(&self) -> &'static str {
+        match self {
+            ClientPacket::Handshake {
+                ..
+            } => "Handshake",
+            ClientPacket::Settings(_) => "Settings",
+            ClientPacket::Input(_) => "Input",
+            ClientPacket::RequestState => "RequestState",
+            ClientPacket::Query => "Query",
+            ClientPacket::Disconnect => "Disconnect",
+            ClientPacket::Ping => "Ping",
+            ClientPacket::Pong(_) => "Pong",
+            ClientPacket::BackupReady(_) => "BackupReady",
+        }
+    }
+}
+
 impl EventHandler for Client {
     fn poll(&self) -> &Poll {
         &self.poll
@@ -176,12 +499,11 @@ impl EventHandler for Client {
         match event.token() {
             SOCKET => {
                 if event.readiness().is_readable() {
-                    // Don't process any new messages while shutting down.
+                    // Don't process any new messages while shutting
+                    // down, apart from the server acknowledging it.
                     if self.needs_shutdown {
-                        return false;
-                    }
-
-                    if let Err(err) = self.socket_readable() {
+                        self.disconnect_acked |= self.recv_disconnect_ack();
+                    } else if let Err(err) = self.socket_readable() {
                         return self.start_shutdown(Some(err));
                     }
                 }
@@ -191,15 +513,32 @@ impl EventHandler for Client {
                     }
                 }
 
-                if self.send_queue.is_empty() && self.needs_shutdown {
-                    // Finished sending all pending messages so shut
-                    // down for real.
+                if self.send_queue.is_empty() &&
+                    self.needs_shutdown &&
+                    self.disconnect_acked
+                {
+                    // Finished sending all pending messages and the
+                    // server knows we're gone, so shut down for real.
                     return true;
                 }
             },
             TIMER => {
-                // Don't respond to timer events while shutting down.
+                // Only retrying the disconnect matters while shutting
+                // down.
                 if self.needs_shutdown {
+                    while let Some(timeout) = self.timer.poll() {
+                        if timeout != TimeoutState::RetryDisconnect {
+                            continue;
+                        }
+                        if self.disconnect_attempts >= MAX_DISCONNECT_ATTEMPTS {
+                            debug!("disconnect was never acknowledged");
+                            return true;
+                        }
+                        if let Err(err) = self.send_disconnect() {
+                            error!("error sending disconnect packet: {}", err);
+                            return true;
+                        }
+                    }
                     return false;
                 }
 
@@ -229,6 +568,12 @@ impl EventHandler for Client {
                                 if let Some(rtt) = rtt.rtt() {
                                     stats.next.rtt = rtt;
                                 }
+                                stats.next.jitter = rtt.jitter().unwrap_or(0.0);
+                                stats.next.rtt_p50 =
+                                    rtt.percentile(0.5).unwrap_or(0.0);
+                                stats.next.rtt_p95 =
+                                    rtt.percentile(0.95).unwrap_or(0.0);
+                                stats.next.rtt_histogram = rtt.histogram();
                             }
                             stats.send.send(stats.next).unwrap();
                             stats.next = NetworkStats::default();
@@ -237,8 +582,23 @@ impl EventHandler for Client {
                                 TimeoutState::UpdateStats,
                             );
                         },
+                        TimeoutState::RetryHandshake => {
+                            if let Err(err) = self.retry_handshake() {
+                                return self.start_shutdown(Some(err));
+                            }
+                        },
+                        // Only set while shutting down.
+                        TimeoutState::RetryDisconnect => (),
                         TimeoutState::LostConnection => {
-                            return self.start_shutdown(Some(Error::TimedOut));
+                            let err = match self.state {
+                                ClientState::Connecting {
+                                    ..
+                                } => Error::NoResponse,
+                                ClientState::Connected {
+                                    ..
+                                } => Error::TimedOut,
+                            };
+                            return self.start_shutdown(Some(err));
                         },
                     }
                 }
@@ -252,34 +612,48 @@ impl EventHandler for Client {
 
         false
     }
+
+    fn panicked(&mut self, err: Error) {
+        *self.connection_state.lock() = ConnectionState::Disconnecting;
+        // The reason for shutting down might already have been sent,
+        // in which case there's no room left for this.
+        match self.state {
+            ClientState::Connecting {
+                ref mut done,
+                ..
+            } => {
+                let _ = done.try_send(Err(Some(err)));
+            },
+            ClientState::Connected {
+                ref mut done,
+                ..
+            } => {
+                let _ = done.try_send(Some(err));
+            },
+        }
+    }
 }
 
 impl Client {
     pub fn new(
-        addr: SocketAddr,
+        options: ClientOptions,
         done: Sender<Result<(Game, ConnectedHandle), Option<Error>>>,
         stats: Option<Sender<NetworkStats>>,
         shutdown: Registration,
-        cursor: Point2<f32>,
     ) -> Result<Client, Error> {
-        let socket =
-            UdpSocket::bind(&"0.0.0.0:0".parse().unwrap()).map_err(|err| {
-                Error::BindSocket {
-                    addr,
-                    err,
-                }
-            })?;
-        socket.connect(addr).map_err(|err| {
-            Error::ConnectSocket {
-                addr,
-                err,
-            }
-        })?;
-        let mut timer = timer::Builder::default()
-            .tick_duration(Duration::from_millis(10))
-            .build();
+        let ClientOptions {
+            transport,
+            addr,
+            rendezvous,
+            cursor,
+            name,
+            hue,
+            spectate,
+            clock,
+        } = options;
+        let mut timer = clock.timer();
         let poll = Poll::new().map_err(Error::poll)?;
-        poll.register(&socket, SOCKET, Ready::readable(), PollOpt::edge())
+        poll.register(&*transport, SOCKET, Ready::readable(), PollOpt::edge())
             .map_err(Error::poll)?;
         poll.register(&timer, TIMER, Ready::readable(), PollOpt::edge())
             .map_err(Error::poll)?;
@@ -293,17 +667,23 @@ impl Client {
         }
 
         let mut client = Client {
-            socket,
+            transport,
+            addr,
             timer,
             recv_buffer: [0; MAX_PACKET_SIZE],
             send_queue: VecDeque::new(),
             poll,
             timeout,
             connection: Connection::default(),
-            reliable: HashMap::new(),
+            reliable: Reliable::default(),
+            ping_schedule: PingSchedule::default(),
             state: ClientState::Connecting {
                 done,
                 cursor,
+                name,
+                hue,
+                key_exchange: KeyExchange::new(),
+                spectate,
             },
             _shutdown: shutdown,
             stats: stats.map(|send| {
@@ -313,16 +693,111 @@ impl Client {
                 }
             }),
             needs_shutdown: false,
+            disconnect_attempts: 0,
+            disconnect_acked: false,
+            backup: None,
+            standby: None,
+            handshake_attempts: Arc::new(AtomicUsize::new(0)),
+            clock,
+            rendezvous,
+            relayed: false,
+            rendezvous_cookie: None,
+            packets_sent: 0,
+            packets_lost: 0,
+            loss: 0.0,
+            connection_state: Arc::new(Mutex::new(
+                ConnectionState::Connecting {
+                    attempts: 0,
+                },
+            )),
         };
 
-        // Send handshake
-        client.send(&ClientPacket::Handshake {
-            cursor,
-        })?;
+        client.send_handshake()?;
 
         Ok(client)
     }
 
+    /// Sends the handshake packet and schedules sending it again in
+    /// case there's no reply.
+    fn send_handshake(&mut self) -> Result<(), Error> {
+        let packet = match self.state {
+            ClientState::Connecting {
+                cursor,
+                ref name,
+                hue,
+                ref key_exchange,
+                spectate,
+                ..
+            } => {
+                ClientPacket::Handshake {
+                    cursor,
+                    name: name.clone(),
+                    hue,
+                    key: key_exchange.as_ref().map(KeyExchange::public_key),
+                    spectate,
+                }
+            },
+            ClientState::Connected {
+                ..
+            } => return Ok(()),
+        };
+        let attempts = self.handshake_attempts.fetch_add(1, Ordering::SeqCst);
+        *self.connection_state.lock() = ConnectionState::Connecting {
+            attempts: attempts + 1,
+        };
+        debug!("sending handshake (attempt {})", attempts + 1);
+        self.send_rendezvous_request(attempts);
+        self.send(&packet)?;
+        self.timer.set_timeout(
+            HANDSHAKE_RETRY_INTERVAL * (1 << attempts),
+            TimeoutState::RetryHandshake,
+        );
+        Ok(())
+    }
+
+    /// Asks the rendezvous to introduce us to the server, or for a
+    /// relay once that's had `PUNCH_ATTEMPTS` to work, if there's a
+    /// rendezvous and we aren't relayed yet.
+    fn send_rendezvous_request(&mut self, attempts: usize) {
+        let rendezvous = match self.rendezvous {
+            Some(rendezvous) if !self.relayed => rendezvous,
+            _ => return,
+        };
+        let request = if attempts < PUNCH_ATTEMPTS {
+            MasterPacket::Introduce {
+                game: self.addr,
+                cookie: self.rendezvous_cookie,
+            }
+        } else {
+            MasterPacket::Relay {
+                game: self.addr,
+                cookie: self.rendezvous_cookie,
+            }
+        };
+        let data = master::encode(&request);
+        if let Err(err) = self.transport.send_to(&data, rendezvous) {
+            warn!("failed to send {:?} to {}: {}", request, rendezvous, err);
+        }
+    }
+
+    /// Sends the handshake again if the server hasn't replied yet,
+    /// or gives up after too many attempts.
+    fn retry_handshake(&mut self) -> Result<(), Error> {
+        if let ClientState::Connected {
+            ..
+        } = self.state
+        {
+            return Ok(());
+        }
+        if self.handshake_attempts.load(Ordering::SeqCst) >=
+            MAX_HANDSHAKE_ATTEMPTS
+        {
+            warn!("no handshake reply from server, giving up");
+            return Err(Error::NoResponse);
+        }
+        self.send_handshake()
+    }
+
     /// Starts shutting down the networking thread, with a provided reason.
     ///
     /// If any errors occur at this point, returns `true` to indicate
@@ -334,6 +809,7 @@ impl Client {
             return true;
         }
 
+        *self.connection_state.lock() = ConnectionState::Disconnecting;
         match self.state {
             ClientState::Connecting {
                 ref mut done,
@@ -350,21 +826,57 @@ impl Client {
         }
         // Get rid of any pending packets.
         self.send_queue.clear();
-        // Send off a bunch of disconnected packets to the server, in
-        // the hopes that at least one gets through.
-        for _ in 0..8 {
-            if let Err(err) = self.send(&ClientPacket::Disconnect) {
-                error!(
-                    "error ocurred while sending disconnect packets: {}",
-                    err
-                );
-                return true;
-            }
-        }
         self.needs_shutdown = true;
+        // Keep sending disconnect packets to the server until it
+        // acknowledges one, or it's taking too long.
+        if let Err(err) = self.send_disconnect() {
+            error!("error ocurred while sending disconnect packet: {}", err);
+            return true;
+        }
         false
     }
 
+    /// Sends a disconnect packet, and schedules sending another one
+    /// if it isn't acknowledged in time.
+    fn send_disconnect(&mut self) -> Result<(), Error> {
+        let (packet, _) = self.connection.encode(&ClientPacket::Disconnect);
+        self.send_queue.push_back(packet);
+        self.disconnect_attempts += 1;
+        self.timer.set_timeout(
+            DISCONNECT_RETRY_INTERVAL,
+            TimeoutState::RetryDisconnect,
+        );
+        self.reregister_socket(true)
+    }
+
+    /// Reads everything the server sent while shutting down, and
+    /// returns whether it acknowledged the disconnect.
+    fn recv_disconnect_ack(&mut self) -> bool {
+        let mut acked = false;
+        loop {
+            match self.transport.recv_from(&mut self.recv_buffer) {
+                Ok((bytes_read, addr)) if addr == self.addr => {
+                    let packet = &self.recv_buffer[..bytes_read];
+                    if let Ok((ServerPacket::DisconnectAck, ..)) =
+                        self.connection.decode(packet)
+                    {
+                        acked = true;
+                    }
+                },
+                Ok(_) => (),
+                Err(err) => {
+                    if err.kind() != io::ErrorKind::WouldBlock {
+                        warn!(
+                            "error receiving packet while shutting down: {}",
+                            err
+                        );
+                    }
+                    return acked;
+                },
+            }
+        }
+    }
+
     fn reregister_socket(&mut self, writable: bool) -> Result<(), Error> {
         let readiness = if writable {
             Ready::readable() | Ready::writable()
@@ -373,14 +885,29 @@ impl Client {
         };
 
         self.poll
-            .reregister(&self.socket, SOCKET, readiness, PollOpt::edge())
+            .reregister(&*self.transport, SOCKET, readiness, PollOpt::edge())
             .map_err(Error::poll)
     }
 
     fn socket_readable(&mut self) -> Result<(), Error> {
         loop {
-            match self.socket.recv(&mut self.recv_buffer) {
-                Ok(bytes_read) => {
+            match self.transport.recv_from(&mut self.recv_buffer) {
+                Ok((bytes_read, addr)) if Some(addr) == self.rendezvous => {
+                    self.on_rendezvous_recv(bytes_read);
+                },
+                Ok((bytes_read, _))
+                    if master::is_master_packet(
+                        &self.recv_buffer[..bytes_read],
+                    ) =>
+                {
+                    // Just the server punching through NAT.
+                    trace!("ignoring punch from server");
+                },
+                Ok((_, addr)) if addr != self.addr => {
+                    // Only listen to the server.
+                    trace!("ignoring packet from {}", addr);
+                },
+                Ok((bytes_read, _)) => {
                     // Reset the connection timeout.
                     self.timer.cancel_timeout(&self.timeout);
                     self.timeout = self.timer.set_timeout(
@@ -391,6 +918,12 @@ impl Client {
                     if let Some(ref mut stats) = self.stats {
                         stats.next.bytes_in += bytes_read as u32;
                     }
+                    packet_log::record::<ServerPacket>(
+                        Endpoint::Client,
+                        Direction::Received,
+                        self.addr,
+                        &self.recv_buffer[..bytes_read],
+                    );
                     if let Err(err) = self.on_recv(bytes_read)? {
                         error!(
                             "receiving packet failed ({:?}): {}",
@@ -400,12 +933,27 @@ impl Client {
                     }
                 },
                 Err(err) => {
-                    if err.kind() != io::ErrorKind::WouldBlock {
-                        error!("error receiving packet on client: {}", err);
-                        return Err(Error::SocketRead(err));
-                    } else {
+                    if err.kind() == io::ErrorKind::WouldBlock {
                         break;
                     }
+                    let connecting = match self.state {
+                        ClientState::Connecting {
+                            ..
+                        } => true,
+                        ClientState::Connected {
+                            ..
+                        } => false,
+                    };
+                    // Nothing is listening on the other end, which some
+                    // platforms find out about from ICMP.
+                    let refused = err.kind() ==
+                        io::ErrorKind::ConnectionRefused ||
+                        err.kind() == io::ErrorKind::ConnectionReset;
+                    if connecting && refused {
+                        return Err(Error::ConnectionRefused);
+                    }
+                    error!("error receiving packet on client: {}", err);
+                    return Err(Error::SocketRead(err));
                 },
             }
         }
@@ -413,9 +961,58 @@ impl Client {
         Ok(())
     }
 
+    /// Handles a packet from the rendezvous, which switches over to a
+    /// relay once it has one.
+    fn on_rendezvous_recv(&mut self, bytes_read: usize) {
+        let rendezvous = self.rendezvous.unwrap();
+        let connecting = match self.state {
+            ClientState::Connecting {
+                ..
+            } => true,
+            ClientState::Connected {
+                ..
+            } => false,
+        };
+        match master::decode(&self.recv_buffer[..bytes_read]) {
+            Ok(MasterPacket::Relayed {
+                port,
+            }) if connecting && !self.relayed => {
+                let relay = SocketAddr::new(rendezvous.ip(), port);
+                info!(
+                    "couldn't reach {} directly, relaying through {}",
+                    self.addr, relay
+                );
+                self.addr = relay;
+                self.relayed = true;
+                // Give the relay as long as the server got.
+                self.handshake_attempts.store(0, Ordering::SeqCst);
+                self.timer.cancel_timeout(&self.timeout);
+                self.timeout = self.timer.set_timeout(
+                    CONNECTION_TIMEOUT,
+                    TimeoutState::LostConnection,
+                );
+            },
+            Ok(MasterPacket::Cookie {
+                cookie,
+            }) if connecting => {
+                // Ask again right away, now that it'll be answered.
+                // Getting the same cookie again means asking again
+                // wouldn't help.
+                if self.rendezvous_cookie != Some(cookie) {
+                    self.rendezvous_cookie = Some(cookie);
+                    let attempts =
+                        self.handshake_attempts.load(Ordering::SeqCst);
+                    self.send_rendezvous_request(attempts.saturating_sub(1));
+                }
+            },
+            Ok(packet) => trace!("ignoring {:?} from rendezvous", packet),
+            Err(err) => warn!("bad packet from rendezvous: {}", err),
+        }
+    }
+
     fn socket_writable(&mut self) -> Result<(), Error> {
         while let Some(packet) = self.send_queue.pop_front() {
-            match self.socket.send(&packet) {
+            match self.transport.send_to(&packet, self.addr) {
                 Err(err) => {
                     if err.kind() != io::ErrorKind::WouldBlock {
                         error!(
@@ -432,6 +1029,12 @@ impl Client {
                         stats.next.packets_sent += 1;
                         stats.next.bytes_out += bytes_written as u32;
                     }
+                    packet_log::record::<ClientPacket>(
+                        Endpoint::Client,
+                        Direction::Sent,
+                        self.addr,
+                        &packet,
+                    );
                     // Pretty sure this never happens?
                     if bytes_written < packet.len() {
                         error!(
@@ -455,39 +1058,83 @@ impl Client {
     }
 
     fn send_ping(&mut self) -> Result<(), Error> {
-        let sequence = self.send(&ClientPacket::Ping)?;
+        let now = self.clock.now();
+        let sequence = if self.ping_schedule.ping(now) {
+            Some(self.send(&ClientPacket::Ping)?)
+        } else {
+            None
+        };
         match self.state {
             ClientState::Connected {
                 ref mut ping,
                 ref mut rtt,
                 ..
             } => {
-                let now = Instant::now();
                 let (_, interval) = ping.next(now);
                 self.timer.set_timeout(interval, TimeoutState::Ping);
-                rtt.ping(sequence, now);
+                if let Some(sequence) = sequence {
+                    rtt.ping(sequence, now);
+                }
             },
             _ => unreachable!(),
         }
+        self.update_connection_state();
         Ok(())
     }
 
+    /// Shares the latest RTT and packet loss with the `ClientHandle`.
+    fn update_connection_state(&mut self) {
+        if self.packets_sent > 0 {
+            let loss = self.packets_lost as f32 / self.packets_sent as f32;
+            self.loss = 0.75 * self.loss + 0.25 * loss.min(1.0);
+        }
+        self.packets_sent = 0;
+        self.packets_lost = 0;
+        if let ClientState::Connected {
+            ref rtt,
+            ..
+        } = self.state
+        {
+            *self.connection_state.lock() = ConnectionState::Connected {
+                rtt: rtt.rtt(),
+                loss: self.loss,
+            };
+        }
+    }
+
     fn send_tick(&mut self) -> Result<(), Error> {
         match self.state {
             ClientState::Connected {
                 ref mut tick,
+                ref mut last_input,
                 ref game,
                 ..
             } => {
-                let now = Instant::now();
+                let now = self.clock.now();
                 let (_, interval) = tick.next(now);
                 self.timer.set_timeout(interval, TimeoutState::Tick);
 
-                let tick_packet = ClientPacket::Input(game.latest_input());
-                trace!("sending tick packet to server: {:?}", tick_packet);
+                let input = game.latest_input();
+                let request_state = game.take_state_request();
+                let settings = game.settings.dirty();
+
+                // With nothing new to tell the server, input only has to
+                // go out often enough to keep acks flowing.
+                let idle = settings.is_none() &&
+                    !request_state &&
+                    match *last_input {
+                        Some((last, time)) => {
+                            last == input &&
+                                now.duration_since(time) < IDLE_INPUT_RATE
+                        },
+                        None => false,
+                    };
+                if !idle {
+                    *last_input = Some((input, now));
+                }
 
                 // If the settings have changed, send that as well.
-                if let Some(settings) = game.settings.dirty() {
+                if let Some(settings) = settings {
                     let settings_packet = ClientPacket::Settings(settings);
                     trace!(
                         "sending settings update packet to server: {:?}",
@@ -496,12 +1143,46 @@ impl Client {
                     self.send(&settings_packet)?;
                 }
 
-                self.send(&tick_packet)?;
+                if request_state {
+                    debug!("requesting full static state from server");
+                    self.send(&ClientPacket::RequestState)?;
+                }
+
+                if !idle {
+                    let tick_packet = ClientPacket::Input(input);
+                    trace!("sending tick packet to server: {:?}", tick_packet);
+                    self.send(&tick_packet)?;
+                }
             },
             // We shouldn't really be sending ticks in any other state.
             _ => unreachable!(),
         }
 
+        self.resend_overdue(self.clock.now())
+    }
+
+    /// Resends reliable packets that have gone unacked for too long,
+    /// and gives up on the connection if the server never acks them.
+    fn resend_overdue(&mut self, now: Instant) -> Result<(), Error> {
+        let overdue = self
+            .reliable
+            .take_overdue(now)
+            .map_err(|GaveUp| Error::TimedOut)?;
+        for (packet, resends) in overdue {
+            let game = match self.state {
+                ClientState::Connecting {
+                    ..
+                } => None,
+                ClientState::Connected {
+                    ref game,
+                    ..
+                } => Some(game),
+            };
+            if packet.resend(game) {
+                debug!("resending unacked packet from client: {:?}", packet);
+                self.send_resend(&packet, resends + 1)?;
+            }
+        }
         Ok(())
     }
 
@@ -514,24 +1195,40 @@ impl Client {
             return Ok(Err(RecvError::PacketTooLarge(bytes_read)));
         }
         let packet = &self.recv_buffer[0..bytes_read];
-        let (packet, sequence, acks, lost) =
-            match self.connection.decode(Cursor::new(packet)) {
+        let last_received = self.connection.acks.latest();
+        let (packet, sequence, acks, lost, duplicate) =
+            match self.connection.decode(packet) {
                 Ok(result) => result,
                 Err(err) => return Ok(Err(err)),
             };
+        if duplicate {
+            trace!("skipping duplicate packet {}", sequence);
+            return Ok(Ok(()));
+        }
 
+        self.packets_lost += lost.len() as u32;
         if let Some(ref mut stats) = self.stats {
-            stats.next.packets_lost += lost.len() as u16;
+            stats.next.packets_received += 1;
+            stats.next.packets_lost_out += lost.len() as u16;
+            // Anything skipped over since the newest received packet
+            // is missing.
+            // Sequence numbers aren't trusted, so saturate rather than
+            // overflow on huge gaps.
+            let skipped = sequence.saturating_sub(last_received).max(1) - 1;
+            stats.next.packets_lost_in = stats
+                .next
+                .packets_lost_in
+                .saturating_add(skipped.min(u32::from(u16::MAX)) as u16);
         }
 
         // Remove acked packets from the reliable packet buffer.
         for ack in acks.iter() {
-            self.reliable.remove(&ack);
+            self.reliable.ack(ack);
         }
 
         // Possibly resend any lost packets.
         for lost in lost.into_iter() {
-            if let Some(packet) = self.reliable.remove(&lost) {
+            if let Some((packet, resends)) = self.reliable.take(lost) {
                 let game = match self.state {
                     ClientState::Connecting {
                         ..
@@ -543,7 +1240,7 @@ impl Client {
                 };
                 if packet.resend(game) {
                     debug!("resending lost packet from client: {:?}", packet);
-                    self.send(&packet)?;
+                    self.send_resend(&packet, resends + 1)?;
                 }
             }
         }
@@ -552,6 +1249,8 @@ impl Client {
             ClientState::Connecting {
                 ref mut done,
                 ref cursor,
+                ref mut key_exchange,
+                ..
             } => {
                 match packet {
                     ServerPacket::Handshake {
@@ -561,7 +1260,25 @@ impl Client {
                         settings,
                         snapshot,
                         id,
+                        hue,
+                        key,
+                        connection_id,
+                        seed,
                     } => {
+                        self.connection.id = connection_id;
+                        // The server only sends a key back if it's
+                        // going to encrypt everything after this.
+                        if let Some(key) = key {
+                            let cipher =
+                                key_exchange.take().and_then(|exchange| {
+                                    exchange.finish(&key, Side::Client)
+                                });
+                            if cipher.is_none() {
+                                return Ok(Err(RecvError::Decrypt));
+                            }
+                            info!("connection is encrypted");
+                            self.connection.cipher = cipher;
+                        }
                         let (game, game_handle) = Game::new(
                             players,
                             snapshot,
@@ -569,10 +1286,13 @@ impl Client {
                             round_duration,
                             settings,
                             id,
+                            hue,
+                            seed,
                             *cursor,
                         );
-                        let tick = Interval::new(TICK_RATE);
-                        let ping = Interval::new(PING_RATE);
+                        let now = self.clock.now();
+                        let tick = Interval::new(TICK_RATE, now);
+                        let ping = Interval::new(PING_RATE, now);
                         // Start the timer for sending input ticks and pings.
                         self.timer
                             .set_timeout(tick.interval(), TimeoutState::Tick);
@@ -583,6 +1303,11 @@ impl Client {
                         let (done_tx, done_rx) = channel::bounded(1);
                         let _ = done.send(Ok((game, done_rx)));
 
+                        *self.connection_state.lock() =
+                            ConnectionState::Connected {
+                                rtt: None,
+                                loss: 0.0,
+                            };
                         info!("completed connection to server");
                         // Transition to connected state.
                         Some(ClientState::Connected {
@@ -591,8 +1316,12 @@ impl Client {
                             tick,
                             ping,
                             rtt: RttEstimator::default(),
+                            last_input: None,
                         })
                     },
+                    ServerPacket::ConnectionDenied(reason) => {
+                        return Err(Error::ConnectionDenied(reason));
+                    },
                     // Ignore non-handshake packets
                     _ => {
                         warn!("received {:?} before handshake", packet);
@@ -607,11 +1336,77 @@ impl Client {
             } => {
                 match packet {
                     ServerPacket::Event(event) => game.event(event),
+                    ServerPacket::Events(events) => {
+                        for event in events {
+                            game.event(event);
+                        }
+                    },
                     ServerPacket::Handshake {
                         ..
                     } => warn!("received a second handshake packet"),
+                    ServerPacket::Info {
+                        ..
+                    } => warn!("received an unrequested info packet"),
+                    ServerPacket::ConnectionDenied(_) => {
+                        warn!("received a denial after connecting")
+                    },
+                    ServerPacket::DisconnectAck => {
+                        warn!("received a disconnect ack before disconnecting")
+                    },
+                    ServerPacket::Backup(saved) => {
+                        if self.standby.is_none() {
+                            let any = match self.addr {
+                                SocketAddr::V4(_) => {
+                                    SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+                                },
+                                SocketAddr::V6(_) => {
+                                    SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+                                },
+                            };
+                            match transport::bind_udp(any) {
+                                Ok(socket) => {
+                                    info!("this client is the backup host");
+                                    self.standby = Some(socket);
+                                },
+                                Err(err) => {
+                                    warn!("can't bind a backup socket: {}", err)
+                                },
+                            }
+                        }
+                        self.backup = Some(saved);
+                        let port = self
+                            .standby
+                            .as_ref()
+                            .and_then(|socket| socket.local_addr().ok())
+                            .map(|addr| addr.port());
+                        if let Some(port) = port {
+                            self.send(&ClientPacket::BackupReady(port))?;
+                        }
+                    },
+                    ServerPacket::Migrate(Some(host)) => {
+                        return Err(Error::Migrated(Migration::Join(host)));
+                    },
+                    ServerPacket::Migrate(None) => {
+                        // The server only hands over to a backup that's
+                        // said it has a socket ready.
+                        match self.standby.take() {
+                            Some(socket) => {
+                                // Even without a synced state, the game
+                                // can still carry on with the default
+                                // settings.
+                                let saved =
+                                    self.backup.take().unwrap_or_default();
+                                return Err(Error::Migrated(Migration::Host(
+                                    saved, socket,
+                                )));
+                            },
+                            None => {
+                                warn!("asked to host without a backup socket")
+                            },
+                        }
+                    },
                     ServerPacket::Pong(sequence) => {
-                        rtt.pong(sequence);
+                        rtt.pong(sequence, self.clock.now());
                     },
                     ServerPacket::Ping => {
                         self.send(&ClientPacket::Pong(sequence))?;
@@ -630,21 +1425,39 @@ impl Client {
     }
 
     fn send(&mut self, contents: &ClientPacket) -> Result<u32, Error> {
+        self.send_resend(contents, 0)
+    }
+
+    /// Like `send`, for a packet that's been sent `resends` times
+    /// before.
+    fn send_resend(
+        &mut self,
+        contents: &ClientPacket,
+        resends: u32,
+    ) -> Result<u32, Error> {
         // Don't send any additional packets while shutting down.
         if self.needs_shutdown {
             panic!("attempted to send packet while already shutting down");
         }
 
-        // Serialization errors are always fatal.
-        let size = bincode::serialized_size(contents).unwrap() as usize;
-        let mut packet = Vec::with_capacity(size + HEADER_BYTES);
-        let sequence = self.connection.send_header(&mut packet);
-        bincode::serialize_into(&mut packet, contents).unwrap();
+        let (packet, sequence) = self.connection.encode(contents);
         self.send_queue.push_back(packet);
+        self.packets_sent += 1;
+        let now = self.clock.now();
+        self.ping_schedule.sent(now);
         self.reregister_socket(true)?;
 
         if contents.reliable() {
-            self.reliable.insert(sequence, contents.clone());
+            let rtt = match self.state {
+                ClientState::Connecting {
+                    ..
+                } => None,
+                ClientState::Connected {
+                    ref rtt,
+                    ..
+                } => rtt.rtt(),
+            };
+            self.reliable.insert(sequence, contents.clone(), resends, rtt, now);
         }
 
         Ok(sequence)
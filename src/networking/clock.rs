@@ -0,0 +1,283 @@
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio_extras::timer;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Where an event loop gets the time from, and schedules its timeouts
+/// with.
+///
+/// This is the system clock unless it was made from a `ManualClock`.
+#[derive(Clone, Debug, Default)]
+pub struct Clock {
+    manual: Option<ManualClock>,
+}
+
+/// A clock that only moves forward when it's told to, so that tests
+/// can step event loops through time without waiting on it.
+#[derive(Clone)]
+pub struct ManualClock {
+    inner: Arc<Mutex<ManualInner>>,
+}
+
+struct ManualInner {
+    now: Instant,
+    /// Wakes up every timer made from this clock, to check whether
+    /// anything is due.
+    timers: Vec<SetReadiness>,
+}
+
+/// Timer that fires timeouts according to a `Clock`.
+///
+/// This works like a `mio_extras` timer, which it is when the clock is
+/// the system clock.
+pub struct Timer<T> {
+    kind: TimerKind<T>,
+}
+
+enum TimerKind<T> {
+    System(timer::Timer<T>),
+    Manual {
+        clock: ManualClock,
+        /// Pending timeouts, in the order they're due.
+        timeouts: BTreeMap<(Instant, u64), T>,
+        /// Used to tell apart timeouts that are due at the same time.
+        next_id: u64,
+        registration: Registration,
+        readiness: SetReadiness,
+    },
+}
+
+/// A timeout set on a `Timer`, which can be used to cancel it.
+#[derive(Clone, Debug)]
+pub struct Timeout {
+    kind: TimeoutKind,
+}
+
+#[derive(Clone, Debug)]
+enum TimeoutKind {
+    System(timer::Timeout),
+    Manual(Instant, u64),
+}
+
+impl Clock {
+    pub fn now(&self) -> Instant {
+        match self.manual {
+            Some(ref manual) => manual.now(),
+            None => Instant::now(),
+        }
+    }
+
+    /// Makes a timer that goes by this clock.
+    pub fn timer<T>(&self) -> Timer<T> {
+        let kind = match self.manual {
+            Some(ref manual) => {
+                let (registration, readiness) = Registration::new2();
+                manual.inner.lock().timers.push(readiness.clone());
+                TimerKind::Manual {
+                    clock: manual.clone(),
+                    timeouts: BTreeMap::new(),
+                    next_id: 0,
+                    registration,
+                    readiness,
+                }
+            },
+            None => {
+                TimerKind::System(
+                    timer::Builder::default()
+                        .tick_duration(Duration::from_millis(5))
+                        .build(),
+                )
+            },
+        };
+        Timer {
+            kind,
+        }
+    }
+}
+
+impl ManualClock {
+    /// Makes a clock that starts at the current time.
+    pub fn new() -> ManualClock {
+        ManualClock {
+            inner: Arc::new(Mutex::new(ManualInner {
+                now: Instant::now(),
+                timers: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.inner.lock().now
+    }
+
+    /// Moves time forward, and wakes up any timers with timeouts that
+    /// are now due.
+    pub fn advance(&self, duration: Duration) {
+        let mut inner = self.inner.lock();
+        inner.now += duration;
+        for timer in &inner.timers {
+            // Timers that were dropped just ignore this.
+            let _ = timer.set_readiness(Ready::readable());
+        }
+    }
+
+    /// Gets a `Clock` for event loops to use, which goes by this one.
+    pub fn clock(&self) -> Clock {
+        Clock {
+            manual: Some(self.clone()),
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl fmt::Debug for ManualClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ManualClock").field("now", &self.now()).finish()
+    }
+}
+
+impl<T> Timer<T> {
+    /// Sets a timeout for `state` to be returned from `poll` after
+    /// `delay`.
+    pub fn set_timeout(&mut self, delay: Duration, state: T) -> Timeout {
+        let kind = match self.kind {
+            TimerKind::System(ref mut timer) => {
+                TimeoutKind::System(timer.set_timeout(delay, state))
+            },
+            TimerKind::Manual {
+                ref clock,
+                ref mut timeouts,
+                ref mut next_id,
+                ref readiness,
+                ..
+            } => {
+                let now = clock.now();
+                let due = now + delay;
+                let id = *next_id;
+                *next_id += 1;
+                timeouts.insert((due, id), state);
+                if due <= now {
+                    let _ = readiness.set_readiness(Ready::readable());
+                }
+                TimeoutKind::Manual(due, id)
+            },
+        };
+        Timeout {
+            kind,
+        }
+    }
+
+    /// Cancels a timeout, returning its state if it hadn't fired yet.
+    pub fn cancel_timeout(&mut self, timeout: &Timeout) -> Option<T> {
+        match (&mut self.kind, &timeout.kind) {
+            (TimerKind::System(timer), TimeoutKind::System(timeout)) => {
+                timer.cancel_timeout(timeout)
+            },
+            (
+                TimerKind::Manual {
+                    timeouts,
+                    ..
+                },
+                &TimeoutKind::Manual(due, id),
+            ) => timeouts.remove(&(due, id)),
+            _ => None,
+        }
+    }
+
+    /// Takes the next timeout that's due, if any.
+    pub fn poll(&mut self) -> Option<T> {
+        match self.kind {
+            TimerKind::System(ref mut timer) => timer.poll(),
+            TimerKind::Manual {
+                ref clock,
+                ref mut timeouts,
+                ref readiness,
+                ..
+            } => {
+                let now = clock.now();
+                let next = timeouts.keys().next().cloned();
+                match next {
+                    Some(key) if key.0 <= now => timeouts.remove(&key),
+                    _ => {
+                        // Clear readable, so that the next time the
+                        // clock moves sets it again.
+                        let _ = readiness.set_readiness(Ready::empty());
+                        None
+                    },
+                }
+            },
+        }
+    }
+}
+
+impl<T> Evented for Timer<T> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match self.kind {
+            TimerKind::System(ref timer) => {
+                timer.register(poll, token, interest, opts)
+            },
+            TimerKind::Manual {
+                ref registration,
+                ..
+            } => registration.register(poll, token, interest, opts),
+        }
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match self.kind {
+            TimerKind::System(ref timer) => {
+                timer.reregister(poll, token, interest, opts)
+            },
+            TimerKind::Manual {
+                ref registration,
+                ..
+            } => registration.reregister(poll, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match self.kind {
+            TimerKind::System(ref timer) => timer.deregister(poll),
+            TimerKind::Manual {
+                ref registration,
+                ..
+            } => registration.deregister(poll),
+        }
+    }
+}
+
+#[test]
+fn manual_timeouts_fire_in_order() {
+    let clock = ManualClock::new();
+    let mut timer = clock.clock().timer();
+    timer.set_timeout(Duration::from_millis(20), "second");
+    timer.set_timeout(Duration::from_millis(10), "first");
+    let cancelled = timer.set_timeout(Duration::from_millis(15), "cancelled");
+    assert_eq!(timer.poll(), None);
+    assert_eq!(timer.cancel_timeout(&cancelled), Some("cancelled"));
+    clock.advance(Duration::from_millis(20));
+    assert_eq!(timer.poll(), Some("first"));
+    assert_eq!(timer.poll(), Some("second"));
+    assert_eq!(timer.poll(), None);
+}
@@ -1,15 +1,50 @@
-use crate::networking::RecvError;
-use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+use crate::networking::crypto::Cipher;
+use crate::networking::{CheckLimits, RecvError, MAX_PACKET_SIZE};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BE};
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use smallvec::SmallVec;
-use std::io::{Read, Write};
+use std::io::Cursor;
+use std::ops::Range;
 
-// 4 u32s
-pub const HEADER_BYTES: usize = 4 + 4 + 4;
+/// Number every packet starts with, so that stray traffic gets
+/// rejected before it's deserialized.
+///
+/// This should change whenever the protocol changes incompatibly.
+pub const PROTOCOL_MAGIC: u32 = 0xBA11_000A;
 
-#[derive(Copy, Clone, Debug, Default)]
+/// Magic that encrypted packets start with instead.
+pub const ENCRYPTED_MAGIC: u32 = 0xBA11_E003;
+
+// Magic, payload checksum, connection id, sequence, ack and ack bits,
+// all u32s.
+pub const HEADER_BYTES: usize = 4 * 6;
+
+/// Offset of the connection id into the header.
+const CONNECTION_ID_OFFSET: usize = 8;
+
+/// Offset of the sequence number into the header.
+const SEQUENCE_OFFSET: usize = 12;
+
+/// Part of the header that encryption authenticates, which is
+/// everything after the checksum.
+const AUTHENTICATED_HEADER: Range<usize> = CONNECTION_ID_OFFSET..HEADER_BYTES;
+
+/// Number of packets that acks keep track of, counting back from the
+/// most recently received one.
+pub const ACK_WINDOW: u32 = 32;
+
+/// Which of the last `ACK_WINDOW` packets have been received.
+///
+/// Sequence numbers start at 0 and count up without wrapping. Packets
+/// older than the window are forgotten, so they're neither acked nor
+/// missing as far as this is concerned. The default is for when
+/// nothing has been received yet.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
 pub struct Acks {
-    /// Bitfield of previous packets.
+    /// Bit `n` is set if packet `ack - n` was received, so bit 0 is
+    /// the most recently received packet itself, unless nothing's
+    /// been received.
     ack_bits: u32,
     /// Sequence number of most recently received packet.
     ack: u32,
@@ -19,63 +54,93 @@ pub struct Acks {
 #[derive(Clone, Debug, Default)]
 pub struct Connection {
     pub local_sequence: u32,
+    /// Id the server assigned this connection in the handshake, which
+    /// stays the same if the client's address changes, or `0` before
+    /// there is one.
+    pub id: u32,
     pub acks: Acks,
     pub remote_acks: Acks,
+    /// Key to encrypt packets with, once one's been agreed on.
+    pub cipher: Option<Cipher>,
+}
+
+/// Shifts bits left, where shifting by the whole width or more gives
+/// zero. Sequence numbers come from the network, so gaps can be
+/// arbitrarily large.
+fn shl(bits: u32, shift: u32) -> u32 {
+    bits.checked_shl(shift).unwrap_or(0)
 }
 
 impl Acks {
-    /// Acknowledges a new received packet.
+    /// Acknowledges a new received packet. Acking a packet twice does
+    /// nothing, and packets too old to fit in the window are ignored.
     pub fn ack(&mut self, sequence: u32) {
         if sequence > self.ack {
             // Packet newer than most recent packet, so shift
             // everything.
-            self.ack_bits = self.ack_bits.wrapping_shl(sequence - self.ack);
+            self.ack_bits = shl(self.ack_bits, sequence - self.ack);
             self.ack_bits |= 1;
             self.ack = sequence;
         } else {
             // Received a packet newer than this one before.
-            self.ack_bits |= 1u32.wrapping_shl(self.ack - sequence);
+            self.ack_bits |= shl(1, self.ack - sequence);
         }
     }
 
-    /// Combines this with another set of acks, and returns the set of
-    /// packets that can now be considered lost.
+    /// Combines this with another set of acks for the same packets,
+    /// and returns the packets that fell out of the window without
+    /// being acked, which can now be considered lost.
+    ///
+    /// Acks can arrive out of order, so an older set only adds what
+    /// it acks, and anything it acks that's already out of the window
+    /// is ignored. Every packet up to `new.latest()` is assumed to
+    /// exist, so the caller has to check that it was really sent.
     pub fn combine(&mut self, new: Acks) -> SmallVec<[u32; 4]> {
         let mut lost = SmallVec::new();
         if new.ack > self.ack {
-            // Anything that is outside the range of the new ack can
-            // be considered lost.
-            let mask = !((!0u32).wrapping_shr(new.ack - self.ack));
-            lost.extend(
-                Acks {
-                    ack_bits: !self.ack_bits & mask,
-                    ack: self.ack,
-                }
-                .iter(),
-            );
-
-            // Shift everything.
-            self.ack_bits = self.ack_bits.wrapping_shl(new.ack - self.ack);
-            self.ack_bits |= new.ack;
+            // Everything between the start of the old window and the
+            // start of the new one leaves the window, including any
+            // packets skipped over entirely.
+            let left = self.oldest()..new.oldest();
+            lost.extend(left.filter(|&sequence| !self.contains(sequence)));
+
+            self.ack_bits = shl(self.ack_bits, new.ack - self.ack);
+            self.ack_bits |= new.ack_bits;
             self.ack = new.ack;
         } else {
-            self.ack_bits |= new.ack.wrapping_shl(self.ack - new.ack);
+            self.ack_bits |= shl(new.ack_bits, self.ack - new.ack);
         }
         lost
     }
 
+    /// Gets the oldest sequence number still in the window.
+    fn oldest(self) -> u32 {
+        self.ack.saturating_sub(ACK_WINDOW - 1)
+    }
+
+    /// Gets the sequence number of the most recently received packet.
+    pub fn latest(self) -> u32 {
+        self.ack
+    }
+
     /// Checks if a particular sequence number is present in this set
     /// of acks.
     pub fn contains(self, sequence: u32) -> bool {
         if self.ack < sequence {
             return false;
         }
-        self.ack_bits & (1u32.wrapping_shl(self.ack - sequence)) != 0
+        self.ack_bits & shl(1, self.ack - sequence) != 0
+    }
+
+    /// Checks if a packet might have been received before, which is
+    /// when it's either acked or too old for the window.
+    pub fn seen(self, sequence: u32) -> bool {
+        sequence < self.oldest() || self.contains(sequence)
     }
 
     /// Returns an iterator over the acked packets.
     pub fn iter(self) -> impl Iterator<Item = u32> {
-        (0..32.min(self.ack)).filter_map(move |offset| {
+        (0..=self.ack - self.oldest()).filter_map(move |offset| {
             if self.ack_bits & (1 << offset) != 0 {
                 Some(self.ack - offset)
             } else {
@@ -86,51 +151,260 @@ impl Acks {
 }
 
 impl Connection {
-    /// Processes the header of a received packet and returns it's
-    /// sequence number, as well as acknowledged packets and lost
-    /// packets.
-    pub fn recv_header<B: Read>(
+    /// Processes the header of a received packet and returns the
+    /// packets that are now considered lost.
+    fn apply_header(
         &mut self,
-        mut packet: B,
-    ) -> Result<(u32, Acks, SmallVec<[u32; 4]>), RecvError> {
-        let sequence =
-            packet.read_u32::<BE>().map_err(RecvError::header_read)?;
-        let ack = packet.read_u32::<BE>().map_err(RecvError::header_read)?;
-        let ack_bits =
-            packet.read_u32::<BE>().map_err(RecvError::header_read)?;
-
+        sequence: u32,
+        acks: Acks,
+    ) -> SmallVec<[u32; 4]> {
         self.acks.ack(sequence);
-        let acks = Acks {
-            ack_bits,
-            ack,
-        };
-        let lost = self.remote_acks.combine(acks);
-        Ok((sequence, acks, lost))
+        if acks.ack >= self.local_sequence {
+            // Acks for packets that haven't been sent yet are bogus,
+            // and would make everything before them look lost.
+            return SmallVec::new();
+        }
+        self.remote_acks.combine(acks)
     }
 
-    /// Writes the header for the next packet into a buffer.
+    /// Serializes a packet with a header in front, returning it along
+    /// with its sequence number.
     ///
-    /// Panics if the buffer is not large enough or if an IO error
-    /// occurs while writing.
-    pub fn send_header<B: Write>(&mut self, mut packet: B) -> u32 {
+    /// The packet is encrypted if there's a cipher.
+    pub fn encode<P: Serialize>(&mut self, packet: &P) -> (Vec<u8>, u32) {
+        // Serialization errors are always bugs.
+        let payload = bincode::serialize(packet).unwrap();
+        self.encode_payload(&payload)
+    }
+
+    /// Like `encode`, but never encrypts the packet.
+    ///
+    /// This is for handshakes, which have to be readable before the
+    /// key exchange finishes.
+    pub fn encode_plain<P: Serialize>(&mut self, packet: &P) -> (Vec<u8>, u32) {
+        let payload = bincode::serialize(packet).unwrap();
+        self.write_packet(payload, false)
+    }
+
+    /// Like `encode`, for a packet that's already been serialized.
+    pub fn encode_payload(&mut self, payload: &[u8]) -> (Vec<u8>, u32) {
+        self.write_packet(payload.to_vec(), true)
+    }
+
+    fn write_packet(
+        &mut self,
+        mut payload: Vec<u8>,
+        encrypt: bool,
+    ) -> (Vec<u8>, u32) {
         let sequence = self.local_sequence;
         self.local_sequence += 1;
-        packet.write_u32::<BE>(sequence).unwrap();
-        packet.write_u32::<BE>(self.acks.ack).unwrap();
-        packet.write_u32::<BE>(self.acks.ack_bits).unwrap();
-        sequence
+        let mut header = Vec::with_capacity(HEADER_BYTES);
+        // Filled in once the payload is final.
+        header.write_u32::<BE>(0).unwrap();
+        header.write_u32::<BE>(0).unwrap();
+        header.write_u32::<BE>(self.id).unwrap();
+        header.write_u32::<BE>(sequence).unwrap();
+        header.write_u32::<BE>(self.acks.ack).unwrap();
+        header.write_u32::<BE>(self.acks.ack_bits).unwrap();
+
+        let magic = match self.cipher {
+            Some(ref cipher) if encrypt => {
+                let aad = &header[AUTHENTICATED_HEADER];
+                cipher.seal(sequence, aad, &mut payload);
+                ENCRYPTED_MAGIC
+            },
+            _ => PROTOCOL_MAGIC,
+        };
+        BE::write_u32(&mut header[0..4], magic);
+        BE::write_u32(&mut header[4..8], crc32fast::hash(&payload));
+        header.extend_from_slice(&payload);
+        (header, sequence)
     }
 
     /// Reads the header of a packet, and then deserializes the
     /// contents with serde. Returns the sequence numbers of packets
-    /// that are now considered lost.
-    pub fn decode<B: Read, P: DeserializeOwned>(
+    /// that are now considered lost, and whether the packet is a
+    /// duplicate that shouldn't be handled again.
+    ///
+    /// The network can deliver a packet more than once, which `acks`
+    /// catches as long as it's still in the window. Anything older
+    /// than the window counts as a duplicate too, since there's no
+    /// telling, and it was never acked so reliable packets get resent.
+    ///
+    /// Packets come from untrusted sources, so the connection is only
+    /// updated once the whole packet decodes and passes its limits,
+    /// and decoding can't allocate more than `MAX_PACKET_SIZE` bytes
+    /// no matter what lengths the packet claims.
+    pub fn decode<P: DeserializeOwned + CheckLimits>(
         &mut self,
-        mut read: B,
-    ) -> Result<(P, u32, Acks, SmallVec<[u32; 4]>), RecvError> {
-        let (sequence, acks, lost) = self.recv_header(&mut read)?;
-        let packet =
-            bincode::deserialize_from(read).map_err(RecvError::deserialize)?;
-        Ok((packet, sequence, acks, lost))
+        data: &[u8],
+    ) -> Result<(P, u32, Acks, SmallVec<[u32; 4]>, bool), RecvError> {
+        let (sequence, acks, encrypted) = read_header(data)?;
+        let payload = &data[HEADER_BYTES..];
+        let mut decrypted;
+        let payload = match (encrypted, &self.cipher) {
+            (false, None) => payload,
+            (true, Some(cipher)) => {
+                decrypted = payload.to_vec();
+                let aad = &data[AUTHENTICATED_HEADER];
+                cipher
+                    .open(sequence, aad, &mut decrypted)
+                    .ok_or(RecvError::Decrypt)?
+            },
+            (true, None) => return Err(RecvError::MissingKey),
+            // Anything could be sent in the clear once there's a key,
+            // so don't trust it.
+            (false, Some(_)) => return Err(RecvError::Unencrypted),
+        };
+        let packet: P = bincode::config()
+            .limit(MAX_PACKET_SIZE as u64)
+            .deserialize(payload)
+            .map_err(RecvError::deserialize)?;
+        packet.check_limits()?;
+        let duplicate = self.acks.seen(sequence);
+        let lost = self.apply_header(sequence, acks);
+        Ok((packet, sequence, acks, lost, duplicate))
+    }
+}
+
+/// Reads and checks a packet header, returning the sequence number,
+/// acks and whether the payload is encrypted.
+fn read_header(data: &[u8]) -> Result<(u32, Acks, bool), RecvError> {
+    let mut header = Cursor::new(data);
+    let magic = header.read_u32::<BE>().map_err(RecvError::header_read)?;
+    let encrypted = match magic {
+        PROTOCOL_MAGIC => false,
+        ENCRYPTED_MAGIC => true,
+        _ => return Err(RecvError::WrongMagic(magic)),
+    };
+    let checksum = header.read_u32::<BE>().map_err(RecvError::header_read)?;
+    // Only used to find the connection in the first place.
+    let _id = header.read_u32::<BE>().map_err(RecvError::header_read)?;
+    let sequence = header.read_u32::<BE>().map_err(RecvError::header_read)?;
+    let ack = header.read_u32::<BE>().map_err(RecvError::header_read)?;
+    let ack_bits = header.read_u32::<BE>().map_err(RecvError::header_read)?;
+    if crc32fast::hash(&data[HEADER_BYTES..]) != checksum {
+        return Err(RecvError::BadChecksum);
+    }
+    Ok((
+        sequence,
+        Acks {
+            ack_bits,
+            ack,
+        },
+        encrypted,
+    ))
+}
+
+/// Gets the sequence number of an encoded packet, without checking
+/// anything else.
+pub fn peek_sequence(data: &[u8]) -> Option<u32> {
+    data.get(SEQUENCE_OFFSET..SEQUENCE_OFFSET + 4).map(BE::read_u32)
+}
+
+/// Gets the connection id of an encoded packet without checking
+/// anything else, or `None` if it doesn't have one.
+pub fn peek_connection_id(data: &[u8]) -> Option<u32> {
+    data.get(CONNECTION_ID_OFFSET..CONNECTION_ID_OFFSET + 4)
+        .map(BE::read_u32)
+        .filter(|&id| id != 0)
+}
+
+#[test]
+fn ack_reordered_and_duplicated() {
+    let mut acks = Acks::default();
+    assert_eq!(acks.iter().count(), 0);
+    for &sequence in &[0, 3, 1, 3, 40, 9, 0, 9, 40] {
+        acks.ack(sequence);
     }
+    // 0, 1 and 3 are out of the window by the time 40 arrives.
+    assert_eq!(acks.latest(), 40);
+    assert_eq!(acks.iter().collect::<Vec<_>>(), vec![40, 9]);
+    assert!(acks.contains(9));
+    assert!(!acks.contains(3));
+    assert!(!acks.contains(41));
+
+    assert!(acks.seen(9));
+    assert!(acks.seen(3));
+    assert!(!acks.seen(10));
+
+    let mut first = Acks::default();
+    assert!(!first.seen(0));
+    first.ack(0);
+    assert_eq!(first.iter().collect::<Vec<_>>(), vec![0]);
+}
+
+#[test]
+fn combine_merges_reordered_acks() {
+    let mut received = Acks::default();
+    received.ack(1);
+    received.ack(2);
+    let old = received;
+    received.ack(4);
+    let new = received;
+
+    let mut acks = Acks::default();
+    assert!(acks.combine(new).is_empty());
+    assert!(acks.combine(old).is_empty());
+    assert_eq!(acks, new);
+    // An older set that acks something the newer one missed.
+    let mut late = Acks::default();
+    late.ack(0);
+    assert!(acks.combine(late).is_empty());
+    assert_eq!(acks.iter().collect::<Vec<_>>(), vec![4, 2, 1, 0]);
+}
+
+#[test]
+fn combine_reports_each_loss_once() {
+    // Every third packet is dropped.
+    let dropped = |sequence: u32| sequence % 3 == 1;
+    let mut received = Acks::default();
+    let mut acks = Acks::default();
+    let mut lost = Vec::new();
+    for sequence in 0..200 {
+        if !dropped(sequence) {
+            received.ack(sequence);
+        }
+        // Only some of the acks make it back.
+        if sequence % 7 == 0 {
+            lost.extend(acks.combine(received));
+        }
+    }
+    // Anything dropped before the window of the last acks is lost.
+    let oldest = acks.latest() - (ACK_WINDOW - 1);
+    let expected =
+        (0..oldest).filter(|&sequence| dropped(sequence)).collect::<Vec<_>>();
+    assert_eq!(lost, expected);
+}
+
+#[test]
+fn combine_reports_skipped_packets() {
+    let mut acks = Acks::default();
+    acks.combine(Acks {
+        ack_bits: 0b101,
+        ack: 2,
+    });
+    let lost = acks.combine(Acks {
+        ack_bits: 1,
+        ack: 2 + 2 * ACK_WINDOW,
+    });
+    let expected = (1..=ACK_WINDOW + 2)
+        .filter(|&sequence| sequence != 2)
+        .collect::<Vec<_>>();
+    assert_eq!(lost.into_vec(), expected);
+}
+
+#[test]
+fn combine_ignores_unsent_acks() {
+    let mut connection = Connection::default();
+    connection.local_sequence = 10;
+    let lost = connection.apply_header(
+        0,
+        Acks {
+            ack_bits: 1,
+            ack: 100,
+        },
+    );
+    assert!(lost.is_empty());
+    assert_eq!(connection.remote_acks, Acks::default());
 }
@@ -0,0 +1,234 @@
+use std::fmt;
+
+/// Public half of a key exchange, sent along with handshakes.
+pub type PublicKey = [u8; 32];
+
+/// Which end of a connection something is on.
+///
+/// Both ends share a key, so this keeps their nonces apart.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// Key that packets on a connection are encrypted and authenticated
+/// with, after a key exchange.
+///
+/// Nonces come from the sequence number of each packet, which is
+/// never reused for one side of a connection.
+#[derive(Clone)]
+pub struct Cipher {
+    #[cfg(feature = "encryption")]
+    key: [u8; 32],
+    #[cfg(feature = "encryption")]
+    side: Side,
+    #[cfg(not(feature = "encryption"))]
+    _never: Never,
+}
+
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+enum Never {}
+
+/// One side of an X25519 key exchange.
+///
+/// Both keys are ephemeral and nothing vouches for the server's, so
+/// this only protects against passive eavesdroppers. Someone who can
+/// rewrite the handshakes can swap in keys of their own and sit in the
+/// middle without either side noticing.
+pub struct KeyExchange {
+    #[cfg(feature = "encryption")]
+    private: ring::agreement::EphemeralPrivateKey,
+    public: PublicKey,
+    #[cfg(not(feature = "encryption"))]
+    _never: Never,
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Don't leak the key into logs.
+        f.write_str("Cipher")
+    }
+}
+
+impl KeyExchange {
+    /// Checks whether this was built with encryption support.
+    pub fn supported() -> bool {
+        cfg!(feature = "encryption")
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+}
+
+#[cfg(feature = "encryption")]
+mod imp {
+    use super::{Cipher, KeyExchange, PublicKey, Side};
+    use ring::aead::{self, Aad, Nonce, CHACHA20_POLY1305};
+    use ring::agreement::{self, EphemeralPrivateKey, X25519};
+    use ring::digest::{self, SHA256};
+    use ring::rand::SystemRandom;
+
+    /// Context mixed into the shared secret to derive the key.
+    const KEY_CONTEXT: &[u8] = b"ball-gfx-hal packet key";
+
+    impl Side {
+        fn other(self) -> Side {
+            match self {
+                Side::Client => Side::Server,
+                Side::Server => Side::Client,
+            }
+        }
+    }
+
+    impl KeyExchange {
+        /// Starts a key exchange with a fresh key pair, or returns
+        /// `None` if encryption isn't supported.
+        pub fn new() -> Option<KeyExchange> {
+            let private =
+                EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+                    .ok()?;
+            let mut public = [0; 32];
+            public.copy_from_slice(private.compute_public_key().ok()?.as_ref());
+            Some(KeyExchange {
+                private,
+                public,
+            })
+        }
+
+        /// Finishes the key exchange with the other side's public key.
+        pub fn finish(self, peer: &PublicKey, side: Side) -> Option<Cipher> {
+            agreement::agree_ephemeral(
+                self.private,
+                &X25519,
+                untrusted::Input::from(peer),
+                (),
+                |secret| {
+                    let mut context = secret.to_vec();
+                    context.extend_from_slice(KEY_CONTEXT);
+                    let mut key = [0; 32];
+                    key.copy_from_slice(
+                        digest::digest(&SHA256, &context).as_ref(),
+                    );
+                    Ok(Cipher {
+                        key,
+                        side,
+                    })
+                },
+            )
+            .ok()
+        }
+    }
+
+    impl Cipher {
+        /// Length of the tag added to each payload.
+        pub fn tag_len() -> usize {
+            CHACHA20_POLY1305.tag_len()
+        }
+
+        fn nonce(side: Side, sequence: u32) -> Nonce {
+            let mut nonce = [0; 12];
+            nonce[0] = side as u8;
+            nonce[8..].copy_from_slice(&sequence.to_be_bytes());
+            Nonce::assume_unique_for_key(nonce)
+        }
+
+        /// Encrypts a payload that's about to be sent, appending the
+        /// tag. `aad` is authenticated but not encrypted.
+        pub fn seal(&self, sequence: u32, aad: &[u8], payload: &mut Vec<u8>) {
+            let key = aead::SealingKey::new(&CHACHA20_POLY1305, &self.key)
+                .expect("invalid packet key");
+            let len = payload.len();
+            payload.resize(len + Cipher::tag_len(), 0);
+            aead::seal_in_place(
+                &key,
+                Cipher::nonce(self.side, sequence),
+                Aad::from(aad),
+                payload,
+                Cipher::tag_len(),
+            )
+            .expect("failed to encrypt packet");
+        }
+
+        /// Decrypts and authenticates a received payload in place,
+        /// returning `None` if it was tampered with.
+        pub fn open<'a>(
+            &self,
+            sequence: u32,
+            aad: &[u8],
+            payload: &'a mut [u8],
+        ) -> Option<&'a [u8]> {
+            let key = aead::OpeningKey::new(&CHACHA20_POLY1305, &self.key)
+                .expect("invalid packet key");
+            aead::open_in_place(
+                &key,
+                Cipher::nonce(self.side.other(), sequence),
+                Aad::from(aad),
+                0,
+                payload,
+            )
+            .ok()
+            .map(|plaintext| &*plaintext)
+        }
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+mod imp {
+    use super::{Cipher, KeyExchange, PublicKey, Side};
+
+    impl KeyExchange {
+        /// Always `None`, since this was built without encryption
+        /// support.
+        pub fn new() -> Option<KeyExchange> {
+            None
+        }
+
+        pub fn finish(self, _: &PublicKey, _: Side) -> Option<Cipher> {
+            match self._never {}
+        }
+    }
+
+    impl Cipher {
+        pub fn seal(&self, _: u32, _: &[u8], _: &mut Vec<u8>) {
+            match self._never {}
+        }
+
+        pub fn open<'a>(
+            &self,
+            _: u32,
+            _: &[u8],
+            _: &'a mut [u8],
+        ) -> Option<&'a [u8]> {
+            match self._never {}
+        }
+    }
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_round_trip() {
+    use crate::networking::client::ClientPacket;
+    use crate::networking::connection::Connection;
+
+    let client_exchange = KeyExchange::new().unwrap();
+    let server_exchange = KeyExchange::new().unwrap();
+    let client_key = client_exchange.public_key();
+    let server_key = server_exchange.public_key();
+    let mut client = Connection::default();
+    let mut server = Connection::default();
+    client.cipher = client_exchange.finish(&server_key, Side::Client);
+    server.cipher = server_exchange.finish(&client_key, Side::Server);
+
+    let (mut data, _) = client.encode(&ClientPacket::Pong(7));
+    match server.decode::<ClientPacket>(&data) {
+        Ok((ClientPacket::Pong(7), ..)) => (),
+        _ => panic!("encrypted packet didn't decode"),
+    }
+    // Flipping a bit in the authenticated header should be caught,
+    // even though the checksum doesn't cover it.
+    data[8] ^= 1;
+    assert!(server.decode::<ClientPacket>(&data).is_err());
+}
@@ -1,5 +1,14 @@
+use super::Error;
 use log::error;
 use mio::{Event, Events, Poll};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Duration;
+
+/// How long `run_event_loops` sleeps when none of the handlers had
+/// any events.
+const IDLE_SLEEP: Duration = Duration::from_millis(1);
 
 pub trait EventHandler {
     /// Returns a reference to the handler's `Poll`.
@@ -7,6 +16,52 @@ pub trait EventHandler {
 
     /// Returns `true` to stop the event loop.
     fn handle(&mut self, event: Event) -> bool;
+
+    /// Reports that `handle` panicked, right before the event loop
+    /// stops, so that whoever is waiting on the handler finds out.
+    fn panicked(&mut self, err: Error);
+}
+
+impl<T: EventHandler + ?Sized> EventHandler for Box<T> {
+    fn poll(&self) -> &Poll {
+        (**self).poll()
+    }
+
+    fn handle(&mut self, event: Event) -> bool {
+        (**self).handle(event)
+    }
+
+    fn panicked(&mut self, err: Error) {
+        (**self).panicked(err)
+    }
+}
+
+/// Handles an event, and returns `true` to stop the event loop.
+///
+/// A panic stops the event loop too, after it's been passed on to the
+/// handler as an error.
+fn handle_event<T: EventHandler>(handler: &mut T, event: Event) -> bool {
+    let result =
+        panic::catch_unwind(AssertUnwindSafe(|| handler.handle(event)));
+    match result {
+        Ok(stop) => stop,
+        Err(payload) => {
+            let err = Error::Panicked(panic_message(&*payload));
+            error!("{}", err);
+            handler.panicked(err);
+            true
+        },
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown cause".to_owned()
+    }
 }
 
 pub fn run_event_loop<T: EventHandler>(mut handler: T) {
@@ -19,9 +74,99 @@ pub fn run_event_loop<T: EventHandler>(mut handler: T) {
         }
 
         for event in events.iter() {
-            if handler.handle(event) {
+            if handle_event(&mut handler, event) {
                 break 'event_loop;
             }
         }
     }
 }
+
+/// Runs several handlers on the current thread, until all of them
+/// stop.
+///
+/// Each handler is polled without blocking in turn, sleeping a bit
+/// whenever none of them have events. This is meant for running lots
+/// of dummy clients, not for anything latency sensitive.
+pub fn run_event_loops<T: EventHandler>(mut handlers: Vec<T>) {
+    let mut events = Events::with_capacity(1024);
+    while !handlers.is_empty() {
+        if !poll_event_loops(&mut handlers, &mut events) {
+            thread::sleep(IDLE_SLEEP);
+        }
+    }
+}
+
+/// Polls each handler once without blocking, handling whatever events
+/// they had and dropping the ones that stop.
+///
+/// Returns whether any of them had events, so that tests can step
+/// handlers on their own thread until everything settles.
+pub fn poll_event_loops<T: EventHandler>(
+    handlers: &mut Vec<T>,
+    events: &mut Events,
+) -> bool {
+    let mut busy = false;
+    let mut i = 0;
+    while i < handlers.len() {
+        let timeout = Some(Duration::from_millis(0));
+        let stop = match handlers[i].poll().poll(events, timeout) {
+            Ok(_) => {
+                busy |= !events.is_empty();
+                let handler = &mut handlers[i];
+                events.iter().any(|event| handle_event(handler, event))
+            },
+            Err(err) => {
+                error!("error when polling event loop: {}", err);
+                true
+            },
+        };
+        if stop {
+            handlers.swap_remove(i);
+        } else {
+            i += 1;
+        }
+    }
+    busy
+}
+
+#[test]
+fn panics_are_reported() {
+    use crossbeam::channel::{self, Sender};
+    use mio::{PollOpt, Ready, Registration, Token};
+
+    struct Panicking {
+        poll: Poll,
+        _registration: Registration,
+        errors: Sender<Error>,
+    }
+
+    impl EventHandler for Panicking {
+        fn poll(&self) -> &Poll {
+            &self.poll
+        }
+
+        fn handle(&mut self, _: Event) -> bool {
+            panic!("oops")
+        }
+
+        fn panicked(&mut self, err: Error) {
+            self.errors.send(err).unwrap();
+        }
+    }
+
+    let poll = Poll::new().unwrap();
+    let (registration, set_readiness) = Registration::new2();
+    poll.register(&registration, Token(0), Ready::readable(), PollOpt::edge())
+        .unwrap();
+    set_readiness.set_readiness(Ready::readable()).unwrap();
+    let (errors_tx, errors_rx) = channel::unbounded();
+    run_event_loop(Panicking {
+        poll,
+        _registration: registration,
+        errors: errors_tx,
+    });
+    match errors_rx.try_recv() {
+        Ok(Error::Panicked(message)) => assert_eq!(message, "oops"),
+        other => panic!("expected a panic error, got {:?}", other),
+    }
+}
@@ -0,0 +1,181 @@
+use crate::game::{
+    quantize::{Quantization, QuantizedSnapshot, DEFAULT_VELOCITY_RANGE},
+    server::Game,
+    Event,
+};
+use crate::networking::client::ClientPacket;
+use crate::networking::connection::Connection;
+use crate::networking::server::ServerPacket;
+use crate::networking::{
+    CheckLimits,
+    RecvError,
+    MAX_NAME_BYTES,
+    MAX_PACKET_SIZE,
+};
+use byteorder::{WriteBytesExt, LE};
+use nalgebra::Point2;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Number of inputs each fuzz test tries.
+const FUZZ_ITERATIONS: usize = 10_000;
+
+/// Decodes untrusted data as a packet the server would receive.
+///
+/// This should return an error rather than panicking or allocating a
+/// huge amount of memory, whatever the input.
+pub fn decode_client_packet(data: &[u8]) {
+    decode::<ClientPacket>(data);
+}
+
+/// Decodes untrusted data as a packet the client would receive, like
+/// `decode_client_packet`.
+pub fn decode_server_packet(data: &[u8]) {
+    decode::<ServerPacket>(data);
+}
+
+fn decode<P: DeserializeOwned + CheckLimits>(data: &[u8]) {
+    let mut connection = Connection::default();
+    let _ = connection.decode::<P>(data);
+}
+
+/// Puts a valid header in front of a payload, so that it gets past
+/// the checksum.
+fn seal(payload: &[u8]) -> Vec<u8> {
+    Connection::default().encode_payload(payload).0
+}
+
+fn encode<P: Serialize>(packet: &P) -> Vec<u8> {
+    bincode::serialize(packet).unwrap()
+}
+
+/// Valid payloads of each kind, for mutating.
+fn sample_packets() -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let client = vec![
+        encode(&ClientPacket::Handshake {
+            cursor: Point2::new(0.1, -0.2),
+            name: "fuzz".to_owned(),
+            hue: Some(0.5),
+            key: Some([7; 32]),
+            spectate: false,
+        }),
+        encode(&ClientPacket::Query),
+        encode(&ClientPacket::Pong(3)),
+    ];
+
+    let mut game = Game::default();
+    let mut events = Vec::new();
+    for i in 0..4 {
+        let name = format!("player {}", i);
+        events.extend(game.add_player(Point2::origin(), &name, None).1);
+    }
+    let quantization =
+        Quantization::new(&game.settings, DEFAULT_VELOCITY_RANGE);
+    events.push(Event::Snapshot(QuantizedSnapshot::new(
+        &game.snapshot(),
+        quantization,
+    )));
+    let mut server = events
+        .iter()
+        .map(|event| encode(&ServerPacket::Event(event.clone())))
+        .collect::<Vec<_>>();
+    server.push(encode(&ServerPacket::Events(events)));
+    server.push(encode(&ServerPacket::Ping));
+    (client, server)
+}
+
+/// Either random bytes, or a valid payload with a few bytes changed
+/// and a valid header.
+fn fuzz_input<R: Rng>(rng: &mut R, samples: &[Vec<u8>]) -> Vec<u8> {
+    if rng.gen() {
+        let len = rng.gen_range(0, MAX_PACKET_SIZE);
+        (0..len).map(|_| rng.gen()).collect()
+    } else {
+        let mut data = samples[rng.gen_range(0, samples.len())].clone();
+        for _ in 0..rng.gen_range(1, 4) {
+            let i = rng.gen_range(0, data.len());
+            data[i] = rng.gen();
+        }
+        let len = rng.gen_range(0, data.len() + 1);
+        data.truncate(len);
+        seal(&data)
+    }
+}
+
+#[test]
+fn fuzz_packet_decoding() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let (client, server) = sample_packets();
+    for _ in 0..FUZZ_ITERATIONS {
+        decode_client_packet(&fuzz_input(&mut rng, &client));
+        decode_server_packet(&fuzz_input(&mut rng, &server));
+    }
+}
+
+#[test]
+fn hostile_length_prefix() {
+    // A handshake whose name claims to be enormous.
+    let mut payload = Vec::new();
+    payload.write_u32::<LE>(0).unwrap();
+    payload.write_f32::<LE>(0.0).unwrap();
+    payload.write_f32::<LE>(0.0).unwrap();
+    payload.write_u64::<LE>(u64::max_value()).unwrap();
+    let mut sender = Connection::default();
+    sender.local_sequence = 5;
+    let (data, _) = sender.encode_payload(&payload);
+    let mut connection = Connection::default();
+    assert!(connection.decode::<ClientPacket>(&data).is_err());
+    // Nothing should be acked from a packet that didn't decode.
+    assert_eq!(connection.acks.latest(), 0);
+}
+
+#[test]
+fn oversized_name_rejected() {
+    let (data, _) = Connection::default().encode(&ClientPacket::Handshake {
+        cursor: Point2::origin(),
+        name: "a".repeat(MAX_NAME_BYTES + 1),
+        hue: None,
+        key: None,
+        spectate: false,
+    });
+    let mut connection = Connection::default();
+    match connection.decode::<ClientPacket>(&data) {
+        Err(RecvError::NameTooLong(_)) => (),
+        Err(err) => panic!("wrong error: {}", err),
+        Ok(_) => panic!("oversized name was accepted"),
+    }
+}
+
+#[test]
+fn stray_traffic_rejected() {
+    let (mut data, _) = Connection::default().encode(&ClientPacket::Query);
+    let mut connection = Connection::default();
+    data[0] ^= 1;
+    match connection.decode::<ClientPacket>(&data) {
+        Err(RecvError::WrongMagic(_)) => (),
+        _ => panic!("wrong magic was accepted"),
+    }
+    data[0] ^= 1;
+    *data.last_mut().unwrap() ^= 1;
+    match connection.decode::<ClientPacket>(&data) {
+        Err(RecvError::BadChecksum) => (),
+        _ => panic!("bad checksum was accepted"),
+    }
+}
+
+#[test]
+fn duplicate_packets_flagged() {
+    let mut sender = Connection::default();
+    let (first, _) = sender.encode(&ClientPacket::Query);
+    let (second, _) = sender.encode(&ClientPacket::Query);
+    let mut connection = Connection::default();
+    let mut duplicate =
+        |data: &[u8]| connection.decode::<ClientPacket>(data).unwrap().4;
+    // Reordered, then delivered again.
+    assert!(!duplicate(&second));
+    assert!(!duplicate(&first));
+    assert!(duplicate(&first));
+    assert!(duplicate(&second));
+}
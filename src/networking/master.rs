@@ -0,0 +1,705 @@
+use crate::networking::query::QueryLimiter;
+use crate::networking::{
+    check_name,
+    CheckLimits,
+    Error,
+    RecvError,
+    MAX_PACKET_SIZE,
+};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BE};
+use crossbeam::channel::{self, Receiver};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, Cursor};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Marks packets of the master server protocol, so stray game traffic
+/// gets ignored.
+pub const MASTER_MAGIC: u32 = 0xBA11_4D02;
+
+/// Rate at which a listed server sends heartbeats.
+pub const HEARTBEAT_RATE: Duration = Duration::from_secs(10);
+
+/// Listings are dropped after this long without a heartbeat.
+const LISTING_TIMEOUT: Duration = Duration::from_secs(35);
+
+/// How long to wait for the master server to send the whole list.
+const LIST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Most games sent in one datagram. Even with the longest names this
+/// stays well under `MAX_PACKET_SIZE`.
+const GAMES_PER_PAGE: usize = 16;
+
+/// Most games the master server lists at once, so it can't be made to
+/// use unbounded memory.
+const MAX_LISTINGS: usize = 4096;
+
+/// Most pages sent in answer to one `List`. A request is tiny next to
+/// the answer, so this bounds how much traffic a spoofed request can
+/// reflect onto someone else.
+const MAX_LIST_PAGES: usize = 8;
+
+/// Most relays the master server runs at once. Each one is a thread
+/// and a socket.
+const MAX_RELAYS: usize = 256;
+
+/// Most relays that clients from one IP address can have at once.
+const MAX_RELAYS_PER_IP: usize = 4;
+
+/// How long a cookie proves its holder's address for. Cookies from the
+/// previous period are still accepted, so one is good for at least
+/// this long.
+const COOKIE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Relays shut down after this long without forwarding anything.
+const RELAY_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MasterPacket {
+    /// Lists a game, or keeps its listing alive. Sent from the game's
+    /// own socket, so the master server sees the address players
+    /// should connect to.
+    Heartbeat {
+        name: String,
+        players: u16,
+        max_players: Option<u16>,
+    },
+    /// Takes a game off the list right away, when it shuts down.
+    Unregister,
+    /// Asks for every listed game.
+    List,
+    /// One page of the answer to `List`.
+    Games {
+        page: u16,
+        pages: u16,
+        games: Vec<GameListing>,
+    },
+    /// Asks to be introduced to a listed game, which might be behind
+    /// NAT. Sent from the socket the client connects with, so the
+    /// master server sees the address the game should punch toward.
+    ///
+    /// Without a valid cookie this is only answered with a `Cookie`.
+    Introduce {
+        game: SocketAddr,
+        cookie: Option<u64>,
+    },
+    /// Proves that whoever asks with it got this from the master
+    /// server, and so really is at the address it was sent to.
+    Cookie {
+        cookie: u64,
+    },
+    /// Tells a listed game that a peer is about to connect, so it
+    /// sends a `Punch` to open its NAT to them.
+    Introduction {
+        peer: SocketAddr,
+    },
+    /// Sent straight to a peer to open a NAT mapping toward it, and
+    /// otherwise ignored.
+    Punch,
+    /// Asks for a relay to a listed game, once punching through
+    /// hasn't worked. Needs a cookie just like `Introduce`.
+    Relay {
+        game: SocketAddr,
+        cookie: Option<u64>,
+    },
+    /// Port on the master server's address that forwards to the game
+    /// in both directions, in reply to `Relay`.
+    Relayed {
+        port: u16,
+    },
+    /// Tells a listed game that a relay on this port of the master
+    /// server's address is going to forward a peer's packets, so it
+    /// punches toward that instead.
+    Relaying {
+        port: u16,
+    },
+}
+
+/// A game as listed by the master server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GameListing {
+    pub addr: SocketAddr,
+    pub name: String,
+    pub players: u16,
+    pub max_players: Option<u16>,
+}
+
+/// Where and as what a server lists itself, through
+/// `ServerConfig::announce`.
+#[derive(Clone, Debug)]
+pub struct Announcement {
+    /// Address of the master server.
+    pub master: SocketAddr,
+    /// Name shown in the list of games.
+    pub name: String,
+}
+
+impl CheckLimits for MasterPacket {
+    fn check_limits(&self) -> Result<(), RecvError> {
+        match self {
+            MasterPacket::Heartbeat {
+                name,
+                ..
+            } => check_name(name),
+            MasterPacket::Games {
+                games,
+                ..
+            } => {
+                for game in games {
+                    check_name(&game.name)?;
+                }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Serializes a packet with `MASTER_MAGIC` in front.
+///
+/// There's no connection to speak of, so unlike game packets there's
+/// no header beyond that.
+pub fn encode(packet: &MasterPacket) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.write_u32::<BE>(MASTER_MAGIC).unwrap();
+    // Serialization errors are always bugs.
+    bincode::serialize_into(&mut data, packet).unwrap();
+    data
+}
+
+/// Checks whether a datagram is part of the master server protocol,
+/// rather than the game protocol.
+pub fn is_master_packet(data: &[u8]) -> bool {
+    data.len() >= 4 && BE::read_u32(&data[..4]) == MASTER_MAGIC
+}
+
+/// Decodes an untrusted packet, with the same limits as game packets.
+pub fn decode(data: &[u8]) -> Result<MasterPacket, RecvError> {
+    let magic =
+        Cursor::new(data).read_u32::<BE>().map_err(RecvError::header_read)?;
+    if magic != MASTER_MAGIC {
+        return Err(RecvError::WrongMagic(magic));
+    }
+    let packet: MasterPacket = bincode::config()
+        .limit(MAX_PACKET_SIZE as u64)
+        .deserialize(&data[4..])
+        .map_err(RecvError::deserialize)?;
+    packet.check_limits()?;
+    Ok(packet)
+}
+
+/// Asks a master server for its list of games on a background thread,
+/// like `query::query`.
+///
+/// The result is sent once on the returned channel, or `None` if the
+/// master server didn't answer in time.
+pub fn list(master: SocketAddr) -> Receiver<Option<Vec<GameListing>>> {
+    let (tx, rx) = channel::bounded(1);
+    thread::spawn(move || {
+        let games = list_blocking(master);
+        if games.is_none() {
+            debug!("no answer to list request from {}", master);
+        }
+        let _ = tx.send(games);
+    });
+    rx
+}
+
+/// Asks for the list of games, waiting for every page until
+/// `LIST_TIMEOUT`. Pages that got lost are just left out.
+pub fn list_blocking(master: SocketAddr) -> Option<Vec<GameListing>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .map_err(|err| warn!("failed to bind master socket: {}", err))
+        .ok()?;
+    let start = Instant::now();
+    socket.send_to(&encode(&MasterPacket::List), master).ok()?;
+
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    let mut received = BTreeMap::new();
+    let mut expected = None;
+    while expected.map_or(true, |pages| received.len() < pages as usize) {
+        let elapsed = start.elapsed();
+        if elapsed >= LIST_TIMEOUT {
+            break;
+        }
+        socket.set_read_timeout(Some(LIST_TIMEOUT - elapsed)).ok()?;
+        let (bytes_read, from) = match socket.recv_from(&mut buffer) {
+            Ok(result) => result,
+            Err(_) => break,
+        };
+        if from != master {
+            continue;
+        }
+        if let Ok(MasterPacket::Games {
+            page,
+            pages,
+            games,
+        }) = decode(&buffer[..bytes_read])
+        {
+            if page < pages {
+                received.insert(page, games);
+                expected = Some(pages);
+            }
+        }
+    }
+
+    if received.is_empty() {
+        None
+    } else {
+        Some(received.into_iter().flat_map(|(_, games)| games).collect())
+    }
+}
+
+/// A game on the master server's list.
+struct Listing {
+    name: String,
+    players: u16,
+    max_players: Option<u16>,
+    last_heartbeat: Instant,
+}
+
+/// A running relay between a client and a listed game.
+struct Relay {
+    game: SocketAddr,
+    port: u16,
+    /// Set by the relay's thread once it's shut down.
+    done: Arc<AtomicBool>,
+}
+
+struct Master {
+    socket: UdpSocket,
+    listings: HashMap<SocketAddr, Listing>,
+    /// Limits how often each address gets the list, since the answer
+    /// is sent without checking that the address is real.
+    list_limiter: QueryLimiter,
+    /// Secret keys for making cookies.
+    cookie_keys: RandomState,
+    started: Instant,
+    /// Relays by the address of the client that asked for them.
+    relays: HashMap<SocketAddr, Relay>,
+}
+
+/// Runs a master server on a new thread, which keeps a list of games
+/// that send it heartbeats and sends the list to anyone who asks.
+///
+/// It's also the rendezvous for games behind NAT. Clients get
+/// introduced so both ends can punch through, and if that doesn't
+/// work they get a relay that forwards their packets.
+pub fn serve(addr: SocketAddr) -> Result<(SocketAddr, JoinHandle<()>), Error> {
+    let bind_error = |err| {
+        Error::BindSocket {
+            addr,
+            err,
+        }
+    };
+    let socket = UdpSocket::bind(addr).map_err(bind_error)?;
+    let addr = socket.local_addr().map_err(bind_error)?;
+    info!("master server listening on {}", addr);
+
+    let thread = thread::spawn(move || {
+        let mut master = Master {
+            socket,
+            listings: HashMap::new(),
+            list_limiter: QueryLimiter::default(),
+            cookie_keys: RandomState::new(),
+            started: Instant::now(),
+            relays: HashMap::new(),
+        };
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        loop {
+            let (bytes_read, from) = match master.socket.recv_from(&mut buffer)
+            {
+                Ok(result) => result,
+                Err(err) => {
+                    warn!("error receiving packet on master server: {}", err);
+                    continue;
+                },
+            };
+            master.listings.retain(|_, listing| {
+                listing.last_heartbeat.elapsed() < LISTING_TIMEOUT
+            });
+            master
+                .relays
+                .retain(|_, relay| !relay.done.load(Ordering::Relaxed));
+            match decode(&buffer[..bytes_read]) {
+                Ok(packet) => master.on_recv(from, packet),
+                Err(err) => {
+                    debug!(
+                        "bad packet for master server from {}: {}",
+                        from, err
+                    )
+                },
+            }
+        }
+    });
+    Ok((addr, thread))
+}
+
+impl Master {
+    fn on_recv(&mut self, from: SocketAddr, packet: MasterPacket) {
+        match packet {
+            MasterPacket::Heartbeat {
+                name,
+                players,
+                max_players,
+            } => {
+                if !self.listings.contains_key(&from) {
+                    if self.listings.len() >= MAX_LISTINGS {
+                        warn!("not listing {}, the list is full", from);
+                        return;
+                    }
+                    info!("listing {:?} on {}", name, from);
+                }
+                self.listings.insert(
+                    from,
+                    Listing {
+                        name,
+                        players,
+                        max_players,
+                        last_heartbeat: Instant::now(),
+                    },
+                );
+            },
+            MasterPacket::Unregister => {
+                if self.listings.remove(&from).is_some() {
+                    info!("unlisted {}", from);
+                }
+            },
+            MasterPacket::List => {
+                if self.list_limiter.allow(from.ip(), Instant::now()) {
+                    self.send_list(from);
+                } else {
+                    debug!("ignoring list request from {}", from);
+                }
+            },
+            MasterPacket::Introduce {
+                game,
+                cookie,
+            } => {
+                if !self.check_cookie(from, cookie) {
+                    return;
+                }
+                // Only listed games have told us where they are, and
+                // this keeps the master server from being used to send
+                // packets anywhere.
+                if self.listings.contains_key(&game) {
+                    debug!("introducing {} to {}", from, game);
+                    self.send(
+                        game,
+                        &MasterPacket::Introduction {
+                            peer: from,
+                        },
+                    );
+                }
+            },
+            MasterPacket::Relay {
+                game,
+                cookie,
+            } => {
+                if !self.check_cookie(from, cookie) {
+                    return;
+                }
+                if self.listings.contains_key(&game) {
+                    self.relay(from, game);
+                }
+            },
+            _ => {
+                debug!(
+                    "ignoring {:?} sent to master server by {}",
+                    packet, from
+                )
+            },
+        }
+    }
+
+    /// Makes the cookie for an address, for the `COOKIE_PERIOD` that
+    /// `period` counts.
+    fn cookie(&self, addr: SocketAddr, period: u64) -> u64 {
+        let mut hasher = self.cookie_keys.build_hasher();
+        (addr, period).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Checks that a request came with a cookie for the address it
+    /// came from, so the address can't be spoofed to introduce or
+    /// relay someone who never asked. If it didn't, one is sent back
+    /// instead, which is no bigger than the request.
+    fn check_cookie(&self, from: SocketAddr, cookie: Option<u64>) -> bool {
+        let period = self.started.elapsed().as_secs() / COOKIE_PERIOD.as_secs();
+        let valid = cookie.map_or(false, |cookie| {
+            cookie == self.cookie(from, period) ||
+                (period > 0 && cookie == self.cookie(from, period - 1))
+        });
+        if !valid {
+            self.send(
+                from,
+                &MasterPacket::Cookie {
+                    cookie: self.cookie(from, period),
+                },
+            );
+        }
+        valid
+    }
+
+    /// Sends the list of games, leaving out any past `MAX_LIST_PAGES`.
+    fn send_list(&self, to: SocketAddr) {
+        let games = self
+            .listings
+            .iter()
+            .take(MAX_LIST_PAGES * GAMES_PER_PAGE)
+            .map(|(&addr, listing)| {
+                GameListing {
+                    addr,
+                    name: listing.name.clone(),
+                    players: listing.players,
+                    max_players: listing.max_players,
+                }
+            })
+            .collect::<Vec<_>>();
+        // Always send at least one page, so an empty list still gets
+        // an answer.
+        let pages =
+            ((games.len() + GAMES_PER_PAGE - 1) / GAMES_PER_PAGE).max(1) as u16;
+        for page in 0..pages {
+            let start = page as usize * GAMES_PER_PAGE;
+            let end = (start + GAMES_PER_PAGE).min(games.len());
+            self.send(
+                to,
+                &MasterPacket::Games {
+                    page,
+                    pages,
+                    games: games[start..end].to_vec(),
+                },
+            );
+        }
+    }
+
+    /// Starts relaying between a client and a game, or reuses the
+    /// client's relay if it already has one, and tells both ends about
+    /// it.
+    fn relay(&mut self, client: SocketAddr, game: SocketAddr) {
+        let from_ip =
+            self.relays.keys().filter(|addr| addr.ip() == client.ip()).count();
+        let port = match self.relays.get(&client) {
+            Some(relay) if relay.game == game => relay.port,
+            _ if self.relays.len() >= MAX_RELAYS => {
+                warn!("not relaying {} to {}, too many relays", client, game);
+                return;
+            },
+            _ if from_ip >= MAX_RELAYS_PER_IP => {
+                warn!(
+                    "not relaying {} to {}, too many relays from {}",
+                    client,
+                    game,
+                    client.ip()
+                );
+                return;
+            },
+            _ => {
+                match spawn_relay(&self.socket, client, game) {
+                    Ok(relay) => {
+                        info!(
+                            "relaying {} to {} on port {}",
+                            client, game, relay.port
+                        );
+                        let port = relay.port;
+                        self.relays.insert(client, relay);
+                        port
+                    },
+                    Err(err) => {
+                        warn!("failed to start relay for {}: {}", client, err);
+                        return;
+                    },
+                }
+            },
+        };
+        self.send(
+            game,
+            &MasterPacket::Relaying {
+                port,
+            },
+        );
+        self.send(
+            client,
+            &MasterPacket::Relayed {
+                port,
+            },
+        );
+    }
+
+    fn send(&self, to: SocketAddr, packet: &MasterPacket) {
+        if let Err(err) = self.socket.send_to(&encode(packet), to) {
+            warn!("failed to send master packet to {}: {}", to, err);
+        }
+    }
+}
+
+/// Forwards datagrams between a client and a game on a new thread,
+/// from a new socket on the same address as the master server.
+///
+/// The client's port can be different from the one it asked for the
+/// relay from, so it's learned from the first packet that comes in
+/// from the client's IP.
+fn spawn_relay(
+    master: &UdpSocket,
+    client: SocketAddr,
+    game: SocketAddr,
+) -> io::Result<Relay> {
+    let socket =
+        UdpSocket::bind(SocketAddr::new(master.local_addr()?.ip(), 0))?;
+    socket.set_read_timeout(Some(RELAY_IDLE_TIMEOUT))?;
+    let port = socket.local_addr()?.port();
+    let done = Arc::new(AtomicBool::new(false));
+    let relay_done = Arc::clone(&done);
+    thread::spawn(move || {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let mut client_addr = None;
+        let mut last_forwarded = Instant::now();
+        while last_forwarded.elapsed() < RELAY_IDLE_TIMEOUT {
+            let (bytes_read, from) = match socket.recv_from(&mut buffer) {
+                Ok(result) => result,
+                Err(_) => break,
+            };
+            let to = if from == game {
+                client_addr
+            } else if from.ip() == client.ip() {
+                client_addr = Some(from);
+                Some(game)
+            } else {
+                None
+            };
+            if let Some(to) = to {
+                if let Err(err) = socket.send_to(&buffer[..bytes_read], to) {
+                    warn!("relay failed to forward to {}: {}", to, err);
+                }
+                last_forwarded = Instant::now();
+            }
+        }
+        info!("stopped relaying {} to {}", client, game);
+        relay_done.store(true, Ordering::Relaxed);
+    });
+    Ok(Relay {
+        game,
+        port,
+        done,
+    })
+}
+
+#[test]
+fn announced_server_is_listed() {
+    use crate::networking::server::{self, ServerConfig};
+
+    let (master, _) = serve("127.0.0.1:0".parse().unwrap()).unwrap();
+    let config = ServerConfig {
+        announce: Some(Announcement {
+            master,
+            name: "test game".to_owned(),
+        }),
+        max_players: Some(8),
+        ..ServerConfig::default()
+    };
+    let (server, thread) =
+        server::host("127.0.0.1:0".parse().unwrap(), config).unwrap();
+
+    let listed = |name: &str| {
+        (0..50).any(|_| {
+            let games = list_blocking(master).unwrap_or_default();
+            let found = games.iter().any(|game| {
+                game.name == name &&
+                    game.players == 0 &&
+                    game.max_players == Some(8)
+            });
+            if !found {
+                thread::sleep(Duration::from_millis(20));
+            }
+            found
+        })
+    };
+    assert!(listed("test game"));
+
+    server.shutdown();
+    thread.join().unwrap();
+    // Asking too often gets ignored for a bit, rather than answered.
+    let empty = (0..5)
+        .any(|_| list_blocking(master).map_or(false, |games| games.is_empty()));
+    assert!(empty);
+}
+
+#[test]
+fn relay_forwards_to_game() {
+    use crate::networking::client;
+    use crate::networking::server::{self, ServerConfig};
+
+    let (master, _) = serve("127.0.0.1:0".parse().unwrap()).unwrap();
+    let config = ServerConfig {
+        announce: Some(Announcement {
+            master,
+            name: "relayed game".to_owned(),
+        }),
+        ..ServerConfig::default()
+    };
+    let (server, _) =
+        server::host("127.0.0.1:0".parse().unwrap(), config).unwrap();
+    let game = (0..50)
+        .filter_map(|_| {
+            let game = list_blocking(master).unwrap_or_default().pop();
+            if game.is_none() {
+                thread::sleep(Duration::from_millis(20));
+            }
+            game
+        })
+        .next()
+        .unwrap()
+        .addr;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(LIST_TIMEOUT)).unwrap();
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    // The first request only gets a cookie back.
+    let request = MasterPacket::Relay {
+        game,
+        cookie: None,
+    };
+    socket.send_to(&encode(&request), master).unwrap();
+    let (bytes_read, _) = socket.recv_from(&mut buffer).unwrap();
+    let cookie = match decode(&buffer[..bytes_read]).unwrap() {
+        MasterPacket::Cookie {
+            cookie,
+        } => Some(cookie),
+        packet => panic!("expected a cookie, got {:?}", packet),
+    };
+    let request = MasterPacket::Relay {
+        game,
+        cookie,
+    };
+    socket.send_to(&encode(&request), master).unwrap();
+    let (bytes_read, _) = socket.recv_from(&mut buffer).unwrap();
+    let port = match decode(&buffer[..bytes_read]).unwrap() {
+        MasterPacket::Relayed {
+            port,
+        } => port,
+        packet => panic!("expected a relay, got {:?}", packet),
+    };
+
+    // The server only sees the relay, which it treats like any client.
+    let relay = SocketAddr::new(master.ip(), port);
+    let (_client, connecting, _) = client::connect(
+        relay,
+        None,
+        nalgebra::Point2::origin(),
+        "relayed".to_owned(),
+        None,
+    )
+    .unwrap();
+    let joined = connecting.recv_timeout(Duration::from_secs(5)).unwrap();
+    let (game, _) = joined.unwrap();
+    assert_eq!(game.players.len(), 1);
+    server.shutdown();
+}
@@ -0,0 +1,207 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(feature = "metrics")]
+pub use self::http::serve;
+
+/// Running totals and latest values from a server's event loop, for
+/// graphing how healthy it is.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    pub players: AtomicUsize,
+    pub packets_sent: AtomicU64,
+    pub packets_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    /// Serialized size of the latest snapshot, without any headers.
+    pub snapshot_bytes: AtomicUsize,
+    /// How long the latest game tick took, in microseconds.
+    pub tick_micros: AtomicU64,
+    /// Number of game ticks run.
+    pub ticks: AtomicU64,
+    /// How long building and encoding the latest round of snapshots
+    /// took, in microseconds.
+    pub snapshot_micros: AtomicU64,
+    /// Number of rounds of snapshots sent.
+    pub snapshots: AtomicU64,
+    /// Number of rounds started.
+    pub rounds: AtomicU64,
+    /// Whether ticks are going over budget, so snapshots are being
+    /// sent less often.
+    pub overloaded: AtomicBool,
+}
+
+impl ServerMetrics {
+    /// Counts a sent datagram.
+    pub fn sent(&self, bytes: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a received datagram.
+    pub fn received(&self, bytes: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Formats every metric in the Prometheus text format.
+    ///
+    /// Packets and bytes are running totals, so rates come from the
+    /// scraper rather than being averaged over some window here.
+    pub fn render(&self) -> String {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed) as f64;
+        let metrics = [
+            (
+                "players",
+                "gauge",
+                "Connected players.",
+                self.players.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "packets_sent_total",
+                "counter",
+                "Datagrams sent.",
+                load(&self.packets_sent),
+            ),
+            (
+                "packets_received_total",
+                "counter",
+                "Datagrams received.",
+                load(&self.packets_received),
+            ),
+            (
+                "bytes_sent_total",
+                "counter",
+                "Bytes sent, including headers.",
+                load(&self.bytes_sent),
+            ),
+            (
+                "bytes_received_total",
+                "counter",
+                "Bytes received, including headers.",
+                load(&self.bytes_received),
+            ),
+            (
+                "snapshot_bytes",
+                "gauge",
+                "Serialized size of the latest snapshot.",
+                self.snapshot_bytes.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "tick_duration_seconds",
+                "gauge",
+                "How long the latest game tick took.",
+                load(&self.tick_micros) / 1_000_000.0,
+            ),
+            ("ticks_total", "counter", "Game ticks run.", load(&self.ticks)),
+            (
+                "snapshot_duration_seconds",
+                "gauge",
+                "How long the latest round of snapshots took to encode.",
+                load(&self.snapshot_micros) / 1_000_000.0,
+            ),
+            (
+                "snapshots_total",
+                "counter",
+                "Rounds of snapshots sent.",
+                load(&self.snapshots),
+            ),
+            ("rounds_total", "counter", "Rounds started.", load(&self.rounds)),
+            (
+                "overloaded",
+                "gauge",
+                "Whether the snapshot rate is reduced to keep up.",
+                self.overloaded.load(Ordering::Relaxed) as u8 as f64,
+            ),
+        ];
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics.iter() {
+            writeln!(output, "# HELP ball_server_{} {}", name, help).unwrap();
+            writeln!(output, "# TYPE ball_server_{} {}", name, kind).unwrap();
+            writeln!(output, "ball_server_{} {}", name, value).unwrap();
+        }
+        output
+    }
+}
+
+#[cfg(feature = "metrics")]
+mod http {
+    use super::ServerMetrics;
+    use crate::networking::Error;
+    use log::{info, warn};
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    /// Longest request that gets read before answering.
+    const MAX_REQUEST_BYTES: u64 = 8192;
+
+    /// How long to wait for a request to come in.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Serves metrics over HTTP on a new thread, so they can be scraped
+    /// by Prometheus.
+    ///
+    /// Every request gets the metrics back, whatever its path.
+    pub fn serve(
+        metrics: Arc<ServerMetrics>,
+        addr: SocketAddr,
+    ) -> Result<(SocketAddr, JoinHandle<()>), Error> {
+        let bind_error = |err| {
+            Error::BindSocket {
+                addr,
+                err,
+            }
+        };
+        let listener = TcpListener::bind(addr).map_err(bind_error)?;
+        let addr = listener.local_addr().map_err(bind_error)?;
+        info!("serving metrics on http://{}/metrics", addr);
+
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result =
+                    stream.and_then(|stream| respond(&metrics, stream));
+                if let Err(err) = result {
+                    warn!("failed to serve metrics: {}", err);
+                }
+            }
+        });
+        Ok((addr, thread))
+    }
+
+    fn respond(metrics: &ServerMetrics, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        // Nothing in the request matters, but it should be read up to the
+        // blank line before answering.
+        let mut reader = BufReader::new((&stream).take(MAX_REQUEST_BYTES));
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+
+        let body = metrics.render();
+        let mut writer = &stream;
+        write!(writer, "HTTP/1.1 200 OK\r\n")?;
+        write!(writer, "Content-Type: text/plain; version=0.0.4\r\n")?;
+        write!(writer, "Content-Length: {}\r\n", body.len())?;
+        write!(writer, "Connection: close\r\n\r\n")?;
+        writer.write_all(body.as_bytes())
+    }
+}
+
+#[test]
+fn render_metrics() {
+    let metrics = ServerMetrics::default();
+    metrics.sent(100);
+    metrics.sent(20);
+    metrics.tick_micros.store(1500, Ordering::Relaxed);
+    let output = metrics.render();
+    assert!(output.contains("\nball_server_packets_sent_total 2\n"));
+    assert!(output.contains("\nball_server_bytes_sent_total 120\n"));
+    assert!(output.contains("\nball_server_tick_duration_seconds 0.0015\n"));
+}
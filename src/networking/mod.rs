@@ -1,28 +1,98 @@
+use crate::game::{
+    PlayerId,
+    StaticPlayerState,
+    MAX_MESSAGE_LENGTH,
+    MAX_NAME_LENGTH,
+};
+use crate::networking::persist::SavedState;
 use bincode;
 use failure::{Backtrace, Fail};
+use mio::net::UdpSocket;
+use smallvec::SmallVec;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::io;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
+pub mod admin;
 pub mod client;
+pub mod clock;
 pub mod connection;
+pub mod crypto;
 pub mod event_loop;
+#[cfg(test)]
+pub mod fuzz;
+pub mod master;
+pub mod metrics;
+pub mod packet_log;
+pub mod peer;
+pub mod persist;
+pub mod query;
+pub mod relay;
+pub mod reliable;
 pub mod server;
+pub mod server_settings;
+#[cfg(test)]
+pub mod testing;
 pub mod tick;
+pub mod transport;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+/// Version of the game, which servers report in answers to queries.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// MTU will probably never be bigger than this, so if a received
 /// packet is bigger, there are probably other problems.
 pub const MAX_PACKET_SIZE: usize = 4096;
 
+/// Most players that a received packet is allowed to list.
+pub const MAX_PACKET_PLAYERS: usize = 256;
+
+/// Longest name in bytes that a received packet is allowed to have.
+/// Names are at most `MAX_NAME_LENGTH` characters, and a character
+/// takes at most 4 bytes.
+pub const MAX_NAME_BYTES: usize = MAX_NAME_LENGTH * 4;
+
+/// Longest server message in bytes that a received packet is allowed
+/// to have, like `MAX_NAME_BYTES`.
+pub const MAX_MESSAGE_BYTES: usize = MAX_MESSAGE_LENGTH * 4;
+
 /// Rate at which both the client and the server send out pings.
 pub const PING_RATE: Duration = Duration::from_millis(500);
 
+/// Rate at which pings are still sent while other packets are already
+/// carrying acks, just to keep measuring RTT.
+pub const BUSY_PING_RATE: Duration = Duration::from_secs(1);
+
 /// Rate at which the server sends snapshots.
 pub const SNAPSHOT_RATE: Duration = Duration::from_millis(30);
 
+/// Player id sent in the handshake to spectators, which no real
+/// player ever gets.
+pub const SPECTATOR_ID: PlayerId = PlayerId::max_value();
+
 /// Seconds to wait before marking a connection as timed out.
 pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// Number of recent RTT samples kept for jitter and percentiles.
+const RTT_SAMPLES: usize = 32;
+
+/// Number of buckets in an RTT histogram.
+pub const RTT_HISTOGRAM_BUCKETS: usize = 20;
+
+/// Width in seconds of each RTT histogram bucket.
+pub const RTT_HISTOGRAM_BUCKET_WIDTH: f32 = 0.01;
+
+/// Gets a percentile from 0 to 1 of some sorted samples, or `None` if
+/// there are no samples.
+pub fn percentile(sorted: &[f32], percentile: f32) -> Option<f32> {
+    // Nearest rank.
+    let rank = (percentile * sorted.len() as f32).ceil() as usize;
+    sorted.get(rank.max(1).min(sorted.len()).checked_sub(1)?).cloned()
+}
+
 /// System to estimate rtt for a connection by periodically sending
 /// pings and recording the time until a response is received.
 #[derive(Default, Debug)]
@@ -32,6 +102,8 @@ pub struct RttEstimator {
     /// This gets set back to `None` once a pong is received.
     last_ping: Option<(u32, Instant)>,
     rtt: Option<f32>,
+    /// The most recent raw samples, oldest first.
+    samples: VecDeque<f32>,
 }
 
 impl RttEstimator {
@@ -41,24 +113,136 @@ impl RttEstimator {
         self.rtt
     }
 
+    /// Gets the mean deviation of the recent samples, or `None` if
+    /// there have been no samples yet.
+    pub fn jitter(&self) -> Option<f32> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let count = self.samples.len() as f32;
+        let mean = self.samples.iter().sum::<f32>() / count;
+        let deviation = self
+            .samples
+            .iter()
+            .map(|sample| (sample - mean).abs())
+            .sum::<f32>();
+        Some(deviation / count)
+    }
+
+    /// Gets a percentile from 0 to 1 of the recent samples, or `None`
+    /// if there have been no samples yet.
+    pub fn percentile(&self, percentile: f32) -> Option<f32> {
+        let mut sorted = self
+            .samples
+            .iter()
+            .cloned()
+            .collect::<SmallVec<[f32; RTT_SAMPLES]>>();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        self::percentile(&sorted, percentile)
+    }
+
+    /// Counts the recent samples in buckets of
+    /// `RTT_HISTOGRAM_BUCKET_WIDTH`, with anything past the end
+    /// counted in the last bucket.
+    pub fn histogram(&self) -> [f32; RTT_HISTOGRAM_BUCKETS] {
+        let mut histogram = [0.0; RTT_HISTOGRAM_BUCKETS];
+        for sample in &self.samples {
+            let bucket = (sample / RTT_HISTOGRAM_BUCKET_WIDTH) as usize;
+            histogram[bucket.min(RTT_HISTOGRAM_BUCKETS - 1)] += 1.0;
+        }
+        histogram
+    }
+
     /// Record a sent ping.
     pub fn ping(&mut self, sequence: u32, now: Instant) {
         self.last_ping = Some((sequence, now));
     }
 
     /// Record a pong response.
-    pub fn pong(&mut self, sequence: u32) {
+    pub fn pong(&mut self, sequence: u32, now: Instant) {
         if let Some((expected, time)) = self.last_ping {
             if sequence == expected {
-                let sample = time.elapsed().as_secs_f32();
-                self.rtt = Some(match self.rtt {
-                    Some(rtt) => 0.875 * rtt + 0.125 * sample,
-                    None => sample,
-                });
+                self.add_sample(now.duration_since(time).as_secs_f32());
                 self.last_ping = None;
             }
         }
     }
+
+    fn add_sample(&mut self, sample: f32) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => 0.875 * rtt + 0.125 * sample,
+            None => sample,
+        });
+        if self.samples.len() == RTT_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+}
+
+/// Keeps track of whether pings are worth sending.
+///
+/// Pings carry acks when nothing else is being sent, as well as
+/// measuring RTT. While other packets are already carrying acks, they
+/// only go out every `BUSY_PING_RATE`.
+#[derive(Default, Debug)]
+pub struct PingSchedule {
+    /// Time the latest packet of any kind was sent.
+    last_sent: Option<Instant>,
+    last_ping: Option<Instant>,
+}
+
+impl PingSchedule {
+    /// Records any packet being sent.
+    pub fn sent(&mut self, now: Instant) {
+        self.last_sent = Some(now);
+    }
+
+    /// Returns whether a ping should be sent now, recording it if so.
+    pub fn ping(&mut self, now: Instant) -> bool {
+        let within = |time: Option<Instant>, duration| {
+            time.map_or(false, |time| now.duration_since(time) < duration)
+        };
+        // Checking against half the ping rate keeps the previous ping
+        // from counting as other traffic.
+        if within(self.last_sent, PING_RATE / 2) &&
+            within(self.last_ping, BUSY_PING_RATE)
+        {
+            return false;
+        }
+        self.last_ping = Some(now);
+        true
+    }
+}
+
+#[test]
+fn rtt_jitter_and_percentiles() {
+    let mut rtt = RttEstimator::default();
+    assert_eq!(rtt.jitter(), None);
+    assert_eq!(rtt.percentile(0.5), None);
+    for &sample in &[0.01, 0.03, 0.01, 0.03] {
+        rtt.add_sample(sample);
+    }
+    assert!((rtt.jitter().unwrap() - 0.01).abs() < 1e-6);
+    assert_eq!(rtt.percentile(0.5), Some(0.01));
+    assert_eq!(rtt.percentile(1.0), Some(0.03));
+    assert_eq!(rtt.histogram()[1], 2.0);
+}
+
+#[test]
+fn pings_suppressed_while_busy() {
+    let start = Instant::now();
+    let mut schedule = PingSchedule::default();
+    assert!(schedule.ping(start));
+    schedule.sent(start);
+    // Nothing else is being sent, so pings are needed for acks.
+    assert!(schedule.ping(start + PING_RATE));
+    schedule.sent(start + PING_RATE);
+    schedule.sent(start + PING_RATE * 2 - PING_RATE / 4);
+    assert!(!schedule.ping(start + PING_RATE * 2));
+    let later = start + PING_RATE + BUSY_PING_RATE;
+    schedule.sent(later);
+    assert!(schedule.ping(later));
 }
 
 /// Non-fatal errors that occur on receiving a packet.
@@ -70,8 +254,82 @@ pub enum RecvError {
     PacketTooLarge(usize),
     #[fail(display = "reading packet header failed: {} {}", _0, _1)]
     HeaderRead(io::Error, Backtrace),
+    #[fail(display = "packet has the wrong protocol magic ({:#x})", _0)]
+    WrongMagic(u32),
+    #[fail(display = "packet payload doesn't match its checksum")]
+    BadChecksum,
+    #[fail(display = "packet is encrypted, but there's no key yet")]
+    MissingKey,
+    #[fail(display = "packet should have been encrypted")]
+    Unencrypted,
+    #[fail(display = "decrypting packet failed")]
+    Decrypt,
     #[fail(display = "deserializing packet payload failed: {} {}", _0, _1)]
     Deserialize(bincode::Error, Backtrace),
+    #[fail(display = "packet has too many players ({})", _0)]
+    TooManyPlayers(usize),
+    #[fail(display = "packet has a name that's too long ({} bytes)", _0)]
+    NameTooLong(usize),
+    #[fail(display = "packet has a message that's too long ({} bytes)", _0)]
+    MessageTooLong(usize),
+}
+
+/// Packets that can be checked for being too big to be legitimate,
+/// after they've been decoded.
+///
+/// Decoding is already limited to `MAX_PACKET_SIZE` bytes, but that's
+/// still enough room for things like hundreds of players.
+pub trait CheckLimits {
+    fn check_limits(&self) -> Result<(), RecvError>;
+}
+
+/// Checks a received number of players against `MAX_PACKET_PLAYERS`.
+pub fn check_players(count: usize) -> Result<(), RecvError> {
+    if count > MAX_PACKET_PLAYERS {
+        Err(RecvError::TooManyPlayers(count))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks a received name against `MAX_NAME_BYTES`.
+pub fn check_name(name: &str) -> Result<(), RecvError> {
+    if name.len() > MAX_NAME_BYTES {
+        Err(RecvError::NameTooLong(name.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks a received server message against `MAX_MESSAGE_BYTES`.
+pub fn check_message(message: &str) -> Result<(), RecvError> {
+    if message.len() > MAX_MESSAGE_BYTES {
+        Err(RecvError::MessageTooLong(message.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks a received set of static player states.
+pub fn check_static_states<'a, I>(states: I) -> Result<(), RecvError>
+where
+    I: ExactSizeIterator<Item = &'a StaticPlayerState>,
+{
+    check_players(states.len())?;
+    for state in states {
+        check_name(&state.name)?;
+    }
+    Ok(())
+}
+
+/// Where a client should carry on the game after the host left.
+#[derive(Debug)]
+pub enum Migration {
+    /// Host the game on the socket bound while this client was the
+    /// backup, starting from the state the last host synced.
+    Host(SavedState, UdpSocket),
+    /// Connect to whoever took over hosting.
+    Join(SocketAddr),
 }
 
 /// (Mostly) fatal errors that should kill either the networking event
@@ -80,6 +338,12 @@ pub enum RecvError {
 pub enum Error {
     #[fail(display = "connection timed out")]
     TimedOut,
+    #[fail(display = "no response from server")]
+    NoResponse,
+    #[fail(display = "connection refused")]
+    ConnectionRefused,
+    #[fail(display = "server denied the connection: {}", _0)]
+    ConnectionDenied(String),
     #[fail(display = "poll error: {} {}", _0, _1)]
     Poll(#[cause] io::Error, Backtrace),
     #[fail(display = "binding socket to {:?} failed: {}", addr, err)]
@@ -88,16 +352,16 @@ pub enum Error {
         #[cause]
         err: io::Error,
     },
-    #[fail(display = "connecting socket to {:?} failed: {}", addr, err)]
-    ConnectSocket {
-        addr: SocketAddr,
-        #[cause]
-        err: io::Error,
-    },
     #[fail(display = "socket write failed: {}", _0)]
     SocketWrite(io::Error),
     #[fail(display = "socket read failed: {}", _0)]
     SocketRead(io::Error),
+    #[fail(display = "networking thread panicked: {}", _0)]
+    Panicked(String),
+    /// The host left and handed the game over, which isn't really an
+    /// error, but it does end the connection.
+    #[fail(display = "the host left the game")]
+    Migrated(Migration),
 }
 
 impl Error {
@@ -0,0 +1,158 @@
+use crate::networking::connection::{peek_sequence, HEADER_BYTES};
+use failure::Fail;
+use lazy_static::lazy_static;
+use log::warn;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Instant;
+
+lazy_static! {
+    static ref PACKET_LOG: Mutex<Option<PacketLog>> = Mutex::new(None);
+}
+
+/// Which side of a connection traced a packet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Endpoint {
+    Client,
+    Server,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A single traced packet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PacketRecord {
+    /// Microseconds since tracing started.
+    pub time: u64,
+    pub endpoint: Endpoint,
+    pub direction: Direction,
+    /// Address on the other side of the connection.
+    pub peer: SocketAddr,
+    /// Sequence number from the header, if the packet had one.
+    pub sequence: Option<u32>,
+    /// Name of the packet type, or `"invalid"` if it couldn't be
+    /// decoded.
+    pub kind: Cow<'static, str>,
+    /// Size of the whole packet in bytes, including the header.
+    pub size: u32,
+}
+
+/// Packets that can be named in a trace.
+pub trait PacketKind: DeserializeOwned {
+    fn kind(&self) -> &'static str;
+}
+
+/// Binary file that packet records are appended to.
+struct PacketLog {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+#[derive(Fail, Debug)]
+pub enum DumpError {
+    #[fail(display = "reading packet log failed: {}", _0)]
+    Read(#[cause] io::Error),
+    #[fail(display = "decoding packet record failed: {}", _0)]
+    Decode(#[cause] bincode::Error),
+    #[fail(display = "writing JSON failed: {}", _0)]
+    Write(#[cause] serde_json::Error),
+}
+
+/// Starts tracing every packet sent or received in this process to a
+/// file, replacing any previous trace.
+pub fn enable(path: &Path) -> Result<(), io::Error> {
+    let file = File::create(path)?;
+    *PACKET_LOG.lock() = Some(PacketLog {
+        writer: BufWriter::new(file),
+        start: Instant::now(),
+    });
+    Ok(())
+}
+
+/// Traces a packet, if tracing is enabled.
+///
+/// The packet is decoded as `P` just to get its type, so this costs
+/// nothing unless tracing is on.
+pub fn record<P: PacketKind>(
+    endpoint: Endpoint,
+    direction: Direction,
+    peer: SocketAddr,
+    data: &[u8],
+) {
+    let mut guard = PACKET_LOG.lock();
+    let log = match guard.as_mut() {
+        Some(log) => log,
+        None => return,
+    };
+
+    let sequence = if data.len() >= HEADER_BYTES {
+        peek_sequence(data)
+    } else {
+        None
+    };
+    let kind = data
+        .get(HEADER_BYTES..)
+        .and_then(|body| bincode::deserialize::<P>(body).ok())
+        .map(|packet| packet.kind())
+        .unwrap_or("invalid");
+    let elapsed = log.start.elapsed();
+    let record = PacketRecord {
+        time: elapsed.as_secs() * 1_000_000 +
+            u64::from(elapsed.subsec_micros()),
+        endpoint,
+        direction,
+        peer,
+        sequence,
+        kind: Cow::Borrowed(kind),
+        size: data.len() as u32,
+    };
+
+    // Flush every record, so the trace survives a crash.
+    let result = bincode::serialize_into(&mut log.writer, &record)
+        .and_then(|_| log.writer.flush().map_err(Into::into));
+    if let Err(err) = result {
+        warn!("failed to write packet log, disabling it: {}", err);
+        *guard = None;
+    }
+}
+
+/// Converts a packet trace into a JSON array of `PacketRecord`s.
+///
+/// A truncated record at the end, like from a crash, is ignored.
+pub fn dump<W: Write>(path: &Path, mut out: W) -> Result<(), DumpError> {
+    let mut reader = BufReader::new(File::open(path).map_err(DumpError::Read)?);
+    writeln!(out, "[").map_err(DumpError::Read)?;
+    let mut first = true;
+    loop {
+        let record: PacketRecord = match bincode::deserialize_from(&mut reader)
+        {
+            Ok(record) => record,
+            Err(err) => {
+                match *err {
+                    bincode::ErrorKind::Io(ref err)
+                        if err.kind() == io::ErrorKind::UnexpectedEof =>
+                    {
+                        break
+                    },
+                    _ => return Err(DumpError::Decode(err)),
+                }
+            },
+        };
+        if !first {
+            writeln!(out, ",").map_err(DumpError::Read)?;
+        }
+        first = false;
+        serde_json::to_writer(&mut out, &record).map_err(DumpError::Write)?;
+    }
+    writeln!(out, "\n]").map_err(DumpError::Read)
+}
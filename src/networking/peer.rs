@@ -0,0 +1,191 @@
+use crate::networking::connection::Connection;
+use crate::networking::reliable::{GaveUp, Reliable};
+use crate::networking::server::ServerPacket;
+use crate::networking::transport::Transport;
+use crate::networking::{Error, PingSchedule, RttEstimator};
+use log::{debug, error};
+use mio::{Poll, PollOpt, Ready, Token};
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// Packets waiting to be sent, with who to send them to.
+pub type SendQueue = VecDeque<(SocketAddr, Vec<u8>)>;
+
+/// Something that gets sent `ServerPacket`s, like a server's client or
+/// a relay's viewer.
+pub trait Peer {
+    fn connection(&mut self) -> &mut Connection;
+    fn rtt(&self) -> &RttEstimator;
+    fn ping_schedule(&mut self) -> &mut PingSchedule;
+    fn reliable(&mut self) -> &mut Reliable<ServerPacket>;
+
+    /// Encodes a packet and possibly saves it in the reliable packet
+    /// buffer.
+    ///
+    /// Returns the sequence number.
+    fn encode(
+        &mut self,
+        packet: &ServerPacket,
+        now: Instant,
+    ) -> (Vec<u8>, u32) {
+        self.encode_resend(packet, 0, now)
+    }
+
+    /// Like `encode`, for a packet that's been sent `resends` times
+    /// before.
+    fn encode_resend(
+        &mut self,
+        packet: &ServerPacket,
+        resends: u32,
+        now: Instant,
+    ) -> (Vec<u8>, u32) {
+        let (data, sequence) = match packet {
+            // The peer can't decrypt anything until it's read this.
+            ServerPacket::Handshake {
+                ..
+            } => self.connection().encode_plain(packet),
+            _ => self.connection().encode(packet),
+        };
+        self.ping_schedule().sent(now);
+        if let Some(reliable) = packet.reliable_part() {
+            let rtt = self.rtt().rtt();
+            self.reliable().insert(sequence, reliable, resends, rtt, now);
+        }
+        (data, sequence)
+    }
+
+    /// Like `encode`, for a packet that's already been serialized, so
+    /// that it only has to be serialized once for everyone.
+    fn encode_payload(
+        &mut self,
+        payload: &[u8],
+        reliable: Option<&ServerPacket>,
+        now: Instant,
+    ) -> Vec<u8> {
+        let (data, sequence) = self.connection().encode_payload(payload);
+        self.ping_schedule().sent(now);
+        if let Some(reliable) = reliable {
+            let rtt = self.rtt().rtt();
+            self.reliable().insert(sequence, reliable.clone(), 0, rtt, now);
+        }
+        data
+    }
+}
+
+/// Receives the next datagram into `buffer`, or `None` once there's
+/// nothing left to receive.
+pub fn recv(
+    transport: &dyn Transport,
+    buffer: &mut [u8],
+) -> Result<Option<(usize, SocketAddr)>, Error> {
+    match transport.recv_from(buffer) {
+        Ok(received) => Ok(Some(received)),
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(err) => {
+            error!("error receiving packet: {}", err);
+            // TODO figure out if any of these are non-fatal
+            Err(Error::SocketRead(err))
+        },
+    }
+}
+
+/// Sends queued packets until the transport would block, calling
+/// `sent` with each one that goes out and how many bytes were written.
+///
+/// Returns the addresses that sending to failed, which should be
+/// dropped.
+pub fn send_queued<F: FnMut(SocketAddr, &[u8], usize)>(
+    transport: &dyn Transport,
+    send_queue: &mut SendQueue,
+    mut sent: F,
+) -> Vec<SocketAddr> {
+    let mut failed = Vec::new();
+    while let Some(&(addr, ref packet)) = send_queue.front() {
+        match transport.send_to(packet, addr) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => {
+                error!("error sending packet to {} ({})", addr, err);
+                failed.push(addr);
+            },
+            Ok(bytes_written) => sent(addr, packet, bytes_written),
+        }
+        send_queue.pop_front();
+    }
+    failed
+}
+
+/// Switches whether the transport gets polled for writability, which
+/// is only needed while there's something queued up.
+pub fn reregister(
+    poll: &Poll,
+    transport: &dyn Transport,
+    token: Token,
+    writable: bool,
+) -> Result<(), Error> {
+    let readiness = if writable {
+        Ready::readable() | Ready::writable()
+    } else {
+        Ready::readable()
+    };
+    poll.reregister(transport, token, readiness, PollOpt::edge())
+        .map_err(Error::poll)
+}
+
+/// Queues up a packet for every peer, serializing it only once.
+pub fn broadcast<'a, P, I>(
+    peers: I,
+    packet: &ServerPacket,
+    send_queue: &mut SendQueue,
+    now: Instant,
+) where
+    P: Peer + 'a,
+    I: IntoIterator<Item = (&'a SocketAddr, &'a mut P)>,
+{
+    let mut peers = peers.into_iter().peekable();
+    if peers.peek().is_none() {
+        return;
+    }
+    let data = bincode::serialize(packet).unwrap();
+    let reliable = packet.reliable_part();
+    for (&addr, peer) in peers {
+        let data = peer.encode_payload(&data, reliable.as_ref(), now);
+        send_queue.push_back((addr, data));
+    }
+}
+
+/// Queues up reliable packets that have gone unacked for too long
+/// again, as `resend` rewrites them for the current state of the game.
+///
+/// Returns the peers that never acked one, which should be dropped.
+pub fn resend_overdue<'a, P, I, F>(
+    peers: I,
+    send_queue: &mut SendQueue,
+    now: Instant,
+    mut resend: F,
+) -> Vec<SocketAddr>
+where
+    P: Peer + 'a,
+    I: IntoIterator<Item = (&'a SocketAddr, &'a mut P)>,
+    F: FnMut(ServerPacket) -> Option<ServerPacket>,
+{
+    let mut gave_up = Vec::new();
+    for (&addr, peer) in peers {
+        let overdue = match peer.reliable().take_overdue(now) {
+            Ok(overdue) => overdue,
+            Err(GaveUp) => {
+                gave_up.push(addr);
+                continue;
+            },
+        };
+        for (packet, resends) in overdue {
+            if let Some(packet) = resend(packet) {
+                debug!("resending unacked packet to {}: {:?}", addr, packet);
+                let (data, _) = peer.encode_resend(&packet, resends + 1, now);
+                send_queue.push_back((addr, data));
+            }
+        }
+    }
+    gave_up
+}
@@ -0,0 +1,55 @@
+use crate::config::Error;
+use crate::game::GameSettings;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Match state that a server saves on shutdown, so that restarting it
+/// doesn't wipe out an ongoing tournament's standings.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    /// Addresses that aren't allowed to connect.
+    pub banned: Vec<IpAddr>,
+    pub settings: GameSettings,
+    /// Win count of every player by name and address, since player ids
+    /// don't survive a restart.
+    pub wins: BTreeMap<String, u32>,
+}
+
+impl SavedState {
+    pub fn load(path: &Path) -> Result<SavedState, Error> {
+        let contents = fs::read_to_string(path).map_err(Error::Read)?;
+        toml::from_str(&contents).map_err(Error::Deserialize)
+    }
+
+    /// Writes the state to a file, creating its directory if needed.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(Error::Write)?;
+        }
+        // Going through a `Value` puts plain values ahead of tables,
+        // which TOML requires and the settings don't do on their own.
+        let value = toml::Value::try_from(self).map_err(Error::Serialize)?;
+        let contents = toml::to_string(&value).map_err(Error::Serialize)?;
+        fs::write(path, contents).map_err(Error::Write)
+    }
+}
+
+#[test]
+fn saved_state_round_trip() {
+    use std::env;
+
+    let mut state = SavedState::default();
+    state.banned.push("10.0.0.1".parse().unwrap());
+    state.settings.respawn_delay = Some(2.0);
+    state.settings.afk_timeout = Some(30.0);
+    state.wins.insert("winner".to_owned(), 3);
+    let path = env::temp_dir()
+        .join(format!("ball-server-state-{}.toml", std::process::id()));
+    state.save(&path).unwrap();
+    let loaded = SavedState::load(&path);
+    let _ = fs::remove_file(&path);
+    assert_eq!(loaded.unwrap(), state);
+}
@@ -0,0 +1,151 @@
+use crate::game::RoundState;
+use crate::networking::client::ClientPacket;
+use crate::networking::connection::Connection;
+use crate::networking::server::ServerPacket;
+use crate::networking::MAX_PACKET_SIZE;
+use crossbeam::channel::{self, Receiver};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a server to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of queries an address can have answered in a row.
+const QUERY_BURST: f32 = 4.0;
+
+/// Number of queries per second an address can keep having answered.
+const QUERY_RATE: f32 = 2.0;
+
+/// Number of addresses a `QueryLimiter` keeps track of at once.
+const MAX_QUERY_ADDRESSES: usize = 4096;
+
+/// Summary of a server, as answered by a query.
+#[derive(Clone, Debug)]
+pub struct ServerInfo {
+    /// Name the server is listed under, or empty if it isn't listed.
+    pub name: String,
+    /// Number of players in the game.
+    pub players: u16,
+    pub round: RoundState,
+    /// Version of the game the server is running.
+    pub version: String,
+    /// Round trip time of the query in seconds.
+    pub ping: f32,
+}
+
+/// Limits how often each address gets its queries answered.
+///
+/// Queries are answered without a handshake, so the sender's address
+/// could be spoofed to have the answers flood someone else.
+#[derive(Debug, Default)]
+pub struct QueryLimiter {
+    /// Queries each address can still have answered right away, and
+    /// when that was last updated.
+    addresses: HashMap<IpAddr, (f32, Instant)>,
+}
+
+impl QueryLimiter {
+    /// Checks whether to answer a query from `ip`, counting it if so.
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.addresses.len() >= MAX_QUERY_ADDRESSES {
+            // Addresses that have been quiet long enough to have a
+            // full burst again are the same as new ones.
+            self.addresses.retain(|_, &mut (available, updated)| {
+                refill(available, updated, now) < QUERY_BURST
+            });
+            if self.addresses.len() >= MAX_QUERY_ADDRESSES &&
+                !self.addresses.contains_key(&ip)
+            {
+                return false;
+            }
+        }
+        let (available, updated) =
+            self.addresses.entry(ip).or_insert((QUERY_BURST, now));
+        *available = refill(*available, *updated, now);
+        *updated = now;
+        if *available < 1.0 {
+            return false;
+        }
+        *available -= 1.0;
+        true
+    }
+}
+
+/// Adds the queries an address has earned since `updated` to what it
+/// had available then.
+fn refill(available: f32, updated: Instant, now: Instant) -> f32 {
+    let elapsed = now.duration_since(updated).as_secs_f32();
+    (available + elapsed * QUERY_RATE).min(QUERY_BURST)
+}
+
+/// Asks a server for a summary of its game on a background thread,
+/// without joining it.
+///
+/// The result is sent once on the returned channel, or `None` if the
+/// server didn't answer in time.
+pub fn query(addr: SocketAddr) -> Receiver<Option<ServerInfo>> {
+    let (tx, rx) = channel::bounded(1);
+    thread::spawn(move || {
+        let info = query_blocking(addr);
+        if info.is_none() {
+            debug!("no answer to query from {}", addr);
+        }
+        let _ = tx.send(info);
+    });
+    rx
+}
+
+fn query_blocking(addr: SocketAddr) -> Option<ServerInfo> {
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .map_err(|err| warn!("failed to bind query socket: {}", err))
+        .ok()?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT)).ok()?;
+
+    let mut connection = Connection::default();
+    let (data, _) = connection.encode(&ClientPacket::Query);
+    let start = Instant::now();
+    socket.send_to(&data, addr).ok()?;
+
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    loop {
+        let (bytes_read, from) = socket.recv_from(&mut buffer).ok()?;
+        if from != addr {
+            continue;
+        }
+        let packet =
+            connection.decode(&buffer[..bytes_read]).map(|(packet, ..)| packet);
+        if let Ok(ServerPacket::Info {
+            name,
+            players,
+            round,
+            version,
+        }) = packet
+        {
+            return Some(ServerInfo {
+                name,
+                players,
+                round,
+                version,
+                ping: start.elapsed().as_secs_f32(),
+            });
+        }
+    }
+}
+
+#[test]
+fn queries_limited_per_address() {
+    let mut limiter = QueryLimiter::default();
+    let start = Instant::now();
+    let spammer = IpAddr::from([10, 0, 0, 1]);
+    let answered = (0..10).filter(|_| limiter.allow(spammer, start)).count();
+    assert_eq!(answered, QUERY_BURST as usize);
+    // Someone else is still answered.
+    assert!(limiter.allow(IpAddr::from([10, 0, 0, 2]), start));
+    // The spammer gets more once enough time has passed.
+    let later = start + Duration::from_secs_f32(1.0 / QUERY_RATE);
+    assert!(limiter.allow(spammer, later));
+    assert!(!limiter.allow(spammer, later));
+}
@@ -0,0 +1,624 @@
+use crate::game::{client::Game, Event};
+use crate::networking::client::{
+    self,
+    ClientHandle,
+    ClientPacket,
+    ConnectedHandle,
+};
+use crate::networking::connection::Connection;
+use crate::networking::event_loop::{run_event_loop, EventHandler};
+use crate::networking::packet_log::{self, Direction, Endpoint};
+use crate::networking::peer::{self, Peer};
+use crate::networking::query::QueryLimiter;
+use crate::networking::reliable::Reliable;
+use crate::networking::server::{batch_events, ServerPacket};
+use crate::networking::tick::Interval;
+use crate::networking::transport::{self, Transport};
+use crate::networking::{
+    Error,
+    PingSchedule,
+    RecvError,
+    RttEstimator,
+    CONNECTION_TIMEOUT,
+    MAX_PACKET_SIZE,
+    PING_RATE,
+    SPECTATOR_ID,
+    VERSION,
+};
+use crossbeam::channel::{self, Receiver, Sender};
+use log::{debug, error, info, trace, warn};
+use mio::{self, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio_extras::timer::{self, Timeout, Timer};
+use rand::{thread_rng, Rng};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often events from the upstream server are passed on to
+/// viewers.
+const FORWARD_RATE: Duration = Duration::from_millis(15);
+
+/// Name the relay spectates the upstream server under.
+const RELAY_NAME: &str = "relay";
+
+const SOCKET: Token = Token(0);
+const TIMER: Token = Token(1);
+const SHUTDOWN: Token = Token(2);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum TimeoutState {
+    Forward,
+    Ping,
+    LostConnection(SocketAddr),
+}
+
+/// Options for running a relay.
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    /// Address of the server to spectate.
+    pub upstream: SocketAddr,
+    /// Address to accept viewers on.
+    pub addr: SocketAddr,
+    /// Number of viewers after which new ones are denied, or `None`
+    /// for no limit.
+    pub max_viewers: Option<u16>,
+}
+
+struct Viewer {
+    connection: Connection,
+    timeout: Timeout,
+    rtt: RttEstimator,
+    ping_schedule: PingSchedule,
+    reliable: Reliable<ServerPacket>,
+}
+
+pub struct Relay {
+    transport: Box<dyn Transport>,
+    timer: Timer<TimeoutState>,
+    recv_buffer: [u8; MAX_PACKET_SIZE],
+    send_queue: VecDeque<(SocketAddr, Vec<u8>)>,
+    viewers: HashMap<SocketAddr, Viewer>,
+    query_limiter: QueryLimiter,
+    /// Copy of the upstream game, which new viewers get in their
+    /// handshake.
+    game: Game,
+    /// Keeps the upstream connection open.
+    _upstream: ClientHandle,
+    upstream_done: ConnectedHandle,
+    config: RelayConfig,
+    forward: Interval,
+    ping: Interval,
+    poll: Poll,
+    done: Sender<Option<Error>>,
+    _shutdown: Registration,
+}
+
+pub struct RelayHandle {
+    shutdown: SetReadiness,
+    pub done: Receiver<Option<Error>>,
+    /// Address that viewers can connect to.
+    pub addr: SocketAddr,
+}
+
+/// Spectates a server, and serves everything it sends to any number
+/// of viewers on another address, so that watching a match doesn't
+/// cost the server any bandwidth.
+///
+/// Viewers connect the same way they would to a server, but never
+/// get a player, and anything they send besides pings is ignored.
+/// This blocks until the upstream server has accepted the relay.
+pub fn relay(
+    config: RelayConfig,
+) -> Result<(RelayHandle, JoinHandle<()>), Error> {
+    let socket = transport::bind_udp(config.addr)?;
+    let addr = socket.local_addr().map_err(|err| {
+        Error::BindSocket {
+            addr: config.addr,
+            err,
+        }
+    })?;
+
+    let (upstream, connecting, _) =
+        client::spectate(config.upstream, RELAY_NAME.to_owned())?;
+    let (mut game, upstream_done) = match connecting.recv() {
+        Ok(Ok(connected)) => connected,
+        Ok(Err(err)) => return Err(err.unwrap_or(Error::NoResponse)),
+        Err(_) => return Err(Error::NoResponse),
+    };
+    info!("spectating {}, relaying to {}", config.upstream, addr);
+    game.keep_relayed();
+
+    let (done_tx, done_rx) = channel::bounded(1);
+    let (shutdown_registration, shutdown_set_readiness) = Registration::new2();
+    let relay = Relay::new(
+        Box::new(socket),
+        config,
+        game,
+        upstream,
+        upstream_done,
+        shutdown_registration,
+        done_tx,
+    )?;
+    let thread = thread::spawn(move || {
+        run_event_loop(relay);
+        info!("relay done");
+    });
+    Ok((
+        RelayHandle {
+            shutdown: shutdown_set_readiness,
+            done: done_rx,
+            addr,
+        },
+        thread,
+    ))
+}
+
+/// Builds the handshake reply for a new viewer from the copy of the
+/// upstream game.
+fn handshake(game: &Game, connection_id: u32) -> ServerPacket {
+    ServerPacket::Handshake {
+        id: SPECTATOR_ID,
+        settings: *game.settings(),
+        players: game.players.clone(),
+        round: game.round,
+        round_duration: game.round_duration,
+        snapshot: game.latest_snapshot().clone(),
+        hue: 0.0,
+        key: None,
+        connection_id,
+        seed: game.seed(),
+    }
+}
+
+fn game_info(game: &Game) -> ServerPacket {
+    ServerPacket::Info {
+        name: String::new(),
+        players: game.players.len() as u16,
+        round: game.round,
+        version: VERSION.to_owned(),
+    }
+}
+
+impl RelayHandle {
+    /// Signals the relay to shut down, along with its upstream
+    /// connection.
+    pub fn shutdown(&self) {
+        if let Err(err) = self.shutdown.set_readiness(Ready::readable()) {
+            warn!("failed to signal shutdown to relay: {}", err)
+        }
+    }
+}
+
+impl Drop for RelayHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Peer for Viewer {
+    fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    fn ping_schedule(&mut self) -> &mut PingSchedule {
+        &mut self.ping_schedule
+    }
+
+    fn reliable(&mut self) -> &mut Reliable<ServerPacket> {
+        &mut self.reliable
+    }
+}
+
+impl EventHandler for Relay {
+    fn poll(&self) -> &Poll {
+        &self.poll
+    }
+
+    fn handle(&mut self, event: mio::Event) -> bool {
+        match event.token() {
+            SOCKET => {
+                if event.readiness().is_readable() {
+                    if let Err(err) = self.socket_readable() {
+                        error!("error on reading relay socket: {}", err);
+                        let _ = self.done.send(Some(err));
+                        return true;
+                    }
+                }
+                if event.readiness().is_writable() {
+                    if let Err(err) = self.socket_writable() {
+                        error!("error on writing relay socket: {}", err);
+                        let _ = self.done.send(Some(err));
+                        return true;
+                    }
+                }
+            },
+            TIMER => {
+                while let Some(timeout) = self.timer.poll() {
+                    let result = match timeout {
+                        TimeoutState::Forward => {
+                            // There's nothing left to relay once the
+                            // upstream connection is gone.
+                            if let Ok(err) = self.upstream_done.try_recv() {
+                                warn!("relay lost its upstream connection");
+                                let _ = self.done.send(err);
+                                return true;
+                            }
+                            self.forward()
+                        },
+                        TimeoutState::Ping => self.send_ping(),
+                        TimeoutState::LostConnection(addr) => {
+                            info!("viewer from {} timed out", addr);
+                            self.viewers.remove(&addr);
+                            Ok(())
+                        },
+                    };
+
+                    if let Err(err) = result {
+                        error!("error on handling relay timer event: {}", err);
+                        let _ = self.done.send(Some(err));
+                        return true;
+                    }
+                }
+            },
+            SHUTDOWN => {
+                info!("relay received shutdown from handle");
+                let _ = self.done.send(None);
+                return true;
+            },
+            _ => unreachable!(),
+        }
+        false
+    }
+
+    fn panicked(&mut self, err: Error) {
+        let _ = self.done.try_send(Some(err));
+    }
+}
+
+impl Relay {
+    fn new(
+        transport: Box<dyn Transport>,
+        config: RelayConfig,
+        game: Game,
+        upstream: ClientHandle,
+        upstream_done: ConnectedHandle,
+        shutdown: Registration,
+        done: Sender<Option<Error>>,
+    ) -> Result<Relay, Error> {
+        let mut timer = timer::Builder::default()
+            .tick_duration(Duration::from_millis(5))
+            .build();
+        let poll = Poll::new().map_err(Error::poll)?;
+        poll.register(&*transport, SOCKET, Ready::readable(), PollOpt::edge())
+            .map_err(Error::poll)?;
+        poll.register(&timer, TIMER, Ready::readable(), PollOpt::edge())
+            .map_err(Error::poll)?;
+        poll.register(&shutdown, SHUTDOWN, Ready::readable(), PollOpt::edge())
+            .map_err(Error::poll)?;
+
+        let now = Instant::now();
+        let forward = Interval::new(FORWARD_RATE, now);
+        timer.set_timeout(forward.interval(), TimeoutState::Forward);
+        let ping = Interval::new(PING_RATE, now);
+        timer.set_timeout(ping.interval(), TimeoutState::Ping);
+
+        Ok(Relay {
+            transport,
+            timer,
+            recv_buffer: [0; MAX_PACKET_SIZE],
+            send_queue: VecDeque::new(),
+            viewers: HashMap::new(),
+            query_limiter: QueryLimiter::default(),
+            game,
+            _upstream: upstream,
+            upstream_done,
+            config,
+            forward,
+            ping,
+            poll,
+            done,
+            _shutdown: shutdown,
+        })
+    }
+
+    /// Catches the copy of the game up with the upstream server, and
+    /// passes on everything it sent to the viewers.
+    fn forward(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        let (dt, interval) = self.forward.next(now);
+        self.timer.set_timeout(interval, TimeoutState::Forward);
+
+        self.game.tick(dt.as_secs_f32());
+        // Nothing gets drawn here, so old snapshots just have to be
+        // cleared out.
+        self.game.update_playback(now, 0.0);
+        self.game.drain_events().for_each(drop);
+
+        for mut batch in batch_events(self.game.drain_relayed()) {
+            let packet = if batch.len() == 1 {
+                ServerPacket::Event(batch.pop().unwrap())
+            } else {
+                ServerPacket::Events(batch)
+            };
+            self.broadcast(&packet)?;
+        }
+        self.resend_overdue(now)
+    }
+
+    /// Resends reliable packets that have gone unacked for too long,
+    /// and drops viewers that never ack them.
+    fn resend_overdue(&mut self, now: Instant) -> Result<(), Error> {
+        let game = &self.game;
+        let gave_up = peer::resend_overdue(
+            &mut self.viewers,
+            &mut self.send_queue,
+            now,
+            |packet| packet.resend(game.round, game.settings(), game.paused()),
+        );
+        for addr in gave_up {
+            info!("viewer from {} never acked a reliable packet", addr);
+            self.viewers.remove(&addr);
+        }
+        if !self.send_queue.is_empty() {
+            self.reregister_socket(true)?;
+        }
+        Ok(())
+    }
+
+    fn send_ping(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        let (_, interval) = self.ping.next(now);
+        self.timer.set_timeout(interval, TimeoutState::Ping);
+
+        for (&addr, viewer) in self.viewers.iter_mut() {
+            if !viewer.ping_schedule.ping(now) {
+                continue;
+            }
+            let (packet, sequence) = viewer.encode(&ServerPacket::Ping, now);
+            self.send_queue.push_back((addr, packet));
+            viewer.rtt.ping(sequence, now);
+        }
+        self.reregister_socket(true)
+    }
+
+    fn socket_readable(&mut self) -> Result<(), Error> {
+        while let Some((bytes_read, addr)) =
+            peer::recv(&*self.transport, &mut self.recv_buffer)?
+        {
+            packet_log::record::<ClientPacket>(
+                Endpoint::Server,
+                Direction::Received,
+                addr,
+                &self.recv_buffer[..bytes_read],
+            );
+            if let Err(err) = self.on_recv(addr, bytes_read)? {
+                error!(
+                    "failed to receive packet from viewer {}: {}",
+                    addr, err
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn socket_writable(&mut self) -> Result<(), Error> {
+        let failed = peer::send_queued(
+            &*self.transport,
+            &mut self.send_queue,
+            |addr, packet, _| {
+                packet_log::record::<ServerPacket>(
+                    Endpoint::Server,
+                    Direction::Sent,
+                    addr,
+                    packet,
+                );
+            },
+        );
+        // Drop any viewer that errors.
+        for addr in failed {
+            self.viewers.remove(&addr);
+        }
+
+        if self.send_queue.is_empty() {
+            self.reregister_socket(false)?;
+        }
+        Ok(())
+    }
+
+    /// Starts sending to a new viewer, unless there are too many.
+    fn new_viewer(
+        &mut self,
+        addr: SocketAddr,
+        mut connection: Connection,
+        name: &str,
+    ) -> Result<(), Error> {
+        if let Some(max_viewers) = self.config.max_viewers {
+            if self.viewers.len() >= max_viewers as usize {
+                info!("denying viewer {:?} from {}, relay is full", name, addr);
+                let packet =
+                    ServerPacket::ConnectionDenied("relay full".to_owned());
+                let (packet, _) = connection.encode(&packet);
+                self.send_queue.push_back((addr, packet));
+                return self.reregister_socket(true);
+            }
+        }
+        info!("new viewer {:?} from {}", name, addr);
+
+        connection.id = self.new_connection_id();
+        let mut viewer = Viewer {
+            connection,
+            timeout: self.timer.set_timeout(
+                CONNECTION_TIMEOUT,
+                TimeoutState::LostConnection(addr),
+            ),
+            rtt: RttEstimator::default(),
+            ping_schedule: PingSchedule::default(),
+            reliable: Reliable::default(),
+        };
+        let now = Instant::now();
+        let packet = handshake(&self.game, viewer.connection.id);
+        let (packet, _) = viewer.encode(&packet, now);
+        self.send_queue.push_back((addr, packet));
+        if self.game.paused() {
+            let packet = ServerPacket::Event(Event::Paused(true));
+            let (packet, _) = viewer.encode(&packet, now);
+            self.send_queue.push_back((addr, packet));
+        }
+        self.viewers.insert(addr, viewer);
+        self.reregister_socket(true)
+    }
+
+    /// Picks a random connection id that isn't in use.
+    fn new_connection_id(&self) -> u32 {
+        let mut rng = thread_rng();
+        loop {
+            let id = rng.gen();
+            if id != 0 &&
+                self.viewers
+                    .values()
+                    .all(|viewer| viewer.connection.id != id)
+            {
+                break id;
+            }
+        }
+    }
+
+    fn on_recv(
+        &mut self,
+        addr: SocketAddr,
+        bytes_read: usize,
+    ) -> Result<Result<(), RecvError>, Error> {
+        let now = Instant::now();
+        if bytes_read > MAX_PACKET_SIZE {
+            return Ok(Err(RecvError::PacketTooLarge(bytes_read)));
+        }
+        trace!(
+            "got packet from viewer {}: {:?}",
+            addr,
+            &self.recv_buffer[..bytes_read]
+        );
+        let packet = &self.recv_buffer[..bytes_read];
+        let viewer = match self.viewers.get_mut(&addr) {
+            Some(viewer) => viewer,
+            None => {
+                let mut connection = Connection::default();
+                let (packet, ..) = match connection.decode(packet) {
+                    Ok(result) => result,
+                    Err(err) => return Ok(Err(err)),
+                };
+                match packet {
+                    ClientPacket::Handshake {
+                        name,
+                        ..
+                    } => self.new_viewer(addr, connection, &name)?,
+                    ClientPacket::Query => {
+                        if !self.query_limiter.allow(addr.ip(), now) {
+                            trace!("ignoring query from {}", addr);
+                            return Ok(Ok(()));
+                        }
+                        let (data, _) =
+                            connection.encode(&game_info(&self.game));
+                        self.send_queue.push_back((addr, data));
+                        self.reregister_socket(true)?;
+                    },
+                    // Ignore anything else.
+                    _ => (),
+                }
+                return Ok(Ok(()));
+            },
+        };
+
+        self.timer.cancel_timeout(&viewer.timeout);
+        viewer.timeout = self.timer.set_timeout(
+            CONNECTION_TIMEOUT,
+            TimeoutState::LostConnection(addr),
+        );
+
+        let (packet, sequence, acks, lost, duplicate) =
+            match viewer.connection.decode(packet) {
+                Ok(result) => result,
+                Err(err) => return Ok(Err(err)),
+            };
+        if duplicate {
+            trace!("skipping duplicate packet {} from {}", sequence, addr);
+            return Ok(Ok(()));
+        }
+        for ack in acks.iter() {
+            viewer.reliable.ack(ack);
+        }
+        for lost in lost.into_iter() {
+            let game = &self.game;
+            let packet =
+                viewer.reliable.take(lost).and_then(|(packet, resends)| {
+                    packet
+                        .resend(game.round, game.settings(), game.paused())
+                        .map(|packet| (packet, resends))
+                });
+            if let Some((packet, resends)) = packet {
+                debug!("resending lost packet to viewer {}", addr);
+                let (packet, _) =
+                    viewer.encode_resend(&packet, resends + 1, now);
+                self.send_queue.push_back((addr, packet));
+            }
+        }
+
+        let mut left = false;
+        let reply = match packet {
+            // The viewer is retrying because our reply was lost.
+            ClientPacket::Handshake {
+                ..
+            } => Some(handshake(&self.game, viewer.connection.id)),
+            ClientPacket::Query => Some(game_info(&self.game)),
+            ClientPacket::RequestState => {
+                Some(ServerPacket::Event(Event::StaticState(
+                    self.game.players.clone(),
+                )))
+            },
+            ClientPacket::Ping => Some(ServerPacket::Pong(sequence)),
+            ClientPacket::Pong(sequence) => {
+                viewer.rtt.pong(sequence, now);
+                None
+            },
+            ClientPacket::Disconnect => {
+                left = true;
+                Some(ServerPacket::DisconnectAck)
+            },
+            // Viewers can't change anything, or take over hosting.
+            ClientPacket::Input(_) |
+            ClientPacket::Settings(_) |
+            ClientPacket::BackupReady(_) => None,
+        };
+        if let Some(reply) = reply {
+            let (packet, _) = viewer.encode(&reply, now);
+            self.send_queue.push_back((addr, packet));
+        }
+        if left {
+            info!("viewer from {} left", addr);
+            self.viewers.remove(&addr);
+        }
+        if !self.send_queue.is_empty() {
+            self.reregister_socket(true)?;
+        }
+
+        Ok(Ok(()))
+    }
+
+    fn reregister_socket(&mut self, writable: bool) -> Result<(), Error> {
+        peer::reregister(&self.poll, &*self.transport, SOCKET, writable)
+    }
+
+    fn broadcast(&mut self, packet: &ServerPacket) -> Result<(), Error> {
+        if self.viewers.is_empty() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        peer::broadcast(&mut self.viewers, packet, &mut self.send_queue, now);
+        self.reregister_socket(true)
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Resend delay before there's an RTT estimate to base it on.
+const DEFAULT_RESEND_DELAY: Duration = Duration::from_millis(250);
+
+/// Shortest resend delay, however low the RTT is, so that acks that
+/// are only a little late don't cause resends.
+const MIN_RESEND_DELAY: Duration = Duration::from_millis(50);
+
+/// Longest resend delay, which backing off stops at.
+const MAX_RESEND_DELAY: Duration = Duration::from_secs(2);
+
+/// Number of times a packet can be resent without being acked before
+/// giving up on the connection.
+pub const MAX_RESENDS: u32 = 5;
+
+/// A reliable packet that hasn't been acked yet.
+#[derive(Debug)]
+struct Unacked<P> {
+    packet: P,
+    /// Number of times the packet was sent before this.
+    resends: u32,
+    /// Time to resend the packet at if it still hasn't been acked.
+    deadline: Instant,
+}
+
+/// Reliable packets that haven't been acked yet, by the sequence
+/// number they were last sent with.
+///
+/// Packets get resent as soon as acks show they were lost, but if
+/// acks stop coming altogether that never happens, so each packet
+/// also has a deadline to be resent by. The delay doubles with every
+/// resend, and after `MAX_RESENDS` the connection is given up on.
+#[derive(Debug)]
+pub struct Reliable<P> {
+    packets: HashMap<u32, Unacked<P>>,
+}
+
+/// Error for when a packet went unacked after `MAX_RESENDS` resends.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct GaveUp;
+
+impl<P> Default for Reliable<P> {
+    fn default() -> Reliable<P> {
+        Reliable {
+            packets: HashMap::new(),
+        }
+    }
+}
+
+impl<P> Reliable<P> {
+    /// Keeps a packet that was sent with `sequence` until it's acked,
+    /// where `resends` is how many times it was sent before.
+    pub fn insert(
+        &mut self,
+        sequence: u32,
+        packet: P,
+        resends: u32,
+        rtt: Option<f32>,
+        now: Instant,
+    ) {
+        let deadline = now + resend_delay(rtt, resends);
+        self.packets.insert(
+            sequence,
+            Unacked {
+                packet,
+                resends,
+                deadline,
+            },
+        );
+    }
+
+    /// Forgets about a packet that was acked.
+    pub fn ack(&mut self, sequence: u32) {
+        self.packets.remove(&sequence);
+    }
+
+    /// Takes out a packet that was lost, along with how many times it
+    /// was sent before.
+    pub fn take(&mut self, sequence: u32) -> Option<(P, u32)> {
+        self.packets
+            .remove(&sequence)
+            .map(|unacked| (unacked.packet, unacked.resends))
+    }
+
+    /// Takes out every packet that's past its deadline, along with
+    /// how many times each was sent before.
+    pub fn take_overdue(
+        &mut self,
+        now: Instant,
+    ) -> Result<Vec<(P, u32)>, GaveUp> {
+        let overdue = self
+            .packets
+            .iter()
+            .filter(|(_, unacked)| unacked.deadline <= now)
+            .map(|(&sequence, unacked)| (sequence, unacked.resends))
+            .collect::<Vec<_>>();
+        if overdue.iter().any(|&(_, resends)| resends >= MAX_RESENDS) {
+            return Err(GaveUp);
+        }
+        Ok(overdue
+            .into_iter()
+            .filter_map(|(sequence, _)| self.take(sequence))
+            .collect())
+    }
+
+    /// Gets the number of packets waiting to be acked.
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+/// Gets how long to wait for an ack before resending a packet that's
+/// been sent `resends` times before.
+fn resend_delay(rtt: Option<f32>, resends: u32) -> Duration {
+    let base = rtt.map_or(DEFAULT_RESEND_DELAY, |rtt| {
+        Duration::from_secs_f32(2.0 * rtt.max(0.0)).max(MIN_RESEND_DELAY)
+    });
+    base.checked_mul(1 << resends.min(16))
+        .unwrap_or(MAX_RESEND_DELAY)
+        .min(MAX_RESEND_DELAY)
+}
+
+#[test]
+fn resends_back_off() {
+    let start = Instant::now();
+    let mut reliable = Reliable::default();
+    reliable.insert(0, "packet", 0, Some(0.1), start);
+    let mut resends = 0;
+    let mut now = start;
+    loop {
+        now += Duration::from_millis(10);
+        match reliable.take_overdue(now) {
+            Ok(overdue) => {
+                for (packet, sent) in overdue {
+                    assert_eq!(sent, resends);
+                    resends += 1;
+                    reliable.insert(resends, packet, resends, Some(0.1), now);
+                }
+            },
+            Err(GaveUp) => break,
+        }
+    }
+    assert_eq!(resends, MAX_RESENDS);
+    // 0.2, 0.4, 0.8, 1.6, 2, then 2 before giving up.
+    let elapsed = now.duration_since(start).as_secs_f32();
+    assert!(elapsed >= 7.0 && elapsed < 7.1, "gave up after {}", elapsed);
+}
+
+#[test]
+fn acked_packets_not_resent() {
+    let now = Instant::now();
+    let mut reliable = Reliable::default();
+    reliable.insert(3, "packet", 0, None, now);
+    reliable.ack(3);
+    assert!(reliable.is_empty());
+    assert_eq!(reliable.take_overdue(now + MAX_RESEND_DELAY), Ok(vec![]));
+}
@@ -1,44 +1,132 @@
 use crate::game::{
+    approach_cursor,
     clamp_cursor,
+    client::STATE_REQUEST_INTERVAL,
+    quantize::{Quantization, QuantizedSnapshot, DEFAULT_VELOCITY_RANGE},
     server::Game,
     Event,
     GameSettings,
     GetPlayer,
+    LeaveReason,
     PlayerId,
     RoundState,
     Snapshot,
     StaticPlayerState,
+    CHARGING_CURSOR_SPEED,
+    DASH_DISTANCE,
+    MAX_MESSAGE_LENGTH,
+    MAX_NAME_LENGTH,
 };
 use crate::networking::client::ClientPacket;
-use crate::networking::connection::{Connection, HEADER_BYTES};
+use crate::networking::clock::{Clock, Timeout, Timer};
+use crate::networking::connection::{
+    peek_connection_id,
+    Acks,
+    Connection,
+    HEADER_BYTES,
+};
+use crate::networking::crypto::{KeyExchange, PublicKey, Side};
 use crate::networking::event_loop::{run_event_loop, EventHandler};
-use crate::networking::tick::Interval;
+use crate::networking::master::{self, MasterPacket, HEARTBEAT_RATE};
+use crate::networking::metrics::ServerMetrics;
+use crate::networking::packet_log::{self, Direction, Endpoint, PacketKind};
+use crate::networking::peer::{self, Peer};
+use crate::networking::persist::SavedState;
+use crate::networking::query::QueryLimiter;
+use crate::networking::reliable::Reliable;
+use crate::networking::server_settings::ServerSettings;
+use crate::networking::tick::{Interval, Load, TickBudget};
+use crate::networking::transport::{self, Transport};
 use crate::networking::{
+    check_message,
+    check_name,
+    check_players,
+    check_static_states,
+    CheckLimits,
     Error,
+    PingSchedule,
     RecvError,
     RttEstimator,
     CONNECTION_TIMEOUT,
     MAX_PACKET_SIZE,
     PING_RATE,
     SNAPSHOT_RATE,
+    SPECTATOR_ID,
+    VERSION,
 };
+use crate::swarm;
 use crossbeam::channel::{self, Receiver, Sender};
 use log::{debug, error, info, trace, warn};
-use mio::net::UdpSocket;
 use mio::{self, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
-use mio_extras::timer::{self, Timeout, Timer};
 use nalgebra::Point2;
+use parking_lot::Mutex;
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, VecDeque};
-use std::io;
-use std::io::Cursor;
+use smallvec::SmallVec;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::f32::consts::PI;
 use std::iter;
-use std::net::SocketAddr;
+use std::mem;
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
 pub const TICK_RATE: Duration = Duration::from_millis(15);
 
+/// Maximum plausible cursor speed, in units per second.
+const MAX_CURSOR_SPEED: f32 = 20.0;
+
+/// How often the status is sent to the `ServerHandle`.
+const STATUS_RATE: Duration = Duration::from_millis(250);
+
+/// Minimum elapsed time in seconds assumed between two input packets
+/// when validating cursor movement.
+///
+/// Input packets can arrive bunched up due to jitter, so this keeps
+/// legitimate movement from getting flagged.
+const MIN_INPUT_ELAPSED: f32 = 0.05;
+
+/// Cursor speed of bots, in units per second.
+const BOT_SPEED: f32 = 0.5;
+
+/// Largest payload that events get batched into, leaving room for
+/// the header and an encryption tag.
+const MAX_BATCH_BYTES: usize = MAX_PACKET_SIZE - HEADER_BYTES - 32;
+
+/// Most packets held back for a client that's over its bandwidth
+/// budget. Past this, the oldest one goes out anyway instead of being
+/// dropped.
+const MAX_DEFERRED: usize = 64;
+
+/// Seconds worth of data that a client's bandwidth budget can save up
+/// for bursts.
+const BANDWIDTH_BURST: f32 = 0.25;
+
+/// Distance from a client's ball, as a fraction of the arena's
+/// radius, past which other balls are only sent to it in some
+/// snapshots.
+const INTEREST_RADIUS: f32 = 0.75;
+
+/// Balls that are further than `INTEREST_RADIUS` away are sent in
+/// every this many snapshots.
+const FAR_SNAPSHOT_INTERVAL: u32 = 2;
+
+/// How often the backup client is sent the state it needs to take
+/// over hosting.
+const BACKUP_RATE: Duration = Duration::from_secs(1);
+
+/// Number of times each client is told about the new host, since
+/// there's no waiting around for acks while shutting down.
+const MIGRATE_COPIES: usize = 3;
+
+/// How long a client's connection is kept around after it leaves, so
+/// resent `Disconnect` packets still get acknowledged. This outlasts
+/// all of the client's retries.
+const DEPARTED_TIME: Duration = Duration::from_secs(2);
+
 const SOCKET: Token = Token(0);
 const TIMER: Token = Token(1);
 const SHUTDOWN: Token = Token(2);
@@ -48,12 +136,18 @@ enum TimeoutState {
     SendSnapshot,
     Tick,
     Ping,
+    UpdateStatus,
+    Heartbeat,
+    SyncBackup,
     LostConnection(SocketAddr),
+    ForgetDeparted(SocketAddr),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ServerPacket {
     Event(Event),
+    /// Several events batched into one datagram, in order.
+    Events(Vec<Event>),
     Ping,
     Pong(u32),
     Handshake {
@@ -63,54 +157,457 @@ pub enum ServerPacket {
         round: RoundState,
         round_duration: f32,
         snapshot: Snapshot,
+        /// Hue assigned to the new player, which is their preferred
+        /// one unless it was too close to someone else's.
+        hue: f32,
+        /// Public key to finish the key exchange with, if everything
+        /// after this is encrypted.
+        key: Option<PublicKey>,
+        /// Id to put in the header of every packet after this, so the
+        /// connection can be recognized from a new address.
+        connection_id: u32,
+        /// Seed the server's game draws its random numbers from.
+        seed: u64,
+    },
+    /// Summary of the game, in response to `ClientPacket::Query`.
+    Info {
+        /// Name the server is listed under, or empty if it isn't
+        /// listed.
+        name: String,
+        players: u16,
+        round: RoundState,
+        /// Version of the game the server is running.
+        version: String,
     },
+    /// Reply to a handshake that isn't going to be accepted, with the
+    /// reason why.
+    ConnectionDenied(String),
+    /// Reply to `ClientPacket::Disconnect`, so the client can stop
+    /// resending it.
+    DisconnectAck,
+    /// State for the backup client to carry on the game with, if the
+    /// server shuts down. Nothing only admins should see, like the ban
+    /// list, is included.
+    Backup(SavedState),
+    /// The server is shutting down, and the game carries on at the
+    /// address the backup client said it's ready to host on, or `None`
+    /// for the backup itself to take over hosting.
+    Migrate(Option<SocketAddr>),
+}
+
+/// Options for running a server.
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    /// Number of cursor movement violations after which a client gets
+    /// kicked, or `None` to never kick.
+    pub max_movement_violations: Option<u32>,
+    /// Number of players after which new connections are denied, or
+    /// `None` for no limit.
+    pub max_players: Option<u16>,
+    /// Whether to encrypt every connection, ignoring clients that
+    /// don't support it. The key exchange isn't authenticated, so this
+    /// only stops passive eavesdroppers, not someone who can tamper
+    /// with the handshake.
+    pub encrypt: bool,
+    /// Cap on how fast data is sent to each client, in kilobits per
+    /// second, or `None` for no limit.
+    pub max_client_kbps: Option<u32>,
+    /// Master server to list the game on, or `None` to keep it
+    /// unlisted.
+    pub announce: Option<master::Announcement>,
+    /// File to save the scores, settings and ban list to on shutdown,
+    /// or `None` to not save them.
+    pub state_file: Option<PathBuf>,
+    /// Whether to start from the state saved in `state_file`.
+    pub resume: bool,
+    /// State to start from instead of `state_file`, like the state
+    /// handed over by a host that left.
+    pub resume_state: Option<SavedState>,
+    /// Whether to hand the game over to one of the players on
+    /// shutdown, for servers that are hosted by a player.
+    pub migrate: bool,
+    /// Settings file that's applied on startup and whenever the server
+    /// is told to reload, or `None` to only use what's given here.
+    pub settings_file: Option<PathBuf>,
+    /// Secret for admin connections, which the settings file can
+    /// replace.
+    pub admin_secret: Option<String>,
+    /// Number of bots to start with, unless the settings file says
+    /// otherwise.
+    pub bots: u16,
+    /// Fastest velocity along each axis that snapshots can carry, in
+    /// units per second, or `None` for `DEFAULT_VELOCITY_RANGE`.
+    pub velocity_range: Option<f32>,
+    /// Seed for everything random in the game, or `None` to pick one
+    /// at random. The same seed and the same inputs play out the same
+    /// way.
+    pub seed: Option<u64>,
+    /// Where the server gets the time from, which tests can step
+    /// through by hand.
+    pub clock: Clock,
+}
+
+/// Live control of a running server through its `ServerHandle`.
+#[derive(Clone, Debug)]
+pub enum ServerCommand {
+    /// Start a round right away, if there are enough players.
+    StartRound,
+    /// Set every player's win count back to zero.
+    ResetScores,
+    /// Change `ServerConfig::max_players`. Players already connected
+    /// are never kicked.
+    SetMaxPlayers(Option<u16>),
+    /// Disconnect a player right away.
+    Kick(PlayerId),
+    /// Disconnect a player and stop anyone from their address from
+    /// connecting again.
+    Ban(PlayerId),
+    /// Let an address connect again.
+    Unban(IpAddr),
+    /// Replace the game settings, which get clamped the same way as
+    /// settings from clients.
+    SetSettings(GameSettings),
+    /// Show a message to every player.
+    Say(String),
+    /// Freeze or unfreeze the game, without disconnecting anyone.
+    SetPaused(bool),
+    /// Send the current status back right away, rather than waiting
+    /// for the next periodic one.
+    Status(Sender<ServerStatus>),
+    /// Apply `ServerConfig::settings_file` again, replying with what's
+    /// wrong with it if it couldn't be.
+    Reload(Sender<Result<(), String>>),
+    /// Add or remove targets, which are bots that never move, until
+    /// there are this many.
+    SetTargets(u16),
+    /// Bring everyone back to life where they started, and go back to
+    /// waiting for a round.
+    Reset,
+}
+
+/// Snapshot of what a server is doing, sent periodically to its
+/// `ServerHandle`.
+#[derive(Clone, Debug)]
+pub struct ServerStatus {
+    pub round: RoundState,
+    pub settings: GameSettings,
+    pub max_players: Option<u16>,
+    pub paused: bool,
+    pub clients: Vec<ClientStatus>,
+    /// Address the game would carry on at if the server shut down now,
+    /// once a backup client has one ready.
+    pub backup: Option<SocketAddr>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientStatus {
+    pub addr: SocketAddr,
+    pub player: PlayerId,
+    pub name: String,
+    pub wins: u32,
+    pub alive: bool,
+    /// Round trip time in seconds, if it's been measured yet.
+    pub rtt: Option<f32>,
+    pub violations: u32,
+    /// Reliable packets that haven't been acked yet.
+    pub unacked: usize,
 }
 
 struct Client {
-    player: PlayerId,
+    /// Player for this client, or `None` for a spectator.
+    player: Option<PlayerId>,
     connection: Connection,
+    /// Public key sent back in the handshake, if the connection is
+    /// encrypted.
+    key: Option<PublicKey>,
     timeout: Timeout,
     rtt: RttEstimator,
+    ping_schedule: PingSchedule,
     last_input: u32,
-    reliable: HashMap<u32, ServerPacket>,
+    /// Time the last input packet was received.
+    last_input_time: Instant,
+    /// Last accepted cursor position.
+    cursor: Point2<f32>,
+    /// Running count of implausible cursor movements, which goes
+    /// back down with valid movements.
+    violations: u32,
+    /// Number of dashes the client had sent as of its last input.
+    dashes: u32,
+    /// Time the static state was last resent, so a client can't make
+    /// the server send it every packet.
+    last_state_request: Option<Instant>,
+    reliable: Reliable<ServerPacket>,
+    /// Budget for data sent to this client, if there's a limit.
+    bandwidth: Option<Bandwidth>,
+    /// Packets held back while the client was over its bandwidth
+    /// budget, which go out in order once it's caught up.
+    deferred: VecDeque<ServerPacket>,
+    /// Port this client has bound to take over hosting on, if it's the
+    /// backup.
+    backup_port: Option<u16>,
+}
+
+/// What someone trying to connect sent in their handshake.
+struct HandshakeRequest {
+    cursor: Point2<f32>,
+    name: String,
+    hue: Option<f32>,
+    /// Public key to encrypt the connection with, if the client
+    /// supports it.
+    key: Option<PublicKey>,
+    spectate: bool,
+}
+
+/// Token bucket limiting how fast data gets sent to one client.
+#[derive(Debug)]
+struct Bandwidth {
+    /// Bytes per second.
+    rate: f32,
+    /// Bytes that can be sent right now. Packets that can't be
+    /// skipped get sent regardless, so this can go negative.
+    available: f32,
+    updated: Instant,
 }
 
 pub struct Server {
-    socket: UdpSocket,
+    transport: Box<dyn Transport>,
     timer: Timer<TimeoutState>,
     recv_buffer: [u8; MAX_PACKET_SIZE],
     send_queue: VecDeque<(SocketAddr, Vec<u8>)>,
     clients: HashMap<SocketAddr, Client>,
+    /// Connections of clients that just left, for acknowledging their
+    /// resent disconnects.
+    departed: HashMap<SocketAddr, (Connection, Timeout)>,
     game: Game,
+    config: ServerConfig,
     send_tick: Interval,
+    /// Next snapshot timeout, or `None` while paused.
+    snapshot_timeout: Option<Timeout>,
+    /// Number of snapshots sent so far, which decides when far away
+    /// balls are sent.
+    snapshots_sent: u32,
     game_tick: Interval,
     ping: Interval,
+    /// Whether the game is frozen. Ticks still happen so commands
+    /// get handled, but the game doesn't move and no snapshots are
+    /// sent.
+    paused: bool,
+    /// Addresses that get denied when they try to connect.
+    banned: HashSet<IpAddr>,
+    query_limiter: QueryLimiter,
+    /// Client that takes over hosting if the server shuts down, when
+    /// `ServerConfig::migrate` is set.
+    backup: Option<SocketAddr>,
+    /// Snapshot interval when the server isn't overloaded.
+    snapshot_rate: Duration,
+    bots: Vec<Bot>,
+    /// Bots that stay where they were put, to practice hitting.
+    targets: Vec<Bot>,
+    admin_secret: Arc<Mutex<Option<String>>>,
+    /// What the server started with, which anything left out of the
+    /// settings file goes back to.
+    default_settings: ServerSettings,
+    /// How long game ticks take, including the snapshots sent since
+    /// the last one.
+    budget: TickBudget,
+    /// Time spent sending snapshots and events since the last game
+    /// tick.
+    snapshot_work: Duration,
+    /// Events waiting to be sent to every client, which get batched
+    /// together at the end of each poll event.
+    pending_events: Vec<Event>,
     poll: Poll,
     done: Sender<Option<Error>>,
+    commands: Receiver<ServerCommand>,
+    status: Sender<ServerStatus>,
+    metrics: Arc<ServerMetrics>,
     _shutdown: Registration,
 }
 
 pub struct ServerHandle {
     shutdown: SetReadiness,
+    commands: Sender<ServerCommand>,
     pub done: Receiver<Option<Error>>,
+    /// Latest status of the server. Old statuses are dropped if this
+    /// isn't read.
+    pub status: Receiver<ServerStatus>,
+    pub metrics: Arc<ServerMetrics>,
+    /// Current secret for admin connections, if there is one.
+    pub admin_secret: Arc<Mutex<Option<String>>>,
+}
+
+/// A player that the server moves around itself, to fill out a game.
+struct Bot {
+    player: PlayerId,
+    cursor: Point2<f32>,
+    /// Direction that the cursor is wandering in, in radians.
+    heading: f32,
 }
 
 /// Launches a server bound to a particular address.
-pub fn host(addr: SocketAddr) -> Result<(ServerHandle, JoinHandle<()>), Error> {
-    let (done_tx, done_rx) = channel::bounded(1);
-    let (shutdown_registration, shutdown_set_readiness) = Registration::new2();
-    let server = Server::new(addr, shutdown_registration, done_tx)?;
+pub fn host(
+    addr: SocketAddr,
+    config: ServerConfig,
+) -> Result<(ServerHandle, JoinHandle<()>), Error> {
+    let socket = transport::bind_udp(addr)?;
+    host_with(Box::new(socket), config)
+}
+
+/// Hosts a server that sends and receives over some transport.
+pub fn host_with(
+    transport: Box<dyn Transport>,
+    config: ServerConfig,
+) -> Result<(ServerHandle, JoinHandle<()>), Error> {
+    let (server, handle) = start_server(transport, config)?;
     let thread = thread::spawn(move || {
         run_event_loop(server);
         info!("server done");
     });
-    Ok((
-        ServerHandle {
-            shutdown: shutdown_set_readiness,
-            done: done_rx,
-        },
-        thread,
-    ))
+    Ok((handle, thread))
+}
+
+/// Creates a server, without starting its event loop.
+pub fn start_server(
+    transport: Box<dyn Transport>,
+    config: ServerConfig,
+) -> Result<(Server, ServerHandle), Error> {
+    let (done_tx, done_rx) = channel::bounded(1);
+    let (commands_tx, commands_rx) = channel::unbounded();
+    let (status_tx, status_rx) = channel::bounded(1);
+    let (shutdown_registration, shutdown_set_readiness) = Registration::new2();
+    let server = Server::new(
+        transport,
+        config,
+        shutdown_registration,
+        done_tx,
+        commands_rx,
+        status_tx,
+    )?;
+    let metrics = Arc::clone(&server.metrics);
+    let admin_secret = Arc::clone(&server.admin_secret);
+    let handle = ServerHandle {
+        shutdown: shutdown_set_readiness,
+        commands: commands_tx,
+        done: done_rx,
+        status: status_rx,
+        metrics,
+        admin_secret,
+    };
+    Ok((server, handle))
+}
+
+/// Builds the handshake reply for a newly joined player, or a
+/// spectator if there's no player.
+fn handshake(
+    game: &Game,
+    id: Option<PlayerId>,
+    key: Option<PublicKey>,
+    connection_id: u32,
+) -> ServerPacket {
+    ServerPacket::Handshake {
+        id: id.unwrap_or(SPECTATOR_ID),
+        settings: game.settings,
+        players: game
+            .players()
+            .map(|(id, player)| (id, player.static_state().clone()))
+            .collect(),
+        round: game.round,
+        round_duration: game.round_duration,
+        snapshot: game.snapshot(),
+        hue: id.map_or(0.0, |id| game.players[&id].hue()),
+        key,
+        connection_id,
+        seed: game.seed(),
+    }
+}
+
+/// Gets the key that a player's wins are saved under, which goes by
+/// their address as well as their name, so that nobody can take over
+/// someone else's score just by joining with their name.
+/// Checks whether a client at `ip` could take over hosting. Clients on
+/// the in-process network, or on the host's own machine, leave along
+/// with it, and link-local addresses can't be reached by the rest.
+fn can_be_backup(ip: IpAddr) -> bool {
+    let link_local = match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    };
+    !ip.is_unspecified() && !ip.is_loopback() && !link_local && !is_host_ip(ip)
+}
+
+/// Checks whether `ip` is one of this machine's own addresses, by
+/// asking which one it would send from to get there. Nothing is
+/// actually sent.
+fn is_host_ip(ip: IpAddr) -> bool {
+    let any = match ip {
+        IpAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+    net::UdpSocket::bind((any, 0))
+        .and_then(|socket| {
+            socket.connect((ip, 9))?;
+            socket.local_addr()
+        })
+        .map_or(false, |local| local.ip() == ip)
+}
+
+fn wins_key(name: &str, ip: IpAddr) -> String {
+    format!("{}@{}", name, ip)
+}
+
+/// Builds a summary of the game for server queries.
+fn game_info(game: &Game, config: &ServerConfig) -> ServerPacket {
+    let name = config.announce.as_ref().map_or("", |announce| &announce.name);
+    ServerPacket::Info {
+        name: name.chars().take(MAX_NAME_LENGTH).collect(),
+        players: game.players.len() as u16,
+        round: game.round,
+        version: VERSION.to_owned(),
+    }
+}
+
+/// Leaves balls that are far from `center` out of all but every
+/// `FAR_SNAPSHOT_INTERVAL`th snapshot, where `count` is the number of
+/// snapshots sent before this one.
+///
+/// Which snapshots a ball is sent in is staggered by player id, so
+/// the far away balls don't all show up in the same ones.
+fn interest_snapshot(
+    snapshot: &Snapshot,
+    center: Point2<f32>,
+    settings: &GameSettings,
+    count: u32,
+) -> Snapshot {
+    let radius = INTEREST_RADIUS * settings.bounds_radius;
+    let players = snapshot.players.iter().filter(|&(&id, state)| {
+        let near = nalgebra::distance_squared(&state.ball.position, &center) <=
+            radius * radius;
+        near || count.wrapping_add(u32::from(id)) % FAR_SNAPSHOT_INTERVAL == 0
+    });
+    Snapshot::new(snapshot.tick, players.map(|(&id, &state)| (id, state)))
+}
+
+/// Splits events into batches that each fit in one datagram, keeping
+/// them in order.
+pub fn batch_events(events: Vec<Event>) -> Vec<Vec<Event>> {
+    // Variant and length prefix of `ServerPacket::Events`.
+    const BATCH_OVERHEAD: u64 = 4 + 8;
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut size = BATCH_OVERHEAD;
+    for event in events {
+        let event_size = bincode::serialized_size(&event).unwrap();
+        if !batch.is_empty() && size + event_size > MAX_BATCH_BYTES as u64 {
+            batches.push(mem::replace(&mut batch, Vec::new()));
+            size = BATCH_OVERHEAD;
+        }
+        size += event_size;
+        batch.push(event);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
 }
 
 impl ServerHandle {
@@ -120,6 +617,14 @@ impl ServerHandle {
             warn!("failed to signal shutdown to server: {}", err)
         }
     }
+
+    /// Sends a command to the server, which gets handled on its next
+    /// tick.
+    pub fn command(&self, command: ServerCommand) {
+        if let Err(err) = self.commands.send(command) {
+            warn!("failed to send {:?} to server, it's shut down", err.0);
+        }
+    }
 }
 
 impl Drop for ServerHandle {
@@ -132,58 +637,268 @@ impl Drop for ServerHandle {
 impl ServerPacket {
     fn reliable(&self) -> bool {
         match self {
-            ServerPacket::Event(event) => {
-                match event {
-                    Event::NewPlayer {
-                        ..
-                    } => true,
-                    Event::RemovePlayer(_) => true,
-                    Event::RoundState(_) => true,
-                    Event::Settings(_) => true,
-                    Event::Snapshot(_) => false,
-                }
-            },
+            ServerPacket::Event(event) => event_reliable(event),
+            ServerPacket::Events(events) => events.iter().any(event_reliable),
             ServerPacket::Handshake {
                 ..
             } => true,
             ServerPacket::Ping => false,
             ServerPacket::Pong(_) => false,
+            ServerPacket::Info {
+                ..
+            } => false,
+            // The client will just retry the handshake if it's lost.
+            ServerPacket::ConnectionDenied(_) => false,
+            // Likewise with disconnecting.
+            ServerPacket::DisconnectAck => false,
+            // A newer one comes along soon anyways.
+            ServerPacket::Backup(_) => false,
+            // Nothing's left to resend it once it's been sent.
+            ServerPacket::Migrate(_) => false,
+        }
+    }
+
+    /// The part of this packet that needs to be kept around in case
+    /// it's lost, if any.
+    pub fn reliable_part(&self) -> Option<ServerPacket> {
+        match self {
+            // Don't hold onto snapshots and the like.
+            ServerPacket::Events(events) => {
+                let events = events
+                    .iter()
+                    .filter(|event| event_reliable(event))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if events.is_empty() {
+                    None
+                } else {
+                    Some(ServerPacket::Events(events))
+                }
+            },
+            _ if self.reliable() => Some(self.clone()),
+            _ => None,
         }
     }
 
-    fn resend(&self, game: &Game) -> bool {
+    /// Returns what's still worth resending of a lost packet, if
+    /// anything, given the current round state, settings and whether
+    /// the game is paused.
+    pub fn resend(
+        self,
+        round: RoundState,
+        settings: &GameSettings,
+        paused: bool,
+    ) -> Option<ServerPacket> {
         match self {
-            ServerPacket::Event(Event::RoundState(round)) => {
-                // Only resend if the round state hasn't
-                // changed again since it was sent.
-                *round == game.round
+            ServerPacket::Event(event) => {
+                if event_resend(&event, round, settings, paused) {
+                    Some(ServerPacket::Event(event))
+                } else {
+                    None
+                }
             },
-            ServerPacket::Event(Event::Settings(settings)) => {
-                // Only resend if the round state hasn't
-                // changed again since it was sent.
-                &game.settings == settings
+            ServerPacket::Events(events) => {
+                let events = events
+                    .into_iter()
+                    .filter(|event| {
+                        event_resend(event, round, settings, paused)
+                    })
+                    .collect::<Vec<_>>();
+                if events.is_empty() {
+                    None
+                } else {
+                    Some(ServerPacket::Events(events))
+                }
             },
             // Everything else is simple.
-            _ => self.reliable(),
+            packet => {
+                if packet.reliable() {
+                    Some(packet)
+                } else {
+                    None
+                }
+            },
         }
     }
 }
 
-impl Client {
-    /// Encodes a packet and possibly saves it in the reliable packet
-    /// buffer.
-    ///
-    /// Returns the sequence number.
-    fn encode(&mut self, packet: &ServerPacket) -> (Vec<u8>, u32) {
-        let size = bincode::serialized_size(packet).unwrap() as usize;
-        let mut data = Vec::with_capacity(size + HEADER_BYTES);
-        let sequence = self.connection.send_header(&mut data);
-        bincode::serialize_into(&mut data, packet).unwrap();
+fn event_reliable(event: &Event) -> bool {
+    match event {
+        Event::NewPlayer {
+            ..
+        } => true,
+        Event::RemovePlayer {
+            ..
+        } => true,
+        Event::Respawn(_) => true,
+        Event::Afk(_) => true,
+        Event::Kill {
+            ..
+        } => true,
+        Event::RoundState(_) => true,
+        Event::Settings(_) => true,
+        Event::Snapshot(_) => false,
+        // A newer one comes along soon anyways.
+        Event::PlayerPing(_) => false,
+        // The client will just request this again if it's lost.
+        Event::StaticState(_) => false,
+        Event::ResetScores => true,
+        Event::Message(_) => true,
+        Event::Paused(_) => true,
+    }
+}
 
-        if packet.reliable() {
-            self.reliable.insert(sequence, packet.clone());
+fn event_resend(
+    event: &Event,
+    round: RoundState,
+    settings: &GameSettings,
+    paused: bool,
+) -> bool {
+    match event {
+        Event::RoundState(sent) => {
+            // Only resend if the round state hasn't
+            // changed again since it was sent.
+            *sent == round
+        },
+        Event::Settings(sent) => {
+            // Only resend if the settings haven't
+            // changed again since they were sent.
+            sent == settings
+        },
+        Event::Paused(sent) => *sent == paused,
+        // Everything else is simple.
+        _ => event_reliable(event),
+    }
+}
+
+impl CheckLimits for ServerPacket {
+    fn check_limits(&self) -> Result<(), RecvError> {
+        match self {
+            ServerPacket::Event(event) => check_event(event),
+            ServerPacket::Events(events) => {
+                events.iter().try_for_each(check_event)
+            },
+            ServerPacket::Handshake {
+                players,
+                snapshot,
+                ..
+            } => {
+                check_static_states(players.values())?;
+                check_players(snapshot.players.len())
+            },
+            ServerPacket::Info {
+                name,
+                ..
+            } => check_name(name),
+            ServerPacket::Backup(saved) => {
+                saved.wins.keys().try_for_each(|name| check_name(name))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+fn check_event(event: &Event) -> Result<(), RecvError> {
+    match event {
+        Event::NewPlayer {
+            static_state,
+            ..
+        } => check_name(&static_state.name),
+        Event::Snapshot(snapshot) => check_players(snapshot.player_count()),
+        Event::PlayerPing(pings) => check_players(pings.len()),
+        Event::StaticState(players) => check_static_states(players.values()),
+        Event::Message(message) => check_message(message),
+        _ => Ok(()),
+    }
+}
+
+impl PacketKind for ServerPacket {
+    fn kind(&self) -> &'static str {
+        match self {
+            ServerPacket::Event(event) => {
+                match event {
+                    Event::RoundState(_) => "Event::RoundState",
+                    Event::Settings(_) => "Event::Settings",
+                    Event::NewPlayer {
+                        ..
+                    } => "Event::NewPlayer",
+                    Event::RemovePlayer {
+                        ..
+                    } => "Event::RemovePlayer",
+                    Event::Respawn(_) => "Event::Respawn",
+                    Event::Afk(_) => "Event::Afk",
+                    Event::Kill {
+                        ..
+                    } => "Event::Kill",
+                    Event::Snapshot(_) => "Event::Snapshot",
+                    Event::PlayerPing(_) => "Event::PlayerPing",
+                    Event::StaticState(_) => "Event::StaticState",
+                    Event::ResetScores => "Event::ResetScores",
+                    Event::Message(_) => "Event::Message",
+                    Event::Paused(_) => "Event::Paused",
+                }
+            },
+            ServerPacket::Events(_) => "Events",
+            ServerPacket::Ping => "Ping",
+            ServerPacket::Pong(_) => "Pong",
+            ServerPacket::Handshake {
+                ..
+            } => "Handshake",
+            ServerPacket::Info {
+                ..
+            } => "Info",
+            ServerPacket::ConnectionDenied(_) => "ConnectionDenied",
+            ServerPacket::DisconnectAck => "DisconnectAck",
+            ServerPacket::Backup(_) => "Backup",
+            ServerPacket::Migrate(_) => "Migrate",
         }
-        (data, sequence)
+    }
+}
+
+impl Bandwidth {
+    fn new(kbps: u32, now: Instant) -> Bandwidth {
+        let rate = kbps as f32 * 1000.0 / 8.0;
+        Bandwidth {
+            rate,
+            available: rate * BANDWIDTH_BURST,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f32();
+        self.available = (self.available + elapsed * self.rate)
+            .min(self.rate * BANDWIDTH_BURST);
+        self.updated = now;
+    }
+
+    fn spend(&mut self, bytes: usize, now: Instant) {
+        self.refill(now);
+        self.available -= bytes as f32;
+    }
+
+    /// Checks whether everything that can be skipped should be.
+    fn exhausted(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.available <= 0.0
+    }
+}
+
+impl Peer for Client {
+    fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    fn ping_schedule(&mut self) -> &mut PingSchedule {
+        &mut self.ping_schedule
+    }
+
+    fn reliable(&mut self) -> &mut Reliable<ServerPacket> {
+        &mut self.reliable
     }
 }
 
@@ -216,9 +931,16 @@ impl EventHandler for Server {
                         TimeoutState::SendSnapshot => self.send_snapshot(),
                         TimeoutState::Tick => self.game_tick(),
                         TimeoutState::Ping => self.send_ping(),
+                        TimeoutState::UpdateStatus => self.send_status(),
+                        TimeoutState::Heartbeat => self.send_heartbeat(),
+                        TimeoutState::SyncBackup => self.sync_backup(),
                         TimeoutState::LostConnection(addr) => {
                             info!("client from {} timed out", addr);
-                            self.remove_client(&addr)
+                            self.remove_client(&addr, LeaveReason::Lost)
+                        },
+                        TimeoutState::ForgetDeparted(addr) => {
+                            self.departed.remove(&addr);
+                            Ok(())
                         },
                     };
 
@@ -231,33 +953,54 @@ impl EventHandler for Server {
             },
             SHUTDOWN => {
                 info!("server received shutdown from handle");
+                if let Some(master) = self
+                    .config
+                    .announce
+                    .as_ref()
+                    .map(|announce| announce.master)
+                {
+                    self.send_master_packet(master, &MasterPacket::Unregister);
+                }
+                self.hand_over();
+                self.save_state();
                 let _ = self.done.send(None);
                 return true;
             },
             _ => unreachable!(),
         }
 
+        if let Err(err) = self.flush_events() {
+            error!("error on sending server events: {}", err);
+            let _ = self.done.send(Some(err));
+            return true;
+        }
         false
     }
+
+    fn panicked(&mut self, err: Error) {
+        let _ = self.done.try_send(Some(err));
+    }
 }
 
 impl Server {
     pub fn new(
-        addr: SocketAddr,
+        transport: Box<dyn Transport>,
+        mut config: ServerConfig,
         shutdown: Registration,
         done: Sender<Option<Error>>,
+        commands: Receiver<ServerCommand>,
+        status: Sender<ServerStatus>,
     ) -> Result<Server, Error> {
-        let socket = UdpSocket::bind(&addr).map_err(|err| {
-            Error::BindSocket {
-                addr,
-                err,
-            }
-        })?;
-        let mut timer = timer::Builder::default()
-            .tick_duration(Duration::from_millis(5))
-            .build();
+        if config.encrypt && !KeyExchange::supported() {
+            error!(
+                "built without the encryption feature, so connections won't \
+                 be encrypted"
+            );
+            config.encrypt = false;
+        }
+        let mut timer = config.clock.timer();
         let poll = Poll::new().map_err(Error::poll)?;
-        poll.register(&socket, SOCKET, Ready::readable(), PollOpt::edge())
+        poll.register(&*transport, SOCKET, Ready::readable(), PollOpt::edge())
             .map_err(Error::poll)?;
         poll.register(&timer, TIMER, Ready::readable(), PollOpt::edge())
             .map_err(Error::poll)?;
@@ -266,52 +1009,134 @@ impl Server {
 
         // Set timeout for the first tick. All subsequent ticks will
         // be generated from Server::send_tick.
-        let send_tick = Interval::new(SNAPSHOT_RATE);
-        timer.set_timeout(send_tick.interval(), TimeoutState::SendSnapshot);
-        let game_tick = Interval::new(TICK_RATE);
+        let now = config.clock.now();
+        let send_tick = Interval::new(SNAPSHOT_RATE, now);
+        let snapshot_timeout =
+            timer.set_timeout(send_tick.interval(), TimeoutState::SendSnapshot);
+        let game_tick = Interval::new(TICK_RATE, now);
         timer.set_timeout(game_tick.interval(), TimeoutState::Tick);
-        let ping = Interval::new(PING_RATE);
+        let ping = Interval::new(PING_RATE, now);
         timer.set_timeout(ping.interval(), TimeoutState::Ping);
+        timer.set_timeout(STATUS_RATE, TimeoutState::UpdateStatus);
+        if config.announce.is_some() {
+            // Get listed right away, rather than after the first
+            // heartbeat interval.
+            timer.set_timeout(Duration::from_secs(0), TimeoutState::Heartbeat);
+        }
+        if config.migrate {
+            timer.set_timeout(BACKUP_RATE, TimeoutState::SyncBackup);
+        }
+
+        let seed = config.seed.unwrap_or_else(|| thread_rng().gen());
+        info!("game seed is {}", seed);
+        let mut game = Game::new(seed);
+        let mut banned = HashSet::new();
+        let mut saved = config.resume_state.take();
+        let resume_from = config.state_file.as_ref().filter(|_| config.resume);
+        if saved.is_some() {
+            info!("resuming from the state handed over by the last host");
+        } else if let Some(path) = resume_from {
+            match SavedState::load(path) {
+                Ok(state) => {
+                    info!("resuming from {}", path.display());
+                    saved = Some(state);
+                },
+                Err(err) => {
+                    warn!("failed to resume from {}: {}", path.display(), err)
+                },
+            }
+        }
+        if let Some(saved) = saved {
+            game.settings = saved.settings.clamp();
+            game.restore_wins(saved.wins);
+            banned = saved.banned.into_iter().collect();
+        }
 
-        Ok(Server {
-            socket,
+        let admin_secret = Arc::new(Mutex::new(config.admin_secret.clone()));
+        let settings_file = config.settings_file.clone();
+        let bots = config.bots;
+        let default_settings = ServerSettings {
+            tick_ms: Some(TICK_RATE.as_millis() as u64),
+            snapshot_ms: Some(SNAPSHOT_RATE.as_millis() as u64),
+            max_players: config.max_players,
+            bots: Some(bots),
+            admin_password: config.admin_secret.clone(),
+            game: Some(game.settings),
+        };
+        let mut server = Server {
+            transport,
             timer,
             recv_buffer: [0; MAX_PACKET_SIZE],
             send_queue: VecDeque::new(),
             clients: HashMap::new(),
-            game: Game::default(),
+            departed: HashMap::new(),
+            game,
+            config,
             send_tick,
+            snapshot_timeout: Some(snapshot_timeout),
+            snapshots_sent: 0,
             game_tick,
             ping,
+            paused: false,
+            banned,
+            query_limiter: QueryLimiter::default(),
+            backup: None,
+            snapshot_rate: SNAPSHOT_RATE,
+            bots: Vec::new(),
+            targets: Vec::new(),
+            admin_secret,
+            default_settings,
+            budget: TickBudget::new(TICK_RATE),
+            snapshot_work: Duration::from_secs(0),
+            pending_events: Vec::new(),
             poll,
             done,
+            commands,
+            status,
+            metrics: Arc::default(),
             _shutdown: shutdown,
-        })
+        };
+        server.set_bots(bots as usize)?;
+        // A missing file is fine at startup, there just isn't anything
+        // to change yet.
+        if let Some(path) = settings_file.filter(|path| path.exists()) {
+            match ServerSettings::load(&path) {
+                Ok(settings) => server.apply_settings(settings)?,
+                Err(err) => warn!("{}, starting without it", err),
+            }
+        }
+        Ok(server)
     }
 
     fn socket_readable(&mut self) -> Result<(), Error> {
-        // Attempt to read packets until recv_from returns WouldBlock.
-        loop {
-            match self.socket.recv_from(&mut self.recv_buffer) {
-                Err(err) => {
-                    if err.kind() != io::ErrorKind::WouldBlock {
-                        error!("error receiving packet on server: {}", err);
-                        // TODO figure out if any of these are non-fatal
-                        return Err(Error::SocketRead(err));
-                    } else {
-                        break;
-                    }
-                },
-                Ok((bytes_read, addr)) => {
-                    if let Err(err) = self.on_recv(addr, bytes_read)? {
-                        error!(
-                            "failed to receive packet from {} ({:?}): {}",
-                            addr,
-                            &self.recv_buffer[..bytes_read],
-                            err
-                        );
-                    }
-                },
+        while let Some((bytes_read, addr)) =
+            peer::recv(&*self.transport, &mut self.recv_buffer)?
+        {
+            self.metrics.received(bytes_read);
+            let from_master = self
+                .config
+                .announce
+                .as_ref()
+                .map_or(false, |announce| announce.master == addr);
+            if from_master &&
+                master::is_master_packet(&self.recv_buffer[..bytes_read])
+            {
+                self.on_master_recv(addr, bytes_read);
+                continue;
+            }
+            packet_log::record::<ClientPacket>(
+                Endpoint::Server,
+                Direction::Received,
+                addr,
+                &self.recv_buffer[..bytes_read],
+            );
+            if let Err(err) = self.on_recv(addr, bytes_read)? {
+                error!(
+                    "failed to receive packet from {} ({:?}): {}",
+                    addr,
+                    &self.recv_buffer[..bytes_read],
+                    err
+                );
             }
         }
 
@@ -319,36 +1144,41 @@ impl Server {
     }
 
     fn socket_writable(&mut self) -> Result<(), Error> {
-        while let Some(&(ref addr, ref packet)) = self.send_queue.front() {
-            match self.socket.send_to(packet, addr) {
-                Err(err) => {
-                    if err.kind() != io::ErrorKind::WouldBlock {
-                        error!("error sending packet to {} ({})", addr, err);
-                        let (addr, _) = self.send_queue.pop_front().unwrap();
-                        // Disconnect any client that errors.
-                        self.remove_client(&addr)?;
-                    } else {
-                        break;
-                    }
-                },
+        let now = self.config.clock.now();
+        let metrics = &self.metrics;
+        let clients = &mut self.clients;
+        let failed = peer::send_queued(
+            &*self.transport,
+            &mut self.send_queue,
+            |addr, packet, bytes_written| {
+                metrics.sent(bytes_written);
+                let bandwidth = clients
+                    .get_mut(&addr)
+                    .and_then(|client| client.bandwidth.as_mut());
+                if let Some(bandwidth) = bandwidth {
+                    bandwidth.spend(bytes_written, now);
+                }
+                packet_log::record::<ServerPacket>(
+                    Endpoint::Server,
+                    Direction::Sent,
+                    addr,
+                    packet,
+                );
                 // Pretty sure this never happens?
-                Ok(bytes_written) => {
-                    if bytes_written < packet.len() {
-                        error!(
-                            "only wrote {} out of {} bytes for packet to {}: \
-                             {:?}",
-                            bytes_written,
-                            packet.len(),
-                            addr,
-                            packet
-                        )
-                    }
-                },
-            }
-
-            // Only pop after making sure it didn't return
-            // WouldBlock.
-            self.send_queue.pop_front();
+                if bytes_written < packet.len() {
+                    error!(
+                        "only wrote {} out of {} bytes for packet to {}: {:?}",
+                        bytes_written,
+                        packet.len(),
+                        addr,
+                        packet
+                    )
+                }
+            },
+        );
+        // Disconnect any client that errors.
+        for addr in failed {
+            self.remove_client(&addr, LeaveReason::Lost)?;
         }
 
         if self.send_queue.is_empty() {
@@ -364,121 +1194,933 @@ impl Server {
         events: E,
     ) -> Result<(), Error> {
         for event in events {
-            self.broadcast(&ServerPacket::Event(event))?;
+            if let Event::RoundState(RoundState::Round) = event {
+                self.metrics.rounds.fetch_add(1, Ordering::Relaxed);
+            }
+            self.pending_events.push(event);
         }
         Ok(())
     }
 
+    /// Sends every queued event, batching as many into each datagram
+    /// as fit.
+    fn flush_events(&mut self) -> Result<(), Error> {
+        if self.pending_events.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let events = mem::replace(&mut self.pending_events, Vec::new());
+        for mut batch in batch_events(events) {
+            let packet = if batch.len() == 1 {
+                ServerPacket::Event(batch.pop().unwrap())
+            } else {
+                ServerPacket::Events(batch)
+            };
+            self.broadcast(&packet)?;
+        }
+        self.snapshot_work += start.elapsed();
+        Ok(())
+    }
+
     fn send_ping(&mut self) -> Result<(), Error> {
-        let now = Instant::now();
+        let now = self.config.clock.now();
         let (_, interval) = self.ping.next(now);
         self.timer.set_timeout(interval, TimeoutState::Ping);
+        // Snapshots stop while paused, so held back packets also go
+        // out here.
+        self.send_deferred(now);
 
         for (&addr, client) in self.clients.iter_mut() {
-            let (packet, sequence) = client.encode(&ServerPacket::Ping);
+            if !client.ping_schedule.ping(now) {
+                continue;
+            }
+            let (packet, sequence) = client.encode(&ServerPacket::Ping, now);
             self.send_queue.push_back((addr, packet));
             client.rtt.ping(sequence, now);
         }
         self.reregister_socket(true)?;
 
+        // Let everyone know how everyone else's connection is doing.
+        let pings = self
+            .clients
+            .values()
+            .filter_map(|client| {
+                let player = client.player?;
+                let rtt = client.rtt.rtt()?;
+                Some((player, (rtt * 1000.0).min(65535.0) as u16))
+            })
+            .collect();
+        self.send_events(iter::once(Event::PlayerPing(pings)))?;
+
         Ok(())
     }
 
     fn send_snapshot(&mut self) -> Result<(), Error> {
+        if self.paused {
+            // This fired just as the game was paused, and resuming
+            // schedules the next one.
+            return Ok(());
+        }
         // Send a snapshot to all connected clients.
-        let now = Instant::now();
+        let now = self.config.clock.now();
         let (_, interval) = self.send_tick.next(now);
-        self.timer.set_timeout(interval, TimeoutState::SendSnapshot);
+        self.snapshot_timeout =
+            Some(self.timer.set_timeout(interval, TimeoutState::SendSnapshot));
 
+        let start = Instant::now();
         let snapshot = self.game.snapshot();
         trace!("sending snapshot: {:#?}", snapshot);
-        self.send_events(iter::once(Event::Snapshot(snapshot)))?;
+        let velocity_range =
+            self.config.velocity_range.unwrap_or(DEFAULT_VELOCITY_RANGE);
+        let quantization =
+            Quantization::new(&self.game.settings, velocity_range);
+        let full = QuantizedSnapshot::new(&snapshot, quantization);
+        if let Ok(size) = bincode::serialized_size(&full) {
+            self.metrics.snapshot_bytes.store(size as usize, Ordering::Relaxed);
+        }
+        // Every client gets their own snapshot, which has to come
+        // after any events that happened before it.
+        self.flush_events()?;
+        self.send_deferred(now);
+        let count = self.snapshots_sent;
+        self.snapshots_sent = count.wrapping_add(1);
+        for (&addr, client) in &mut self.clients {
+            // Like in `broadcast_filter`, snapshots are what gets
+            // skipped for clients over their bandwidth budget.
+            let exhausted = client
+                .bandwidth
+                .as_mut()
+                .map_or(false, |bandwidth| bandwidth.exhausted(now));
+            if exhausted {
+                trace!("skipping snapshot for {}", addr);
+                continue;
+            }
+            // Spectators don't have a ball, so they get everything.
+            let center = client
+                .player
+                .and_then(|id| snapshot.players.get(&id))
+                .map(|state| state.ball.position);
+            let packet = match center {
+                Some(center) => {
+                    let interest = interest_snapshot(
+                        &snapshot,
+                        center,
+                        &self.game.settings,
+                        count,
+                    );
+                    let quantized =
+                        QuantizedSnapshot::new(&interest, quantization);
+                    ServerPacket::Event(Event::Snapshot(quantized))
+                },
+                None => ServerPacket::Event(Event::Snapshot(full.clone())),
+            };
+            let (data, _) = client.encode(&packet, now);
+            self.send_queue.push_back((addr, data));
+        }
+        self.reregister_socket(true)?;
+        let elapsed = start.elapsed();
+        self.snapshot_work += elapsed;
+        let micros = elapsed.as_micros() as u64;
+        self.metrics.snapshot_micros.store(micros, Ordering::Relaxed);
+        self.metrics.snapshots.fetch_add(1, Ordering::Relaxed);
 
         Ok(())
     }
 
     fn game_tick(&mut self) -> Result<(), Error> {
-        let now = Instant::now();
+        let now = self.config.clock.now();
         let (dt, interval) = self.game_tick.next(now);
         let dt = dt.as_secs_f32();
         self.timer.set_timeout(interval, TimeoutState::Tick);
 
+        while let Ok(command) = self.commands.try_recv() {
+            self.handle_command(command)?;
+        }
+        self.resend_overdue(now)?;
+        if self.paused {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        self.move_bots(dt);
         let events = self.game.tick(dt);
         self.send_events(events)?;
+        let elapsed = start.elapsed();
+        let micros = elapsed.as_micros() as u64;
+        self.metrics.tick_micros.store(micros, Ordering::Relaxed);
+        self.metrics.ticks.fetch_add(1, Ordering::Relaxed);
+        self.metrics.players.store(self.player_count(), Ordering::Relaxed);
+
+        let work = elapsed +
+            mem::replace(&mut self.snapshot_work, Duration::from_secs(0));
+        match self.budget.record(work) {
+            // Snapshots are the most expensive thing that can be put
+            // off, so send them half as often until things calm down.
+            Some(Load::Overloaded) => {
+                warn!("server is overloaded, halving the snapshot rate");
+                self.send_tick.set_interval(self.snapshot_rate * 2);
+            },
+            Some(Load::Recovered) => {
+                info!("server recovered, restoring the snapshot rate");
+                self.send_tick.set_interval(self.snapshot_rate);
+            },
+            None => (),
+        }
+        self.metrics
+            .overloaded
+            .store(self.budget.overloaded(), Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn handle_command(&mut self, command: ServerCommand) -> Result<(), Error> {
+        info!("server received command {:?}", command);
+        match command {
+            ServerCommand::StartRound => {
+                let events = self.game.start_round();
+                self.send_events(events)
+            },
+            ServerCommand::ResetScores => {
+                let events = self.game.reset_scores();
+                self.send_events(events)
+            },
+            ServerCommand::SetMaxPlayers(max_players) => {
+                self.config.max_players = max_players;
+                Ok(())
+            },
+            ServerCommand::Kick(id) => {
+                match self.player_addr(id) {
+                    Some(addr) => {
+                        self.remove_client(&addr, LeaveReason::Kicked)
+                    },
+                    None => {
+                        warn!(
+                            "can't kick player {}, they aren't connected",
+                            id
+                        );
+                        Ok(())
+                    },
+                }
+            },
+            ServerCommand::Ban(id) => {
+                match self.player_addr(id) {
+                    Some(addr) => {
+                        info!("banning {}", addr.ip());
+                        self.banned.insert(addr.ip());
+                        self.remove_client(&addr, LeaveReason::Kicked)
+                    },
+                    None => {
+                        warn!("can't ban player {}, they aren't connected", id);
+                        Ok(())
+                    },
+                }
+            },
+            ServerCommand::Unban(ip) => {
+                if !self.banned.remove(&ip) {
+                    warn!("can't unban {}, it isn't banned", ip);
+                }
+                Ok(())
+            },
+            ServerCommand::SetSettings(settings) => {
+                let settings = settings.clamp();
+                self.game.settings = settings;
+                self.send_events(iter::once(Event::Settings(settings)))
+            },
+            ServerCommand::Say(message) => {
+                let message =
+                    message.chars().take(MAX_MESSAGE_LENGTH).collect();
+                self.send_events(iter::once(Event::Message(message)))
+            },
+            ServerCommand::Status(reply) => {
+                let _ = reply.send(self.status());
+                Ok(())
+            },
+            ServerCommand::SetPaused(paused) => self.set_paused(paused),
+            ServerCommand::SetTargets(count) => {
+                self.set_targets(count as usize)
+            },
+            ServerCommand::Reset => {
+                let events = self.game.reset();
+                self.send_events(events)
+            },
+            ServerCommand::Reload(reply) => {
+                let settings = match self.config.settings_file {
+                    Some(ref path) => ServerSettings::load(path),
+                    None => {
+                        Err("there's no settings file to reload".to_owned())
+                    },
+                };
+                match settings {
+                    Ok(settings) => {
+                        self.apply_settings(settings)?;
+                        let _ = reply.send(Ok(()));
+                    },
+                    Err(err) => {
+                        warn!("failed to reload settings: {}", err);
+                        let _ = reply.send(Err(err));
+                    },
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Applies whatever a settings file sets, which has already been
+    /// validated.
+    fn apply_settings(
+        &mut self,
+        settings: ServerSettings,
+    ) -> Result<(), Error> {
+        info!("applying server settings {:?}", settings);
+        let settings = settings.or(&self.default_settings);
+        if let Some(tick_rate) = settings.tick_rate() {
+            self.game_tick.set_interval(tick_rate);
+            self.budget = TickBudget::new(tick_rate);
+        }
+        if let Some(snapshot_rate) = settings.snapshot_rate() {
+            self.snapshot_rate = snapshot_rate;
+            self.send_tick.set_interval(snapshot_rate);
+        }
+        self.config.max_players = settings.max_players;
+        *self.admin_secret.lock() = settings.admin_password;
+        if let Some(game) = settings.game {
+            self.game.settings = game;
+            self.send_events(iter::once(Event::Settings(game)))?;
+        }
+        if let Some(bots) = settings.bots {
+            self.set_bots(bots as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Adds or removes bots until there are `count` of them.
+    fn set_bots(&mut self, count: usize) -> Result<(), Error> {
+        while self.bots.len() < count {
+            let name = format!("bot {}", self.bots.len() + 1);
+            let bot = self.add_bot(&name)?;
+            self.bots.push(bot);
+        }
+        while self.bots.len() > count {
+            let bot = self.bots.pop().unwrap();
+            let events = self.game.remove_player(bot.player, LeaveReason::Left);
+            self.send_events(events)?;
+        }
+        Ok(())
+    }
+
+    /// Adds or removes targets until there are `count` of them.
+    fn set_targets(&mut self, count: usize) -> Result<(), Error> {
+        while self.targets.len() < count {
+            let name = format!("target {}", self.targets.len() + 1);
+            let target = self.add_bot(&name)?;
+            self.targets.push(target);
+        }
+        while self.targets.len() > count {
+            let target = self.targets.pop().unwrap();
+            let events =
+                self.game.remove_player(target.player, LeaveReason::Left);
+            self.send_events(events)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a player for a bot, with its cursor somewhere random.
+    fn add_bot(&mut self, name: &str) -> Result<Bot, Error> {
+        let cursor = swarm::random_cursor(self.game.rng());
+        let (player, events) = self.game.add_player(cursor, name, None);
+        self.send_events(events)?;
+        Ok(Bot {
+            player,
+            cursor,
+            heading: self.game.rng().gen_range(0.0, 2.0 * PI),
+        })
+    }
+
+    fn move_bots(&mut self, dt: f32) {
+        for bot in &mut self.bots {
+            let cursor = swarm::wander(
+                bot.cursor,
+                &mut bot.heading,
+                BOT_SPEED,
+                dt,
+                self.game.rng(),
+            );
+            bot.cursor = clamp_cursor(cursor, &self.game.settings);
+            self.game.set_player_cursor(bot.player, bot.cursor);
+        }
+        // Targets don't move, but setting their cursor is still what
+        // brings them back to life between rounds.
+        for target in &self.targets {
+            self.game.set_player_cursor(target.player, target.cursor);
+        }
+    }
+
+    /// Finds the address of the client playing as a player.
+    fn player_addr(&self, id: PlayerId) -> Option<SocketAddr> {
+        self.clients
+            .iter()
+            .find(|(_, client)| client.player == Some(id))
+            .map(|(&addr, _)| addr)
+    }
+
+    /// Gets the scores, settings and ban list, to carry on the game
+    /// with later.
+    ///
+    /// Everyone still connected is saved along with anyone from a
+    /// previous run who never came back. Bots aren't saved, since they
+    /// don't come back.
+    fn saved_state(&self) -> SavedState {
+        let mut wins = self
+            .game
+            .saved_wins()
+            .map(|(key, &wins)| (key.clone(), wins))
+            .collect::<BTreeMap<_, _>>();
+        for (addr, client) in &self.clients {
+            if let Some(player) = client.player {
+                let state = &self.game.players[&player].static_state;
+                wins.insert(wins_key(&state.name, addr.ip()), state.wins);
+            }
+        }
+        let mut banned = self.banned.iter().cloned().collect::<Vec<_>>();
+        banned.sort();
+        SavedState {
+            banned,
+            settings: self.game.settings,
+            wins,
+        }
+    }
+
+    /// Writes the saved state to `ServerConfig::state_file`, if there
+    /// is one.
+    fn save_state(&self) {
+        let path = match self.config.state_file {
+            Some(ref path) => path,
+            None => return,
+        };
+        match self.saved_state().save(path) {
+            Ok(()) => info!("saved server state to {}", path.display()),
+            Err(err) => {
+                warn!(
+                    "failed to save server state to {}: {}",
+                    path.display(),
+                    err
+                )
+            },
+        }
+    }
+
+    /// Sends the backup client the state it needs to carry on the
+    /// game, picking a new backup first if the last one left.
+    fn sync_backup(&mut self) -> Result<(), Error> {
+        self.timer.set_timeout(BACKUP_RATE, TimeoutState::SyncBackup);
+
+        let clients = &self.clients;
+        let current = self.backup.filter(|addr| clients.contains_key(addr));
+        // Go for whoever has the best connection out of the players on
+        // other machines, since the rest leave along with the host.
+        let backup = current.or_else(|| {
+            clients
+                .iter()
+                .filter(|(addr, client)| {
+                    client.player.is_some() && can_be_backup(addr.ip())
+                })
+                .min_by_key(|(_, client)| {
+                    client
+                        .rtt
+                        .rtt()
+                        .map_or(u32::max_value(), |rtt| (rtt * 1000.0) as u32)
+                })
+                .map(|(&addr, _)| addr)
+        });
+        if backup != self.backup {
+            match backup {
+                Some(addr) => info!("{} is the backup host", addr),
+                None => info!("nobody is left to be the backup host"),
+            }
+            if let Some(old) =
+                self.backup.and_then(|old| self.clients.get_mut(&old))
+            {
+                old.backup_port = None;
+            }
+            self.backup = backup;
+        }
+        let addr = match backup {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+
+        let mut saved = self.saved_state();
+        // Whoever takes over is just another player, so they don't get
+        // to see who's banned.
+        saved.banned.clear();
+        let fits = bincode::serialized_size(&saved)
+            .map_or(false, |size| size <= MAX_BATCH_BYTES as u64);
+        if !fits {
+            debug!("server state is too big to send to the backup host");
+            return Ok(());
+        }
+        let now = self.config.clock.now();
+        let client = self.clients.get_mut(&addr).unwrap();
+        let (packet, _) = client.encode(&ServerPacket::Backup(saved), now);
+        self.send_queue.push_back((addr, packet));
+        self.reregister_socket(true)
+    }
+
+    /// Tells every client where the game carries on as the server
+    /// shuts down, if there's a backup host to hand it over to.
+    ///
+    /// The packets are sent straight away, since the event loop stops
+    /// before the send queue would be flushed.
+    fn hand_over(&mut self) {
+        let (backup, host) = match self.backup_host() {
+            Some(backup) => backup,
+            None => return,
+        };
+        info!("handing the game over to {}", host);
+        let now = self.config.clock.now();
+        for (&addr, client) in &mut self.clients {
+            let packet = if addr == backup {
+                ServerPacket::Migrate(None)
+            } else {
+                ServerPacket::Migrate(Some(host))
+            };
+            for _ in 0..MIGRATE_COPIES {
+                let (data, _) = client.encode(&packet, now);
+                match self.transport.send_to(&data, addr) {
+                    Ok(bytes_written) => self.metrics.sent(bytes_written),
+                    Err(err) => {
+                        warn!("failed to hand over to {}: {}", addr, err);
+                        break;
+                    },
+                }
+            }
+        }
+    }
+
+    /// Gets the backup client's address, and the address it's ready to
+    /// carry on hosting the game at, if it's said it is.
+    fn backup_host(&self) -> Option<(SocketAddr, SocketAddr)> {
+        let backup = self.backup?;
+        let port = self.clients.get(&backup)?.backup_port?;
+        Some((backup, SocketAddr::new(backup.ip(), port)))
+    }
+
+    fn set_paused(&mut self, paused: bool) -> Result<(), Error> {
+        if paused == self.paused {
+            return Ok(());
+        }
+        self.paused = paused;
+        if paused {
+            info!("pausing the game");
+            if let Some(timeout) = self.snapshot_timeout.take() {
+                self.timer.cancel_timeout(&timeout);
+            }
+        } else {
+            info!("resuming the game");
+            // Neither interval should count the time spent paused, or
+            // the first tick back would try to cover all of it.
+            let now = self.config.clock.now();
+            self.game_tick.restart(now);
+            self.send_tick.restart(now);
+            self.snapshot_timeout = Some(self.timer.set_timeout(
+                self.send_tick.interval(),
+                TimeoutState::SendSnapshot,
+            ));
+        }
+        self.send_events(iter::once(Event::Paused(paused)))
+    }
+
+    fn send_status(&mut self) -> Result<(), Error> {
+        self.timer.set_timeout(STATUS_RATE, TimeoutState::UpdateStatus);
+
+        // Nobody might be reading this, so don't block if they aren't.
+        let _ = self.status.try_send(self.status());
 
         Ok(())
     }
 
+    fn send_heartbeat(&mut self) -> Result<(), Error> {
+        self.timer.set_timeout(HEARTBEAT_RATE, TimeoutState::Heartbeat);
+
+        if let Some(announce) = self.config.announce.clone() {
+            let packet = MasterPacket::Heartbeat {
+                name: announce.name.chars().take(MAX_NAME_LENGTH).collect(),
+                players: self.player_count() as u16,
+                max_players: self.config.max_players,
+            };
+            self.send_master_packet(announce.master, &packet);
+        }
+        Ok(())
+    }
+
+    /// Sends a master server protocol packet straight away. It's sent
+    /// from the game socket so the master server sees the game's
+    /// address, and failures are only logged since the next heartbeat
+    /// or punch tries again anyway.
+    fn send_master_packet(&mut self, to: SocketAddr, packet: &MasterPacket) {
+        match self.transport.send_to(&master::encode(packet), to) {
+            Ok(bytes_written) => self.metrics.sent(bytes_written),
+            Err(err) => warn!("failed to send {:?} to {}: {}", packet, to, err),
+        }
+    }
+
+    /// Handles a packet from the master server, which only ever asks
+    /// for a punch through NAT toward a peer that's about to connect.
+    fn on_master_recv(&mut self, master: SocketAddr, bytes_read: usize) {
+        let peer = match master::decode(&self.recv_buffer[..bytes_read]) {
+            Ok(MasterPacket::Introduction {
+                peer,
+            }) => peer,
+            Ok(MasterPacket::Relaying {
+                port,
+            }) => SocketAddr::new(master.ip(), port),
+            Ok(packet) => {
+                debug!("ignoring {:?} from master server", packet);
+                return;
+            },
+            Err(err) => {
+                warn!("bad packet from master server: {}", err);
+                return;
+            },
+        };
+        debug!("punching toward {}", peer);
+        self.send_master_packet(peer, &MasterPacket::Punch);
+    }
+
+    fn status(&self) -> ServerStatus {
+        let game = &self.game;
+        let mut clients = self
+            .clients
+            .iter()
+            .filter_map(|(&addr, client)| {
+                let id = client.player?;
+                let player = game.players.get(&id)?;
+                Some(ClientStatus {
+                    addr,
+                    player: id,
+                    name: player.static_state().name.clone(),
+                    wins: player.static_state().wins,
+                    alive: player.state().alive(),
+                    rtt: client.rtt.rtt(),
+                    violations: client.violations,
+                    unacked: client.reliable.len(),
+                })
+            })
+            .collect::<Vec<_>>();
+        clients.sort_by_key(|client| client.player);
+        ServerStatus {
+            round: game.round,
+            settings: game.settings,
+            max_players: self.config.max_players,
+            paused: self.paused,
+            clients,
+            backup: self.backup_host().map(|(_, host)| host),
+        }
+    }
+
+    /// Number of connected clients that have a player, rather than
+    /// just spectating.
+    fn player_count(&self) -> usize {
+        self.clients.values().filter(|client| client.player.is_some()).count()
+    }
+
     fn new_client(
         &mut self,
         addr: SocketAddr,
-        connection: Connection,
-        cursor: Point2<f32>,
+        mut connection: Connection,
+        request: HandshakeRequest,
     ) -> Result<(), Error> {
-        info!("new player from {}", addr);
+        let HandshakeRequest {
+            cursor,
+            name,
+            hue,
+            key: client_key,
+            spectate,
+        } = request;
+        if self.banned.contains(&addr.ip()) {
+            info!("denying {:?} from {}, they're banned", name, addr);
+            return self.deny_client(addr, connection, "banned");
+        }
+        if let Some(max_players) = self.config.max_players {
+            // Spectators don't take up a player's place.
+            if !spectate && self.player_count() >= max_players as usize {
+                info!("denying {:?} from {}, server is full", name, addr);
+                return self.deny_client(addr, connection, "server full");
+            }
+        }
 
+        let mut key = None;
+        if self.config.encrypt {
+            let (client_key, exchange) = match (client_key, KeyExchange::new())
+            {
+                (Some(client_key), Some(exchange)) => (client_key, exchange),
+                _ => {
+                    info!(
+                        "denying {:?} from {}, client doesn't support \
+                         encryption",
+                        name, addr
+                    );
+                    return self.deny_client(
+                        addr,
+                        connection,
+                        "server requires encryption",
+                    );
+                },
+            };
+            key = Some(exchange.public_key());
+            connection.cipher = exchange.finish(&client_key, Side::Server);
+            if connection.cipher.is_none() {
+                info!("ignoring {:?} from {}, invalid key", name, addr);
+                return Ok(());
+            }
+        }
         let timeout = self.timer.set_timeout(
             CONNECTION_TIMEOUT,
             TimeoutState::LostConnection(addr),
         );
 
-        let (player_id, events) =
-            self.game.add_player(clamp_cursor(cursor, &self.game.settings));
-        self.send_events(events)?;
+        if let Some((_, timeout)) = self.departed.remove(&addr) {
+            self.timer.cancel_timeout(&timeout);
+        }
+        connection.id = self.new_connection_id();
+        let cursor = clamp_cursor(cursor, &self.game.settings);
+        let player_id = if spectate {
+            info!("new spectator {:?} from {}", name, addr);
+            None
+        } else {
+            info!("new player {:?} from {}", name, addr);
+            let key = wins_key(&name, addr.ip());
+            let (player_id, events) =
+                self.game.add_returning_player(cursor, &name, hue, &key);
+            // The new player gets all this in the handshake, so it has
+            // to go out before they're added.
+            self.send_events(events)?;
+            self.flush_events()?;
+            Some(player_id)
+        };
 
         // Now start processing this client.
+        let now = self.config.clock.now();
         let client = self.clients.entry(addr).or_insert(Client {
             timeout,
             player: player_id,
             connection,
+            key,
             rtt: RttEstimator::default(),
+            ping_schedule: PingSchedule::default(),
             last_input: 0,
-            reliable: HashMap::new(),
+            last_input_time: now,
+            cursor,
+            violations: 0,
+            dashes: 0,
+            last_state_request: None,
+            reliable: Reliable::default(),
+            bandwidth: self
+                .config
+                .max_client_kbps
+                .map(|kbps| Bandwidth::new(kbps, now)),
+            deferred: VecDeque::new(),
+            backup_port: None,
         });
 
         // Send handshake message to the new client.
-        let packet = ServerPacket::Handshake {
-            id: player_id,
-            settings: self.game.settings,
-            players: self
-                .game
-                .players()
-                .map(|(id, player)| (id, player.static_state().clone()))
-                .collect(),
-            round: self.game.round,
-            round_duration: self.game.round_duration,
-            snapshot: self.game.snapshot(),
-        };
-        let (packet, _) = client.encode(&packet);
+        let packet =
+            handshake(&self.game, player_id, key, client.connection.id);
+        let (packet, _) = client.encode(&packet, now);
         self.send_queue.push_back((addr, packet));
+        if self.paused {
+            let packet = ServerPacket::Event(Event::Paused(true));
+            let (packet, _) = client.encode(&packet, now);
+            self.send_queue.push_back((addr, packet));
+        }
         self.reregister_socket(true)?;
 
         Ok(())
     }
 
-    fn remove_client(&mut self, addr: &SocketAddr) -> Result<(), Error> {
-        if let Some(client) = self.clients.remove(addr) {
-            info!("player {} from {} left", client.player, addr);
-            let events = self.game.remove_player(client.player);
-            self.send_events(events)?;
+    /// Picks a random connection id that isn't in use.
+    fn new_connection_id(&self) -> u32 {
+        let mut rng = thread_rng();
+        loop {
+            let id = rng.gen();
+            if id != 0 &&
+                self.clients
+                    .values()
+                    .all(|client| client.connection.id != id)
+            {
+                break id;
+            }
         }
+    }
 
+    /// Moves a known connection over to a new address, if a packet
+    /// from that address carries its id.
+    ///
+    /// This happens when a client's address changes, like when they
+    /// switch networks or a NAT mapping expires.
+    fn try_migrate(&mut self, addr: SocketAddr, bytes_read: usize) {
+        let packet = &self.recv_buffer[..bytes_read];
+        let id = match peek_connection_id(packet) {
+            Some(id) => id,
+            None => return,
+        };
+        let old_addr = match self
+            .clients
+            .iter()
+            .find(|(_, client)| client.connection.id == id)
+        {
+            Some((&old_addr, _)) => old_addr,
+            None => return,
+        };
+        // Only trust the id if the rest of the packet checks out, and
+        // it's newer than anything from the old address, since anyone
+        // on the path could replay an old packet. This decodes with a
+        // copy so the real connection sees it just once.
+        let mut connection = self.clients[&old_addr].connection.clone();
+        let latest = connection.acks.latest();
+        let fresh = match connection.decode::<ClientPacket>(packet) {
+            Ok((_, sequence, _, _, duplicate)) => {
+                !duplicate && sequence > latest
+            },
+            Err(_) => false,
+        };
+        if !fresh {
+            return;
+        }
+
+        info!("connection {:08x} moved from {} to {}", id, old_addr, addr);
+        let client = self.clients.remove(&old_addr).unwrap();
+        // The timeout still points at the old address, but it's reset
+        // as soon as this packet is handled.
+        self.clients.insert(addr, client);
+        for (queued_addr, _) in &mut self.send_queue {
+            if *queued_addr == old_addr {
+                *queued_addr = addr;
+            }
+        }
+    }
+
+    /// Tells someone trying to connect that they won't be accepted,
+    /// without keeping any state around for them.
+    fn deny_client(
+        &mut self,
+        addr: SocketAddr,
+        mut connection: Connection,
+        reason: &str,
+    ) -> Result<(), Error> {
+        let packet = ServerPacket::ConnectionDenied(reason.to_owned());
+        let (packet, _) = connection.encode_plain(&packet);
+        self.send_queue.push_back((addr, packet));
+        self.reregister_socket(true)
+    }
+
+    /// Resends reliable packets that have gone unacked for too long,
+    /// and drops clients that never ack them.
+    fn resend_overdue(&mut self, now: Instant) -> Result<(), Error> {
+        let (round, settings, paused) =
+            (self.game.round, &self.game.settings, self.paused);
+        let gave_up = peer::resend_overdue(
+            &mut self.clients,
+            &mut self.send_queue,
+            now,
+            |packet| packet.resend(round, settings, paused),
+        );
+        for addr in gave_up {
+            info!("client from {} never acked a reliable packet", addr);
+            self.remove_client(&addr, LeaveReason::Lost)?;
+        }
+        if !self.send_queue.is_empty() {
+            self.reregister_socket(true)?;
+        }
         Ok(())
     }
 
+    fn remove_client(
+        &mut self,
+        addr: &SocketAddr,
+        reason: LeaveReason,
+    ) -> Result<(), Error> {
+        let how = match reason {
+            LeaveReason::Left => "left",
+            LeaveReason::Lost => "lost connection",
+            LeaveReason::Kicked => "was kicked",
+        };
+        let client = match self.clients.remove(addr) {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        self.timer.cancel_timeout(&client.timeout);
+        let forget = self
+            .timer
+            .set_timeout(DEPARTED_TIME, TimeoutState::ForgetDeparted(*addr));
+        if let Some((_, timeout)) =
+            self.departed.insert(*addr, (client.connection, forget))
+        {
+            self.timer.cancel_timeout(&timeout);
+        }
+        match client.player {
+            Some(player) => {
+                info!("player {} from {} {}", player, addr, how);
+                let events = self.game.remove_player(player, reason);
+                self.send_events(events)?;
+            },
+            None => info!("spectator from {} {}", addr, how),
+        }
+
+        Ok(())
+    }
+
+    /// Acknowledges a `Disconnect` resent by a client that's already
+    /// been removed, since the first acknowledgement may have been
+    /// lost. Returns whether the packet was one.
+    fn ack_departed(&mut self, addr: SocketAddr, bytes_read: usize) -> bool {
+        let connection = match self.departed.get_mut(&addr) {
+            Some((connection, _)) => connection,
+            None => return false,
+        };
+        let packet = &self.recv_buffer[..bytes_read];
+        match connection.decode(packet) {
+            Ok((ClientPacket::Disconnect, ..)) => {
+                debug!("resending disconnect ack to {}", addr);
+                let (packet, _) =
+                    connection.encode(&ServerPacket::DisconnectAck);
+                self.send_queue.push_back((addr, packet));
+                true
+            },
+            _ => false,
+        }
+    }
+
     fn on_recv(
         &mut self,
         addr: SocketAddr,
         bytes_read: usize,
     ) -> Result<Result<(), RecvError>, Error> {
+        let now = self.config.clock.now();
         let mut reregister = false;
 
         // Reset timeout.
         if bytes_read > MAX_PACKET_SIZE {
             return Ok(Err(RecvError::PacketTooLarge(bytes_read)));
         }
+        trace!(
+            "got packet from {}: {:?}",
+            addr,
+            &self.recv_buffer[..bytes_read]
+        );
+        if !self.clients.contains_key(&addr) {
+            if self.ack_departed(addr, bytes_read) {
+                self.reregister_socket(true)?;
+                return Ok(Ok(()));
+            }
+            self.try_migrate(addr, bytes_read);
+        }
         let packet = &self.recv_buffer[..bytes_read];
-        trace!("got packet from {}: {:?}", addr, &packet);
+        // Copied out so resending doesn't borrow all of `self` while a
+        // client is borrowed.
+        let round = self.game.round;
+        let settings = &self.game.settings;
+        let paused = self.paused;
         match self.clients.get_mut(&addr) {
             Some(client) => {
                 // Reset timeout.
@@ -489,91 +2131,284 @@ impl Server {
                 );
 
                 // Existing player.
-                let (packet, sequence, acks, lost) =
-                    match client.connection.decode(Cursor::new(packet)) {
-                        Ok(result) => result,
-                        Err(err) => return Ok(Err(err)),
-                    };
+                let decoded = match client.connection.decode(packet) {
+                    // Handshakes are never encrypted, so one the client
+                    // is retrying because our reply was lost can't be
+                    // read with the key.
+                    Err(RecvError::Unencrypted) => {
+                        match Connection::default().decode(packet) {
+                            Ok((
+                                handshake @ ClientPacket::Handshake {
+                                    ..
+                                },
+                                sequence,
+                                ..,
+                            )) => {
+                                Ok((
+                                    handshake,
+                                    sequence,
+                                    Acks::default(),
+                                    SmallVec::new(),
+                                    false,
+                                ))
+                            },
+                            _ => Err(RecvError::Unencrypted),
+                        }
+                    },
+                    decoded => decoded,
+                };
+                let (packet, sequence, acks, lost, duplicate) = match decoded {
+                    Ok(result) => result,
+                    Err(err) => return Ok(Err(err)),
+                };
+                if duplicate {
+                    trace!(
+                        "skipping duplicate packet {} from {}",
+                        sequence,
+                        addr
+                    );
+                    return Ok(Ok(()));
+                }
 
                 // Remove acked packets from the reliable packet
                 // buffer.
                 for ack in acks.iter() {
-                    client.reliable.remove(&ack);
+                    client.reliable.ack(ack);
                 }
 
                 // Possibly resend any lost packets.
                 for lost in lost.into_iter() {
-                    if let Some(packet) = client.reliable.remove(&lost) {
-                        if packet.resend(&self.game) {
-                            debug!(
-                                "resending lost packet to {:?}: {:?}",
-                                addr, packet
-                            );
-                            let (packet, _) = client.encode(&packet);
-                            self.send_queue.push_back((addr, packet));
-                            reregister = true;
-                        }
+                    let packet = client.reliable.take(lost).and_then(
+                        |(packet, resends)| {
+                            packet
+                                .resend(round, settings, paused)
+                                .map(|packet| (packet, resends))
+                        },
+                    );
+                    if let Some((packet, resends)) = packet {
+                        debug!(
+                            "resending lost packet to {:?}: {:?}",
+                            addr, packet
+                        );
+                        let (packet, _) =
+                            client.encode_resend(&packet, resends + 1, now);
+                        self.send_queue.push_back((addr, packet));
+                        reregister = true;
                     }
                 }
 
                 match packet {
                     ClientPacket::Input(input) => {
-                        // Ignore out of order input packets.
-                        if sequence > client.last_input {
+                        // Ignore out of order input packets, and any
+                        // from spectators, who have nothing to move.
+                        let player = client
+                            .player
+                            .filter(|_| sequence > client.last_input);
+                        if let Some(player) = player {
                             client.last_input = sequence;
-                            self.game.set_player_cursor(
-                                client.player,
-                                clamp_cursor(input.cursor, &self.game.settings),
-                            );
+                            let elapsed = now
+                                .duration_since(client.last_input_time)
+                                .as_secs_f32()
+                                .max(MIN_INPUT_ELAPSED);
+                            client.last_input_time = now;
+
+                            // A new dash lets the cursor jump that much
+                            // further, unless it's still cooling down.
+                            let dashed = input.dashes != client.dashes &&
+                                self.game.dash(player);
+                            client.dashes = input.dashes;
+                            let dash = if dashed {
+                                DASH_DISTANCE
+                            } else {
+                                0.0
+                            };
+
+                            // Limit the cursor to moving a plausible
+                            // distance since the last input.
+                            let cursor =
+                                clamp_cursor(input.cursor, &self.game.settings);
+                            let delta = cursor - client.cursor;
+                            let max_delta = MAX_CURSOR_SPEED * elapsed + dash;
+                            let cursor = if delta.norm() > max_delta {
+                                debug!(
+                                    "player {} moved cursor too fast ({} \
+                                     units in {} secs)",
+                                    player,
+                                    delta.norm(),
+                                    elapsed
+                                );
+                                client.violations += 1;
+                                client.cursor + delta.normalize() * max_delta
+                            } else {
+                                client.violations =
+                                    client.violations.saturating_sub(1);
+                                cursor
+                            };
+                            // Charging slows the cursor down, which
+                            // clients do themselves, so going over
+                            // isn't counted as a violation.
+                            let cursor = if input.charging {
+                                approach_cursor(
+                                    client.cursor,
+                                    cursor,
+                                    CHARGING_CURSOR_SPEED * elapsed + dash,
+                                )
+                            } else {
+                                cursor
+                            };
+                            client.cursor = cursor;
+                            self.game
+                                .set_player_charging(player, input.charging);
+                            self.game.set_player_cursor(player, cursor);
+
+                            let kick = self
+                                .config
+                                .max_movement_violations
+                                .map(|max| client.violations > max)
+                                .unwrap_or(false);
+                            if kick {
+                                warn!(
+                                    "kicking player {} from {} for repeated \
+                                     movement violations",
+                                    player, addr
+                                );
+                                self.remove_client(&addr, LeaveReason::Kicked)?;
+                            }
                         }
                     },
+                    // Spectators can't change anything.
+                    ClientPacket::Settings(_) if client.player.is_none() => {
+                        debug!("ignoring settings from spectator {}", addr);
+                    },
                     ClientPacket::Settings(settings) => {
+                        let valid = settings.validate();
+                        if !valid {
+                            warn!(
+                                "clamping invalid settings from {}: {:?}",
+                                addr, settings
+                            );
+                        }
+                        let settings = settings.clamp();
                         // Update the server settings.
                         self.game.settings = settings;
 
-                        // Forward this change to the other clients.
+                        // Forward this change to the other clients. If
+                        // the settings had to be clamped, the sender
+                        // needs the corrected version too.
                         let packet =
                             ServerPacket::Event(Event::Settings(settings));
                         self.broadcast_filter(&packet, |(&client_addr, _)| {
-                            addr != client_addr
+                            !valid || addr != client_addr
                         })?;
                     },
                     ClientPacket::Handshake {
                         ..
                     } => {
-                        warn!(
-                            "received a second handshake packet from {:?}",
-                            addr
-                        )
+                        // The client is retrying because our reply was
+                        // lost, so send it again.
+                        debug!("resending handshake to {}", addr);
+                        let packet = handshake(
+                            &self.game,
+                            client.player,
+                            client.key,
+                            client.connection.id,
+                        );
+                        let (packet, _) = client.encode(&packet, now);
+                        self.send_queue.push_back((addr, packet));
+                        reregister = true;
+                    },
+                    ClientPacket::Query => {
+                        let info = game_info(&self.game, &self.config);
+                        let (packet, _) = client.encode(&info, now);
+                        self.send_queue.push_back((addr, packet));
+                        reregister = true;
+                    },
+                    ClientPacket::RequestState => {
+                        if client.last_state_request.map_or(false, |last| {
+                            now.duration_since(last) < STATE_REQUEST_INTERVAL
+                        }) {
+                            trace!("ignoring state request from {}", addr);
+                            return Ok(Ok(()));
+                        }
+                        client.last_state_request = Some(now);
+                        debug!("resending static state to {}", addr);
+                        let players = self
+                            .game
+                            .players()
+                            .map(|(id, player)| {
+                                (id, player.static_state().clone())
+                            })
+                            .collect();
+                        let packet =
+                            ServerPacket::Event(Event::StaticState(players));
+                        let (packet, _) = client.encode(&packet, now);
+                        self.send_queue.push_back((addr, packet));
+                        reregister = true;
                     },
                     ClientPacket::Disconnect => {
-                        self.remove_client(&addr)?;
+                        let (packet, _) =
+                            client.encode(&ServerPacket::DisconnectAck, now);
+                        self.send_queue.push_back((addr, packet));
+                        reregister = true;
+                        self.remove_client(&addr, LeaveReason::Left)?;
                     },
                     ClientPacket::Ping => {
                         let (packet, _) =
-                            client.encode(&ServerPacket::Pong(sequence));
+                            client.encode(&ServerPacket::Pong(sequence), now);
                         self.send_queue.push_back((addr, packet));
                         reregister = true;
                     },
                     ClientPacket::Pong(sequence) => {
-                        client.rtt.pong(sequence);
+                        client.rtt.pong(sequence, now);
+                    },
+                    ClientPacket::BackupReady(port) => {
+                        if self.backup == Some(addr) {
+                            if client.backup_port != Some(port) {
+                                info!("backup host is ready on port {}", port);
+                            }
+                            client.backup_port = Some(port);
+                        }
                     },
                 }
             },
             None => {
                 // New player.
                 let mut connection = Connection::default();
-                let (packet, ..) = match connection.decode(Cursor::new(packet))
-                {
+                let (packet, ..) = match connection.decode(packet) {
                     Ok(result) => result,
                     Err(err) => return Ok(Err(err)),
                 };
-                // Ignore non-handshake packets.
-                if let ClientPacket::Handshake {
-                    cursor,
-                } = packet
-                {
-                    self.new_client(addr, connection, cursor)?;
+                match packet {
+                    ClientPacket::Handshake {
+                        cursor,
+                        name,
+                        hue,
+                        key,
+                        spectate,
+                    } => {
+                        let request = HandshakeRequest {
+                            cursor,
+                            name,
+                            hue,
+                            key,
+                            spectate,
+                        };
+                        self.new_client(addr, connection, request)?;
+                    },
+                    ClientPacket::Query => {
+                        // Answer without keeping any state around for
+                        // the sender, other than how often it asks.
+                        if !self.query_limiter.allow(addr.ip(), now) {
+                            trace!("ignoring query from {}", addr);
+                            return Ok(Ok(()));
+                        }
+                        let info = game_info(&self.game, &self.config);
+                        let (data, _) = connection.encode(&info);
+                        self.send_queue.push_back((addr, data));
+                        reregister = true;
+                    },
+                    // Ignore anything else.
+                    _ => (),
                 }
             },
         }
@@ -586,15 +2421,28 @@ impl Server {
     }
 
     fn reregister_socket(&mut self, writable: bool) -> Result<(), Error> {
-        let readiness = if writable {
-            Ready::readable() | Ready::writable()
-        } else {
-            Ready::readable()
-        };
+        peer::reregister(&self.poll, &*self.transport, SOCKET, writable)
+    }
 
-        self.poll
-            .reregister(&self.socket, SOCKET, readiness, PollOpt::edge())
-            .map_err(Error::poll)
+    /// Sends the packets held back for every client that's back under
+    /// its bandwidth budget.
+    fn send_deferred(&mut self, now: Instant) {
+        for (&addr, client) in &mut self.clients {
+            if client.deferred.is_empty() {
+                continue;
+            }
+            let exhausted = client
+                .bandwidth
+                .as_mut()
+                .map_or(false, |bandwidth| bandwidth.exhausted(now));
+            if exhausted {
+                continue;
+            }
+            while let Some(packet) = client.deferred.pop_front() {
+                let (data, _) = client.encode(&packet, now);
+                self.send_queue.push_back((addr, data));
+            }
+        }
     }
 
     fn broadcast(&mut self, packet: &ServerPacket) -> Result<(), Error> {
@@ -607,26 +2455,112 @@ impl Server {
         mut pred: F,
     ) -> Result<(), Error> {
         if !self.clients.is_empty() {
-            let data = bincode::serialize(packet).unwrap();
-
-            for (&addr, client) in self
+            let now = self.config.clock.now();
+            let mut overflowed = Vec::new();
+            let ready = self
                 .clients
                 .iter_mut()
                 .filter(|(addr, client)| pred((addr, &*client)))
-            {
-                let mut with_header =
-                    Vec::with_capacity(data.len() + HEADER_BYTES);
-                let sequence = client.connection.send_header(&mut with_header);
-                with_header.extend_from_slice(&data);
-                self.send_queue.push_back((addr, with_header));
-
-                if packet.reliable() {
-                    client.reliable.insert(sequence, packet.clone());
-                }
-            }
+                .filter_map(|(addr, client)| {
+                    // Clients that are over their bandwidth budget have
+                    // everything held back until they've caught up,
+                    // which lowers their snapshot rate in the meantime.
+                    // Anything already held back keeps this from
+                    // overtaking it.
+                    let exhausted = client
+                        .bandwidth
+                        .as_mut()
+                        .map_or(false, |bandwidth| bandwidth.exhausted(now));
+                    if !exhausted && client.deferred.is_empty() {
+                        return Some((addr, client));
+                    }
+                    trace!("deferring {} for {}", packet.kind(), addr);
+                    client.deferred.push_back(packet.clone());
+                    if client.deferred.len() > MAX_DEFERRED {
+                        let oldest = client.deferred.pop_front().unwrap();
+                        let (data, _) = client.encode(&oldest, now);
+                        overflowed.push((*addr, data));
+                    }
+                    None
+                });
+            peer::broadcast(ready, packet, &mut self.send_queue, now);
+            self.send_queue.extend(overflowed);
 
             self.reregister_socket(true)?;
         }
         Ok(())
     }
 }
+
+#[test]
+fn events_batched_to_fit() {
+    let message = |i: usize| Event::Message(format!("{:03}", i).repeat(60));
+    let events = (0..100).map(message).collect::<Vec<_>>();
+    let batches = batch_events(events.clone());
+    assert!(batches.len() > 1);
+    for batch in &batches {
+        let packet = ServerPacket::Events(batch.clone());
+        let size = bincode::serialized_size(&packet).unwrap();
+        assert!(size <= MAX_BATCH_BYTES as u64);
+    }
+    // Events aren't comparable, but every message is different.
+    let flattened = batches.into_iter().flatten().collect::<Vec<_>>();
+    assert_eq!(format!("{:?}", flattened), format!("{:?}", events));
+}
+
+#[test]
+fn far_balls_sent_less_often() {
+    use crate::game::{Ball, PlayerState};
+    use nalgebra::Vector2;
+
+    let settings = GameSettings::default();
+    let state = |x: f32| {
+        PlayerState {
+            cursor: None,
+            ball: Ball {
+                position: Point2::new(x, 0.0),
+                velocity: Vector2::zeros(),
+                charge: 0.0,
+            },
+        }
+    };
+    let snapshot = Snapshot::new(
+        0,
+        vec![
+            (0, state(-0.5)),
+            (1, state(0.0)),
+            (2, state(0.5)),
+            (3, state(0.6)),
+        ],
+    );
+    let center = snapshot.players[&0].ball.position;
+    let sent = (0..4)
+        .map(|count| {
+            let interest =
+                interest_snapshot(&snapshot, center, &settings, count);
+            let mut ids = interest.players.keys().cloned().collect::<Vec<_>>();
+            ids.sort();
+            ids
+        })
+        .collect::<Vec<_>>();
+    // Only the far balls take turns.
+    assert_eq!(
+        sent,
+        vec![vec![0, 1, 2], vec![0, 1, 3], vec![0, 1, 2], vec![0, 1, 3]]
+    );
+}
+
+#[test]
+fn bandwidth_budget() {
+    let start = Instant::now();
+    // 8 kbps is 1000 bytes per second.
+    let mut bandwidth = Bandwidth::new(8, start);
+    assert!(!bandwidth.exhausted(start));
+    bandwidth.spend(1000, start);
+    assert!(bandwidth.exhausted(start));
+    // Saving up is capped at the burst size.
+    let later = start + Duration::from_secs(10);
+    assert!(!bandwidth.exhausted(later));
+    bandwidth.spend(300, later);
+    assert!(bandwidth.exhausted(later));
+}
@@ -0,0 +1,145 @@
+use crate::game::GameSettings;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Most bots that a settings file can ask for.
+pub const MAX_BOTS: u16 = 32;
+
+/// Range of game tick intervals allowed, in milliseconds.
+const TICK_MS_RANGE: (u64, u64) = (5, 100);
+
+/// Range of snapshot intervals allowed, in milliseconds.
+const SNAPSHOT_MS_RANGE: (u64, u64) = (10, 250);
+
+/// Settings for a dedicated server, read from `server.toml` when it
+/// starts and again whenever it's reloaded.
+///
+/// Anything left out of the file goes back to what the server started
+/// with, so removing a line and reloading undoes it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// Milliseconds between game ticks.
+    pub tick_ms: Option<u64>,
+    /// Milliseconds between snapshots, before any slowdown from the
+    /// server being overloaded.
+    pub snapshot_ms: Option<u64>,
+    pub max_players: Option<u16>,
+    /// Number of players that the server moves around itself.
+    pub bots: Option<u16>,
+    /// Secret that admin connections have to send, replacing
+    /// `network.admin_secret` from the config.
+    pub admin_password: Option<String>,
+    /// Game settings, with any left out taking their defaults.
+    pub game: Option<GameSettings>,
+}
+
+impl ServerSettings {
+    /// Reads and validates a settings file, describing everything
+    /// that's wrong with it if it can't be used.
+    pub fn load(path: &Path) -> Result<ServerSettings, String> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            format!("reading {} failed: {}", path.display(), err)
+        })?;
+        let settings =
+            toml::from_str::<ServerSettings>(&contents).map_err(|err| {
+                format!("parsing {} failed: {}", path.display(), err)
+            })?;
+        settings.validate().map_err(|err| {
+            format!("{} has invalid settings: {}", path.display(), err)
+        })?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        let in_range =
+            |value: u64, (min, max): (u64, u64)| value >= min && value <= max;
+        if let Some(tick_ms) = self.tick_ms {
+            if !in_range(tick_ms, TICK_MS_RANGE) {
+                errors.push(format!(
+                    "tick_ms must be from {} to {}",
+                    TICK_MS_RANGE.0, TICK_MS_RANGE.1
+                ));
+            }
+        }
+        if let Some(snapshot_ms) = self.snapshot_ms {
+            if !in_range(snapshot_ms, SNAPSHOT_MS_RANGE) {
+                errors.push(format!(
+                    "snapshot_ms must be from {} to {}",
+                    SNAPSHOT_MS_RANGE.0, SNAPSHOT_MS_RANGE.1
+                ));
+            }
+        }
+        if self.max_players == Some(0) {
+            errors.push("max_players must be at least 1".to_owned());
+        }
+        if self.bots.map_or(false, |bots| bots > MAX_BOTS) {
+            errors.push(format!("bots can be at most {}", MAX_BOTS));
+        }
+        if self.admin_password.as_ref().map_or(false, String::is_empty) {
+            errors.push("admin_password can't be empty".to_owned());
+        }
+        if let Some(game) = self.game {
+            if game.clamp() != game {
+                errors.push("game settings are out of range".to_owned());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+
+    /// Fills in everything left out of these settings from `defaults`.
+    pub fn or(self, defaults: &ServerSettings) -> ServerSettings {
+        ServerSettings {
+            tick_ms: self.tick_ms.or(defaults.tick_ms),
+            snapshot_ms: self.snapshot_ms.or(defaults.snapshot_ms),
+            max_players: self.max_players.or(defaults.max_players),
+            bots: self.bots.or(defaults.bots),
+            admin_password: self
+                .admin_password
+                .or_else(|| defaults.admin_password.clone()),
+            game: self.game.or(defaults.game),
+        }
+    }
+
+    pub fn tick_rate(&self) -> Option<Duration> {
+        self.tick_ms.map(Duration::from_millis)
+    }
+
+    pub fn snapshot_rate(&self) -> Option<Duration> {
+        self.snapshot_ms.map(Duration::from_millis)
+    }
+}
+
+#[test]
+fn validate_settings() {
+    let parse = |s: &str| {
+        let settings = toml::from_str::<ServerSettings>(s).unwrap();
+        settings.validate().map(|()| settings)
+    };
+    let settings =
+        parse("tick_ms = 20\nbots = 3\n\n[game]\nrespawn_delay = 2.0\n")
+            .unwrap();
+    assert_eq!(settings.tick_rate(), Some(Duration::from_millis(20)));
+    assert_eq!(settings.max_players, None);
+    assert_eq!(settings.game.unwrap().ball_radius, 0.15);
+
+    let err = parse("tick_ms = 1\nmax_players = 0\n").unwrap_err();
+    assert_eq!(
+        err,
+        "tick_ms must be from 5 to 100, max_players must be at least 1"
+    );
+    assert!(parse("[game]\ndamping = 2.0\n").is_err());
+    assert!(toml::from_str::<ServerSettings>("tick_rate = 20").is_err());
+
+    let defaults = parse("tick_ms = 30\nbots = 4\n").unwrap();
+    let settings = parse("bots = 1\n").unwrap().or(&defaults);
+    assert_eq!(settings.tick_ms, Some(30));
+    assert_eq!(settings.bots, Some(1));
+}
@@ -0,0 +1,742 @@
+use crate::game::client::Game;
+use crate::networking::client::{
+    self,
+    ClientHandle,
+    ClientOptions,
+    ConnectedHandle,
+    ConnectingHandle,
+    ConnectionState,
+};
+use crate::networking::clock::ManualClock;
+use crate::networking::event_loop::{poll_event_loops, EventHandler};
+use crate::networking::server::{
+    self,
+    ServerConfig,
+    ServerHandle,
+    ServerStatus,
+};
+use crate::networking::transport::{
+    self,
+    ChannelNetwork,
+    ChannelTransport,
+    Transport,
+};
+use crate::networking::Error;
+use crossbeam::channel::{Receiver, TryRecvError};
+use mio::{Evented, Events, Poll, PollOpt, Ready, Token};
+use nalgebra::Point2;
+use parking_lot::Mutex;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for something to happen before failing a test.
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Step that tests move the clock and tick client games by.
+pub const TEST_TICK: Duration = Duration::from_millis(10);
+
+/// Most rounds of polling that the event loops get to run out of
+/// things to do, before a test gives up on them ever settling.
+const MAX_SETTLE_POLLS: usize = 10_000;
+
+/// Servers and clients whose event loops all run on the test's
+/// thread, talking over an in-process network and going by a clock
+/// that only moves when the test steps it.
+///
+/// Nothing happens between steps, so tests don't depend on how fast
+/// the machine running them is.
+pub struct TestNetwork {
+    pub network: ChannelNetwork,
+    pub clock: ManualClock,
+    handlers: Vec<Box<dyn EventHandler>>,
+    events: Events,
+}
+
+/// Transport that deterministically drops some of the datagrams
+/// going each way, to simulate packet loss.
+pub struct LossyTransport {
+    inner: ChannelTransport,
+    /// Every this many datagrams in each direction, one is dropped.
+    drop_every: usize,
+    sent: AtomicUsize,
+    received: AtomicUsize,
+}
+
+/// Transport that keeps a copy of every datagram it sends, so tests
+/// can replay them.
+pub struct RecordingTransport {
+    inner: ChannelTransport,
+    pub sent: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+/// A server on a `TestNetwork`, for testing clients against.
+pub struct TestServer {
+    pub handle: ServerHandle,
+    pub addr: SocketAddr,
+    /// Latest status received from the server.
+    status: Option<ServerStatus>,
+}
+
+/// A client that's finished connecting to a `TestServer`.
+pub struct TestClient {
+    pub handle: ClientHandle,
+    pub done: ConnectedHandle,
+    pub game: Game,
+}
+
+impl TestNetwork {
+    pub fn new() -> TestNetwork {
+        TestNetwork {
+            network: ChannelNetwork::new(),
+            clock: ManualClock::new(),
+            handlers: Vec::new(),
+            events: Events::with_capacity(1024),
+        }
+    }
+
+    /// Runs every event loop until none of them have anything left to
+    /// do at the current time.
+    pub fn settle(&mut self) {
+        for _ in 0..MAX_SETTLE_POLLS {
+            if !poll_event_loops(&mut self.handlers, &mut self.events) {
+                return;
+            }
+        }
+        panic!("event loops never settled");
+    }
+
+    /// Moves the clock forward by `TEST_TICK`, and lets everything
+    /// catch up.
+    pub fn step(&mut self) {
+        self.clock.advance(TEST_TICK);
+        self.settle();
+    }
+
+    /// Steps until `f` returns `true`, and returns whether it did
+    /// before `TEST_TIMEOUT` went by on the clock.
+    pub fn step_until<F: FnMut() -> bool>(&mut self, mut f: F) -> bool {
+        let steps = TEST_TIMEOUT.as_millis() / TEST_TICK.as_millis();
+        self.settle();
+        for _ in 0..steps {
+            if f() {
+                return true;
+            }
+            self.step();
+        }
+        f()
+    }
+
+    /// Hosts a server on the network.
+    pub fn host(&mut self, config: ServerConfig) -> TestServer {
+        let transport = self.network.bind();
+        let addr = transport.local_addr();
+        self.host_with(Box::new(transport), addr, config)
+    }
+
+    /// Hosts a server over some transport, which is bound to `addr`.
+    pub fn host_with(
+        &mut self,
+        transport: Box<dyn Transport>,
+        addr: SocketAddr,
+        config: ServerConfig,
+    ) -> TestServer {
+        let config = ServerConfig {
+            clock: self.clock.clock(),
+            ..config
+        };
+        let (server, handle) = server::start_server(transport, config).unwrap();
+        self.handlers.push(Box::new(server));
+        TestServer {
+            handle,
+            addr,
+            status: None,
+        }
+    }
+
+    /// Starts connecting a client to `addr`, without waiting for the
+    /// handshake.
+    pub fn start_client(
+        &mut self,
+        transport: Box<dyn Transport>,
+        addr: SocketAddr,
+        name: &str,
+    ) -> (ClientHandle, ConnectingHandle) {
+        let options = ClientOptions {
+            transport,
+            addr,
+            rendezvous: None,
+            cursor: Point2::origin(),
+            name: name.to_owned(),
+            hue: None,
+            spectate: false,
+            clock: self.clock.clock(),
+        };
+        let (client, handle, connecting) =
+            client::start_client(options, None).unwrap();
+        self.handlers.push(Box::new(client));
+        (handle, connecting)
+    }
+
+    /// Connects a client on the network, panicking if the handshake
+    /// doesn't finish.
+    pub fn connect(&mut self, server: &TestServer, name: &str) -> TestClient {
+        let transport = self.network.bind();
+        self.connect_with(Box::new(transport), server, name)
+    }
+
+    pub fn connect_with(
+        &mut self,
+        transport: Box<dyn Transport>,
+        server: &TestServer,
+        name: &str,
+    ) -> TestClient {
+        let (handle, connecting) =
+            self.start_client(transport, server.addr, name);
+        match self.wait_for(&connecting) {
+            Ok((game, done)) => {
+                TestClient {
+                    handle,
+                    done,
+                    game,
+                }
+            },
+            Err(err) => panic!("{} failed to connect: {:?}", name, err),
+        }
+    }
+
+    /// Steps until something comes out of `receiver`, panicking if
+    /// nothing does.
+    pub fn wait_for<T>(&mut self, receiver: &Receiver<T>) -> T {
+        let mut received = None;
+        let arrived = self.step_until(|| {
+            match receiver.try_recv() {
+                Ok(value) => received = Some(value),
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => {
+                    panic!("channel closed while waiting on it")
+                },
+            }
+            received.is_some()
+        });
+        assert!(arrived, "timed out waiting on a channel");
+        received.unwrap()
+    }
+}
+
+impl Default for TestNetwork {
+    fn default() -> TestNetwork {
+        TestNetwork::new()
+    }
+}
+
+impl LossyTransport {
+    pub fn bind(network: &ChannelNetwork, drop_every: usize) -> LossyTransport {
+        LossyTransport {
+            inner: network.bind(),
+            drop_every,
+            sent: AtomicUsize::new(0),
+            received: AtomicUsize::new(0),
+        }
+    }
+
+    fn should_drop(&self, count: &AtomicUsize) -> bool {
+        (count.fetch_add(1, Ordering::SeqCst) + 1) % self.drop_every == 0
+    }
+}
+
+impl Transport for LossyTransport {
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if self.should_drop(&self.sent) {
+            Ok(data.len())
+        } else {
+            self.inner.send_to(data, addr)
+        }
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let received = self.inner.recv_from(buffer)?;
+            if !self.should_drop(&self.received) {
+                break Ok(received);
+            }
+        }
+    }
+}
+
+impl Evented for LossyTransport {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.deregister(poll)
+    }
+}
+
+impl RecordingTransport {
+    pub fn bind(network: &ChannelNetwork) -> RecordingTransport {
+        RecordingTransport {
+            inner: network.bind(),
+            sent: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let sent = self.inner.send_to(data, addr)?;
+        self.sent.lock().push(data.to_vec());
+        Ok(sent)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.inner.recv_from(buffer)
+    }
+}
+
+impl Evented for RecordingTransport {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.deregister(poll)
+    }
+}
+
+/// Calls `f` every `TEST_TICK` of real time until it returns `true`,
+/// and returns whether it did before `TEST_TIMEOUT`.
+///
+/// This is only for event loops on their own threads, like a relay's,
+/// which a `TestNetwork` can't step.
+pub fn wait_until<F: FnMut() -> bool>(mut f: F) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < TEST_TIMEOUT {
+        if f() {
+            return true;
+        }
+        thread::sleep(TEST_TICK);
+    }
+    false
+}
+
+impl TestServer {
+    /// Gets the latest status, if the server has sent one yet.
+    pub fn status(&mut self) -> Option<&ServerStatus> {
+        if let Some(status) = self.handle.status.try_iter().last() {
+            self.status = Some(status);
+        }
+        self.status.as_ref()
+    }
+
+    /// Shuts the server down and waits for it to finish.
+    pub fn shutdown(self, network: &mut TestNetwork) {
+        self.handle.shutdown();
+        let err = network.wait_for(&self.handle.done);
+        assert!(err.is_none(), "server failed: {:?}", err);
+    }
+}
+
+impl TestClient {
+    /// Handles events from the server and ticks the game by
+    /// `TEST_TICK`.
+    pub fn update(&mut self) {
+        self.game.handle_events();
+        self.game.tick(TEST_TICK.as_secs_f32());
+        self.game.drain_events().for_each(drop);
+    }
+
+    /// Shuts the client down and waits for it to finish.
+    pub fn shutdown(self, network: &mut TestNetwork) {
+        self.handle.shutdown();
+        let err = network.wait_for(&self.done);
+        assert!(err.is_none(), "client failed: {:?}", err);
+    }
+}
+
+#[test]
+fn handshake_and_snapshots() {
+    let mut network = TestNetwork::new();
+    let server = network.host(ServerConfig::default());
+    let mut clients = (0..3)
+        .map(|i| network.connect(&server, &format!("client {}", i)))
+        .collect::<Vec<_>>();
+    assert!(network.step_until(|| {
+        for client in &mut clients {
+            client.update();
+        }
+        clients.iter().all(|client| {
+            client.game.players.len() == 3 && client.game.snapshot_count() > 0
+        })
+    }));
+    for client in clients {
+        client.shutdown(&mut network);
+    }
+    server.shutdown(&mut network);
+}
+
+#[test]
+fn disconnect_removes_player() {
+    let mut network = TestNetwork::new();
+    let mut server = network.host(ServerConfig::default());
+    let mut staying = network.connect(&server, "staying");
+    let leaving = network.connect(&server, "leaving");
+    assert!(network.step_until(|| {
+        staying.update();
+        staying.game.players.len() == 2
+    }));
+    leaving.shutdown(&mut network);
+    assert!(network.step_until(|| {
+        staying.update();
+        staying.game.players.len() == 1
+    }));
+    assert!(network.step_until(|| {
+        server.status().map_or(false, |status| status.clients.len() == 1)
+    }));
+    staying.shutdown(&mut network);
+    server.shutdown(&mut network);
+}
+
+#[test]
+fn reliable_events_survive_loss() {
+    let mut network = TestNetwork::new();
+    let server = network.host(ServerConfig::default());
+    let transport = LossyTransport::bind(&network.network, 3);
+    let mut lossy = network.connect_with(Box::new(transport), &server, "lossy");
+    // Joining and leaving are both only announced once, reliably.
+    let other = network.connect(&server, "other");
+    assert!(network.step_until(|| {
+        lossy.update();
+        lossy.game.players.len() == 2
+    }));
+    other.shutdown(&mut network);
+    assert!(network.step_until(|| {
+        lossy.update();
+        lossy.game.players.len() == 1
+    }));
+    lossy.shutdown(&mut network);
+    server.shutdown(&mut network);
+}
+
+#[test]
+fn pause_reaches_late_joiners() {
+    use crate::networking::server::ServerCommand;
+
+    let mut network = TestNetwork::new();
+    let mut server = network.host(ServerConfig::default());
+    let mut first = network.connect(&server, "first");
+    server.handle.command(ServerCommand::SetPaused(true));
+    assert!(network.step_until(|| {
+        first.update();
+        first.game.paused()
+    }));
+    assert!(network
+        .step_until(|| server.status().map_or(false, |status| status.paused)));
+
+    let mut second = network.connect(&server, "second");
+    assert!(network.step_until(|| {
+        second.update();
+        second.game.paused()
+    }));
+
+    server.handle.command(ServerCommand::SetPaused(false));
+    assert!(network.step_until(|| {
+        first.update();
+        second.update();
+        !first.game.paused() && !second.game.paused()
+    }));
+    second.shutdown(&mut network);
+    first.shutdown(&mut network);
+    server.shutdown(&mut network);
+}
+
+#[test]
+fn reload_changes_bots() {
+    use crate::networking::server::ServerCommand;
+    use crossbeam::channel;
+    use std::{env, fs};
+
+    let path = env::temp_dir()
+        .join(format!("ball-server-settings-{}.toml", std::process::id()));
+    fs::write(&path, "bots = 2\n").unwrap();
+    let mut network = TestNetwork::new();
+    let server = network.host(ServerConfig {
+        settings_file: Some(path.clone()),
+        ..ServerConfig::default()
+    });
+    let mut client = network.connect(&server, "human");
+    assert!(network.step_until(|| {
+        client.update();
+        client.game.players.len() == 3
+    }));
+
+    fs::write(&path, "bots = 0\nmax_players = 0\n").unwrap();
+    let (reply_tx, reply_rx) = channel::bounded(1);
+    server.handle.command(ServerCommand::Reload(reply_tx));
+    assert!(network.wait_for(&reply_rx).is_err());
+
+    fs::write(&path, "bots = 0\n").unwrap();
+    let (reply_tx, reply_rx) = channel::bounded(1);
+    server.handle.command(ServerCommand::Reload(reply_tx));
+    let result = network.wait_for(&reply_rx);
+    let _ = fs::remove_file(&path);
+    assert_eq!(result, Ok(()));
+    assert!(network.step_until(|| {
+        client.update();
+        client.game.players.len() == 1
+    }));
+    client.shutdown(&mut network);
+    server.shutdown(&mut network);
+}
+
+#[test]
+fn relay_serves_viewers() {
+    use crate::networking::relay::{self, RelayConfig};
+
+    // The relay runs its own upstream client on another thread, so
+    // this one goes over real sockets in real time.
+    let udp = || transport::bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+    let connect = |transport: Box<dyn Transport>, addr, name: &str| {
+        let (handle, connecting, _) = client::connect_with(
+            transport,
+            addr,
+            None,
+            Point2::origin(),
+            name.to_owned(),
+            None,
+        )
+        .unwrap();
+        match connecting.recv_timeout(TEST_TIMEOUT) {
+            Ok(Ok((game, done))) => {
+                TestClient {
+                    handle,
+                    done,
+                    game,
+                }
+            },
+            Ok(Err(err)) => panic!("{} failed to connect: {:?}", name, err),
+            Err(_) => panic!("{} timed out connecting", name),
+        }
+    };
+    let shutdown = |client: TestClient| {
+        client.handle.shutdown();
+        let err = client.done.recv_timeout(TEST_TIMEOUT).unwrap();
+        assert!(err.is_none(), "client failed: {:?}", err);
+    };
+
+    let socket = udp();
+    let addr = socket.local_addr().unwrap();
+    let (server, _) =
+        server::host_with(Box::new(socket), ServerConfig::default()).unwrap();
+    let mut player = connect(Box::new(udp()), addr, "player");
+    let (relay, _) = relay::relay(RelayConfig {
+        upstream: addr,
+        addr: "127.0.0.1:0".parse().unwrap(),
+        max_viewers: None,
+    })
+    .unwrap();
+    let mut viewer = connect(Box::new(udp()), relay.addr, "viewer");
+    // Neither the relay nor the viewer get a player.
+    assert!(wait_until(|| {
+        player.update();
+        viewer.update();
+        viewer.game.players.len() == 1 && viewer.game.snapshot_count() > 0
+    }));
+    assert!(wait_until(|| {
+        let status = server.status.try_iter().last();
+        status.map_or(false, |status| status.clients.len() == 1)
+    }));
+
+    // Players joining later are passed on too.
+    let mut other = connect(Box::new(udp()), addr, "other");
+    assert!(wait_until(|| {
+        player.update();
+        other.update();
+        viewer.update();
+        viewer.game.players.len() == 2
+    }));
+
+    shutdown(viewer);
+    relay.shutdown();
+    let err = relay.done.recv_timeout(TEST_TIMEOUT).unwrap();
+    assert!(err.is_none(), "relay failed: {:?}", err);
+    shutdown(other);
+    shutdown(player);
+    server.shutdown();
+    let err = server.done.recv_timeout(TEST_TIMEOUT).unwrap();
+    assert!(err.is_none(), "server failed: {:?}", err);
+}
+
+#[test]
+fn full_server_denies_connection() {
+    let mut network = TestNetwork::new();
+    let server = network.host(ServerConfig {
+        max_players: Some(1),
+        ..ServerConfig::default()
+    });
+    let first = network.connect(&server, "first");
+    let transport = Box::new(network.network.bind());
+    let (_client, connecting) =
+        network.start_client(transport, server.addr, "second");
+    match network.wait_for(&connecting) {
+        Err(Some(Error::ConnectionDenied(_))) => (),
+        Err(err) => panic!("wrong error: {:?}", err),
+        Ok(_) => panic!("connected to a full server"),
+    }
+    first.shutdown(&mut network);
+    server.shutdown(&mut network);
+}
+
+#[test]
+fn replayed_packet_doesnt_migrate() {
+    let mut network = TestNetwork::new();
+    let server = network.host(ServerConfig::default());
+    let transport = RecordingTransport::bind(&network.network);
+    let sent = transport.sent.clone();
+    let mut client =
+        network.connect_with(Box::new(transport), &server, "replayed");
+    // Take a packet sent after the handshake, which the server has
+    // already seen since it was sent before being recorded.
+    let handshake = sent.lock().len();
+    assert!(network.step_until(|| {
+        client.update();
+        sent.lock().len() > handshake
+    }));
+    let replayed = sent.lock().last().unwrap().clone();
+
+    let attacker = network.network.bind();
+    attacker.send_to(&replayed, server.addr).unwrap();
+    for _ in 0..20 {
+        network.step();
+    }
+    let mut buffer = [0; 2048];
+    let reply = attacker.recv_from(&mut buffer);
+    assert!(
+        reply.map_err(|err| err.kind()) == Err(io::ErrorKind::WouldBlock),
+        "replayed packet moved the connection"
+    );
+    let snapshots = client.game.snapshot_count();
+    assert!(network.step_until(|| {
+        client.update();
+        client.game.snapshot_count() > snapshots
+    }));
+    client.shutdown(&mut network);
+    server.shutdown(&mut network);
+}
+
+#[test]
+fn handshake_gives_up_without_reply() {
+    // Nothing is bound to this address, so every handshake goes
+    // nowhere.
+    let mut network = TestNetwork::new();
+    let nowhere = network.network.bind().local_addr();
+    let transport = Box::new(network.network.bind());
+    let (client, connecting) =
+        network.start_client(transport, nowhere, "lonely");
+    match network.wait_for(&connecting) {
+        Err(Some(Error::NoResponse)) => (),
+        Err(err) => panic!("wrong error: {:?}", err),
+        Ok(_) => panic!("connected to nothing"),
+    }
+    assert_eq!(client.handshake_attempts(), client::MAX_HANDSHAKE_ATTEMPTS);
+    assert_eq!(client.state(), ConnectionState::Disconnecting);
+}
+
+#[test]
+fn host_migrates_to_backup() {
+    use crate::networking::persist::SavedState;
+    use crate::networking::Migration;
+
+    let mut network = TestNetwork::new();
+    let loopback = "127.0.0.1".parse().unwrap();
+    let mut saved = SavedState::default();
+    saved.banned.push("198.51.100.1".parse().unwrap());
+    saved.wins.insert("local@127.0.0.1".to_owned(), 2);
+    let mut server = network.host(ServerConfig {
+        migrate: true,
+        resume_state: Some(saved),
+        ..ServerConfig::default()
+    });
+    // Only the remote player isn't on the host's machine.
+    let host = network.connect(&server, "host");
+    let transport = network.network.bind_ip(loopback);
+    let local = network.connect_with(Box::new(transport), &server, "local");
+    let transport = network.network.bind_ip("192.0.2.1".parse().unwrap());
+    let remote_addr = transport.local_addr();
+    let remote = network.connect_with(Box::new(transport), &server, "remote");
+    assert!(network.step_until(|| {
+        server.status().map_or(false, |status| status.backup.is_some())
+    }));
+    let backup = server.status().unwrap().backup.unwrap();
+    assert_eq!(backup.ip(), remote_addr.ip());
+    server.shutdown(&mut network);
+
+    let mut migration = |client: TestClient| {
+        match network.wait_for(&client.done) {
+            Some(Error::Migrated(migration)) => migration,
+            other => panic!("client didn't migrate: {:?}", other),
+        }
+    };
+    let mut joins_backup = |client| {
+        match migration(client) {
+            Migration::Join(addr) => addr == backup,
+            Migration::Host(..) => false,
+        }
+    };
+    assert!(joins_backup(host));
+    assert!(joins_backup(local));
+    let (saved, socket) = match migration(remote) {
+        Migration::Host(saved, socket) => (saved, socket),
+        other => panic!("wrong migration: {:?}", other),
+    };
+    assert_eq!(socket.local_addr().unwrap().port(), backup.port());
+    assert!(saved.banned.is_empty());
+
+    // The backup carries on with everyone's scores.
+    let server = network.host(ServerConfig {
+        resume_state: Some(saved),
+        ..ServerConfig::default()
+    });
+    let transport = network.network.bind_ip(loopback);
+    let local = network.connect_with(Box::new(transport), &server, "local");
+    let id = local.game.player_id();
+    assert_eq!(local.game.players[&id].wins, 2);
+    local.shutdown(&mut network);
+    server.shutdown(&mut network);
+}
@@ -1,6 +1,17 @@
 use log::{trace, warn};
 use std::time::{Duration, Instant};
 
+/// Number of ticks that budget overruns are counted over.
+const BUDGET_WINDOW: u32 = 200;
+
+/// Fraction of the ticks in a window that have to go over budget for
+/// it to count as overloaded.
+const OVERLOAD_FRACTION: f32 = 0.1;
+
+/// Number of windows in a row that have to stay within budget before
+/// an overload is over.
+const RECOVERY_WINDOWS: u32 = 5;
+
 /// State used to attempt to generate regular interval ticks.
 ///
 /// This will try to take into account how late or early the tick is
@@ -11,11 +22,33 @@ pub struct Interval {
     next: Instant,
 }
 
+/// Keeps track of how long the work for each tick takes compared to
+/// the tick interval, to notice when it can't keep up.
+pub struct TickBudget {
+    budget: Duration,
+    /// Ticks so far in the current window.
+    ticks: u32,
+    /// Ticks in the current window that went over budget.
+    overruns: u32,
+    total: Duration,
+    max: Duration,
+    overloaded: bool,
+    /// Windows in a row that were within budget, while overloaded.
+    calm_windows: u32,
+}
+
+/// Change in whether a `TickBudget` is overloaded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Load {
+    Overloaded,
+    Recovered,
+}
+
 impl Interval {
-    pub fn new(interval: Duration) -> Interval {
+    pub fn new(interval: Duration, now: Instant) -> Interval {
         Interval {
             interval,
-            next: Instant::now() + interval,
+            next: now + interval,
         }
     }
 
@@ -57,4 +90,120 @@ impl Interval {
         self.next = tick + interval;
         (tick_length, interval)
     }
+
+    /// Starts counting from `now` again, as if a tick just happened.
+    ///
+    /// This is for after ticks have been stopped for a while, so the
+    /// next tick isn't treated as late and doesn't cover the gap.
+    pub fn restart(&mut self, now: Instant) {
+        self.next = now + self.interval;
+    }
+
+    /// Changes the interval, starting after the next tick.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+}
+
+impl TickBudget {
+    pub fn new(budget: Duration) -> TickBudget {
+        TickBudget {
+            budget,
+            ticks: 0,
+            overruns: 0,
+            total: Duration::from_secs(0),
+            max: Duration::from_secs(0),
+            overloaded: false,
+            calm_windows: 0,
+        }
+    }
+
+    pub fn overloaded(&self) -> bool {
+        self.overloaded
+    }
+
+    /// Records how long one tick's worth of work took, returning
+    /// whether that made it overloaded or recovered.
+    ///
+    /// This only changes at the end of a window of ticks, and a
+    /// warning with the window's stats is logged every time one goes
+    /// over budget too often.
+    pub fn record(&mut self, work: Duration) -> Option<Load> {
+        self.ticks += 1;
+        self.total += work;
+        self.max = self.max.max(work);
+        if work > self.budget {
+            self.overruns += 1;
+        }
+        if self.ticks < BUDGET_WINDOW {
+            return None;
+        }
+
+        let over = self.overruns as f32 > OVERLOAD_FRACTION * self.ticks as f32;
+        if over {
+            warn!(
+                "{} of the last {} ticks went over the {:.1} ms budget (mean \
+                 {:.2} ms, max {:.2} ms)",
+                self.overruns,
+                self.ticks,
+                self.budget.as_secs_f32() * 1000.0,
+                (self.total / self.ticks).as_secs_f32() * 1000.0,
+                self.max.as_secs_f32() * 1000.0
+            );
+        }
+        let change = match (self.overloaded, over) {
+            (false, true) => {
+                self.overloaded = true;
+                self.calm_windows = 0;
+                Some(Load::Overloaded)
+            },
+            (true, true) => {
+                self.calm_windows = 0;
+                None
+            },
+            (true, false) => {
+                self.calm_windows += 1;
+                if self.calm_windows >= RECOVERY_WINDOWS {
+                    self.overloaded = false;
+                    Some(Load::Recovered)
+                } else {
+                    None
+                }
+            },
+            (false, false) => None,
+        };
+
+        self.ticks = 0;
+        self.overruns = 0;
+        self.total = Duration::from_secs(0);
+        self.max = Duration::from_secs(0);
+        change
+    }
+}
+
+#[test]
+fn budget_overload_and_recovery() {
+    let budget = Duration::from_millis(10);
+    let mut load = TickBudget::new(budget);
+    let mut record = |work: Duration, ticks: u32| {
+        (0..ticks).filter_map(|_| load.record(work)).collect::<Vec<_>>()
+    };
+    // A few slow ticks aren't enough.
+    assert_eq!(record(budget * 2, BUDGET_WINDOW / 20), vec![]);
+    assert_eq!(record(budget / 2, BUDGET_WINDOW), vec![]);
+    assert_eq!(record(budget * 2, BUDGET_WINDOW), vec![Load::Overloaded]);
+    assert_eq!(
+        record(budget / 2, BUDGET_WINDOW * (RECOVERY_WINDOWS - 1)),
+        vec![]
+    );
+    assert_eq!(record(budget / 2, BUDGET_WINDOW), vec![Load::Recovered]);
+}
+
+#[test]
+fn restart_after_pause() {
+    let interval = Duration::from_millis(10);
+    let mut tick = Interval::new(interval, Instant::now());
+    let resumed = Instant::now() + Duration::from_secs(10);
+    tick.restart(resumed);
+    assert_eq!(tick.next(resumed + interval), (interval, interval));
 }
@@ -0,0 +1,313 @@
+use crate::networking::Error;
+use crossbeam::channel::{self, Receiver, Sender, TryRecvError};
+use log::warn;
+use mio::net::UdpSocket;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+/// Something that the client and server can send datagrams over.
+///
+/// Registering a transport with a `Poll` should give readable and
+/// writable events like a `UdpSocket` would.
+pub trait Transport: Evented + Send {
+    /// Sends a datagram, returning how many bytes were sent.
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Receives a datagram, returning its size and who sent it.
+    ///
+    /// Returns `WouldBlock` when there's nothing to receive.
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+/// An in-process network, which `ChannelTransport`s can send
+/// datagrams to each other over without going through the OS.
+///
+/// Addresses on the network are all `0.0.0.0` with a made up port,
+/// which can't be mistaken for a real peer.
+#[derive(Clone, Default)]
+pub struct ChannelNetwork {
+    inner: Arc<Mutex<NetworkInner>>,
+}
+
+#[derive(Default)]
+struct NetworkInner {
+    endpoints: HashMap<SocketAddr, Endpoint>,
+    /// Last port handed out.
+    port: u16,
+}
+
+/// The sending half of a `ChannelTransport`.
+struct Endpoint {
+    inbox: Sender<(SocketAddr, Vec<u8>)>,
+    readiness: SetReadiness,
+}
+
+/// Transport that's bound to a `ChannelNetwork`.
+///
+/// This is always writable, and is readable whenever there are
+/// datagrams queued up. Datagrams sent to addresses that aren't bound
+/// are silently dropped, like UDP.
+pub struct ChannelTransport {
+    addr: SocketAddr,
+    network: ChannelNetwork,
+    inbox: Receiver<(SocketAddr, Vec<u8>)>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+/// Transport for a hosted server, which takes local players over a
+/// `ChannelNetwork` and remote players over UDP.
+pub struct HostTransport {
+    udp: UdpSocket,
+    local: ChannelTransport,
+}
+
+/// Binds a UDP socket to use as a transport.
+pub fn bind_udp(addr: SocketAddr) -> Result<UdpSocket, Error> {
+    UdpSocket::bind(&addr).map_err(|err| {
+        Error::BindSocket {
+            addr,
+            err,
+        }
+    })
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, data, &addr)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buffer)
+    }
+}
+
+impl ChannelNetwork {
+    pub fn new() -> ChannelNetwork {
+        ChannelNetwork::default()
+    }
+
+    /// Binds a new transport to an unused address on the network.
+    pub fn bind(&self) -> ChannelTransport {
+        self.bind_ip(Ipv4Addr::UNSPECIFIED.into())
+    }
+
+    /// Like `bind`, with an address on `ip`, to stand in for another
+    /// machine.
+    pub fn bind_ip(&self, ip: IpAddr) -> ChannelTransport {
+        let (registration, readiness) = Registration::new2();
+        // Channel transports can always be written to.
+        if let Err(err) = readiness.set_readiness(Ready::writable()) {
+            warn!("failed to set channel transport readiness: {}", err);
+        }
+        let (inbox_tx, inbox_rx) = channel::unbounded();
+
+        let mut inner = self.inner.lock();
+        let addr = loop {
+            // Port 0 usually means any port, so skip it.
+            inner.port = inner.port.wrapping_add(1).max(1);
+            let addr = SocketAddr::new(ip, inner.port);
+            if !inner.endpoints.contains_key(&addr) {
+                break addr;
+            }
+        };
+        inner.endpoints.insert(
+            addr,
+            Endpoint {
+                inbox: inbox_tx,
+                readiness: readiness.clone(),
+            },
+        );
+        ChannelTransport {
+            addr,
+            network: self.clone(),
+            inbox: inbox_rx,
+            registration,
+            readiness,
+        }
+    }
+
+    /// Checks whether an address is bound on this network.
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.inner.lock().endpoints.contains_key(&addr)
+    }
+}
+
+impl ChannelTransport {
+    /// Gets the address that this is bound to on its network.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn network(&self) -> &ChannelNetwork {
+        &self.network
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let inner = self.network.inner.lock();
+        if let Some(endpoint) = inner.endpoints.get(&addr) {
+            if endpoint.inbox.send((self.addr, data.to_vec())).is_ok() {
+                endpoint
+                    .readiness
+                    .set_readiness(Ready::readable() | Ready::writable())?;
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (addr, data) = match self.inbox.try_recv() {
+            Ok(datagram) => datagram,
+            Err(TryRecvError::Empty) => {
+                // Clear readable before checking again, so a datagram
+                // sent in between still sets it back.
+                self.readiness.set_readiness(Ready::writable())?;
+                match self.inbox.try_recv() {
+                    Ok(datagram) => {
+                        self.readiness.set_readiness(
+                            Ready::readable() | Ready::writable(),
+                        )?;
+                        datagram
+                    },
+                    Err(_) => return Err(io::ErrorKind::WouldBlock.into()),
+                }
+            },
+            // Can't happen while the network still has the sender.
+            Err(TryRecvError::Disconnected) => {
+                return Err(io::ErrorKind::NotConnected.into());
+            },
+        };
+        // Truncate like UDP does with datagrams that are too big.
+        let len = data.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&data[..len]);
+        Ok((len, addr))
+    }
+}
+
+impl Evented for ChannelTransport {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl Drop for ChannelTransport {
+    fn drop(&mut self) {
+        self.network.inner.lock().endpoints.remove(&self.addr);
+    }
+}
+
+impl HostTransport {
+    pub fn new(udp: UdpSocket, local: ChannelTransport) -> HostTransport {
+        HostTransport {
+            udp,
+            local,
+        }
+    }
+}
+
+impl Transport for HostTransport {
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if self.local.network().contains(addr) {
+            self.local.send_to(data, addr)
+        } else {
+            Transport::send_to(&self.udp, data, addr)
+        }
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.local.recv_from(buffer) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                Transport::recv_from(&self.udp, buffer)
+            },
+            result => result,
+        }
+    }
+}
+
+impl Evented for HostTransport {
+    // Both halves share the token, so an event from either one means
+    // the whole thing should be read from.
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.udp.register(poll, token, interest, opts)?;
+        self.local.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.udp.reregister(poll, token, interest, opts)?;
+        self.local.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.udp.deregister(poll)?;
+        self.local.deregister(poll)
+    }
+}
+
+#[test]
+fn client_connects_over_channel() {
+    use crate::networking::{client, server};
+    use nalgebra::Point2;
+    use std::time::Duration;
+
+    let network = ChannelNetwork::new();
+    let server_transport = network.bind();
+    let server_addr = server_transport.local_addr();
+    let (server, _) = server::host_with(
+        Box::new(server_transport),
+        server::ServerConfig::default(),
+    )
+    .unwrap();
+    let (client, connecting, _) = client::connect_with(
+        Box::new(network.bind()),
+        server_addr,
+        None,
+        Point2::origin(),
+        "local".to_owned(),
+        None,
+    )
+    .unwrap();
+    match connecting.recv_timeout(Duration::from_secs(5)) {
+        Ok(Ok(_)) => (),
+        _ => panic!("client failed to connect over channel"),
+    }
+    client.shutdown();
+    server.shutdown();
+}
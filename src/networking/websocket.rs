@@ -0,0 +1,555 @@
+use crate::networking::transport::Transport;
+use crate::networking::{Error, CONNECTION_TIMEOUT, MAX_PACKET_SIZE};
+use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+use crossbeam::channel::{self, Receiver, Sender, TryRecvError};
+use log::{debug, info, warn};
+use mio::net::UdpSocket;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use parking_lot::Mutex;
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Appended to the client's key before hashing it for the handshake
+/// reply, as RFC 6455 says.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest handshake request that gets read.
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// How long a new connection has to finish the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most connections open at once, since each one takes two threads.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Most connections open at once from one IP address.
+const MAX_CONNECTIONS_PER_IP: usize = 4;
+
+/// Datagrams that can be waiting to be sent on one connection before
+/// more get dropped, like a full UDP buffer would.
+const OUTBOX_SIZE: usize = 256;
+
+/// Datagrams that can be waiting to be received before more get
+/// dropped.
+const INBOX_SIZE: usize = 4096;
+
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Frames waiting to be written to a connection, as an opcode and a
+/// payload.
+type Outbox = Sender<(u8, Vec<u8>)>;
+
+/// Transport that accepts WebSocket connections, for clients that
+/// can't send UDP like a browser.
+///
+/// Every binary message is one datagram, with the same header and
+/// acks as over UDP, and each connection is addressed by its TCP peer
+/// address. The ordering and retransmission TCP adds are redundant,
+/// but harmless.
+///
+/// Connections are read and written on threads of their own, so this
+/// is readable whenever messages are queued up and always writable.
+pub struct WebSocketTransport {
+    addr: SocketAddr,
+    inbox: Receiver<(SocketAddr, Vec<u8>)>,
+    connections: Arc<Mutex<HashMap<SocketAddr, Outbox>>>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+/// Counts the connections that are open, in total and per IP address.
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts a connection as open until it's dropped.
+struct ConnectionGuard {
+    ip: IpAddr,
+    counts: Arc<Mutex<ConnectionCounts>>,
+}
+
+impl ConnectionGuard {
+    /// Counts a new connection, or returns `None` if there are already
+    /// too many.
+    fn new(ip: IpAddr, counts: &Arc<Mutex<ConnectionCounts>>) -> Option<Self> {
+        let mut locked = counts.lock();
+        let from_ip = locked.per_ip.get(&ip).cloned().unwrap_or(0);
+        if locked.total >= MAX_CONNECTIONS || from_ip >= MAX_CONNECTIONS_PER_IP
+        {
+            return None;
+        }
+        locked.total += 1;
+        locked.per_ip.insert(ip, from_ip + 1);
+        Some(ConnectionGuard {
+            ip,
+            counts: Arc::clone(counts),
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock();
+        counts.total -= 1;
+        let remove = match counts.per_ip.get_mut(&self.ip) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false,
+        };
+        if remove {
+            counts.per_ip.remove(&self.ip);
+        }
+    }
+}
+
+/// Transport for a headless server that takes native clients over
+/// UDP and browser clients over WebSockets at the same time.
+pub struct UdpWebSocketTransport {
+    udp: UdpSocket,
+    websocket: WebSocketTransport,
+}
+
+/// Accepts WebSocket connections on a new thread, to use as a
+/// transport.
+///
+/// Connections past `MAX_CONNECTIONS`, or `MAX_CONNECTIONS_PER_IP` from
+/// one address, are closed straight away.
+pub fn bind(addr: SocketAddr) -> Result<WebSocketTransport, Error> {
+    let bind_error = |err| {
+        Error::BindSocket {
+            addr,
+            err,
+        }
+    };
+    let listener = TcpListener::bind(addr).map_err(bind_error)?;
+    let addr = listener.local_addr().map_err(bind_error)?;
+    info!("accepting websocket connections on {}", addr);
+
+    let (registration, readiness) = Registration::new2();
+    // Sending only ever queues up a message, so it can't block.
+    if let Err(err) = readiness.set_readiness(Ready::writable()) {
+        warn!("failed to set websocket transport readiness: {}", err);
+    }
+    let (inbox_tx, inbox_rx) = channel::bounded(INBOX_SIZE);
+    let connections = Arc::new(Mutex::new(HashMap::new()));
+
+    let accept_readiness = readiness.clone();
+    let accept_connections = Arc::clone(&connections);
+    thread::spawn(move || {
+        let counts = Arc::new(Mutex::new(ConnectionCounts::default()));
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("failed to accept websocket connection: {}", err);
+                    continue;
+                },
+            };
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(_) => continue,
+            };
+            let guard = match ConnectionGuard::new(peer.ip(), &counts) {
+                Some(guard) => guard,
+                None => {
+                    debug!("turning away websocket connection from {}", peer);
+                    continue;
+                },
+            };
+            let inbox = inbox_tx.clone();
+            let readiness = accept_readiness.clone();
+            let connections = Arc::clone(&accept_connections);
+            thread::spawn(move || {
+                let _guard = guard;
+                let result =
+                    handle_connection(stream, &inbox, &readiness, &connections);
+                if let Err(err) = result {
+                    debug!(
+                        "websocket connection from {} failed: {}",
+                        peer, err
+                    );
+                }
+            });
+        }
+    });
+
+    Ok(WebSocketTransport {
+        addr,
+        inbox: inbox_rx,
+        connections,
+        registration,
+        readiness,
+    })
+}
+
+impl WebSocketTransport {
+    /// Gets the address that connections are accepted on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Checks whether there's a connection from an address.
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.connections.lock().contains_key(&addr)
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        // Like UDP, anything sent to a closed connection or one that
+        // isn't keeping up is silently dropped.
+        if let Some(outbox) = self.connections.lock().get(&addr) {
+            let _ = outbox.try_send((OPCODE_BINARY, data.to_vec()));
+        }
+        Ok(data.len())
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (addr, data) = match self.inbox.try_recv() {
+            Ok(datagram) => datagram,
+            Err(TryRecvError::Empty) => {
+                // Clear readable before checking again, so a message
+                // received in between still sets it back.
+                self.readiness.set_readiness(Ready::writable())?;
+                match self.inbox.try_recv() {
+                    Ok(datagram) => {
+                        self.readiness.set_readiness(
+                            Ready::readable() | Ready::writable(),
+                        )?;
+                        datagram
+                    },
+                    Err(_) => return Err(io::ErrorKind::WouldBlock.into()),
+                }
+            },
+            // Can't happen while the accepting thread has the sender.
+            Err(TryRecvError::Disconnected) => {
+                return Err(io::ErrorKind::NotConnected.into());
+            },
+        };
+        let len = data.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&data[..len]);
+        Ok((len, addr))
+    }
+}
+
+impl Evented for WebSocketTransport {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl UdpWebSocketTransport {
+    pub fn new(
+        udp: UdpSocket,
+        websocket: WebSocketTransport,
+    ) -> UdpWebSocketTransport {
+        UdpWebSocketTransport {
+            udp,
+            websocket,
+        }
+    }
+}
+
+impl Transport for UdpWebSocketTransport {
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if self.websocket.contains(addr) {
+            self.websocket.send_to(data, addr)
+        } else {
+            Transport::send_to(&self.udp, data, addr)
+        }
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.websocket.recv_from(buffer) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                Transport::recv_from(&self.udp, buffer)
+            },
+            result => result,
+        }
+    }
+}
+
+impl Evented for UdpWebSocketTransport {
+    // Both halves share the token, like `HostTransport`.
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.udp.register(poll, token, interest, opts)?;
+        self.websocket.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.udp.reregister(poll, token, interest, opts)?;
+        self.websocket.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.udp.deregister(poll)?;
+        self.websocket.deregister(poll)
+    }
+}
+
+/// Does the handshake and then passes messages along until the
+/// connection closes, with frames written from a second thread.
+fn handle_connection(
+    stream: TcpStream,
+    inbox: &Sender<(SocketAddr, Vec<u8>)>,
+    readiness: &SetReadiness,
+    connections: &Mutex<HashMap<SocketAddr, Outbox>>,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    accept_handshake(&mut reader, &stream)?;
+    debug!("websocket connection from {}", peer);
+    // Clients send something at least every ping, so a connection
+    // that goes quiet for this long is dead.
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+
+    let (outbox, outbox_rx) = channel::bounded(OUTBOX_SIZE);
+    let writer = stream.try_clone()?;
+    let writer_thread = thread::spawn(move || {
+        for (opcode, payload) in outbox_rx {
+            if write_frame(&mut &writer, opcode, &payload).is_err() {
+                break;
+            }
+        }
+    });
+    connections.lock().insert(peer, outbox.clone());
+
+    let result = read_messages(&mut reader, peer, inbox, readiness, &outbox);
+    // The writer finishes once every outbox is gone.
+    connections.lock().remove(&peer);
+    drop(outbox);
+    let _ = writer_thread.join();
+    let _ = stream.shutdown(Shutdown::Both);
+    debug!("websocket connection from {} closed", peer);
+    result
+}
+
+fn read_messages<R: Read>(
+    reader: &mut R,
+    peer: SocketAddr,
+    inbox: &Sender<(SocketAddr, Vec<u8>)>,
+    readiness: &SetReadiness,
+    outbox: &Outbox,
+) -> io::Result<()> {
+    while let Some((opcode, payload)) = read_frame(reader)? {
+        match opcode {
+            OPCODE_BINARY => {
+                if inbox.try_send((peer, payload)).is_ok() {
+                    readiness
+                        .set_readiness(Ready::readable() | Ready::writable())?;
+                }
+            },
+            OPCODE_PING => {
+                let _ = outbox.try_send((OPCODE_PONG, payload));
+            },
+            OPCODE_PONG => (),
+            OPCODE_CLOSE => {
+                let _ = outbox.try_send((OPCODE_CLOSE, Vec::new()));
+                break;
+            },
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "only binary messages are supported",
+                ));
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Reads the HTTP upgrade request and answers it.
+fn accept_handshake<R: BufRead>(
+    reader: &mut R,
+    mut writer: &TcpStream,
+) -> io::Result<()> {
+    let mut request = reader.take(MAX_REQUEST_BYTES);
+    let mut key = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if request.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(i) = line.find(':') {
+            if line[..i].trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(line[i + 1..].trim().to_owned());
+            }
+        }
+    }
+    let key = key.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "not a websocket handshake")
+    })?;
+    write!(
+        writer,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: \
+         websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    )
+}
+
+/// Hashes the client's handshake key into the value the reply has to
+/// have.
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    base64::encode(&sha1.digest().bytes())
+}
+
+/// Reads one frame from a client, returning its opcode and unmasked
+/// payload, or `None` if the connection was closed.
+///
+/// Browsers never fragment a message this small, so fragments aren't
+/// supported.
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let first = match reader.read_u8() {
+        Ok(first) => first,
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Ok(None);
+        },
+        Err(err) => return Err(err),
+    };
+    if first & 0x80 == 0 {
+        return Err(invalid("fragmented messages aren't supported"));
+    }
+    let second = reader.read_u8()?;
+    if second & 0x80 == 0 {
+        return Err(invalid("client frames have to be masked"));
+    }
+    let len = match second & 0x7F {
+        126 => u64::from(reader.read_u16::<BE>()?),
+        127 => reader.read_u64::<BE>()?,
+        len => u64::from(len),
+    };
+    if len > MAX_PACKET_SIZE as u64 {
+        return Err(invalid("frame is too large"));
+    }
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some((first & 0x0F, payload)))
+}
+
+/// Writes one unmasked frame, which is how servers send them.
+fn write_frame<W: Write>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.push(0x80 | opcode);
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= u16::max_value() as usize {
+        frame.push(126);
+        frame.write_u16::<BE>(payload.len() as u16)?;
+    } else {
+        frame.push(127);
+        frame.write_u64::<BE>(payload.len() as u64)?;
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)
+}
+
+#[test]
+fn handshake_accept_key() {
+    // The example from RFC 6455.
+    assert_eq!(
+        accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kHJsTNYWN0FXWo="
+    );
+}
+
+#[test]
+fn masked_frame_round_trip() {
+    let payload = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x80 | OPCODE_BINARY, 0x80 | 126];
+    frame.write_u16::<BE>(payload.len() as u16).unwrap();
+    frame.extend_from_slice(&mask);
+    frame
+        .extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+
+    let mut reader = &frame[..];
+    let (opcode, read) = read_frame(&mut reader).unwrap().unwrap();
+    assert_eq!(opcode, OPCODE_BINARY);
+    assert_eq!(read, payload);
+    assert!(read_frame(&mut reader).unwrap().is_none());
+
+    // Unmasked frames, like servers send, aren't accepted.
+    let mut unmasked = Vec::new();
+    write_frame(&mut unmasked, OPCODE_BINARY, &payload).unwrap();
+    assert!(read_frame(&mut &unmasked[..]).is_err());
+}
+
+#[test]
+fn connections_are_capped() {
+    let counts = Arc::new(Mutex::new(ConnectionCounts::default()));
+    let ip = "10.0.0.1".parse().unwrap();
+    let guards: Vec<_> = (0..MAX_CONNECTIONS_PER_IP)
+        .map(|_| ConnectionGuard::new(ip, &counts).unwrap())
+        .collect();
+    assert!(ConnectionGuard::new(ip, &counts).is_none());
+    let other = ConnectionGuard::new("10.0.0.2".parse().unwrap(), &counts);
+    assert!(other.is_some());
+    drop(guards);
+    assert!(ConnectionGuard::new(ip, &counts).is_some());
+    drop(other);
+    assert!(counts.lock().per_ip.is_empty());
+}
@@ -0,0 +1,127 @@
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of variants in `Section`.
+pub const SECTION_COUNT: usize = 5;
+
+/// Every section, in the order they happen during a frame.
+pub const SECTIONS: [Section; SECTION_COUNT] = [
+    Section::Events,
+    Section::Update,
+    Section::Interpolation,
+    Section::Record,
+    Section::Present,
+];
+
+lazy_static! {
+    static ref PROFILER: Mutex<Profiler> = Mutex::new(Profiler {
+        times: [Duration::from_secs(0); SECTION_COUNT],
+        open: Vec::new(),
+    });
+}
+
+/// Part of the main loop that's timed separately.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Section {
+    /// Polling window events.
+    Events,
+    /// Ticking the game.
+    Update,
+    /// Interpolating snapshots for drawing.
+    Interpolation,
+    /// Recording draw commands.
+    Record,
+    /// Submitting and presenting the frame.
+    Present,
+}
+
+/// Time spent in each section since the last `take_frame`.
+///
+/// Sections can nest, like interpolating while recording draw
+/// commands, in which case only the innermost one is counting. This
+/// way the sections add up to the total time.
+struct Profiler {
+    times: [Duration; SECTION_COUNT],
+    /// Sections that are currently open, innermost last, with when
+    /// each one last started counting.
+    open: Vec<(Section, Instant)>,
+}
+
+/// Counts time towards a section until it's dropped.
+///
+/// Use `profile_scope!` instead of making this directly. Scopes are
+/// only meant to be used from the main thread.
+pub struct Scope {
+    _private: (),
+}
+
+/// Times the rest of the enclosing block as part of a `Section`.
+#[macro_export]
+macro_rules! profile_scope {
+    ($section:ident) => {
+        let _profile_scope =
+            $crate::profile::Scope::new($crate::profile::Section::$section);
+    };
+}
+
+impl Section {
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::Events => "Events",
+            Section::Update => "Update",
+            Section::Interpolation => "Interpolation",
+            Section::Record => "Record",
+            Section::Present => "Present",
+        }
+    }
+}
+
+impl Profiler {
+    /// Adds the time since the innermost section started counting to
+    /// it.
+    fn pause(&mut self, now: Instant) {
+        if let Some(&(section, start)) = self.open.last() {
+            self.times[section as usize] += now - start;
+        }
+    }
+}
+
+impl Scope {
+    pub fn new(section: Section) -> Scope {
+        let now = Instant::now();
+        let mut profiler = PROFILER.lock();
+        profiler.pause(now);
+        profiler.open.push((section, now));
+        Scope {
+            _private: (),
+        }
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let now = Instant::now();
+        let mut profiler = PROFILER.lock();
+        profiler.pause(now);
+        profiler.open.pop();
+        // Resume the outer section.
+        if let Some((_, start)) = profiler.open.last_mut() {
+            *start = now;
+        }
+    }
+}
+
+/// Gets the time in seconds spent in each section since the last
+/// call, and starts counting again from zero.
+///
+/// The times are indexed in the same order as `SECTIONS`.
+pub fn take_frame() -> [f32; SECTION_COUNT] {
+    let mut profiler = PROFILER.lock();
+    let mut frame = [0.0; SECTION_COUNT];
+    for (time, seconds) in profiler.times.iter_mut().zip(frame.iter_mut()) {
+        *seconds = time.as_secs_f32();
+        *time = Duration::from_secs(0);
+    }
+    frame
+}
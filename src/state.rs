@@ -1,36 +1,182 @@
+use crate::animation::{Animations, Easing, Tween};
+use crate::audio::{Audio, Sound};
+use crate::camera::{Camera, CameraMode, SpectatorCamera};
+use crate::config::{Bookmark, Config, GamepadConfig};
 use crate::debug::DebugState;
+use crate::decorations;
+use crate::display::Display;
+use crate::effects::Effects;
 use crate::game::{
+    approach_cursor,
     clamp_cursor,
-    client::Game,
+    client::{Game, GameEvent},
+    hue_color,
+    replay::{
+        Highlight,
+        HighlightCutter,
+        Recording,
+        Replay,
+        MAX_REPLAY_SPEED,
+        MIN_REPLAY_SPEED,
+    },
+    tutorial::{Tutorial, TutorialStep, TUTORIAL_STEPS},
     GameSettings,
     GetPlayer,
+    InterpolationMode,
+    Palette,
+    PlayerId,
     RoundState,
+    CHARGING_CURSOR_SPEED,
+    MAX_NAME_LENGTH,
 };
 use crate::graphics::{Circle, CircleRenderer, DrawContext};
+use crate::input::{CursorInput, Gamepad};
 use crate::networking::{
     self,
-    client::{self, ClientHandle, ConnectedHandle, ConnectingHandle},
-    server::{self, ServerHandle},
+    client::{
+        self,
+        ClientHandle,
+        ConnectedHandle,
+        ConnectingHandle,
+        ConnectionState,
+    },
+    crypto::KeyExchange,
+    master::{self, Announcement, GameListing},
+    query::{self, ServerInfo},
+    server::{self, ServerCommand, ServerConfig, ServerHandle, ServerStatus},
+    transport::{self, ChannelNetwork, HostTransport},
+    Migration,
 };
-use easer::functions::*;
+use crate::profile_scope;
+use crate::stats::{SessionStats, Stats};
+use crate::ui;
+use crate::viewport::ViewportMapping;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
 use gfx_hal::Backend;
-use imgui::{im_str, ImString, Ui};
-use log::{debug, error, warn};
-use nalgebra::Point2;
+use imgui::{im_str, ImGui, ImString, Ui};
+use log::{debug, error, info, warn};
+use mio::net::UdpSocket;
+use nalgebra::{Point2, Vector2};
 use palette::LinSrgb;
+use smallvec::SmallVec;
+use std::collections::HashMap;
 use std::iter;
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::time::Instant;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use winit::{
-    dpi::LogicalSize,
+    dpi::{LogicalPosition, LogicalSize},
     ElementState,
     MouseButton,
+    MouseScrollDelta,
     VirtualKeyCode,
+    Window,
     WindowEvent,
 };
 
 const SCALE: f32 = 0.9;
 
+/// Brightness of players while warming up in the lobby.
+const WARM_UP_DIM: f32 = 0.5;
+
+/// Radius of the dot drawn in place of the OS cursor while the mouse
+/// is captured.
+const CAPTURED_CURSOR_RADIUS: f32 = 0.015;
+
+/// Radius of the latency indicator, as a fraction of the ball radius.
+const LATENCY_DOT_RADIUS: f32 = 0.25;
+
+/// Round trip time in milliseconds at which the latency indicator is
+/// fully red.
+const HIGH_LATENCY: f32 = 250.0;
+
+/// Radius of the dash cooldown ring, as a multiple of the cursor
+/// radius.
+const DASH_RING_RADIUS: f32 = 2.0;
+
+/// Number of different outline patterns players are marked with.
+const OUTLINE_PATTERNS: PlayerId = 4;
+
+/// Most highlights that are kept, after which the oldest are dropped.
+const MAX_HIGHLIGHTS: usize = 20;
+
+/// Number of targets put in practice games.
+const PRACTICE_TARGETS: u16 = 3;
+
+/// Seconds that players and targets in practice games take to come
+/// back after being hit.
+const PRACTICE_RESPAWN_DELAY: f32 = 1.0;
+
+/// Side length in pixels of the minimap.
+const MINIMAP_SIZE: f32 = 160.0;
+
+/// Distance in pixels scrolled by touchpads that counts as scrolling
+/// one line.
+const PIXELS_PER_LINE: f32 = 20.0;
+
+/// Length in seconds of the round transition animations.
+const TRANSITION_DURATION: f32 = 0.3;
+
+/// Time in seconds the winner is shown before the arena shrinks away.
+const WINNER_REVEAL_DELAY: f32 = 0.5;
+
+/// Seconds the camera takes to zoom out to the whole arena again for
+/// the next round.
+const CAMERA_ZOOM_OUT_DURATION: f32 = 0.8;
+
+/// Ids of animations that play while in a game.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Transition {
+    /// Scale of the whole arena.
+    ArenaScale,
+    /// Radius of the circle in the winner's color that covers the
+    /// arena, as a fraction of the arena radius.
+    WinnerReveal,
+    /// How far the camera is zoomed in on the winner's ball.
+    CameraFocus,
+}
+
+/// How long to wait for a server before giving up on connecting.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait on exit for networking threads to stop before
+/// leaving them behind.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn time_left(deadline: Instant) -> Duration {
+    let now = Instant::now();
+    if now < deadline {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+/// Waits until a done channel gets a message or its sender is
+/// dropped, either of which means that the thread is finishing.
+///
+/// Returns `false` if that doesn't happen before the deadline.
+fn finished<T>(done: &Receiver<T>, deadline: Instant) -> bool {
+    match done.recv_timeout(time_left(deadline)) {
+        Err(RecvTimeoutError::Timeout) => false,
+        _ => true,
+    }
+}
+
+/// Joins any networking threads that have already finished, so that
+/// they don't pile up over a long session.
+fn join_finished(threads: &mut Vec<JoinHandle<()>>) {
+    let (finished, running) =
+        threads.drain(..).partition::<Vec<_>, _>(|thread| thread.is_finished());
+    *threads = running;
+    for thread in finished {
+        if thread.join().is_err() {
+            warn!("a networking thread panicked");
+        }
+    }
+}
+
 fn bounds_circle(scale: f32, settings: Option<&GameSettings>) -> Circle {
     let bounds_radius = match settings {
         Some(settings) => settings.bounds_radius,
@@ -39,22 +185,634 @@ fn bounds_circle(scale: f32, settings: Option<&GameSettings>) -> Circle {
     Circle {
         center: Point2::new(0.0, 0.0),
         radius: scale * bounds_radius,
+        inner_radius: 0.0,
         color: LinSrgb::new(1.0, 1.0, 1.0),
     }
 }
 
+/// Whether this client's player isn't in play, because it's either
+/// spectating or dead, which leaves the camera free to move around.
+fn watching(game: &Game) -> bool {
+    let player_id = game.player_id();
+    game.latest_players()
+        .into_iter()
+        .find(|&(id, _)| id == player_id)
+        .map_or(true, |(_, player)| !player.state().alive())
+}
+
+/// Gets where every surviving player's ball is, for the spectator
+/// camera to pick from.
+fn living_balls(game: &Game) -> Vec<(PlayerId, Point2<f32>)> {
+    game.latest_players()
+        .into_iter()
+        .filter(|(_, player)| player.state().alive())
+        .map(|(id, player)| (id, player.state().ball.position))
+        .collect()
+}
+
+/// Moves a circle by the screen shake offset.
+fn shaken(circle: Circle, offset: Vector2<f32>) -> Circle {
+    Circle {
+        center: circle.center + offset,
+        ..circle
+    }
+}
+
+/// Gets a dot just above a player's ball that goes from green to red
+/// as their round trip time, `ping`, goes up.
+fn latency_circle(ball: Circle, ping: u16) -> Circle {
+    let lag = (f32::from(ping) / HIGH_LATENCY).min(1.0);
+    let radius = LATENCY_DOT_RADIUS * ball.radius;
+    Circle {
+        center: ball.center - Vector2::new(0.0, ball.radius + 2.0 * radius),
+        radius,
+        inner_radius: 0.0,
+        color: LinSrgb::new(lag, 1.0 - lag, 0.0),
+    }
+}
+
+/// Gets a ring around a cursor that thins out as the dash cools down,
+/// where `cooldown` is the fraction of it left.
+fn dash_ring(cursor: Circle, cooldown: f32) -> Circle {
+    let radius = DASH_RING_RADIUS * cursor.radius;
+    Circle {
+        radius,
+        inner_radius: radius - cooldown * (radius - cursor.radius),
+        ..cursor
+    }
+}
+
+/// Gets rings to draw over a player's ball, in one of a few patterns
+/// picked by their id, so that players with similar colors can still
+/// be told apart.
+fn outline_circles(id: PlayerId, ball: Circle) -> SmallVec<[Circle; 2]> {
+    let light = LinSrgb::new(1.0, 1.0, 1.0);
+    let dark = LinSrgb::new(0.0, 0.0, 0.0);
+    let ring = |inner: f32, outer: f32, color: LinSrgb| {
+        Circle {
+            inner_radius: inner * ball.radius,
+            radius: outer * ball.radius,
+            color,
+            ..ball
+        }
+    };
+    let mut circles = SmallVec::new();
+    match id % OUTLINE_PATTERNS {
+        0 => circles.push(ring(0.8, 1.0, light)),
+        1 => circles.push(ring(0.8, 1.0, dark)),
+        2 => {
+            circles.push(ring(0.8, 1.0, light));
+            circles.push(ring(0.0, 0.25, light));
+        },
+        _ => {
+            circles.push(ring(0.8, 1.0, dark));
+            circles.push(ring(0.4, 0.55, dark));
+        },
+    }
+    circles
+}
+
+/// Lists every connected player along with how they're doing.
+fn scoreboard_window<'a>(ui: &Ui<'a>, game: &Game) {
+    ui.window(im_str!("Scoreboard"))
+        .always_auto_resize(true)
+        .title_bar(false)
+        .build(|| {
+            ui.columns(5, im_str!("scoreboard"), false);
+            for header in &["", "name", "status", "wins", "ping"] {
+                ui.text(header);
+                ui.next_column();
+            }
+            ui.separator();
+
+            let alive = game
+                .latest_players()
+                .into_iter()
+                .map(|(id, player)| (id, player.state().alive()))
+                .collect::<HashMap<_, _>>();
+            let mut players = game.players.iter().collect::<Vec<_>>();
+            players.sort_by(|(_, a), (_, b)| b.wins.cmp(&a.wins));
+            for (id, player) in players {
+                let color = player.color;
+                ui.color_button(
+                    &ImString::new(format!("##{}", id)),
+                    [color.red, color.green, color.blue, 1.0],
+                )
+                .build();
+                ui.next_column();
+                ui.text(&player.name);
+                ui.next_column();
+                let status = match alive.get(id) {
+                    Some(true) => "alive",
+                    Some(false) => "dead",
+                    None => "joining",
+                };
+                ui.text(status);
+                ui.next_column();
+                ui.text(format!("{}", player.wins));
+                ui.next_column();
+                match game.ping(*id) {
+                    Some(ping) => ui.text(format!("{} ms", ping)),
+                    None => ui.text("?"),
+                }
+                ui.next_column();
+            }
+            ui.columns(1, im_str!("scoreboard"), false);
+        });
+}
+
+/// Shows an overview of the whole arena in a small window, with
+/// balls filled in and cursors outlined.
+fn minimap_window<'a>(
+    ui: &Ui<'a>,
+    game: &Game,
+    cursor: Point2<f32>,
+    mode: InterpolationMode,
+) {
+    let settings = game.settings();
+    let players =
+        game.interpolated_players(clamp_cursor(cursor, settings), mode);
+    ui.window(im_str!("Minimap"))
+        .always_auto_resize(true)
+        .title_bar(false)
+        .build(|| {
+            let (x, y) = ui.get_cursor_screen_pos();
+            let half = 0.5 * MINIMAP_SIZE;
+            let center = Point2::new(x + half, y + half);
+            let scale = half / settings.bounds_radius;
+            let to_screen = |point: Point2<f32>| {
+                let point = center + point.coords * scale;
+                (point.x, point.y)
+            };
+
+            let draw_list = ui.get_window_draw_list();
+            draw_list
+                .add_circle((center.x, center.y), half, [1.0, 1.0, 1.0, 1.0])
+                .build();
+            for (_, player) in players.into_iter() {
+                let color = player.static_state().color;
+                let color = [color.red, color.green, color.blue, 1.0];
+                let state = player.state();
+                draw_list
+                    .add_circle(
+                        to_screen(state.ball.position),
+                        (scale * settings.ball_radius).max(1.0),
+                        color,
+                    )
+                    .filled(true)
+                    .build();
+                if let Some(cursor) = state.cursor {
+                    draw_list
+                        .add_circle(
+                            to_screen(cursor),
+                            (scale * settings.cursor_radius).max(1.0),
+                            color,
+                        )
+                        .build();
+                }
+            }
+            drop(draw_list);
+            ui.dummy((MINIMAP_SIZE, MINIMAP_SIZE));
+        });
+}
+
+/// Shows recent messages from the server, if there are any.
+fn messages_window<'a>(ui: &Ui<'a>, game: &Game) {
+    if game.messages().next().is_none() {
+        return;
+    }
+    ui.window(im_str!("Messages"))
+        .always_auto_resize(true)
+        .title_bar(false)
+        .build(|| {
+            for message in game.messages() {
+                ui.text(message);
+            }
+        });
+}
+
+/// Lets everyone know the server has frozen the game.
+fn server_paused_window<'a>(ui: &Ui<'a>, game: &Game) {
+    if !game.paused() {
+        return;
+    }
+    ui.window(im_str!("Server paused"))
+        .always_auto_resize(true)
+        .title_bar(false)
+        .build(|| {
+            ui.text(im_str!("The game is paused"));
+        });
+}
+
+/// Shows what the camera is looking at while not playing, with
+/// buttons to switch what it does.
+fn camera_window<'a>(
+    ui: &Ui<'a>,
+    spectator: &mut SpectatorCamera,
+    game: &Game,
+) {
+    ui.window(im_str!("Camera")).always_auto_resize(true).build(|| {
+        match spectator.mode {
+            CameraMode::Arena => ui.text(im_str!("Watching the whole arena")),
+            CameraMode::Free => ui.text(im_str!("Free camera")),
+            CameraMode::Follow(id) => {
+                match game.players.get(&id) {
+                    Some(player) => {
+                        ui.text(format!("Following {}", player.name))
+                    },
+                    None => ui.text(im_str!("Following someone who left")),
+                }
+            },
+            CameraMode::Director => ui.text(im_str!("Following the action")),
+        }
+        if ui.small_button(im_str!("Whole arena")) {
+            spectator.mode = CameraMode::Arena;
+        }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Director")) {
+            spectator.mode = CameraMode::Director;
+        }
+        ui.text(im_str!("Click a ball or use the arrow keys to follow it."));
+        ui.text(im_str!("Drag to pan and scroll to zoom."));
+    });
+}
+
+/// Shows the controls for playing back a replay, returning whether
+/// it should be closed.
+fn replay_window<'a>(ui: &Ui<'a>, replay: &mut Replay, audio: &Audio) -> bool {
+    let mut close = false;
+    ui.window(im_str!("Replay")).always_auto_resize(true).build(|| {
+        let mut time = replay.time();
+        if ui
+            .slider_float(im_str!("Time"), &mut time, 0.0, replay.duration())
+            .build()
+        {
+            replay.seek(time);
+        }
+        let label = if replay.playing {
+            im_str!("Pause")
+        } else {
+            im_str!("Play")
+        };
+        if ui.small_button(label) {
+            audio.play(Sound::Click);
+            // Playing again from the end starts over.
+            if !replay.playing && replay.time() >= replay.duration() {
+                replay.seek(0.0);
+            }
+            replay.playing = !replay.playing;
+        }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Step back")) {
+            audio.play(Sound::Click);
+            replay.step_back();
+        }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Step forward")) {
+            audio.play(Sound::Click);
+            replay.step_forward();
+        }
+        ui.slider_float(
+            im_str!("Speed"),
+            &mut replay.speed,
+            MIN_REPLAY_SPEED,
+            MAX_REPLAY_SPEED,
+        )
+        .build();
+        ui.separator();
+        if ui.small_button(im_str!("Back to menu")) {
+            audio.play(Sound::Click);
+            close = true;
+        }
+    });
+    close
+}
+
+/// Shows what the hosted server is doing, with some controls over
+/// it.
+fn server_window<'a>(
+    ui: &Ui<'a>,
+    server: &ServerHandle,
+    status: Option<&ServerStatus>,
+    audio: &Audio,
+) {
+    ui.window(im_str!("Server")).always_auto_resize(true).build(|| {
+        let status = match status {
+            Some(status) => status,
+            None => {
+                ui.text(im_str!("Waiting for server status..."));
+                return;
+            },
+        };
+        ui.text(format!("round: {:?}", status.round));
+        match status.max_players {
+            Some(max_players) => {
+                ui.text(format!(
+                    "players: {}/{}",
+                    status.clients.len(),
+                    max_players
+                ))
+            },
+            None => ui.text(format!("players: {}", status.clients.len())),
+        }
+
+        if ui.small_button(im_str!("Start round")) {
+            audio.play(Sound::Click);
+            server.command(ServerCommand::StartRound);
+        }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Reset scores")) {
+            audio.play(Sound::Click);
+            server.command(ServerCommand::ResetScores);
+        }
+        ui.same_line(0.0);
+        let label = if status.paused {
+            im_str!("Resume game")
+        } else {
+            im_str!("Pause game")
+        };
+        if ui.small_button(label) {
+            audio.play(Sound::Click);
+            server.command(ServerCommand::SetPaused(!status.paused));
+        }
+        // Zero means no limit.
+        let mut max_players = i32::from(status.max_players.unwrap_or(0));
+        if ui.input_int(im_str!("Max players"), &mut max_players).build() {
+            let max_players = max_players.max(0).min(i32::from(std::u16::MAX));
+            let max_players = if max_players == 0 {
+                None
+            } else {
+                Some(max_players as u16)
+            };
+            server.command(ServerCommand::SetMaxPlayers(max_players));
+        }
+        ui.separator();
+
+        ui.columns(7, im_str!("clients"), false);
+        for header in
+            &["id", "name", "address", "wins", "ping", "violations", "unacked"]
+        {
+            ui.text(header);
+            ui.next_column();
+        }
+        ui.separator();
+        for client in &status.clients {
+            ui.text(format!("{}", client.player));
+            ui.next_column();
+            if client.alive {
+                ui.text(&client.name);
+            } else {
+                ui.text_disabled(&ImString::new(client.name.as_str()));
+            }
+            ui.next_column();
+            ui.text(format!("{}", client.addr));
+            ui.next_column();
+            ui.text(format!("{}", client.wins));
+            ui.next_column();
+            match client.rtt {
+                Some(rtt) => ui.text(format!("{:.0} ms", rtt * 1000.0)),
+                None => ui.text("?"),
+            }
+            ui.next_column();
+            ui.text(format!("{}", client.violations));
+            ui.next_column();
+            ui.text(format!("{}", client.unacked));
+            ui.next_column();
+        }
+        ui.columns(1, im_str!("clients"), false);
+    });
+}
+
+/// Shows the controls for a practice game, which can have targets
+/// turned on and off, and be reset.
+fn practice_window<'a>(
+    ui: &Ui<'a>,
+    server: &ServerHandle,
+    targets: &mut bool,
+    tutorial: &mut Option<Tutorial>,
+    audio: &Audio,
+) {
+    ui.window(im_str!("Practice")).always_auto_resize(true).build(|| {
+        if ui.checkbox(im_str!("Targets"), targets) {
+            audio.play(Sound::Click);
+            let count = if *targets {
+                PRACTICE_TARGETS
+            } else {
+                0
+            };
+            server.command(ServerCommand::SetTargets(count));
+        }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Reset")) {
+            audio.play(Sound::Click);
+            server.command(ServerCommand::Reset);
+        }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Tutorial")) {
+            audio.play(Sound::Click);
+            *tutorial = Some(Tutorial::default());
+        }
+    });
+}
+
+/// Shows the current tutorial prompt, with a button to skip the rest
+/// of it, or to close it once it's done.
+fn tutorial_window<'a>(
+    ui: &Ui<'a>,
+    tutorial: &mut Option<Tutorial>,
+    audio: &Audio,
+) {
+    let step = match tutorial {
+        Some(tutorial) => tutorial.step(),
+        None => return,
+    };
+    let mut close = false;
+    ui.window(im_str!("Tutorial")).always_auto_resize(true).build(|| {
+        if step != TutorialStep::Done {
+            ui.text(format!("Step {} of {}", step.number(), TUTORIAL_STEPS));
+        }
+        for line in step.prompt() {
+            ui.text(line);
+        }
+        let label = if step == TutorialStep::Done {
+            im_str!("Close")
+        } else {
+            im_str!("Skip tutorial")
+        };
+        if ui.small_button(label) {
+            audio.play(Sound::Click);
+            close = true;
+        }
+    });
+    if close {
+        *tutorial = None;
+    }
+}
+
+/// Lists the saved highlights, returning the index of one to watch.
+fn highlights_window<'a>(
+    ui: &Ui<'a>,
+    highlights: &mut Vec<Highlight>,
+    open: &mut bool,
+    audio: &Audio,
+) -> Option<usize> {
+    let mut watch = None;
+    ui.window(im_str!("Highlights"))
+        .always_auto_resize(true)
+        .opened(open)
+        .build(|| {
+            if highlights.is_empty() {
+                ui.text(im_str!("No highlights yet."));
+            }
+            let mut remove = None;
+            for (i, highlight) in highlights.iter().enumerate() {
+                if ui.small_button(&im_str!("Watch##{}", i)) {
+                    audio.play(Sound::Click);
+                    watch = Some(i);
+                }
+                ui.same_line(0.0);
+                if ui.small_button(&im_str!("Remove##{}", i)) {
+                    audio.play(Sound::Click);
+                    remove = Some(i);
+                }
+                ui.same_line(0.0);
+                ui.text(&highlight.name);
+            }
+            if let Some(i) = remove {
+                highlights.remove(i);
+            }
+        });
+    watch
+}
+
+/// Names a highlight of a kill that this client's player was part of.
+fn highlight_name(game: &Game, killer: PlayerId, victim: PlayerId) -> String {
+    let name = |id| {
+        game.players.get(&id).map_or("someone", |player| player.name.as_str())
+    };
+    let player_id = game.player_id();
+    if killer == victim {
+        "You knocked yourself out".to_owned()
+    } else if killer == player_id {
+        format!("You knocked out {}", name(victim))
+    } else {
+        format!("{} knocked you out", name(killer))
+    }
+}
+
+/// Shows the recorded history for every server.
+fn stats_window<'a>(ui: &Ui<'a>, stats: &Stats, open: &mut bool) {
+    ui.window(im_str!("Stats")).always_auto_resize(true).opened(open).build(
+        || {
+            if stats.servers.is_empty() {
+                ui.text(im_str!("No games played yet."));
+            }
+            for (server, sessions) in &stats.servers {
+                ui.tree_node(&ImString::new(server.as_str())).build(|| {
+                    let totals = Stats::totals(sessions);
+                    ui.text(im_str!("Total"));
+                    session_text(ui, &totals);
+                    for (i, session) in sessions.iter().enumerate().rev() {
+                        ui.separator();
+                        ui.text(format!("Session {}", i + 1));
+                        session_text(ui, session);
+                    }
+                });
+            }
+        },
+    );
+}
+
+fn session_text<'a>(ui: &Ui<'a>, session: &SessionStats) {
+    ui.text(format!(
+        "kills: {}, deaths: {}, wins: {}",
+        session.kills, session.deaths, session.wins
+    ));
+    ui.text(format!("play time: {:.0} min", session.play_time / 60.0));
+}
+
+/// Latest known status of a bookmarked server.
+enum QueryState {
+    Pending(Receiver<Option<ServerInfo>>),
+    Answered(ServerInfo),
+    NoResponse,
+}
+
+struct BookmarkEntry {
+    bookmark: Bookmark,
+    status: QueryState,
+}
+
+/// Latest list of games from the master server.
+enum GameListState {
+    /// Not asked for yet, which happens once the list is first shown.
+    NotRequested,
+    Pending(Receiver<Option<Vec<GameListing>>>),
+    Answered(Vec<GameListing>),
+    NoResponse,
+}
+
 struct Connecting {
+    addr: SocketAddr,
     server: Option<ServerHandle>,
     client: ClientHandle,
     done: ConnectingHandle,
+    started: Instant,
+    /// Preferred hue sent in the handshake.
+    hue: Option<f32>,
+    /// Whether this is an offline practice game.
+    practice: bool,
 }
 
 pub struct GameState {
     error_text: Option<ImString>,
     server_addr: ImString,
+    rendezvous_addr: ImString,
     server_addr_host: ImString,
+    /// Whether hosted servers encrypt connections.
+    host_encrypted: bool,
+    /// Whether hosted servers are listed on the master server.
+    host_announced: bool,
+    master_server: Option<String>,
+    internet_games: GameListState,
+    bookmark_name: ImString,
+    bookmarks: Vec<BookmarkEntry>,
+    player_name: ImString,
+    /// Preferred player hue, or `None` for a random one.
+    color_hue: Option<f32>,
     cursor: Point2<f32>,
+    /// Direction the cursor last moved in, which dashes go towards.
+    cursor_direction: Vector2<f32>,
+    /// Whether the cursor jumped, so the OS cursor has to be moved to
+    /// match.
+    warp_cursor: bool,
     screen: Screen,
+    stats: Stats,
+    show_stats: bool,
+    show_minimap: bool,
+    appearance: ui::Appearance,
+    display: Display,
+    /// Whether the window has the cursor grabbed and hidden, which
+    /// follows whether it's captured in a game.
+    grabbed: bool,
+    audio: Audio,
+    gamepad: Gamepad,
+    gamepad_config: GamepadConfig,
+    /// Every networking thread that's been started, which are joined
+    /// on shutdown.
+    threads: Vec<JoinHandle<()>>,
+    /// Where to carry on the last game after its host left, along
+    /// with the address it was at.
+    migration: Option<(SocketAddr, Migration)>,
+    /// Recording of the last game that was left, to watch from the
+    /// main menu.
+    last_recording: Option<Recording>,
+    /// Clips of the kills this client's player was part of, oldest
+    /// first.
+    highlights: Vec<Highlight>,
+    show_highlights: bool,
+    /// Whether practice games have targets in them.
+    practice_targets: bool,
 }
 
 enum Screen {
@@ -62,12 +820,65 @@ enum Screen {
         connecting: Option<Connecting>,
     },
     InGame {
+        /// Address of the server, for recording stats.
+        addr: SocketAddr,
         server: Option<ServerHandle>,
-        _client: ClientHandle,
+        /// Whether this is an offline practice game, which doesn't
+        /// count towards stats.
+        practice: bool,
+        /// Tutorial being gone through, which practice games start
+        /// with.
+        tutorial: Option<Tutorial>,
+        client: ClientHandle,
         done: ConnectedHandle,
         game: Game,
         locked: bool,
+        /// Whether the pause menu is open. The game keeps running,
+        /// but cursor input isn't sent.
+        paused: bool,
+        /// Whether the mouse is captured, so that the cursor moves
+        /// with raw mouse motion and can't leave the window.
+        captured: bool,
         show_settings: bool,
+        /// Whether the scoreboard key is held down.
+        show_scoreboard: bool,
+        /// Latest status of the hosted server, if there is one.
+        server_status: Option<ServerStatus>,
+        animations: Animations<Transition>,
+        effects: Effects,
+        camera: Camera,
+        /// Camera that can be moved around while not playing, which
+        /// `camera` follows whenever the round isn't over.
+        spectator: SpectatorCamera,
+        /// Whether the left mouse button is held down to pan the
+        /// spectator camera.
+        dragging: bool,
+        /// Another player sharing this window, if one has joined.
+        second: Option<SecondPlayer>,
+        /// Everything the server sent, to watch again after leaving.
+        recording: Recording,
+        /// Follows `recording` to cut highlights out of it.
+        highlight_cutter: HighlightCutter,
+    },
+    Replay {
+        replay: Replay,
+        /// Whether this is the last game, rather than a highlight, so
+        /// it goes back to be watched again.
+        last_game: bool,
+    },
+}
+
+/// A second local player, who has their own connection to the same
+/// server and moves with the WASD keys or a gamepad.
+enum SecondPlayer {
+    Connecting(Connecting),
+    Joined {
+        client: ClientHandle,
+        done: ConnectedHandle,
+        game: Game,
+        cursor: Point2<f32>,
+        /// Whether each of W, A, S and D is held down.
+        keys: [bool; 4],
     },
 }
 
@@ -76,68 +887,614 @@ impl Connecting {
         addr: SocketAddr,
         debug: &DebugState,
         cursor: Point2<f32>,
+        name: &str,
+        hue: Option<f32>,
+        config: ServerConfig,
+        threads: &mut Vec<JoinHandle<()>>,
+    ) -> Result<Connecting, networking::Error> {
+        let socket = transport::bind_udp(addr)?;
+        Connecting::host_on(socket, debug, cursor, name, hue, config, threads)
+    }
+
+    /// Like `host`, on a socket that's already bound.
+    fn host_on(
+        socket: UdpSocket,
+        debug: &DebugState,
+        cursor: Point2<f32>,
+        name: &str,
+        hue: Option<f32>,
+        config: ServerConfig,
+        threads: &mut Vec<JoinHandle<()>>,
+    ) -> Result<Connecting, networking::Error> {
+        let addr = socket.local_addr().map_err(|err| {
+            networking::Error::BindSocket {
+                addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                err,
+            }
+        })?;
+        join_finished(threads);
+        // Play over an in-process channel, while remote players still
+        // connect over UDP.
+        let network = ChannelNetwork::new();
+        let local = network.bind();
+        let local_addr = local.local_addr();
+        let transport = HostTransport::new(socket, local);
+        // Someone else takes over when this player leaves, rather
+        // than the game ending for everyone.
+        let config = ServerConfig {
+            migrate: true,
+            ..config
+        };
+        let (server, server_thread) =
+            server::host_with(Box::new(transport), config)?;
+        threads.push(server_thread);
+        let (client, done, client_thread) = client::connect_with(
+            Box::new(network.bind()),
+            local_addr,
+            Some(debug.network_tx.clone()),
+            cursor,
+            name.to_owned(),
+            hue,
+        )?;
+        threads.push(client_thread);
+        Ok(Connecting {
+            addr,
+            server: Some(server),
+            client,
+            done,
+            started: Instant::now(),
+            hue,
+            practice: false,
+        })
+    }
+
+    /// Starts an offline practice game, on a server that only this
+    /// client can reach.
+    fn practice(
+        debug: &DebugState,
+        cursor: Point2<f32>,
+        name: &str,
+        hue: Option<f32>,
+        targets: bool,
+        threads: &mut Vec<JoinHandle<()>>,
     ) -> Result<Connecting, networking::Error> {
-        let (server, _) = server::host(addr)?;
-        let (client, done, _) =
-            client::connect(addr, Some(debug.network_tx.clone()), cursor)?;
+        join_finished(threads);
+        // Nothing outside the process can send to a channel network,
+        // so there's no socket at all.
+        let network = ChannelNetwork::new();
+        let transport = network.bind();
+        let addr = transport.local_addr();
+        let (server, server_thread) =
+            server::host_with(Box::new(transport), ServerConfig::default())?;
+        threads.push(server_thread);
+        // Targets never move, so they'd be killed for idling, and they
+        // come back soon after being hit so there's always something
+        // to aim at. Rounds only end when the player resets them.
+        server.command(ServerCommand::SetSettings(GameSettings {
+            afk_timeout: None,
+            respawn_delay: Some(PRACTICE_RESPAWN_DELAY),
+            round_time_limit: None,
+            ..GameSettings::default()
+        }));
+        if targets {
+            server.command(ServerCommand::SetTargets(PRACTICE_TARGETS));
+        }
+        let (client, done, client_thread) = client::connect_with(
+            Box::new(network.bind()),
+            addr,
+            Some(debug.network_tx.clone()),
+            cursor,
+            name.to_owned(),
+            hue,
+        )?;
+        threads.push(client_thread);
         Ok(Connecting {
+            addr,
             server: Some(server),
             client,
             done,
+            started: Instant::now(),
+            hue,
+            practice: true,
         })
     }
 
+    /// Connects to a server, through a rendezvous if it might be
+    /// behind NAT.
     fn connect(
         addr: SocketAddr,
+        rendezvous: Option<SocketAddr>,
         debug: &DebugState,
         cursor: Point2<f32>,
+        name: &str,
+        hue: Option<f32>,
+        threads: &mut Vec<JoinHandle<()>>,
+    ) -> Result<Connecting, networking::Error> {
+        join_finished(threads);
+        let stats = Some(debug.network_tx.clone());
+        let (client, done, thread) = match rendezvous {
+            Some(rendezvous) => {
+                client::connect_via(
+                    addr,
+                    rendezvous,
+                    stats,
+                    cursor,
+                    name.to_owned(),
+                    hue,
+                )?
+            },
+            None => client::connect(addr, stats, cursor, name.to_owned(), hue)?,
+        };
+        threads.push(thread);
+        Ok(Connecting {
+            addr,
+            server: None,
+            client,
+            done,
+            started: Instant::now(),
+            hue,
+            practice: false,
+        })
+    }
+
+    /// Connects a second local player to the server that the first
+    /// one is playing on.
+    fn second_player(
+        addr: SocketAddr,
+        name: &str,
+        threads: &mut Vec<JoinHandle<()>>,
     ) -> Result<Connecting, networking::Error> {
-        let (client, done, _) =
-            client::connect(addr, Some(debug.network_tx.clone()), cursor)?;
+        join_finished(threads);
+        // A hosted server is bound to every interface, but that can't
+        // be connected to.
+        let ip = match addr.ip() {
+            ip if ip.is_unspecified() && ip.is_ipv4() => {
+                IpAddr::V4(Ipv4Addr::LOCALHOST)
+            },
+            ip if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        let (client, done, thread) = client::connect(
+            SocketAddr::new(ip, addr.port()),
+            None,
+            Point2::origin(),
+            name.to_owned(),
+            None,
+        )?;
+        threads.push(thread);
         Ok(Connecting {
+            addr,
             server: None,
             client,
             done,
+            started: Instant::now(),
+            hue: None,
+            practice: false,
         })
     }
+
+    /// Describes how far along the handshake is.
+    fn progress_text(&self) -> String {
+        let stage = match self.client.state() {
+            ConnectionState::Connecting {
+                attempts,
+            } if attempts <= 1 => "handshake sent, awaiting reply".to_owned(),
+            ConnectionState::Connecting {
+                attempts,
+            } => {
+                format!(
+                    "retrying {}/{}",
+                    attempts,
+                    client::MAX_HANDSHAKE_ATTEMPTS
+                )
+            },
+            ConnectionState::Connected {
+                ..
+            } => "connected, loading".to_owned(),
+            ConnectionState::Disconnecting => "giving up".to_owned(),
+        };
+        format!(
+            "Connecting to {}... {} ({:.1} s)",
+            self.addr,
+            stage,
+            self.started.elapsed().as_secs_f32()
+        )
+    }
+}
+
+impl BookmarkEntry {
+    fn new(bookmark: Bookmark) -> BookmarkEntry {
+        let status = BookmarkEntry::query(&bookmark.address);
+        BookmarkEntry {
+            bookmark,
+            status,
+        }
+    }
+
+    fn query(address: &str) -> QueryState {
+        match address.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+        {
+            Some(addr) => QueryState::Pending(query::query(addr)),
+            None => QueryState::NoResponse,
+        }
+    }
+
+    /// Asks the server for its status again.
+    fn refresh(&mut self) {
+        self.status = BookmarkEntry::query(&self.bookmark.address);
+    }
+
+    /// Checks if a pending query has been answered.
+    fn poll(&mut self) {
+        let answer = match self.status {
+            QueryState::Pending(ref rx) => rx.try_recv().ok(),
+            _ => None,
+        };
+        if let Some(answer) = answer {
+            self.status = match answer {
+                Some(info) => QueryState::Answered(info),
+                None => QueryState::NoResponse,
+            };
+        }
+    }
+
+    fn status_text(&self) -> String {
+        match self.status {
+            QueryState::Pending(_) => "querying...".to_owned(),
+            QueryState::Answered(ref info) => {
+                let mut text = format!(
+                    "{} players, {:.0} ms",
+                    info.players,
+                    info.ping * 1000.0
+                );
+                if info.version != networking::VERSION {
+                    text += &format!(", version {}", info.version);
+                }
+                text
+            },
+            QueryState::NoResponse => "no response".to_owned(),
+        }
+    }
+}
+
+impl GameListState {
+    fn request(master_server: &str) -> GameListState {
+        match master_server
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+        {
+            Some(addr) => GameListState::Pending(master::list(addr)),
+            None => GameListState::NoResponse,
+        }
+    }
+
+    /// Checks if a pending request has been answered.
+    fn poll(&mut self) {
+        let answer = match *self {
+            GameListState::Pending(ref rx) => rx.try_recv().ok(),
+            _ => None,
+        };
+        if let Some(answer) = answer {
+            *self = match answer {
+                Some(games) => GameListState::Answered(games),
+                None => GameListState::NoResponse,
+            };
+        }
+    }
+}
+
+impl SecondPlayer {
+    /// Finishes connecting or keeps the game up to date, returning
+    /// `None` once the player has left.
+    ///
+    /// If the host leaves and picked the second player to take over,
+    /// that's passed on through `migration`, for the first player to
+    /// host instead.
+    fn update(
+        self,
+        dt: f32,
+        addr: SocketAddr,
+        migration: &mut Option<(SocketAddr, Migration)>,
+        error_text: &mut Option<ImString>,
+    ) -> Option<SecondPlayer> {
+        match self {
+            SecondPlayer::Connecting(connecting) => {
+                match connecting.done.try_recv() {
+                    Ok(Ok((game, done))) => {
+                        info!("second player joined as {}", game.player_id());
+                        Some(SecondPlayer::Joined {
+                            client: connecting.client,
+                            done,
+                            game,
+                            cursor: Point2::origin(),
+                            keys: [false; 4],
+                        })
+                    },
+                    Ok(Err(err)) => {
+                        if let Some(err) = err {
+                            let err = format!(
+                                "second player failed to join: {}",
+                                err
+                            );
+                            error!("{}", err);
+                            *error_text = Some(ImString::new(err));
+                        }
+                        None
+                    },
+                    Err(_)
+                        if connecting.started.elapsed() > CONNECT_TIMEOUT =>
+                    {
+                        let err = "timed out adding a second player";
+                        warn!("{}", err);
+                        *error_text = Some(ImString::new(err));
+                        None
+                    },
+                    Err(_) => Some(SecondPlayer::Connecting(connecting)),
+                }
+            },
+            SecondPlayer::Joined {
+                client,
+                done,
+                mut game,
+                cursor,
+                keys,
+            } => {
+                game.tick(dt);
+                // Only the first player's game gets drawn, and plays
+                // sounds, so this one just has to keep up.
+                game.update_playback(Instant::now(), 0.0);
+                game.drain_events().for_each(drop);
+                match done.try_recv() {
+                    Ok(Some(networking::Error::Migrated(
+                        next @ Migration::Host(..),
+                    ))) => {
+                        info!("second player was picked to take over hosting");
+                        *migration = Some((addr, next));
+                        None
+                    },
+                    // Otherwise the first player's client follows the
+                    // game, and the second player can join again after.
+                    Ok(Some(networking::Error::Migrated(_))) => None,
+                    Ok(err) => {
+                        if let Some(err) = err {
+                            let err = format!(
+                                "second player's client stopped with error: {}",
+                                err
+                            );
+                            error!("{}", err);
+                            *error_text = Some(ImString::new(err));
+                        }
+                        None
+                    },
+                    Err(_) => {
+                        Some(SecondPlayer::Joined {
+                            client,
+                            done,
+                            game,
+                            cursor,
+                            keys,
+                        })
+                    },
+                }
+            },
+        }
+    }
 }
 
-impl Default for GameState {
-    fn default() -> GameState {
+impl GameState {
+    /// Creates the game state with preferences restored from the
+    /// config.
+    pub fn new(config: &Config) -> GameState {
         GameState {
             error_text: None,
-            server_addr: ImString::with_capacity(64),
-            server_addr_host: ImString::new("0.0.0.0:6666"),
+            server_addr: ui::input_string(&config.network.server_address),
+            rendezvous_addr: ui::input_string(
+                &config.network.rendezvous_address,
+            ),
+            server_addr_host: ui::input_string(&config.network.host_address),
+            host_encrypted: config.network.host_encrypted,
+            host_announced: config.network.host_announced,
+            master_server: config.network.master_server.clone(),
+            internet_games: GameListState::NotRequested,
+            bookmark_name: ui::input_string(""),
+            bookmarks: config
+                .network
+                .bookmarks
+                .iter()
+                .cloned()
+                .map(BookmarkEntry::new)
+                .collect(),
+            player_name: ui::input_string(&config.gameplay.player_name),
+            color_hue: config.gameplay.color_hue,
             cursor: Point2::new(0.0, 0.0),
+            cursor_direction: Vector2::zeros(),
+            warp_cursor: false,
             screen: Screen::MainMenu {
                 connecting: None,
             },
+            stats: Stats::load(),
+            show_stats: false,
+            show_minimap: false,
+            appearance: ui::Appearance::new(&config.interface),
+            display: Display::new(&config.graphics),
+            grabbed: false,
+            audio: Audio::new(config.audio.volume),
+            gamepad: Gamepad::new(),
+            gamepad_config: config.gamepad.clone(),
+            threads: Vec::new(),
+            migration: None,
+            last_recording: None,
+            highlights: Vec::new(),
+            show_highlights: false,
+            practice_targets: true,
+        }
+    }
+
+    /// Records the stats for the current session, if there is one,
+    /// stores preferences back into the config, and stops networking.
+    ///
+    /// This should be called before exiting.
+    pub fn shutdown(&mut self, config: &mut Config) {
+        if let Screen::InGame {
+            addr,
+            ref game,
+            practice: false,
+            ..
+        } = self.screen
+        {
+            self.stats.record(addr, game.session_stats());
+        }
+
+        config.network.server_address = self.server_addr.to_str().to_owned();
+        config.network.rendezvous_address =
+            self.rendezvous_addr.to_str().to_owned();
+        config.network.host_address = self.server_addr_host.to_str().to_owned();
+        config.network.host_encrypted = self.host_encrypted;
+        config.network.host_announced = self.host_announced;
+        config.network.bookmarks =
+            self.bookmarks.iter().map(|entry| entry.bookmark.clone()).collect();
+        config.gameplay.player_name = self.player_name.to_str().to_owned();
+        config.gameplay.color_hue = self.color_hue;
+        config.audio.volume = self.audio.volume();
+        config.gamepad = self.gamepad_config.clone();
+        self.appearance.save(&mut config.interface);
+        self.display.save(&mut config.graphics);
+        self.stop_networking();
+    }
+
+    /// Tells the connection and any hosted server to shut down, waits
+    /// for them to finish, and joins every networking thread.
+    ///
+    /// If something doesn't finish within `SHUTDOWN_TIMEOUT`, the
+    /// threads are left running rather than hanging on exit.
+    fn stop_networking(&mut self) {
+        let screen = mem::replace(
+            &mut self.screen,
+            Screen::MainMenu {
+                connecting: None,
+            },
+        );
+        let mut connecting = Vec::new();
+        let mut connected = Vec::new();
+        let mut servers = Vec::new();
+        match screen {
+            Screen::MainMenu {
+                connecting: Some(state),
+            } => connecting.push(state),
+            Screen::MainMenu {
+                connecting: None,
+            } => (),
+            Screen::InGame {
+                server,
+                client,
+                done,
+                second,
+                ..
+            } => {
+                client.shutdown();
+                connected.push(done);
+                servers.extend(server);
+                match second {
+                    Some(SecondPlayer::Connecting(state)) => {
+                        connecting.push(state)
+                    },
+                    Some(SecondPlayer::Joined {
+                        client,
+                        done,
+                        ..
+                    }) => {
+                        client.shutdown();
+                        connected.push(done);
+                    },
+                    None => (),
+                }
+            },
+            Screen::Replay {
+                ..
+            } => (),
+        }
+        for state in &mut connecting {
+            state.client.shutdown();
+            servers.extend(state.server.take());
+        }
+        for server in &servers {
+            server.shutdown();
+        }
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        let connecting_done = connecting.iter().all(|state| {
+            // The handshake might have finished just now, in which case
+            // the connection has to finish too.
+            match state.done.recv_timeout(time_left(deadline)) {
+                Ok(Ok((_, done))) => finished(&done, deadline),
+                Err(RecvTimeoutError::Timeout) => false,
+                _ => true,
+            }
+        });
+        let done = connecting_done &&
+            connected.iter().all(|done| finished(done, deadline)) &&
+            servers.iter().all(|server| finished(&server.done, deadline));
+        if !done {
+            warn!("networking didn't stop in time, leaving it running");
+            return;
+        }
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                warn!("a networking thread panicked");
+            }
         }
     }
-}
 
-impl GameState {
     pub fn handle_event(&mut self, size: &LogicalSize, event: &WindowEvent) {
+        // The OS cursor is stuck while captured, so raw motion from
+        // `handle_mouse_motion` moves the cursor instead.
         if let WindowEvent::CursorMoved {
             position,
             ..
         } = event
         {
-            let scale = (2.0 / size.width.min(size.height) as f32) / SCALE;
-            self.cursor = Point2::new(
-                scale * (position.x as f32 - 0.5 * size.width as f32),
-                scale * (position.y as f32 - 0.5 * size.height as f32),
+            if self.cursor_captured() {
+                return;
+            }
+            let position = self.display.mapping.to_world(
+                size.width as f32,
+                size.height as f32,
+                Point2::new(position.x as f32, position.y as f32),
             );
+            let last = self.cursor;
+            self.handle_cursor(CursorInput::Absolute(position / SCALE));
+            if let Screen::InGame {
+                ref game,
+                ref mut spectator,
+                dragging: true,
+                ..
+            } = self.screen
+            {
+                if watching(game) {
+                    spectator.pan((self.cursor - last) * SCALE, SCALE);
+                }
+            }
         }
 
         match self.screen {
             Screen::MainMenu {
                 ..
+            } |
+            Screen::Replay {
+                ..
             } => (),
             Screen::InGame {
-                ref game,
                 ref mut locked,
+                ref mut paused,
+                ref mut captured,
                 ref mut show_settings,
+                ref mut show_scoreboard,
+                ref mut second,
+                ref mut game,
+                ref camera,
+                ref mut spectator,
+                ref mut dragging,
                 ..
             } => {
                 match event {
@@ -145,47 +1502,423 @@ impl GameState {
                         input,
                         ..
                     } => {
+                        if let Some(SecondPlayer::Joined {
+                            ref mut keys,
+                            ..
+                        }) = *second
+                        {
+                            let key = match input.virtual_keycode {
+                                Some(VirtualKeyCode::W) => Some(0),
+                                Some(VirtualKeyCode::A) => Some(1),
+                                Some(VirtualKeyCode::S) => Some(2),
+                                Some(VirtualKeyCode::D) => Some(3),
+                                _ => None,
+                            };
+                            if let Some(key) = key {
+                                keys[key] =
+                                    input.state == ElementState::Pressed;
+                                return;
+                            }
+                        }
                         match input.virtual_keycode {
                             Some(VirtualKeyCode::S)
                                 if input.state == ElementState::Pressed =>
                             {
                                 *show_settings = !*show_settings;
-                            }
+                            },
+                            Some(VirtualKeyCode::Escape)
+                                if input.state == ElementState::Pressed =>
+                            {
+                                *paused = !*paused;
+                                *locked = false;
+                                *captured = false;
+                            },
+                            Some(VirtualKeyCode::M)
+                                if input.state == ElementState::Pressed =>
+                            {
+                                self.show_minimap = !self.show_minimap;
+                            },
+                            Some(VirtualKeyCode::Tab) => {
+                                *show_scoreboard =
+                                    input.state == ElementState::Pressed;
+                            },
+                            Some(VirtualKeyCode::Space)
+                                if input.state == ElementState::Pressed &&
+                                    !*paused &&
+                                    !*locked &&
+                                    !watching(game) =>
+                            {
+                                let direction = self.cursor_direction;
+                                if let Some(cursor) = game.dash(direction) {
+                                    self.cursor = cursor;
+                                    self.warp_cursor = true;
+                                }
+                            },
+                            Some(key @ VirtualKeyCode::Left) |
+                            Some(key @ VirtualKeyCode::Right)
+                                if input.state == ElementState::Pressed &&
+                                    watching(game) =>
+                            {
+                                let forward = key == VirtualKeyCode::Right;
+                                spectator.cycle(&living_balls(game), forward);
+                            },
                             _ => (),
                         }
                     },
-                    WindowEvent::CursorMoved {
+                    WindowEvent::MouseInput {
+                        state: ElementState::Pressed,
+                        button: MouseButton::Left,
                         ..
-                    } if !*locked => game.update_cursor(self.cursor),
+                    } if !*paused && watching(game) => {
+                        // Follow whoever was clicked, or pan if it
+                        // wasn't anyone.
+                        let point = camera.unapply(self.cursor * SCALE, SCALE);
+                        let balls = living_balls(game);
+                        let radius = game.settings().ball_radius;
+                        *dragging = !spectator.select(point, &balls, radius);
+                    },
                     WindowEvent::MouseInput {
                         state: ElementState::Pressed,
-                        button: MouseButton::Middle,
+                        button: MouseButton::Left,
+                        ..
+                    } if !*paused => {
+                        game.update_charging(true);
+                    },
+                    WindowEvent::MouseInput {
+                        state: ElementState::Released,
+                        button: MouseButton::Left,
                         ..
                     } => {
+                        *dragging = false;
+                        // Catch the cursor up to the mouse, which it
+                        // was trailing behind while charging.
+                        if game.charging() && !*locked && !*paused {
+                            game.update_cursor(self.cursor);
+                        }
+                        game.update_charging(false);
+                    },
+                    WindowEvent::MouseWheel {
+                        delta,
+                        ..
+                    } if !*paused && watching(game) => {
+                        let lines = match *delta {
+                            MouseScrollDelta::LineDelta(_, y) => y,
+                            MouseScrollDelta::PixelDelta(position) => {
+                                position.y as f32 / PIXELS_PER_LINE
+                            },
+                        };
+                        spectator.zoom(lines);
+                    },
+                    WindowEvent::MouseInput {
+                        state: ElementState::Pressed,
+                        button: MouseButton::Middle,
+                        ..
+                    } if !*paused => {
                         *locked = !*locked;
                     },
+                    WindowEvent::MouseInput {
+                        state: ElementState::Pressed,
+                        button: MouseButton::Right,
+                        ..
+                    } if !*paused => {
+                        *captured = !*captured;
+                    },
+                    // Let go of the mouse when switching to another
+                    // window.
+                    WindowEvent::Focused(false) => {
+                        *captured = false;
+                        game.update_charging(false);
+                    },
                     _ => (),
                 }
             },
         }
     }
 
+    /// Applies any changes to the UI appearance, which has to be done
+    /// before starting a frame.
+    pub fn apply_appearance(&mut self, imgui: &mut ImGui) {
+        self.appearance.apply(imgui);
+    }
+
+    /// Moves the cursor by raw mouse motion while the mouse is
+    /// captured. Raw motion is in physical pixels, so the window size
+    /// is scaled by the hidpi factor to match.
+    pub fn handle_mouse_motion(
+        &mut self,
+        size: &LogicalSize,
+        hidpi_factor: f64,
+        delta: (f64, f64),
+    ) {
+        if !self.cursor_captured() {
+            return;
+        }
+        let size = size.to_physical(hidpi_factor);
+        let delta = self.display.mapping.delta_to_world(
+            size.width as f32,
+            size.height as f32,
+            Vector2::new(delta.0 as f32, delta.1 as f32),
+        );
+        self.handle_cursor(CursorInput::Relative(delta / SCALE));
+    }
+
+    /// Whether the cursor is moved by raw mouse motion, which is only
+    /// while playing and not paused.
+    fn cursor_captured(&self) -> bool {
+        match self.screen {
+            Screen::InGame {
+                captured: true,
+                paused: false,
+                ..
+            } => true,
+            _ => false,
+        }
+    }
+
+    /// Grabs and hides the cursor when it's captured, and releases it
+    /// when it isn't.
+    pub fn apply_cursor_capture(&mut self, window: &Window) {
+        let captured = self.cursor_captured();
+        if captured == self.grabbed {
+            return;
+        }
+        self.grabbed = captured;
+        if let Err(err) = window.grab_cursor(captured) {
+            // Raw motion still works, the cursor just isn't stopped
+            // at the edge of the window.
+            warn!("failed to grab the cursor: {}", err);
+        }
+        window.hide_cursor(captured);
+    }
+
+    /// Moves the OS cursor to where a dash jumped the cursor to, since
+    /// the next mouse movement would put it back otherwise.
+    pub fn apply_cursor_warp(&mut self, window: &Window) {
+        if !mem::replace(&mut self.warp_cursor, false) || self.cursor_captured()
+        {
+            return;
+        }
+        let size = match window.get_inner_size() {
+            Some(size) => size,
+            None => return,
+        };
+        let position = self.display.mapping.to_window(
+            size.width as f32,
+            size.height as f32,
+            self.cursor * SCALE,
+        );
+        let position =
+            LogicalPosition::new(f64::from(position.x), f64::from(position.y));
+        if let Err(err) = window.set_cursor_position(position) {
+            warn!("failed to move the cursor: {}", err);
+        }
+    }
+
+    /// Switches the window in or out of fullscreen if that was asked
+    /// for, returning whether it was.
+    pub fn apply_display(&mut self, window: &Window) -> bool {
+        self.display.apply(window)
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        self.display.toggle_fullscreen();
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.display.fullscreen
+    }
+
+    pub fn viewport_mapping(&self) -> ViewportMapping {
+        self.display.mapping
+    }
+
+    /// Gets the state of the connection to a server, if there is one.
+    pub fn connection_state(&self) -> Option<ConnectionState> {
+        match self.screen {
+            Screen::MainMenu {
+                connecting: Some(ref connecting),
+            } => Some(connecting.client.state()),
+            Screen::MainMenu {
+                connecting: None,
+            } |
+            Screen::Replay {
+                ..
+            } => None,
+            Screen::InGame {
+                ref client,
+                ..
+            } => Some(client.state()),
+        }
+    }
+
+    /// Gets the game being played, if any, along with the cursor
+    /// position used to predict this client's player.
+    pub fn game(&self) -> Option<(&Game, Point2<f32>)> {
+        match self.screen {
+            Screen::InGame {
+                ref game,
+                ..
+            } => Some((game, clamp_cursor(self.cursor, game.settings()))),
+            _ => None,
+        }
+    }
+
+    /// Moves the cursor from any input source, and passes it on to
+    /// the game unless it's locked or paused.
+    pub fn handle_cursor(&mut self, input: CursorInput) {
+        let (settings, game) = match self.screen {
+            Screen::InGame {
+                ref game,
+                locked: false,
+                paused: false,
+                ..
+            } => (*game.settings(), Some(game)),
+            Screen::InGame {
+                ref game,
+                ..
+            } => (*game.settings(), None),
+            Screen::MainMenu {
+                ..
+            } |
+            Screen::Replay {
+                ..
+            } => (GameSettings::default(), None),
+        };
+        let last = self.cursor;
+        self.cursor = match input {
+            CursorInput::Absolute(cursor) => cursor,
+            // Relative movement has nothing to stop it, so keep it
+            // inside the arena.
+            CursorInput::Relative(offset) => {
+                clamp_cursor(self.cursor + offset, &settings)
+            },
+        };
+        let moved = self.cursor - last;
+        if moved != Vector2::zeros() {
+            self.cursor_direction = moved.normalize();
+        }
+        match game {
+            // The cursor catches up in `update` instead, since it's
+            // slowed down while charging.
+            Some(game) if !game.charging() => game.update_cursor(self.cursor),
+            _ => (),
+        }
+    }
+
+    /// Moves the second local player's cursor with the WASD keys and
+    /// any gamepad input, or returns false if there isn't a second
+    /// player to move.
+    fn move_second_player(
+        &mut self,
+        input: Option<CursorInput>,
+        dt: f32,
+    ) -> bool {
+        let speed = self.gamepad_config.sensitivity * dt;
+        match self.screen {
+            Screen::InGame {
+                paused,
+                second:
+                    Some(SecondPlayer::Joined {
+                        ref game,
+                        ref mut cursor,
+                        keys,
+                        ..
+                    }),
+                ..
+            } => {
+                let axis = |negative: bool, positive: bool| {
+                    positive as u8 as f32 - negative as u8 as f32
+                };
+                let mut offset = Vector2::new(
+                    axis(keys[1], keys[3]),
+                    axis(keys[0], keys[2]),
+                );
+                if offset != Vector2::zeros() {
+                    offset = offset.normalize() * speed;
+                }
+                if let Some(CursorInput::Relative(stick)) = input {
+                    offset += stick;
+                }
+                if !paused && offset != Vector2::zeros() {
+                    *cursor = clamp_cursor(*cursor + offset, game.settings());
+                    game.update_cursor(*cursor);
+                }
+                true
+            },
+            _ => false,
+        }
+    }
+
     pub fn update(&mut self, dt: f32) {
+        for entry in &mut self.bookmarks {
+            entry.poll();
+        }
+        self.internet_games.poll();
+        let gamepad = self.gamepad.update(dt, &self.gamepad_config);
+        if !self.move_second_player(gamepad, dt) {
+            if let Some(input) = gamepad {
+                self.handle_cursor(input);
+            }
+        }
+
         let error_text = &mut self.error_text;
+        let stats = &mut self.stats;
+        let audio = &self.audio;
+        let migration = &mut self.migration;
+        let highlights = &mut self.highlights;
+        let cursor = self.cursor;
         let transition = match self.screen {
             Screen::MainMenu {
                 connecting: ref mut connecting_persist,
             } => {
                 connecting_persist.take().and_then(|connecting| {
                     match connecting.done.try_recv() {
-                        Ok(Ok((game, done))) => {
+                        Ok(Ok((mut game, done))) => {
+                            if let Some(hue) = connecting.hue {
+                                // The server wraps hues into range, so
+                                // a hue of 1 comes back as 0.
+                                if game.hue() != hue - hue.floor() {
+                                    info!(
+                                        "preferred hue {} was taken, got {}",
+                                        hue,
+                                        game.hue()
+                                    );
+                                    *error_text = Some(ImString::new(
+                                        "Your color is too close to another \
+                                         player's, so the nearest free one \
+                                         was picked instead.",
+                                    ));
+                                }
+                            }
+                            let recording = Recording::new(&mut game);
+                            let highlight_cutter =
+                                HighlightCutter::new(&recording);
                             Some(Screen::InGame {
+                                addr: connecting.addr,
                                 server: connecting.server,
-                                _client: connecting.client,
+                                practice: connecting.practice,
+                                tutorial: if connecting.practice {
+                                    Some(Tutorial::default())
+                                } else {
+                                    None
+                                },
+                                client: connecting.client,
                                 done,
                                 game,
                                 locked: false,
+                                paused: false,
+                                captured: false,
                                 show_settings: false,
+                                show_scoreboard: false,
+                                server_status: None,
+                                animations: Animations::default(),
+                                effects: Effects::default(),
+                                camera: Camera::default(),
+                                spectator: SpectatorCamera::default(),
+                                dragging: false,
+                                second: None,
+                                recording,
+                                highlight_cutter,
                             })
                         },
                         Ok(Err(err)) => {
@@ -199,20 +1932,212 @@ impl GameState {
                             }
                             None
                         },
-                        Err(_) => {
-                            *connecting_persist = Some(connecting);
-                            None
+                        Err(_) => {
+                            if connecting.started.elapsed() > CONNECT_TIMEOUT {
+                                let err = format!(
+                                    "timed out connecting to {}",
+                                    connecting.addr
+                                );
+                                warn!("{}", err);
+                                *error_text = Some(ImString::new(err));
+                            } else {
+                                *connecting_persist = Some(connecting);
+                            }
+                            None
+                        },
+                    }
+                })
+            },
+            Screen::InGame {
+                addr,
+                practice,
+                ref mut tutorial,
+                ref mut game,
+                ref mut done,
+                ref mut server,
+                ref mut server_status,
+                ref mut animations,
+                ref mut effects,
+                ref mut camera,
+                ref mut spectator,
+                ref mut second,
+                ref mut recording,
+                ref mut highlight_cutter,
+                locked,
+                paused,
+                ..
+            } => {
+                if let Some(server) = server {
+                    if let Some(status) = server.status.try_iter().last() {
+                        *server_status = Some(status);
+                    }
+                }
+                *second = second.take().and_then(|second| {
+                    second.update(dt, addr, migration, error_text)
+                });
+                game.tick(dt);
+                if game.charging() && !locked && !paused {
+                    let target = clamp_cursor(cursor, game.settings());
+                    let max_distance = CHARGING_CURSOR_SPEED * dt;
+                    let cursor =
+                        approach_cursor(game.cursor(), target, max_distance);
+                    game.update_cursor(cursor);
+                }
+                recording.record(game);
+                highlight_cutter.update(recording);
+                animations.tick(dt);
+                let focus =
+                    animations.value(&Transition::CameraFocus).unwrap_or(0.0);
+                if let RoundState::Winner(_) = game.round {
+                    // The winner reveal is in control of the camera.
+                    camera.focus = focus;
+                    spectator.jump(*camera);
+                } else {
+                    let target = if watching(game) {
+                        spectator.target(&living_balls(game))
+                    } else {
+                        Camera {
+                            focus,
+                            ..*camera
+                        }
+                    };
+                    spectator.ease(dt, target);
+                    *camera = spectator.camera();
+                }
+                effects.update(dt, game);
+                if let Some(tutorial) = tutorial {
+                    tutorial.update(dt, game);
+                }
+                let player_id = game.player_id();
+                // Collected first, since effects look at the game.
+                let events = game.drain_events().collect::<Vec<_>>();
+                for event in events {
+                    effects.handle_event(&event, game);
+                    if let Some(tutorial) = tutorial {
+                        tutorial.handle_event(&event, player_id);
+                    }
+                    match event {
+                        GameEvent::Winner(winner) => {
+                            // Zoom in on the winner while the arena
+                            // fills with their color.
+                            let winner = winner.and_then(|winner| {
+                                game.latest_players()
+                                    .into_iter()
+                                    .find(|&(id, _)| id == winner)
+                                    .map(|(_, player)| {
+                                        player.state().ball.position
+                                    })
+                            });
+                            if let Some(position) = winner {
+                                camera.target = position;
+                                animations.start(
+                                    Transition::CameraFocus,
+                                    Tween::new(
+                                        0.0,
+                                        1.0,
+                                        TRANSITION_DURATION +
+                                            WINNER_REVEAL_DELAY,
+                                        Easing::ExpoOut,
+                                    ),
+                                );
+                            }
+                            animations.start(
+                                Transition::WinnerReveal,
+                                Tween::new(
+                                    0.0,
+                                    1.0,
+                                    TRANSITION_DURATION,
+                                    Easing::ExpoOut,
+                                ),
+                            );
+                            animations.start(
+                                Transition::ArenaScale,
+                                Tween::new(
+                                    SCALE,
+                                    0.0,
+                                    TRANSITION_DURATION,
+                                    Easing::ExpoIn,
+                                )
+                                .delay(WINNER_REVEAL_DELAY),
+                            );
+                        },
+                        GameEvent::Kill {
+                            killer,
+                            victim,
+                        } if killer == player_id || victim == player_id => {
+                            let name = highlight_name(game, killer, victim);
+                            highlights
+                                .push(highlight_cutter.cut(recording, name));
+                            if highlights.len() > MAX_HIGHLIGHTS {
+                                highlights.remove(0);
+                            }
+                        },
+                        _ => (),
+                    }
+
+                    let sound = match event {
+                        GameEvent::Collision(intensity) => {
+                            Some(Sound::Collision(intensity))
+                        },
+                        GameEvent::Kill {
+                            victim,
+                            ..
+                        } if victim == player_id => Some(Sound::Death),
+                        GameEvent::Kill {
+                            ..
+                        } => Some(Sound::Kill),
+                        GameEvent::RoundStart => Some(Sound::RoundStart),
+                        GameEvent::RoundEnd => Some(Sound::RoundEnd),
+                        GameEvent::Afk(id) if id == player_id => {
+                            Some(Sound::Death)
                         },
+                        GameEvent::Afk(_) => None,
+                        GameEvent::Respawn(_) => None,
+                        GameEvent::Winner(_) => None,
+                        GameEvent::NextRound => None,
+                    };
+                    if let Some(sound) = sound {
+                        audio.play(sound);
                     }
-                })
-            },
-            Screen::InGame {
-                ref mut game,
-                ref mut done,
-                ref mut server,
-                ..
-            } => {
-                game.tick(dt);
+                }
+                // The round can leave the winner reveal without a
+                // `NextRound`, like when the game is reset, and the
+                // camera and arena have to come back however it
+                // happens.
+                let revealing =
+                    animations.value(&Transition::WinnerReveal).is_some();
+                let winner = match game.round {
+                    RoundState::Winner(_) => true,
+                    _ => false,
+                };
+                if revealing && !winner {
+                    animations.stop(&Transition::WinnerReveal);
+                    let focus = animations
+                        .value(&Transition::CameraFocus)
+                        .unwrap_or(0.0);
+                    animations.start(
+                        Transition::CameraFocus,
+                        Tween::new(
+                            focus,
+                            0.0,
+                            CAMERA_ZOOM_OUT_DURATION,
+                            Easing::ExpoOut,
+                        ),
+                    );
+                    let scale = animations
+                        .value(&Transition::ArenaScale)
+                        .unwrap_or(0.0)
+                        .max(0.0);
+                    animations.start(
+                        Transition::ArenaScale,
+                        Tween::new(
+                            scale,
+                            SCALE,
+                            TRANSITION_DURATION,
+                            Easing::ExpoOut,
+                        ),
+                    );
+                }
                 // Check if either the server or client has shut down.
                 server
                     .as_mut()
@@ -230,26 +2155,59 @@ impl GameState {
                     })
                     .or_else(|| {
                         done.try_recv().ok().map(|err| {
-                            if let Some(err) = err {
-                                let err = format!(
-                                    "client stopped with error: {}",
-                                    err
-                                );
-                                error!("{}", err);
-                                *error_text = Some(ImString::new(err));
+                            match err {
+                                Some(networking::Error::Migrated(next)) => {
+                                    info!("the host left {}", addr);
+                                    // The second player might already
+                                    // have been told to take over, in
+                                    // which case this player hosts.
+                                    let hosting = match migration {
+                                        Some((_, Migration::Host(..))) => true,
+                                        _ => false,
+                                    };
+                                    if !hosting {
+                                        *migration = Some((addr, next));
+                                    }
+                                },
+                                Some(err) => {
+                                    let err = format!(
+                                        "client stopped with error: {}",
+                                        err
+                                    );
+                                    error!("{}", err);
+                                    *error_text = Some(ImString::new(err));
+                                },
+                                None => (),
                             }
                         })
                     })
                     .map(|_| {
+                        if !practice {
+                            stats.record(addr, game.session_stats());
+                        }
                         Screen::MainMenu {
                             connecting: None,
                         }
                     })
             },
+            Screen::Replay {
+                ref mut replay,
+                ..
+            } => {
+                replay.update(dt);
+                None
+            },
         };
 
         if let Some(screen) = transition {
-            self.screen = screen;
+            let left = mem::replace(&mut self.screen, screen);
+            if let Screen::InGame {
+                recording,
+                ..
+            } = left
+            {
+                self.last_recording = Some(recording);
+            }
         }
     }
 
@@ -268,70 +2226,126 @@ impl GameState {
             },
             Screen::InGame {
                 ref mut game,
+                ref animations,
+                ref effects,
+                ref camera,
+                ref second,
+                captured,
+                paused,
                 ..
             } => {
                 // TODO use the z-buffer to reduce overdraw here
 
-                game.clean_old_snapshots(now, debug.interpolation_delay);
+                game.scrub(debug.scrub);
+                // Hold the last frame still during a hit-stop. The
+                // playback clock catches up smoothly afterwards.
+                if !effects.hit_stop() {
+                    profile_scope!(Interpolation);
+                    game.update_playback(now, debug.interpolation_delay);
+                }
 
-                let (round_circles, scale) = match (game.last_round, game.round)
-                {
-                    (Some(RoundState::Winner(_)), RoundState::Waiting) => {
-                        let scale = Expo::ease_out(
-                            game.round_duration,
-                            0.0,
-                            SCALE,
-                            0.3,
-                        );
-                        (None, scale)
-                    },
-                    (_, RoundState::Winner(winner)) => {
-                        // No winner is black
+                let scale = animations
+                    .value(&Transition::ArenaScale)
+                    .unwrap_or(SCALE)
+                    .max(0.0);
+                let round_circles = match (
+                    game.round,
+                    animations.value(&Transition::WinnerReveal),
+                ) {
+                    (RoundState::Winner(winner), Some(reveal)) => {
+                        // No winner is gray.
                         let color = match winner {
                             Some(id) => game.players[&id].color,
                             None => LinSrgb::new(0.5, 0.5, 0.5),
                         };
-                        let radius = Expo::ease_out(
-                            game.round_duration,
-                            0.0,
-                            game.settings().bounds_radius,
-                            0.3,
-                        );
-
-                        let scale = if game.round_duration > 0.5 {
-                            Expo::ease_in(
-                                game.round_duration - 0.5,
-                                SCALE,
-                                -SCALE,
-                                0.3,
-                            )
-                            .max(0.0)
-                        } else {
-                            SCALE
-                        };
-
-                        (
-                            Some(Circle {
-                                center: Point2::new(0.0, 0.0),
-                                radius: scale * radius,
-                                color,
-                            }),
-                            scale,
-                        )
+                        Some(Circle {
+                            center: Point2::new(0.0, 0.0),
+                            radius: scale *
+                                reveal *
+                                game.settings().bounds_radius,
+                            inner_radius: 0.0,
+                            color,
+                        })
                     },
-                    _ => (None, SCALE),
+                    _ => None,
                 };
 
-                let players = game.interpolated_players(
-                    now,
-                    clamp_cursor(self.cursor, game.settings()),
-                    debug.interpolation_delay,
-                );
-                let circles = players.into_iter().flat_map(|(_, player)| {
-                    player.draw(scale, game.settings())
+                let players = {
+                    profile_scope!(Interpolation);
+                    let mut cursors = SmallVec::<[_; 2]>::new();
+                    let cursor = if game.charging() {
+                        game.cursor()
+                    } else {
+                        clamp_cursor(self.cursor, game.settings())
+                    };
+                    cursors.push((game.player_id(), cursor));
+                    if let Some(SecondPlayer::Joined {
+                        game: ref second_game,
+                        cursor,
+                        ..
+                    }) = *second
+                    {
+                        cursors.push((second_game.player_id(), cursor));
+                    }
+                    game.interpolated_players_for(
+                        &cursors,
+                        debug.interpolation_mode,
+                    )
+                };
+                // Dim everyone while warming up, since nothing counts.
+                let dim = if game.round.warm_up() {
+                    WARM_UP_DIM
+                } else {
+                    1.0
+                };
+                let player_outlines = self.appearance.player_outlines;
+                let latency_indicators = self.appearance.latency_indicators;
+                let player_id = game.player_id();
+                let dash_cooldown = game.dash_cooldown();
+                let circles = players.into_iter().flat_map(|(id, player)| {
+                    // Fade respawning players in from the background.
+                    let fade = dim * game.respawn_fade(id);
+                    let mut circles = player.draw(scale, game.settings());
+                    let alive = player.state().alive();
+                    if id == player_id && alive && dash_cooldown > 0.0 {
+                        circles.push(dash_ring(circles[1], dash_cooldown));
+                    }
+                    if player_outlines {
+                        let outlines = outline_circles(id, circles[0]);
+                        circles.extend(outlines);
+                    }
+                    match game.ping(id) {
+                        Some(ping) if latency_indicators => {
+                            circles.push(latency_circle(circles[0], ping))
+                        },
+                        _ => (),
+                    }
+                    circles.into_iter().map(move |circle| {
+                        Circle {
+                            color: circle.color * fade,
+                            ..circle
+                        }
+                    })
                 });
 
                 let bounds_circle = bounds_circle(scale, Some(game.settings()));
+                let decoration_circles = decorations::circles(game, scale);
+                let effect_circles = effects.circles(scale, game.settings());
+                let shake = effects.shake_offset() * scale;
+
+                // Stand in for the hidden OS cursor, on top of
+                // everything else.
+                let captured_cursor = if captured && !paused {
+                    Some(Circle {
+                        center: clamp_cursor(self.cursor, game.settings()) *
+                            scale,
+                        radius: CAPTURED_CURSOR_RADIUS * scale,
+                        inner_radius: 0.0,
+                        color: LinSrgb::new(1.0, 1.0, 1.0),
+                    })
+                } else {
+                    None
+                };
 
                 if debug.draw_latest_snapshot {
                     let players = game.latest_players();
@@ -349,23 +2363,71 @@ impl GameState {
                     circle_rend.draw(
                         ctx,
                         iter::once(bounds_circle)
+                            .chain(decoration_circles)
+                            .chain(effect_circles)
                             .chain(debug_circles)
                             .chain(circles)
-                            .chain(round_circles),
+                            .chain(round_circles)
+                            .map(|circle| {
+                                shaken(camera.apply(circle, scale), shake)
+                            })
+                            .chain(captured_cursor),
                     );
                 } else {
                     circle_rend.draw(
                         ctx,
                         iter::once(bounds_circle)
+                            .chain(decoration_circles)
+                            .chain(effect_circles)
                             .chain(circles)
-                            .chain(round_circles),
+                            .chain(round_circles)
+                            .map(|circle| {
+                                shaken(camera.apply(circle, scale), shake)
+                            })
+                            .chain(captured_cursor),
                     );
                 }
             },
+            Screen::Replay {
+                ref replay,
+                ..
+            } => {
+                let game = replay.game();
+                let settings = game.settings();
+                let players = game
+                    .interpolated_players_for(&[], debug.interpolation_mode);
+                let dim = if game.round.warm_up() {
+                    WARM_UP_DIM
+                } else {
+                    1.0
+                };
+                let circles = players.into_iter().flat_map(|(id, player)| {
+                    let fade = dim * game.respawn_fade(id);
+                    player.draw(SCALE, settings).into_iter().map(
+                        move |circle| {
+                            Circle {
+                                color: circle.color * fade,
+                                ..circle
+                            }
+                        },
+                    )
+                });
+                circle_rend.draw(
+                    ctx,
+                    iter::once(bounds_circle(SCALE, Some(settings)))
+                        .chain(decorations::circles(game, SCALE))
+                        .chain(circles),
+                );
+            },
         }
     }
 
     pub fn ui<'a>(&mut self, ui: &Ui<'a>, debug: &DebugState) {
+        // Following the game needs the debug state for network stats,
+        // so it happens here rather than in `update`.
+        if let Some((addr, migration)) = self.migration.take() {
+            self.migrate(addr, migration, debug);
+        }
         if let Some(ref err) = self.error_text {
             ui.open_popup(im_str!("error"));
             let mut open = true;
@@ -385,34 +2447,223 @@ impl GameState {
             }
         }
 
+        let mut disconnect = false;
+        let mut watch = None;
+        let mut watch_highlight = None;
+        let mut close_replay = false;
         match self.screen {
             Screen::MainMenu {
                 ref mut connecting,
             } => {
                 let server_addr = &mut self.server_addr;
+                let rendezvous_addr = &mut self.rendezvous_addr;
+                let bookmark_name = &mut self.bookmark_name;
+                let bookmarks = &mut self.bookmarks;
                 let server_addr_host = &mut self.server_addr_host;
+                let host_encrypted = &mut self.host_encrypted;
+                let host_announced = &mut self.host_announced;
+                let master_server = &self.master_server;
+                let internet_games = &mut self.internet_games;
+                let player_name = &mut self.player_name;
+                let color_hue = &mut self.color_hue;
                 let error_text = &mut self.error_text;
+                let show_stats = &mut self.show_stats;
+                let show_highlights = &mut self.show_highlights;
+                let appearance = &mut self.appearance;
+                let display = &mut self.display;
+                let audio = &mut self.audio;
+                let gamepad_config = &mut self.gamepad_config;
+                let threads = &mut self.threads;
+                let practice_targets = self.practice_targets;
+                let last_recording = &mut self.last_recording;
+                let watch = &mut watch;
                 let cursor = self.cursor;
                 ui.window(im_str!("Main Menu")).always_auto_resize(true).build(
                     || {
-                        if connecting.is_some() {
-                            ui.text(im_str!("Connecting..."));
+                        ui.input_text(im_str!("Name"), player_name).build();
+                        let mut random_color = color_hue.is_none();
+                        if ui.checkbox(
+                            im_str!("Random color"),
+                            &mut random_color,
+                        ) {
+                            *color_hue = if random_color {
+                                None
+                            } else {
+                                Some(0.0)
+                            };
+                        }
+                        if let Some(hue) = color_hue.as_mut() {
+                            ui.slider_float(im_str!("Color"), hue, 0.0, 1.0)
+                                .build();
+                            ui.same_line(0.0);
+                            let color = hue_color(*hue);
+                            ui.color_button(
+                                im_str!("##color"),
+                                [color.red, color.green, color.blue, 1.0],
+                            )
+                            .build();
+                        }
+                        ui.separator();
+
+                        if let Some(state) = connecting.as_ref() {
+                            ui.text(state.progress_text());
                             ui.same_line(0.0);
                             if ui.small_button(im_str!("Cancel")) {
+                                audio.play(Sound::Click);
                                 *connecting = None;
                             }
                             ui.separator();
                         }
 
+                        ui.text(im_str!("Servers"));
+                        let mut connect_to = None;
+                        let mut rendezvous_to = None;
+                        let mut remove = None;
+                        for (i, entry) in bookmarks.iter().enumerate() {
+                            if ui.small_button(&im_str!("Connect##{}", i)) {
+                                audio.play(Sound::Click);
+                                connect_to =
+                                    Some(entry.bookmark.address.clone());
+                            }
+                            ui.same_line(0.0);
+                            if ui.small_button(&im_str!("Remove##{}", i)) {
+                                audio.play(Sound::Click);
+                                remove = Some(i);
+                            }
+                            ui.same_line(0.0);
+                            ui.text(format!(
+                                "{} ({}): {}",
+                                entry.bookmark.name,
+                                entry.bookmark.address,
+                                entry.status_text()
+                            ));
+                        }
+                        if let Some(i) = remove {
+                            bookmarks.remove(i);
+                        }
+                        if ui.small_button(im_str!("Refresh")) {
+                            audio.play(Sound::Click);
+                            for entry in bookmarks.iter_mut() {
+                                entry.refresh();
+                            }
+                        }
+
+                        ui.tree_node(im_str!("Internet games")).build(|| {
+                            let master_server = match master_server {
+                                Some(master_server) => master_server,
+                                None => {
+                                    ui.text(im_str!(
+                                        "Set network.master_server in the \
+                                         config to browse internet games."
+                                    ));
+                                    return;
+                                },
+                            };
+                            if let GameListState::NotRequested = *internet_games
+                            {
+                                *internet_games =
+                                    GameListState::request(master_server);
+                            }
+                            match *internet_games {
+                                GameListState::NotRequested |
+                                GameListState::Pending(_) => {
+                                    ui.text(im_str!("Asking master server..."))
+                                },
+                                GameListState::NoResponse => {
+                                    ui.text(im_str!(
+                                        "The master server didn't respond."
+                                    ))
+                                },
+                                GameListState::Answered(ref games) => {
+                                    if games.is_empty() {
+                                        ui.text(im_str!("No games listed."));
+                                    }
+                                    for (i, game) in games.iter().enumerate() {
+                                        if ui.small_button(&im_str!(
+                                            "Connect##internet{}",
+                                            i
+                                        )) {
+                                            audio.play(Sound::Click);
+                                            connect_to =
+                                                Some(game.addr.to_string());
+                                            // The game might be behind NAT.
+                                            rendezvous_to =
+                                                Some(master_server.clone());
+                                        }
+                                        ui.same_line(0.0);
+                                        let players = match game.max_players {
+                                            Some(max) => {
+                                                format!(
+                                                    "{}/{}",
+                                                    game.players, max
+                                                )
+                                            },
+                                            None => game.players.to_string(),
+                                        };
+                                        ui.text(format!(
+                                            "{} ({}): {} players",
+                                            game.name, game.addr, players
+                                        ));
+                                    }
+                                },
+                            }
+                            if ui.small_button(im_str!("Refresh##internet")) {
+                                audio.play(Sound::Click);
+                                *internet_games =
+                                    GameListState::request(master_server);
+                            }
+                        });
+
+                        ui.input_text(im_str!("Bookmark name"), bookmark_name)
+                            .build();
                         ui.input_text(im_str!("Remote address"), server_addr)
                             .build();
+                        ui.input_text(im_str!("Rendezvous"), rendezvous_addr)
+                            .build();
+                        if ui.small_button(im_str!("Add bookmark")) {
+                            audio.play(Sound::Click);
+                            bookmarks.push(BookmarkEntry::new(Bookmark {
+                                name: bookmark_name.to_str().to_owned(),
+                                address: server_addr.to_str().to_owned(),
+                            }));
+                        }
+                        ui.same_line(0.0);
                         if ui.small_button(im_str!("Connect to server")) {
-                            match server_addr.to_str().to_socket_addrs() {
+                            audio.play(Sound::Click);
+                            connect_to = Some(server_addr.to_str().to_owned());
+                            if !rendezvous_addr.to_str().is_empty() {
+                                rendezvous_to =
+                                    Some(rendezvous_addr.to_str().to_owned());
+                            }
+                        }
+
+                        let rendezvous = rendezvous_to.and_then(|address| {
+                            let addr = address
+                                .to_socket_addrs()
+                                .ok()
+                                .and_then(|mut addrs| addrs.next());
+                            if addr.is_none() {
+                                warn!(
+                                    "couldn't resolve rendezvous address {}, \
+                                     connecting directly",
+                                    address
+                                );
+                            }
+                            addr
+                        });
+                        if let Some(address) = connect_to {
+                            match address.to_socket_addrs() {
                                 Ok(mut addrs) => {
                                     match addrs.next() {
                                         Some(addr) => {
                                             match Connecting::connect(
-                                                addr, debug, cursor,
+                                                addr,
+                                                rendezvous,
+                                                debug,
+                                                cursor,
+                                                player_name.to_str(),
+                                                *color_hue,
+                                                threads,
                                             ) {
                                                 Ok(state) => {
                                                     *connecting = Some(state)
@@ -434,7 +2685,7 @@ impl GameState {
                                             let err = format!(
                                                 "couldn't resolve server \
                                                  address: {}",
-                                                server_addr.to_str()
+                                                address
                                             );
                                             warn!("{}", err);
                                             *error_text =
@@ -445,7 +2696,7 @@ impl GameState {
                                 Err(_) => {
                                     let err = format!(
                                         "couldn't parse server address: {}",
-                                        server_addr.to_str()
+                                        address
                                     );
                                     warn!("{}", err);
                                     *error_text = Some(ImString::new(err));
@@ -460,13 +2711,63 @@ impl GameState {
                             server_addr_host,
                         )
                         .build();
+                        if KeyExchange::supported() {
+                            ui.checkbox(
+                                im_str!("Encrypt connections"),
+                                host_encrypted,
+                            );
+                        }
+                        if master_server.is_some() {
+                            ui.checkbox(
+                                im_str!("List in internet games"),
+                                host_announced,
+                            );
+                        }
                         if ui.small_button(im_str!("Host server")) {
+                            audio.play(Sound::Click);
+                            let announce = master_server
+                                .as_ref()
+                                .filter(|_| *host_announced)
+                                .and_then(|master_server| {
+                                    master_server
+                                        .to_socket_addrs()
+                                        .ok()
+                                        .and_then(|mut addrs| addrs.next())
+                                })
+                                .map(|master| {
+                                    Announcement {
+                                        master,
+                                        name: format!(
+                                            "{}'s game",
+                                            player_name.to_str()
+                                        ),
+                                    }
+                                });
+                            if master_server.is_some() &&
+                                *host_announced &&
+                                announce.is_none()
+                            {
+                                warn!(
+                                    "couldn't resolve the master server, so \
+                                     the game won't be listed"
+                                );
+                            }
                             match server_addr_host.to_str().to_socket_addrs() {
                                 Ok(mut addrs) => {
                                     match addrs.next() {
                                         Some(addr) => {
                                             match Connecting::host(
-                                                addr, debug, cursor,
+                                                addr,
+                                                debug,
+                                                cursor,
+                                                player_name.to_str(),
+                                                *color_hue,
+                                                ServerConfig {
+                                                    encrypt: *host_encrypted,
+                                                    announce,
+                                                    ..ServerConfig::default()
+                                                },
+                                                threads,
                                             ) {
                                                 Ok(state) => {
                                                     *connecting = Some(state)
@@ -507,14 +2808,242 @@ impl GameState {
                                 },
                             }
                         }
+
+                        if ui.small_button(im_str!("Practice")) {
+                            audio.play(Sound::Click);
+                            match Connecting::practice(
+                                debug,
+                                cursor,
+                                player_name.to_str(),
+                                *color_hue,
+                                practice_targets,
+                                threads,
+                            ) {
+                                Ok(state) => *connecting = Some(state),
+                                Err(err) => {
+                                    let err = format!(
+                                        "error starting practice: {}",
+                                        err
+                                    );
+                                    error!("{}", err);
+                                    *error_text = Some(ImString::new(err));
+                                },
+                            }
+                        }
+
+                        ui.separator();
+
+                        if ui.small_button(im_str!("Stats")) {
+                            audio.play(Sound::Click);
+                            *show_stats = !*show_stats;
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Interface")) {
+                            audio.play(Sound::Click);
+                            appearance.show_window = !appearance.show_window;
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Display")) {
+                            audio.play(Sound::Click);
+                            display.show_window = !display.show_window;
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Highlights")) {
+                            audio.play(Sound::Click);
+                            *show_highlights = !*show_highlights;
+                        }
+                        if last_recording.is_some() && connecting.is_none() {
+                            ui.same_line(0.0);
+                            if ui.small_button(im_str!("Watch last game")) {
+                                audio.play(Sound::Click);
+                                *watch = last_recording.take();
+                            }
+                        }
+
+                        ui.separator();
+
+                        let mut volume = audio.volume();
+                        if ui
+                            .slider_float(
+                                im_str!("Volume"),
+                                &mut volume,
+                                0.0,
+                                1.0,
+                            )
+                            .build()
+                        {
+                            audio.set_volume(volume);
+                        }
+
+                        ui.tree_node(im_str!("Gamepad")).build(|| {
+                            ui.slider_float(
+                                im_str!("Sensitivity"),
+                                &mut gamepad_config.sensitivity,
+                                0.1,
+                                5.0,
+                            )
+                            .build();
+                            ui.slider_float(
+                                im_str!("Dead zone"),
+                                &mut gamepad_config.dead_zone,
+                                0.0,
+                                0.9,
+                            )
+                            .build();
+                            ui.slider_float(
+                                im_str!("Response curve"),
+                                &mut gamepad_config.curve,
+                                1.0,
+                                4.0,
+                            )
+                            .build();
+                        });
                     },
                 );
+
+                if self.show_stats {
+                    stats_window(ui, &self.stats, &mut self.show_stats);
+                }
+                if self.show_highlights {
+                    watch_highlight = highlights_window(
+                        ui,
+                        &mut self.highlights,
+                        &mut self.show_highlights,
+                        &self.audio,
+                    );
+                }
             },
             Screen::InGame {
-                ref show_settings,
+                addr,
+                ref mut paused,
+                ref mut show_settings,
+                show_scoreboard,
                 ref mut game,
+                ref server,
+                practice,
+                ref mut tutorial,
+                ref server_status,
+                ref mut second,
+                ref mut spectator,
                 ..
             } => {
+                if show_scoreboard {
+                    scoreboard_window(ui, game);
+                }
+                if watching(game) {
+                    camera_window(ui, spectator, game);
+                }
+                messages_window(ui, game);
+                server_paused_window(ui, game);
+
+                if self.show_minimap {
+                    minimap_window(
+                        ui,
+                        game,
+                        self.cursor,
+                        debug.interpolation_mode,
+                    );
+                }
+
+                match server {
+                    Some(server) if practice => {
+                        practice_window(
+                            ui,
+                            server,
+                            &mut self.practice_targets,
+                            tutorial,
+                            &self.audio,
+                        );
+                        tutorial_window(ui, tutorial, &self.audio);
+                    },
+                    Some(server) => {
+                        server_window(
+                            ui,
+                            server,
+                            server_status.as_ref(),
+                            &self.audio,
+                        );
+                    },
+                    None => (),
+                }
+
+                if *paused {
+                    let audio = &self.audio;
+                    let appearance = &mut self.appearance;
+                    let display = &mut self.display;
+                    let mut toggle_second = false;
+                    ui.window(im_str!("Paused"))
+                        .always_auto_resize(true)
+                        .build(|| {
+                            if ui.small_button(im_str!("Resume")) {
+                                audio.play(Sound::Click);
+                                *paused = false;
+                            }
+                            let label = match second {
+                                Some(_) => im_str!("Remove Player 2"),
+                                None => im_str!("Add Player 2"),
+                            };
+                            // A second player would have to connect
+                            // over UDP, which practice games don't
+                            // listen on.
+                            if !practice && ui.small_button(label) {
+                                audio.play(Sound::Click);
+                                toggle_second = true;
+                            }
+                            if second.is_some() {
+                                ui.text(
+                                    "Player 2 moves with WASD or a gamepad.",
+                                );
+                            }
+                            if ui.small_button(im_str!("Settings")) {
+                                audio.play(Sound::Click);
+                                *show_settings = !*show_settings;
+                            }
+                            if ui.small_button(im_str!("Interface")) {
+                                audio.play(Sound::Click);
+                                appearance.show_window =
+                                    !appearance.show_window;
+                            }
+                            if ui.small_button(im_str!("Display")) {
+                                audio.play(Sound::Click);
+                                display.show_window = !display.show_window;
+                            }
+                            if ui.small_button(im_str!("Disconnect")) {
+                                audio.play(Sound::Click);
+                                disconnect = true;
+                            }
+                        });
+
+                    // Dropping the second player's client disconnects it.
+                    if toggle_second && second.take().is_none() {
+                        let name = self
+                            .player_name
+                            .to_str()
+                            .chars()
+                            .take(MAX_NAME_LENGTH - 2)
+                            .collect::<String>();
+                        let name = format!("{} 2", name);
+                        match Connecting::second_player(
+                            addr,
+                            &name,
+                            &mut self.threads,
+                        ) {
+                            Ok(connecting) => {
+                                *second =
+                                    Some(SecondPlayer::Connecting(connecting));
+                            },
+                            Err(err) => {
+                                let err = format!(
+                                    "error adding a second player: {}",
+                                    err
+                                );
+                                error!("{}", err);
+                                self.error_text = Some(ImString::new(err));
+                            },
+                        }
+                    }
+                }
+
                 if *show_settings {
                     ui.window(im_str!("Game Settings"))
                         .always_auto_resize(true)
@@ -557,10 +3086,127 @@ impl GameState {
                                     &mut settings.bounds_radius,
                                 )
                                 .build();
+                            changed |= ui
+                                .slider_float(
+                                    im_str!("damping"),
+                                    &mut settings.damping,
+                                    0.0,
+                                    0.95,
+                                )
+                                .build();
+                            changed |= ui
+                                .slider_float(
+                                    im_str!("restitution"),
+                                    &mut settings.restitution,
+                                    0.0,
+                                    1.0,
+                                )
+                                .build();
+                            changed |= ui
+                                .slider_float(
+                                    im_str!("gravity"),
+                                    &mut settings.gravity,
+                                    -10.0,
+                                    10.0,
+                                )
+                                .build();
+                            changed |= ui
+                                .slider_float(
+                                    im_str!("vortex"),
+                                    &mut settings.vortex,
+                                    -10.0,
+                                    10.0,
+                                )
+                                .build();
                             changed |= ui.checkbox(
                                 im_str!("players can kill own cursor"),
                                 &mut settings.kill_own_cursor,
                             );
+                            let mut respawn = settings.respawn_delay.is_some();
+                            let mut respawn_delay =
+                                settings.respawn_delay.unwrap_or(3.0);
+                            changed |= ui.checkbox(
+                                im_str!("respawn dead players"),
+                                &mut respawn,
+                            );
+                            if respawn {
+                                changed |= ui
+                                    .input_float(
+                                        im_str!("respawn delay"),
+                                        &mut respawn_delay,
+                                    )
+                                    .build();
+                            }
+                            settings.respawn_delay = if respawn {
+                                Some(respawn_delay)
+                            } else {
+                                None
+                            };
+                            // The time limit only matters when players
+                            // respawn.
+                            if respawn {
+                                let mut limit =
+                                    settings.round_time_limit.is_some();
+                                let mut round_time_limit =
+                                    settings.round_time_limit.unwrap_or(180.0);
+                                changed |= ui.checkbox(
+                                    im_str!("limit round time"),
+                                    &mut limit,
+                                );
+                                if limit {
+                                    changed |= ui
+                                        .input_float(
+                                            im_str!("round time limit"),
+                                            &mut round_time_limit,
+                                        )
+                                        .build();
+                                }
+                                settings.round_time_limit = if limit {
+                                    Some(round_time_limit)
+                                } else {
+                                    None
+                                };
+                            }
+                            let mut afk = settings.afk_timeout.is_some();
+                            let mut afk_timeout =
+                                settings.afk_timeout.unwrap_or(30.0);
+                            changed |= ui.checkbox(
+                                im_str!("kill idle players"),
+                                &mut afk,
+                            );
+                            if afk {
+                                changed |= ui
+                                    .input_float(
+                                        im_str!("idle timeout"),
+                                        &mut afk_timeout,
+                                    )
+                                    .build();
+                            }
+                            settings.afk_timeout = if afk {
+                                Some(afk_timeout)
+                            } else {
+                                None
+                            };
+                            // This only applies to players who join
+                            // after it's changed.
+                            changed |= ui::enum_combo(
+                                ui,
+                                im_str!("palette"),
+                                &mut settings.palette,
+                                &[
+                                    im_str!("full"),
+                                    im_str!("deuteranopia"),
+                                    im_str!("protanopia"),
+                                    im_str!("tritanopia"),
+                                ],
+                                &[
+                                    Palette::Full,
+                                    Palette::Deuteranopia,
+                                    Palette::Protanopia,
+                                    Palette::Tritanopia,
+                                ],
+                                4,
+                            );
                             if changed {
                                 debug!(
                                     "sending updated settings: {:?}",
@@ -571,6 +3217,135 @@ impl GameState {
                         });
                 }
             },
+            Screen::Replay {
+                ref mut replay,
+                ..
+            } => {
+                messages_window(ui, replay.game());
+                close_replay = replay_window(ui, replay, &self.audio);
+            },
+        }
+
+        self.appearance.window(ui);
+        self.display.window(ui);
+
+        if disconnect {
+            self.disconnect();
+        }
+        if let Some(recording) = watch {
+            self.screen = Screen::Replay {
+                replay: Replay::new(recording),
+                last_game: true,
+            };
+        }
+        if let Some(i) = watch_highlight {
+            let recording = self.highlights[i].recording.clone();
+            self.screen = Screen::Replay {
+                replay: Replay::new(recording),
+                last_game: false,
+            };
+        }
+        if close_replay {
+            let screen = mem::replace(
+                &mut self.screen,
+                Screen::MainMenu {
+                    connecting: None,
+                },
+            );
+            if let Screen::Replay {
+                replay,
+                last_game: true,
+            } = screen
+            {
+                self.last_recording = Some(replay.into_recording());
+            }
+        }
+    }
+
+    /// Carries on a game at `addr` after its host left, either by
+    /// hosting it from the state they handed over or by connecting to
+    /// whoever took over.
+    fn migrate(
+        &mut self,
+        addr: SocketAddr,
+        migration: Migration,
+        debug: &DebugState,
+    ) {
+        let connecting = match migration {
+            Migration::Host(saved, socket) => {
+                info!("taking over hosting the game");
+                // Players that follow the game were told the port this
+                // is bound to.
+                Connecting::host_on(
+                    socket,
+                    debug,
+                    self.cursor,
+                    self.player_name.to_str(),
+                    self.color_hue,
+                    ServerConfig {
+                        encrypt: self.host_encrypted,
+                        resume_state: Some(saved),
+                        ..ServerConfig::default()
+                    },
+                    &mut self.threads,
+                )
+            },
+            Migration::Join(host) => {
+                info!("following the game to {}", host);
+                Connecting::connect(
+                    host,
+                    None,
+                    debug,
+                    self.cursor,
+                    self.player_name.to_str(),
+                    self.color_hue,
+                    &mut self.threads,
+                )
+            },
+        };
+        match connecting {
+            Ok(connecting) => {
+                self.screen = Screen::MainMenu {
+                    connecting: Some(connecting),
+                };
+            },
+            Err(err) => {
+                let err =
+                    format!("couldn't carry on after the host left: {}", err);
+                error!("{}", err);
+                self.error_text = Some(ImString::new(err));
+            },
+        }
+    }
+
+    /// Leaves the current game, if there is one, explicitly shutting
+    /// down the client and any hosted server.
+    fn disconnect(&mut self) {
+        let screen = mem::replace(
+            &mut self.screen,
+            Screen::MainMenu {
+                connecting: None,
+            },
+        );
+        if let Screen::InGame {
+            addr,
+            server,
+            practice,
+            client,
+            game,
+            recording,
+            ..
+        } = screen
+        {
+            info!("disconnecting from {}", addr);
+            if !practice {
+                self.stats.record(addr, game.session_stats());
+            }
+            self.last_recording = Some(recording);
+            client.shutdown();
+            if let Some(server) = server {
+                server.shutdown();
+            }
         }
     }
 }
@@ -0,0 +1,142 @@
+use crate::config::{self, Error};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+/// Name of the stats file inside the config directory.
+const STATS_FILE: &str = "stats.toml";
+
+/// Sessions kept per server before the oldest get folded together.
+const MAX_SESSIONS: usize = 50;
+
+/// Servers kept in the history before the least played are forgotten.
+const MAX_SERVERS: usize = 100;
+
+/// Statistics for the local player over a single connection to a
+/// server.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SessionStats {
+    pub kills: u32,
+    pub deaths: u32,
+    pub wins: u32,
+    /// Seconds spent connected to the server.
+    pub play_time: f32,
+}
+
+/// History of every recorded session, keyed by server address.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub servers: BTreeMap<String, Vec<SessionStats>>,
+}
+
+impl SessionStats {
+    /// Adds another session's stats onto this one.
+    pub fn add(&mut self, other: &SessionStats) {
+        self.kills += other.kills;
+        self.deaths += other.deaths;
+        self.wins += other.wins;
+        self.play_time += other.play_time;
+    }
+}
+
+impl Stats {
+    /// Loads the stats file, falling back to empty stats if it
+    /// doesn't exist or can't be read.
+    pub fn load() -> Stats {
+        config::load_or_default(STATS_FILE)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        config::save_file(STATS_FILE, self)
+    }
+
+    /// Adds a finished session to the history and saves it.
+    pub fn record(&mut self, server: SocketAddr, session: SessionStats) {
+        info!("recording session stats for {}: {:?}", server, session);
+        let server = server.to_string();
+        let sessions =
+            self.servers.entry(server.clone()).or_insert_with(Vec::new);
+        sessions.push(session);
+        Stats::fold_oldest(sessions);
+        self.forget_least_played(&server);
+        if let Err(err) = self.save() {
+            warn!("failed to save stats: {}", err);
+        }
+    }
+
+    /// Merges the oldest sessions into a single entry once there are
+    /// more than `MAX_SESSIONS`, so the totals stay the same but the
+    /// file doesn't grow forever.
+    fn fold_oldest(sessions: &mut Vec<SessionStats>) {
+        if sessions.len() > MAX_SESSIONS {
+            let excess = sessions.len() - MAX_SESSIONS + 1;
+            let folded = Stats::totals(&sessions[..excess]);
+            sessions.splice(..excess, Some(folded));
+        }
+    }
+
+    /// Drops the servers with the least play time once there are more
+    /// than `MAX_SERVERS`, never dropping `current`.
+    fn forget_least_played(&mut self, current: &str) {
+        while self.servers.len() > MAX_SERVERS {
+            let least = self
+                .servers
+                .iter()
+                .filter(|(server, _)| server.as_str() != current)
+                .map(|(server, sessions)| {
+                    (server, Stats::totals(sessions).play_time)
+                })
+                .min_by(|a, b| {
+                    a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .map(|(server, _)| server.clone());
+            match least {
+                Some(server) => {
+                    self.servers.remove(&server);
+                },
+                None => break,
+            }
+        }
+    }
+
+    /// Sums up every session on a server.
+    pub fn totals(sessions: &[SessionStats]) -> SessionStats {
+        let mut totals = SessionStats::default();
+        for session in sessions {
+            totals.add(session);
+        }
+        totals
+    }
+}
+
+#[test]
+fn stats_are_capped() {
+    let mut sessions = vec![
+        SessionStats {
+            kills: 1,
+            deaths: 0,
+            wins: 0,
+            play_time: 1.0,
+        };
+        MAX_SESSIONS + 10
+    ];
+    Stats::fold_oldest(&mut sessions);
+    assert_eq!(sessions.len(), MAX_SESSIONS);
+    assert_eq!(Stats::totals(&sessions).kills, MAX_SESSIONS as u32 + 10);
+
+    let mut stats = Stats::default();
+    for i in 0..MAX_SERVERS + 5 {
+        stats.servers.insert(
+            i.to_string(),
+            vec![SessionStats {
+                play_time: i as f32,
+                ..SessionStats::default()
+            }],
+        );
+    }
+    stats.forget_least_played("0");
+    assert_eq!(stats.servers.len(), MAX_SERVERS);
+    assert!(stats.servers.contains_key("0"));
+    assert!(!stats.servers.contains_key("1"));
+}
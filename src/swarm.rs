@@ -0,0 +1,522 @@
+use crate::debug::NetworkStats;
+use crate::game::{clamp_cursor, client::Game, GetPlayer};
+use crate::networking::client::{self, ConnectedHandle, ConnectingHandle};
+use crossbeam::channel::{self, TryRecvError};
+use log::{info, warn};
+use nalgebra::{Point2, Vector2};
+use ord_subset::OrdSubsetIterExt;
+use rand::{thread_rng, Rng};
+use std::f32::consts::PI;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Rate at which bots move their cursors.
+const UPDATE_RATE: Duration = Duration::from_millis(16);
+
+/// Rate at which aggregate stats are printed.
+const REPORT_RATE: Duration = Duration::from_secs(5);
+
+/// Radius of the circle that `Pattern::Circle` moves around.
+const CIRCLE_RADIUS: f32 = 0.15;
+
+/// Maximum rate in radians per second that `Pattern::Random` turns
+/// at.
+const WANDER_TURN_RATE: f32 = 4.0;
+
+/// Gap in seconds between when each bot in a swarm starts following a
+/// path, so they don't all move as one.
+const PATH_OFFSET: f32 = 1.0;
+
+/// How a bot moves its cursor.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pattern {
+    /// Never move.
+    Still,
+    /// Move around a small circle.
+    Circle,
+    /// Wander around randomly.
+    Random,
+    /// Follow a recorded path, over and over.
+    Path,
+    /// Go after the nearest living player's cursor.
+    Chase,
+}
+
+/// How bots move their cursors.
+#[derive(Clone, Debug)]
+pub struct Behavior {
+    pub pattern: Pattern,
+    /// Cursor speed in game units per second. Doesn't apply to
+    /// `Pattern::Path`, which goes as fast as it was recorded.
+    pub speed: f32,
+    /// Path for `Pattern::Path` to follow.
+    pub path: Option<Arc<CursorPath>>,
+}
+
+/// Cursor positions over time, which `Pattern::Path` plays back.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CursorPath {
+    /// Points in time order, each with its time in seconds from the
+    /// start of the path.
+    points: Vec<(f32, Point2<f32>)>,
+}
+
+/// Options for running a swarm of bots.
+#[derive(Clone, Debug)]
+pub struct SwarmConfig {
+    pub addr: SocketAddr,
+    /// Number of bots to connect.
+    pub count: usize,
+    pub behavior: Behavior,
+}
+
+/// A bot that's connected, or is still connecting.
+enum Bot {
+    Connecting {
+        connecting: ConnectingHandle,
+        /// Starting cursor position.
+        start: Point2<f32>,
+    },
+    Connected {
+        game: Game,
+        done: ConnectedHandle,
+        start: Point2<f32>,
+        cursor: Point2<f32>,
+        /// Direction that the cursor is wandering in, in radians.
+        heading: f32,
+    },
+    Done,
+}
+
+/// Network stats summed over every bot since the last report.
+#[derive(Default)]
+struct Totals {
+    packets_sent: u32,
+    packets_received: u32,
+    packets_lost_out: u32,
+    packets_lost_in: u32,
+    rtt_sum: f32,
+    rtt_samples: u32,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pattern, String> {
+        match s {
+            "still" => Ok(Pattern::Still),
+            "circle" => Ok(Pattern::Circle),
+            "random" => Ok(Pattern::Random),
+            "path" => Ok(Pattern::Path),
+            "chase" => Ok(Pattern::Chase),
+            _ => Err(format!("unknown pattern {:?}", s)),
+        }
+    }
+}
+
+/// Generates a random cursor position within the inner 30% of the
+/// circle.
+pub fn random_cursor<R: Rng>(rng: &mut R) -> Point2<f32> {
+    // Rejection sampling because I'm lazy...
+    loop {
+        let cursor =
+            Point2::new(rng.gen_range(-0.3, 0.3), rng.gen_range(-0.3, 0.3));
+        if cursor.coords.norm_squared() < 0.5 * 0.5 {
+            break cursor;
+        }
+    }
+}
+
+/// Turns a wandering cursor a random amount and moves it along its
+/// heading, returning where it ends up.
+pub fn wander<R: Rng>(
+    cursor: Point2<f32>,
+    heading: &mut f32,
+    speed: f32,
+    dt: f32,
+    rng: &mut R,
+) -> Point2<f32> {
+    *heading += rng.gen_range(-PI, PI) * WANDER_TURN_RATE * dt;
+    let direction = Vector2::new(heading.cos(), heading.sin());
+    cursor + direction * speed * dt
+}
+
+/// Moves a cursor straight toward a target, but no further than
+/// `max_distance`.
+fn approach(
+    cursor: Point2<f32>,
+    target: Point2<f32>,
+    max_distance: f32,
+) -> Point2<f32> {
+    let offset = target - cursor;
+    let distance = offset.norm();
+    if distance <= max_distance {
+        target
+    } else {
+        cursor + offset * (max_distance / distance)
+    }
+}
+
+impl CursorPath {
+    /// Reads a path from a file.
+    pub fn load(path: &Path) -> Result<CursorPath, String> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            format!("reading {} failed: {}", path.display(), err)
+        })?;
+        contents.parse().map_err(|err| {
+            format!("{} isn't a valid path: {}", path.display(), err)
+        })
+    }
+
+    /// Gets the cursor position at some time, looping back to the
+    /// start once the path is over.
+    pub fn at(&self, time: f32) -> Point2<f32> {
+        let &(duration, last) = self.points.last().unwrap();
+        let time = if duration > 0.0 {
+            time % duration
+        } else {
+            0.0
+        };
+        match self.points.iter().position(|&(t, _)| t > time) {
+            Some(0) => self.points[0].1,
+            Some(i) => {
+                let (t0, p0) = self.points[i - 1];
+                let (t1, p1) = self.points[i];
+                p0 + (p1 - p0) * ((time - t0) / (t1 - t0))
+            },
+            None => last,
+        }
+    }
+}
+
+impl FromStr for CursorPath {
+    type Err = String;
+
+    /// Parses a path with a `seconds x y` point on each line, in time
+    /// order.
+    ///
+    /// Blank lines and lines starting with `#` are skipped. Paths
+    /// loop, so one that ends where it started plays back smoothly.
+    fn from_str(s: &str) -> Result<CursorPath, String> {
+        let mut points = Vec::<(f32, Point2<f32>)>::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values = line
+                .split_whitespace()
+                .map(str::parse::<f32>)
+                .collect::<Result<Vec<_>, _>>();
+            let (time, point) = match values.as_ref().map(Vec::as_slice) {
+                Ok(&[time, x, y]) => (time, Point2::new(x, y)),
+                _ => {
+                    return Err(format!(
+                        "line {} should be `seconds x y`",
+                        i + 1
+                    ))
+                },
+            };
+            if points.last().map_or(time < 0.0, |&(last, _)| time <= last) {
+                return Err(format!(
+                    "line {} has to be later than the line before",
+                    i + 1
+                ));
+            }
+            points.push((time, point));
+        }
+        if points.is_empty() {
+            return Err("there are no points".to_owned());
+        }
+        Ok(CursorPath {
+            points,
+        })
+    }
+}
+
+impl Totals {
+    fn add(&mut self, stats: &NetworkStats) {
+        self.packets_sent += u32::from(stats.packets_sent);
+        self.packets_received += u32::from(stats.packets_received);
+        self.packets_lost_out += u32::from(stats.packets_lost_out);
+        self.packets_lost_in += u32::from(stats.packets_lost_in);
+        if stats.rtt > 0.0 {
+            self.rtt_sum += stats.rtt;
+            self.rtt_samples += 1;
+        }
+    }
+
+    fn report(&self, connecting: usize, connected: usize, failed: usize) {
+        let ratio = |lost: u32, total: u32| {
+            if total > 0 {
+                100.0 * lost as f32 / total as f32
+            } else {
+                0.0
+            }
+        };
+        let rtt = if self.rtt_samples > 0 {
+            1000.0 * self.rtt_sum / self.rtt_samples as f32
+        } else {
+            0.0
+        };
+        info!(
+            "swarm: {} connected, {} connecting, {} failed, mean rtt {:.1} \
+             ms, loss out {:.1} %, loss in {:.1} %",
+            connected,
+            connecting,
+            failed,
+            rtt,
+            ratio(self.packets_lost_out, self.packets_sent),
+            ratio(
+                self.packets_lost_in,
+                self.packets_received + self.packets_lost_in
+            ),
+        );
+    }
+}
+
+/// Connects a swarm of bots to a server, and moves them around until
+/// they all disconnect or the process is interrupted.
+///
+/// Aggregate stats are logged periodically.
+pub fn run(config: SwarmConfig) {
+    let mut rng = thread_rng();
+    let starts =
+        (0..config.count).map(|_| random_cursor(&mut rng)).collect::<Vec<_>>();
+    let clients = starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| (start, format!("bot {}", i + 1)))
+        .collect();
+    // Every bot sends stats at the same rate, so leave plenty of room.
+    let (stats_tx, stats_rx) = channel::bounded(32 * config.count.max(1));
+    let (handles, thread) =
+        match client::connect_many(config.addr, Some(stats_tx), clients) {
+            Ok(result) => result,
+            Err(err) => {
+                warn!("failed to start swarm: {}", err);
+                return;
+            },
+        };
+    let (client_handles, mut bots): (Vec<_>, Vec<_>) = handles
+        .into_iter()
+        .zip(starts)
+        .map(|((handle, connecting), start)| {
+            (
+                handle,
+                Bot::Connecting {
+                    connecting,
+                    start,
+                },
+            )
+        })
+        .unzip();
+    ctrlc::set_handler(move || {
+        for handle in &client_handles {
+            handle.shutdown();
+        }
+    })
+    .unwrap();
+
+    let start = Instant::now();
+    let mut last_update = start;
+    let mut last_report = start;
+    let mut failed = 0;
+    let mut totals = Totals::default();
+    loop {
+        thread::sleep(UPDATE_RATE);
+        let now = Instant::now();
+        let dt = now.duration_since(last_update).as_secs_f32();
+        let time = now.duration_since(start).as_secs_f32();
+        last_update = now;
+
+        for (i, bot) in bots.iter_mut().enumerate() {
+            if update_bot(bot, &config.behavior, i, time, dt, &mut rng) {
+                failed += 1;
+            }
+        }
+        for stats in stats_rx.try_iter() {
+            totals.add(&stats);
+        }
+
+        let (mut connecting, mut connected) = (0, 0);
+        for bot in &bots {
+            match bot {
+                Bot::Connecting {
+                    ..
+                } => connecting += 1,
+                Bot::Connected {
+                    ..
+                } => connected += 1,
+                Bot::Done => (),
+            }
+        }
+        let finished = connecting == 0 && connected == 0;
+        if finished || now.duration_since(last_report) >= REPORT_RATE {
+            totals.report(connecting, connected, failed);
+            totals = Totals::default();
+            last_report = now;
+        }
+        if finished {
+            break;
+        }
+    }
+    thread.join().unwrap();
+}
+
+/// Moves the cursor of a single client that's connecting, until it
+/// disconnects.
+pub fn drive(
+    connecting: ConnectingHandle,
+    cursor: Point2<f32>,
+    behavior: Behavior,
+) {
+    let mut rng = thread_rng();
+    let mut bot = Bot::Connecting {
+        connecting,
+        start: cursor,
+    };
+    let start = Instant::now();
+    let mut last_update = start;
+    loop {
+        thread::sleep(UPDATE_RATE);
+        let now = Instant::now();
+        let dt = now.duration_since(last_update).as_secs_f32();
+        let time = now.duration_since(start).as_secs_f32();
+        last_update = now;
+
+        update_bot(&mut bot, &behavior, 0, time, dt, &mut rng);
+        if let Bot::Done = bot {
+            break;
+        }
+    }
+}
+
+/// Moves a bot, and returns whether it just failed to connect.
+fn update_bot<R: Rng>(
+    bot: &mut Bot,
+    behavior: &Behavior,
+    index: usize,
+    time: f32,
+    dt: f32,
+    rng: &mut R,
+) -> bool {
+    let next = match bot {
+        Bot::Connecting {
+            connecting,
+            start,
+        } => {
+            match connecting.try_recv() {
+                Ok(Ok((game, done))) => {
+                    Bot::Connected {
+                        game,
+                        done,
+                        start: *start,
+                        cursor: *start,
+                        heading: rng.gen_range(0.0, 2.0 * PI),
+                    }
+                },
+                Ok(Err(err)) => {
+                    if let Some(err) = err {
+                        warn!("bot {} failed to connect: {}", index + 1, err);
+                    }
+                    *bot = Bot::Done;
+                    return true;
+                },
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => {
+                    *bot = Bot::Done;
+                    return true;
+                },
+            }
+        },
+        Bot::Connected {
+            game,
+            done,
+            start,
+            cursor,
+            heading,
+        } => {
+            if let Ok(err) = done.try_recv() {
+                if let Some(err) = err {
+                    warn!("bot {} disconnected: {}", index + 1, err);
+                }
+                *bot = Bot::Done;
+                return false;
+            }
+
+            game.handle_events();
+            game.tick(dt);
+            game.drain_events().for_each(drop);
+
+            let target = match behavior.pattern {
+                Pattern::Still => *start,
+                Pattern::Circle => {
+                    // Offset each bot around the circle.
+                    let angle =
+                        behavior.speed * time / CIRCLE_RADIUS + index as f32;
+                    *start +
+                        Vector2::new(angle.cos(), angle.sin()) * CIRCLE_RADIUS
+                },
+                Pattern::Random => {
+                    wander(*cursor, heading, behavior.speed, dt, rng)
+                },
+                Pattern::Path => {
+                    match behavior.path {
+                        Some(ref path) => {
+                            path.at(time + index as f32 * PATH_OFFSET)
+                        },
+                        None => *start,
+                    }
+                },
+                Pattern::Chase => {
+                    match nearest_cursor(game, *cursor) {
+                        Some(prey) => {
+                            approach(*cursor, prey, behavior.speed * dt)
+                        },
+                        None => {
+                            wander(*cursor, heading, behavior.speed, dt, rng)
+                        },
+                    }
+                },
+            };
+            *cursor = clamp_cursor(target, game.settings());
+            game.update_cursor(*cursor);
+            return false;
+        },
+        Bot::Done => return false,
+    };
+    *bot = next;
+    false
+}
+
+/// Finds the cursor of the nearest living player other than our own.
+fn nearest_cursor(game: &Game, cursor: Point2<f32>) -> Option<Point2<f32>> {
+    let own = game.player_id();
+    game.latest_players()
+        .into_iter()
+        .filter(|&(id, _)| id != own)
+        .filter_map(|(_, player)| (&player).state().cursor)
+        .ord_subset_min_by_key(|other| {
+            nalgebra::distance_squared(other, &cursor)
+        })
+}
+
+#[test]
+fn cursor_path_playback() {
+    let path = "# a triangle\n0 0 0\n1 0.2 0\n\n2 0 0.2\n"
+        .parse::<CursorPath>()
+        .unwrap();
+    assert_eq!(path.at(0.5), Point2::new(0.1, 0.0));
+    assert_eq!(path.at(2.0), Point2::new(0.0, 0.0));
+    assert_eq!(path.at(3.5), Point2::new(0.1, 0.1));
+
+    assert!("0 0 0\n0 1 1\n".parse::<CursorPath>().is_err());
+    assert!("0 0\n".parse::<CursorPath>().is_err());
+    assert!("# nothing\n".parse::<CursorPath>().is_err());
+}
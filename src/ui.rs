@@ -1,4 +1,130 @@
-pub use imgui::{ImStr, Ui};
+use crate::config::InterfaceConfig;
+use imgui::{im_str, sys, ImGui, ImGuiStyle};
+pub use imgui::{ImStr, ImString, Ui};
+use serde::{Deserialize, Serialize};
+
+/// Maximum length of strings edited through `input_text`.
+const INPUT_CAPACITY: usize = 64;
+
+/// Creates a string that can be edited with `input_text` and grow up
+/// to a reasonable length, since an `ImString` can't grow past its
+/// capacity while being edited.
+pub fn input_string(value: &str) -> ImString {
+    let mut string = ImString::with_capacity(INPUT_CAPACITY);
+    string.push_str(value);
+    string
+}
+
+/// Range of the UI scale slider.
+const MIN_SCALE: f32 = 0.5;
+const MAX_SCALE: f32 = 3.0;
+
+/// Built-in imgui color schemes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Theme {
+    Dark,
+    Light,
+    Classic,
+}
+
+/// User-selected look of the UI.
+///
+/// Changes are made from the preferences window, and applied to the
+/// imgui context before the next frame.
+pub struct Appearance {
+    pub theme: Theme,
+    /// Multiplier for every size in the UI, including fonts.
+    pub scale: f32,
+    /// Whether players are marked with patterns as well as colors.
+    pub player_outlines: bool,
+    /// Whether balls show their player's latency.
+    pub latency_indicators: bool,
+    pub show_window: bool,
+    /// Unscaled style, since scaling the live style is cumulative.
+    base_style: Option<ImGuiStyle>,
+    dirty: bool,
+}
+
+impl Appearance {
+    pub fn new(config: &InterfaceConfig) -> Appearance {
+        Appearance {
+            theme: config.theme,
+            scale: config.scale.max(MIN_SCALE).min(MAX_SCALE),
+            player_outlines: config.player_outlines,
+            latency_indicators: config.latency_indicators,
+            show_window: false,
+            base_style: None,
+            dirty: true,
+        }
+    }
+
+    /// Updates the imgui style if anything changed since it was last
+    /// applied.
+    pub fn apply(&mut self, imgui: &mut ImGui) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+
+        let style = imgui.style_mut();
+        let base = *self.base_style.get_or_insert(*style);
+        *style = base;
+        unsafe {
+            match self.theme {
+                Theme::Dark => sys::igStyleColorsDark(style),
+                Theme::Light => sys::igStyleColorsLight(style),
+                Theme::Classic => sys::igStyleColorsClassic(style),
+            }
+            sys::ImGuiStyle_ScaleAllSizes(style, self.scale);
+        }
+        imgui.set_font_global_scale(self.scale);
+    }
+
+    /// Shows the preferences window, if it's open.
+    pub fn window<'a>(&mut self, ui: &Ui<'a>) {
+        if !self.show_window {
+            return;
+        }
+        let theme = &mut self.theme;
+        let scale = &mut self.scale;
+        let player_outlines = &mut self.player_outlines;
+        let latency_indicators = &mut self.latency_indicators;
+        let mut changed = false;
+        ui.window(im_str!("Interface"))
+            .always_auto_resize(true)
+            .opened(&mut self.show_window)
+            .build(|| {
+                changed |= enum_combo(
+                    ui,
+                    im_str!("Theme"),
+                    theme,
+                    &[im_str!("Dark"), im_str!("Light"), im_str!("Classic")],
+                    &[Theme::Dark, Theme::Light, Theme::Classic],
+                    3,
+                );
+                changed |= ui
+                    .slider_float(
+                        im_str!("UI scale"),
+                        scale,
+                        MIN_SCALE,
+                        MAX_SCALE,
+                    )
+                    .build();
+                // Only read when drawing, so it doesn't restyle imgui.
+                ui.checkbox(im_str!("Player outlines"), player_outlines);
+                ui.checkbox(im_str!("Latency indicators"), latency_indicators);
+            });
+        self.dirty |= changed;
+    }
+
+    /// Stores the preferences back into the config.
+    pub fn save(&self, config: &mut InterfaceConfig) {
+        config.theme = self.theme;
+        config.scale = self.scale;
+        config.player_outlines = self.player_outlines;
+        config.latency_indicators = self.latency_indicators;
+    }
+}
 
 pub fn enum_combo<'p, 'ui, T: PartialEq + Eq + Copy>(
     ui: &Ui<'ui>,
@@ -0,0 +1,129 @@
+use nalgebra::{Point2, Vector2};
+use serde::{Deserialize, Serialize};
+
+/// Largest UI margin, as a fraction of the window's shorter side.
+pub const MAX_MARGIN: f32 = 0.25;
+
+/// How the arena is fitted into windows that aren't square.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Framing {
+    /// The whole arena fits along the shorter side, leaving empty
+    /// space along the longer one.
+    Fit,
+    /// The arena covers the longer side, cropping the shorter one.
+    Fill,
+    /// The arena always covers the height, whatever the width is.
+    FixedHeight,
+}
+
+/// Maps between world space, where the arena view spans -1 to 1, and
+/// the window.
+///
+/// The same mapping is used for the global UBO and for turning cursor
+/// positions into world space, so that they always agree.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ViewportMapping {
+    pub framing: Framing,
+    /// Space kept clear around the arena for UI windows, as a
+    /// fraction of the window's shorter side.
+    pub margin: f32,
+}
+
+impl Default for ViewportMapping {
+    fn default() -> ViewportMapping {
+        ViewportMapping {
+            framing: Framing::Fit,
+            margin: 0.0,
+        }
+    }
+}
+
+impl ViewportMapping {
+    /// Gets the size in pixels of one world unit, for a window of the
+    /// given size.
+    ///
+    /// The size can be in physical or logical pixels, as long as
+    /// positions passed along with it are in the same ones.
+    pub fn pixels_per_unit(&self, width: f32, height: f32) -> f32 {
+        let margin =
+            2.0 * self.margin.max(0.0).min(MAX_MARGIN) * width.min(height);
+        let (width, height) =
+            ((width - margin).max(1.0), (height - margin).max(1.0));
+        let side = match self.framing {
+            Framing::Fit => width.min(height),
+            Framing::Fill => width.max(height),
+            Framing::FixedHeight => height,
+        };
+        0.5 * side
+    }
+
+    /// Gets the scale from world space to clip space.
+    pub fn scale(&self, width: f32, height: f32) -> [f32; 2] {
+        let unit = self.pixels_per_unit(width, height);
+        [2.0 * unit / width, 2.0 * unit / height]
+    }
+
+    /// Converts a position in the window, from the top left, into
+    /// world space.
+    pub fn to_world(
+        &self,
+        width: f32,
+        height: f32,
+        position: Point2<f32>,
+    ) -> Point2<f32> {
+        let center = Point2::new(0.5 * width, 0.5 * height);
+        Point2::from(self.delta_to_world(width, height, position - center))
+    }
+
+    /// Converts a position in world space into the window, from the
+    /// top left, undoing `to_world`.
+    pub fn to_window(
+        &self,
+        width: f32,
+        height: f32,
+        position: Point2<f32>,
+    ) -> Point2<f32> {
+        let center = Point2::new(0.5 * width, 0.5 * height);
+        center + position.coords * self.pixels_per_unit(width, height)
+    }
+
+    /// Converts a distance moved in the window into world space.
+    pub fn delta_to_world(
+        &self,
+        width: f32,
+        height: f32,
+        delta: Vector2<f32>,
+    ) -> Vector2<f32> {
+        delta / self.pixels_per_unit(width, height)
+    }
+}
+
+#[test]
+fn fit_matches_shorter_side() {
+    let mapping = ViewportMapping::default();
+    assert_eq!(mapping.scale(200.0, 100.0), [0.5, 1.0]);
+    assert_eq!(mapping.scale(100.0, 200.0), [1.0, 0.5]);
+    let corner = mapping.to_world(200.0, 100.0, Point2::new(150.0, 100.0));
+    assert_eq!(corner, Point2::new(1.0, 1.0));
+}
+
+#[test]
+fn margin_shrinks_arena() {
+    let mapping = ViewportMapping {
+        framing: Framing::Fill,
+        margin: 0.1,
+    };
+    assert_eq!(mapping.pixels_per_unit(200.0, 100.0), 90.0);
+}
+
+#[test]
+fn to_window_undoes_to_world() {
+    let mapping = ViewportMapping {
+        framing: Framing::Fill,
+        margin: 0.1,
+    };
+    let position = Point2::new(130.0, 40.0);
+    let world = mapping.to_world(300.0, 200.0, position);
+    let window = mapping.to_window(300.0, 200.0, world);
+    assert!(nalgebra::distance(&window, &position) < 1e-3);
+}